@@ -0,0 +1,91 @@
+// CPU hot path benchmarks. Run with `cargo bench --features testing`.
+//
+// Baseline, with emulate_cycle still printing a trace line per instruction (stdout to a file):
+//   adc_branch_loop/1000 instructions     ~2.1 ms
+//   indexed_copy_loop/1000 instructions   ~2.0 ms
+//   frame/one NTSC frame                  ~25 ms
+//
+// With no formatting or IO left in emulate_cycle:
+//   adc_branch_loop/1000 instructions     ~30 us
+//   indexed_copy_loop/1000 instructions   ~33 us
+//   frame/one NTSC frame                  ~365 us
+//
+// Keeping the crash report's ring of recent instructions in every fetch made no difference
+// outside noise (adc_branch_loop ~280 us before, ~286 us after, at p = 0.11). On its own:
+//   trace_ring/1000 records               ~1.6 us
+
+#[macro_use]
+extern crate criterion;
+extern crate nes;
+
+use criterion::Criterion;
+use nes::asm::assemble;
+use nes::cpu::CPU;
+use nes::crash::{TraceRecord, TraceRing};
+use nes::testing::build_program;
+
+const INSTRUCTIONS: usize = 1000;
+
+const ADC_BRANCH_LOOP: &str = "
+loop:   ADC #$01
+        BNE skip
+        INX
+skip:   JMP loop
+";
+
+// Half the reads and writes cross a page
+const INDEXED_COPY_LOOP: &str = "
+loop:   LDA $0280,X
+        STA $0380,X
+        INX
+        JMP loop
+";
+
+fn build(source: &str) -> CPU {
+    build_program(&assemble(source, 0x8000).unwrap())
+}
+
+fn run_instructions(cpu: &mut CPU, count: usize) {
+    for _ in 0..count {
+        cpu.emulate_cycle().unwrap();
+    }
+}
+
+fn run_frame(cpu: &mut CPU) {
+    let frame = cpu.memory().ppu.frame;
+    while cpu.memory().ppu.frame == frame {
+        cpu.emulate_cycle().unwrap();
+    }
+}
+
+fn adc_branch_loop(c: &mut Criterion) {
+    let mut cpu = build(ADC_BRANCH_LOOP);
+    c.bench_function("adc_branch_loop/1000 instructions", |b| b.iter(|| run_instructions(&mut cpu, INSTRUCTIONS)));
+}
+
+fn indexed_copy_loop(c: &mut Criterion) {
+    let mut cpu = build(INDEXED_COPY_LOOP);
+    c.bench_function("indexed_copy_loop/1000 instructions", |b| b.iter(|| run_instructions(&mut cpu, INSTRUCTIONS)));
+}
+
+fn frame(c: &mut Criterion) {
+    let mut cpu = build(ADC_BRANCH_LOOP);
+    c.bench_function("frame/one NTSC frame", |b| b.iter(|| run_frame(&mut cpu)));
+}
+
+fn trace_ring(c: &mut Criterion) {
+    let mut ring = TraceRing::new();
+    c.bench_function("trace_ring/1000 records", |b| b.iter(|| {
+        for i in 0..INSTRUCTIONS {
+            ring.record(TraceRecord { pc: i as u16, opcode: i as u8, ..TraceRecord::default() });
+        }
+        criterion::black_box(&ring);
+    }));
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(20);
+    targets = adc_branch_loop, indexed_copy_loop, frame, trace_ring
+}
+criterion_main!(benches);
@@ -0,0 +1,50 @@
+import init, { WasmNes } from "./pkg/nes.js";
+
+// Shift register bit for each key
+const KEYS = {
+  KeyX: 0, KeyZ: 1, ShiftLeft: 2, Enter: 3,
+  ArrowUp: 4, ArrowDown: 5, ArrowLeft: 6, ArrowRight: 7,
+};
+
+const canvas = document.getElementById("screen");
+const ctx = canvas.getContext("2d");
+const image = ctx.createImageData(256, 240);
+let nes = null;
+
+function frame() {
+  if (nes) {
+    image.data.set(nes.run_frame());
+    ctx.putImageData(image, 0, 0);
+  }
+  requestAnimationFrame(frame);
+}
+
+function key(pressed) {
+  return (event) => {
+    if (nes && event.code in KEYS) {
+      nes.set_button(0, KEYS[event.code], pressed);
+      event.preventDefault();
+    } else if (nes && pressed && event.code === "KeyP") {
+      nes.set_paused(!nes.is_paused());
+    } else if (nes && pressed && event.code === "Backslash") {
+      // Steps one frame; the next animation frame redraws it
+      nes.frame_advance();
+    }
+  };
+}
+
+document.getElementById("rom").addEventListener("change", async (event) => {
+  const file = event.target.files[0];
+  const rom = new Uint8Array(await file.arrayBuffer());
+  try {
+    nes = new WasmNes(rom);
+  } catch (e) {
+    alert(`Can't load ${file.name}: ${e}`);
+  }
+});
+
+window.addEventListener("keydown", key(true));
+window.addEventListener("keyup", key(false));
+
+await init();
+requestAnimationFrame(frame);
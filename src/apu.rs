@@ -0,0 +1,1193 @@
+// The APU's pulse, triangle and noise channels, the frame counter that clocks them and raises
+// the frame IRQ, and the mixer that turns them and the DMC into sound.
+//
+// Everything up to the output samples is integer arithmetic: the mixer's two nonlinear curves
+// are tables worked out with integer division, the output rate is reached with an integer
+// band-limited resampler, and the DC-blocking filter is fixed point. The same ROM and input
+// give the same samples bit for bit on any host and at any optimization level, so audio can be hashed for
+// regression runs just like the framebuffer. Samples only become floats at the host boundary.
+
+use dmc::Dmc;
+use region::TimingConfig;
+use resampler::Resampler;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use std::str::FromStr;
+
+// Length counter loads, by the top five bits of $4003, $4007, $400B and $400F
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+// Pulse waveforms by duty setting, the sequencer's first step in the top bit
+const DUTY_TABLE: [u8; 4] = [0b0100_0000, 0b0110_0000, 0b0111_1000, 0b1001_1111];
+
+const TRIANGLE_TABLE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+// $4017 bits
+const FIVE_STEP: u8 = 1 << 7;
+const IRQ_INHIBIT: u8 = 1 << 6;
+// $4015 bits
+pub const STATUS_FRAME_IRQ: u8 = 1 << 6;
+
+// Pulse 1, pulse 2, triangle and noise, in $4015's bit order
+const CHANNELS: usize = 4;
+const TRIANGLE: usize = 2;
+const NOISE: usize = 3;
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+// A channel the mixer hears, which can be muted while debugging. Pulse 1 through the DMC, in
+// $4015's bit order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    Dmc,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 5] = [Channel::Pulse1, Channel::Pulse2, Channel::Triangle, Channel::Noise, Channel::Dmc];
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Channel::Pulse1 => "pulse1",
+            Channel::Pulse2 => "pulse2",
+            Channel::Triangle => "triangle",
+            Channel::Noise => "noise",
+            Channel::Dmc => "dmc",
+        }
+    }
+}
+
+impl FromStr for Channel {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Channel, &'static str> {
+        Channel::ALL.iter().find(|channel| channel.name().eq_ignore_ascii_case(s)).cloned()
+            .ok_or("channel must be pulse1, pulse2, triangle, noise or dmc")
+    }
+}
+
+// The mixer's output for the two pulses summed, and for the triangle, noise and DMC weighted
+// 3:2:1 and summed, scaled so the two together just fit an i16. These are the usual
+// 95.52 / (8128 / n + 100) and 163.67 / (24329 / n + 100), rearranged so integer division
+// gives them exactly.
+const MIX_SCALE: u64 = 32767;
+const PULSE_TABLE: [u16; 31] = pulse_table();
+const TND_TABLE: [u16; 203] = tnd_table();
+
+const fn pulse_table() -> [u16; 31] {
+    let mut table = [0; 31];
+    let mut n = 0;
+    while n < table.len() {
+        table[n] = (9552 * n as u64 * MIX_SCALE / (812800 + 10000 * n as u64)) as u16;
+        n += 1;
+    }
+    table
+}
+
+const fn tnd_table() -> [u16; 203] {
+    let mut table = [0; 203];
+    let mut n = 0;
+    while n < table.len() {
+        table[n] = (16367 * n as u64 * MIX_SCALE / (2432900 + 10000 * n as u64)) as u16;
+        n += 1;
+    }
+    table
+}
+
+// The DC-blocking filter's corner in Hz, and the fraction bits of its coefficient
+const HIGH_PASS_HZ: u64 = 90;
+const FILTER_BITS: u32 = 15;
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+// Clock a timer that counts down to 0 and then reloads, clocks times over. Returns how many
+// times it reloaded.
+fn run_timer(timer: &mut u16, reload: u16, clocks: u64) -> u64 {
+    if clocks <= *timer as u64 {
+        *timer -= clocks as u16;
+        return 0
+    }
+    let rest = clocks - *timer as u64 - 1;
+    let period = reload as u64 + 1;
+    *timer = (reload as u64 - rest % period) as u16;
+    return 1 + rest / period
+}
+
+// A channel's length counter. The channel is silenced while it's zero.
+//
+// Writes take effect at the end of the CPU cycle they land on, after the frame counter has had
+// its turn. That's what makes the two races blargg's apu_test checks come out right: a reload
+// on the cycle the counter is clocked is lost unless the counter was already zero, and a halt
+// written on that cycle doesn't stop that clock.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+struct LengthCounter {
+    enabled: bool,
+    // The envelope loop flag, or the triangle's linear counter control
+    halt: bool,
+    count: u8,
+    pending_halt: Option<bool>,
+    pending_reload: Option<u8>,
+}
+
+impl LengthCounter {
+    // One CPU cycle, clocking the counter first if it's a half frame
+    fn step(&mut self, half_frame: bool) {
+        let before = self.count;
+        if half_frame && !self.halt && self.count > 0 {
+            self.count -= 1;
+        }
+        if let Some(halt) = self.pending_halt.take() {
+            self.halt = halt;
+        }
+        if let Some(count) = self.pending_reload.take() {
+            if !half_frame || before == 0 {
+                self.count = count;
+            }
+        }
+    }
+
+    // Enabling doesn't reload the counter, only the next write to the length register does.
+    // Disabling zeroes it at once.
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.count = 0;
+            self.pending_reload = None;
+        }
+    }
+
+    // A write to the channel's length register, which is ignored while it's disabled
+    fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.pending_reload = Some(LENGTH_TABLE[index as usize]);
+        }
+    }
+
+    fn is_pending(&self) -> bool {
+        self.pending_halt.is_some() || self.pending_reload.is_some()
+    }
+}
+
+// The volume of a pulse or the noise: either constant, or a sawtooth decaying from 15 once
+// per quarter frame that starts over if the loop flag (the length counter's halt) is set
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+struct Envelope {
+    constant: bool,
+    // The constant volume, or the decay's divider period
+    volume: u8,
+    start: bool,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    fn write(&mut self, val: u8) {
+        self.constant = val & 0x10 != 0;
+        self.volume = val & 0x0F;
+    }
+
+    fn quarter_frame(&mut self, looping: bool) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider > 0 {
+            self.divider -= 1;
+        } else {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if looping {
+                self.decay = 15;
+            }
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.constant { self.volume } else { self.decay }
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+struct Pulse {
+    // Pulse 1's sweep negates with ones' complement, pulse 2's with two's
+    ones_complement: bool,
+    duty: u8,
+    step: u8,
+    // In APU cycles, two CPU cycles each
+    period: u16,
+    timer: u16,
+    envelope: Envelope,
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_divider: u8,
+    sweep_reload: bool,
+}
+
+impl Pulse {
+    // The channel's four registers, by their low two address bits
+    fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => {
+                self.duty = val >> 6;
+                self.envelope.write(val);
+            },
+            1 => {
+                self.sweep_enabled = val & 0x80 != 0;
+                self.sweep_period = (val >> 4) & 7;
+                self.sweep_negate = val & 0x08 != 0;
+                self.sweep_shift = val & 7;
+                self.sweep_reload = true;
+            },
+            2 => self.period = (self.period & 0x700) | val as u16,
+            _ => {
+                self.period = (self.period & 0xFF) | (val as u16 & 7) << 8;
+                self.step = 0;
+                self.envelope.start = true;
+            },
+        }
+    }
+
+    // True when the sequencer moves on
+    fn clock_timer(&mut self) -> bool {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return false
+        }
+        self.timer = self.period;
+        self.step = (self.step + 1) & 7;
+        return true
+    }
+
+    // Whether the sequencer's position makes any difference to what's heard
+    fn audible(&self, length: &LengthCounter) -> bool {
+        length.count > 0 && !self.muted() && self.envelope.output() > 0
+    }
+
+    fn skip(&mut self, clocks: u64) {
+        let reloads = run_timer(&mut self.timer, self.period, clocks);
+        self.step = ((self.step as u64 + reloads) & 7) as u8;
+    }
+
+    // The period the sweep is heading for. The channel is muted while it's out of range, even
+    // with the sweep disabled.
+    fn sweep_target(&self) -> u16 {
+        let change = self.period >> self.sweep_shift;
+        if !self.sweep_negate {
+            self.period + change
+        } else if self.ones_complement {
+            self.period.saturating_sub(change + 1)
+        } else {
+            self.period.saturating_sub(change)
+        }
+    }
+
+    fn muted(&self) -> bool {
+        self.period < 8 || self.sweep_target() > 0x7FF
+    }
+
+    fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.muted() {
+            self.period = self.sweep_target();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    fn output(&self, length: &LengthCounter) -> u8 {
+        let high = DUTY_TABLE[self.duty as usize] & (0x80 >> self.step) != 0;
+        if !high || length.count == 0 || self.muted() { 0 } else { self.envelope.output() }
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+struct Triangle {
+    // In CPU cycles
+    period: u16,
+    timer: u16,
+    step: u8,
+    linear_period: u8,
+    linear_count: u8,
+    linear_reload: bool,
+}
+
+impl Triangle {
+    fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => self.linear_period = val & 0x7F,
+            2 => self.period = (self.period & 0x700) | val as u16,
+            3 => {
+                self.period = (self.period & 0xFF) | (val as u16 & 7) << 8;
+                self.linear_reload = true;
+            },
+            _ => {},
+        }
+    }
+
+    // The sequencer only moves while both counters are running, so a stopped triangle holds
+    // its level rather than dropping to 0
+    fn clock_timer(&mut self, length: &LengthCounter) -> bool {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return false
+        }
+        self.timer = self.period;
+        if self.running(length) {
+            self.step = (self.step + 1) & 31;
+            return true
+        }
+        return false
+    }
+
+    fn running(&self, length: &LengthCounter) -> bool {
+        self.linear_count > 0 && length.count > 0
+    }
+
+    fn skip(&mut self, cycles: u64, length: &LengthCounter) {
+        let reloads = run_timer(&mut self.timer, self.period, cycles);
+        if self.running(length) {
+            self.step = ((self.step as u64 + reloads) & 31) as u8;
+        }
+    }
+
+    // The control flag is the length counter's halt flag
+    fn clock_linear(&mut self, control: bool) {
+        if self.linear_reload {
+            self.linear_count = self.linear_period;
+        } else if self.linear_count > 0 {
+            self.linear_count -= 1;
+        }
+        if !control {
+            self.linear_reload = false;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        TRIANGLE_TABLE[self.step as usize]
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Noise {
+    // Timer periods in CPU cycles for the region, by $400E's index
+    periods: [u16; 16],
+    period: u16,
+    timer: u16,
+    // Short mode takes feedback from bit 6 rather than bit 1, for a 93-step metallic loop
+    short_mode: bool,
+    shift: u16,
+    envelope: Envelope,
+}
+
+impl Noise {
+    fn new(periods: [u16; 16]) -> Noise {
+        Noise {
+            periods: periods,
+            period: periods[0],
+            timer: 0,
+            short_mode: false,
+            shift: 1,
+            envelope: Envelope::default(),
+        }
+    }
+
+    fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => self.envelope.write(val),
+            2 => {
+                self.short_mode = val & 0x80 != 0;
+                self.period = self.periods[(val & 0x0F) as usize];
+            },
+            3 => self.envelope.start = true,
+            _ => {},
+        }
+    }
+
+    fn clock_timer(&mut self) -> bool {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return false
+        }
+        self.timer = self.period - 1;
+        self.clock_shift();
+        return true
+    }
+
+    fn clock_shift(&mut self) {
+        let tap = if self.short_mode { 6 } else { 1 };
+        let feedback = (self.shift ^ self.shift >> tap) & 1;
+        self.shift = self.shift >> 1 | feedback << 14;
+    }
+
+    fn audible(&self, length: &LengthCounter) -> bool {
+        length.count > 0 && self.envelope.output() > 0
+    }
+
+    fn skip(&mut self, cycles: u64) {
+        for _ in 0..run_timer(&mut self.timer, self.period - 1, cycles) {
+            self.clock_shift();
+        }
+    }
+
+    fn output(&self, length: &LengthCounter) -> u8 {
+        if self.shift & 1 != 0 || length.count == 0 { 0 } else { self.envelope.output() }
+    }
+}
+
+// A one-pole high-pass on the resampled output, to take out the DC the mixer never swings below
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct DcBlocker {
+    // The filter's coefficient, with FILTER_BITS fraction bits, and its last input and output
+    pole: i64,
+    last_in: i64,
+    last_out: i64,
+}
+
+impl DcBlocker {
+    fn new(sample_rate: u32) -> DcBlocker {
+        // 1 - 2 pi fc / fs, with pi as 355/113
+        let pole = (1u64 << FILTER_BITS).saturating_sub(((2 * 355 * HIGH_PASS_HZ) << FILTER_BITS) / (113 * sample_rate.max(1) as u64));
+        DcBlocker { pole: pole as i64, last_in: 0, last_out: 0 }
+    }
+
+    fn filter(&mut self, input: i32) -> i16 {
+        let input = input as i64;
+        let output = input - self.last_in + ((self.last_out * self.pole) >> FILTER_BITS);
+        self.last_in = input;
+        self.last_out = output;
+        output.clamp(i16::MIN as i64, i16::MAX as i64) as i16
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Apu {
+    // Frame counter step cycles for the region
+    steps: [u32; 5],
+    length: [LengthCounter; CHANNELS],
+    pulse: [Pulse; 2],
+    triangle: Triangle,
+    noise: Noise,
+    five_step: bool,
+    irq_inhibit: bool,
+    frame_irq: bool,
+    // CPU cycles since the frame counter's sequence started over
+    frame_cycle: u32,
+    // A $4017 write restarts the sequence after 3 or 4 cycles, depending on which half of an
+    // APU cycle it lands in
+    restart_delay: u8,
+    // Cycles since power-on, for that parity and for the pulse timers, which run at half speed
+    cycles: u64,
+    // What was last written to $4017, which a reset writes again
+    frame_counter: u8,
+    resampler: Resampler,
+    dc_blocker: DcBlocker,
+    // Samples the frontend hasn't taken yet. They're output, not machine state, so a save
+    // state leaves them out.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    samples: Vec<i16>,
+    // The mixer's last output and the DMC level that went into it. Most cycles change nothing
+    // the mixer hears, so it only runs again once something has, or the DMC level moves.
+    mixed: Option<(u16, u8)>,
+    // The cartridge's sound channels, already in mixer units
+    expansion: u16,
+    // The DMC's level when the mixer last ran, for channel_outputs
+    dmc_level: u8,
+    // Bit n set mutes Channel::ALL[n] in the mix. It's a debugging aid, so save states and
+    // power cycles leave it alone.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    muted: u8,
+    // Cycles still to come that quiet_cycles has said are quiet, and quiet cycles that have
+    // gone by without the channels being brought up to date
+    idle: u64,
+    deferred: u64,
+    // FNV-1a over this frame's samples so far, and over the whole of the last frame's
+    hash: u64,
+    frame_hash: u64,
+}
+
+impl Apu {
+    pub fn new(timing: &TimingConfig) -> Apu {
+        Apu::with_timing(timing.frame_counter_steps, timing.noise_periods, timing.cpu_clock_hz, DEFAULT_SAMPLE_RATE)
+    }
+
+    fn with_timing(steps: [u32; 5], noise_periods: [u16; 16], cpu_clock_hz: u32, sample_rate: u32) -> Apu {
+        Apu {
+            steps: steps,
+            length: [LengthCounter::default(); CHANNELS],
+            pulse: [Pulse { ones_complement: true, ..Pulse::default() }, Pulse::default()],
+            triangle: Triangle::default(),
+            noise: Noise::new(noise_periods),
+            five_step: false,
+            irq_inhibit: false,
+            frame_irq: false,
+            frame_cycle: 0,
+            restart_delay: 0,
+            cycles: 0,
+            frame_counter: 0,
+            resampler: Resampler::new(cpu_clock_hz, sample_rate),
+            dc_blocker: DcBlocker::new(sample_rate),
+            samples: Vec::new(),
+            mixed: None,
+            expansion: 0,
+            dmc_level: 0,
+            muted: 0,
+            idle: 0,
+            deferred: 0,
+            hash: FNV_OFFSET,
+            frame_hash: FNV_OFFSET,
+        }
+    }
+
+    pub fn set_timing(&mut self, timing: &TimingConfig) {
+        self.sync();
+        self.steps = timing.frame_counter_steps;
+        let index = self.noise.periods.iter().position(|&period| period == self.noise.period).unwrap_or(0);
+        self.noise.periods = timing.noise_periods;
+        self.noise.period = self.noise.periods[index];
+        self.resampler = Resampler::new(timing.cpu_clock_hz, self.sample_rate());
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.resampler.out_rate()
+    }
+
+    // Starts the output over at the new rate. Setting the rate already in use does nothing.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        if sample_rate != self.sample_rate() {
+            self.sync();
+            self.resampler = Resampler::new(self.resampler.in_rate(), sample_rate);
+            self.dc_blocker = DcBlocker::new(sample_rate);
+        }
+    }
+
+    // Take on a saved APU's state, keeping this one's output rate and the samples not yet
+    // taken, which belong to the host rather than the machine
+    pub fn load_state(&mut self, state: &Apu) {
+        let sample_rate = self.sample_rate();
+        let samples = std::mem::take(&mut self.samples);
+        let muted = self.muted;
+        *self = state.clone();
+        self.samples = samples;
+        self.muted = muted;
+        self.mixed = None;
+        self.set_sample_rate(sample_rate);
+    }
+
+    // The machine powers up as if $4017 had been written with 0 just before the first
+    // instruction, so the frame IRQ is on until a game turns it off
+    pub fn power_on(&mut self) {
+        let samples = std::mem::take(&mut self.samples);
+        let muted = self.muted;
+        *self = Apu::with_timing(self.steps, self.noise.periods, self.resampler.in_rate(), self.sample_rate());
+        self.samples = samples;
+        self.muted = muted;
+        self.write_frame_counter(0);
+    }
+
+    // Reset silences every channel and writes the last $4017 value again
+    pub fn reset(&mut self) {
+        self.set_enabled(0);
+        self.frame_irq = false;
+        let frame_counter = self.frame_counter;
+        self.write_frame_counter(frame_counter);
+    }
+
+    // $4000-$400F
+    pub fn write(&mut self, addr: u16, val: u8) {
+        self.sync();
+        self.mixed = None;
+        let channel = ((addr & 0x0F) >> 2) as usize;
+        let reg = addr & 3;
+        match channel {
+            0 | 1 => self.pulse[channel].write(reg, val),
+            TRIANGLE => self.triangle.write(reg, val),
+            _ => self.noise.write(reg, val),
+        }
+        match (channel, reg) {
+            (TRIANGLE, 0) => self.length[channel].pending_halt = Some(val & 0x80 != 0),
+            (_, 0) => self.length[channel].pending_halt = Some(val & 0x20 != 0),
+            (_, 3) => self.length[channel].load(val >> 3),
+            _ => {},
+        }
+    }
+
+    // $4015's low four bits. The DMC takes bit 4.
+    pub fn set_enabled(&mut self, val: u8) {
+        self.sync();
+        self.mixed = None;
+        for (i, length) in self.length.iter_mut().enumerate() {
+            length.set_enabled(val & (1 << i) != 0);
+        }
+    }
+
+    // Reading $4015: whether each channel's length counter is running, and the frame IRQ,
+    // which the read acknowledges
+    pub fn read_status(&mut self) -> u8 {
+        let status = self.peek_status();
+        self.frame_irq = false;
+        return status
+    }
+
+    // $4015 without acknowledging anything
+    pub fn peek_status(&self) -> u8 {
+        let mut status = 0;
+        for (i, length) in self.length.iter().enumerate() {
+            if length.count > 0 {
+                status |= 1 << i;
+            }
+        }
+        if self.frame_irq {
+            status |= STATUS_FRAME_IRQ;
+        }
+        return status
+    }
+
+    // Each channel's length counter, in $4015's order, for debugging
+    pub fn length_counts(&self) -> [u8; CHANNELS] {
+        let mut counts = [0; CHANNELS];
+        for (count, length) in counts.iter_mut().zip(self.length.iter()) {
+            *count = length.count;
+        }
+        return counts
+    }
+
+    // $4017
+    pub fn write_frame_counter(&mut self, val: u8) {
+        self.sync();
+        self.frame_counter = val;
+        self.five_step = val & FIVE_STEP != 0;
+        self.irq_inhibit = val & IRQ_INHIBIT != 0;
+        if self.irq_inhibit {
+            self.frame_irq = false;
+        }
+        self.restart_delay = if self.cycles % 2 == 1 { 4 } else { 3 };
+    }
+
+    pub fn irq(&self) -> bool {
+        self.frame_irq
+    }
+
+    // Run the channels for cycles CPU cycles, with the DMC alongside them so the mixer sees
+    // its level on every cycle.
+    //
+    // Most cycles only count timers down. Once quiet_cycles has worked out how many of those
+    // are coming, they're only counted off, and the channels are brought up to date in one go
+    // when they run out or a register write needs them to be.
+    pub fn clock(&mut self, cycles: u32, dmc: &mut Dmc) {
+        let mut remaining = cycles as u64;
+        while remaining > 0 {
+            if self.idle == 0 {
+                self.flush();
+                self.idle = self.quiet_cycles(dmc);
+                if self.idle == 0 {
+                    self.step(dmc);
+                    remaining -= 1;
+                    continue;
+                }
+            }
+            let quiet = self.idle.min(remaining);
+            dmc.skip(quiet as u16);
+            self.deferred += quiet;
+            self.idle -= quiet;
+            remaining -= quiet;
+        }
+    }
+
+    // Bring the channels up to date and forget how quiet the cycles ahead are, before anything
+    // changes them. The DMC's registers count: its level goes into the mix.
+    pub fn sync(&mut self) {
+        self.flush();
+        self.idle = 0;
+    }
+
+    fn flush(&mut self) {
+        if self.deferred > 0 {
+            let cycles = std::mem::replace(&mut self.deferred, 0);
+            self.skip(cycles);
+        }
+    }
+
+    // How many cycles from now will pass with nothing happening but timers counting down,
+    // and the same level going to the resampler: no frame counter step or pending write, no
+    // timer running out on a channel that can be heard, and no sample finished
+    fn quiet_cycles(&self, dmc: &Dmc) -> u64 {
+        if self.mixed.is_none() || self.restart_delay > 0 || self.length.iter().any(LengthCounter::is_pending) {
+            return 0
+        }
+        let last = self.last_step();
+        if self.frame_cycle + 2 >= last {
+            return 0
+        }
+        let mut quiet = [self.steps[0], self.steps[1], self.steps[2], last - 1].iter()
+            .filter(|&&step| step > self.frame_cycle)
+            .map(|&step| (step - self.frame_cycle - 1) as u64)
+            .min().unwrap_or(0);
+        // The pulses' timers are clocked on odd cycles
+        let first_clock = if self.cycles % 2 == 1 { 0 } else { 1 };
+        for (pulse, length) in self.pulse.iter().zip(self.length.iter()) {
+            if pulse.audible(length) {
+                quiet = quiet.min(first_clock + 2 * pulse.timer as u64);
+            }
+        }
+        if self.triangle.running(&self.length[TRIANGLE]) {
+            quiet = quiet.min(self.triangle.timer as u64);
+        }
+        if self.noise.audible(&self.length[NOISE]) {
+            quiet = quiet.min(self.noise.timer as u64);
+        }
+        quiet.min(dmc.quiet_cycles() as u64).min(self.resampler.quiet())
+    }
+
+    // Run through cycles that quiet_cycles has said are quiet. The DMC's timer has already
+    // been counted down.
+    fn skip(&mut self, cycles: u64) {
+        let pulse_clocks = (self.cycles + cycles) / 2 - self.cycles / 2;
+        self.pulse[0].skip(pulse_clocks);
+        self.pulse[1].skip(pulse_clocks);
+        self.triangle.skip(cycles, &self.length[TRIANGLE]);
+        self.noise.skip(cycles);
+        self.frame_cycle += cycles as u32;
+        self.cycles += cycles;
+        if let Some((level, _)) = self.mixed {
+            self.resampler.run(level as i32, cycles);
+        }
+    }
+
+    fn step(&mut self, dmc: &mut Dmc) {
+        let (quarter_frame, half_frame) = self.step_frame_counter();
+        let mut changed = quarter_frame;
+        if quarter_frame {
+            self.pulse[0].envelope.quarter_frame(self.length[0].halt);
+            self.pulse[1].envelope.quarter_frame(self.length[1].halt);
+            self.noise.envelope.quarter_frame(self.length[NOISE].halt);
+            self.triangle.clock_linear(self.length[TRIANGLE].halt);
+        }
+        if half_frame {
+            self.pulse[0].clock_sweep();
+            self.pulse[1].clock_sweep();
+        }
+        if half_frame || self.length.iter().any(LengthCounter::is_pending) {
+            for length in self.length.iter_mut() {
+                length.step(half_frame);
+            }
+            changed = true;
+        }
+
+        if self.cycles % 2 == 1 {
+            changed |= self.pulse[0].clock_timer();
+            changed |= self.pulse[1].clock_timer();
+        }
+        changed |= self.triangle.clock_timer(&self.length[TRIANGLE]);
+        changed |= self.noise.clock_timer();
+        dmc.tick();
+        self.cycles += 1;
+
+        let level = match self.mixed {
+            Some((level, dmc_level)) if !changed && dmc_level == dmc.output() => level,
+            _ => {
+                let level = self.mix(dmc.output());
+                self.mixed = Some((level, dmc.output()));
+                self.dmc_level = dmc.output();
+                level
+            },
+        };
+        self.resampler.run(level as i32, 1);
+        self.emit_ready();
+    }
+
+    // What the cartridge's sound is putting out, from Mapper::audio_output. It's summed in
+    // after the APU's own channels, and held until the next call.
+    pub fn set_expansion_audio(&mut self, level: u16) {
+        if level != self.expansion {
+            self.sync();
+            self.expansion = level;
+            self.mixed = None;
+        }
+    }
+
+    // Mute or unmute a channel in the mix. The channel keeps running underneath, so its length
+    // counter, $4015 and IRQs carry on as the game expects.
+    pub fn set_channel_enabled(&mut self, channel: Channel, enabled: bool) {
+        self.sync();
+        let bit = 1 << channel as u8;
+        if enabled {
+            self.muted &= !bit;
+        } else {
+            self.muted |= bit;
+        }
+        self.mixed = None;
+    }
+
+    pub fn channel_enabled(&self, channel: Channel) -> bool {
+        self.muted & (1 << channel as u8) == 0
+    }
+
+    // Each channel's level going into the mixer, from 0 to 1, in Channel::ALL's order. Muted
+    // channels still show theirs.
+    pub fn channel_outputs(&self) -> [f32; 5] {
+        let levels = self.levels(self.dmc_level);
+        let mut outputs = [0.0; 5];
+        for (i, output) in outputs.iter_mut().enumerate() {
+            let max = if i == Channel::Dmc as usize { 127.0 } else { 15.0 };
+            *output = levels[i] as f32 / max;
+        }
+        return outputs
+    }
+
+    // Each channel's output, before muting
+    fn levels(&self, dmc: u8) -> [u8; 5] {
+        [self.pulse[0].output(&self.length[0]), self.pulse[1].output(&self.length[1]), self.triangle.output(),
+         self.noise.output(&self.length[NOISE]), dmc]
+    }
+
+    fn mix(&self, dmc: u8) -> u16 {
+        let mut levels = self.levels(dmc);
+        for (i, level) in levels.iter_mut().enumerate() {
+            if self.muted & (1 << i) != 0 {
+                *level = 0;
+            }
+        }
+        let pulses = levels[0] + levels[1];
+        let tnd = 3 * levels[2] as usize + 2 * levels[3] as usize + levels[4] as usize;
+        (PULSE_TABLE[pulses as usize] + TND_TABLE[tnd]).saturating_add(self.expansion)
+    }
+
+    fn emit_ready(&mut self) {
+        while let Some(level) = self.resampler.next_output() {
+            let sample = self.dc_blocker.filter(level);
+            self.emit(sample);
+        }
+    }
+
+    // Samples pile up until they're taken, but a frontend that never takes them only ever
+    // holds the last second or so
+    fn emit(&mut self, sample: i16) {
+        for byte in sample.to_le_bytes() {
+            self.hash = (self.hash ^ byte as u64).wrapping_mul(FNV_PRIME);
+        }
+        if self.samples.len() >= self.sample_rate() as usize {
+            let half = self.samples.len() / 2;
+            self.samples.drain(..half);
+        }
+        self.samples.push(sample);
+    }
+
+    // The samples made since the last call, oldest first
+    pub fn take_samples(&mut self) -> Vec<i16> {
+        std::mem::take(&mut self.samples)
+    }
+
+    // The samples made since they were last taken or cleared. Reading these and then clearing
+    // keeps the buffer's capacity, so a frontend doing it every frame never allocates.
+    pub fn samples(&self) -> &[i16] {
+        &self.samples
+    }
+
+    pub fn clear_samples(&mut self) {
+        self.samples.clear();
+    }
+
+    // Called as each PPU frame ends, to close off that frame's hash
+    pub fn end_frame(&mut self) {
+        self.frame_hash = self.hash;
+        self.hash = FNV_OFFSET;
+    }
+
+    // FNV-1a over the samples of the last complete frame, as little-endian i16s. The audio's
+    // counterpart to PPU::frame_hash.
+    pub fn output_hash_for_frame(&self) -> u64 {
+        self.frame_hash
+    }
+
+    // The sequence ends on the fourth step in four-step mode and the fifth in five-step mode,
+    // and starts over on the cycle after
+    fn last_step(&self) -> u32 {
+        if self.five_step { self.steps[4] } else { self.steps[3] }
+    }
+
+    // Move the sequence on a cycle. Returns whether it was a quarter frame, when the envelopes
+    // and the triangle's linear counter are clocked, and whether it was a half frame, when the
+    // length counters and sweeps are as well.
+    fn step_frame_counter(&mut self) -> (bool, bool) {
+        if self.restart_delay > 0 {
+            self.restart_delay -= 1;
+            if self.restart_delay == 0 {
+                self.frame_cycle = 0;
+                // Five-step mode clocks everything as soon as it starts
+                return (self.five_step, self.five_step)
+            }
+        }
+        self.frame_cycle += 1;
+        let cycle = self.frame_cycle;
+        let last = self.last_step();
+        // In four-step mode the IRQ flag is set on the cycles either side of the last step too
+        if !self.five_step && !self.irq_inhibit && cycle + 1 >= last {
+            self.frame_irq = true;
+        }
+        if cycle > last {
+            self.frame_cycle = 0;
+        }
+        let half = cycle == self.steps[1] || cycle == last;
+        let quarter = half || cycle == self.steps[0] || cycle == self.steps[2];
+        return (quarter, half)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use region::{NTSC_TIMING, PAL_TIMING};
+
+    // Pulse 1 at full constant volume with its length counter loaded. Two cycles in, the
+    // sequencer has moved onto the 50% duty's first high step and stays there for 508 more.
+    fn playing_pulse() -> (Apu, Dmc) {
+        let mut apu = Apu::new(&NTSC_TIMING);
+        let mut dmc = Dmc::new(&NTSC_TIMING);
+        apu.set_enabled(0x01);
+        apu.write(0x4000, 0xBF);
+        apu.write(0x4002, 0xFD);
+        apu.write(0x4003, 0x08);
+        apu.clock(2, &mut dmc);
+        (apu, dmc)
+    }
+
+    #[test]
+    fn muted_pulse_is_silent_but_still_running() {
+        // The triangle holds its first step's level while stopped, so silence isn't 0
+        let silent = Apu::new(&NTSC_TIMING).mix(0);
+        let (mut apu, mut dmc) = playing_pulse();
+        assert!(apu.mix(0) > silent);
+
+        apu.set_channel_enabled(Channel::Pulse1, false);
+        apu.clock(10, &mut dmc);
+        assert!(!apu.channel_enabled(Channel::Pulse1));
+        assert_eq!(apu.mix(0), silent);
+        assert_eq!(apu.peek_status() & 0x01, 0x01);
+        assert!(apu.length_counts()[0] > 0);
+        assert_eq!(apu.channel_outputs()[Channel::Pulse1 as usize], 1.0);
+
+        apu.set_channel_enabled(Channel::Pulse1, true);
+        assert!(apu.mix(0) > silent);
+    }
+
+    #[test]
+    fn noise_periods_follow_the_region() {
+        let mut apu = Apu::new(&PAL_TIMING);
+        apu.write(0x400E, 0x05);
+        assert_eq!(apu.noise.period, 88);
+        apu.write(0x400E, 0x0F);
+        assert_eq!(apu.noise.period, 3778);
+        // Switching keeps the index $400E chose
+        apu.set_timing(&NTSC_TIMING);
+        assert_eq!(apu.noise.period, 4068);
+        apu.write(0x400E, 0x05);
+        assert_eq!(apu.noise.period, 96);
+        apu.set_timing(&PAL_TIMING);
+        assert_eq!(apu.noise.period, 88);
+    }
+
+    #[test]
+    fn mutes_survive_power_on() {
+        let (mut apu, _) = playing_pulse();
+        apu.set_channel_enabled(Channel::Noise, false);
+        apu.power_on();
+        assert!(!apu.channel_enabled(Channel::Noise));
+        assert!(apu.channel_enabled(Channel::Pulse1));
+    }
+
+    #[test]
+    fn channel_names_parse() {
+        for &channel in Channel::ALL.iter() {
+            assert_eq!(channel.name().parse::<Channel>(), Ok(channel));
+        }
+        assert_eq!("DMC".parse::<Channel>(), Ok(Channel::Dmc));
+        assert!("square".parse::<Channel>().is_err());
+    }
+
+    // Each channel's length register, in $4015's order
+    const LENGTH_REGISTERS: [u16; 4] = [0x4003, 0x4007, 0x400B, 0x400F];
+
+    fn powered_on() -> (Apu, Dmc) {
+        let mut apu = Apu::new(&NTSC_TIMING);
+        apu.power_on();
+        (apu, Dmc::new(&NTSC_TIMING))
+    }
+
+    // The cycle after power-on of the first half-frame clock, found by watching a counter
+    fn first_half_frame() -> u32 {
+        let (mut apu, mut dmc) = powered_on();
+        apu.set_enabled(0x01);
+        apu.write(0x4003, 0x08);
+        apu.clock(1, &mut dmc);
+        let mut cycles = 1;
+        while apu.length_counts()[0] == 254 {
+            apu.clock(1, &mut dmc);
+            cycles += 1;
+        }
+        cycles
+    }
+
+    #[test]
+    fn enabling_a_channel_does_not_reload_its_length() {
+        for (channel, &register) in LENGTH_REGISTERS.iter().enumerate() {
+            let bit = 1 << channel;
+            let (mut apu, mut dmc) = powered_on();
+            // Written while disabled, the length is ignored, and enabling doesn't load it
+            apu.write(register, 0x08);
+            apu.set_enabled(0x0F);
+            apu.clock(1, &mut dmc);
+            assert_eq!(apu.read_status() & bit, 0, "channel {}", channel);
+            apu.write(register, 0x08);
+            apu.clock(1, &mut dmc);
+            assert_eq!(apu.read_status() & bit, bit, "channel {}", channel);
+
+            // Disabling clears it at once, and enabling again doesn't bring it back
+            apu.set_enabled(0x0F & !bit);
+            assert_eq!(apu.read_status() & bit, 0, "channel {}", channel);
+            apu.set_enabled(0x0F);
+            apu.clock(1, &mut dmc);
+            assert_eq!(apu.read_status() & bit, 0, "channel {}", channel);
+        }
+    }
+
+    #[test]
+    fn length_writes_on_a_half_frame_clock() {
+        let half_frame = first_half_frame();
+        // Pulse 1 with its counter at count, then on cycle at a write of val to reg
+        let run = |count_index: Option<u8>, at: u32, reg: u16, val: u8| {
+            let (mut apu, mut dmc) = powered_on();
+            apu.set_enabled(0x01);
+            if let Some(index) = count_index {
+                apu.write(0x4003, index << 3);
+            }
+            apu.clock(at - 1, &mut dmc);
+            apu.write(reg, val);
+            apu.clock(1, &mut dmc);
+            apu.length_counts()[0]
+        };
+        // A reload on the clock is lost while the counter is running, and lands if it was 0
+        // or a cycle later
+        assert_eq!(run(Some(3), half_frame, 0x4003, 0x08), 1);
+        assert_eq!(run(None, half_frame, 0x4003, 0x08), 254);
+        assert_eq!(run(Some(3), half_frame + 1, 0x4003, 0x08), 254);
+        // A halt written on the clock is too late to stop it, and one before isn't
+        assert_eq!(run(Some(3), half_frame, 0x4000, 0x20), 1);
+        assert_eq!(run(Some(3), half_frame - 1, 0x4000, 0x20), 2);
+    }
+
+    #[test]
+    fn frame_irq_sets_after_power_on_until_read() {
+        let (mut apu, mut dmc) = powered_on();
+        apu.clock(29830, &mut dmc);
+        assert!(!apu.irq());
+        apu.clock(1, &mut dmc);
+        assert!(apu.irq());
+        assert_eq!(apu.read_status() & STATUS_FRAME_IRQ, STATUS_FRAME_IRQ);
+        assert!(!apu.irq());
+        assert_eq!(apu.peek_status() & STATUS_FRAME_IRQ, 0);
+
+        // Setting the inhibit bit acknowledges it too, and keeps it off
+        apu.clock(29830, &mut dmc);
+        assert!(apu.irq());
+        apu.write_frame_counter(IRQ_INHIBIT);
+        assert!(!apu.irq());
+        apu.clock(2 * 29830, &mut dmc);
+        assert!(!apu.irq());
+    }
+
+    #[test]
+    fn dmc_restarts_only_once_its_sample_is_done() {
+        let mut dmc = Dmc::new(&NTSC_TIMING);
+        // Two bytes
+        dmc.write(0x4013, 0x00);
+        dmc.write(0x4013, 0x01);
+        dmc.set_enabled(true);
+        assert_eq!(dmc.status(), ::dmc::STATUS_ACTIVE);
+        let start = dmc.fetch_address();
+        dmc.fill(0);
+        // Enabling again mid-sample carries on where it was
+        dmc.set_enabled(true);
+        assert_eq!(dmc.fetch_address(), start + 1);
+        // Disabling drops what's left
+        dmc.set_enabled(false);
+        assert_eq!(dmc.status(), 0);
+        dmc.set_enabled(true);
+        assert_eq!((dmc.status(), dmc.fetch_address()), (::dmc::STATUS_ACTIVE, start));
+    }
+
+    // Every channel playing in five-step mode, as tests/roms/apu_channels.s has them
+    fn every_channel(apu: &mut Apu) {
+        apu.write_frame_counter(0x80);
+        apu.set_enabled(0x0F);
+        for &(addr, val) in [(0x4000, 0xBF), (0x4002, 0xFD), (0x4003, 0x08), (0x4004, 0x44), (0x4005, 0x8A),
+                             (0x4006, 0x80), (0x4007, 0x09), (0x4008, 0x60), (0x400A, 0x40), (0x400B, 0x08),
+                             (0x400C, 0x28), (0x400E, 0x06), (0x400F, 0x08)].iter() {
+            apu.write(addr, val);
+        }
+    }
+
+    #[test]
+    fn skipping_quiet_cycles_sounds_the_same_as_stepping() {
+        let run = |skipping: bool| {
+            let (mut apu, mut dmc) = powered_on();
+            every_channel(&mut apu);
+            for frame in 0..20u32 {
+                if skipping {
+                    apu.clock(29780, &mut dmc);
+                } else {
+                    for _ in 0..29780 {
+                        apu.step(&mut dmc);
+                    }
+                }
+                apu.write(0x4002, 0xFF - frame as u8);
+                apu.end_frame();
+            }
+            (apu.take_samples(), apu.output_hash_for_frame())
+        };
+        let (samples, hash) = run(true);
+        assert!(samples.iter().any(|&sample| sample != samples[0]));
+        assert_eq!((samples, hash), run(false));
+    }
+
+    #[test]
+    fn pulse_period_253_is_440_hz() {
+        let (mut apu, mut dmc) = powered_on();
+        apu.set_enabled(0x01);
+        apu.write(0x4000, 0xBF);
+        apu.write(0x4002, 0xFD);
+        apu.write(0x4003, 0x08);
+        apu.clock(NTSC_TIMING.cpu_clock_hz, &mut dmc);
+        let samples = apu.take_samples();
+        assert_eq!(samples.len(), DEFAULT_SAMPLE_RATE as usize);
+        // Rising edges, after the high-pass has settled from the step at power-on
+        let rising: Vec<usize> = samples.windows(2).enumerate().skip(1000)
+            .filter(|&(_, pair)| pair[0] < 0 && pair[1] >= 0).map(|(i, _)| i).collect();
+        let hz = (rising.len() - 1) as f64 * DEFAULT_SAMPLE_RATE as f64 / (rising[rising.len() - 1] - rising[0]) as f64;
+        assert!((hz - 440.4).abs() < 0.5, "{} Hz", hz);
+    }
+}
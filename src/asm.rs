@@ -0,0 +1,488 @@
+// A small two-pass 6502 assembler, for writing test programs without hand-encoding them.
+//
+// One statement per line, with ; starting a comment:
+//   loop:              a label, which can also share a line with a statement
+//   LDA #$10           numbers are decimal, $hex, 0xhex or %binary
+//   STA table,X        any addressing mode the disassembler prints
+//   BNE loop           branch and jump targets can be labels defined further down
+//   .byte 1, $02       raw bytes
+//   .word loop, $1234  little-endian words
+// Operands can add and subtract terms, and <expr and >expr take the low and high byte.
+// Labels and numbers that fit in a byte pick zero page modes unless written with more than two
+// hex digits, as the disassembler does for absolute addresses. Labels that aren't defined yet
+// always get absolute modes.
+
+use opcodes::{encode, AddressingMode, Mnemonic, OPCODE_TABLE};
+
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsmErrorKind {
+    UnknownMnemonic(String),
+    UnknownDirective(String),
+    BadNumber(String),
+    BadOperand(String),
+    BadLabel(String),
+    DuplicateLabel(String),
+    UndefinedLabel(String),
+    NoSuchMode { mnemonic: Mnemonic, mode: AddressingMode },
+    BranchOutOfRange { target: u16, offset: i32 },
+    DoesNotFit { value: i32, bytes: u8 },
+    PastEndOfMemory,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    // Counting from 1
+    pub line: usize,
+    pub kind: AsmErrorKind,
+}
+
+impl fmt::Display for AsmErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AsmErrorKind::UnknownMnemonic(ref m) => write!(f, "unknown mnemonic {}", m),
+            AsmErrorKind::UnknownDirective(ref d) => write!(f, "unknown directive {}", d),
+            AsmErrorKind::BadNumber(ref n) => write!(f, "bad number {}", n),
+            AsmErrorKind::BadOperand(ref o) => write!(f, "can't parse operand {}", o),
+            AsmErrorKind::BadLabel(ref l) => write!(f, "{} can't be used as a label", l),
+            AsmErrorKind::DuplicateLabel(ref l) => write!(f, "label {} is already defined", l),
+            AsmErrorKind::UndefinedLabel(ref l) => write!(f, "label {} is never defined", l),
+            AsmErrorKind::NoSuchMode { mnemonic, mode } =>
+                write!(f, "{} has no {:?} addressing mode", mnemonic, mode),
+            AsmErrorKind::BranchOutOfRange { target, offset } =>
+                write!(f, "branch to ${:04X} is {} bytes away, more than a branch can reach", target, offset),
+            AsmErrorKind::DoesNotFit { value, bytes } =>
+                write!(f, "{} doesn't fit in {} byte(s)", value, bytes),
+            AsmErrorKind::PastEndOfMemory => write!(f, "program runs past $FFFF"),
+        }
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.kind)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Select {
+    Whole,
+    Low,
+    High,
+}
+
+#[derive(Clone)]
+enum Term {
+    Number(i32),
+    Label(String),
+}
+
+#[derive(Clone)]
+struct Expr {
+    select: Select,
+    // Each term with the sign it is added with
+    terms: Vec<(i32, Term)>,
+    // Written as a hex number with more than two digits, so wants an absolute mode
+    wide: bool,
+}
+
+impl Expr {
+    // None while a label in it is still undefined
+    fn value(&self, labels: &HashMap<String, u16>) -> Option<i32> {
+        let mut total = 0i32;
+        for &(sign, ref term) in self.terms.iter() {
+            let val = match *term {
+                Term::Number(n) => n,
+                Term::Label(ref name) => *labels.get(name)? as i32,
+            };
+            total = total.wrapping_add(sign * val);
+        }
+        return Some(match self.select {
+            Select::Whole => total,
+            Select::Low => total & 0xFF,
+            Select::High => (total >> 8) & 0xFF,
+        })
+    }
+
+    fn first_undefined(&self, labels: &HashMap<String, u16>) -> Option<String> {
+        self.terms.iter().filter_map(|(_, term)| match *term {
+            Term::Label(ref name) if !labels.contains_key(name) => Some(name.clone()),
+            _ => None,
+        }).next()
+    }
+
+    // Whether it can be given to a zero page mode, as far as is known so far
+    fn is_byte(&self, labels: &HashMap<String, u16>) -> bool {
+        !self.wide && self.value(labels).is_some_and(|v| (0..=0xFF).contains(&v))
+    }
+}
+
+enum Operand {
+    None,
+    Accumulator,
+    Immediate(Expr),
+    Plain(Expr),
+    IndexedX(Expr),
+    IndexedY(Expr),
+    Indirect(Expr),
+    IndirectX(Expr),
+    IndirectY(Expr),
+}
+
+enum Statement {
+    Instruction { opcode: u8, mode: AddressingMode, operand: Option<Expr> },
+    Bytes(Vec<Expr>),
+    Words(Vec<Expr>),
+}
+
+impl Statement {
+    fn len(&self) -> u32 {
+        match *self {
+            Statement::Instruction { opcode, .. } => OPCODE_TABLE[opcode as usize].len as u32,
+            Statement::Bytes(ref exprs) => exprs.len() as u32,
+            Statement::Words(ref exprs) => exprs.len() as u32 * 2,
+        }
+    }
+}
+
+fn is_ident(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => chars.all(|c| c.is_ascii_alphanumeric() || c == '_'),
+        _ => false,
+    }
+}
+
+fn is_register(s: &str) -> bool {
+    s.eq_ignore_ascii_case("a") || s.eq_ignore_ascii_case("x") || s.eq_ignore_ascii_case("y")
+}
+
+// A number and whether it was written as hex wider than a byte
+fn parse_number(s: &str) -> Result<(i32, bool), AsmErrorKind> {
+    let bad = || AsmErrorKind::BadNumber(s.to_string());
+    let (digits, radix) = if let Some(hex) = s.strip_prefix('$') {
+        (hex, 16)
+    } else if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        (hex, 16)
+    } else if let Some(bin) = s.strip_prefix('%') {
+        (bin, 2)
+    } else {
+        (s, 10)
+    };
+    if digits.is_empty() {
+        return Err(bad());
+    }
+    let val = i32::from_str_radix(digits, radix).map_err(|_| bad())?;
+    return Ok((val, radix == 16 && digits.len() > 2))
+}
+
+fn parse_expr(s: &str) -> Result<Expr, AsmErrorKind> {
+    let (select, rest) = match s.chars().next() {
+        Some('<') => (Select::Low, &s[1..]),
+        Some('>') => (Select::High, &s[1..]),
+        _ => (Select::Whole, s),
+    };
+
+    let mut expr = Expr { select: select, terms: Vec::new(), wide: false };
+    let mut sign = 1;
+    let mut start = 0;
+    // Split on + and -, allowing a sign in front of the first term
+    for (i, c) in rest.char_indices().chain(Some((rest.len(), '+'))) {
+        if c != '+' && c != '-' {
+            continue;
+        }
+        let term = &rest[start..i];
+        if term.is_empty() {
+            if i != 0 || i == rest.len() {
+                return Err(AsmErrorKind::BadOperand(s.to_string()));
+            }
+        } else if is_ident(term) {
+            expr.terms.push((sign, Term::Label(term.to_string())));
+        } else {
+            let (val, wide) = parse_number(term)?;
+            expr.wide |= wide;
+            expr.terms.push((sign, Term::Number(val)));
+        }
+        sign = if c == '-' { -1 } else { 1 };
+        start = i + 1;
+    }
+    return Ok(expr)
+}
+
+fn parse_operand(text: &str) -> Result<Operand, AsmErrorKind> {
+    let s: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+    let upper = s.to_ascii_uppercase();
+    let bad = || AsmErrorKind::BadOperand(text.to_string());
+
+    if s.is_empty() {
+        return Ok(Operand::None);
+    }
+    if upper == "A" {
+        return Ok(Operand::Accumulator);
+    }
+    if let Some(value) = s.strip_prefix('#') {
+        return Ok(Operand::Immediate(parse_expr(value)?));
+    }
+    if s.starts_with('(') {
+        let inner = |end: usize| parse_expr(&s[1..s.len() - end]);
+        return if upper.ends_with("),Y") {
+            Ok(Operand::IndirectY(inner(3)?))
+        } else if upper.ends_with(",X)") {
+            Ok(Operand::IndirectX(inner(3)?))
+        } else if upper.ends_with(')') {
+            Ok(Operand::Indirect(inner(1)?))
+        } else {
+            Err(bad())
+        };
+    }
+    if upper.ends_with(",X") {
+        return Ok(Operand::IndexedX(parse_expr(&s[..s.len() - 2])?));
+    }
+    if upper.ends_with(",Y") {
+        return Ok(Operand::IndexedY(parse_expr(&s[..s.len() - 2])?));
+    }
+    return Ok(Operand::Plain(parse_expr(&s)?))
+}
+
+fn parse_mnemonic(word: &str) -> Option<Mnemonic> {
+    OPCODE_TABLE.iter()
+        .map(|op| op.mnemonic)
+        .find(|m| *m != Mnemonic::Illegal && m.to_string().eq_ignore_ascii_case(word))
+}
+
+// Pick the addressing mode and opcode, preferring zero page when the operand is known to fit
+fn instruction(mnemonic: Mnemonic, operand: Operand, labels: &HashMap<String, u16>)
+               -> Result<Statement, AsmErrorKind> {
+    let has = |mode| encode(mnemonic, mode).is_some();
+    let sized = |expr: &Expr, zp, abs| if expr.is_byte(labels) && has(zp) { zp } else { abs };
+
+    let (mode, expr) = match operand {
+        Operand::None if has(AddressingMode::Accumulator) => (AddressingMode::Accumulator, None),
+        Operand::None => (AddressingMode::Implied, None),
+        Operand::Accumulator => (AddressingMode::Accumulator, None),
+        Operand::Immediate(e) => (AddressingMode::Immediate, Some(e)),
+        Operand::Plain(e) => {
+            let mode = if has(AddressingMode::Relative) {
+                AddressingMode::Relative
+            } else {
+                sized(&e, AddressingMode::ZeroPage, AddressingMode::Absolute)
+            };
+            (mode, Some(e))
+        },
+        Operand::IndexedX(e) => (sized(&e, AddressingMode::ZeroPageX, AddressingMode::AbsoluteX), Some(e)),
+        Operand::IndexedY(e) => (sized(&e, AddressingMode::ZeroPageY, AddressingMode::AbsoluteY), Some(e)),
+        Operand::Indirect(e) => (AddressingMode::Indirect, Some(e)),
+        Operand::IndirectX(e) => (AddressingMode::IndirectX, Some(e)),
+        Operand::IndirectY(e) => (AddressingMode::IndirectY, Some(e)),
+    };
+    let opcode = encode(mnemonic, mode).ok_or(AsmErrorKind::NoSuchMode { mnemonic: mnemonic, mode: mode })?;
+    Ok(Statement::Instruction { opcode: opcode, mode: mode, operand: expr })
+}
+
+fn parse_list(text: &str) -> Result<Vec<Expr>, AsmErrorKind> {
+    text.split(',')
+        .map(|item| parse_expr(&item.chars().filter(|c| !c.is_whitespace()).collect::<String>()))
+        .collect()
+}
+
+fn statement(text: &str, labels: &HashMap<String, u16>) -> Result<Statement, AsmErrorKind> {
+    let (word, rest) = match text.find(char::is_whitespace) {
+        Some(i) => (&text[..i], text[i..].trim()),
+        None => (text, ""),
+    };
+    if word.starts_with('.') {
+        return match word.to_ascii_lowercase().as_str() {
+            ".byte" | ".db" => Ok(Statement::Bytes(parse_list(rest)?)),
+            ".word" | ".dw" => Ok(Statement::Words(parse_list(rest)?)),
+            _ => Err(AsmErrorKind::UnknownDirective(word.to_string())),
+        };
+    }
+    let mnemonic = parse_mnemonic(word).ok_or_else(|| AsmErrorKind::UnknownMnemonic(word.to_string()))?;
+    instruction(mnemonic, parse_operand(rest)?, labels)
+}
+
+fn fit(value: i32, bytes: u8) -> Result<u16, AsmErrorKind> {
+    let (min, max) = if bytes == 1 { (-0x80, 0xFF) } else { (-0x8000, 0xFFFF) };
+    if value < min || value > max {
+        return Err(AsmErrorKind::DoesNotFit { value: value, bytes: bytes });
+    }
+    Ok(value as u16)
+}
+
+fn evaluate(expr: &Expr, labels: &HashMap<String, u16>) -> Result<i32, AsmErrorKind> {
+    expr.value(labels).ok_or_else(|| AsmErrorKind::UndefinedLabel(expr.first_undefined(labels).unwrap_or_default()))
+}
+
+fn emit(stmt: &Statement, pc: u16, labels: &HashMap<String, u16>, out: &mut Vec<u8>) -> Result<(), AsmErrorKind> {
+    match *stmt {
+        Statement::Instruction { opcode, mode, ref operand } => {
+            out.push(opcode);
+            let expr = match *operand {
+                Some(ref expr) => expr,
+                None => return Ok(()),
+            };
+            let value = evaluate(expr, labels)?;
+            match mode {
+                AddressingMode::Relative => {
+                    let target = fit(value, 2)?;
+                    let offset = target as i32 - (pc as i32 + 2);
+                    if !(-128..=127).contains(&offset) {
+                        return Err(AsmErrorKind::BranchOutOfRange { target: target, offset: offset });
+                    }
+                    out.push(offset as u8);
+                },
+                _ if mode.operand_len() == 1 => out.push(fit(value, 1)? as u8),
+                _ => {
+                    let word = fit(value, 2)?;
+                    out.push(word as u8);
+                    out.push((word >> 8) as u8);
+                },
+            }
+        },
+        Statement::Bytes(ref exprs) => {
+            for expr in exprs.iter() {
+                out.push(fit(evaluate(expr, labels)?, 1)? as u8);
+            }
+        },
+        Statement::Words(ref exprs) => {
+            for expr in exprs.iter() {
+                let word = fit(evaluate(expr, labels)?, 2)?;
+                out.push(word as u8);
+                out.push((word >> 8) as u8);
+            }
+        },
+    }
+    Ok(())
+}
+
+// Assemble source into machine code that will run from origin
+pub fn assemble(source: &str, origin: u16) -> Result<Vec<u8>, AsmError> {
+    assemble_with_labels(source, origin).map(|(code, _)| code)
+}
+
+// Assemble source, also giving back where each label ended up
+pub fn assemble_with_labels(source: &str, origin: u16) -> Result<(Vec<u8>, HashMap<String, u16>), AsmError> {
+    let mut labels: HashMap<String, u16> = HashMap::new();
+    let mut statements: Vec<(usize, u16, Statement)> = Vec::new();
+    let mut pc = origin as u32;
+
+    // First pass: define labels and fix the size of every statement
+    for (i, line) in source.lines().enumerate() {
+        let line_no = i + 1;
+        let err = |kind| AsmError { line: line_no, kind: kind };
+        let mut text = line.split(';').next().unwrap_or("").trim();
+
+        while let Some(colon) = text.find(':') {
+            let label = text[..colon].trim();
+            if !is_ident(label) || is_register(label) || parse_mnemonic(label).is_some() {
+                return Err(err(AsmErrorKind::BadLabel(label.to_string())));
+            }
+            if pc > 0xFFFF {
+                return Err(err(AsmErrorKind::PastEndOfMemory));
+            }
+            if labels.insert(label.to_string(), pc as u16).is_some() {
+                return Err(err(AsmErrorKind::DuplicateLabel(label.to_string())));
+            }
+            text = text[colon + 1..].trim();
+        }
+        if text.is_empty() {
+            continue;
+        }
+
+        let stmt = statement(text, &labels).map_err(err)?;
+        if pc + stmt.len() > 0x10000 {
+            return Err(err(AsmErrorKind::PastEndOfMemory));
+        }
+        statements.push((line_no, pc as u16, stmt));
+        pc += statements.last().map_or(0, |s| s.2.len());
+    }
+
+    // Second pass: every label is known, so fill in the operands
+    let mut out = Vec::with_capacity((pc - origin as u32) as usize);
+    for &(line_no, pc, ref stmt) in statements.iter() {
+        emit(stmt, pc, &labels, &mut out).map_err(|kind| AsmError { line: line_no, kind: kind })?;
+    }
+    return Ok((out, labels))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opcodes::disassemble;
+
+    #[test]
+    fn every_opcode_round_trips_through_the_disassembler() {
+        // A few operands for each opcode, including ones that look like zero page addresses and
+        // branches both ways
+        for opcode in 0..=0xFFu8 {
+            for &(lo, hi) in [(0x00, 0x00), (0x12, 0x00), (0x80, 0x7F), (0xFF, 0xFF)].iter() {
+                let bytes = [opcode, lo, hi];
+                let (text, len) = disassemble(&bytes, 0xC000);
+                let assembled = assemble(&text, 0xC000).unwrap_or_else(|e| panic!("{}: {}", text, e));
+                assert_eq!(assembled, &bytes[..len as usize], "{}", text);
+                assert_eq!(disassemble(&assembled, 0xC000).0, text);
+            }
+        }
+    }
+
+    #[test]
+    fn labels_can_be_used_before_they_are_defined() {
+        let source = "
+start:  LDX #10         ; count down
+loop:   DEX
+        BNE loop
+        BEQ done
+        JMP start
+done:   JSR sub
+sub:    RTS
+table:  .word start, done
+";
+        let (bytes, labels) = assemble_with_labels(source, 0x8000).unwrap();
+        assert_eq!(bytes, [
+            0xA2, 0x0A, 0xCA, 0xD0, 0xFD, 0xF0, 0x03, 0x4C, 0x00, 0x80, 0x20, 0x0D, 0x80, 0x60,
+            0x00, 0x80, 0x0A, 0x80,
+        ]);
+        assert_eq!(labels["done"], 0x800A);
+        assert_eq!(labels["table"], 0x800E);
+    }
+
+    #[test]
+    fn literals_directives_and_byte_selectors() {
+        let source = "
+value:  .byte 10, $0A, 0x0A, %00001010
+        LDA #<value
+        LDA #>value+$100
+        LDA $10
+        LDA $0010
+        LDA value
+        .word $1234
+";
+        assert_eq!(assemble(source, 0x0040).unwrap(), [
+            0x0A, 0x0A, 0x0A, 0x0A, 0xA9, 0x40, 0xA9, 0x01, 0xA5, 0x10, 0xAD, 0x10, 0x00, 0xA5, 0x40,
+            0x34, 0x12,
+        ]);
+    }
+
+    #[test]
+    fn errors_give_the_line() {
+        let check = |source: &str, line: usize, kind: AsmErrorKind| {
+            assert_eq!(assemble(source, 0x8000), Err(AsmError { line: line, kind: kind }), "{}", source);
+        };
+        check("NOP\nFOO", 2, AsmErrorKind::UnknownMnemonic("FOO".to_string()));
+        check("NOP\n\n.fill 3", 3, AsmErrorKind::UnknownDirective(".fill".to_string()));
+        check("LDA #$1G", 1, AsmErrorKind::BadNumber("$1G".to_string()));
+        check("x1: NOP\nx1: NOP", 2, AsmErrorKind::DuplicateLabel("x1".to_string()));
+        check("NOP\na: NOP", 2, AsmErrorKind::BadLabel("a".to_string()));
+        check("NOP\nJMP nowhere", 2, AsmErrorKind::UndefinedLabel("nowhere".to_string()));
+        check("STA #1", 1, AsmErrorKind::NoSuchMode { mnemonic: Mnemonic::STA, mode: AddressingMode::Immediate });
+        check("LDA #256", 1, AsmErrorKind::DoesNotFit { value: 256, bytes: 1 });
+        check("here: BNE here+200", 1, AsmErrorKind::BranchOutOfRange { target: 0x80C8, offset: 198 });
+        assert_eq!(assemble(".byte 1, 2", 0xFFFF).unwrap_err().kind, AsmErrorKind::PastEndOfMemory);
+
+        let err = assemble("NOP\nLDA ($10", 0x8000).unwrap_err();
+        assert_eq!(err.line, 2);
+        assert!(err.to_string().starts_with("line 2: "), "{}", err);
+    }
+}
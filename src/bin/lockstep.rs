@@ -0,0 +1,74 @@
+// Compares two lockstep streams and reports the first instruction where they differ.
+//
+//   lockstep A B [--context N]
+//
+// Each stream is either a binary one from nes --lockstep-out or Nintendulator-style trace text,
+// such as nestest.log or nes --trace output. --context is how many instructions before the
+// divergence to show. Exits with 1 if the streams diverge.
+#![allow(clippy::needless_return, clippy::redundant_field_names)]
+extern crate nes;
+
+use nes::lockstep::{self, Outcome};
+
+use std::env;
+use std::process;
+
+struct Args {
+    streams: Vec<String>,
+    context: usize,
+}
+
+impl Args {
+    fn parse_args() -> Result<Args, &'static str> {
+        let mut streams = Vec::new();
+        let mut context = lockstep::DEFAULT_CONTEXT;
+        let mut argv = env::args().skip(1);
+        while let Some(arg) = argv.next() {
+            match arg.as_str() {
+                "--context" => {
+                    let count = argv.next().ok_or("--context needs an instruction count")?;
+                    context = count.parse().map_err(|_| "--context needs a number")?;
+                }
+                _ => {
+                    streams.push(arg);
+                }
+            }
+        }
+        if streams.len() != 2 {
+            return Err("usage: lockstep A B [--context N]")
+        }
+        Ok(Args { streams: streams, context: context })
+    }
+}
+
+fn open(path: &str) -> lockstep::StreamReader<std::io::BufReader<std::fs::File>> {
+    match lockstep::open(path) {
+        Ok(stream) => stream,
+        Err(e) => {
+            eprintln!("Can't open {}: {}", path, e);
+            process::exit(2);
+        }
+    }
+}
+
+fn main() {
+    let args = match Args::parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        }
+    };
+    println!("a: {}\nb: {}", args.streams[0], args.streams[1]);
+    match lockstep::compare(open(&args.streams[0]), open(&args.streams[1]), args.context) {
+        Ok(Outcome::Match(count)) => println!("Streams match for all {} instructions", count),
+        Ok(Outcome::Diverged(divergence)) => {
+            print!("{}", divergence);
+            process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("Can't compare: {}", e);
+            process::exit(2);
+        }
+    }
+}
@@ -0,0 +1,109 @@
+// Runs every ROM in a regression manifest and prints a pass/fail table.
+//
+//   regression ROM_DIR [--manifest FILE] [--bless]
+//
+// The manifest defaults to ROM_DIR/regression.toml. --bless writes each ROM's current frame and
+// audio hashes back into the manifest instead of failing on them. Exits with 1 if anything
+// failed. Manifests listing .s programs, like tests/roms, need the testing feature.
+#![allow(clippy::needless_return, clippy::redundant_field_names)]
+extern crate nes;
+
+use nes::regression::{self, Manifest, Verdict};
+
+use std::env;
+use std::path::PathBuf;
+use std::process;
+
+struct Args {
+    rom_dir: PathBuf,
+    manifest: Option<PathBuf>,
+    bless: bool,
+}
+
+impl Args {
+    fn parse_args() -> Result<Args, &'static str> {
+        let mut rom_dir = None;
+        let mut manifest = None;
+        let mut bless = false;
+        let mut argv = env::args().skip(1);
+        while let Some(arg) = argv.next() {
+            match arg.as_str() {
+                "--manifest" => {
+                    manifest = Some(PathBuf::from(argv.next().ok_or("--manifest needs a path")?));
+                }
+                "--bless" => {
+                    bless = true;
+                }
+                _ => {
+                    rom_dir = Some(PathBuf::from(arg));
+                }
+            }
+        }
+        Ok(Args {
+            rom_dir: rom_dir.ok_or("usage: regression ROM_DIR [--manifest FILE] [--bless]")?,
+            manifest: manifest,
+            bless: bless,
+        })
+    }
+}
+
+fn main() {
+    let args = match Args::parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        }
+    };
+    let manifest_path = args.manifest.clone().unwrap_or_else(|| args.rom_dir.join("regression.toml"));
+    let mut manifest = match Manifest::load(&manifest_path) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            eprintln!("Can't load {}: {}", manifest_path.display(), e);
+            process::exit(2);
+        }
+    };
+
+    let width = manifest.entries.iter().map(|entry| entry.file.len()).max().unwrap_or(0).max(3);
+    println!("{:<6} {:<width$} {:>6}  DETAIL", "RESULT", "ROM", "FRAME", width = width);
+    let mut failures = 0;
+    let mut blessed = 0;
+    for entry in manifest.entries.iter_mut() {
+        let mut was_blessed = false;
+        let verdict = match regression::run_twice(entry, &args.rom_dir) {
+            // Blessing only takes hashes from runs that agreed with each other
+            Ok(outcome) => {
+                if args.bless && (entry.hash != Some(outcome.hash) || entry.audio_hash != Some(outcome.audio_hash)) {
+                    entry.hash = Some(outcome.hash);
+                    entry.audio_hash = Some(outcome.audio_hash);
+                    was_blessed = true;
+                    blessed += 1;
+                }
+                regression::judge(entry, &outcome)
+            },
+            Err(verdict) => verdict,
+        };
+        let (result, detail) = match verdict {
+            Verdict::Pass if was_blessed => ("BLESS", format!("hash is now {:016x}, audio {:016x}",
+                                                              entry.hash.unwrap(), entry.audio_hash.unwrap())),
+            Verdict::Pass => ("PASS", verdict.to_string()),
+            _ => ("FAIL", verdict.to_string()),
+        };
+        if !verdict.passed() {
+            failures += 1;
+        }
+        println!("{:<6} {:<width$} {:>6}  {}", result, entry.file, entry.frame, detail, width = width);
+    }
+
+    if blessed > 0 {
+        if let Err(e) = manifest.save(&manifest_path) {
+            eprintln!("Can't write {}: {}", manifest_path.display(), e);
+            process::exit(2);
+        }
+        println!("Blessed {} hashes in {}", blessed, manifest_path.display());
+    }
+    println!("{} of {} passed", manifest.entries.len() - failures, manifest.entries.len());
+    if failures > 0 {
+        process::exit(1);
+    }
+}
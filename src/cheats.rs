@@ -0,0 +1,222 @@
+use std::fmt;
+
+// Game Genie letters, in the order of the nibble values they encode
+const GAME_GENIE_LETTERS: &str = "APZLGITYEOXUKSVN";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheatError {
+    BadLength(usize),
+    BadLetter(char),
+    BadFreeze,
+    NotRam(u16),
+}
+
+impl fmt::Display for CheatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CheatError::BadLength(len) => write!(f, "Game Genie codes are 6 or 8 letters, got {}", len),
+            CheatError::BadLetter(c) => write!(f, "'{}' is not a Game Genie letter", c),
+            CheatError::BadFreeze => write!(f, "RAM freezes are written as ADDR:VALUE in hex"),
+            CheatError::NotRam(addr) => write!(f, "{:#06x} is not a RAM address", addr),
+        }
+    }
+}
+
+// A decoded Game Genie code. 8-letter codes only patch the read when the ROM holds `compare`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GameGenieCode {
+    pub addr: u16,
+    pub value: u8,
+    pub compare: Option<u8>,
+}
+
+impl GameGenieCode {
+    pub fn parse(code: &str) -> Result<GameGenieCode, CheatError> {
+        let mut n = [0u16; 8];
+        let len = code.chars().count();
+        if len != 6 && len != 8 {
+            return Err(CheatError::BadLength(len))
+        }
+        for (i, c) in code.chars().enumerate() {
+            let upper = c.to_ascii_uppercase();
+            n[i] = GAME_GENIE_LETTERS.find(upper).ok_or(CheatError::BadLetter(c))? as u16;
+        }
+
+        let addr = 0x8000
+            | ((n[3] & 7) << 12)
+            | ((n[5] & 7) << 8) | ((n[4] & 8) << 8)
+            | ((n[2] & 7) << 4) | ((n[1] & 8) << 4)
+            | (n[4] & 7) | (n[3] & 8);
+        let value = ((n[1] & 7) << 4) | ((n[0] & 8) << 4) | (n[0] & 7);
+
+        if len == 6 {
+            Ok(GameGenieCode {
+                addr: addr,
+                value: (value | (n[5] & 8)) as u8,
+                compare: None,
+            })
+        } else {
+            let compare = ((n[7] & 7) << 4) | ((n[6] & 8) << 4) | (n[6] & 7) | (n[5] & 8);
+            Ok(GameGenieCode {
+                addr: addr,
+                value: (value | (n[7] & 8)) as u8,
+                compare: Some(compare as u8),
+            })
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cheat {
+    // Patches reads from PRG
+    GameGenie(GameGenieCode),
+    // Rewrites a RAM address every frame
+    Freeze { addr: u16, value: u8 },
+}
+
+impl Cheat {
+    // Accepts either a Game Genie code ("SXIOPO") or a RAM freeze ("075A:09")
+    pub fn parse(code: &str) -> Result<Cheat, CheatError> {
+        if let Some(colon) = code.find(':') {
+            let addr = u16::from_str_radix(&code[..colon], 16).map_err(|_| CheatError::BadFreeze)?;
+            let value = u8::from_str_radix(&code[colon + 1..], 16).map_err(|_| CheatError::BadFreeze)?;
+            if addr >= 0x2000 {
+                return Err(CheatError::NotRam(addr))
+            }
+            return Ok(Cheat::Freeze { addr: addr, value: value })
+        }
+        GameGenieCode::parse(code).map(Cheat::GameGenie)
+    }
+}
+
+#[derive(Default)]
+pub struct Cheats {
+    active: Vec<Cheat>,
+}
+
+impl Cheats {
+    pub fn new() -> Cheats {
+        Cheats::default()
+    }
+
+    pub fn add(&mut self, cheat: Cheat) {
+        if !self.active.contains(&cheat) {
+            self.active.push(cheat);
+        }
+    }
+
+    // Returns whether the cheat was active
+    pub fn remove(&mut self, cheat: &Cheat) -> bool {
+        let before = self.active.len();
+        self.active.retain(|c| c != cheat);
+        self.active.len() != before
+    }
+
+    pub fn list(&self) -> &[Cheat] {
+        &self.active
+    }
+
+    // Overlay any Game Genie codes on a byte read from PRG
+    pub fn patch_read(&self, addr: u16, val: u8) -> u8 {
+        for cheat in self.active.iter() {
+            if let Cheat::GameGenie(ref code) = *cheat {
+                if code.addr == addr && code.compare.is_none_or(|compare| compare == val) {
+                    return code.value
+                }
+            }
+        }
+        return val
+    }
+
+    pub fn freezes<'a>(&'a self) -> impl Iterator<Item = (u16, u8)> + 'a {
+        self.active.iter().filter_map(|cheat| match *cheat {
+            Cheat::Freeze { addr, value } => Some((addr, value)),
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use controller::InputFrame;
+    use emulator::Nes;
+    use testing;
+
+    #[test]
+    fn decodes_published_codes() {
+        // Super Mario Bros. infinite lives, and the examples from the nesdev wiki
+        assert_eq!(GameGenieCode::parse("SXIOPO"), Ok(GameGenieCode { addr: 0x91D9, value: 0xAD, compare: None }));
+        assert_eq!(GameGenieCode::parse("GOSSIP"), Ok(GameGenieCode { addr: 0xD1DD, value: 0x14, compare: None }));
+        assert_eq!(GameGenieCode::parse("ZEXPYGLA"), Ok(GameGenieCode { addr: 0x94A7, value: 0x02, compare: Some(0x03) }));
+        assert_eq!(GameGenieCode::parse("sxiopo"), GameGenieCode::parse("SXIOPO"));
+        assert_eq!(GameGenieCode::parse("NNNNNNNN"), Ok(GameGenieCode { addr: 0xFFFF, value: 0xFF, compare: Some(0xFF) }));
+    }
+
+    #[test]
+    fn rejects_bad_codes() {
+        assert_eq!(GameGenieCode::parse("SXIOP"), Err(CheatError::BadLength(5)));
+        assert_eq!(GameGenieCode::parse("SXIOPOPOP"), Err(CheatError::BadLength(9)));
+        assert_eq!(GameGenieCode::parse("SXIOPB"), Err(CheatError::BadLetter('B')));
+        assert_eq!(Cheat::parse("075A:09"), Ok(Cheat::Freeze { addr: 0x075A, value: 0x09 }));
+        assert_eq!(Cheat::parse("8000:09"), Err(CheatError::NotRam(0x8000)));
+        assert_eq!(Cheat::parse("075A:109"), Err(CheatError::BadFreeze));
+        assert_eq!(Cheat::parse("GOSSIP"), Ok(Cheat::GameGenie(GameGenieCode::parse("GOSSIP").unwrap())));
+    }
+
+    #[test]
+    fn compare_byte_gates_the_patch() {
+        let mut cheats = Cheats::new();
+        cheats.add(Cheat::parse("ZEXPYGLA").unwrap());
+        assert_eq!(cheats.patch_read(0x94A7, 0x03), 0x02);
+        assert_eq!(cheats.patch_read(0x94A7, 0x04), 0x04);
+        assert_eq!(cheats.patch_read(0x94A8, 0x03), 0x03);
+    }
+
+    #[test]
+    fn cheats_patch_the_bus_until_removed() {
+        // LDA $8010 into $10, round and round, with $42 at $8010
+        let mut program = vec![0xAD, 0x10, 0x80, 0x85, 0x10, 0x4C, 0x00, 0x80];
+        program.resize(0x11, 0);
+        program[0x10] = 0x42;
+        let mut cpu = testing::build_program(&program);
+        let patch = Cheat::GameGenie(GameGenieCode { addr: 0x8010, value: 0x99, compare: Some(0x42) });
+        let ignored = Cheat::GameGenie(GameGenieCode { addr: 0x8010, value: 0x55, compare: Some(0x43) });
+        cpu.add_cheat(ignored);
+        cpu.add_cheat(patch);
+        cpu.add_cheat(patch);
+        assert_eq!(cpu.list_cheats(), [ignored, patch]);
+        for _ in 0..3 {
+            cpu.emulate_cycle().unwrap();
+        }
+        assert_eq!(cpu.memory().peek(0x10), 0x99);
+        assert_eq!(cpu.memory().peek(0x8010), 0x99);
+
+        assert!(cpu.remove_cheat(&patch));
+        assert!(!cpu.remove_cheat(&patch));
+        for _ in 0..3 {
+            cpu.emulate_cycle().unwrap();
+        }
+        assert_eq!(cpu.memory().peek(0x10), 0x42);
+        assert_eq!(cpu.list_cheats(), [ignored]);
+    }
+
+    #[test]
+    fn freezes_hold_ram_every_frame() {
+        // Counts up $0300 as fast as it can
+        let rom = testing::build_test_rom("reset: INC $0300\n JMP reset", None);
+        let mut nes = Nes::builder().rom_bytes(&rom).build().unwrap();
+        let freeze = Cheat::parse("0300:07").unwrap();
+        nes.cpu_mut().add_cheat(freeze);
+        for _ in 0..3 {
+            // Frames end at the start of vblank, just as the freeze goes back in
+            nes.run_frame(InputFrame::default()).unwrap();
+            assert_eq!(nes.cpu().memory().peek(0x0300), 0x07);
+        }
+        nes.cpu_mut().remove_cheat(&freeze);
+        nes.run_frame(InputFrame::default()).unwrap();
+        let free = nes.cpu().memory().peek(0x0300);
+        nes.run_frame(InputFrame::default()).unwrap();
+        assert_ne!(nes.cpu().memory().peek(0x0300), free);
+    }
+}
@@ -0,0 +1,113 @@
+use image::Image;
+
+use std;
+
+pub const TILE_SIZE: usize = 16;
+pub const PATTERN_TABLE_SIZE: usize = 0x1000;
+
+// Decode one row of a tile into 2-bit pixel values, leftmost pixel first.
+// A tile is 8 bytes of low bit-plane followed by 8 bytes of high bit-plane.
+pub fn tile_row(tile: &[u8], row: usize) -> [u8; 8] {
+    decode_row(tile[row], tile[row + 8])
+}
+
+// The same from the two bit-plane bytes of the row
+pub fn decode_row(lo: u8, hi: u8) -> [u8; 8] {
+    let mut pixels = [0; 8];
+    for (i, pixel) in pixels.iter_mut().enumerate() {
+        let bit = 7 - i;
+        *pixel = ((lo >> bit) & 1) | (((hi >> bit) & 1) << 1);
+    }
+    return pixels
+}
+
+// Draw the 16x16 tile grid of a single pattern table at (x, y) in the image
+fn draw_pattern_table(img: &mut Image, table: &[u8], x: usize, y: usize) {
+    for (index, tile) in table.chunks(TILE_SIZE).enumerate() {
+        if tile.len() < TILE_SIZE { break; }
+        let tile_x = x + (index % 16) * 8;
+        let tile_y = y + (index / 16) * 8;
+        for row in 0..8 {
+            for (col, &pixel) in tile_row(tile, row).iter().enumerate() {
+                img.set(tile_x + col, tile_y + row, pixel);
+            }
+        }
+    }
+}
+
+// Render both pattern tables from the first 8 KiB of CHR as a 128x256 image of 2-bit pixel
+// values: $0000 on top, $1000 below. Missing CHR (e.g. CHR RAM carts) renders as blank tiles.
+pub fn render_pattern_tables(chr: &[u8]) -> Image {
+    let mut img = Image::new(128, 256);
+    for table in 0..2 {
+        let start = table * PATTERN_TABLE_SIZE;
+        if start >= chr.len() { break; }
+        let end = std::cmp::min(start + PATTERN_TABLE_SIZE, chr.len());
+        draw_pattern_table(&mut img, &chr[start..end], 0, table * 128);
+    }
+    return img
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use palette;
+
+    // A checkerboard of colors 1 and 2 in tile 1 of the first table, and one of 3 and 0 in
+    // tile 0 of the second
+    fn checkerboards() -> Vec<u8> {
+        let mut chr = vec![0; 2 * PATTERN_TABLE_SIZE];
+        for row in 0..8 {
+            let (even, odd) = if row % 2 == 0 { (0xAA, 0x55) } else { (0x55, 0xAA) };
+            chr[TILE_SIZE + row] = even;
+            chr[TILE_SIZE + row + 8] = odd;
+            chr[PATTERN_TABLE_SIZE + row] = even;
+            chr[PATTERN_TABLE_SIZE + row + 8] = even;
+        }
+        return chr
+    }
+
+    #[test]
+    fn decodes_a_checkerboard_tile() {
+        let chr = checkerboards();
+        assert_eq!(tile_row(&chr[TILE_SIZE..], 0), [1, 2, 1, 2, 1, 2, 1, 2]);
+        assert_eq!(tile_row(&chr[TILE_SIZE..], 1), [2, 1, 2, 1, 2, 1, 2, 1]);
+        assert_eq!(tile_row(&chr[PATTERN_TABLE_SIZE..], 0), [3, 0, 3, 0, 3, 0, 3, 0]);
+        assert_eq!(tile_row(&chr[PATTERN_TABLE_SIZE..], 1), [0, 3, 0, 3, 0, 3, 0, 3]);
+    }
+
+    #[test]
+    fn pattern_tables_place_each_tile() {
+        let img = render_pattern_tables(&checkerboards());
+        assert_eq!((img.width, img.height), (128, 256));
+        for y in 0..8 {
+            for x in 0..8 {
+                let light = (x + y) % 2 == 0;
+                assert_eq!(img.get(8 + x, y), if light { 1 } else { 2 }, "({}, {})", x, y);
+                assert_eq!(img.get(x, 128 + y), if light { 3 } else { 0 }, "({}, {})", x, y);
+                // Tile 0 of the first table is empty
+                assert_eq!(img.get(x, y), 0);
+            }
+        }
+        // CHR that stops short leaves the rest blank
+        let img = render_pattern_tables(&checkerboards()[..PATTERN_TABLE_SIZE]);
+        assert!(img.pixels[128 * 128..].iter().all(|&pixel| pixel == 0));
+    }
+
+    #[test]
+    fn dump_uses_the_palette_group() {
+        let colors = palette::group_rgb(&[0x0F, 0x16, 0x2A, 0x30]);
+        let mut ppm = Vec::new();
+        render_pattern_tables(&checkerboards()).write_ppm(&mut ppm, &colors).unwrap();
+        let header = b"P6\n128 256\n255\n";
+        assert_eq!(&ppm[..header.len()], &header[..]);
+        let pixel = |x: usize, y: usize| {
+            let at = header.len() + (y * 128 + x) * 3;
+            [ppm[at], ppm[at + 1], ppm[at + 2]]
+        };
+        assert_eq!(pixel(8, 0), palette::SYSTEM_PALETTE[0x16]);
+        assert_eq!(pixel(9, 0), palette::SYSTEM_PALETTE[0x2A]);
+        assert_eq!(pixel(0, 128), palette::SYSTEM_PALETTE[0x30]);
+        assert_eq!(pixel(1, 128), palette::SYSTEM_PALETTE[0x0F]);
+    }
+}
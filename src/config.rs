@@ -0,0 +1,256 @@
+// Settings kept between runs, so the palette, keymap, region and so on don't have to be given
+// on every command line. The file is ~/.config/nes_level/config.toml unless the frontend is
+// told otherwise, and anything on the command line overrides it.
+//
+// The file is a flat subset of TOML, one setting per line:
+//   region = "pal"
+//   palette = "/home/me/fbx.pal"
+//   sprite_limit = false
+//   speed = 1.5
+//   turbo_rate = 4
+// Values are double-quoted strings (with \" and \\ escapes), true or false, or numbers, and #
+// starts a comment. Unknown keys are skipped, so a newer file still loads in an older build.
+
+use flat_toml::{self, quote, Value};
+use keymap::TurboConfig;
+use mem::RamInit;
+use overscan::Overscan;
+use postprocess::PostProcess;
+use region::Region;
+
+use std::env;
+use std::fmt;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Syntax { line: usize },
+    BadValue { line: usize, name: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Io(ref e) => write!(f, "{}", e),
+            ConfigError::Syntax { line } => write!(f, "line {}: expected key = value", line),
+            ConfigError::BadValue { line, ref name } => write!(f, "line {}: bad value for {}", line, name),
+        }
+    }
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(e: io::Error) -> ConfigError {
+        ConfigError::Io(e)
+    }
+}
+
+// A key the file sets that this build doesn't know, and ignored
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownKey {
+    pub line: usize,
+    pub name: String,
+}
+
+impl fmt::Display for UnknownKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: unknown key {}, ignored", self.line, self.name)
+    }
+}
+
+// Every setting the frontend takes that makes sense to keep, as opposed to what to do on one
+// run (dumps, movies, breakpoints)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    // None follows the ROM header
+    pub region: Option<Region>,
+    pub palette: Option<String>,
+    pub keymap: Option<String>,
+    pub fds_bios: Option<String>,
+    pub ram_init: RamInit,
+    pub illegal_nop: bool,
+    pub cycle_step: bool,
+    pub sprite_limit: bool,
+    pub four_score: bool,
+    pub show_stats: bool,
+    // Multiple of full speed, 0 for as fast as possible
+    pub speed: f32,
+    // Frames per press and release of the turbo buttons, see keymap::TurboConfig
+    pub turbo_rate: u8,
+    pub overscan: Overscan,
+    pub post_process: PostProcess,
+    pub screenshot: String,
+    // RUST_LOG-style filters
+    pub log_level: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            region: None,
+            palette: None,
+            keymap: None,
+            fds_bios: None,
+            ram_init: RamInit::Zero,
+            illegal_nop: false,
+            cycle_step: false,
+            sprite_limit: true,
+            four_score: false,
+            show_stats: false,
+            speed: 0.0,
+            turbo_rate: TurboConfig::default().rate_frames,
+            overscan: Overscan::NONE,
+            post_process: PostProcess::None,
+            screenshot: "screenshot.ppm".to_string(),
+            log_level: None,
+        }
+    }
+}
+
+impl Config {
+    // The directory the config and anything else kept between runs live in, if there's a home
+    // directory
+    pub fn dir() -> Option<PathBuf> {
+        env::var_os("HOME").map(|home| Path::new(&home).join(".config").join("nes_level"))
+    }
+
+    // Where the config lives when the frontend isn't told
+    pub fn default_path() -> Option<PathBuf> {
+        Config::dir().map(|dir| dir.join("config.toml"))
+    }
+
+    pub fn load(path: &Path) -> Result<(Config, Vec<UnknownKey>), ConfigError> {
+        Config::parse(&fs::read_to_string(path)?)
+    }
+
+    // Settings from text, on top of the defaults, and whatever keys were skipped
+    pub fn parse(text: &str) -> Result<(Config, Vec<UnknownKey>), ConfigError> {
+        let mut config = Config::default();
+        let mut unknown = Vec::new();
+        for (line, content) in flat_toml::content_lines(text) {
+            let (name, value) = content.split_once('=').ok_or(ConfigError::Syntax { line: line })?;
+            let name = name.trim();
+            if name.is_empty() || name.contains(char::is_whitespace) {
+                return Err(ConfigError::Syntax { line: line })
+            }
+            let bad_value = || ConfigError::BadValue { line: line, name: name.to_string() };
+            let value = flat_toml::parse_value(value.trim()).ok_or_else(bad_value)?;
+            match (name, value) {
+                ("region", Value::Str(s)) => config.region = Some(s.parse().map_err(|_| bad_value())?),
+                ("palette", Value::Str(s)) => config.palette = Some(s),
+                ("keymap", Value::Str(s)) => config.keymap = Some(s),
+                ("fds_bios", Value::Str(s)) => config.fds_bios = Some(s),
+                ("ram_init", Value::Str(s)) => config.ram_init = s.parse().map_err(|_| bad_value())?,
+                ("illegal_nop", Value::Bool(b)) => config.illegal_nop = b,
+                ("cycle_step", Value::Bool(b)) => config.cycle_step = b,
+                ("sprite_limit", Value::Bool(b)) => config.sprite_limit = b,
+                ("four_score", Value::Bool(b)) => config.four_score = b,
+                ("show_stats", Value::Bool(b)) => config.show_stats = b,
+                ("speed", Value::Number(n)) => config.speed = f32::from_str(&n).ok().filter(|s| *s >= 0.0).ok_or_else(bad_value)?,
+                ("turbo_rate", Value::Number(n)) => config.turbo_rate = u8::from_str(&n).ok().filter(|r| *r >= 1).ok_or_else(bad_value)?,
+                ("overscan", Value::Str(s)) => config.overscan = s.parse().map_err(|_| bad_value())?,
+                ("post_process", Value::Str(s)) => config.post_process = s.parse().map_err(|_| bad_value())?,
+                ("screenshot", Value::Str(s)) => config.screenshot = s,
+                ("log_level", Value::Str(s)) => config.log_level = Some(s),
+                ("region", _) | ("palette", _) | ("keymap", _) | ("fds_bios", _) | ("ram_init", _) |
+                ("illegal_nop", _) | ("cycle_step", _) | ("sprite_limit", _) | ("four_score", _) |
+                ("show_stats", _) | ("speed", _) | ("overscan", _) | ("post_process", _) | ("screenshot", _) |
+                ("log_level", _) | ("turbo_rate", _) =>
+                    return Err(bad_value()),
+                _ => unknown.push(UnknownKey { line: line, name: name.to_string() }),
+            }
+        }
+        Ok((config, unknown))
+    }
+
+    // Every setting, in a form parse reads back to the same Config. Settings left to their
+    // automatic value (no region, no palette file) are left out.
+    pub fn to_toml(&self) -> String {
+        let mut out = String::new();
+        let strings = [("region", self.region.map(|r| r.to_string())), ("palette", self.palette.clone()),
+                       ("keymap", self.keymap.clone()), ("fds_bios", self.fds_bios.clone())];
+        for (name, value) in strings.iter() {
+            if let Some(ref value) = *value {
+                writeln!(out, "{} = {}", name, quote(value)).unwrap();
+            }
+        }
+        writeln!(out, "ram_init = {}", quote(&self.ram_init.to_string())).unwrap();
+        let bools = [("illegal_nop", self.illegal_nop), ("cycle_step", self.cycle_step),
+                     ("sprite_limit", self.sprite_limit), ("four_score", self.four_score),
+                     ("show_stats", self.show_stats)];
+        for &(name, value) in bools.iter() {
+            writeln!(out, "{} = {}", name, value).unwrap();
+        }
+        writeln!(out, "speed = {:?}", self.speed).unwrap();
+        writeln!(out, "turbo_rate = {}", self.turbo_rate).unwrap();
+        writeln!(out, "overscan = {}", quote(&self.overscan.to_string())).unwrap();
+        writeln!(out, "post_process = {}", quote(&self.post_process.to_string())).unwrap();
+        writeln!(out, "screenshot = {}", quote(&self.screenshot)).unwrap();
+        if let Some(ref filters) = self.log_level {
+            writeln!(out, "log_level = {}", quote(filters)).unwrap();
+        }
+        return out
+    }
+
+    // Write the config out, making its directory if need be
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, self.to_toml())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn configs_round_trip_through_toml() {
+        let config = Config {
+            region: Some(Region::Pal),
+            palette: Some("C:\\palettes\\\"fbx\" #2.pal".to_string()),
+            keymap: None,
+            fds_bios: Some("/bios/disksys.rom".to_string()),
+            ram_init: RamInit::Random(99),
+            illegal_nop: true,
+            cycle_step: true,
+            sprite_limit: false,
+            four_score: true,
+            show_stats: true,
+            speed: 1.5,
+            turbo_rate: 4,
+            overscan: "ntsc".parse().unwrap(),
+            post_process: PostProcess::FrameBlend,
+            screenshot: "shots/#1.ppm".to_string(),
+            log_level: Some("nes::ppu=debug".to_string()),
+        };
+        assert_eq!(Config::parse(&config.to_toml()).unwrap(), (config, Vec::new()));
+        assert_eq!(Config::parse(&Config::default().to_toml()).unwrap(), (Config::default(), Vec::new()));
+    }
+
+    #[test]
+    fn comments_blanks_and_unknown_keys_are_skipped() {
+        let text = "# settings\n\nregion = \"ntsc\"  # not pal\nwindow_scale = 3\nspeed = 1_0\n";
+        let (config, unknown) = Config::parse(text).unwrap();
+        assert_eq!((config.region, config.speed), (Some(Region::Ntsc), 10.0));
+        assert_eq!(unknown, [UnknownKey { line: 4, name: "window_scale".to_string() }]);
+        assert_eq!(unknown[0].to_string(), "line 4: unknown key window_scale, ignored");
+    }
+
+    #[test]
+    fn malformed_files_name_the_line() {
+        let error = |text: &str| Config::parse(text).unwrap_err().to_string();
+        assert_eq!(error("speed = 1\nregion = \"mars\"\n"), "line 2: bad value for region");
+        assert_eq!(error("\n\nsprite_limit\n"), "line 3: expected key = value");
+        assert_eq!(error("four score = true"), "line 1: expected key = value");
+        assert_eq!(error("palette = \"unterminated"), "line 1: bad value for palette");
+        assert_eq!(error("# speed\nspeed = -1"), "line 2: bad value for speed");
+        assert_eq!(error("turbo_rate = 0"), "line 1: bad value for turbo_rate");
+        assert_eq!(error("show_stats = \"yes\""), "line 1: bad value for show_stats");
+    }
+}
@@ -0,0 +1,303 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+// Standard controller buttons, as bit positions in the order the shift register reports them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+    A = 0,
+    B = 1,
+    Select = 2,
+    Start = 3,
+    Up = 4,
+    Down = 5,
+    Left = 6,
+    Right = 7,
+}
+
+impl Button {
+    // The button reported in bit n of the shift register
+    pub fn from_index(n: u8) -> Option<Button> {
+        match n {
+            0 => Some(Button::A),
+            1 => Some(Button::B),
+            2 => Some(Button::Select),
+            3 => Some(Button::Start),
+            4 => Some(Button::Up),
+            5 => Some(Button::Down),
+            6 => Some(Button::Left),
+            7 => Some(Button::Right),
+            _ => None,
+        }
+    }
+}
+
+// The buttons held on both ports for one frame, in set_buttons' layout. Everything a frame
+// needs from outside the machine, so a state plus the frames after it replay exactly.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct InputFrame {
+    pub ports: [u8; 2],
+    // Controllers 3 and 4, only read when a Four Score is plugged in
+    pub four_score: [u8; 2],
+}
+
+impl InputFrame {
+    pub fn new(port1: u8, port2: u8) -> InputFrame {
+        InputFrame { ports: [port1, port2], four_score: [0, 0] }
+    }
+
+    // All four controllers, for a Four Score
+    pub fn four_players(buttons: [u8; 4]) -> InputFrame {
+        InputFrame { ports: [buttons[0], buttons[1]], four_score: [buttons[2], buttons[3]] }
+    }
+}
+
+impl From<[u8; 2]> for InputFrame {
+    fn from(ports: [u8; 2]) -> InputFrame {
+        InputFrame::new(ports[0], ports[1])
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Controller {
+    buttons: u8,
+    shift: u8,
+    strobe: bool,
+    // What the shift register took when the strobe last went low
+    latched: u8,
+}
+
+impl Controller {
+    pub fn new() -> Controller {
+        Controller::default()
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        if pressed {
+            self.buttons |= 1 << button as u8;
+        } else {
+            self.buttons &= !(1 << button as u8);
+        }
+    }
+
+    // All eight buttons at once, A in bit 0 through Right in bit 7
+    pub fn set_buttons(&mut self, buttons: u8) {
+        self.buttons = buttons;
+    }
+
+    pub fn buttons(&self) -> u8 {
+        self.buttons
+    }
+
+    // The buttons the game last latched, in set_buttons' layout. This is what it saw, which can
+    // differ from buttons() if they changed since it last polled.
+    pub fn latched(&self) -> u8 {
+        self.latched
+    }
+
+    // $4016 writes. While the strobe is high the shift register keeps reloading from the
+    // buttons, so what gets shifted out is what was held when it went low.
+    pub fn write(&mut self, val: u8) {
+        let strobe = val & 1 != 0;
+        if self.strobe || strobe {
+            self.shift = self.buttons;
+        }
+        if self.strobe && !strobe {
+            self.latched = self.buttons;
+        }
+        self.strobe = strobe;
+    }
+
+    // Shift the next button out into bit 0. While the strobe is high that's always A, as it's
+    // held right now. After all eight the register has shifted in 1s, which is what reads.
+    pub fn read(&mut self) -> u8 {
+        if self.strobe {
+            return self.buttons & 1
+        }
+        let bit = self.shift & 1;
+        self.shift = self.shift >> 1 | 0x80;
+        return bit
+    }
+}
+
+// The Four Score adapter, which multiplexes four controllers onto the two ports. Each port
+// reads 24 bits after a strobe: its own controller, the controller behind it (3 on $4016, 4 on
+// $4017), then a signature byte telling the game the adapter is there.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct FourScore {
+    // Controllers 1-4. Port n reads controller n, then controller n + 2.
+    pub controllers: [Controller; 4],
+    // Bits read from each port since the last strobe
+    reads: [u8; 2],
+    strobe: bool,
+}
+
+impl FourScore {
+    // The signature bytes, read LSB first after both controllers
+    pub const SIGNATURES: [u8; 2] = [0x10, 0x20];
+
+    pub fn new() -> FourScore {
+        FourScore::default()
+    }
+
+    // Buttons for all four controllers, in set_buttons' layout
+    pub fn set_buttons(&mut self, buttons: [u8; 4]) {
+        for (controller, &buttons) in self.controllers.iter_mut().zip(buttons.iter()) {
+            controller.set_buttons(buttons);
+        }
+    }
+
+    // $4016 writes go to every controller, and restart both sequences
+    pub fn write(&mut self, val: u8) {
+        self.strobe = val & 1 != 0;
+        for controller in self.controllers.iter_mut() {
+            controller.write(val);
+        }
+        if self.strobe {
+            self.reads = [0, 0];
+        }
+    }
+
+    // The next bit of port's sequence, 0 for $4016 and 1 for $4017. Past the 24th bit the
+    // adapter reads 1.
+    pub fn read(&mut self, port: usize) -> u8 {
+        if self.strobe {
+            return self.controllers[port].read()
+        }
+        let n = self.reads[port];
+        self.reads[port] = n.saturating_add(1);
+        match n {
+            0..=7 => self.controllers[port].read(),
+            8..=15 => self.controllers[port + 2].read(),
+            16..=23 => (FourScore::SIGNATURES[port] >> (n - 16)) & 1,
+            _ => 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mem::{Addressable, Memory};
+    use testing;
+
+    // Eight reads, first bit first
+    fn read_byte(controller: &mut Controller) -> Vec<u8> {
+        (0..8).map(|_| controller.read()).collect()
+    }
+
+    #[test]
+    fn strobe_high_reads_a_live() {
+        let mut controller = Controller::new();
+        controller.write(1);
+        for &held in [true, false, true, true, false].iter() {
+            controller.set_buttons(0xFE);
+            controller.set_button(Button::A, held);
+            // However many times it's read, nothing shifts
+            assert_eq!(read_byte(&mut controller), vec![held as u8; 8]);
+        }
+    }
+
+    #[test]
+    fn strobe_falling_freezes_the_buttons() {
+        let mut controller = Controller::new();
+        controller.write(1);
+        controller.set_buttons(0xFF);
+        // What's held when the strobe drops is what reads, not what was held when it rose
+        controller.set_buttons(0b1010_0101);
+        controller.write(0);
+        controller.set_buttons(0);
+        assert_eq!(controller.latched(), 0b1010_0101);
+        assert_eq!(read_byte(&mut controller), [1, 0, 1, 0, 0, 1, 0, 1]);
+        // The register has shifted in 1s behind the buttons
+        assert_eq!(read_byte(&mut controller), [1; 8]);
+        assert_eq!(read_byte(&mut controller), [1; 8]);
+    }
+
+    #[test]
+    fn writing_zero_again_doesnt_relatch() {
+        let mut controller = Controller::new();
+        controller.set_buttons(0b0000_0011);
+        controller.write(1);
+        controller.write(0);
+        assert_eq!(controller.read(), 1);
+        controller.set_buttons(0);
+        controller.write(0);
+        assert_eq!(controller.latched(), 0b0000_0011);
+        assert_eq!(read_byte(&mut controller), [1, 0, 0, 0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn port_reads_through_the_bus() {
+        // LDA #$01, STA $4016, LDA $4016, LDA $4016, LDA #$00, STA $4016, then 10 LDA $4016
+        let mut program = vec![0xA9, 0x01, 0x8D, 0x16, 0x40, 0xAD, 0x16, 0x40, 0xAD, 0x16, 0x40,
+                               0xA9, 0x00, 0x8D, 0x16, 0x40];
+        for _ in 0..10 {
+            program.extend_from_slice(&[0xAD, 0x16, 0x40]);
+        }
+        let mut cpu = testing::build_program(&program);
+        for _ in 0..2 {
+            cpu.emulate_cycle().unwrap();
+        }
+        // A, then not A, while the strobe is high. Bit 6 is open bus, from the address.
+        cpu.memory_mut().controllers[0].set_buttons(0x01);
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.a(), 0x41);
+        cpu.memory_mut().controllers[0].set_buttons(0x80);
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.a(), 0x40);
+        for _ in 0..2 {
+            cpu.emulate_cycle().unwrap();
+        }
+        cpu.memory_mut().controllers[0].set_buttons(0);
+        let bits: Vec<u8> = (0..10).map(|_| {
+            cpu.emulate_cycle().unwrap();
+            cpu.a() & 1
+        }).collect();
+        assert_eq!(bits, [0, 0, 0, 0, 0, 0, 0, 1, 1, 1]);
+    }
+
+    // Bits in the order they're read, as 0 and 1 characters
+    fn bits(mut read: impl FnMut() -> u8, count: usize) -> String {
+        (0..count).map(|_| if read() & 1 != 0 { '1' } else { '0' }).collect()
+    }
+
+    #[test]
+    fn four_score_reads_24_bits_a_port() {
+        let mut four_score = FourScore::new();
+        four_score.set_buttons([0x01, 0x82, 0x44, 0xF0]);
+        four_score.write(1);
+        four_score.write(0);
+        // Controller 1, controller 3, then the signature and 1s after it
+        assert_eq!(bits(|| four_score.read(0), 26), "10000000001000100000100011");
+        assert_eq!(bits(|| four_score.read(1), 26), "01000001000011110000010011");
+
+        // Strobing starts both over, and while it's high port reads are A live
+        four_score.write(1);
+        assert_eq!(bits(|| four_score.read(0), 3), "111");
+        assert_eq!(bits(|| four_score.read(1), 3), "000");
+        four_score.write(0);
+        assert_eq!(bits(|| four_score.read(1), 9), "010000010");
+    }
+
+    #[test]
+    fn four_score_plugs_into_both_ports() {
+        let mut cpu = testing::build_program(&[]);
+        let memory = cpu.memory_mut();
+        let strobe = |memory: &mut Memory| {
+            memory.storeb(0x4016, 1);
+            memory.storeb(0x4016, 0);
+        };
+        // Without it, controller 2 is followed by 1s
+        memory.controllers[1].set_buttons(0x03);
+        strobe(memory);
+        assert_eq!(bits(|| memory.loadb(0x4017), 24), "110000001111111111111111");
+
+        memory.set_four_score(true);
+        memory.four_score.as_mut().unwrap().set_buttons([0, 0x03, 0, 0x80]);
+        strobe(memory);
+        assert_eq!(bits(|| memory.loadb(0x4017), 24), "110000000000000100000100");
+    }
+}
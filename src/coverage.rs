@@ -0,0 +1,136 @@
+// Which bytes of PRG ROM have run as code or been read as data, for reverse engineering
+
+use std::io;
+use std::io::prelude::*;
+
+// Per-byte flags, laid out as in an FCEUX code/data log
+pub const CDL_CODE: u8 = 0x01;
+pub const CDL_DATA: u8 = 0x02;
+// Bits 2-3 hold which 8K window of $8000-$FFFF the byte was seen through
+const CDL_WINDOW_SHIFT: u8 = 2;
+
+pub struct CoverageMap {
+    // One entry per byte of PRG ROM, indexed by offset into the ROM
+    prg: Vec<u8>,
+    chr_len: usize,
+}
+
+impl CoverageMap {
+    pub fn new(prg_len: usize, chr_len: usize) -> CoverageMap {
+        CoverageMap {
+            prg: vec![0; prg_len],
+            chr_len: chr_len,
+        }
+    }
+
+    fn window(addr: u16) -> u8 {
+        (((addr >> 13) & 3) as u8) << CDL_WINDOW_SHIFT
+    }
+
+    // offset is where in PRG ROM the CPU address addr landed
+    pub fn mark_code(&mut self, offset: usize, addr: u16) {
+        if let Some(flags) = self.prg.get_mut(offset) {
+            *flags |= CDL_CODE | CoverageMap::window(addr);
+        }
+    }
+
+    pub fn mark_data(&mut self, offset: usize, addr: u16) {
+        if let Some(flags) = self.prg.get_mut(offset) {
+            *flags |= CDL_DATA | CoverageMap::window(addr);
+        }
+    }
+
+    pub fn flags(&self, offset: usize) -> u8 {
+        self.prg.get(offset).cloned().unwrap_or(0)
+    }
+
+    pub fn is_code(&self, offset: usize) -> bool {
+        self.flags(offset) & CDL_CODE != 0
+    }
+
+    pub fn is_data(&self, offset: usize) -> bool {
+        self.flags(offset) & CDL_DATA != 0
+    }
+
+    // Bytes of PRG ROM seen as code and as data
+    pub fn counts(&self) -> (usize, usize) {
+        let code = self.prg.iter().filter(|f| *f & CDL_CODE != 0).count();
+        let data = self.prg.iter().filter(|f| *f & CDL_DATA != 0).count();
+        (code, data)
+    }
+
+    pub fn clear(&mut self) {
+        self.prg.iter_mut().for_each(|f| *f = 0);
+    }
+
+    // The PRG flags followed by one byte per byte of CHR ROM. CHR accesses aren't tracked, so
+    // that part is all zeros, but FCEUX expects it to be there.
+    pub fn write_cdl<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_all(&self.prg)?;
+        out.write_all(&vec![0; self.chr_len])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::CPU;
+    use rom::ROM;
+    use testing;
+
+    #[test]
+    fn code_and_data_are_flagged_apart() {
+        // $8000-$800E is code and the table at $800F is data, of which only the first three
+        // bytes are read
+        let rom = testing::build_test_rom("
+        LDX #$00
+loop:   LDA table,X
+        STA $10,X
+        INX
+        CPX #3
+        BNE loop
+done:   JMP done
+table:  .byte $11, $22, $33, $44
+", Some(&[0xFF; 16]));
+        let mut cpu = CPU::from_rom(ROM::from_bytes(&rom).unwrap());
+        cpu.power_on();
+        cpu.enable_coverage();
+        for _ in 0..20 {
+            cpu.emulate_cycle().unwrap();
+        }
+
+        let mut cdl = Vec::new();
+        cpu.coverage().unwrap().write_cdl(&mut cdl).unwrap();
+        // 32 KiB of PRG flags, then 8 KiB of zeros for CHR
+        assert_eq!(cdl.len(), 0x8000 + 0x2000);
+        assert!(cdl[0x8000..].iter().all(|&f| f == 0));
+        assert_eq!(cdl[..0x0F], [CDL_CODE; 0x0F]);
+        assert_eq!(cdl[0x0F..0x13], [CDL_DATA, CDL_DATA, CDL_DATA, 0]);
+        // The vector reads aren't fetches, and nothing else ran
+        assert!(cdl[0x13..0x8000].iter().all(|&f| f == 0));
+        assert_eq!(cpu.coverage().unwrap().counts(), (0x0F, 3));
+    }
+
+    #[test]
+    fn flags_record_the_window() {
+        let mut coverage = CoverageMap::new(0x8000, 0);
+        coverage.mark_code(0x0000, 0x8000);
+        coverage.mark_data(0x7FFF, 0xFFFF);
+        coverage.mark_data(0x2000, 0xA000);
+        coverage.mark_code(0x2000, 0xA000);
+        // Past the end of PRG is ignored
+        coverage.mark_code(0x8000, 0x8000);
+        assert_eq!(coverage.flags(0x0000), CDL_CODE);
+        assert_eq!(coverage.flags(0x7FFF), CDL_DATA | 0x0C);
+        assert_eq!(coverage.flags(0x2000), CDL_CODE | CDL_DATA | 0x04);
+        assert!(coverage.is_code(0x2000) && coverage.is_data(0x2000));
+        assert_eq!(coverage.counts(), (2, 2));
+
+        coverage.clear();
+        assert_eq!(coverage.counts(), (0, 0));
+        let mut cdl = Vec::new();
+        coverage.write_cdl(&mut cdl).unwrap();
+        assert_eq!(cdl, vec![0; 0x8000]);
+    }
+}
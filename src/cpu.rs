@@ -1,393 +1,1137 @@
+use cheats;
+use coverage::CoverageMap;
+use crash::{TraceRecord, TraceRing};
+use hash;
+use mapper;
+use opcodes::{AddressingMode, Mnemonic, Opcode, OPCODE_TABLE};
 use mem;
 use mem::Addressable;
+use profile::{Location, Profiler};
+use region::Region;
+use registers::*;
 use rom;
+use state::{self, SaveState, StateDelta, StateError, STATE_VERSION};
+use util;
+
+pub use registers::StatusFlags;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
 use std::fmt;
 
-#[derive(Default)]
-struct Registers {
-    a: u8,
-    x: u8,
-    y: u8,
-    s: u8, // Stack pointer
-    flags: u8, // Status register
-    pc: u16,
+// Vectors
+pub const NMI_VECTOR: u16 = 0xFFFA;
+pub const RESET_VECTOR: u16 = 0xFFFC;
+pub const IRQ_VECTOR: u16 = 0xFFFE;
+
+// Cycles spent pushing state and fetching the vector when an interrupt is taken
+const INTERRUPT_CYCLES: u8 = 7;
+
+// A snapshot of the registers, with the status flags also broken out by name
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuState {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub s: u8,
+    pub pc: u16,
+    pub flags: u8,
+    pub negative: bool,
+    pub overflow: bool,
+    pub break_flag: bool,
+    pub decimal: bool,
+    pub interrupt_disable: bool,
+    pub zero: bool,
+    pub carry: bool,
+    pub cycles: u64,
 }
 
-impl fmt::Debug for Registers {
+// Flags are shown NV-BDIZC, upper case when set
+impl fmt::Display for CpuState {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Registers a: {:#02x}, x: {:#02x}, y: {:#02x}, s: {:#02x}, flags: {:#02x}, pc: {:#04x}",
-            self.a, self.x, self.y, self.s, self.flags, self.pc)
+        let flag = |set: bool, letter: char| if set { letter } else { letter.to_ascii_lowercase() };
+        write!(f, "A:{:02X} X:{:02X} Y:{:02X} S:{:02X} PC:{:04X} P:{}{}-{}{}{}{}{} CYC:{}",
+               self.a, self.x, self.y, self.s, self.pc,
+               flag(self.negative, 'N'), flag(self.overflow, 'V'), flag(self.break_flag, 'B'),
+               flag(self.decimal, 'D'), flag(self.interrupt_disable, 'I'), flag(self.zero, 'Z'),
+               flag(self.carry, 'C'), self.cycles)
     }
 }
 
-// Bits for Registers::flags
-const CARRY_FLAG: u8 = 1 << 0;
-const ZERO_FLAG: u8 = 1 << 1;
-const INT_FLAG: u8 = 1 << 2;
-const DEC_FLAG: u8 = 1 << 3;
-const S1_FLAG: u8 = 1 << 4;
-const S2_FLAG: u8 = 1 << 5;
-const OVERFLOW_FLAG: u8 = 1 << 6;
-const NEG_FLAG: u8 = 1 << 7;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmulationError {
+    IllegalOpcode { pc: u16, opcode: u8 },
+    // The game turned on a mapper feature that isn't emulated
+    UnsupportedMapperFeature { feature: &'static str },
+    // The program wrote its exit code to the debug device
+    Exited { code: u8 },
+}
 
-// Vectors
-const NMI_VECTOR: u16 = 0xFFFA;
-const RESET_VECTOR: u16 = 0xFFFC;
-const IRQ_VECTOR: u16 = 0xFFFE;
+impl fmt::Display for EmulationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EmulationError::IllegalOpcode { pc, opcode } =>
+                write!(f, "illegal or unimplemented opcode ${:02X} at ${:04X}", opcode, pc),
+            EmulationError::UnsupportedMapperFeature { feature } =>
+                write!(f, "the cartridge's {} isn't supported", feature),
+            EmulationError::Exited { code } => write!(f, "the program exited with code {}", code),
+        }
+    }
+}
+
+// What to do on an opcode the CPU doesn't implement
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IllegalOpcodePolicy {
+    // Stop with EmulationError::IllegalOpcode
+    #[default]
+    Error,
+    // Treat it as a one-byte, two-cycle NOP
+    Nop,
+}
+
+// How much emulate_cycle's building blocks do at a time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StepMode {
+    // Each instruction runs in one go, with the machine clocked around its bus accesses
+    #[default]
+    Instruction,
+    // Each instruction is split into its cycles and run one per tick(), so the rest of the
+    // machine sees every bus access on the cycle the hardware makes it
+    Cycle,
+}
+
+// The machine state just before an instruction executes, handed to the trace hook
+#[derive(Debug, Clone, Copy)]
+pub struct TraceEvent {
+    pub pc: u16,
+    pub opcode: u8,
+    pub op: Opcode,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub s: u8,
+    pub flags: u8,
+    // Where the PPU is, as scanline and dot, in nestest's PPU column
+    pub scanline: u16,
+    pub dot: u16,
+    pub cycles: u64,
+    // The PRG bank mapped at pc, for looking up symbols
+    pub bank: Option<usize>,
+}
+
+impl fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04X}  {:02X}  {}  A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:3},{:3} CYC:{}",
+               self.pc, self.opcode, self.op.mnemonic, self.a, self.x, self.y, self.flags, self.s,
+               self.scanline, self.dot, self.cycles)
+    }
+}
+
+pub type TraceHook = Box<dyn FnMut(&TraceEvent) + Send>;
 
 pub struct CPU {
     regs: Registers,
     memory: mem::Memory,
+    // Total CPU cycles executed
+    pub cycles: u64,
+    // NMIs taken since power-on
+    pub nmi_count: u64,
+    pub illegal_opcode_policy: IllegalOpcodePolicy,
+    // Called before every instruction. Unset, tracing costs nothing.
+    trace_hook: Option<TraceHook>,
+    // The last few instructions, always kept for crash reports
+    recent: TraceRing,
+    // Which PRG bytes have run or been read, once enabled
+    coverage: Option<CoverageMap>,
+    // Execution counts per opcode and address, once enabled
+    profiler: Option<Profiler>,
+    pub step_mode: StepMode,
+    // The instruction or interrupt tick() is partway through
+    in_flight: Option<InFlight>,
+    // An NMI seen when interrupts were last polled, to be taken after the current instruction
+    nmi_latched: bool,
+    // CLI, SEI and PLP change I on their last cycle, after interrupts are polled, so the poll
+    // at their end still sees the old value. This is that value, until the next instruction.
+    delayed_int_flag: Option<bool>,
+    // Cycles the instruction being executed costs beyond its plan, i.e. a taken branch
+    extra_cycles: u8,
+}
+
+// An instruction's operand once its addressing mode has been decoded
+#[derive(Clone, Copy)]
+enum Operand {
+    Implied,
+    Accumulator,
+    // A byte of the instruction itself
+    Immediate { addr: u16 },
+    // fixup is the un-carried address indexed modes read from while fixing up the high byte
+    Memory { addr: u16, fixup: Option<u16> },
+    // A read-modify-write target whose value has already been read (and written back)
+    Latched { addr: u16, val: u8 },
+}
+
+impl Operand {
+    // Whether indexing carried into the high byte of the address
+    fn page_crossed(&self) -> bool {
+        matches!(*self, Operand::Memory { addr, fixup: Some(fixup) } if fixup != addr)
+    }
 }
 
-trait AddressingMode {
-    fn load(cpu: &mut CPU) -> u8;
-    fn store(cpu: &mut CPU, val: u8);
+type Handler = fn(&mut CPU, Operand);
+
+// One cycle's worth of an instruction after the opcode fetch
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum MicroOp {
+    // Nothing the rest of the machine needs to see
+    Internal,
+    // Operand bytes at PC
+    FetchLo,
+    FetchHi,
+    // The two bytes of an indirect pointer
+    PointerLo,
+    PointerHi,
+    // The read indexed stores and read-modify-writes always make from the un-carried address.
+    // The address is resolved here, once, for the accesses after it.
+    Fixup,
+    // Read-modify-write: read the target, then write it straight back
+    ReadLatch,
+    WriteBack,
+    // The handler, which makes whatever access is left: a read, a write, pushes and pulls
+    Execute,
 }
 
-struct AccumulatorAddressingMode;
-impl AddressingMode for AccumulatorAddressingMode {
-    fn load(cpu: &mut CPU) -> u8 { cpu.regs.a }
-    fn store(cpu: &mut CPU, val: u8) { cpu.regs.a = val; }
+const MAX_PLAN: usize = 7;
+
+// Cycles 2 and on of an instruction
+#[derive(Clone, Copy)]
+struct Plan {
+    ops: [MicroOp; MAX_PLAN],
+    len: usize,
+    // Indexed reads take a cycle more when the index carries into the high byte. Writes and
+    // read-modify-writes always take it, and the opcode table counts it for them already.
+    page_penalty: bool,
 }
 
-struct ImmediateAddressingMode;
-impl AddressingMode for ImmediateAddressingMode {
-    fn load(cpu: &mut CPU) -> u8 {
-        cpu.loadb_move()
+impl Plan {
+    const EMPTY: Plan = Plan { ops: [MicroOp::Internal; MAX_PLAN], len: 0, page_penalty: false };
+
+    // Lay an instruction's bus accesses out over the cycles the opcode table gives it: operand
+    // fetches first, the handler's accesses last, anything left over idle in between. A plan
+    // longer than the cycles available (JMP, which has no access of its own) finishes on its
+    // last cycle.
+    const fn new(op: Opcode) -> Plan {
+        use self::MicroOp::*;
+        let address: &[MicroOp] = match op.mode {
+            AddressingMode::Implied | AddressingMode::Accumulator | AddressingMode::Immediate |
+            AddressingMode::Relative => &[],
+            AddressingMode::ZeroPage | AddressingMode::ZeroPageX | AddressingMode::ZeroPageY => &[FetchLo],
+            AddressingMode::Absolute | AddressingMode::AbsoluteX | AddressingMode::AbsoluteY => &[FetchLo, FetchHi],
+            AddressingMode::Indirect => &[FetchLo, FetchHi, PointerLo, PointerHi],
+            AddressingMode::IndirectX | AddressingMode::IndirectY => &[FetchLo, PointerLo, PointerHi],
+        };
+        let rmw = matches!(op.mnemonic, Mnemonic::ASL | Mnemonic::LSR | Mnemonic::ROL | Mnemonic::ROR |
+                                        Mnemonic::INC | Mnemonic::DEC);
+        let store = matches!(op.mnemonic, Mnemonic::STA | Mnemonic::STX | Mnemonic::STY);
+        let indexed = matches!(op.mode, AddressingMode::AbsoluteX | AddressingMode::AbsoluteY | AddressingMode::IndirectY);
+        let tail: &[MicroOp] = match op.mode {
+            AddressingMode::Implied | AddressingMode::Accumulator => &[Execute],
+            AddressingMode::AbsoluteX | AddressingMode::AbsoluteY if rmw => &[Fixup, ReadLatch, WriteBack, Execute],
+            _ if indexed && store => &[Fixup, Execute],
+            _ if rmw => &[ReadLatch, WriteBack, Execute],
+            _ => &[Execute],
+        };
+        let idle = (op.cycles as usize - 1).saturating_sub(address.len() + tail.len());
+
+        let mut plan = Plan::EMPTY;
+        let mut i = 0;
+        while i < address.len() {
+            plan.ops[i] = address[i];
+            i += 1;
+        }
+        let mut i = 0;
+        while i < tail.len() {
+            plan.ops[address.len() + idle + i] = tail[i];
+            i += 1;
+        }
+        plan.len = address.len() + idle + tail.len();
+        plan.page_penalty = indexed && !store && !rmw;
+        return plan
     }
-    fn store(_cpu: &mut CPU, _val: u8) { panic!("Can't store to an immediate"); }
 }
 
-struct AbsoluteAddressingMode;
-impl AddressingMode for AbsoluteAddressingMode {
-    fn load(cpu: &mut CPU) -> u8 {
-        let addr = cpu.loadw_move();
-        cpu.memory.loadb(addr)
+const PLANS: [Plan; 256] = {
+    let mut plans = [Plan::EMPTY; 256];
+    let mut i = 0;
+    while i < 256 {
+        plans[i] = Plan::new(OPCODE_TABLE[i]);
+        i += 1;
+    }
+    plans
+};
+
+// An instruction (or interrupt) partway through its cycles
+#[derive(Clone, Copy)]
+struct InFlight {
+    // None for an interrupt
+    op: Option<(Opcode, Handler)>,
+    // What an interrupt jumps through
+    vector: u16,
+    cycles: u8,
+    // Cycles done, the opcode fetch being the first
+    cycle: u8,
+    plan: Plan,
+    // The next entry of the plan to run
+    next: usize,
+    lo: u8,
+    hi: u8,
+    ptr_lo: u8,
+    ptr_hi: u8,
+    // The operand once a Fixup has resolved it, then the value a ReadLatch read
+    latched: Option<Operand>,
+    // Whether the page-crossing cycle has been spent
+    crossed: bool,
+}
+
+impl InFlight {
+    fn instruction(opcode: u8, exec: Handler) -> InFlight {
+        let op = OPCODE_TABLE[opcode as usize];
+        InFlight {
+            op: Some((op, exec)),
+            vector: 0,
+            cycles: op.cycles,
+            cycle: 1,
+            plan: PLANS[opcode as usize],
+            next: 0,
+            lo: 0,
+            hi: 0,
+            ptr_lo: 0,
+            ptr_hi: 0,
+            latched: None,
+            crossed: false,
+        }
+    }
+
+    // Pushes and the vector fetch all land on the last cycle
+    fn interrupt(vector: u16) -> InFlight {
+        InFlight {
+            op: None,
+            vector: vector,
+            cycles: INTERRUPT_CYCLES,
+            cycle: 0,
+            plan: Plan::EMPTY,
+            next: 0,
+            lo: 0,
+            hi: 0,
+            ptr_lo: 0,
+            ptr_hi: 0,
+            latched: None,
+            crossed: false,
+        }
     }
-    fn store(cpu: &mut CPU, val: u8) {
-        let addr = cpu.loadw_move();
-        cpu.memory.storeb(addr, val);
+
+    fn is_interrupt(&self) -> bool {
+        self.op.is_none()
     }
 }
 
-struct AbsoluteWBAddressingMode;
-impl AddressingMode for AbsoluteWBAddressingMode {
-    fn load(cpu: &mut CPU) -> u8 {
-        let addr = cpu.memory.loadb(cpu.regs.pc) as u16;
-        cpu.memory.loadb(addr)
+// The address an indexed access sees before the carry is added into the high byte
+fn uncarried(base: u16, addr: u16) -> u16 {
+    (base & 0xFF00) | (addr & 0x00FF)
+}
+
+impl CPU {
+    pub fn new(rom_file: &str) -> Result<CPU, rom::RomError> {
+        let rom = rom::ROM::from_file(rom_file)?;
+        Ok(CPU::from_rom(rom))
     }
-    fn store(cpu: &mut CPU, val: u8) {
-        let addr = cpu.loadw_move();
-        cpu.memory.storeb(addr, val);
+
+    pub fn from_rom(rom: rom::ROM) -> CPU {
+        let mapper = mapper::for_rom(&rom);
+        CPU::with_mapper(rom, mapper)
     }
-}
 
-struct AbsoluteXAddressingMode;
-impl AddressingMode for AbsoluteXAddressingMode {
-    fn load(cpu: &mut CPU) -> u8 {
-        let mut addr = cpu.loadw_move();
-        addr += cpu.regs.x as u16;
-        cpu.memory.loadb(addr)
+    pub fn with_mapper(rom: rom::ROM, mapper: Box<dyn mapper::Mapper>) -> CPU {
+        CPU {
+            regs: Registers::default(),
+            memory: mem::Memory::with_mapper(rom, mapper),
+            cycles: 0,
+            nmi_count: 0,
+            illegal_opcode_policy: IllegalOpcodePolicy::default(),
+            trace_hook: None,
+            recent: TraceRing::new(),
+            coverage: None,
+            profiler: None,
+            step_mode: StepMode::default(),
+            in_flight: None,
+            nmi_latched: false,
+            delayed_int_flag: None,
+            extra_cycles: 0,
+        }
     }
-    fn store(cpu: &mut CPU, val: u8) {
-        let mut addr = cpu.loadw_move();
-        addr += cpu.regs.x as u16;
-        cpu.memory.storeb(addr, val);
+
+    pub fn set_region(&mut self, region: Region) {
+        self.memory.ppu.set_timing(region.timing());
+        self.memory.apu.set_timing(region.timing());
+        self.memory.dmc.set_timing(region.timing());
     }
-}
 
-struct AbsoluteXWBAddressingMode;
-impl AddressingMode for AbsoluteXWBAddressingMode {
-    fn load(cpu: &mut CPU) -> u8 {
-        let mut addr = cpu.memory.loadb(cpu.regs.pc) as u16;
-        addr += cpu.regs.x as u16;
-        cpu.memory.loadb(addr)
+    pub fn a(&self) -> u8 { self.regs.a }
+    pub fn x(&self) -> u8 { self.regs.x }
+    pub fn y(&self) -> u8 { self.regs.y }
+    pub fn s(&self) -> u8 { self.regs.s }
+    pub fn pc(&self) -> u16 { self.regs.pc }
+    pub fn flags(&self) -> StatusFlags { StatusFlags::from_bits(self.regs.flags()) }
+
+    pub fn set_a(&mut self, val: u8) { self.regs.a = val; }
+    pub fn set_x(&mut self, val: u8) { self.regs.x = val; }
+    pub fn set_y(&mut self, val: u8) { self.regs.y = val; }
+    pub fn set_s(&mut self, val: u8) { self.regs.s = val; }
+    pub fn set_flags(&mut self, flags: StatusFlags) { self.regs.set_flags(flags.bits()); }
+
+    // Jump straight to addr, e.g. to start somewhere other than the reset vector
+    pub fn set_pc(&mut self, addr: u16) {
+        self.regs.pc = addr;
     }
-    fn store(cpu: &mut CPU, val: u8) {
-        let mut addr = cpu.loadw_move();
-        addr += cpu.regs.x as u16;
-        cpu.memory.storeb(addr, val);
+
+    pub fn set_trace_hook(&mut self, hook: TraceHook) {
+        self.trace_hook = Some(hook);
     }
-}
 
-struct AbsoluteYAddressingMode;
-impl AddressingMode for AbsoluteYAddressingMode {
-    fn load(cpu: &mut CPU) -> u8 {
-        let mut addr = cpu.loadw_move();
-        addr += cpu.regs.y as u16;
-        cpu.memory.loadb(addr)
+    pub fn clear_trace_hook(&mut self) {
+        self.trace_hook = None;
     }
-    fn store(cpu: &mut CPU, val: u8) {
-        let mut addr = cpu.loadw_move();
-        addr += cpu.regs.y as u16;
-        cpu.memory.storeb(addr, val);
+
+    pub fn recent_trace(&self) -> &TraceRing {
+        &self.recent
     }
-}
 
-struct ZeroPageAddressingMode;
-impl AddressingMode for ZeroPageAddressingMode {
-    fn load(cpu: &mut CPU) -> u8 {
-        let addr = cpu.loadb_move() as u16;
-        cpu.memory.loadb(addr)
+    // Start recording which PRG bytes are executed and which are read as data
+    pub fn enable_coverage(&mut self) {
+        if self.coverage.is_none() {
+            let rom = &self.memory.rom;
+            self.coverage = Some(CoverageMap::new(rom.prg.len(), rom.chr.len()));
+        }
     }
-    fn store(cpu: &mut CPU, val: u8) {
-        let addr = cpu.loadb_move() as u16;
-        cpu.memory.storeb(addr, val);
+
+    pub fn coverage(&self) -> Option<&CoverageMap> {
+        self.coverage.as_ref()
     }
-}
 
-struct ZeroPageWBAddressingMode;
-impl AddressingMode for ZeroPageWBAddressingMode {
-    fn load(cpu: &mut CPU) -> u8 {
-        let addr = cpu.memory.loadb(cpu.regs.pc) as u16;
-        cpu.memory.loadb(addr)
+    // Start counting how often each opcode and instruction address runs
+    pub fn enable_profiler(&mut self) {
+        if self.profiler.is_none() {
+            self.profiler = Some(Profiler::new());
+        }
     }
-    fn store(cpu: &mut CPU, val: u8) {
-        let addr = cpu.loadb_move() as u16;
-        cpu.memory.storeb(addr, val);
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
     }
-}
 
-struct ZeroPageXAddressingMode;
-impl AddressingMode for ZeroPageXAddressingMode {
-    fn load(cpu: &mut CPU) -> u8 {
-        let mut addr = cpu.loadb_move() as u16;
-        addr += cpu.regs.x as u16;
-        cpu.memory.loadb(addr)
+    pub fn profiler_mut(&mut self) -> Option<&mut Profiler> {
+        self.profiler.as_mut()
     }
-    fn store(cpu: &mut CPU, val: u8) {
-        let mut addr = cpu.loadb_move() as u16;
-        addr += cpu.regs.x as u16;
-        cpu.memory.storeb(addr, val);
+
+    // The profiler's report with the top hottest addresses, None if it isn't enabled
+    pub fn profile_report(&self, top: usize) -> Option<String> {
+        self.profiler.as_ref().map(|profiler| profiler.report(&self.memory, self.cycles, top))
     }
-}
 
-struct ZeroPageXWBAddressingMode;
-impl AddressingMode for ZeroPageXWBAddressingMode {
-    fn load(cpu: &mut CPU) -> u8 {
-        let mut addr = cpu.memory.loadb(cpu.regs.pc) as u16;
-        addr += cpu.regs.x as u16;
-        cpu.memory.loadb(addr)
+    fn mark_code(&mut self, addr: u16) {
+        if let Some(ref mut coverage) = self.coverage {
+            if let Some(offset) = self.memory.prg_offset(addr) {
+                coverage.mark_code(offset, addr);
+            }
+        }
     }
-    fn store(cpu: &mut CPU, val: u8) {
-        let mut addr = cpu.loadb_move() as u16;
-        addr += cpu.regs.x as u16;
-        cpu.memory.storeb(addr, val);
+
+    fn mark_data(&mut self, addr: u16) {
+        if let Some(ref mut coverage) = self.coverage {
+            if let Some(offset) = self.memory.prg_offset(addr) {
+                coverage.mark_data(offset, addr);
+            }
+        }
     }
-}
 
-struct ZeroPageYAddressingMode;
-impl AddressingMode for ZeroPageYAddressingMode {
-    fn load(cpu: &mut CPU) -> u8 {
-        let mut addr = cpu.loadb_move() as u16;
-        addr += cpu.regs.y as u16;
-        cpu.memory.loadb(addr)
+    pub fn memory(&self) -> &mem::Memory {
+        &self.memory
     }
-    fn store(cpu: &mut CPU, val: u8) {
-        let mut addr = cpu.loadb_move() as u16;
-        addr += cpu.regs.y as u16;
-        cpu.memory.storeb(addr, val);
+
+    pub fn memory_mut(&mut self) -> &mut mem::Memory {
+        &mut self.memory
     }
-}
 
-impl CPU {
-    pub fn new(rom_file: &str) -> CPU {
-        let rom = rom::ROM::from_file(rom_file);
-        CPU {
-            regs: Registers::default(),
-            memory: mem::Memory::from_rom(rom),
-        }
+    pub fn add_cheat(&mut self, cheat: cheats::Cheat) {
+        self.memory.add_cheat(cheat);
+    }
+
+    pub fn remove_cheat(&mut self, cheat: &cheats::Cheat) -> bool {
+        self.memory.remove_cheat(cheat)
+    }
+
+    pub fn list_cheats(&self) -> &[cheats::Cheat] {
+        self.memory.list_cheats()
     }
 
     // Read a byte at the PC and increment it
     fn loadb_move(&mut self) -> u8 {
         let val = self.memory.loadb(self.regs.pc);
-        self.regs.pc += 1;
+        self.regs.pc = self.regs.pc.wrapping_add(1);
         return val;
     }
 
     // Read a word at the PC and increment it by 2
     fn loadw_move(&mut self) -> u16 {
         let val = self.memory.loadw(self.regs.pc);
-        self.regs.pc += 2;
+        self.regs.pc = self.regs.pc.wrapping_add(2);
         return val;
     }
 
+    // Read a word from the zero page, wrapping around within it
+    fn loadw_zp(&mut self, addr: u8) -> u16 {
+        let lo = self.memory.loadb(addr as u16) as u16;
+        let hi = self.memory.loadb(addr.wrapping_add(1) as u16) as u16;
+        (hi << 8) | lo
+    }
+
+    fn load(&mut self, operand: Operand) -> u8 {
+        match operand {
+            Operand::Accumulator => self.regs.a,
+            Operand::Immediate { addr } => self.memory.loadb(addr),
+            Operand::Memory { addr, .. } => {
+                self.mark_data(addr);
+                self.memory.loadb(addr)
+            },
+            Operand::Latched { val, .. } => val,
+            Operand::Implied => unreachable!("the opcode table gives no implied instruction an operand to load"),
+        }
+    }
+
+    fn store(&mut self, operand: Operand, val: u8) {
+        match operand {
+            Operand::Accumulator => { self.regs.a = val; },
+            Operand::Memory { addr, .. } | Operand::Latched { addr, .. } => { self.memory.storeb(addr, val); },
+            Operand::Implied | Operand::Immediate { .. } =>
+                unreachable!("the opcode table gives no implied or immediate instruction an operand to store to"),
+        }
+    }
+
+    // Read-modify-write. The read and the write of the old value back happen on the cycles before
+    // the instruction's last, see MicroOp::ReadLatch and MicroOp::WriteBack.
+    fn modify<F: FnOnce(&mut CPU, u8) -> u8>(&mut self, operand: Operand, f: F) {
+        let val = self.load(operand);
+        let val = f(self, val);
+        self.store(operand, val);
+    }
+
     fn get_flag(&self, flag: u8) -> bool {
-        (self.regs.flags & flag == 1)
+        self.regs.get_flag(flag)
     }
 
     fn set_flag(&mut self, flag: u8, value: bool) {
-        if value {
-            self.regs.flags |= flag;
-        } else {
-            self.regs.flags &= !flag;
+        self.regs.set_flag(flag, value);
+    }
+
+    // Execute one instruction and clock the PPU alongside it. Returns the cycles it took.
+    pub fn emulate_cycle(&mut self) -> Result<u8, EmulationError> {
+        if self.step_mode == StepMode::Cycle || self.in_flight.is_some() {
+            return self.tick_instruction();
+        }
+
+        let mut state = self.fetch_opcode()?;
+        // The bus access that matters to the rest of the machine is on an instruction's last
+        // cycle, so let the PPU catch up to just before it and finish the instruction after
+        let mut cycles = state.cycles;
+        self.clock(cycles - 1);
+        self.run_addressing(&mut state);
+        if self.cross_page(&mut state) {
+            self.clock(1);
+            cycles += 1;
+        }
+        self.finish_plan(&mut state);
+        self.clock(1);
+        let extra = std::mem::replace(&mut self.extra_cycles, 0);
+        self.clock(extra);
+        cycles += extra;
+
+        self.check_mapper()?;
+
+        if self.memory.ppu.take_nmi() {
+            self.interrupt(NMI_VECTOR);
+            self.nmi_count += 1;
+            self.clock(INTERRUPT_CYCLES);
+            cycles += INTERRUPT_CYCLES;
+        } else if self.irq_asserted() {
+            self.interrupt(IRQ_VECTOR);
+            self.clock(INTERRUPT_CYCLES);
+            cycles += INTERRUPT_CYCLES;
+        }
+        return Ok(cycles + self.take_stall());
+    }
+
+    // Run one CPU cycle, plus any the DMC stole during it. Returns whether it was the last of
+    // an instruction or interrupt.
+    pub fn tick(&mut self) -> Result<bool, EmulationError> {
+        let done = self.tick_cycle();
+        self.take_stall();
+        done
+    }
+
+    fn tick_cycle(&mut self) -> Result<bool, EmulationError> {
+        let mut state = match self.in_flight.take() {
+            Some(state) => state,
+            None if self.nmi_latched => {
+                self.nmi_latched = false;
+                InFlight::interrupt(NMI_VECTOR)
+            },
+            None if self.irq_asserted() => InFlight::interrupt(IRQ_VECTOR),
+            None => {
+                let state = self.fetch_opcode()?;
+                self.end_tick(state);
+                return Ok(false)
+            },
+        };
+
+        state.cycle += 1;
+        if state.cycle == state.cycles {
+            // Penalty cycles push the end of the instruction back as they turn up
+            if self.cross_page(&mut state) {
+                state.cycles += 1;
+                self.end_tick(state);
+                return Ok(false)
+            }
+            self.finish_plan(&mut state);
+            if self.extra_cycles > 0 {
+                state.cycles += std::mem::replace(&mut self.extra_cycles, 0);
+                self.end_tick(state);
+                return Ok(false)
+            }
+            if state.is_interrupt() {
+                self.interrupt(state.vector);
+                if state.vector == NMI_VECTOR {
+                    self.nmi_count += 1;
+                }
+            }
+            self.clock(1);
+            self.check_mapper()?;
+            return Ok(true)
+        }
+        self.run_micro_op(&mut state);
+        self.end_tick(state);
+        Ok(false)
+    }
+
+    // Clock the cycle just run and poll for interrupts at the end of the second-to-last one
+    fn end_tick(&mut self, state: InFlight) {
+        self.clock(1);
+        if state.cycle == state.cycles - 1 && !state.is_interrupt() && self.memory.ppu.take_nmi() {
+            self.nmi_latched = true;
+        }
+        self.in_flight = Some(state);
+    }
+
+    // Whether the bus's IRQ line is asserted and interrupts aren't disabled. The line is level
+    // triggered, so it's polled after every instruction and keeps interrupting until every
+    // source has been acknowledged.
+    fn irq_asserted(&self) -> bool {
+        let disabled = self.delayed_int_flag.unwrap_or_else(|| self.get_flag(INT_FLAG));
+        !disabled && self.memory.irq.is_asserted()
+    }
+
+    // Whether the machine has to stop after an instruction: the cartridge used something that
+    // isn't emulated, or a test program exited through the debug device
+    fn check_mapper(&self) -> Result<(), EmulationError> {
+        if let Some(code) = self.memory.debug_device.as_ref().and_then(|device| device.exit_code()) {
+            return Err(EmulationError::Exited { code: code })
+        }
+        match self.memory.mapper.borrow().unsupported() {
+            Some(feature) => Err(EmulationError::UnsupportedMapperFeature { feature: feature }),
+            None => Ok(()),
+        }
+    }
+
+    // Tick through the rest of an instruction, and any interrupt taken after it
+    fn tick_instruction(&mut self) -> Result<u8, EmulationError> {
+        let start = self.cycles;
+        loop {
+            if self.tick()? && !self.nmi_latched && !self.irq_asserted() {
+                return Ok((self.cycles - start) as u8)
+            }
         }
     }
 
-    pub fn emulate_cycle(&mut self) {
-        // Fetch opcode
+    // Cycle 1: fetch and decode the opcode
+    fn fetch_opcode(&mut self) -> Result<InFlight, EmulationError> {
+        self.delayed_int_flag = None;
+        let pc = self.regs.pc;
         let opcode = self.loadb_move();
-        println!("{:?}", self.regs);
-        println!("{:#x}: Got opcode ${:x}", self.regs.pc - 1, opcode);
-        // Process opcode
-        match opcode {
-            // Arithmetic
-            // -- Adds
-            0x69 => { self.adc::<ImmediateAddressingMode>(); }, 0x65 => { self.adc::<ZeroPageAddressingMode>(); },
-            0x75 => { self.adc::<ZeroPageXAddressingMode>(); }, 0x6d => { self.adc::<AbsoluteAddressingMode>(); },
-            0x7d => { self.adc::<AbsoluteXAddressingMode>(); }, 0x79 => { self.adc::<AbsoluteYAddressingMode>(); },
-            // -- Subs
-            0xe9 => { self.sbc::<ImmediateAddressingMode>(); }, 0xe5 => { self.sbc::<ZeroPageAddressingMode>(); },
-            0xf5 => { self.sbc::<ZeroPageXAddressingMode>(); }, 0xed => { self.sbc::<AbsoluteAddressingMode>(); },
-            0xfd => { self.sbc::<AbsoluteXAddressingMode>(); }, 0xf9 => { self.sbc::<AbsoluteYAddressingMode>(); },
-            // Comparisons
-            // -- Cmp A
-            0xc9 => { self.cmp::<ImmediateAddressingMode>(); }, 0xc5 => { self.cmp::<ZeroPageAddressingMode>(); },
-            0xd5 => { self.cmp::<ZeroPageXAddressingMode>(); }, 0xcd => { self.cmp::<AbsoluteAddressingMode>(); },
-            0xdd => { self.cmp::<AbsoluteXAddressingMode>(); }, 0xd9 => { self.cmp::<AbsoluteYAddressingMode>(); },
-            // -- Cmp X
-            0xe0 => { self.cpx::<ImmediateAddressingMode>(); }, 0xe4 => { self.cpx::<ZeroPageAddressingMode>(); },
-            0xec => { self.cpx::<AbsoluteAddressingMode>(); },
-            // -- Cmp Y
-            0xc0 => { self.cpy::<ImmediateAddressingMode>(); }, 0xc4 => { self.cpy::<ZeroPageAddressingMode>(); },
-            0xcc => { self.cpy::<AbsoluteAddressingMode>(); },
-            // Loads
-            // -- Load A
-            0xa9 => { self.lda::<ImmediateAddressingMode>(); }, 0xa5 => { self.lda::<ZeroPageAddressingMode>(); },
-            0xb5 => { self.lda::<ZeroPageXAddressingMode>(); }, 0xad => { self.lda::<AbsoluteAddressingMode>(); },
-            0xbd => { self.lda::<AbsoluteXAddressingMode>(); }, 0xb9 => { self.lda::<AbsoluteYAddressingMode>(); },
-            // -- Load X
-            0xa2 => { self.ldx::<ImmediateAddressingMode>(); }, 0xa6 => { self.ldx::<ZeroPageAddressingMode>(); },
-            0xb6 => { self.ldx::<ZeroPageYAddressingMode>(); }, 0xae => { self.ldx::<AbsoluteAddressingMode>(); },
-            0xbe => { self.ldx::<AbsoluteYAddressingMode>(); },
-            // -- Load Y
-            0xa0 => { self.ldy::<ImmediateAddressingMode>(); }, 0xa4 => { self.lda::<ZeroPageAddressingMode>(); },
-            0xb4 => { self.lda::<ZeroPageXAddressingMode>(); }, 0xac => { self.ldy::<AbsoluteAddressingMode>(); },
-            0xbc => { self.ldy::<AbsoluteXAddressingMode>(); },
-            // Stores
-            // -- Store A
-            0x85 => { self.sta::<ZeroPageAddressingMode>(); }, 0x95 => { self.sta::<ZeroPageXAddressingMode>(); },
-            0x8d => { self.sta::<AbsoluteAddressingMode>(); }, 0x9d => { self.sta::<AbsoluteXAddressingMode>(); },
-            0x99 => { self.sta::<AbsoluteYAddressingMode>(); },
-            // -- Store X
-            0x86 => { self.stx::<ZeroPageAddressingMode>(); }, 0x96 => { self.stx::<ZeroPageXAddressingMode>(); },
-            0x8e => { self.stx::<AbsoluteAddressingMode>(); },
-            // -- Store Y
-            0x84 => { self.sty::<ZeroPageAddressingMode>(); }, 0x94 => { self.sty::<ZeroPageXAddressingMode>(); },
-            0x8c => { self.sty::<AbsoluteAddressingMode>(); },
-            // Nop
-            0xea => { self.nop(); },
-            // Boolean
-            // -- And
-            0x29 => { self.and::<ImmediateAddressingMode>(); }, 0x25 => { self.and::<ZeroPageAddressingMode>(); },
-            0x35 => { self.and::<ZeroPageXAddressingMode>(); }, 0x2d => { self.and::<AbsoluteAddressingMode>(); },
-            0x3d => { self.and::<AbsoluteXAddressingMode>(); }, 0x39 => { self.and::<AbsoluteYAddressingMode>(); },
-            // -- Or
-            0x09 => { self.ora::<ImmediateAddressingMode>(); }, 0x05 => { self.ora::<ZeroPageAddressingMode>(); },
-            0x15 => { self.ora::<ZeroPageXAddressingMode>(); }, 0x0d => { self.ora::<AbsoluteAddressingMode>(); },
-            0x1d => { self.ora::<AbsoluteXAddressingMode>(); }, 0x19 => { self.ora::<AbsoluteYAddressingMode>(); },
-            // -- Eor
-            0x49 => { self.eor::<ImmediateAddressingMode>(); }, 0x45 => { self.eor::<ZeroPageAddressingMode>(); },
-            0x55 => { self.eor::<ZeroPageXAddressingMode>(); }, 0x4d => { self.eor::<AbsoluteAddressingMode>(); },
-            0x5d => { self.eor::<AbsoluteXAddressingMode>(); }, 0x59 => { self.eor::<AbsoluteYAddressingMode>(); },
-            // -- Bit set
-            // Shifts
-            // -- Asl
-            0x0a => { self.asl::<AccumulatorAddressingMode>(); }, 0x06 => { self.asl::<ZeroPageWBAddressingMode>(); },
-            0x16 => { self.asl::<ZeroPageXWBAddressingMode>(); }, 0x0e => { self.asl::<AbsoluteWBAddressingMode>(); },
-            0x1e => { self.asl::<AbsoluteXWBAddressingMode>(); },
-            // -- Rol
-            0x2a => { self.rol::<AccumulatorAddressingMode>(); }, 0x26 => { self.rol::<ZeroPageWBAddressingMode>(); },
-            0x36 => { self.rol::<ZeroPageXWBAddressingMode>(); }, 0x2e => { self.rol::<AbsoluteWBAddressingMode>(); },
-            0x3e => { self.rol::<AbsoluteXWBAddressingMode>(); },
-            // -- Lsr
-            0x4a => { self.lsr::<AccumulatorAddressingMode>(); }, 0x46 => { self.lsr::<ZeroPageWBAddressingMode>(); },
-            0x56 => { self.lsr::<ZeroPageXWBAddressingMode>(); }, 0x4e => { self.lsr::<AbsoluteWBAddressingMode>(); },
-            0x5e => { self.lsr::<AbsoluteXWBAddressingMode>(); },
-            // -- Ror
-            0x6a => { self.ror::<AccumulatorAddressingMode>(); }, 0x66 => { self.ror::<ZeroPageWBAddressingMode>(); },
-            0x76 => { self.ror::<ZeroPageXWBAddressingMode>(); }, 0x6e => { self.ror::<AbsoluteWBAddressingMode>(); },
-            0x7e => { self.ror::<AbsoluteXWBAddressingMode>(); },
-            // Branches
-            0x10 => { self.bpl(); }, 0x30 => { self.bmi(); }, 0x50 => { self.bvc(); }, 0x70 => { self.bvs(); },
-            0x90 => { self.bcc(); }, 0xb0 => { self.bcs(); }, 0xd0 => { self.bne(); }, 0xf0 => { self.beq(); },
-            // Jumps
-            0x4c => { self.jmp(); },
-            // Increment and decrement
-            0xca => { self.dex(); }, 0x88 => { self.dey(); }, 0xe8 => { self.inx(); }, 0xc8 => { self.iny(); },
-            _ => {
-                panic!("Illegal/unimplemented opcode {:#02x}", opcode);
+        let op = OPCODE_TABLE[opcode as usize];
+        self.recent.record(TraceRecord {
+            pc: pc,
+            opcode: opcode,
+            a: self.regs.a,
+            x: self.regs.x,
+            y: self.regs.y,
+            s: self.regs.s,
+            flags: self.regs.flags(),
+        });
+        if self.coverage.is_some() {
+            for i in 0..op.len as u16 {
+                self.mark_code(pc.wrapping_add(i));
             }
         }
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.record(Location { pc: pc, prg_offset: self.memory.prg_offset(pc) }, opcode);
+        }
+        if let Some(ref mut hook) = self.trace_hook {
+            hook(&TraceEvent {
+                pc: pc,
+                opcode: opcode,
+                op: op,
+                a: self.regs.a,
+                x: self.regs.x,
+                y: self.regs.y,
+                s: self.regs.s,
+                flags: self.regs.flags(),
+                scanline: self.memory.ppu.scanline(),
+                dot: self.memory.ppu.dot(),
+                cycles: self.cycles,
+                bank: self.memory.prg_bank(pc),
+            });
+        }
+
+        let exec = match CPU::handler(op.mnemonic) {
+            Some(exec) => exec,
+            None if self.illegal_opcode_policy == IllegalOpcodePolicy::Error => {
+                // Leave PC on the offending opcode
+                self.regs.pc = pc;
+                return Err(EmulationError::IllegalOpcode { pc: pc, opcode: opcode });
+            },
+            None => CPU::nop,
+        };
+        Ok(InFlight::instruction(opcode, exec))
+    }
+
+    // Let the rest of the machine catch up with cycles the CPU just spent
+    fn clock(&mut self, cycles: u8) {
+        self.cycles += cycles as u64;
+        self.memory.clock(cycles as u32);
+    }
+
+    // Count the cycles the DMC stole from the CPU. The rest of the machine ran through them
+    // when they happened.
+    fn take_stall(&mut self) -> u8 {
+        let stall = self.memory.take_dma_stall();
+        self.cycles += stall as u64;
+        stall as u8
+    }
+
+    fn push(&mut self, val: u8) {
+        self.memory.storeb(0x100 | self.regs.s as u16, val);
+        self.regs.s = self.regs.s.wrapping_sub(1);
+    }
+
+    fn pull(&mut self) -> u8 {
+        self.regs.s = self.regs.s.wrapping_add(1);
+        self.memory.loadb(0x100 | self.regs.s as u16)
+    }
+
+    fn push_pc(&mut self) {
+        let pc = self.regs.pc;
+        self.push((pc >> 8) as u8);
+        self.push(pc as u8);
+    }
+
+    // Hardware interrupts push PC and the flags with B clear, then jump through the vector
+    fn interrupt(&mut self, vector: u16) {
+        self.delayed_int_flag = None;
+        self.push_pc();
+        let flags = self.regs.flags();
+        self.push(flags);
+        self.set_flag(INT_FLAG, true);
+        self.regs.pc = self.memory.loadw(vector);
     }
 
+    // Plug in another cartridge and power on with it, see Memory::swap_cartridge. Coverage and
+    // profiles start over, since they're by offset into the old PRG.
+    pub fn swap_cartridge(&mut self, rom: rom::ROM, mapper: Box<dyn mapper::Mapper>) -> bool {
+        let kept_prg_ram = self.memory.swap_cartridge(rom, mapper);
+        if self.coverage.is_some() {
+            self.coverage = None;
+            self.enable_coverage();
+        }
+        if self.profiler.is_some() {
+            self.profiler = None;
+            self.enable_profiler();
+        }
+        self.power_on();
+        kept_prg_ram
+    }
+
+    // Cold boot: registers take their documented power-up values and RAM is cleared
+    pub fn power_on(&mut self) {
+        self.regs = Registers::default();
+        self.regs.s = 0xFD;
+        self.regs.set_flags(INT_FLAG);
+        self.memory.power_on();
+        self.in_flight = None;
+        self.nmi_latched = false;
+        self.delayed_int_flag = None;
+        self.extra_cycles = 0;
+        self.regs.pc = self.memory.loadw(RESET_VECTOR);
+    }
+
+    // The reset button: RAM and cartridge state survive, the stack pointer moves down as if
+    // three bytes were pushed (nothing is written) and interrupts are disabled
     pub fn reset(&mut self) {
-        // Reset registers
+        self.regs.s = self.regs.s.wrapping_sub(3);
+        self.set_flag(INT_FLAG, true);
+        self.memory.reset();
+        self.in_flight = None;
+        self.nmi_latched = false;
+        self.delayed_int_flag = None;
+        self.extra_cycles = 0;
         self.regs.pc = self.memory.loadw(RESET_VECTOR);
     }
 }
 
+// Save states
+impl CPU {
+    // Only possible between instructions: in cycle-stepped mode, finish the one in flight first
+    pub fn save_state(&self) -> Result<SaveState, StateError> {
+        let mut state = self.save_registers()?;
+        let memory = &self.memory;
+        state.ram = memory.ram.data.to_vec();
+        state.prg_ram = memory.prg_ram.clone();
+        state.ppu = memory.ppu.save_state();
+        state.mapper = memory.mapper.borrow().save_state();
+        Ok(state)
+    }
+
+    // A whole state that starts a new round of dirty page tracking, for save_delta to build on
+    pub fn save_snapshot(&mut self) -> Result<SaveState, StateError> {
+        let state = self.save_state()?;
+        self.memory.take_dirty_pages();
+        Ok(state)
+    }
+
+    // What's changed since base, which has to be the last snapshot or what the last delta
+    // after it gives applied. Only the pages written since then are copied.
+    pub fn save_delta(&mut self, base: &SaveState) -> Result<StateDelta, StateError> {
+        let rest = self.save_registers()?;
+        let dirty = self.memory.take_dirty_pages();
+        let memory = &self.memory;
+        let mapper = memory.mapper.borrow().save_state();
+        Ok(StateDelta {
+            rest: rest,
+            ram: state::dirty_pages(&memory.ram.data, dirty.ram),
+            prg_ram: state::dirty_pages(&memory.prg_ram, dirty.prg_ram),
+            vram: state::dirty_pages(memory.ppu.nametable_ram(), dirty.vram),
+            framebuffer: state::changed_pages(&memory.ppu.framebuffer, &base.ppu.framebuffer),
+            mapper: state::changed_pages(&mapper, &base.mapper),
+            mapper_len: mapper.len(),
+        })
+    }
+
+    // Everything but RAM, PRG RAM, VRAM, the picture and the mapper, which are left empty
+    fn save_registers(&self) -> Result<SaveState, StateError> {
+        if self.in_flight.is_some() {
+            return Err(StateError::MidInstruction)
+        }
+        let memory = &self.memory;
+        Ok(SaveState {
+            version: STATE_VERSION,
+            rom_md5: hash::hex(&memory.rom.md5()),
+            cpu: self.dump_state(),
+            nmi_count: self.nmi_count,
+            nmi_latched: self.nmi_latched,
+            delayed_int_flag: self.delayed_int_flag,
+            ram: Vec::new(),
+            prg_ram: Vec::new(),
+            controllers: memory.controllers.clone(),
+            four_score: memory.four_score.clone(),
+            ppu: memory.ppu.save_registers(),
+            apu: memory.apu.clone(),
+            dmc: memory.dmc.clone(),
+            mapper: Vec::new(),
+        })
+    }
+
+    // Nothing is changed unless the whole state loads
+    pub fn load_state(&mut self, state: &SaveState) -> Result<(), StateError> {
+        if state.version != STATE_VERSION {
+            return Err(StateError::WrongVersion { found: state.version, expected: STATE_VERSION })
+        }
+        if state.rom_md5 != hash::hex(&self.memory.rom.md5()) {
+            return Err(StateError::WrongRom)
+        }
+        if state.ram.len() != self.memory.ram.data.len() {
+            return Err(StateError::Corrupt("RAM"))
+        }
+        if state.prg_ram.len() != self.memory.prg_ram.len() {
+            return Err(StateError::Corrupt("PRG RAM"))
+        }
+        if state.four_score.is_some() != self.memory.four_score.is_some() {
+            return Err(StateError::Corrupt("controller setup"))
+        }
+        // The PPU and mapper check their own parts, so take copies to put back if they fail
+        let ppu = self.memory.ppu.save_state();
+        let mapper = self.memory.mapper.borrow().save_state();
+        let restored = self.memory.ppu.load_state(&state.ppu)
+            .and_then(|_| self.memory.mapper.borrow_mut().load_state(&state.mapper));
+        if let Err(e) = restored {
+            self.memory.ppu.load_state(&ppu).unwrap();
+            self.memory.mapper.borrow_mut().load_state(&mapper).unwrap();
+            return Err(e)
+        }
+
+        self.memory.ram.data.copy_from_slice(&state.ram);
+        self.memory.prg_ram.copy_from_slice(&state.prg_ram);
+        self.memory.controllers = state.controllers.clone();
+        self.memory.four_score = state.four_score.clone();
+        self.memory.apu.load_state(&state.apu);
+        self.memory.dmc = state.dmc.clone();
+        self.regs.a = state.cpu.a;
+        self.regs.x = state.cpu.x;
+        self.regs.y = state.cpu.y;
+        self.regs.s = state.cpu.s;
+        self.regs.pc = state.cpu.pc;
+        self.regs.set_flags(state.cpu.flags);
+        self.cycles = state.cpu.cycles;
+        self.nmi_count = state.nmi_count;
+        self.nmi_latched = state.nmi_latched;
+        self.delayed_int_flag = state.delayed_int_flag;
+        self.in_flight = None;
+        self.memory.mark_all_dirty();
+        self.memory.update_irq();
+        Ok(())
+    }
+}
+
+// Cycle-by-cycle execution
+impl CPU {
+    fn run_micro_op(&mut self, state: &mut InFlight) {
+        if state.next >= state.plan.len {
+            return;
+        }
+        let micro_op = state.plan.ops[state.next];
+        state.next += 1;
+
+        let (op, exec) = match state.op {
+            Some(op) => op,
+            None => return,
+        };
+        match micro_op {
+            MicroOp::Internal => {},
+            MicroOp::FetchLo => state.lo = self.loadb_move(),
+            MicroOp::FetchHi => state.hi = self.loadb_move(),
+            MicroOp::PointerLo => {
+                let ptr = self.pointer(op.mode, state);
+                state.ptr_lo = self.memory.loadb(ptr);
+            },
+            MicroOp::PointerHi => {
+                // The pointer's high byte is fetched without carrying into its page
+                let ptr = self.pointer(op.mode, state);
+                let ptr_hi = match op.mode {
+                    AddressingMode::Indirect => uncarried(ptr, ptr.wrapping_add(1)),
+                    _ => (ptr as u8).wrapping_add(1) as u16,
+                };
+                if op.mode == AddressingMode::Indirect {
+                    self.mark_data(ptr_hi);
+                }
+                state.ptr_hi = self.memory.loadb(ptr_hi);
+            },
+            MicroOp::Fixup => {
+                let operand = self.operand(op.mode, state);
+                if let Operand::Memory { fixup: Some(fixup), .. } = operand {
+                    self.memory.loadb(fixup);
+                }
+                state.latched = Some(operand);
+            },
+            MicroOp::ReadLatch => {
+                let operand = match state.latched {
+                    Some(operand) => operand,
+                    None => self.operand(op.mode, state),
+                };
+                let addr = match operand {
+                    Operand::Memory { addr, .. } => addr,
+                    _ => unreachable!("only memory operands are latched"),
+                };
+                self.mark_data(addr);
+                let val = self.memory.loadb(addr);
+                state.latched = Some(Operand::Latched { addr: addr, val: val });
+            },
+            MicroOp::WriteBack => {
+                if let Some(Operand::Latched { addr, val }) = state.latched {
+                    self.memory.storeb(addr, val);
+                }
+            },
+            MicroOp::Execute => {
+                let operand = match state.latched {
+                    Some(operand) => operand,
+                    None => self.operand(op.mode, state),
+                };
+                exec(self, operand);
+            },
+        }
+    }
+
+    // Run whatever is left of the plan, for an instruction's last cycle or the whole of it
+    fn finish_plan(&mut self, state: &mut InFlight) {
+        while state.next < state.plan.len {
+            self.run_micro_op(state);
+        }
+    }
+
+    // Run the plan up to the handler, which is always its last entry
+    fn run_addressing(&mut self, state: &mut InFlight) {
+        while state.next + 1 < state.plan.len {
+            self.run_micro_op(state);
+        }
+    }
+
+    // Spend the extra cycle an indexed read takes when its address crosses a page, if it does
+    // and hasn't yet. The cycle goes on a read of the address before the carry.
+    fn cross_page(&mut self, state: &mut InFlight) -> bool {
+        if !state.plan.page_penalty || state.crossed {
+            return false
+        }
+        let mode = match state.op {
+            Some((op, _)) => op.mode,
+            None => return false,
+        };
+        match self.operand(mode, state) {
+            operand @ Operand::Memory { fixup: Some(fixup), .. } if operand.page_crossed() => {
+                self.memory.loadb(fixup);
+                state.crossed = true;
+                true
+            },
+            _ => false,
+        }
+    }
+
+    // The address of an indirect mode's pointer, once its operand bytes are in
+    fn pointer(&mut self, mode: AddressingMode, state: &InFlight) -> u16 {
+        let ptr = match mode {
+            AddressingMode::Indirect => (state.hi as u16) << 8 | state.lo as u16,
+            AddressingMode::IndirectX => state.lo.wrapping_add(self.regs.x) as u16,
+            _ => state.lo as u16,
+        };
+        if mode == AddressingMode::Indirect {
+            self.mark_data(ptr);
+        }
+        return ptr
+    }
+
+    // What the operand bytes fetched so far refer to. Immediate operands are the byte at PC,
+    // which this steps over.
+    fn operand(&mut self, mode: AddressingMode, state: &InFlight) -> Operand {
+        let indexed = |base: u16, index: u8| {
+            let addr = base.wrapping_add(index as u16);
+            Operand::Memory { addr: addr, fixup: Some(uncarried(base, addr)) }
+        };
+        let direct = |addr: u16| Operand::Memory { addr: addr, fixup: None };
+        let word = (state.hi as u16) << 8 | state.lo as u16;
+        let pointee = (state.ptr_hi as u16) << 8 | state.ptr_lo as u16;
+
+        match mode {
+            AddressingMode::Implied => Operand::Implied,
+            AddressingMode::Accumulator => Operand::Accumulator,
+            AddressingMode::Immediate | AddressingMode::Relative => {
+                let addr = self.regs.pc;
+                self.regs.pc = self.regs.pc.wrapping_add(1);
+                Operand::Immediate { addr: addr }
+            },
+            AddressingMode::ZeroPage => direct(state.lo as u16),
+            AddressingMode::ZeroPageX => direct(state.lo.wrapping_add(self.regs.x) as u16),
+            AddressingMode::ZeroPageY => direct(state.lo.wrapping_add(self.regs.y) as u16),
+            AddressingMode::Absolute => direct(word),
+            AddressingMode::AbsoluteX => indexed(word, self.regs.x),
+            AddressingMode::AbsoluteY => indexed(word, self.regs.y),
+            AddressingMode::Indirect | AddressingMode::IndirectX => direct(pointee),
+            AddressingMode::IndirectY => indexed(pointee, self.regs.y),
+        }
+    }
+}
+
 // Instructions implementation
 impl CPU {
-    fn ora<AM: AddressingMode>(&mut self) {
-        let val = AM::load(self);
-        println!("OR-ing A {:#x} and {:#x}", self.regs.a, val);
+    // The implementation of an instruction, if there is one
+    fn handler(mnemonic: Mnemonic) -> Option<Handler> {
+        let exec: Handler = match mnemonic {
+            Mnemonic::ADC => CPU::adc, Mnemonic::AND => CPU::and, Mnemonic::ASL => CPU::asl, Mnemonic::BCC => CPU::bcc,
+            Mnemonic::BCS => CPU::bcs, Mnemonic::BEQ => CPU::beq, Mnemonic::BMI => CPU::bmi, Mnemonic::BNE => CPU::bne,
+            Mnemonic::BPL => CPU::bpl, Mnemonic::BRK => CPU::brk, Mnemonic::BVC => CPU::bvc, Mnemonic::BVS => CPU::bvs,
+            Mnemonic::CLI => CPU::cli, Mnemonic::CMP => CPU::cmp, Mnemonic::CPX => CPU::cpx, Mnemonic::CPY => CPU::cpy, Mnemonic::DEC => CPU::dec,
+            Mnemonic::DEX => CPU::dex, Mnemonic::DEY => CPU::dey, Mnemonic::EOR => CPU::eor, Mnemonic::INC => CPU::inc,
+            Mnemonic::INX => CPU::inx, Mnemonic::INY => CPU::iny, Mnemonic::JMP => CPU::jmp, Mnemonic::JSR => CPU::jsr,
+            Mnemonic::LDA => CPU::lda, Mnemonic::LDX => CPU::ldx, Mnemonic::LDY => CPU::ldy, Mnemonic::LSR => CPU::lsr,
+            Mnemonic::NOP => CPU::nop, Mnemonic::ORA => CPU::ora, Mnemonic::PHP => CPU::php, Mnemonic::PLP => CPU::plp,
+            Mnemonic::ROL => CPU::rol, Mnemonic::ROR => CPU::ror, Mnemonic::RTI => CPU::rti, Mnemonic::RTS => CPU::rts,
+            Mnemonic::SBC => CPU::sbc, Mnemonic::SEI => CPU::sei, Mnemonic::STA => CPU::sta, Mnemonic::STX => CPU::stx, Mnemonic::STY => CPU::sty,
+            _ => return None,
+        };
+        return Some(exec)
+    }
+
+    fn ora(&mut self, operand: Operand) {
+        let val = self.load(operand);
         self.regs.a |= val;
     }
 
-    fn eor<AM: AddressingMode>(&mut self) {
-        let val = AM::load(self);
-        println!("EOR-ing A {:#x} and {:#x}", self.regs.a, val);
+    fn eor(&mut self, operand: Operand) {
+        let val = self.load(operand);
         self.regs.a ^= val;
     }
 
-    fn and<AM: AddressingMode>(&mut self) {
-        let val = AM::load(self);
-        println!("AND-ing A {:#x} and {:#x}", self.regs.a, val);
+    fn and(&mut self, operand: Operand) {
+        let val = self.load(operand);
         self.regs.a &= val;
     }
 
-    fn asl<AM: AddressingMode>(&mut self) {
-        let mut val = AM::load(self);
-        let top_bit = (val & 0x80) != 0;
-        val <<= 1;
-        self.set_flag(CARRY_FLAG, top_bit);
-        AM::store(self, val);
+    fn asl(&mut self, operand: Operand) {
+        self.modify(operand, |cpu, mut val| {
+            let top_bit = (val & 0x80) != 0;
+            val <<= 1;
+            cpu.set_flag(CARRY_FLAG, top_bit);
+            val
+        });
     }
 
-    fn rol<AM: AddressingMode>(&mut self) {
-        let mut val = AM::load(self);
-        let top_bit = (val & 0x80) != 0;
-        val <<= 1;
-        val |= self.get_flag(CARRY_FLAG) as u8;
-        self.set_flag(CARRY_FLAG, top_bit);
-        AM::store(self, val);
+    fn rol(&mut self, operand: Operand) {
+        self.modify(operand, |cpu, mut val| {
+            let top_bit = (val & 0x80) != 0;
+            val <<= 1;
+            val |= cpu.get_flag(CARRY_FLAG) as u8;
+            cpu.set_flag(CARRY_FLAG, top_bit);
+            val
+        });
     }
 
-    fn lsr<AM: AddressingMode>(&mut self) {
-        let mut val = AM::load(self);
-        let low_bit = (val & 0x1) != 0;
-        val >>= 1;
-        self.set_flag(CARRY_FLAG, low_bit);
-        AM::store(self, val);
+    fn lsr(&mut self, operand: Operand) {
+        self.modify(operand, |cpu, mut val| {
+            let low_bit = (val & 0x1) != 0;
+            val >>= 1;
+            cpu.set_flag(CARRY_FLAG, low_bit);
+            val
+        });
     }
 
-    fn ror<AM: AddressingMode>(&mut self) {
-        let mut val = AM::load(self);
-        let low_bit = (val & 0x1) != 0;
-        val >>= 1;
-        val |= (self.get_flag(CARRY_FLAG) as u8) << 7;
-        self.set_flag(CARRY_FLAG, low_bit);
-        AM::store(self, val);
+    fn ror(&mut self, operand: Operand) {
+        self.modify(operand, |cpu, mut val| {
+            let low_bit = (val & 0x1) != 0;
+            val >>= 1;
+            val |= (cpu.get_flag(CARRY_FLAG) as u8) << 7;
+            cpu.set_flag(CARRY_FLAG, low_bit);
+            val
+        });
     }
 
-    fn adc<AM: AddressingMode>(&mut self) {
+    fn adc(&mut self, operand: Operand) {
         let mut result = self.regs.a as u16;
-        let val = AM::load(self);
-        println!("Adding {} to {}", result, val);
+        let val = self.load(operand);
         result += val as u16;
         if self.get_flag(CARRY_FLAG) { result += 1; }
 
@@ -396,224 +1140,873 @@ impl CPU {
         self.regs.a = result as u8;
     }
 
-    fn sbc<AM: AddressingMode>(&mut self) {
+    fn sbc(&mut self, operand: Operand) {
         let mut result = self.regs.a as u16;
-        let val = AM::load(self);
-        println!("Subtracting {} from {}", result, val);
-        result -= val as u16;
-        if self.get_flag(CARRY_FLAG) { result -= 1; }
+        let val = self.load(operand);
+        result = result.wrapping_sub(val as u16);
+        if self.get_flag(CARRY_FLAG) { result = result.wrapping_sub(1); }
 
         self.set_flag(CARRY_FLAG, (result & 0x100) != 0);
 
         self.regs.a = result as u8;
     }
 
-    fn lda<AM: AddressingMode>(&mut self) {
-        let val = AM::load(self);
-        println!("Loading {} into A", val);
+    fn lda(&mut self, operand: Operand) {
+        let val = self.load(operand);
         self.regs.a = val;
     }
 
-    fn ldx<AM: AddressingMode>(&mut self) {
-        let val = AM::load(self);
-        println!("Loading {} into X", val);
+    fn ldx(&mut self, operand: Operand) {
+        let val = self.load(operand);
         self.regs.x = val;
     }
 
-    fn ldy<AM: AddressingMode>(&mut self) {
-        let val = AM::load(self);
-        println!("Loading {} into Y", val);
+    fn ldy(&mut self, operand: Operand) {
+        let val = self.load(operand);
         self.regs.y = val;
     }
 
-    fn nop(&mut self) {}
+    fn nop(&mut self, _operand: Operand) {}
 
-    fn sta<AM: AddressingMode>(&mut self) {
+    fn sta(&mut self, operand: Operand) {
         let val = self.regs.a;
-        println!("Storing {:#x} from A", val);
-        AM::store(self, val);
+        self.store(operand, val);
     }
 
-    fn stx<AM: AddressingMode>(&mut self) {
+    fn stx(&mut self, operand: Operand) {
         let val = self.regs.x;
-        println!("Storing {:#x} from X", val);
-        AM::store(self, val);
+        self.store(operand, val);
     }
 
-    fn sty<AM: AddressingMode>(&mut self) {
+    fn sty(&mut self, operand: Operand) {
         let val = self.regs.y;
-        println!("Storing {:#x} from Y", val);
-        AM::store(self, val);
+        self.store(operand, val);
     }
 
     fn compare(&mut self, first: u8, second: u8) {
         let result = (first as u16).wrapping_sub(second as u16);
-        println!("Comparing {:#x} and {:#x}", first, second);
         self.set_flag(CARRY_FLAG, (result & 0x100) != 0);
         self.set_flag(ZERO_FLAG, result == 0);
     }
 
-    fn cmp<AM: AddressingMode>(&mut self) {
-        let val = AM::load(self);
+    fn cmp(&mut self, operand: Operand) {
+        let val = self.load(operand);
         let a = self.regs.a;
         self.compare(a, val);
     }
 
-    fn cpx<AM: AddressingMode>(&mut self) {
-        let val = AM::load(self);
+    fn cpx(&mut self, operand: Operand) {
+        let val = self.load(operand);
         let x = self.regs.x;
         self.compare(x, val);
     }
 
-    fn cpy<AM: AddressingMode>(&mut self) {
-        let val = AM::load(self);
+    fn cpy(&mut self, operand: Operand) {
+        let val = self.load(operand);
         let y = self.regs.y;
         self.compare(y, val);
     }
 
-    fn bpl(&mut self) {
-        let offset = ImmediateAddressingMode::load(self);
-        println!("BPL might branch to +{:#x}", offset);
-        if (self.regs.flags & NEG_FLAG) == 0 {
-            println!("Taking the branch!");
-            self.regs.pc += offset as u16;
+    // A taken branch costs a cycle, and another if it lands on a different page
+    fn branch(&mut self, operand: Operand, taken: bool) {
+        let offset = self.load(operand);
+        if taken {
+            let pc = self.regs.pc;
+            self.regs.pc = pc.wrapping_add(offset as i8 as u16);
+            self.extra_cycles = if pc & 0xFF00 == self.regs.pc & 0xFF00 { 1 } else { 2 };
         }
     }
 
-    fn bmi(&mut self) {
-        let offset = ImmediateAddressingMode::load(self);
-        println!("BMI might branch to +{:#x}", offset);
-        if (self.regs.flags & NEG_FLAG) != 0 {
-            println!("Taking the branch!");
-            self.regs.pc += offset as u16;
-        }
+    fn bpl(&mut self, operand: Operand) {
+        let taken = !self.get_flag(NEG_FLAG);
+        self.branch(operand, taken);
     }
 
-    fn bvc(&mut self) {
-        let offset = ImmediateAddressingMode::load(self);
-        println!("BVC might branch to +{:#x}", offset);
-        if (self.regs.flags & OVERFLOW_FLAG) == 0 {
-            println!("Taking the branch!");
-            self.regs.pc += offset as u16;
-        }
+    fn bmi(&mut self, operand: Operand) {
+        let taken = self.get_flag(NEG_FLAG);
+        self.branch(operand, taken);
     }
 
-    fn bvs(&mut self) {
-        let offset = ImmediateAddressingMode::load(self);
-        println!("BVS might branch to +{:#x}", offset);
-        if (self.regs.flags & OVERFLOW_FLAG) != 0 {
-            println!("Taking the branch!");
-            self.regs.pc += offset as u16;
-        }
+    fn bvc(&mut self, operand: Operand) {
+        let taken = !self.get_flag(OVERFLOW_FLAG);
+        self.branch(operand, taken);
     }
 
-    fn bcc(&mut self) {
-        let offset = ImmediateAddressingMode::load(self);
-        println!("BCC might branch to +{:#x}", offset);
-        if (self.regs.flags & CARRY_FLAG) == 0 {
-            println!("Taking the branch!");
-            self.regs.pc += offset as u16;
-        }
+    fn bvs(&mut self, operand: Operand) {
+        let taken = self.get_flag(OVERFLOW_FLAG);
+        self.branch(operand, taken);
     }
 
-    fn bcs(&mut self) {
-        let offset = ImmediateAddressingMode::load(self);
-        println!("BCS might branch to +{:#x}", offset);
-        if (self.regs.flags & CARRY_FLAG) != 0 {
-            println!("Taking the branch!");
-            self.regs.pc += offset as u16;
-        }
+    fn bcc(&mut self, operand: Operand) {
+        let taken = !self.get_flag(CARRY_FLAG);
+        self.branch(operand, taken);
     }
 
-    fn bne(&mut self) {
-        let offset = ImmediateAddressingMode::load(self);
-        println!("BNE might branch to +{:#x}", offset);
-        if (self.regs.flags & ZERO_FLAG) == 0 {
-            println!("Taking the branch!");
-            self.regs.pc += offset as u16;
-        }
+    fn bcs(&mut self, operand: Operand) {
+        let taken = self.get_flag(CARRY_FLAG);
+        self.branch(operand, taken);
     }
 
-    fn beq(&mut self) {
-        let offset = ImmediateAddressingMode::load(self);
-        println!("BEQ might branch to +{:#x}", offset);
-        if (self.regs.flags & ZERO_FLAG) != 0 {
-            println!("Taking the branch!");
-            self.regs.pc += offset as u16;
-        }
+    fn bne(&mut self, operand: Operand) {
+        let taken = !self.get_flag(ZERO_FLAG);
+        self.branch(operand, taken);
+    }
+
+    fn beq(&mut self, operand: Operand) {
+        let taken = self.get_flag(ZERO_FLAG);
+        self.branch(operand, taken);
+    }
+
+    fn inc(&mut self, operand: Operand) {
+        self.modify(operand, |_, val| val.wrapping_add(1));
+    }
+
+    fn dec(&mut self, operand: Operand) {
+        self.modify(operand, |_, val| val.wrapping_sub(1));
+    }
+
+    fn dex(&mut self, _operand: Operand) {
+        self.regs.x = self.regs.x.wrapping_sub(1);
+    }
+
+    fn dey(&mut self, _operand: Operand) {
+        self.regs.y = self.regs.y.wrapping_sub(1);
     }
 
-    fn dex(&mut self) {
-        println!("Decrementing X");
-        self.regs.x.wrapping_sub(1);
+    fn inx(&mut self, _operand: Operand) {
+        self.regs.x = self.regs.x.wrapping_add(1);
     }
 
-    fn dey(&mut self) {
-        println!("Decrementing Y");
-        self.regs.y.wrapping_sub(1);
+    fn iny(&mut self, _operand: Operand) {
+        self.regs.y = self.regs.y.wrapping_add(1);
     }
 
-    fn inx(&mut self) {
-        println!("Incrementing X");
-        self.regs.x.wrapping_add(1);
+    fn brk(&mut self, _operand: Operand) {
+        // BRK skips a padding byte, and its pushed flags have B set to tell it apart from IRQ
+        self.regs.pc = self.regs.pc.wrapping_add(1);
+        self.push_pc();
+        let flags = self.regs.flags() | S1_FLAG;
+        self.push(flags);
+        self.set_flag(INT_FLAG, true);
+        self.regs.pc = self.memory.loadw(IRQ_VECTOR);
     }
 
-    fn iny(&mut self) {
-        println!("Incrementing Y");
-        self.regs.y.wrapping_add(1);
+    fn rti(&mut self, _operand: Operand) {
+        let flags = self.pull();
+        self.regs.set_flags(flags);
+        let lo = self.pull() as u16;
+        let hi = self.pull() as u16;
+        self.regs.pc = (hi << 8) | lo;
     }
 
-    fn jmp(&mut self) {
-        let addr = self.loadw_move();
-        println!("Jumping to {:#x}", addr);
+    fn php(&mut self, _operand: Operand) {
+        let flags = self.regs.flags() | S1_FLAG;
+        self.push(flags);
+    }
+
+    fn plp(&mut self, _operand: Operand) {
+        let flags = self.pull();
+        self.delayed_int_flag = Some(self.get_flag(INT_FLAG));
+        self.regs.set_flags(flags);
+    }
+
+    fn cli(&mut self, _operand: Operand) {
+        self.delayed_int_flag = Some(self.get_flag(INT_FLAG));
+        self.set_flag(INT_FLAG, false);
+    }
+
+    fn sei(&mut self, _operand: Operand) {
+        self.delayed_int_flag = Some(self.get_flag(INT_FLAG));
+        self.set_flag(INT_FLAG, true);
+    }
+
+    fn jmp(&mut self, operand: Operand) {
+        let addr = match operand {
+            Operand::Memory { addr, .. } => addr,
+            _ => unreachable!("the opcode table only decodes JMP with an address"),
+        };
+        self.regs.pc = addr;
+    }
+
+    // The return address pushed is the last byte of the JSR, which RTS makes up for
+    fn jsr(&mut self, operand: Operand) {
+        let addr = match operand {
+            Operand::Memory { addr, .. } => addr,
+            _ => unreachable!("the opcode table only decodes JSR with an address"),
+        };
+        self.regs.pc = self.regs.pc.wrapping_sub(1);
+        self.push_pc();
         self.regs.pc = addr;
     }
+
+    fn rts(&mut self, _operand: Operand) {
+        let lo = self.pull() as u16;
+        let hi = self.pull() as u16;
+        self.regs.pc = ((hi << 8) | lo).wrapping_add(1);
+    }
 }
 
 // Formatting
 impl CPU {
-    pub fn print_memory(&self, start: u16, end: u16) {
-        if end == 0 {
-            print!("{}", hexdump(&self.memory.ram.data[..], 0x0));
-        } else {
-            print!("{}", hexdump(&self.memory.ram.data[start as usize..end as usize], 0x0));
+    // Hexdump of [start, end) as the CPU sees it, read through the bus. The range is clamped
+    // to the address space and an empty or backwards one gives an empty dump.
+    pub fn dump_memory(&mut self, start: u16, end: u32) -> String {
+        let end = end.min(0x10000);
+        let data: Vec<u8> = (start as u32..end).map(|addr| self.memory.loadb(addr as u16)).collect();
+        util::hexdump(&data, start as u32, 16)
+    }
+
+    pub fn print_memory(&mut self, start: u16, end: u32) {
+        print!("{}", self.dump_memory(start, end));
+    }
+
+    // Hexdump of up to len bytes of PRG ROM starting at CPU address start, cut short at the end
+    // of the ROM
+    pub fn dump_prg(&self, start: u16, len: usize) -> String {
+        let prg = &self.memory.rom.prg;
+        let offset = (start as usize).saturating_sub(0x8000).min(prg.len());
+        let end = offset.saturating_add(len).min(prg.len());
+        util::hexdump(&prg[offset..end], 0x8000 + offset as u32, 16)
+    }
+
+    pub fn dump_state(&self) -> CpuState {
+        let flags = self.regs.flags();
+        CpuState {
+            a: self.regs.a,
+            x: self.regs.x,
+            y: self.regs.y,
+            s: self.regs.s,
+            pc: self.regs.pc,
+            flags: flags,
+            negative: flags & NEG_FLAG != 0,
+            overflow: flags & OVERFLOW_FLAG != 0,
+            break_flag: flags & S1_FLAG != 0,
+            decimal: flags & DEC_FLAG != 0,
+            interrupt_disable: flags & INT_FLAG != 0,
+            zero: flags & ZERO_FLAG != 0,
+            carry: flags & CARRY_FLAG != 0,
+            cycles: self.cycles,
         }
     }
 }
 
 impl fmt::Display for CPU {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}", self.regs)
+        write!(f, "{}", self.dump_state())
     }
 }
 
 impl fmt::Debug for CPU {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let mut memdump = String::new();
-        // This is stupid. Why is the width the ROM length? Write a proper dumping method
-        let len = match f.width() {
-            Some(x) => { x },
-            None => { self.memory.rom.prg.len() },
-        };
-
-        memdump.push_str(&hexdump(&self.memory.rom.prg[0..len], 0x8000));
-        try!(write!(f, "{}", memdump));
-        try!(write!(f, "{:?}", self.regs));
-        Result::Ok(())
+        write!(f, "{:?}", self.dump_state())
     }
 }
 
-fn hexdump(hex: &[u8], start: u32) -> String {
-    let mut addr = start;
-    let mut dump = String::new();
-    for byte in hex.iter() {
-        if addr % 32 == 0 {
-            dump.push_str(&format!("\n{:04x}: ", addr));
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm;
+    use controller::InputFrame;
+    use emulator::Nes;
+    use mem::{AccessKind, BusObserver, IrqSource, RamInit};
+    use rom::ROM;
+    use testing;
+
+    use std::sync::{Arc, Mutex};
+
+    // Every access the bus sees, as (write, address)
+    #[derive(Default)]
+    struct BusLog(Vec<(bool, u16)>);
+
+    impl BusObserver for BusLog {
+        fn on_read(&mut self, addr: u16, _val: u8) { self.0.push((false, addr)); }
+        fn on_write(&mut self, addr: u16, _val: u8) { self.0.push((true, addr)); }
+    }
+
+    #[test]
+    fn reset_keeps_ram_and_power_on_clears_it() {
+        // LDA #$42, STA $10, LDX #$07, LDY #$09
+        let mut cpu = testing::build_program(&[0xA9, 0x42, 0x85, 0x10, 0xA2, 0x07, 0xA0, 0x09]);
+        for _ in 0..4 {
+            cpu.emulate_cycle().unwrap();
+        }
+        cpu.set_flag(INT_FLAG, false);
+
+        // Three pushes that write nothing, with interrupts off
+        cpu.reset();
+        assert_eq!((cpu.regs.s, cpu.regs.pc), (0xFA, 0x8000));
+        assert!(cpu.get_flag(INT_FLAG));
+        assert_eq!((cpu.regs.a, cpu.regs.x, cpu.regs.y), (0x42, 0x07, 0x09));
+        assert_eq!(cpu.memory().peek(0x10), 0x42);
+        assert_eq!(cpu.memory().peek(0x01FB), 0x00);
+        cpu.reset();
+        assert_eq!(cpu.regs.s, 0xF7);
+
+        cpu.memory_mut().ram_init = RamInit::Ff;
+        cpu.power_on();
+        assert_eq!((cpu.regs.s, cpu.regs.pc, cpu.regs.flags()), (0xFD, 0x8000, 0x24));
+        assert_eq!((cpu.regs.a, cpu.regs.x, cpu.regs.y), (0, 0, 0));
+        assert_eq!(cpu.memory().peek(0x10), 0xFF);
+    }
+
+    // Runs the program's first instructions in both step modes, handing each CPU to check
+    fn after_steps<F: Fn(&mut CPU)>(program: &[u8], setup: F, steps: usize) -> Vec<CPU> {
+        [StepMode::Instruction, StepMode::Cycle].iter().map(|&mode| {
+            let mut cpu = testing::build_program(program);
+            cpu.step_mode = mode;
+            setup(&mut cpu);
+            for _ in 0..steps {
+                cpu.emulate_cycle().unwrap();
+            }
+            cpu
+        }).collect()
+    }
+
+    #[test]
+    fn php_and_brk_push_b_and_bit_5() {
+        for cpu in after_steps(&[0x08], |_| {}, 1) {
+            assert_eq!(cpu.flags().bits(), 0x24);
+            assert_eq!((cpu.s(), cpu.memory().peek(0x01FD)), (0xFC, 0x34), "{:?}", cpu.step_mode);
+        }
+        // BRK pushes the address past its padding byte, then the flags
+        for cpu in after_steps(&[0x00, 0xEA], |cpu| cpu.set_flags(StatusFlags::from_bits(0xC3)), 1) {
+            assert_eq!((cpu.memory().peek(0x01FD), cpu.memory().peek(0x01FC)), (0x80, 0x02));
+            assert_eq!(cpu.memory().peek(0x01FB), 0xF3, "{:?}", cpu.step_mode);
+            assert_eq!(cpu.flags().bits(), 0xE7);
+        }
+    }
+
+    #[test]
+    fn plp_and_rti_keep_bit_5_and_drop_b() {
+        for &(stacked, flags) in [(0xFF, 0xEF), (0x00, 0x20), (0x10, 0x20)].iter() {
+            // PLP
+            let setup = |cpu: &mut CPU| cpu.memory_mut().storeb(0x01FE, stacked);
+            for cpu in after_steps(&[0x28], setup, 1) {
+                assert_eq!((cpu.flags().bits(), cpu.s()), (flags, 0xFE), "PLP ${:02X} {:?}", stacked, cpu.step_mode);
+            }
+            // RTI to $9234
+            let setup = |cpu: &mut CPU| {
+                cpu.set_s(0xFA);
+                cpu.memory_mut().storeb(0x01FB, stacked);
+                cpu.memory_mut().storeb(0x01FC, 0x34);
+                cpu.memory_mut().storeb(0x01FD, 0x92);
+            };
+            for cpu in after_steps(&[0x40], setup, 1) {
+                assert_eq!((cpu.flags().bits(), cpu.pc()), (flags, 0x9234), "RTI ${:02X} {:?}", stacked, cpu.step_mode);
+            }
+        }
+    }
+
+    #[test]
+    fn interrupts_push_b_clear() {
+        // An NMI from vblank and an IRQ from the APU frame counter, each copying the flags it
+        // stacked into $10 or $11. The main loop leaves S at $FD, so they're at $01FB.
+        let rom = testing::build_test_rom("
+reset:  LDA #$80
+        STA $2000
+        CLI
+loop:   JMP loop
+nmi:    LDA $01FB
+        STA $10
+        RTI
+irq:    LDA $01FB
+        STA $11
+        LDA $4015
+        RTI
+", None);
+        let mut nes = Nes::builder().rom_bytes(&rom).ppu_warmup(false).build().unwrap();
+        for _ in 0..3 {
+            nes.run_frame(InputFrame::default()).unwrap();
+        }
+        let memory = nes.cpu().memory();
+        for &saved in [0x10, 0x11].iter() {
+            let stacked = memory.peek(saved);
+            assert_eq!(stacked & 0x30, 0x20, "${:02X} from ${:02X}", stacked, saved);
+        }
+    }
+
+    #[test]
+    fn setters_change_what_runs() {
+        // ADC #$01 adds the carry in
+        for &(carry, sum) in [(false, 0x11), (true, 0x12)].iter() {
+            let mut cpu = testing::build_program(&[0x69, 0x01]);
+            cpu.set_a(0x10);
+            let mut flags = cpu.flags();
+            flags.set_carry(carry);
+            cpu.set_flags(flags);
+            assert_eq!(cpu.flags().carry(), carry);
+            cpu.emulate_cycle().unwrap();
+            assert_eq!(cpu.a(), sum);
+        }
+
+        // LDA $0200,X, LDA $0200,Y and PHP, from $8003 rather than the reset vector
+        let mut cpu = testing::build_program(&[0xEA, 0xEA, 0xEA, 0xBD, 0x00, 0x02, 0x85, 0x10, 0xB9, 0x00, 0x02, 0x08]);
+        for addr in 0x0200..0x0210 {
+            cpu.memory_mut().storeb(addr, addr as u8 | 0x80);
+        }
+        cpu.set_pc(0x8003);
+        cpu.set_x(0x05);
+        cpu.set_y(0x0A);
+        cpu.set_s(0x80);
+        for _ in 0..4 {
+            cpu.emulate_cycle().unwrap();
+        }
+        assert_eq!((cpu.memory().peek(0x10), cpu.a()), (0x85, 0x8A));
+        assert_eq!((cpu.s(), cpu.memory().peek(0x0180)), (0x7F, cpu.flags().bits() | 0x10));
+        assert_eq!(cpu.pc(), 0x800C);
+    }
+
+    #[test]
+    fn status_flags_name_each_bit() {
+        fn check(set: fn(&mut StatusFlags, bool), get: fn(&StatusFlags) -> bool, bit: u8) {
+            let mut flags = StatusFlags::from_bits(0x20);
+            set(&mut flags, true);
+            assert!(get(&flags));
+            assert_eq!(flags.bits(), 0x20 | bit);
+            set(&mut flags, false);
+            assert!(!get(&flags));
+            assert_eq!(flags.bits(), 0x20);
+        }
+        check(StatusFlags::set_carry, StatusFlags::carry, 0x01);
+        check(StatusFlags::set_zero, StatusFlags::zero, 0x02);
+        check(StatusFlags::set_interrupt_disable, StatusFlags::interrupt_disable, 0x04);
+        check(StatusFlags::set_decimal, StatusFlags::decimal, 0x08);
+        check(StatusFlags::set_overflow, StatusFlags::overflow, 0x40);
+        check(StatusFlags::set_negative, StatusFlags::negative, 0x80);
+    }
+
+    #[test]
+    fn state_spells_out_the_flags() {
+        let mut cpu = testing::build_program(&[]);
+        let cycles = cpu.cycles;
+        assert_eq!(cpu.dump_state().to_string(), format!("A:00 X:00 Y:00 S:FD PC:8000 P:nv-bdIzc CYC:{}", cycles));
+
+        cpu.set_a(0x12);
+        cpu.set_x(0x34);
+        cpu.set_y(0x56);
+        cpu.set_s(0x78);
+        cpu.set_pc(0x9ABC);
+        cpu.set_flags(StatusFlags::from_bits(0xC3));
+        let state = cpu.dump_state();
+        assert!(state.negative && state.overflow && state.zero && state.carry);
+        assert!(!state.break_flag && !state.decimal && !state.interrupt_disable);
+        assert_eq!(state.to_string(), format!("A:12 X:34 Y:56 S:78 PC:9ABC P:NV-bdiZC CYC:{}", cycles));
+    }
+
+    #[test]
+    fn dump_prg_stops_at_the_end_of_prg() {
+        let cpu = testing::build_program(&[0xA9, 0x42]);
+        assert_eq!(cpu.dump_prg(0xFFFA, 0x1000), "fffa:  00 00 00 80 00 00                                 |......|\n");
+        // Below PRG starts at PRG
+        assert_eq!(cpu.dump_prg(0x6000, 2), "8000:  a9 42                                             |.B|\n");
+        assert_eq!(cpu.dump_prg(0x8000, 0), "");
+        assert_eq!(cpu.dump_prg(0xFFFF, usize::MAX), "ffff:  00                                                |.|\n");
+    }
+
+    #[test]
+    fn dump_memory_reads_the_bus_and_clamps() {
+        let mut cpu = testing::build_program(&[]);
+        for (addr, &byte) in (0x0000..).zip(b"NES!".iter()) {
+            cpu.memory_mut().storeb(addr, byte);
+        }
+        // $0800 mirrors RAM
+        assert_eq!(cpu.dump_memory(0x0800, 0x0804), "0800:  4e 45 53 21                                       |NES!|\n");
+        // Cut off at the top of the address space, where the vectors are
+        assert_eq!(cpu.dump_memory(0xFFFC, 0x20000), "fffc:  00 80 00 00                                       |....|\n");
+        assert_eq!(cpu.dump_memory(0x0010, 0x0008), "");
+    }
+
+    #[test]
+    fn illegal_opcodes_stop_on_the_opcode() {
+        // LDA #$01, then JAM
+        let mut cpu = testing::build_program(&[0xA9, 0x01, 0x02]);
+        cpu.emulate_cycle().unwrap();
+        let err = cpu.emulate_cycle().unwrap_err();
+        assert_eq!(err, EmulationError::IllegalOpcode { pc: 0x8002, opcode: 0x02 });
+        assert_eq!(err.to_string(), "illegal or unimplemented opcode $02 at $8002");
+        assert_eq!(cpu.pc(), 0x8002);
+        // Stepping again gives the same error rather than running past it
+        assert_eq!(cpu.emulate_cycle(), Err(err));
+
+        let mut cpu = testing::build_program(&[0x02, 0xA9, 0x01]);
+        cpu.illegal_opcode_policy = IllegalOpcodePolicy::Nop;
+        assert_eq!(cpu.emulate_cycle(), Ok(2));
+        cpu.emulate_cycle().unwrap();
+        assert_eq!((cpu.pc(), cpu.a()), (0x8003, 0x01));
+    }
+
+    #[test]
+    fn writes_to_rom_are_ignored() {
+        // LDA #$55, STA $8000, STA $FFFC
+        let mut cpu = testing::build_program(&[0xA9, 0x55, 0x8D, 0x00, 0x80, 0x8D, 0xFC, 0xFF]);
+        for _ in 0..3 {
+            cpu.emulate_cycle().unwrap();
+        }
+        assert_eq!((cpu.memory().peek(0x8000), cpu.memory().peek(0xFFFC)), (0xA9, 0x00));
+    }
+
+    #[test]
+    fn addressing_wraps_instead_of_overflowing() {
+        // LDA #$42, STA $00, LDX #$01, LDA $FFFF,X, STA $10, LDA $FF,X, STA $11, LDX #$FF,
+        // LDA ($01,X) with the pointer at $00/$01 after wrapping, STA $12
+        let mut cpu = testing::build_program(&[
+            0xA9, 0x42, 0x85, 0x00, 0xA2, 0x01, 0xBD, 0xFF, 0xFF, 0x85, 0x10, 0xB5, 0xFF, 0x85, 0x11,
+            0xA2, 0xFF, 0xA1, 0x01, 0x85, 0x12,
+        ]);
+        for _ in 0..10 {
+            cpu.emulate_cycle().unwrap();
+        }
+        let memory = cpu.memory();
+        assert_eq!((memory.peek(0x10), memory.peek(0x11)), (0x42, 0x42));
+        // $01 + $FF is $00, which with $01 points at $0042
+        assert_eq!(memory.peek(0x12), memory.peek(0x0042));
+
+        // A push with the stack full wraps S around to $FF
+        let mut cpu = testing::build_program(&[0x08]);
+        cpu.set_s(0x00);
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.s(), 0xFF);
+    }
+
+    #[test]
+    fn unsupported_mapper_features_stop_the_machine() {
+        // MMC5 powers on with its last bank everywhere, so the program goes in the image's last
+        // 8 KiB and starts at $8000. LDA #$01, STA $5104 turns on extended attributes.
+        let mut image = testing::build_rom(&[]);
+        image[6] = 0x50;
+        image[16 + 0x6000..16 + 0x6005].copy_from_slice(&[0xA9, 0x01, 0x8D, 0x04, 0x51]);
+        let mut cpu = CPU::from_rom(rom::ROM::from_bytes(&image).unwrap());
+        cpu.power_on();
+        cpu.emulate_cycle().unwrap();
+        let err = cpu.emulate_cycle().unwrap_err();
+        assert_eq!(err, EmulationError::UnsupportedMapperFeature { feature: "MMC5 extended attribute mode" });
+        assert_eq!(err.to_string(), "the cartridge's MMC5 extended attribute mode isn't supported");
+    }
+
+    #[test]
+    fn the_debug_device_exits_with_the_code() {
+        let rom = testing::build_test_rom("
+        LDA #$03
+        STA $4019
+        LDA #$04
+        STA $4019
+", None);
+        let mut nes = Nes::builder().rom_bytes(&rom).debug_device(true).build().unwrap();
+        assert!(nes.step().unwrap().is_continue());
+        let err = nes.step().unwrap_err();
+        assert_eq!(err, EmulationError::Exited { code: 3 });
+        assert_eq!(err.to_string(), "the program exited with code 3");
+
+        // Without the device the write is just an unused register
+        let mut nes = Nes::builder().rom_bytes(&rom).build().unwrap();
+        for _ in 0..4 {
+            assert!(nes.step().unwrap().is_continue());
+        }
+    }
+
+    #[test]
+    fn step_modes_agree_over_a_whole_program() {
+        let rom = testing::build_test_rom(include_str!("../tests/roms/cpu_arith.s"), None);
+        let runs = [StepMode::Instruction, StepMode::Cycle].iter().map(|&mode| {
+            let mut cpu = CPU::from_rom(rom::ROM::from_bytes(&rom).unwrap());
+            cpu.step_mode = mode;
+            cpu.power_on();
+            let mut trace = Vec::new();
+            for _ in 0..500 {
+                let cycles = cpu.emulate_cycle().unwrap();
+                trace.push((cpu.pc(), cpu.a(), cpu.x(), cpu.y(), cpu.s(), cpu.flags().bits(), cycles, cpu.cycles));
+            }
+            (trace, cpu.memory().dump_range(0x0200, 0x100))
+        }).collect::<Vec<_>>();
+        // The program finished, in the same number of cycles and with the same results
+        assert_eq!(runs[0].1[0xFF], 0x01);
+        assert_eq!(runs[0].0, runs[1].0);
+        assert_eq!(runs[0].1, runs[1].1);
+    }
+
+    #[test]
+    fn indexed_store_dummy_reads_before_writing() {
+        // LDX #$20, STA $12F0,X
+        let program = [0xA2, 0x20, 0x9D, 0xF0, 0x12];
+        for &mode in [StepMode::Instruction, StepMode::Cycle].iter() {
+            let mut cpu = testing::build_program(&program);
+            cpu.step_mode = mode;
+            cpu.emulate_cycle().unwrap();
+            let log = Arc::new(Mutex::new(BusLog::default()));
+            cpu.memory_mut().set_observer(Box::new(log.clone()));
+
+            assert_eq!(cpu.emulate_cycle().unwrap(), 5, "{:?}", mode);
+            // The opcode and operand, then a read of $12F0 + $20 without the carry into the
+            // high byte, and the write where the carry lands
+            assert_eq!(log.lock().unwrap().0, vec![
+                (false, 0x8002), (false, 0x8003), (false, 0x8004), (false, 0x1210), (true, 0x1310),
+            ], "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn indexed_store_within_a_page_still_dummy_reads() {
+        // LDX #$05, STA $1200,X: no page crossed, but the same five cycles and the read at the
+        // address the write goes to
+        let program = [0xA2, 0x05, 0x9D, 0x00, 0x12];
+        for &mode in [StepMode::Instruction, StepMode::Cycle].iter() {
+            let mut cpu = testing::build_program(&program);
+            cpu.step_mode = mode;
+            cpu.emulate_cycle().unwrap();
+            let log = Arc::new(Mutex::new(BusLog::default()));
+            cpu.memory_mut().set_observer(Box::new(log.clone()));
+
+            assert_eq!(cpu.emulate_cycle().unwrap(), 5, "{:?}", mode);
+            assert_eq!(log.lock().unwrap().0[3..], [(false, 0x1205), (true, 0x1205)], "{:?}", mode);
+        }
+    }
+
+    // The bus accesses of the instruction after the first setup_steps of program, as
+    // (write, address, value), and the cycles it took, in both step modes
+    fn instruction_accesses(program: &[u8], setup_steps: usize) -> (Vec<(bool, u16, u8)>, u8) {
+        let mut results = Vec::new();
+        for &mode in [StepMode::Instruction, StepMode::Cycle].iter() {
+            let mut cpu = testing::build_program(program);
+            cpu.step_mode = mode;
+            for _ in 0..setup_steps {
+                cpu.emulate_cycle().unwrap();
+            }
+            cpu.memory_mut().enable_access_log();
+            let cycles = cpu.emulate_cycle().unwrap();
+            let log = cpu.memory_mut().take_access_log().iter()
+                .map(|access| (access.kind == AccessKind::Write, access.addr, access.value))
+                .collect::<Vec<_>>();
+            results.push((log, cycles));
+        }
+        assert_eq!(results[0], results[1], "instruction and cycle stepping disagree");
+        results.pop().unwrap()
+    }
+
+    // Just the addresses and directions
+    fn addresses(accesses: &[(bool, u16, u8)]) -> Vec<(bool, u16)> {
+        accesses.iter().map(|&(write, addr, _)| (write, addr)).collect()
+    }
+
+    #[test]
+    fn reads_pay_for_page_crossings_and_writes_always_do() {
+        let cycles = |program: &[u8], setup_steps| instruction_accesses(program, setup_steps).1;
+        // LDX, LDA $12F0,X
+        assert_eq!(cycles(&[0xA2, 0x0F, 0xBD, 0xF0, 0x12], 1), 4);
+        assert_eq!(cycles(&[0xA2, 0x10, 0xBD, 0xF0, 0x12], 1), 5);
+        // LDY, LDA $12F0,Y
+        assert_eq!(cycles(&[0xA0, 0x0F, 0xB9, 0xF0, 0x12], 1), 4);
+        assert_eq!(cycles(&[0xA0, 0x10, 0xB9, 0xF0, 0x12], 1), 5);
+        // LDA #$F0, STA $10, LDY, LDA ($10),Y
+        assert_eq!(cycles(&[0xA9, 0xF0, 0x85, 0x10, 0xA0, 0x0F, 0xB1, 0x10], 3), 5);
+        assert_eq!(cycles(&[0xA9, 0xF0, 0x85, 0x10, 0xA0, 0x10, 0xB1, 0x10], 3), 6);
+        // LDX, then STA $12F0,X and INC $12F0,X whether or not they cross
+        for &x in [0x0F, 0x10].iter() {
+            assert_eq!(cycles(&[0xA2, x, 0x9D, 0xF0, 0x12], 1), 5);
+            assert_eq!(cycles(&[0xA2, x, 0xFE, 0xF0, 0x12], 1), 7);
         }
-        dump.push_str(&format!("{:02x} ", byte));
-        addr += 1;
     }
-    dump.push_str(&"\n");
 
-    return dump;
+    #[test]
+    fn branches_pay_for_being_taken_and_crossing_a_page() {
+        let cycles = |program: &[u8], setup_steps| instruction_accesses(program, setup_steps).1;
+        // LDX #$01 clears Z. BNE +2 stays in the page, and BEQ isn't taken.
+        assert_eq!(cycles(&[0xA2, 0x01, 0xD0, 0x02], 1), 3);
+        assert_eq!(cycles(&[0xA2, 0x01, 0xF0, 0x02], 1), 2);
+        // BNE -4 back from $8004 to $8000
+        assert_eq!(cycles(&[0xA2, 0x01, 0xD0, 0xFC], 1), 3);
+        // JMP $80FA, then at $80FA LDX #$01, BNE +2 from $80FE to $8100
+        let mut program = vec![0xEA; 0x100];
+        program[..3].copy_from_slice(&[0x4C, 0xFA, 0x80]);
+        program[0xFA..0xFE].copy_from_slice(&[0xA2, 0x01, 0xD0, 0x02]);
+        assert_eq!(cycles(&program, 2), 4);
+        // while BNE -14 from $80FE to $80F0 stays in the page
+        program[0xFD] = 0xF2;
+        assert_eq!(cycles(&program, 2), 3);
+    }
+
+    #[test]
+    fn indexed_load_dummy_reads_only_across_a_page() {
+        // LDX #$20, LDA $12F0,X
+        let (accesses, cycles) = instruction_accesses(&[0xA2, 0x20, 0xBD, 0xF0, 0x12], 1);
+        assert_eq!(cycles, 5);
+        assert_eq!(addresses(&accesses), [(false, 0x8002), (false, 0x8003), (false, 0x8004), (false, 0x1210), (false, 0x1310)]);
+
+        // LDX #$05, LDA $1200,X
+        let (accesses, cycles) = instruction_accesses(&[0xA2, 0x05, 0xBD, 0x00, 0x12], 1);
+        assert_eq!(cycles, 4);
+        assert_eq!(addresses(&accesses), [(false, 0x8002), (false, 0x8003), (false, 0x8004), (false, 0x1205)]);
+    }
+
+    #[test]
+    fn indirect_indexed_load_dummy_reads_across_a_page() {
+        // LDA #$F0, STA $10, LDA #$12, STA $11, LDY #$20, LDA ($10),Y
+        let program = [0xA9, 0xF0, 0x85, 0x10, 0xA9, 0x12, 0x85, 0x11, 0xA0, 0x20, 0xB1, 0x10];
+        let (accesses, cycles) = instruction_accesses(&program, 5);
+        assert_eq!(cycles, 6);
+        assert_eq!(addresses(&accesses), [
+            (false, 0x800A), (false, 0x800B), (false, 0x0010), (false, 0x0011), (false, 0x1210), (false, 0x1310),
+        ]);
+    }
+
+    #[test]
+    fn read_modify_write_writes_twice() {
+        // LDA #$41, STA $0300, ASL $0300
+        let program = [0xA9, 0x41, 0x8D, 0x00, 0x03, 0x0E, 0x00, 0x03];
+        let (accesses, cycles) = instruction_accesses(&program, 2);
+        assert_eq!(cycles, 6);
+        assert_eq!(accesses[3..], [(false, 0x0300, 0x41), (true, 0x0300, 0x41), (true, 0x0300, 0x82)]);
+
+        // LDA #$7F, STA $10, INC $10
+        let (accesses, cycles) = instruction_accesses(&[0xA9, 0x7F, 0x85, 0x10, 0xE6, 0x10], 2);
+        assert_eq!(cycles, 5);
+        assert_eq!(accesses, [
+            (false, 0x8004, 0xE6), (false, 0x8005, 0x10), (false, 0x0010, 0x7F), (true, 0x0010, 0x7F), (true, 0x0010, 0x80),
+        ]);
+    }
+
+    #[test]
+    fn indexed_read_modify_write_always_dummy_reads() {
+        // LDX #$01, LDA #$01, STA $0300, DEC $02FF,X
+        let program = [0xA2, 0x01, 0xA9, 0x01, 0x8D, 0x00, 0x03, 0xDE, 0xFF, 0x02];
+        let (accesses, cycles) = instruction_accesses(&program, 3);
+        assert_eq!(cycles, 7);
+        assert_eq!(addresses(&accesses[3..4]), [(false, 0x0200)]);
+        assert_eq!(accesses[4..], [(false, 0x0300, 0x01), (true, 0x0300, 0x01), (true, 0x0300, 0x00)]);
+
+        // LDX #$01, ROL $0300,X within the page: still seven cycles and a dummy read
+        let (accesses, cycles) = instruction_accesses(&[0xA2, 0x01, 0x3E, 0x00, 0x03], 1);
+        assert_eq!(cycles, 7);
+        assert_eq!(addresses(&accesses[3..]), [(false, 0x0301), (false, 0x0301), (true, 0x0301), (true, 0x0301)]);
+    }
+
+    // Raises IRQs from the DMC, when its one-byte sample ends, and from the APU frame counter,
+    // with I set. Once both are pending the test moves on from spin to clear, flip or pull.
+    // The handler counts its entries in $10 and saves $11, how far the main code got, in $12.
+    const TWO_IRQS: &str = "
+reset:  SEI
+        LDA #$80
+        STA $4010
+        LDA #$00
+        STA $4013
+        LDA #$10
+        STA $4015
+        LDA #$00
+        STA $4017
+spin:   JMP spin
+clear:  CLI
+        INC $11
+        INC $11
+idle:   JMP idle
+flip:   CLI
+        SEI
+        INC $11
+        JMP idle
+pull:   PHP
+        CLI
+        PLP
+        INC $11
+        JMP idle
+irq:    INC $10
+        LDA $11
+        STA $12
+        LDA $10
+        CMP #1
+        BNE frame
+        LDA #$00
+        STA $4010
+        RTI
+frame:  LDA $4015
+        RTI
+";
+
+    // Runs TWO_IRQS from label once both IRQs are pending, then its memory at $10-$12 and
+    // whether the line is still asserted
+    fn run_two_irqs(label: &str, steps: usize) -> ([u8; 3], bool) {
+        let labels = asm::assemble_with_labels(TWO_IRQS, 0x8000).unwrap().1;
+        let mut cpu = CPU::from_rom(ROM::from_bytes(&testing::build_test_rom(TWO_IRQS, None)).unwrap());
+        cpu.power_on();
+        let both = |cpu: &CPU| {
+            let irq = &cpu.memory().irq;
+            irq.is_asserted_by(IrqSource::Dmc) && irq.is_asserted_by(IrqSource::FrameCounter)
+        };
+        while !both(&cpu) {
+            cpu.emulate_cycle().unwrap();
+        }
+        assert_eq!(cpu.pc(), labels["spin"]);
+        cpu.set_pc(labels[label]);
+        for _ in 0..steps {
+            cpu.emulate_cycle().unwrap();
+        }
+        let memory = cpu.memory();
+        ([memory.peek(0x10), memory.peek(0x11), memory.peek(0x12)], memory.irq.is_asserted())
+    }
+
+    #[test]
+    fn irq_handler_reenters_until_every_source_is_acknowledged() {
+        // The handler only acknowledges one source at a time, so it runs twice
+        let (memory, asserted) = run_two_irqs("clear", 100);
+        assert_eq!(memory[0], 2);
+        assert!(!asserted);
+    }
+
+    #[test]
+    fn irqs_wait_an_instruction_after_cli_sei_and_plp() {
+        // After CLI one more instruction runs before the IRQ is taken
+        assert_eq!(run_two_irqs("clear", 100).0, [2, 2, 1]);
+        // CLI then SEI lets the IRQ in once, after the SEI, and then keeps it out
+        assert_eq!(run_two_irqs("flip", 100), ([1, 1, 0], true));
+        // PLP putting I back does the same
+        assert_eq!(run_two_irqs("pull", 100), ([1, 1, 0], true));
+    }
+
+    #[test]
+    fn trace_lines_have_nestest_columns() {
+        let mut cpu = testing::build_program(&[0xA2, 0x05, 0xEA]);
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let log = lines.clone();
+        cpu.set_trace_hook(Box::new(move |event| log.lock().unwrap().push(event.to_string())));
+        cpu.emulate_cycle().unwrap();
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(*lines.lock().unwrap(), [
+            "8000  A2  LDX  A:00 X:00 Y:00 P:24 SP:FD PPU:  0,  0 CYC:0",
+            "8002  EA  NOP  A:00 X:05 Y:00 P:24 SP:FD PPU:  0,  6 CYC:2",
+        ]);
+    }
+
+    #[test]
+    fn deltas_carry_only_the_pages_written() {
+        // INC $0300 forever
+        let mut cpu = testing::build_program(&[0xEE, 0x00, 0x03, 0x4C, 0x00, 0x80]);
+        let base = cpu.save_snapshot().unwrap();
+        for _ in 0..100 {
+            cpu.emulate_cycle().unwrap();
+        }
+        assert_eq!(cpu.memory_mut().take_dirty_pages(), state::DirtyPages { ram: 1 << 3, ..state::DirtyPages::default() });
+
+        for _ in 0..100 {
+            cpu.emulate_cycle().unwrap();
+        }
+        let delta = cpu.save_delta(&base).unwrap();
+        assert_eq!(delta.ram.iter().map(|page| page.index).collect::<Vec<_>>(), [3]);
+        assert!(delta.prg_ram.is_empty() && delta.vram.is_empty() && delta.mapper.is_empty());
+        assert_eq!(delta.page_bytes(), 256);
+        let full = cpu.save_state().unwrap();
+        assert_eq!(delta.apply(&base).unwrap(), full);
+
+        // A chain of deltas, each on the last one applied, rebuilds the state as it is now
+        let mut state = full;
+        for _ in 0..3 {
+            for _ in 0..1000 {
+                cpu.emulate_cycle().unwrap();
+            }
+            state = cpu.save_delta(&state).unwrap().apply(&state).unwrap();
+        }
+        assert_eq!(state, cpu.save_state().unwrap());
+        // Every other instruction is the INC
+        assert_eq!(state.ram[0x300], (1600 % 256) as u8);
+
+        // Loading a state replaces everything
+        cpu.load_state(&base).unwrap();
+        assert_eq!(cpu.memory_mut().take_dirty_pages(), state::DirtyPages::all());
+    }
 }
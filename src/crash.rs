@@ -0,0 +1,253 @@
+// What's left behind when the emulator panics: a bundle of files in a temp directory with
+// the last instructions run, RAM, the cartridge's identity and the panic itself, for attaching
+// to a bug report.
+//
+// The CPU always keeps the last TRACE_LEN instructions in a TraceRing. Each one is a fixed
+// 8-byte record written with no branches or allocation, so it's cheap enough to leave on.
+
+use cpu::CPU;
+use hash;
+
+use std::backtrace::Backtrace;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Instructions kept, a power of two so the index wraps by masking
+pub const TRACE_LEN: usize = 256;
+
+// The files a bundle is made of
+pub const PANIC_FILE: &str = "panic.txt";
+pub const TRACE_FILE: &str = "trace.txt";
+pub const TRACE_BIN_FILE: &str = "trace.bin";
+pub const RAM_FILE: &str = "ram.bin";
+pub const ROM_FILE: &str = "rom.txt";
+
+// The registers just before an instruction ran. In trace.bin each is 8 bytes in field order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TraceRecord {
+    pub pc: u16,
+    pub opcode: u8,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub s: u8,
+    pub flags: u8,
+}
+
+impl TraceRecord {
+    pub const SIZE: usize = 8;
+
+    pub fn to_bytes(&self) -> [u8; TraceRecord::SIZE] {
+        [self.pc as u8, (self.pc >> 8) as u8, self.opcode, self.a, self.x, self.y, self.s, self.flags]
+    }
+
+    pub fn from_bytes(data: &[u8; TraceRecord::SIZE]) -> TraceRecord {
+        TraceRecord {
+            pc: data[0] as u16 | (data[1] as u16) << 8,
+            opcode: data[2],
+            a: data[3],
+            x: data[4],
+            y: data[5],
+            s: data[6],
+            flags: data[7],
+        }
+    }
+}
+
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04X}  {:02X}  A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
+               self.pc, self.opcode, self.a, self.x, self.y, self.flags, self.s)
+    }
+}
+
+// The last TRACE_LEN instructions, overwritten oldest first
+pub struct TraceRing {
+    records: Box<[TraceRecord; TRACE_LEN]>,
+    // Instructions recorded so far
+    count: u64,
+}
+
+impl Default for TraceRing {
+    fn default() -> TraceRing { TraceRing::new() }
+}
+
+impl TraceRing {
+    pub fn new() -> TraceRing {
+        TraceRing {
+            records: Box::new([TraceRecord::default(); TRACE_LEN]),
+            count: 0,
+        }
+    }
+
+    #[inline]
+    pub fn record(&mut self, record: TraceRecord) {
+        self.records[self.count as usize & (TRACE_LEN - 1)] = record;
+        self.count = self.count.wrapping_add(1);
+    }
+
+    pub fn len(&self) -> usize {
+        self.count.min(TRACE_LEN as u64) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    // Oldest first
+    pub fn records(&self) -> Vec<TraceRecord> {
+        let start = self.count as usize - self.len();
+        (start..self.count as usize).map(|i| self.records[i & (TRACE_LEN - 1)]).collect()
+    }
+}
+
+// What the panic hook saw, waiting for write_bundle
+static LAST_PANIC: Mutex<Option<String>> = Mutex::new(None);
+
+// Keep each panic's message, location and backtrace for write_bundle, then report it as usual
+pub fn install_panic_hook() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let report = format!("{}\n\nBacktrace:\n{}", info, Backtrace::force_capture());
+        if let Ok(mut last) = LAST_PANIC.lock() {
+            *last = Some(report);
+        }
+        default_hook(info);
+    }));
+}
+
+// The report of the most recent panic, if the hook has seen one
+pub fn take_panic_report() -> Option<String> {
+    LAST_PANIC.lock().ok().and_then(|mut last| last.take())
+}
+
+// A new, empty bundle directory under the system's temp directory
+pub fn bundle_dir() -> io::Result<PathBuf> {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let dir = std::env::temp_dir().join(format!("nes-crash-{}-{}", secs, process::id()));
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+// Write everything worth knowing about the machine and the panic into dir
+pub fn write_bundle(dir: &Path, cpu: &CPU, panic_report: &str) -> io::Result<()> {
+    let memory = cpu.memory();
+    fs::write(dir.join(PANIC_FILE), format!("{}\n\nCPU: {}\n", panic_report, cpu.dump_state()))?;
+
+    let records = cpu.recent_trace().records();
+    let mut text = String::new();
+    let mut bin = Vec::with_capacity(records.len() * TraceRecord::SIZE);
+    for record in records.iter() {
+        text.push_str(&format!("{}\n", record));
+        bin.extend_from_slice(&record.to_bytes());
+    }
+    fs::write(dir.join(TRACE_FILE), text)?;
+    fs::write(dir.join(TRACE_BIN_FILE), bin)?;
+
+    fs::write(dir.join(RAM_FILE), &memory.ram_snapshot()[..])?;
+    let rom = &memory.rom;
+    fs::write(dir.join(ROM_FILE), format!("{}\nHeader:      {}\nMD5:         {}\n",
+                                          rom.summary(), hash::hex(&rom.header.to_bytes()), hash::hex(&rom.md5())))?;
+    Ok(())
+}
+
+// Read a trace.bin back, oldest first
+pub fn read_trace(data: &[u8]) -> Option<Vec<TraceRecord>> {
+    if !data.len().is_multiple_of(TraceRecord::SIZE) {
+        return None
+    }
+    Some(data.chunks_exact(TraceRecord::SIZE).map(|chunk| {
+        let mut bytes = [0; TraceRecord::SIZE];
+        bytes.copy_from_slice(chunk);
+        TraceRecord::from_bytes(&bytes)
+    }).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mem::BusObserver;
+    use testing;
+
+    use std::panic::AssertUnwindSafe;
+
+    // Stands in for a bug somewhere on the bus: panics when the CPU reads addr
+    struct PanicOnRead(u16);
+
+    impl BusObserver for PanicOnRead {
+        fn on_read(&mut self, addr: u16, _val: u8) {
+            if addr == self.0 {
+                panic!("deliberate panic reading ${:04X}", addr);
+            }
+        }
+        fn on_write(&mut self, _addr: u16, _val: u8) {}
+    }
+
+    #[test]
+    fn trace_ring_keeps_the_newest() {
+        let mut ring = TraceRing::new();
+        assert!(ring.is_empty());
+        for pc in 0..300u16 {
+            ring.record(TraceRecord { pc: pc, ..TraceRecord::default() });
+        }
+        let records = ring.records();
+        assert_eq!(ring.len(), TRACE_LEN);
+        assert_eq!((records[0].pc, records[TRACE_LEN - 1].pc), (300 - TRACE_LEN as u16, 299));
+    }
+
+    #[test]
+    fn panic_leaves_a_bundle_that_reads_back() {
+        // Count X to 200, three instructions a trip, then read $0300
+        let program = [
+            0xA2, 0x00,             // LDX #$00
+            0xE8,                   // loop: INX
+            0x86, 0x10,             // STX $10
+            0xE0, 0xC8,             // CPX #$C8
+            0xD0, 0xF9,             // BNE loop
+            0xAD, 0x00, 0x03,       // LDA $0300
+        ];
+        let mut cpu = testing::build_program(&program);
+        cpu.memory_mut().set_observer(Box::new(PanicOnRead(0x0300)));
+        install_panic_hook();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| loop {
+            cpu.emulate_cycle().unwrap();
+        }));
+        assert!(result.is_err());
+        let report = take_panic_report().unwrap();
+        assert!(report.contains("deliberate panic reading $0300"), "{}", report);
+
+        let dir = std::env::temp_dir().join(format!("nes-crash-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        write_bundle(&dir, &cpu, &report).unwrap();
+
+        let panic_text = fs::read_to_string(dir.join(PANIC_FILE)).unwrap();
+        assert!(panic_text.contains("deliberate panic") && panic_text.contains("Backtrace:"));
+
+        // The last 256 instructions, ending on the LDA that panicked
+        let trace = read_trace(&fs::read(dir.join(TRACE_BIN_FILE)).unwrap()).unwrap();
+        assert_eq!(trace.len(), TRACE_LEN);
+        assert_eq!(trace, cpu.recent_trace().records());
+        let last = trace[TRACE_LEN - 1];
+        assert_eq!((last.pc, last.opcode, last.x), (0x8009, 0xAD, 0xC8));
+        assert_eq!((trace[TRACE_LEN - 2].pc, trace[TRACE_LEN - 2].opcode), (0x8007, 0xD0));
+        let text = fs::read_to_string(dir.join(TRACE_FILE)).unwrap();
+        let lines: Vec<String> = trace.iter().map(|record| record.to_string()).collect();
+        assert_eq!(text.lines().collect::<Vec<_>>(), lines);
+
+        let ram = fs::read(dir.join(RAM_FILE)).unwrap();
+        assert_eq!(ram.len(), 0x800);
+        assert_eq!(ram[0x10], 0xC8);
+        let rom = fs::read_to_string(dir.join(ROM_FILE)).unwrap();
+        assert!(rom.contains(&hash::hex(&cpu.memory().rom.md5())), "{}", rom);
+
+        // Half a record isn't a trace
+        assert_eq!(read_trace(&[0; TraceRecord::SIZE + 4]), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,54 @@
+// A debug device for homebrew test programs, wired into two of the CPU test registers that
+// nothing on a retail console answers to. It's off unless the builder or --debug-device turns
+// it on, and then:
+//
+//   $4018  a write appends the byte to the output, as a character
+//   $4019  a write stops emulation with EmulationError::Exited, the byte being the exit code
+//
+// So a test prints with `LDA #'H' / STA $4018` and finishes with `LDA #0 / STA $4019`. Reads
+// of both are unaffected.
+
+pub const OUTPUT: u16 = 0x4018;
+pub const EXIT: u16 = 0x4019;
+
+#[derive(Debug, Clone, Default)]
+pub struct DebugDevice {
+    output: String,
+    exit_code: Option<u8>,
+}
+
+impl DebugDevice {
+    pub fn new() -> DebugDevice {
+        DebugDevice::default()
+    }
+
+    // A write to $4000-$401F. False if it isn't one of the device's addresses.
+    pub fn write(&mut self, addr: u16, val: u8) -> bool {
+        match addr {
+            OUTPUT => self.output.push(val as char),
+            // The first exit sticks, so the code is the one the program stopped with
+            EXIT => { self.exit_code.get_or_insert(val); },
+            _ => return false,
+        }
+        return true
+    }
+
+    // Everything written to $4018 since power-on, bytes taken as Latin-1
+    pub fn output(&self) -> &str {
+        &self.output
+    }
+
+    // The byte written to $4019, once the program has exited
+    pub fn exit_code(&self) -> Option<u8> {
+        self.exit_code
+    }
+
+    // What Memory::describe calls the device's addresses
+    pub fn register_name(addr: u16) -> Option<&'static str> {
+        match addr {
+            OUTPUT => Some("debug device output"),
+            EXIT => Some("debug device exit"),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,1361 @@
+// Breakpoints and watch expressions. Expressions are checked between instructions against the
+// CPU's registers and memory as the next instruction would see them:
+//   A X Y S P PC       registers
+//   C Z I D V N        flags, 1 if set
+//   [$10]  [[$FE]]     the byte at an address, and the little-endian word at one
+//   $3F 0x3F 63 %101   numbers
+//   + -  == != < <= > >=  !  &&  ||  ( )
+//   main_loop          a loaded symbol's address, for names that aren't registers or flags
+// Precedence is C's: ! binds tightest, then + -, comparisons, ==/!=, && and ||. Names are case
+// insensitive. Memory reads go through peek, so watching a register like $2002 doesn't disturb it.
+//
+// A breakpoint is an address, a condition or both: "$C123", "$C123 if A == $3F",
+// "if [$10] > 3". "changes EXPR" instead breaks whenever EXPR's value changes, e.g.
+// "changes [[$FE]]". The address can be BANK:ADDR, "3:$8123", to only break while that PRG
+// bank is mapped there, or a symbol, which brings its bank along if it has one.
+//
+// Monitor commands look at the machine and drive it, one per line:
+//   map EXPR         what the address EXPR evaluates to is wired to, e.g. "map $2002" or "map PC"
+//   maps             the whole memory map as it's banked right now
+//   mem EXPR[, LEN]  LEN bytes (16 by default) from EXPR, read without side effects
+//   regs             the CPU's registers
+//   break SPEC       add a breakpoint, written as above
+//   sym NAME|EXPR    where a symbol is, or the symbol nearest the address EXPR evaluates to
+//   watch EXPR       add a watch expression
+//   step [N]         run N instructions, 1 by default
+//   asm EXPR CODE    assemble CODE at EXPR and write it there through the bus, as the CPU
+//                    would. Statements are separated by |, e.g. "asm $0300 LDX #5 | loop: DEX |
+//                    BNE loop", and the listing shows what was written back.
+//   run [FRAMES]     run until a breakpoint fires, or for at most FRAMES frames
+//   diff [ram|vram|oam]
+//                    the first time, snapshot memory, RAM unless another space is given. After
+//                    that, list the candidates that changed, old -> new, and keep only them.
+//                    Naming a space starts over in it.
+//   diff watch VAL   keep the candidates that now hold VAL, and list them
+//   source FILE      run the commands in FILE. Blank lines and lines starting with # are skipped.
+//   layers           which layers the picture shows
+//   layers L on|off  show or hide layer L, bg or sprites, or turn tint on to draw each sprite
+//                    in a color for its OAM entry. Only the picture changes, not the game.
+//   pal              palette RAM, one sub-palette per line, with each entry's color
+//   pal set IDX VAL  change palette entry IDX, 0-31, to color VAL, as a write to $3F00 + IDX
+//                    would, so entry $10 is the backdrop at $00
+//   channels         which sound channels the mixer hears
+//   channels C on|off
+//                    unmute or mute channel C: pulse1, pulse2, triangle, noise or dmc. The
+//                    channel keeps running, so $4015 reads the same.
+//   sprites          how many sprites each line had in the last whole frame
+//   sprites LINE     the sprites on LINE in the last whole frame, in OAM order. Past the
+//                    first 8 are marked dropped.
+//   oam              all 64 OAM entries, decoded
+//   slots            the save-state slots in use, with the frame and time each was saved
+//   save SLOT        save the machine to a slot
+//   load SLOT        load a slot
+// The slot commands need the serde feature and a state_manager to have been set.
+
+use apu::Channel;
+use asm::{self, AsmError};
+use cpu::{EmulationError, CPU};
+use mem::Addressable;
+use opcodes;
+use ppu::{Layer, SpriteInfo, SCREEN_HEIGHT};
+use registers::{CARRY_FLAG, DEC_FLAG, INT_FLAG, NEG_FLAG, OVERFLOW_FLAG, ZERO_FLAG};
+use search::{MemorySearch, SearchSpace};
+#[cfg(feature = "serde")]
+use slots::{SlotError, StateManager};
+use symbols::SymbolTable;
+use util;
+
+use std::fmt;
+use std::fmt::Write;
+use std::fs;
+
+// How many candidates diff watch lists before it only counts them
+const MAX_LISTED_CANDIDATES: usize = 16;
+
+// How deep source can nest, so a file that sources itself fails instead of overflowing
+const MAX_SOURCE_DEPTH: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExprErrorKind {
+    UnexpectedChar(char),
+    UnexpectedEnd,
+    // A token that was missing
+    Expected(&'static str),
+    UnknownName(String),
+    BadNumber(String),
+    Empty,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExprError {
+    // Counting from 1
+    pub column: usize,
+    pub kind: ExprErrorKind,
+}
+
+impl fmt::Display for ExprErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExprErrorKind::UnexpectedChar(c) => write!(f, "unexpected {:?}", c),
+            ExprErrorKind::UnexpectedEnd => write!(f, "expression ends early"),
+            ExprErrorKind::Expected(what) => write!(f, "expected {}", what),
+            ExprErrorKind::UnknownName(ref name) => write!(f, "{} isn't a register, flag or symbol", name),
+            ExprErrorKind::BadNumber(ref n) => write!(f, "bad number {}", n),
+            ExprErrorKind::Empty => write!(f, "empty expression"),
+        }
+    }
+}
+
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "column {}: {}", self.column, self.kind)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandError {
+    Unknown(String),
+    // How the command should have been used
+    Usage(&'static str),
+    Expr(ExprError),
+    Emulation(EmulationError),
+    Io { path: String, message: String },
+    SourceTooDeep,
+    // A command in a script failed, at this line counting from 1
+    Script { path: String, line: usize, error: Box<CommandError> },
+    // A save-state slot couldn't be listed, saved or loaded
+    Slots(String),
+    // asm's code didn't assemble. The line is the statement, counting from 1.
+    Asm(AsmError),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CommandError::Unknown(ref name) => write!(f, "unknown command {}", name),
+            CommandError::Usage(usage) => write!(f, "usage: {}", usage),
+            CommandError::Expr(ref e) => write!(f, "{}", e),
+            CommandError::Emulation(ref e) => write!(f, "CPU stopped: {}", e),
+            CommandError::Io { ref path, ref message } => write!(f, "can't read {}: {}", path, message),
+            CommandError::SourceTooDeep => write!(f, "source nested more than {} deep", MAX_SOURCE_DEPTH),
+            CommandError::Script { ref path, line, ref error } => write!(f, "{} line {}: {}", path, line, error),
+            CommandError::Slots(ref message) => write!(f, "{}", message),
+            CommandError::Asm(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<ExprError> for CommandError {
+    fn from(e: ExprError) -> CommandError {
+        CommandError::Expr(e)
+    }
+}
+
+impl From<AsmError> for CommandError {
+    fn from(e: AsmError) -> CommandError {
+        CommandError::Asm(e)
+    }
+}
+
+impl From<EmulationError> for CommandError {
+    fn from(e: EmulationError) -> CommandError {
+        CommandError::Emulation(e)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<SlotError> for CommandError {
+    fn from(e: SlotError) -> CommandError {
+        CommandError::Slots(e.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    A,
+    X,
+    Y,
+    S,
+    P,
+    PC,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Number(u32),
+    Register(Register),
+    // One of the *_FLAG bits
+    Flag(u8),
+    Byte(Box<Expr>),
+    Word(Box<Expr>),
+    Not(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Expr, ExprError> {
+        Expr::parse_with(text, &SymbolTable::new())
+    }
+
+    // Names that aren't registers or flags are looked up in symbols
+    pub fn parse_with(text: &str, symbols: &SymbolTable) -> Result<Expr, ExprError> {
+        let mut parser = Parser { chars: text.chars().collect(), pos: 0, symbols: symbols };
+        parser.skip_space();
+        if parser.pos == parser.chars.len() {
+            return Err(parser.error(ExprErrorKind::Empty))
+        }
+        let expr = parser.or()?;
+        parser.skip_space();
+        match parser.peek() {
+            None => Ok(expr),
+            Some(c) => Err(parser.error(ExprErrorKind::UnexpectedChar(c))),
+        }
+    }
+
+    // Comparisons and boolean operators give 1 or 0. Arithmetic wraps.
+    pub fn eval(&self, cpu: &CPU) -> u32 {
+        match *self {
+            Expr::Number(n) => n,
+            Expr::Register(reg) => match reg {
+                Register::A => cpu.a() as u32,
+                Register::X => cpu.x() as u32,
+                Register::Y => cpu.y() as u32,
+                Register::S => cpu.s() as u32,
+                Register::P => cpu.flags().bits() as u32,
+                Register::PC => cpu.pc() as u32,
+            },
+            Expr::Flag(flag) => (cpu.flags().bits() & flag != 0) as u32,
+            Expr::Byte(ref addr) => cpu.memory().peek(addr.eval(cpu) as u16) as u32,
+            Expr::Word(ref addr) => {
+                let addr = addr.eval(cpu) as u16;
+                let memory = cpu.memory();
+                memory.peek(addr) as u32 | (memory.peek(addr.wrapping_add(1)) as u32) << 8
+            },
+            Expr::Not(ref e) => (e.eval(cpu) == 0) as u32,
+            Expr::Binary(op, ref lhs, ref rhs) => {
+                let lhs = lhs.eval(cpu);
+                // && and || short-circuit, so a guard can keep a read from happening
+                match op {
+                    BinaryOp::And if lhs == 0 => return 0,
+                    BinaryOp::Or if lhs != 0 => return 1,
+                    _ => {},
+                }
+                let rhs = rhs.eval(cpu);
+                match op {
+                    BinaryOp::Add => lhs.wrapping_add(rhs),
+                    BinaryOp::Sub => lhs.wrapping_sub(rhs),
+                    BinaryOp::Eq => (lhs == rhs) as u32,
+                    BinaryOp::Ne => (lhs != rhs) as u32,
+                    BinaryOp::Lt => (lhs < rhs) as u32,
+                    BinaryOp::Le => (lhs <= rhs) as u32,
+                    BinaryOp::Gt => (lhs > rhs) as u32,
+                    BinaryOp::Ge => (lhs >= rhs) as u32,
+                    BinaryOp::And | BinaryOp::Or => (rhs != 0) as u32,
+                }
+            },
+        }
+    }
+}
+
+// Recursive descent, one function per precedence level
+struct Parser<'a> {
+    chars: Vec<char>,
+    pos: usize,
+    symbols: &'a SymbolTable,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, kind: ExprErrorKind) -> ExprError {
+        ExprError { column: self.pos + 1, kind: kind }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).cloned()
+    }
+
+    fn skip_space(&mut self) {
+        while self.peek().is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    // Consume token if it's next
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_space();
+        let end = self.pos + token.len();
+        if end <= self.chars.len() && self.chars[self.pos..end].iter().cloned().eq(token.chars()) {
+            self.pos = end;
+            return true
+        }
+        false
+    }
+
+    fn expect(&mut self, token: &'static str) -> Result<(), ExprError> {
+        if self.eat(token) {
+            return Ok(())
+        }
+        Err(self.error(match self.peek() {
+            None => ExprErrorKind::UnexpectedEnd,
+            Some(_) => ExprErrorKind::Expected(token),
+        }))
+    }
+
+    fn binary(op: BinaryOp, lhs: Expr, rhs: Expr) -> Expr {
+        Expr::Binary(op, Box::new(lhs), Box::new(rhs))
+    }
+
+    fn or(&mut self) -> Result<Expr, ExprError> {
+        let mut lhs = self.and()?;
+        while self.eat("||") {
+            lhs = Parser::binary(BinaryOp::Or, lhs, self.and()?);
+        }
+        Ok(lhs)
+    }
+
+    fn and(&mut self) -> Result<Expr, ExprError> {
+        let mut lhs = self.equality()?;
+        while self.eat("&&") {
+            lhs = Parser::binary(BinaryOp::And, lhs, self.equality()?);
+        }
+        Ok(lhs)
+    }
+
+    fn equality(&mut self) -> Result<Expr, ExprError> {
+        let mut lhs = self.comparison()?;
+        loop {
+            let op = if self.eat("==") {
+                BinaryOp::Eq
+            } else if self.eat("!=") {
+                BinaryOp::Ne
+            } else {
+                return Ok(lhs)
+            };
+            lhs = Parser::binary(op, lhs, self.comparison()?);
+        }
+    }
+
+    fn comparison(&mut self) -> Result<Expr, ExprError> {
+        let mut lhs = self.sum()?;
+        loop {
+            // The two-character operators first, so < doesn't take the start of <=
+            let op = if self.eat("<=") {
+                BinaryOp::Le
+            } else if self.eat(">=") {
+                BinaryOp::Ge
+            } else if self.eat("<") {
+                BinaryOp::Lt
+            } else if self.eat(">") {
+                BinaryOp::Gt
+            } else {
+                return Ok(lhs)
+            };
+            lhs = Parser::binary(op, lhs, self.sum()?);
+        }
+    }
+
+    fn sum(&mut self) -> Result<Expr, ExprError> {
+        let mut lhs = self.unary()?;
+        loop {
+            let op = if self.eat("+") {
+                BinaryOp::Add
+            } else if self.eat("-") {
+                BinaryOp::Sub
+            } else {
+                return Ok(lhs)
+            };
+            lhs = Parser::binary(op, lhs, self.unary()?);
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, ExprError> {
+        // Not the start of !=, which can't begin an operand anyway
+        if self.eat("!") {
+            return Ok(Expr::Not(Box::new(self.unary()?)))
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, ExprError> {
+        if self.eat("[[") {
+            let addr = self.or()?;
+            self.expect("]]")?;
+            return Ok(Expr::Word(Box::new(addr)))
+        }
+        if self.eat("[") {
+            let addr = self.or()?;
+            self.expect("]")?;
+            return Ok(Expr::Byte(Box::new(addr)))
+        }
+        if self.eat("(") {
+            let e = self.or()?;
+            self.expect(")")?;
+            return Ok(e)
+        }
+
+        self.skip_space();
+        let start = self.pos;
+        let c = match self.peek() {
+            Some(c) => c,
+            None => return Err(self.error(ExprErrorKind::UnexpectedEnd)),
+        };
+        if !(c.is_ascii_alphanumeric() || c == '_' || c == '$' || c == '%') {
+            return Err(self.error(ExprErrorKind::UnexpectedChar(c)))
+        }
+        self.pos += 1;
+        while self.peek().is_some_and(|c| c.is_ascii_alphanumeric() || c == '_') {
+            self.pos += 1;
+        }
+        let word: String = self.chars[start..self.pos].iter().collect();
+        let at_start = |kind| ExprError { column: start + 1, kind: kind };
+
+        if c.is_ascii_digit() || c == '$' || c == '%' {
+            return parse_number(&word).map(Expr::Number).ok_or_else(|| at_start(ExprErrorKind::BadNumber(word)))
+        }
+        let expr = match word.to_ascii_uppercase().as_str() {
+            "A" => Expr::Register(Register::A),
+            "X" => Expr::Register(Register::X),
+            "Y" => Expr::Register(Register::Y),
+            "S" => Expr::Register(Register::S),
+            "P" => Expr::Register(Register::P),
+            "PC" => Expr::Register(Register::PC),
+            "C" => Expr::Flag(CARRY_FLAG),
+            "Z" => Expr::Flag(ZERO_FLAG),
+            "I" => Expr::Flag(INT_FLAG),
+            "D" => Expr::Flag(DEC_FLAG),
+            "V" => Expr::Flag(OVERFLOW_FLAG),
+            "N" => Expr::Flag(NEG_FLAG),
+            _ => match self.symbols.lookup(&word) {
+                Some(symbol) => Expr::Number(symbol.addr as u32),
+                None => return Err(at_start(ExprErrorKind::UnknownName(word))),
+            },
+        };
+        Ok(expr)
+    }
+}
+
+// One OAM entry on a line, e.g. "#05 X:$40 Y:$5F tile:$12 attr:$41 palette 1, front, flip H"
+fn describe_sprite(sprite: &SpriteInfo) -> String {
+    let mut text = format!("#{:02} X:${:02X} Y:${:02X} tile:${:02X} attr:${:02X} palette {}, {}",
+                           sprite.oam_index, sprite.x, sprite.y, sprite.tile, sprite.attributes, sprite.palette(),
+                           if sprite.behind_background() { "behind" } else { "front" });
+    if sprite.flip_h() {
+        text.push_str(", flip H");
+    }
+    if sprite.flip_v() {
+        text.push_str(", flip V");
+    }
+    return text
+}
+
+fn parse_number(word: &str) -> Option<u32> {
+    let lower = word.to_ascii_lowercase();
+    if let Some(hex) = lower.strip_prefix('$').or_else(|| lower.strip_prefix("0x")) {
+        u32::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = lower.strip_prefix('%') {
+        u32::from_str_radix(bin, 2).ok()
+    } else {
+        lower.parse().ok()
+    }
+}
+
+// A breakpoint's address: "$C123", "3:$8123" or a symbol
+fn parse_location(text: &str, symbols: &SymbolTable) -> Option<(u16, Option<usize>)> {
+    if let Some(symbol) = symbols.lookup(text) {
+        return Some((symbol.addr, symbol.bank))
+    }
+    let (bank, addr) = match text.split_once(':') {
+        Some((bank, addr)) => (Some(parse_number(bank)? as usize), addr),
+        None => (None, text),
+    };
+    match parse_number(addr) {
+        Some(addr) if addr <= 0xFFFF => Some((addr as u16, bank)),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Condition {
+    Always,
+    When(Expr),
+    // The value last seen, None until the first check
+    Changes { expr: Expr, last: Option<u32> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+    // Only checked when PC is here, or before every instruction if None
+    pub addr: Option<u16>,
+    // And only when this PRG bank is mapped at addr
+    pub bank: Option<usize>,
+    pub condition: Condition,
+    // As it was written, for reporting
+    pub spec: String,
+}
+
+impl Breakpoint {
+    // "[ADDR] [if EXPR | changes EXPR]", with at least one of the two
+    pub fn parse(spec: &str) -> Result<Breakpoint, ExprError> {
+        Breakpoint::parse_with(spec, &SymbolTable::new())
+    }
+
+    // ADDR and the expression can use the names in symbols
+    pub fn parse_with(spec: &str, symbols: &SymbolTable) -> Result<Breakpoint, ExprError> {
+        let trimmed = spec.trim_start();
+        let offset = spec.len() - trimmed.len();
+        let (addr_text, rest) = match trimmed.find(char::is_whitespace) {
+            _ if trimmed.starts_with("if ") || trimmed.starts_with("changes ") => ("", trimmed),
+            Some(end) => (&trimmed[..end], trimmed[end..].trim_start()),
+            None => (trimmed, ""),
+        };
+        let (addr, bank) = if addr_text.is_empty() {
+            (None, None)
+        } else {
+            match parse_location(addr_text, symbols) {
+                Some((addr, bank)) => (Some(addr), bank),
+                None => return Err(ExprError { column: offset + 1, kind: ExprErrorKind::BadNumber(addr_text.to_string()) }),
+            }
+        };
+        let condition = if rest.is_empty() {
+            Condition::Always
+        } else {
+            let (text, changes) = if let Some(text) = rest.strip_prefix("if ") {
+                (text, false)
+            } else if let Some(text) = rest.strip_prefix("changes ") {
+                (text, true)
+            } else {
+                let column = spec.len() - rest.len() + 1;
+                return Err(ExprError { column: column, kind: ExprErrorKind::Expected("if or changes") })
+            };
+            // Errors in the expression count columns from the start of the whole spec
+            let column = spec.len() - text.len();
+            let expr = Expr::parse_with(text, symbols).map_err(|e| ExprError { column: e.column + column, ..e })?;
+            if changes { Condition::Changes { expr: expr, last: None } } else { Condition::When(expr) }
+        };
+        if addr.is_none() && condition == Condition::Always {
+            return Err(ExprError { column: offset + 1, kind: ExprErrorKind::Empty })
+        }
+        Ok(Breakpoint { addr: addr, bank: bank, condition: condition, spec: spec.trim().to_string() })
+    }
+
+    // Whether to stop before the instruction at PC. Change conditions are re-read on every
+    // check so they compare against the last instruction they were checked at.
+    pub fn check(&mut self, cpu: &CPU) -> bool {
+        if self.addr.is_some_and(|addr| addr != cpu.pc()) {
+            return false
+        }
+        if self.bank.is_some() && cpu.memory().prg_bank(cpu.pc()) != self.bank {
+            return false
+        }
+        match self.condition {
+            Condition::Always => true,
+            Condition::When(ref expr) => expr.eval(cpu) != 0,
+            Condition::Changes { ref expr, ref mut last } => {
+                let value = expr.eval(cpu);
+                let changed = last.is_some_and(|last| last != value);
+                *last = Some(value);
+                changed
+            },
+        }
+    }
+}
+
+pub struct Watch {
+    pub text: String,
+    pub expr: Expr,
+}
+
+#[derive(Default)]
+pub struct Debugger {
+    pub breakpoints: Vec<Breakpoint>,
+    pub watches: Vec<Watch>,
+    // Names breakpoints, watches and commands can use for addresses
+    pub symbols: SymbolTable,
+    // The diff command's cheat search
+    pub search: MemorySearch,
+    // Files being sourced right now
+    source_depth: usize,
+    // Where the slot commands keep their states
+    #[cfg(feature = "serde")]
+    pub state_manager: Option<StateManager>,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger::default()
+    }
+
+    pub fn add_breakpoint(&mut self, spec: &str) -> Result<(), ExprError> {
+        self.breakpoints.push(Breakpoint::parse_with(spec, &self.symbols)?);
+        Ok(())
+    }
+
+    pub fn add_watch(&mut self, text: &str) -> Result<(), ExprError> {
+        let expr = Expr::parse_with(text, &self.symbols)?;
+        self.watches.push(Watch { text: text.trim().to_string(), expr: expr });
+        Ok(())
+    }
+
+    // The first breakpoint that fires before the instruction at PC. Every breakpoint is checked
+    // so change conditions all stay current.
+    pub fn check(&mut self, cpu: &CPU) -> Option<&Breakpoint> {
+        let mut hit = None;
+        for (i, breakpoint) in self.breakpoints.iter_mut().enumerate() {
+            if breakpoint.check(cpu) && hit.is_none() {
+                hit = Some(i);
+            }
+        }
+        hit.map(move |i| &self.breakpoints[i])
+    }
+
+    // Each watch's current value, one per line
+    pub fn watch_report(&self, cpu: &CPU) -> String {
+        let mut out = String::new();
+        for watch in &self.watches {
+            let value = watch.expr.eval(cpu);
+            writeln!(out, "{} = ${:02X} ({})", watch.text, value, value).unwrap();
+        }
+        return out
+    }
+
+    // What to show when breakpoint spec stops a run of steps instructions
+    pub fn break_report(&self, cpu: &CPU, spec: &str, steps: u64) -> String {
+        let name = self.symbols.describe(cpu.pc(), cpu.memory()).map(|name| format!(" ({})", name)).unwrap_or_default();
+        format!("Breakpoint {} hit at ${:04X}{} after {} instructions\n{}\n{}", spec, cpu.pc(), name, steps,
+                cpu.dump_state(), self.watch_report(cpu))
+    }
+
+    // Run a monitor command, adding what it prints to out
+    pub fn run_command(&mut self, cpu: &mut CPU, line: &str, out: &mut String) -> Result<(), CommandError> {
+        let line = line.trim();
+        let (name, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+        // Errors count columns from the start of the whole command
+        let column = line.len() - rest.len();
+        let symbols = &self.symbols;
+        let parse = |text: &str| Expr::parse_with(text, symbols).map_err(|e| ExprError { column: e.column + column, ..e });
+        match name {
+            "map" => {
+                if rest.is_empty() {
+                    return Err(CommandError::Usage("map EXPR"))
+                }
+                let addr = parse(rest)?.eval(cpu) as u16;
+                writeln!(out, "{}", cpu.memory().describe(addr)).unwrap();
+            },
+            "maps" => {
+                if !rest.is_empty() {
+                    return Err(CommandError::Usage("maps"))
+                }
+                for range in cpu.memory().layout() {
+                    writeln!(out, "{}", range).unwrap();
+                }
+            },
+            "mem" => {
+                if rest.is_empty() {
+                    return Err(CommandError::Usage("mem EXPR[, LEN]"))
+                }
+                let (start, len) = match rest.split_once(',') {
+                    Some((start, len)) => (start, parse_number(len.trim()).ok_or(CommandError::Usage("mem EXPR[, LEN]"))?),
+                    None => (rest, 16),
+                };
+                let start = parse(start)?.eval(cpu) as u16;
+                let end = (start as u32 + len).min(0x10000);
+                let data: Vec<u8> = (start as u32..end).map(|addr| cpu.memory().peek(addr as u16)).collect();
+                out.push_str(&util::hexdump(&data, start as u32, 16));
+            },
+            "regs" => {
+                if !rest.is_empty() {
+                    return Err(CommandError::Usage("regs"))
+                }
+                writeln!(out, "{}", cpu.dump_state()).unwrap();
+            },
+            "break" => {
+                if rest.is_empty() {
+                    return Err(CommandError::Usage("break SPEC"))
+                }
+                let breakpoint = Breakpoint::parse_with(rest, &self.symbols).map_err(|e| ExprError { column: e.column + column, ..e })?;
+                self.breakpoints.push(breakpoint);
+            },
+            "sym" => {
+                if rest.is_empty() {
+                    return Err(CommandError::Usage("sym NAME|EXPR"))
+                }
+                if let Some(symbol) = self.symbols.lookup(rest) {
+                    writeln!(out, "{}", symbol).unwrap();
+                } else {
+                    let addr = parse(rest)?.eval(cpu) as u16;
+                    match self.symbols.describe(addr, cpu.memory()) {
+                        Some(name) => writeln!(out, "${:04X} = {}", addr, name).unwrap(),
+                        None => writeln!(out, "${:04X} has no symbol", addr).unwrap(),
+                    }
+                }
+            },
+            "watch" => {
+                if rest.is_empty() {
+                    return Err(CommandError::Usage("watch EXPR"))
+                }
+                let expr = parse(rest)?;
+                self.watches.push(Watch { text: rest.to_string(), expr: expr });
+            },
+            "step" => {
+                let count = if rest.is_empty() { 1 } else { parse_number(rest).ok_or(CommandError::Usage("step [N]"))? };
+                for _ in 0..count {
+                    cpu.emulate_cycle()?;
+                }
+                writeln!(out, "{}", cpu.dump_state()).unwrap();
+            },
+            "asm" => {
+                let (addr, code) = rest.split_once(char::is_whitespace).ok_or(CommandError::Usage("asm EXPR CODE"))?;
+                let addr = parse(addr)?.eval(cpu) as u16;
+                let bytes = asm::assemble(&code.split('|').collect::<Vec<_>>().join("\n"), addr)?;
+                for (i, &byte) in bytes.iter().enumerate() {
+                    cpu.memory_mut().storeb(addr.wrapping_add(i as u16), byte);
+                }
+                // Listed from memory, so a write that didn't stick, to ROM say, shows
+                let written: Vec<u8> = (0..bytes.len() as u16).map(|i| cpu.memory().peek(addr.wrapping_add(i))).collect();
+                let mut offset = 0;
+                while offset < written.len() {
+                    let pc = addr.wrapping_add(offset as u16);
+                    let (text, len) = opcodes::disassemble(&written[offset..], pc);
+                    writeln!(out, "${:04X}  {}", pc, text).unwrap();
+                    offset += (len as usize).max(1);
+                }
+            },
+            "run" => {
+                let limit = if rest.is_empty() { None } else { Some(parse_number(rest).ok_or(CommandError::Usage("run [FRAMES]"))?) };
+                self.run(cpu, limit.map(|frames| frames as u64), out)?;
+            },
+            "diff" => {
+                let usage = "diff [ram|vram|oam] | diff watch VAL";
+                let words: Vec<&str> = rest.split_whitespace().collect();
+                match words[..] {
+                    [] if self.search.is_started() => {
+                        let changes = self.search.changes(cpu.memory());
+                        for change in changes.iter() {
+                            writeln!(out, "${:04X}: {:02X} -> {:02X}", change.addr, change.old, change.new).unwrap();
+                        }
+                        self.search.refine_changed(cpu.memory());
+                        writeln!(out, "{} candidates changed", changes.len()).unwrap();
+                    },
+                    [] | [_] => {
+                        let (space, name) = match words.first().map(|word| word.to_ascii_lowercase()).as_deref() {
+                            None | Some("ram") => (SearchSpace::Ram, "RAM"),
+                            Some("vram") => (SearchSpace::Vram, "VRAM"),
+                            Some("oam") => (SearchSpace::Oam, "OAM"),
+                            _ => return Err(CommandError::Usage(usage)),
+                        };
+                        self.search = MemorySearch::new(space);
+                        self.search.start(cpu.memory());
+                        writeln!(out, "Searching {} bytes of {}", self.search.candidates().len(), name).unwrap();
+                    },
+                    ["watch", val] => {
+                        let val = parse_number(val).filter(|&val| val < 0x100).ok_or(CommandError::Usage(usage))?;
+                        self.search.refine_equals(cpu.memory(), val as u8);
+                        let candidates = self.search.candidates();
+                        for &addr in candidates.iter().take(MAX_LISTED_CANDIDATES) {
+                            writeln!(out, "${:04X}", addr).unwrap();
+                        }
+                        if candidates.len() > MAX_LISTED_CANDIDATES {
+                            writeln!(out, "... and {} more", candidates.len() - MAX_LISTED_CANDIDATES).unwrap();
+                        }
+                        writeln!(out, "{} candidates hold ${:02X}", candidates.len(), val).unwrap();
+                    },
+                    _ => return Err(CommandError::Usage(usage)),
+                }
+            },
+            "source" => {
+                if rest.is_empty() {
+                    return Err(CommandError::Usage("source FILE"))
+                }
+                self.source(cpu, rest, out)?;
+            },
+            "slots" => {
+                if !rest.is_empty() {
+                    return Err(CommandError::Usage("slots"))
+                }
+                self.list_slots(out)?;
+            },
+            "save" | "load" => {
+                let usage = if name == "save" { "save SLOT" } else { "load SLOT" };
+                let slot = parse_number(rest).ok_or(CommandError::Usage(usage))?;
+                self.use_slot(cpu, name == "save", slot as usize, out)?;
+            },
+            "layers" => {
+                let usage = "layers [bg|sprites|tint on|off]";
+                let ppu = &mut cpu.memory_mut().ppu;
+                if !rest.is_empty() {
+                    let (layer, state) = rest.split_once(char::is_whitespace).ok_or(CommandError::Usage(usage))?;
+                    let on = match state.trim() {
+                        "on" => true,
+                        "off" => false,
+                        _ => return Err(CommandError::Usage(usage)),
+                    };
+                    match layer {
+                        "tint" => ppu.set_sprite_tint(on),
+                        _ => ppu.set_layer_visibility(layer.parse().map_err(|_| CommandError::Usage(usage))?, on),
+                    }
+                }
+                let on_off = |on: bool| if on { "on" } else { "off" };
+                writeln!(out, "bg {}, sprites {}, tint {}", on_off(ppu.layer_visible(Layer::Background)),
+                         on_off(ppu.layer_visible(Layer::Sprites)), on_off(ppu.sprite_tint())).unwrap();
+            },
+            "pal" => {
+                let usage = "pal [set IDX VAL]";
+                let ppu = &mut cpu.memory_mut().ppu;
+                if !rest.is_empty() {
+                    let words: Vec<&str> = rest.split_whitespace().collect();
+                    let (index, val) = match words[..] {
+                        ["set", index, val] => (parse_number(index), parse_number(val)),
+                        _ => return Err(CommandError::Usage(usage)),
+                    };
+                    match (index, val) {
+                        (Some(index), Some(val)) if index < 32 && val < 0x40 => ppu.set_palette_entry(index as usize, val as u8),
+                        _ => return Err(CommandError::Usage(usage)),
+                    }
+                }
+                out.push_str(&ppu.palette_listing());
+            },
+            "channels" => {
+                let usage = "channels [pulse1|pulse2|triangle|noise|dmc on|off]";
+                let apu = &mut cpu.memory_mut().apu;
+                if !rest.is_empty() {
+                    let (channel, state) = rest.split_once(char::is_whitespace).ok_or(CommandError::Usage(usage))?;
+                    let on = match state.trim() {
+                        "on" => true,
+                        "off" => false,
+                        _ => return Err(CommandError::Usage(usage)),
+                    };
+                    apu.set_channel_enabled(channel.parse().map_err(|_| CommandError::Usage(usage))?, on);
+                }
+                let states: Vec<String> = Channel::ALL.iter()
+                    .map(|&channel| format!("{} {}", channel.name(), if apu.channel_enabled(channel) { "on" } else { "off" }))
+                    .collect();
+                writeln!(out, "{}", states.join(", ")).unwrap();
+            },
+            "sprites" => {
+                let ppu = &cpu.memory().ppu;
+                if rest.is_empty() {
+                    for line in 0..SCREEN_HEIGHT as u16 {
+                        let sprites = ppu.sprites_on_scanline(line);
+                        let dropped = sprites.iter().filter(|sprite| sprite.dropped).count();
+                        match (sprites.len(), dropped) {
+                            (0, _) => {},
+                            (found, 0) => writeln!(out, "Line {:3}: {}", line, found).unwrap(),
+                            (found, dropped) => writeln!(out, "Line {:3}: {}, {} dropped", line, found, dropped).unwrap(),
+                        }
+                    }
+                } else {
+                    let line = parse_number(rest).filter(|&line| line < SCREEN_HEIGHT as u32)
+                        .ok_or(CommandError::Usage("sprites [LINE]"))?;
+                    let sprites = ppu.sprites_on_scanline(line as u16);
+                    if sprites.is_empty() {
+                        writeln!(out, "No sprites on line {}", line).unwrap();
+                    }
+                    for sprite in sprites {
+                        writeln!(out, "{}{}", describe_sprite(&sprite), if sprite.dropped { "  dropped" } else { "" }).unwrap();
+                    }
+                }
+            },
+            "oam" => {
+                if !rest.is_empty() {
+                    return Err(CommandError::Usage("oam"))
+                }
+                let oam = &cpu.memory().ppu.oam;
+                for n in 0..64 {
+                    writeln!(out, "{}", describe_sprite(&SpriteInfo::from_oam(oam, n))).unwrap();
+                }
+            },
+            _ => return Err(CommandError::Unknown(name.to_string())),
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    fn state_manager(&self) -> Result<&StateManager, CommandError> {
+        self.state_manager.as_ref().ok_or_else(|| CommandError::Slots("no save-state directory".to_string()))
+    }
+
+    #[cfg(feature = "serde")]
+    fn list_slots(&self, out: &mut String) -> Result<(), CommandError> {
+        let manager = self.state_manager()?;
+        let slots = manager.list();
+        if slots.is_empty() {
+            writeln!(out, "No slots saved").unwrap();
+        }
+        let newest = manager.newest_slot();
+        for info in slots {
+            writeln!(out, "{}{}", info, if Some(info.slot) == newest { " (newest)" } else { "" }).unwrap();
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    fn use_slot(&self, cpu: &mut CPU, save: bool, slot: usize, out: &mut String) -> Result<(), CommandError> {
+        let manager = self.state_manager()?;
+        let info = if save { manager.save_slot(cpu, slot)? } else { manager.load_slot(cpu, slot)? };
+        writeln!(out, "{} {}", if save { "Saved" } else { "Loaded" }, info).unwrap();
+        Ok(())
+    }
+
+    #[cfg(not(feature = "serde"))]
+    fn list_slots(&self, _out: &mut String) -> Result<(), CommandError> {
+        Err(CommandError::Slots("save-state slots need the serde feature".to_string()))
+    }
+
+    #[cfg(not(feature = "serde"))]
+    fn use_slot(&self, _cpu: &mut CPU, _save: bool, _slot: usize, _out: &mut String) -> Result<(), CommandError> {
+        Err(CommandError::Slots("save-state slots need the serde feature".to_string()))
+    }
+
+    // Run until a breakpoint fires or frames frames have gone by. The instruction run starts
+    // at isn't checked, so running again after a breakpoint moves past it.
+    fn run(&mut self, cpu: &mut CPU, frames: Option<u64>, out: &mut String) -> Result<(), CommandError> {
+        let end = frames.map(|frames| cpu.memory().ppu.frame + frames);
+        let mut steps = 0u64;
+        loop {
+            if end.is_some_and(|end| cpu.memory().ppu.frame >= end) {
+                writeln!(out, "Ran {} instructions to frame {}", steps, cpu.memory().ppu.frame).unwrap();
+                return Ok(())
+            }
+            if steps > 0 {
+                if let Some(spec) = self.check(cpu).map(|breakpoint| breakpoint.spec.clone()) {
+                    out.push_str(&self.break_report(cpu, &spec, steps));
+                    return Ok(())
+                }
+            }
+            cpu.emulate_cycle()?;
+            steps += 1;
+        }
+    }
+
+    // Run each line of a file as a command
+    pub fn source(&mut self, cpu: &mut CPU, path: &str, out: &mut String) -> Result<(), CommandError> {
+        let text = fs::read_to_string(path).map_err(|e| CommandError::Io { path: path.to_string(), message: e.to_string() })?;
+        self.run_script(cpu, path, &text, out)
+    }
+
+    // Run each line of text as a command, stopping at the first that fails. name is what
+    // errors call the script.
+    pub fn run_script(&mut self, cpu: &mut CPU, name: &str, text: &str, out: &mut String) -> Result<(), CommandError> {
+        if self.source_depth >= MAX_SOURCE_DEPTH {
+            return Err(CommandError::SourceTooDeep)
+        }
+        self.source_depth += 1;
+        let mut result = Ok(());
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Err(e) = self.run_command(cpu, line, out) {
+                result = Err(CommandError::Script { path: name.to_string(), line: index + 1, error: Box::new(e) });
+                break;
+            }
+        }
+        self.source_depth -= 1;
+        return result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm;
+    use controller::InputFrame;
+    use emulator::Nes;
+    use rom::ROM;
+    use testing;
+
+    // Shows the background, where tile 0 is solid color 1
+    fn background_nes() -> Nes {
+        let mut chr = vec![0; 16];
+        chr[..8].copy_from_slice(&[0xFF; 8]);
+        let rom = testing::build_test_rom("reset: LDA #$0A\n STA $2001\nloop: JMP loop", Some(&chr));
+        Nes::builder().rom_bytes(&rom).ppu_warmup(false).build().unwrap()
+    }
+
+    #[test]
+    fn pal_set_changes_the_picture() {
+        let mut nes = background_nes();
+        let mut debugger = Debugger::new();
+        let mut out = String::new();
+        debugger.run_command(nes.cpu_mut(), "pal set 1 $16", &mut out).unwrap();
+        assert!(out.starts_with("BG0 $3F00:"));
+        assert!(out.lines().next().unwrap().contains(" 16 #"));
+        assert_eq!(out.lines().count(), 8);
+        nes.run_frame(InputFrame::default()).unwrap();
+        let frame = nes.run_frame(InputFrame::default()).unwrap().framebuffer.to_vec();
+        assert!(frame.iter().all(|&pixel| pixel == 0x16));
+    }
+
+    #[test]
+    fn asm_writes_code_that_lists_back() {
+        let mut cpu = testing::build_program(&[]);
+        let mut debugger = Debugger::new();
+        let mut out = String::new();
+        debugger.run_command(&mut cpu, "asm $0300 LDX #5 | loop: DEX | CPX #0 | BNE loop | STX $10 | JMP $0300", &mut out).unwrap();
+        assert_eq!(out, "\
+$0300  LDX #$05
+$0302  DEX
+$0303  CPX #$00
+$0305  BNE $0302
+$0307  STX $10
+$0309  JMP $0300
+");
+        let written = || (0x0300..0x030C).map(|addr| cpu.memory().peek(addr)).collect::<Vec<_>>();
+        assert_eq!(written(), [0xA2, 0x05, 0xCA, 0xE0, 0x00, 0xD0, 0xFB, 0x86, 0x10, 0x4C, 0x00, 0x03]);
+        // The listing assembles back to the same bytes
+        let listing: Vec<&str> = out.lines().map(|line| &line[7..]).collect();
+        assert_eq!(asm::assemble(&listing.join("\n"), 0x0300).unwrap(), written());
+
+        // And it runs
+        cpu.memory_mut().storeb(0x10, 0xFF);
+        cpu.set_pc(0x0300);
+        for _ in 0..17 {
+            cpu.emulate_cycle().unwrap();
+        }
+        assert_eq!((cpu.pc(), cpu.memory().peek(0x10)), (0x0309, 0));
+
+        match debugger.run_command(&mut cpu, "asm $0300 LDX #5 | FOO", &mut out) {
+            Err(CommandError::Asm(e)) => assert_eq!(e.to_string(), "line 2: unknown mnemonic FOO"),
+            other => panic!("expected an assembly error, got {:?}", other.map(|_| ())),
+        }
+        assert!(matches!(debugger.run_command(&mut cpu, "asm $0300", &mut out), Err(CommandError::Usage(_))));
+    }
+
+    #[test]
+    fn diff_narrows_down_to_the_counter() {
+        // INC $10, JMP $8000
+        let mut cpu = testing::build_program(&[0xE6, 0x10, 0x4C, 0x00, 0x80]);
+        let mut debugger = Debugger::new();
+        let mut out = String::new();
+        debugger.run_command(&mut cpu, "diff", &mut out).unwrap();
+        assert_eq!(out, "Searching 2048 bytes of RAM\n");
+
+        out.clear();
+        debugger.run_command(&mut cpu, "step 2", &mut out).unwrap();
+        out.clear();
+        debugger.run_command(&mut cpu, "diff", &mut out).unwrap();
+        assert_eq!(out, "$0010: 00 -> 01\n1 candidates changed\n");
+        assert_eq!(debugger.search.candidates(), [0x0010]);
+
+        debugger.run_command(&mut cpu, "step 4", &mut out).unwrap();
+        out.clear();
+        debugger.run_command(&mut cpu, "diff watch 3", &mut out).unwrap();
+        assert_eq!(out, "$0010\n1 candidates hold $03\n");
+        out.clear();
+        debugger.run_command(&mut cpu, "diff watch 4", &mut out).unwrap();
+        assert_eq!(out, "0 candidates hold $04\n");
+
+        // A new search, watched straight away, lists only the first few
+        out.clear();
+        debugger.run_command(&mut cpu, "diff ram", &mut out).unwrap();
+        debugger.run_command(&mut cpu, "diff watch 0", &mut out).unwrap();
+        assert!(out.starts_with("Searching 2048 bytes of RAM\n$0000\n$0001\n"), "{}", out);
+        assert!(out.ends_with("$000F\n... and 2031 more\n2047 candidates hold $00\n"), "{}", out);
+
+        for command in ["diff watch", "diff watch 256", "diff rom", "diff ram vram"] {
+            assert!(matches!(debugger.run_command(&mut cpu, command, &mut out), Err(CommandError::Usage(_))), "{}", command);
+        }
+    }
+
+    #[test]
+    fn pal_set_checks_its_arguments() {
+        let mut cpu = testing::build_program(&[]);
+        let mut debugger = Debugger::new();
+        let mut out = String::new();
+        for command in ["pal set 32 $00", "pal set 0 $40", "pal set 0", "pal 3"] {
+            assert!(matches!(debugger.run_command(&mut cpu, command, &mut out), Err(CommandError::Usage(_))), "{}", command);
+        }
+        debugger.run_command(&mut cpu, "pal set $10 $21", &mut out).unwrap();
+        assert_eq!(cpu.memory().ppu.palette_ram()[0], 0x21);
+    }
+
+    #[test]
+    fn map_and_maps_describe_the_bus() {
+        let mut cpu = testing::build_program(&[0xEA; 4]);
+        let mut debugger = Debugger::new();
+        let mut out = String::new();
+        debugger.run_command(&mut cpu, "map $0800 + $123", &mut out).unwrap();
+        debugger.run_command(&mut cpu, "map PC", &mut out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert!(lines[0].starts_with("$0923  RAM ") && lines[0].contains("RAM $0123 via mirror"), "{}", lines[0]);
+        assert!(lines[1].starts_with("$8000  PRG ROM ") && lines[1].contains("PRG bank 0 offset $0000"), "{}", lines[1]);
+
+        out.clear();
+        debugger.run_command(&mut cpu, "maps", &mut out).unwrap();
+        let starts: Vec<&str> = out.lines().map(|line| &line[..11]).collect();
+        assert_eq!(starts[..4], ["$0000-$07FF", "$0800-$1FFF", "$2000-$2000", "$2001-$2001"]);
+        assert_eq!(starts.last(), Some(&"$8000-$FFFF"));
+        for command in ["map", "maps 1"] {
+            assert!(matches!(debugger.run_command(&mut cpu, command, &mut out), Err(CommandError::Usage(_))), "{}", command);
+        }
+    }
+
+    // Counts X up to 5 in $10
+    const COUNT_TO_5: &str = "
+        LDX #$00
+loop:   INX
+        STX $10
+        CPX #5
+        BNE loop
+done:   JMP done
+";
+
+    #[test]
+    fn scripts_set_breakpoints_run_and_dump() {
+        let mut cpu = testing::build_program(&asm::assemble(COUNT_TO_5, 0x8000).unwrap());
+        let mut debugger = Debugger::new();
+        let mut out = String::new();
+        let script = "
+# Stop on the third pass round the loop
+break $8002 if X == 3
+watch [$10]
+
+run
+mem $10, 2
+step 3
+mem $10, 1
+bogus
+regs
+";
+        let error = debugger.run_script(&mut cpu, "count.txt", script, &mut out).unwrap_err();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[0], "Breakpoint $8002 if X == 3 hit at $8002 after 13 instructions");
+        assert!(lines[1].starts_with("A:00 X:03 Y:00 S:FD PC:8002 "), "{}", lines[1]);
+        assert_eq!(lines[2], "[$10] = $03 (3)");
+        assert!(lines[3].starts_with("0010:  03 00 "), "{}", lines[3]);
+        assert!(lines[4].starts_with("A:00 X:04 Y:00 S:FD PC:8007 "), "{}", lines[4]);
+        assert!(lines[5].starts_with("0010:  04 "), "{}", lines[5]);
+        // The script stops at the bad command, without running the rest
+        assert_eq!(lines.iter().filter(|line| !line.is_empty()).count(), 6);
+        assert_eq!(error.to_string(), "count.txt line 10: unknown command bogus");
+    }
+
+    #[test]
+    fn sourced_errors_give_every_file_and_line() {
+        let dir = std::env::temp_dir().join(format!("nes-monitor-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let outer = dir.join("outer.txt");
+        let inner = dir.join("inner.txt");
+        let looping = dir.join("loop.txt");
+        fs::write(&outer, format!("regs\nsource {}\n", inner.display())).unwrap();
+        fs::write(&inner, "\n# nothing yet\nbogus\n").unwrap();
+        fs::write(&looping, format!("source {}\n", looping.display())).unwrap();
+
+        let mut cpu = testing::build_program(&[]);
+        let mut debugger = Debugger::new();
+        let mut out = String::new();
+        let error = debugger.run_command(&mut cpu, &format!("source {}", outer.display()), &mut out).unwrap_err();
+        assert_eq!(error.to_string(), format!("{} line 2: {} line 3: unknown command bogus", outer.display(), inner.display()));
+        // A script that sources itself runs out of depth instead of stack
+        let error = debugger.source(&mut cpu, looping.to_str().unwrap(), &mut out).unwrap_err();
+        assert!(error.to_string().ends_with(&format!("source nested more than {} deep", MAX_SOURCE_DEPTH)), "{}", error);
+        assert!(matches!(debugger.source(&mut cpu, "/nonexistent/monitor.txt", &mut out), Err(CommandError::Io { .. })));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // What text evaluates to on a CPU that has run count instructions of program
+    fn eval_after(program: &str, count: usize, text: &str) -> u32 {
+        let mut cpu = testing::build_program(&asm::assemble(program, 0x8000).unwrap());
+        for _ in 0..count {
+            cpu.emulate_cycle().unwrap();
+        }
+        Expr::parse(text).unwrap().eval(&cpu)
+    }
+
+    fn eval(text: &str) -> u32 {
+        eval_after("", 0, text)
+    }
+
+    #[test]
+    fn expressions_follow_c_precedence() {
+        assert_eq!(eval("1 + 2 == 3"), 1);
+        assert_eq!(eval("5 - 2 - 1"), 2);
+        assert_eq!(eval("1 < 2 == 1"), 1);
+        assert_eq!(eval("0 && 1 || 1"), 1);
+        assert_eq!(eval("1 || 0 && 0"), 1);
+        assert_eq!(eval("!0 + 1"), 2);
+        assert_eq!(eval("!(0 + 1)"), 0);
+        assert_eq!(eval("(1 || 0) + 4"), 5);
+        assert_eq!(eval("0 - 1"), u32::MAX);
+        assert_eq!(eval("3 >= 3 && 3 <= 3 && 2 != 3 && !(2 > 3)"), 1);
+    }
+
+    #[test]
+    fn numbers_in_every_base() {
+        for text in ["$3F", "0x3F", "0X3f", "63", "%111111"] {
+            assert_eq!(eval(text), 63, "{}", text);
+        }
+    }
+
+    #[test]
+    fn registers_flags_and_memory() {
+        const SETUP: &str = "
+        LDA #$3F
+        LDX #$05
+        STA $10
+        STX $11
+        CMP #$3F
+";
+        let at_end = |text| eval_after(SETUP, 5, text);
+        assert_eq!(at_end("a == $3F && x == 5 && Y == 0"), 1);
+        assert_eq!(at_end("PC"), 0x800A);
+        assert_eq!(at_end("S"), 0xFD);
+        assert_eq!((at_end("Z"), at_end("I"), at_end("N")), (1, 1, 0));
+        assert_eq!(at_end("P != 0"), 1);
+        assert_eq!(at_end("[$10]"), 0x3F);
+        assert_eq!(at_end("[[$10]]"), 0x053F);
+        assert_eq!(at_end("[$0F + 2]"), 0x05);
+        // Reading a register through peek has no side effects
+        assert_eq!(at_end("[$2002] == [$2002]"), 1);
+    }
+
+    #[test]
+    fn bad_expressions_say_where() {
+        let error = |text| Expr::parse(text).unwrap_err().to_string();
+        assert_eq!(error(""), "column 1: empty expression");
+        assert_eq!(error("A =="), "column 5: expression ends early");
+        assert_eq!(error("A = 3"), "column 3: unexpected '='");
+        assert_eq!(error("[$10"), "column 5: expression ends early");
+        assert_eq!(error("[$10 + 1)"), "column 9: expected ]");
+        assert_eq!(error("$GG + 1"), "column 1: bad number $GG");
+        assert_eq!(error("A == foo"), "column 6: foo isn't a register, flag or symbol");
+
+        let error = |spec| Breakpoint::parse(spec).unwrap_err().to_string();
+        assert_eq!(error("$C123 when A"), "column 7: expected if or changes");
+        assert_eq!(error("$C123 if A =="), "column 14: expression ends early");
+        assert_eq!(error("$ZZ"), "column 1: bad number $ZZ");
+        assert_eq!(error("  "), "column 3: empty expression");
+    }
+
+    #[test]
+    fn breakpoint_specs() {
+        let breakpoint = Breakpoint::parse("$C123 if A == $3F").unwrap();
+        assert_eq!((breakpoint.addr, breakpoint.bank), (Some(0xC123), None));
+        assert!(matches!(breakpoint.condition, Condition::When(_)));
+        assert_eq!(Breakpoint::parse("3:$8123").unwrap().bank, Some(3));
+        assert_eq!(Breakpoint::parse("if [$10] > 3").unwrap().addr, None);
+        assert!(matches!(Breakpoint::parse("changes [[$FE]]").unwrap().condition, Condition::Changes { last: None, .. }));
+    }
+
+    // Steps a loop counting X up and storing it in $10 until a breakpoint fires, and gives the
+    // X at each hit
+    fn hits(spec: &str, instructions: usize) -> Vec<u8> {
+        const COUNT_UP: &str = "
+        LDX #$00
+loop:   INX
+        STX $10
+        JMP loop
+";
+        let mut cpu = testing::build_program(&asm::assemble(COUNT_UP, 0x8000).unwrap());
+        let mut debugger = Debugger::new();
+        debugger.add_breakpoint(spec).unwrap();
+        let mut hits = Vec::new();
+        for _ in 0..instructions {
+            if debugger.check(&cpu).is_some() {
+                hits.push(cpu.x());
+            }
+            cpu.emulate_cycle().unwrap();
+        }
+        hits
+    }
+
+    #[test]
+    fn conditional_breakpoints_fire_on_the_matching_pass() {
+        // 20 trips round the loop
+        assert_eq!(hits("$8003 if X == 3", 61), [3]);
+        assert_eq!(hits("$8003", 61).len(), 20);
+        assert_eq!(hits("if X == 3 && PC == $8005", 61), [3]);
+        // Every store changes $10, and it's seen before the next instruction
+        assert_eq!(hits("changes [$10]", 61), (1..=20).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn watches_are_reported_at_stops() {
+        let mut cpu = testing::build_program(&asm::assemble("LDA #$34\nLDX #$12", 0x8000).unwrap());
+        cpu.emulate_cycle().unwrap();
+        cpu.emulate_cycle().unwrap();
+        let mut debugger = Debugger::new();
+        debugger.add_watch("A").unwrap();
+        debugger.add_watch(" X + 1 ").unwrap();
+        assert_eq!(debugger.watch_report(&cpu), "A = $34 (52)\nX + 1 = $13 (19)\n");
+        let report = debugger.break_report(&cpu, "$8004", 2);
+        assert!(report.starts_with("Breakpoint $8004 hit at $8004 after 2 instructions\n"), "{}", report);
+        assert!(report.ends_with("A = $34 (52)\nX + 1 = $13 (19)\n"));
+    }
+
+    // GxROM with two 32 KiB banks. Bank 0 switches to bank 1, using its own LDA operand to
+    // get past the bus conflict, and bank 1 spins at $8005 where the switch lands.
+    fn two_bank_cpu() -> CPU {
+        let mut image = vec![b'N', b'E', b'S', 0x1A, 4, 1, 0x20, 0x40, 0, 0, 0, 0, 0, 0, 0, 0];
+        let mut prg = vec![0xEA; 0x10000];
+        prg[..5].copy_from_slice(&[0xA9, 0x10, 0x8D, 0x01, 0x80]);
+        prg[0x8005..0x8008].copy_from_slice(&[0x4C, 0x05, 0x80]);
+        for bank in 0..2 {
+            prg[bank * 0x8000 + 0x7FFC..bank * 0x8000 + 0x7FFE].copy_from_slice(&[0x00, 0x80]);
+        }
+        image.extend_from_slice(&prg);
+        image.extend_from_slice(&[0; 0x2000]);
+        let mut cpu = CPU::from_rom(ROM::from_bytes(&image).unwrap());
+        cpu.power_on();
+        cpu
+    }
+
+    // How many of the first 10 instructions a breakpoint stopped before
+    fn bank_hits(spec: &str) -> usize {
+        let mut cpu = two_bank_cpu();
+        let mut debugger = Debugger::new();
+        debugger.symbols.parse("switch = 0:$8002\nspin0 = 0:$8005\nspin1 = 1:$8005").unwrap();
+        debugger.add_breakpoint(spec).unwrap();
+        let mut hits = 0;
+        for _ in 0..10 {
+            if debugger.check(&cpu).is_some() {
+                hits += 1;
+            }
+            cpu.emulate_cycle().unwrap();
+        }
+        hits
+    }
+
+    #[test]
+    fn banked_breakpoints_wait_for_their_bank() {
+        assert_eq!(bank_hits("switch"), 1);
+        // $8005 only ever runs once bank 1 is in
+        assert_eq!(bank_hits("spin0"), 0);
+        assert_eq!(bank_hits("0:$8005"), 0);
+        assert_eq!(bank_hits("spin1"), 8);
+        assert_eq!(bank_hits("1:$8005 if A == $10"), 8);
+        assert_eq!(bank_hits("$8005"), 8);
+    }
+
+    #[test]
+    fn sym_looks_up_names_and_addresses() {
+        let mut cpu = two_bank_cpu();
+        let mut debugger = Debugger::new();
+        debugger.symbols.parse("spin0 = 0:$8005\nspin1 = 1:$8005").unwrap();
+        let mut out = String::new();
+        debugger.run_command(&mut cpu, "sym spin1", &mut out).unwrap();
+        debugger.run_command(&mut cpu, "sym $8006", &mut out).unwrap();
+        // Bank 0 is in until the switch
+        assert_eq!(out, "spin1 = 1:$8005\n$8006 = spin0+$1\n");
+        debugger.run_command(&mut cpu, "step 2", &mut out).unwrap();
+        out.clear();
+        debugger.run_command(&mut cpu, "sym $8006", &mut out).unwrap();
+        debugger.run_command(&mut cpu, "sym $9000", &mut out).unwrap();
+        assert_eq!(out, "$8006 = spin1+$1\n$9000 has no symbol\n");
+    }
+}
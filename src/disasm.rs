@@ -0,0 +1,410 @@
+// Static disassembly of a whole ROM, bank by bank. Code is found by following the flow from
+// the reset, NMI and IRQ vectors; anything never reached is shown as .byte, unless a linear
+// sweep is asked for, which decodes what's left as code wherever it can. Each bank's listing
+// assembles back to the same bytes with asm::assemble at the bank's address.
+//
+// Only the vectors seed the traversal, so code in switchable banks, which is only reached
+// through the mapper, needs the linear sweep to show up.
+//
+// Symbols, when given, name the instructions they point at in place of the generated labels.
+
+use cpu::{IRQ_VECTOR, NMI_VECTOR, RESET_VECTOR};
+use opcodes::{self, AddressingMode, Mnemonic, Opcode, OPCODE_TABLE};
+use rom::ROM;
+use symbols::SymbolTable;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fmt::Write;
+
+// Data bytes per .byte line
+const BYTES_PER_LINE: usize = 8;
+// Where instructions start in the listing
+const INDENT: &str = "    ";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisasmError {
+    // Only mappers 0-4 have a layout simple enough to know statically
+    UnsupportedMapper(u16),
+    NoPrg,
+}
+
+impl fmt::Display for DisasmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DisasmError::UnsupportedMapper(mapper) => write!(f, "don't know mapper {}'s PRG layout", mapper),
+            DisasmError::NoPrg => write!(f, "ROM has no PRG"),
+        }
+    }
+}
+
+// A stretch of PRG and the address the CPU sees it at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bank {
+    pub index: usize,
+    // Offset into PRG
+    pub offset: usize,
+    pub len: usize,
+    pub base: u16,
+    // Always mapped, where the vectors are
+    pub fixed: bool,
+}
+
+impl Bank {
+    fn contains(&self, addr: u16) -> bool {
+        addr >= self.base && (addr as usize) < self.base as usize + self.len
+    }
+
+    fn index_of(&self, addr: u16) -> usize {
+        (addr - self.base) as usize
+    }
+}
+
+// How the mapper lays PRG out. The fixed banks sit at the top of memory; switchable banks are
+// shown at $8000, where UxROM, MMC1 and MMC3 (in its usual mode) put them.
+pub fn banks(rom: &ROM) -> Result<Vec<Bank>, DisasmError> {
+    if rom.prg.is_empty() {
+        return Err(DisasmError::NoPrg)
+    }
+    let (bank_size, fixed) = match rom.header.mapper() {
+        0 | 3 => (rom.prg.len().min(0x8000), 1),
+        1 | 2 => (0x4000, 1),
+        4 => (0x2000, 2),
+        mapper => return Err(DisasmError::UnsupportedMapper(mapper)),
+    };
+    let count = rom.prg.len().div_ceil(bank_size);
+    let banks = (0..count).map(|index| {
+        let offset = index * bank_size;
+        let len = bank_size.min(rom.prg.len() - offset);
+        let fixed = index >= count.saturating_sub(fixed);
+        let base = if fixed { 0x10000 - (count - index) * bank_size } else { 0x8000 };
+        Bank { index: index, offset: offset, len: len, base: base as u16, fixed: fixed }
+    }).collect();
+    Ok(banks)
+}
+
+pub fn disassemble(rom: &ROM, linear_sweep: bool) -> Result<String, DisasmError> {
+    disassemble_with(rom, linear_sweep, &SymbolTable::new())
+}
+
+// Symbols for a bank, or for every bank, label the instructions they land on
+pub fn disassemble_with(rom: &ROM, linear_sweep: bool, symbols: &SymbolTable) -> Result<String, DisasmError> {
+    let mut out = String::new();
+    for bank in banks(rom)? {
+        let bytes = &rom.prg[bank.offset..bank.offset + bank.len];
+        let listing = Listing::new(bank, bytes, linear_sweep, symbols);
+        listing.write(&mut out);
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+// The instruction at i, if there's a whole legal one there
+fn decode(bytes: &[u8], i: usize) -> Option<Opcode> {
+    let op = OPCODE_TABLE[bytes[i] as usize];
+    if op.mnemonic == Mnemonic::Illegal || i + op.len as usize > bytes.len() {
+        return None
+    }
+    Some(op)
+}
+
+// Where a branch, JMP or JSR at addr goes. Indirect jumps go wherever memory says at the time.
+fn target(op: Opcode, bytes: &[u8], i: usize, addr: u16) -> Option<u16> {
+    match (op.mnemonic, op.mode) {
+        (_, AddressingMode::Relative) => Some(addr.wrapping_add(2).wrapping_add(bytes[i + 1] as i8 as u16)),
+        (Mnemonic::JMP, AddressingMode::Absolute) | (Mnemonic::JSR, AddressingMode::Absolute) =>
+            Some((bytes[i + 2] as u16) << 8 | bytes[i + 1] as u16),
+        _ => None,
+    }
+}
+
+// Whether execution never carries on to the next instruction
+fn ends_flow(op: Opcode) -> bool {
+    matches!(op.mnemonic, Mnemonic::JMP | Mnemonic::RTS | Mnemonic::RTI | Mnemonic::BRK)
+}
+
+struct Listing<'a> {
+    bank: Bank,
+    bytes: &'a [u8],
+    // Indexed by offset into the bank: where instructions start, and every byte they cover
+    starts: Vec<bool>,
+    covered: Vec<bool>,
+    labels: BTreeMap<u16, Vec<String>>,
+}
+
+impl<'a> Listing<'a> {
+    fn new(bank: Bank, bytes: &'a [u8], linear_sweep: bool, symbols: &SymbolTable) -> Listing<'a> {
+        let mut listing = Listing {
+            bank: bank,
+            bytes: bytes,
+            starts: vec![false; bytes.len()],
+            covered: vec![false; bytes.len()],
+            labels: BTreeMap::new(),
+        };
+        let vectors = listing.vectors();
+        listing.traverse(vectors.iter().map(|&(_, addr)| addr).collect());
+        if linear_sweep {
+            listing.sweep();
+        }
+        listing.find_labels(&vectors, symbols);
+        return listing
+    }
+
+    // The vectors' names and targets, if they're in this bank
+    fn vectors(&self) -> Vec<(&'static str, u16)> {
+        let mut vectors = Vec::new();
+        for &(name, vector) in [("nmi", NMI_VECTOR), ("reset", RESET_VECTOR), ("irq", IRQ_VECTOR)].iter() {
+            if self.bank.contains(vector) {
+                let i = self.bank.index_of(vector);
+                vectors.push((name, (self.bytes[i + 1] as u16) << 8 | self.bytes[i] as u16));
+            }
+        }
+        return vectors
+    }
+
+    // Mark an instruction, unless it overlaps one already found
+    fn mark(&mut self, i: usize, op: Opcode) -> bool {
+        let end = i + op.len as usize;
+        if self.covered[i..end].iter().any(|&c| c) {
+            return false
+        }
+        self.starts[i] = true;
+        for c in self.covered[i..end].iter_mut() {
+            *c = true;
+        }
+        true
+    }
+
+    // Recursive traversal: follow every path from the entry points that stays in the bank
+    fn traverse(&mut self, mut work: Vec<u16>) {
+        while let Some(mut addr) = work.pop() {
+            while self.bank.contains(addr) {
+                let i = self.bank.index_of(addr);
+                let op = match decode(self.bytes, i) {
+                    Some(op) => op,
+                    None => break,
+                };
+                if !self.mark(i, op) {
+                    break;
+                }
+                if let Some(target) = target(op, self.bytes, i, addr) {
+                    work.push(target);
+                }
+                if ends_flow(op) {
+                    break;
+                }
+                addr = match addr.checked_add(op.len as u16) {
+                    Some(next) => next,
+                    None => break,
+                };
+            }
+        }
+    }
+
+    // Decode whatever the traversal didn't reach, in order, where it fits between what it did
+    fn sweep(&mut self) {
+        let mut i = 0;
+        while i < self.bytes.len() {
+            if self.covered[i] {
+                i += 1;
+                continue;
+            }
+            match decode(self.bytes, i) {
+                Some(op) if self.mark(i, op) => { i += op.len as usize; },
+                _ => { i += 1; },
+            }
+        }
+    }
+
+    // Symbols, vector targets by name, and branch and jump targets in the bank as Lxxxx. Only
+    // instruction starts get labels, so the listing assembles back to the same bytes.
+    fn find_labels(&mut self, vectors: &[(&'static str, u16)], symbols: &SymbolTable) {
+        let index = self.bank.index;
+        let names = symbols.iter()
+            .filter(|symbol| symbol.bank.is_none_or(|bank| bank == index))
+            .map(|symbol| (symbol.name.as_str(), symbol.addr))
+            .chain(vectors.iter().cloned());
+        for (name, addr) in names {
+            if self.bank.contains(addr) && self.starts[self.bank.index_of(addr)] {
+                let labels = self.labels.entry(addr).or_default();
+                if !labels.iter().any(|label| label.eq_ignore_ascii_case(name)) {
+                    labels.push(name.to_string());
+                }
+            }
+        }
+        for i in 0..self.bytes.len() {
+            if !self.starts[i] {
+                continue;
+            }
+            let op = OPCODE_TABLE[self.bytes[i] as usize];
+            let addr = self.bank.base + i as u16;
+            if let Some(target) = target(op, self.bytes, i, addr) {
+                if self.bank.contains(target) && self.starts[self.bank.index_of(target)] && !self.labels.contains_key(&target) {
+                    self.labels.insert(target, vec![format!("L{:04X}", target)]);
+                }
+            }
+        }
+    }
+
+    fn label(&self, addr: u16) -> Option<&str> {
+        self.labels.get(&addr).map(|names| names[0].as_str())
+    }
+
+    fn write(&self, out: &mut String) {
+        let bank = self.bank;
+        writeln!(out, "; Bank {}: PRG ${:05X}-${:05X} at ${:04X}{}", bank.index, bank.offset,
+                 bank.offset + bank.len - 1, bank.base, if bank.fixed { " (fixed)" } else { "" }).unwrap();
+
+        // The vector table, when it's in this bank and isn't code
+        let table = if bank.contains(NMI_VECTOR) { Some(self.bank.index_of(NMI_VECTOR)) } else { None };
+        let mut i = 0;
+        while i < self.bytes.len() {
+            let addr = bank.base.wrapping_add(i as u16);
+            for name in self.labels.get(&addr).into_iter().flatten() {
+                writeln!(out, "{}:", name).unwrap();
+            }
+
+            if self.starts[i] {
+                let op = OPCODE_TABLE[self.bytes[i] as usize];
+                let len = op.len as usize;
+                let (mut text, _) = opcodes::disassemble(&self.bytes[i..i + len], addr);
+                if let Some(label) = target(op, self.bytes, i, addr).and_then(|target| self.label(target).map(|label| (target, label))) {
+                    text = text.replace(&format!("${:04X}", label.0), label.1);
+                }
+                writeln!(out, "{}{:<23} ; ${:04X}", INDENT, text, addr).unwrap();
+                i += len;
+            } else if table == Some(i) && self.covered[i..i + 6].iter().all(|&c| !c) {
+                let words: Vec<String> = (0..3).map(|n| {
+                    let vector = (self.bytes[i + 2 * n + 1] as u16) << 8 | self.bytes[i + 2 * n] as u16;
+                    self.label(vector).map_or_else(|| format!("${:04X}", vector), |label| label.to_string())
+                }).collect();
+                writeln!(out, "{}{:<23} ; ${:04X}", INDENT, format!(".word {}", words.join(", ")), addr).unwrap();
+                i += 6;
+            } else {
+                // Data runs up to the next instruction, the vector table or a full line
+                let mut end = i + 1;
+                while end < self.bytes.len() && end - i < BYTES_PER_LINE && !self.covered[end] && table != Some(end) {
+                    end += 1;
+                }
+                let data: Vec<String> = self.bytes[i..end].iter().map(|b| format!("${:02X}", b)).collect();
+                writeln!(out, "{}{:<23} ; ${:04X}", INDENT, format!(".byte {}", data.join(", ")), addr).unwrap();
+                i = end;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm;
+
+    const PROGRAM: &str = "
+reset:  LDX #$00
+loop:   JSR double
+        INX
+        CPX #4
+        BNE loop
+done:   JMP done
+double: LDA table,X
+        ASL A
+        STA $10,X
+        RTS
+table:  .byte $01, $02, $03, $04, $05, $06, $07, $08, $09
+nmi:    RTI
+";
+
+    // An NROM-128 image with the program at $C000, or a mapper's image with that as its last
+    // 16 KiB and the banks before it numbered by their first byte
+    fn rom(mapper: u8, prg_banks: u8) -> ROM {
+        let (program, labels) = asm::assemble_with_labels(PROGRAM, 0xC000).unwrap();
+        let mut last = vec![0; 0x4000];
+        last[..program.len()].copy_from_slice(&program);
+        for (i, &vector) in [labels["nmi"], labels["reset"], labels["nmi"]].iter().enumerate() {
+            last[0x3FFA + i * 2] = vector as u8;
+            last[0x3FFB + i * 2] = (vector >> 8) as u8;
+        }
+        let mut image = vec![b'N', b'E', b'S', 0x1A, prg_banks, 0, mapper << 4, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        for bank in 1..prg_banks {
+            image.extend_from_slice(&[bank; 0x4000]);
+        }
+        image.extend_from_slice(&last);
+        ROM::from_bytes(&image).unwrap()
+    }
+
+    #[test]
+    fn listing_labels_code_and_keeps_data() {
+        let listing = disassemble(&rom(0, 1), false).unwrap();
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines[..21], [
+            "; Bank 0: PRG $00000-$03FFF at $C000 (fixed)",
+            "reset:",
+            "    LDX #$00                ; $C000",
+            "LC002:",
+            "    JSR LC00D               ; $C002",
+            "    INX                     ; $C005",
+            "    CPX #$04                ; $C006",
+            "    BNE LC002               ; $C008",
+            "LC00A:",
+            "    JMP LC00A               ; $C00A",
+            "LC00D:",
+            "    LDA $C014,X             ; $C00D",
+            "    ASL A                   ; $C010",
+            "    STA $10,X               ; $C011",
+            "    RTS                     ; $C013",
+            "    .byte $01, $02, $03, $04, $05, $06, $07, $08 ; $C014",
+            "    .byte $09               ; $C01C",
+            "nmi:",
+            "irq:",
+            "    RTI                     ; $C01D",
+            "    .byte $00, $00, $00, $00, $00, $00, $00, $00 ; $C01E",
+        ]);
+        assert_eq!(listing.trim_end().lines().last(), Some("    .word nmi, reset, nmi   ; $FFFA"));
+    }
+
+    #[test]
+    fn listings_assemble_back_to_the_same_bytes() {
+        for &(mapper, prg_banks) in [(0, 1), (0, 2), (1, 4), (2, 4), (4, 4)].iter() {
+            let rom = rom(mapper, prg_banks);
+            for &linear_sweep in [false, true].iter() {
+                let listing = disassemble(&rom, linear_sweep).unwrap();
+                let sections: Vec<&str> = listing.split("\n\n").filter(|section| !section.is_empty()).collect();
+                let banks = banks(&rom).unwrap();
+                assert_eq!(sections.len(), banks.len());
+                for (section, bank) in sections.iter().zip(banks.iter()) {
+                    let bytes = asm::assemble(section, bank.base).unwrap();
+                    assert!(bytes[..] == rom.prg[bank.offset..bank.offset + bank.len],
+                            "mapper {} bank {} sweep {}", mapper, bank.index, linear_sweep);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn symbols_replace_the_generated_labels() {
+        let (_, labels) = asm::assemble_with_labels(PROGRAM, 0xC000).unwrap();
+        let rom = rom(0, 1);
+        let mut symbols = SymbolTable::new();
+        symbols.parse(&format!("twice = ${:04X}\nelsewhere = 1:${:04X}", labels["double"], labels["loop"])).unwrap();
+        let listing = disassemble_with(&rom, false, &symbols).unwrap();
+        assert!(listing.contains("\ntwice:") && listing.contains("JSR twice"), "{}", listing);
+        // A symbol for another bank doesn't apply
+        assert!(!listing.contains("elsewhere"), "{}", listing);
+        assert!(asm::assemble(&listing, 0xC000).unwrap()[..] == rom.prg[..]);
+    }
+
+    #[test]
+    fn banks_follow_the_mapper_layout() {
+        let layout = |mapper, prg_banks| -> Vec<(usize, u16, bool)> {
+            banks(&rom(mapper, prg_banks)).unwrap().iter().map(|bank| (bank.offset, bank.base, bank.fixed)).collect()
+        };
+        assert_eq!(layout(0, 1), [(0, 0xC000, true)]);
+        assert_eq!(layout(0, 2), [(0, 0x8000, true)]);
+        assert_eq!(layout(2, 4), [(0, 0x8000, false), (0x4000, 0x8000, false), (0x8000, 0x8000, false),
+                                  (0xC000, 0xC000, true)]);
+        let mmc3 = layout(4, 2);
+        assert_eq!(mmc3.len(), 4);
+        assert_eq!(mmc3[1..], [(0x2000, 0x8000, false), (0x4000, 0xC000, true), (0x6000, 0xE000, true)]);
+        assert_eq!(banks(&rom(5, 1)), Err(DisasmError::UnsupportedMapper(5)));
+    }
+}
@@ -0,0 +1,191 @@
+// The APU's delta modulation channel. The DMC reads its samples straight from the CPU bus,
+// stealing cycles from the CPU to do it, and raises an IRQ when a sample ends; games time
+// raster effects and controller reads around both. Each bit of a sample byte moves a 7-bit
+// output level up or down by 2, and the APU mixes that level in with the other channels.
+
+use region::TimingConfig;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+// $4010 bits
+const IRQ_ENABLE: u8 = 1 << 7;
+const LOOP: u8 = 1 << 6;
+// $4015 bits
+pub const STATUS_ACTIVE: u8 = 1 << 4;
+pub const STATUS_IRQ: u8 = 1 << 7;
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dmc {
+    // Timer periods in CPU cycles for the region, by $4010's rate index
+    rates: [u16; 16],
+    irq_enabled: bool,
+    looping: bool,
+    period: u16,
+    timer: u16,
+    // Where and how long a sample is, from $4012 and $4013
+    sample_address: u16,
+    sample_length: u16,
+    // Where the next byte comes from, and how many are left
+    address: u16,
+    bytes_remaining: u16,
+    // The byte fetched for the output unit to play next
+    buffer: Option<u8>,
+    // The byte being played, shifted out low bit first, and how many bits are still to go
+    shift: u8,
+    bits_remaining: u8,
+    // Set for an output cycle that started with nothing in the buffer, which holds the level
+    silence: bool,
+    level: u8,
+    irq_flag: bool,
+}
+
+impl Dmc {
+    pub fn new(timing: &TimingConfig) -> Dmc {
+        Dmc {
+            rates: timing.dmc_rates,
+            irq_enabled: false,
+            looping: false,
+            period: timing.dmc_rates[0],
+            timer: timing.dmc_rates[0],
+            sample_address: 0xC000,
+            sample_length: 1,
+            address: 0xC000,
+            bytes_remaining: 0,
+            buffer: None,
+            shift: 0,
+            bits_remaining: 0,
+            silence: true,
+            level: 0,
+            irq_flag: false,
+        }
+    }
+
+    pub fn set_timing(&mut self, timing: &TimingConfig) {
+        let index = self.rates.iter().position(|&rate| rate == self.period).unwrap_or(0);
+        self.rates = timing.dmc_rates;
+        self.period = self.rates[index];
+    }
+
+    // $4010-$4013
+    pub fn write(&mut self, addr: u16, val: u8) {
+        match addr & 3 {
+            0 => {
+                self.irq_enabled = val & IRQ_ENABLE != 0;
+                self.looping = val & LOOP != 0;
+                self.period = self.rates[(val & 0x0F) as usize];
+                if !self.irq_enabled {
+                    self.irq_flag = false;
+                }
+            },
+            1 => { self.level = val & 0x7F; },
+            2 => { self.sample_address = 0xC000 | (val as u16) << 6; },
+            _ => { self.sample_length = (val as u16) << 4 | 1; },
+        }
+    }
+
+    // $4015's DMC bit. Enabling starts the sample over if it had finished; either way the IRQ
+    // is acknowledged.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq_flag = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    // The DMC's bits of $4015
+    pub fn status(&self) -> u8 {
+        let mut status = 0;
+        if self.bytes_remaining > 0 {
+            status |= STATUS_ACTIVE;
+        }
+        if self.irq_flag {
+            status |= STATUS_IRQ;
+        }
+        return status
+    }
+
+    pub fn irq(&self) -> bool {
+        self.irq_flag
+    }
+
+    fn restart(&mut self) {
+        self.address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    // One CPU cycle. The APU clocks the DMC along with its other channels.
+    pub fn tick(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return
+        }
+        self.timer = self.period - 1;
+        if self.bits_remaining > 0 {
+            if !self.silence {
+                if self.shift & 1 != 0 {
+                    if self.level <= 125 {
+                        self.level += 2;
+                    }
+                } else if self.level >= 2 {
+                    self.level -= 2;
+                }
+            }
+            self.shift >>= 1;
+            self.bits_remaining -= 1;
+        }
+        // A new output cycle takes the buffered byte to play, emptying the buffer
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.buffer.take() {
+                Some(val) => {
+                    self.shift = val;
+                    self.silence = false;
+                },
+                None => self.silence = true,
+            }
+        }
+    }
+
+    // Cycles until the timer next runs out
+    pub fn quiet_cycles(&self) -> u16 {
+        self.timer
+    }
+
+    // Count the timer down without it running out
+    pub fn skip(&mut self, cycles: u16) {
+        self.timer -= cycles;
+    }
+
+    // The output level, 0 to 127
+    pub fn output(&self) -> u8 {
+        self.level
+    }
+
+    // Whether the buffer is empty with sample left to fetch into it
+    pub fn needs_fetch(&self) -> bool {
+        self.buffer.is_none() && self.bytes_remaining > 0
+    }
+
+    // Where the next fetch reads from
+    pub fn fetch_address(&self) -> u16 {
+        self.address
+    }
+
+    // Take the byte a fetch read. Addresses past $FFFF wrap to $8000.
+    pub fn fill(&mut self, val: u8) {
+        self.buffer = Some(val);
+        self.address = if self.address == 0xFFFF { 0x8000 } else { self.address + 1 };
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq_flag = true;
+            }
+        }
+    }
+}
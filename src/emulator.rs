@@ -0,0 +1,1051 @@
+use apu;
+use controller::InputFrame;
+use cpu;
+use fds;
+use mapper;
+use mem::RamInit;
+use osd::{self, Osd};
+use overscan::{CroppedFrame, Overscan};
+use palette;
+use patch::{self, PatchError};
+use ppu::AccuracyLevel;
+use region::Region;
+use rom;
+use state::{SaveState, StateError};
+
+use std::fmt;
+use std::fs;
+use std::ops::ControlFlow;
+
+#[derive(Debug)]
+pub enum BuildError {
+    MissingRom,
+    Rom(rom::RomError),
+    Fds(fds::FdsError),
+    // Disk images need the FDS BIOS, see EmulatorBuilder::fds_bios
+    MissingFdsBios,
+    Patch(PatchError),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BuildError::MissingRom => write!(f, "no ROM was given"),
+            BuildError::Rom(ref e) => write!(f, "{}", e),
+            BuildError::Fds(ref e) => write!(f, "{}", e),
+            BuildError::MissingFdsBios => write!(f, "disk images need the FDS BIOS (disksys.rom)"),
+            BuildError::Patch(ref e) => write!(f, "can't apply patch: {}", e),
+        }
+    }
+}
+
+impl From<rom::RomError> for BuildError {
+    fn from(e: rom::RomError) -> BuildError {
+        BuildError::Rom(e)
+    }
+}
+
+impl From<PatchError> for BuildError {
+    fn from(e: PatchError) -> BuildError {
+        BuildError::Patch(e)
+    }
+}
+
+impl From<fds::FdsError> for BuildError {
+    fn from(e: fds::FdsError) -> BuildError {
+        BuildError::Fds(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SwapError {
+    // The machine's timing is fixed when it's built, so a ROM for the other region can't go in
+    RegionMismatch { running: Region, rom: Region },
+}
+
+impl fmt::Display for SwapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SwapError::RegionMismatch { running, rom } =>
+                write!(f, "the ROM is for {} but the console is running as {}", rom, running),
+        }
+    }
+}
+
+enum RomSource {
+    Path(String),
+    Bytes(Vec<u8>),
+    Raw { data: Vec<u8>, load_addr: u16 },
+}
+
+// What one call to Nes::run_frame produced
+pub struct FrameOutput<'a> {
+    // NES color indices, SCREEN_WIDTH x SCREEN_HEIGHT
+    pub framebuffer: &'a [u8],
+    // Mono samples generated during the frame, from -1.0 to 1.0 at the builder's sample rate
+    pub audio: &'a [f32],
+    // Frames completed since power-on, including this one
+    pub frame: u64,
+    // CPU cycles the frame took
+    pub cycles: u64,
+    // The frame wasn't run to the end, because emulation is paused or a callback asked to pause
+    pub paused: bool,
+    // Messages to show over the frame
+    pub osd: &'a Osd,
+}
+
+impl<'a> FrameOutput<'a> {
+    // The picture without the edges a TV would hide
+    pub fn cropped(&self, overscan: Overscan) -> CroppedFrame<'a> {
+        CroppedFrame::new(self.framebuffer, overscan)
+    }
+
+    // A copy of the frame with the on-screen messages drawn over it, for display
+    pub fn with_osd(&self) -> Vec<u8> {
+        self.osd.composite(self.framebuffer)
+    }
+}
+
+// What event callbacks get to look at. The machine can't be changed from inside a callback.
+pub struct NesView<'a> {
+    pub cpu: &'a cpu::CPU,
+    pub frame: u64,
+    pub scanline: u16,
+}
+
+// Returning ControlFlow::Break pauses the emulator after the current instruction, until resume()
+pub type Callback = Box<dyn FnMut(&NesView) -> ControlFlow<()> + Send>;
+pub type ScanlineCallback = Box<dyn FnMut(u16, &NesView) -> ControlFlow<()> + Send>;
+
+#[derive(Default)]
+struct Callbacks {
+    frame: Vec<Callback>,
+    scanline: Vec<ScanlineCallback>,
+    nmi: Vec<Callback>,
+}
+
+// A powered-on console
+pub struct Nes {
+    cpu: cpu::CPU,
+    region: Region,
+    sample_rate: u32,
+    // The last frame's samples, as handed out in FrameOutput
+    audio: Vec<f32>,
+    callbacks: Callbacks,
+    // Set by pause(), run_frame does nothing until resume()
+    paused: bool,
+    osd: Osd,
+    // Whether each frame's latched buttons go to the OSD's input display
+    input_display: bool,
+}
+
+impl Nes {
+    pub fn builder() -> EmulatorBuilder {
+        EmulatorBuilder::new()
+    }
+
+    pub fn cpu(&self) -> &cpu::CPU {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut cpu::CPU {
+        &mut self.cpu
+    }
+
+    // Called each time the PPU finishes a frame
+    pub fn on_frame<F: FnMut(&NesView) -> ControlFlow<()> + Send + 'static>(&mut self, callback: F) {
+        self.callbacks.frame.push(Box::new(callback));
+    }
+
+    // Called as each scanline starts, with its number
+    pub fn on_scanline<F: FnMut(u16, &NesView) -> ControlFlow<()> + Send + 'static>(&mut self, callback: F) {
+        self.callbacks.scanline.push(Box::new(callback));
+    }
+
+    // Called after the CPU has taken an NMI
+    pub fn on_nmi<F: FnMut(&NesView) -> ControlFlow<()> + Send + 'static>(&mut self, callback: F) {
+        self.callbacks.nmi.push(Box::new(callback));
+    }
+
+    // Execute one instruction and fire the callbacks for whatever it caused. Breaks if any
+    // callback asked to pause.
+    pub fn step(&mut self) -> Result<ControlFlow<()>, cpu::EmulationError> {
+        let scanline = self.cpu.memory().ppu.scanline();
+        let frame = self.cpu.memory().ppu.frame;
+        let nmis = self.cpu.nmi_count;
+        self.cpu.emulate_cycle()?;
+
+        let ppu = &self.cpu.memory().ppu;
+        let view = NesView {
+            cpu: &self.cpu,
+            frame: ppu.frame,
+            scanline: ppu.scanline(),
+        };
+        let mut flow = ControlFlow::Continue(());
+        // OAM DMA stalls the CPU for over four scanlines, so one instruction can start several
+        let mut line = scanline;
+        while line != view.scanline {
+            line = (line + 1) % ppu.scanlines_per_frame();
+            for callback in self.callbacks.scanline.iter_mut() {
+                if callback(line, &view).is_break() { flow = ControlFlow::Break(()); }
+            }
+        }
+        if view.frame != frame {
+            for callback in self.callbacks.frame.iter_mut() {
+                if callback(&view).is_break() { flow = ControlFlow::Break(()); }
+            }
+        }
+        if self.cpu.nmi_count != nmis {
+            for callback in self.callbacks.nmi.iter_mut() {
+                if callback(&view).is_break() { flow = ControlFlow::Break(()); }
+            }
+        }
+        return Ok(flow)
+    }
+
+    // Hold input's buttons and run until the PPU reaches the start of vblank, which is where a
+    // finished picture is available. The instruction that crosses the boundary runs to
+    // completion, so its extra cycles count towards the next frame. Stops early if a callback
+    // pauses. While paused, nothing runs and the last frame is handed back again with no audio.
+    pub fn run_frame(&mut self, input: InputFrame) -> Result<FrameOutput<'_>, cpu::EmulationError> {
+        self.apply_input(input);
+        if self.paused {
+            self.audio.clear();
+            let ppu = &self.cpu.memory().ppu;
+            return Ok(FrameOutput {
+                framebuffer: &ppu.framebuffer,
+                audio: &[],
+                frame: ppu.frame,
+                cycles: 0,
+                paused: true,
+                osd: &self.osd,
+            });
+        }
+        self.run_one_frame()
+    }
+
+    // Run exactly one frame whether or not emulation is paused, and stay paused afterwards
+    pub fn frame_advance(&mut self, input: InputFrame) -> Result<FrameOutput<'_>, cpu::EmulationError> {
+        self.paused = true;
+        self.apply_input(input);
+        self.run_one_frame()
+    }
+
+    // One frame per input. Resyncing after a rollback is load_state() and then this with the
+    // inputs recorded since the state was taken.
+    pub fn run_frames_with(&mut self, inputs: &[InputFrame]) -> Result<(), cpu::EmulationError> {
+        for &input in inputs {
+            self.run_frame(input)?;
+        }
+        Ok(())
+    }
+
+    // Buttons only live in the controllers, which pick them up here each frame
+    fn apply_input(&mut self, input: InputFrame) {
+        let memory = self.cpu.memory_mut();
+        memory.controllers[0].set_buttons(input.ports[0]);
+        memory.controllers[1].set_buttons(input.ports[1]);
+        if let Some(ref mut four_score) = memory.four_score {
+            four_score.set_buttons([input.ports[0], input.ports[1], input.four_score[0], input.four_score[1]]);
+        }
+    }
+
+    pub fn save_state(&mut self) -> Result<SaveState, StateError> {
+        let state = self.cpu.save_state()?;
+        self.osd.notify("State saved", osd::DEFAULT_DURATION);
+        Ok(state)
+    }
+
+    pub fn load_state(&mut self, state: &SaveState) -> Result<(), StateError> {
+        self.cpu.load_state(state)?;
+        self.osd.notify("State loaded", osd::DEFAULT_DURATION);
+        Ok(())
+    }
+
+    // Swap the disk in an FDS drive for another side, numbered from 0. The drive stays empty
+    // for a moment first, the way the BIOS expects a swap to look. False if there's no drive
+    // or no such side.
+    pub fn insert_disk_side(&mut self, side: usize) -> bool {
+        let inserted = self.cpu.memory().mapper.borrow_mut().insert_disk_side(side);
+        if inserted {
+            let text = format!("Disk {} side {}", side / 2 + 1, (b'A' + (side % 2) as u8) as char);
+            self.osd.notify(&text, osd::DEFAULT_DURATION);
+        }
+        inserted
+    }
+
+    // Replace the cartridge and power on, for reloading a ROM that was just rebuilt. PRG RAM
+    // is kept if the new board has as much as the old one. Settings, callbacks, cheats and
+    // whatever the frontend keeps, like breakpoints and symbols, stay as they were.
+    pub fn swap_rom(&mut self, rom: rom::ROM) -> Result<(), SwapError> {
+        if let Some(region) = rom.header.region().filter(|&region| region != self.region) {
+            return Err(SwapError::RegionMismatch { running: self.region, rom: region })
+        }
+        let mapper = mapper::for_rom(&rom);
+        self.cpu.swap_cartridge(rom, mapper);
+        self.audio.clear();
+        self.osd.notify("ROM reloaded", osd::DEFAULT_DURATION);
+        Ok(())
+    }
+
+    // On-screen messages, which frontends draw with FrameOutput::with_osd. Notify through
+    // osd_mut() for frontend events like toggling fast-forward.
+    pub fn osd(&self) -> &Osd {
+        &self.osd
+    }
+
+    pub fn osd_mut(&mut self) -> &mut Osd {
+        &mut self.osd
+    }
+
+    // Show what the controllers latched each frame in the corner of the OSD, for checking
+    // input handling and TAS work
+    pub fn set_input_display(&mut self, enabled: bool) {
+        self.input_display = enabled;
+        if !enabled {
+            self.osd.hide_input();
+        }
+    }
+
+    pub fn input_display(&self) -> bool {
+        self.input_display
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    fn run_one_frame(&mut self) -> Result<FrameOutput<'_>, cpu::EmulationError> {
+        let start_frame = self.cpu.memory().ppu.frame;
+        let start_cycles = self.cpu.cycles;
+        let mut paused = false;
+        while self.cpu.memory().ppu.frame == start_frame {
+            if self.step()?.is_break() {
+                self.paused = true;
+                paused = self.cpu.memory().ppu.frame == start_frame;
+                break;
+            }
+        }
+        if !paused {
+            self.osd.tick();
+        }
+        if self.input_display {
+            let latched = self.cpu.memory().latched_buttons();
+            self.osd.show_input(&latched);
+        }
+        // The APU's samples are integers so they hash the same everywhere; they only become
+        // floats here, on the way out
+        let apu = &mut self.cpu.memory_mut().apu;
+        self.audio.clear();
+        self.audio.extend(apu.samples().iter().map(|&sample| sample as f32 / 32768.0));
+        apu.clear_samples();
+
+        let ppu = &self.cpu.memory().ppu;
+        Ok(FrameOutput {
+            framebuffer: &ppu.framebuffer,
+            audio: &self.audio,
+            frame: ppu.frame,
+            cycles: self.cpu.cycles - start_cycles,
+            paused: paused,
+            osd: &self.osd,
+        })
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    // What the program has printed through the debug device, empty if it isn't wired up. Once
+    // the program exits, stepping fails with EmulationError::Exited.
+    pub fn debug_output(&self) -> &str {
+        self.cpu.memory().debug_device.as_ref().map_or("", |device| device.output())
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // The samples from the last call to run_frame or frame_advance, the same as its
+    // FrameOutput had
+    pub fn audio(&self) -> &[f32] {
+        &self.audio
+    }
+}
+
+pub struct EmulatorBuilder {
+    rom: Option<RomSource>,
+    fds_bios: Option<String>,
+    // IPS or BPS patches, applied in order
+    patches: Vec<String>,
+    region: Option<Region>,
+    palette: palette::Palette,
+    sample_rate: u32,
+    muted: Vec<apu::Channel>,
+    ram_init: RamInit,
+    illegal_opcode_policy: cpu::IllegalOpcodePolicy,
+    start_at: Option<u16>,
+    sprite_limit: bool,
+    ppu_warmup: bool,
+    ppu_accuracy: AccuracyLevel,
+    bus_accuracy: AccuracyLevel,
+    step_mode: cpu::StepMode,
+    four_score: bool,
+    debug_device: bool,
+}
+
+impl Default for EmulatorBuilder {
+    fn default() -> EmulatorBuilder { EmulatorBuilder::new() }
+}
+
+impl EmulatorBuilder {
+    pub fn new() -> EmulatorBuilder {
+        EmulatorBuilder {
+            rom: None,
+            fds_bios: None,
+            patches: Vec::new(),
+            region: None,
+            palette: palette::SYSTEM_PALETTE,
+            sample_rate: apu::DEFAULT_SAMPLE_RATE,
+            muted: Vec::new(),
+            ram_init: RamInit::default(),
+            illegal_opcode_policy: cpu::IllegalOpcodePolicy::default(),
+            start_at: None,
+            sprite_limit: true,
+            ppu_warmup: true,
+            ppu_accuracy: AccuracyLevel::default(),
+            bus_accuracy: AccuracyLevel::default(),
+            step_mode: cpu::StepMode::default(),
+            four_score: false,
+            debug_device: false,
+        }
+    }
+
+    pub fn rom_path(mut self, path: &str) -> EmulatorBuilder {
+        self.rom = Some(RomSource::Path(path.to_string()));
+        self
+    }
+
+    pub fn rom_bytes(mut self, data: &[u8]) -> EmulatorBuilder {
+        self.rom = Some(RomSource::Bytes(data.to_vec()));
+        self
+    }
+
+    // A headerless program instead of an iNES file, see ROM::raw_binary
+    pub fn raw_binary(mut self, data: &[u8], load_addr: u16) -> EmulatorBuilder {
+        self.rom = Some(RomSource::Raw { data: data.to_vec(), load_addr: load_addr });
+        self
+    }
+
+    // The FDS BIOS (disksys.rom), needed to run disk images
+    pub fn fds_bios(mut self, path: &str) -> EmulatorBuilder {
+        self.fds_bios = Some(path.to_string());
+        self
+    }
+
+    // Apply an IPS or BPS patch to the ROM file before loading it. Patches stack, in the order
+    // they're added.
+    pub fn patch(mut self, path: &str) -> EmulatorBuilder {
+        self.patches.push(path.to_string());
+        self
+    }
+
+    // Start executing here instead of at the reset vector
+    pub fn start_at(mut self, addr: u16) -> EmulatorBuilder {
+        self.start_at = Some(addr);
+        self
+    }
+
+    // Overrides whatever region the ROM header asks for
+    pub fn region(mut self, region: Region) -> EmulatorBuilder {
+        self.region = Some(region);
+        self
+    }
+
+    pub fn palette(mut self, palette: palette::Palette) -> EmulatorBuilder {
+        self.palette = palette;
+        self
+    }
+
+    pub fn sample_rate(mut self, sample_rate: u32) -> EmulatorBuilder {
+        self.sample_rate = sample_rate;
+        self
+    }
+
+    // Leave a sound channel out of the mix, see APU::set_channel_enabled
+    pub fn mute(mut self, channel: apu::Channel) -> EmulatorBuilder {
+        self.muted.push(channel);
+        self
+    }
+
+    pub fn ram_init(mut self, ram_init: RamInit) -> EmulatorBuilder {
+        self.ram_init = ram_init;
+        self
+    }
+
+    pub fn illegal_opcode_policy(mut self, policy: cpu::IllegalOpcodePolicy) -> EmulatorBuilder {
+        self.illegal_opcode_policy = policy;
+        self
+    }
+
+    // Whether to drop sprites past the eighth on a line, as the hardware does
+    pub fn sprite_limit(mut self, enabled: bool) -> EmulatorBuilder {
+        self.sprite_limit = enabled;
+        self
+    }
+
+    // Whether the PPU ignores its control and scroll registers for the first frame or so after
+    // power-on and reset, as the hardware does. Programs that don't wait for it need it off.
+    pub fn ppu_warmup(mut self, enabled: bool) -> EmulatorBuilder {
+        self.ppu_warmup = enabled;
+        self
+    }
+
+    pub fn ppu_accuracy(mut self, accuracy: AccuracyLevel) -> EmulatorBuilder {
+        self.ppu_accuracy = accuracy;
+        self
+    }
+
+    // Only the DMC's controller read glitch
+    pub fn bus_accuracy(mut self, accuracy: AccuracyLevel) -> EmulatorBuilder {
+        self.bus_accuracy = accuracy;
+        self
+    }
+
+    // The PPU and the bus both
+    pub fn accuracy(self, accuracy: AccuracyLevel) -> EmulatorBuilder {
+        self.ppu_accuracy(accuracy).bus_accuracy(accuracy)
+    }
+
+    pub fn step_mode(mut self, mode: cpu::StepMode) -> EmulatorBuilder {
+        self.step_mode = mode;
+        self
+    }
+
+    // Plug in a Four Score for four players instead of the two standard controllers
+    pub fn four_score(mut self, enabled: bool) -> EmulatorBuilder {
+        self.four_score = enabled;
+        self
+    }
+
+    // Wire up the debug device test programs print and exit through, see debug_device
+    pub fn debug_device(mut self, enabled: bool) -> EmulatorBuilder {
+        self.debug_device = enabled;
+        self
+    }
+
+    pub fn build(self) -> Result<Nes, BuildError> {
+        let (rom, mapper) = match self.rom {
+            Some(RomSource::Path(ref path)) => self.load_image(&self.patched(fs::read(path).map_err(rom::RomError::from)?)?)?,
+            Some(RomSource::Bytes(ref data)) => self.load_image(&self.patched(data.clone())?)?,
+            Some(RomSource::Raw { ref data, load_addr }) => {
+                let rom = rom::ROM::raw_binary(&self.patched(data.clone())?, load_addr)?;
+                let mapper = mapper::for_rom(&rom);
+                (rom, mapper)
+            },
+            None => return Err(BuildError::MissingRom),
+        };
+        let region = self.region.or_else(|| rom.header.region()).unwrap_or(Region::Ntsc);
+
+        let mut cpu = cpu::CPU::with_mapper(rom, mapper);
+        cpu.set_region(region);
+        cpu.illegal_opcode_policy = self.illegal_opcode_policy;
+        cpu.step_mode = self.step_mode;
+        cpu.memory_mut().ram_init = self.ram_init;
+        cpu.memory_mut().ppu.rgb_palette = self.palette;
+        cpu.memory_mut().ppu.sprite_limit = self.sprite_limit;
+        cpu.memory_mut().ppu.warmup = self.ppu_warmup;
+        cpu.memory_mut().ppu.accuracy = self.ppu_accuracy;
+        cpu.memory_mut().apu.set_sample_rate(self.sample_rate);
+        for &channel in self.muted.iter() {
+            cpu.memory_mut().apu.set_channel_enabled(channel, false);
+        }
+        cpu.memory_mut().accuracy = self.bus_accuracy;
+        cpu.memory_mut().set_four_score(self.four_score);
+        cpu.memory_mut().set_debug_device(self.debug_device);
+        cpu.power_on();
+        if let Some(addr) = self.start_at {
+            cpu.set_pc(addr);
+        }
+
+        Ok(Nes {
+            cpu: cpu,
+            region: region,
+            sample_rate: self.sample_rate,
+            audio: Vec::new(),
+            callbacks: Callbacks::default(),
+            paused: false,
+            osd: Osd::new(),
+            input_display: false,
+        })
+    }
+
+    // The file's bytes with every patch applied, before anything looks at its header
+    fn patched(&self, mut data: Vec<u8>) -> Result<Vec<u8>, PatchError> {
+        for path in self.patches.iter() {
+            patch::apply(&mut data, &fs::read(path)?)?;
+        }
+        Ok(data)
+    }
+
+    // An iNES file, or a disk image run through the FDS BIOS
+    fn load_image(&self, data: &[u8]) -> Result<(rom::ROM, Box<dyn mapper::Mapper>), BuildError> {
+        if !fds::is_disk_image(data) {
+            let rom = rom::ROM::from_bytes(data)?;
+            let mapper = mapper::for_rom(&rom);
+            return Ok((rom, mapper))
+        }
+        let image = fds::FdsImage::from_bytes(data)?;
+        let path = self.fds_bios.as_ref().ok_or(BuildError::MissingFdsBios)?;
+        let bios = fs::read(path).map_err(fds::FdsError::from)?;
+        let rom = fds::FdsImage::rom(&bios)?;
+        Ok((rom, Box::new(fds::FdsMapper::new(&image, &bios))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use debugger::Debugger;
+    use mem::Addressable;
+    use movie;
+    use testing;
+
+    use std::sync::{Arc, Mutex};
+
+    // Latches the controllers at the start of every NMI, without reading them
+    const STROBE_EVERY_NMI: &str = "
+reset:  LDA #$80
+        STA $2000
+loop:   JMP loop
+nmi:    LDA #$01
+        STA $4016
+        LDA #$00
+        STA $4016
+        RTI
+";
+
+    fn idle_rom() -> Vec<u8> {
+        testing::build_test_rom("loop: JMP loop", None)
+    }
+
+    #[test]
+    fn builder_needs_a_rom_it_can_load() {
+        assert!(matches!(Nes::builder().build(), Err(BuildError::MissingRom)));
+        assert!(matches!(Nes::builder().rom_path("/nonexistent/game.nes").build(), Err(BuildError::Rom(_))));
+        assert!(matches!(Nes::builder().rom_bytes(b"NES").build(), Err(BuildError::Rom(_))));
+
+        let path = std::env::temp_dir().join(format!("nes-builder-{}.nes", std::process::id()));
+        fs::write(&path, idle_rom()).unwrap();
+        let nes = Nes::builder().rom_path(path.to_str().unwrap()).build();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(nes.unwrap().cpu().memory().peek(0x8000), 0x4C);
+    }
+
+    #[test]
+    fn ram_init_fills_ram() {
+        let patterns = [
+            (RamInit::Zero, [0x00; 8]),
+            (RamInit::Ff, [0xFF; 8]),
+            (RamInit::Pattern00Ff, [0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF]),
+        ];
+        for &(init, expected) in patterns.iter() {
+            let mut nes = Nes::builder().rom_bytes(&idle_rom()).ram_init(init).build().unwrap();
+            let ram: Vec<u8> = (0x0300..0x0308).map(|addr| nes.cpu_mut().memory_mut().loadb(addr)).collect();
+            assert_eq!(ram, expected, "{}", init);
+        }
+        let random = |seed| {
+            let nes = Nes::builder().rom_bytes(&idle_rom()).ram_init(RamInit::Random(seed)).build().unwrap();
+            (0..0x800).map(|addr| nes.cpu().memory().peek(addr)).collect::<Vec<u8>>()
+        };
+        assert_eq!(random(1), random(1));
+        assert_ne!(random(1), random(2));
+    }
+
+    #[test]
+    fn region_comes_from_the_header_unless_given() {
+        let mut pal = idle_rom();
+        pal[7] = 0x08;
+        pal[12] = 0x01;
+        let region = |rom: &[u8], region: Option<Region>| {
+            let mut builder = Nes::builder().rom_bytes(rom);
+            if let Some(region) = region {
+                builder = builder.region(region);
+            }
+            builder.build().unwrap().region()
+        };
+        assert_eq!(region(&idle_rom(), None), Region::Ntsc);
+        assert_eq!(region(&pal, None), Region::Pal);
+        assert_eq!(region(&pal, Some(Region::Ntsc)), Region::Ntsc);
+        assert_eq!(region(&idle_rom(), Some(Region::Pal)), Region::Pal);
+    }
+
+    #[test]
+    fn palette_colors_the_frame() {
+        let mut palette = [[0; 3]; 64];
+        palette[0x0F] = [1, 2, 3];
+        let mut nes = Nes::builder().rom_bytes(&idle_rom()).palette(palette).ram_init(RamInit::Zero).build().unwrap();
+        nes.cpu_mut().memory_mut().ppu.set_palette_entry(0, 0x0F);
+        nes.run_frame(InputFrame::default()).unwrap();
+        assert_eq!(&nes.cpu().memory().ppu.framebuffer_rgba()[..8], &[1, 2, 3, 255, 1, 2, 3, 255]);
+    }
+
+    #[test]
+    fn sample_rate_sets_samples_per_frame() {
+        for &rate in [22050, 48000].iter() {
+            let mut nes = Nes::builder().rom_bytes(&idle_rom()).sample_rate(rate).build().unwrap();
+            assert_eq!(nes.sample_rate(), rate);
+            // The first frame stops at the first vblank, part way through
+            nes.run_frame(InputFrame::default()).unwrap();
+            let mut samples = 0;
+            for _ in 0..60 {
+                samples += nes.run_frame(InputFrame::default()).unwrap().audio.len();
+            }
+            // 60 NTSC frames are a little under a second
+            let expected = (rate as f64 * 60.0 / 60.0988) as i64;
+            assert!((samples as i64 - expected).abs() <= 2, "{}: {} samples", rate, samples);
+        }
+    }
+
+    #[test]
+    fn illegal_opcode_policy_decides_what_jam_does() {
+        let rom = testing::build_test_rom(".byte $02\nloop: JMP loop", None);
+        let mut nes = Nes::builder().rom_bytes(&rom).build().unwrap();
+        match nes.step() {
+            Err(cpu::EmulationError::IllegalOpcode { pc, opcode }) => assert_eq!((pc, opcode), (0x8000, 0x02)),
+            other => panic!("expected IllegalOpcode, got {:?}", other.map(|_| ())),
+        }
+
+        let mut nes = Nes::builder().rom_bytes(&rom).illegal_opcode_policy(cpu::IllegalOpcodePolicy::Nop).build().unwrap();
+        assert!(nes.step().unwrap().is_continue());
+        assert_eq!(nes.cpu().pc(), 0x8001);
+        nes.run_frame(InputFrame::default()).unwrap();
+    }
+
+    #[test]
+    fn frames_average_29780_and_a_half_cycles() {
+        // Rendering on, so every other frame is a dot short
+        let rom = testing::build_test_rom("LDA #$1E\nSTA $2001\nloop: JMP loop", None);
+        let mut nes = Nes::builder().rom_bytes(&rom).ppu_warmup(false).build().unwrap();
+        nes.run_frame(InputFrame::default()).unwrap();
+        let start = nes.cpu().cycles;
+        let mut total = 0;
+        for frame in 2..62 {
+            let output = nes.run_frame(InputFrame::default()).unwrap();
+            assert_eq!(output.frame, frame);
+            // A frame ends on the instruction that crosses into vblank, and the next one
+            // starts from there, so no single frame is more than an instruction off
+            assert!((output.cycles as i64 - 29780).abs() <= 4, "frame {}: {} cycles", frame, output.cycles);
+            total += output.cycles;
+        }
+        assert_eq!(total, nes.cpu().cycles - start);
+        assert!((total as i64 - 60 * 59561 / 2).abs() <= 3, "{} cycles", total);
+    }
+
+    #[test]
+    fn raw_binary_runs_from_start_at() {
+        // LDA #$42, STA $6000, LDX $6000, STX $10. The reset vector is empty, so only
+        // start_at gets there.
+        let program = [0xA9, 0x42, 0x8D, 0x00, 0x60, 0xAE, 0x00, 0x60, 0x86, 0x10];
+        let mut nes = Nes::builder().raw_binary(&program, 0xC000).start_at(0xC000).build().unwrap();
+        assert_eq!(nes.cpu().pc(), 0xC000);
+        for _ in 0..4 {
+            assert!(nes.step().unwrap().is_continue());
+        }
+        assert_eq!(nes.cpu().pc(), 0xC00A);
+        assert_eq!((nes.cpu().x(), nes.cpu().memory().peek(0x10)), (0x42, 0x42));
+
+        let too_big = vec![0xEA; 0x4001];
+        assert!(matches!(Nes::builder().raw_binary(&too_big, 0xC000).build(),
+                         Err(BuildError::Rom(rom::RomError::RawDoesNotFit { len: 0x4001, load_addr: 0xC000 }))));
+    }
+
+    #[test]
+    fn frame_length_follows_the_region() {
+        // 262 lines of 341 dots at 3 a cycle, and 312 lines at 3.2 a cycle, with rendering off
+        // so NTSC's odd frames aren't a dot short
+        for &(region, cycles_per_60) in [(Region::Ntsc, 1786840), (Region::Pal, 1994850)].iter() {
+            let rom = testing::build_test_rom("loop: JMP loop", None);
+            let mut nes = Nes::builder().rom_bytes(&rom).region(region).build().unwrap();
+            nes.run_frame(InputFrame::default()).unwrap();
+            let start = nes.cpu().cycles;
+            for _ in 0..60 {
+                nes.run_frame(InputFrame::default()).unwrap();
+            }
+            // Frames end on the instruction that crosses into vblank
+            let cycles = (nes.cpu().cycles - start) as i64;
+            assert!((cycles - cycles_per_60).abs() < 3, "{}: {} cycles", region, cycles);
+            assert_eq!(nes.cpu().memory().ppu.frame, 61);
+        }
+    }
+
+    // Copies a page to OAM every NMI, 513 cycles of DMA in one instruction
+    const DMA_EVERY_NMI: &str = "
+reset:  LDA #$80
+        STA $2000
+loop:   JMP loop
+nmi:    LDA #$02
+        STA $4014
+        RTI
+";
+
+    #[test]
+    fn callbacks_see_every_frame_scanline_and_nmi() {
+        let rom = testing::build_test_rom(DMA_EVERY_NMI, None);
+        let mut nes = Nes::builder().rom_bytes(&rom).ppu_warmup(false).build().unwrap();
+        let frames = Arc::new(Mutex::new(Vec::new()));
+        let scanlines = Arc::new(Mutex::new(Vec::new()));
+        let nmis = Arc::new(Mutex::new(0));
+        let (f, s, n) = (frames.clone(), scanlines.clone(), nmis.clone());
+        nes.on_frame(move |view| { f.lock().unwrap().push(view.frame); ControlFlow::Continue(()) });
+        nes.on_scanline(move |line, _| { s.lock().unwrap().push(line); ControlFlow::Continue(()) });
+        nes.on_nmi(move |_| { *n.lock().unwrap() += 1; ControlFlow::Continue(()) });
+        for _ in 0..5 {
+            nes.run_frame(InputFrame::default()).unwrap();
+        }
+
+        assert_eq!(*frames.lock().unwrap(), vec![1, 2, 3, 4, 5]);
+        // Each frame ends on the instruction that took its NMI
+        assert_eq!(*nmis.lock().unwrap(), 5);
+        // The DMA in each NMI skips over several lines at once, and they're still all there,
+        // in order: 241 up to the first vblank, then 262 a frame
+        let scanlines = scanlines.lock().unwrap();
+        assert_eq!(scanlines.len(), 241 + 4 * 262);
+        for (i, &line) in scanlines.iter().enumerate() {
+            assert_eq!(line as usize, (i + 1) % 262, "callback {}", i);
+        }
+    }
+
+    #[test]
+    fn a_callback_can_pause() {
+        let mut nes = strobing_nes();
+        nes.on_scanline(|line, _| if line == 100 { ControlFlow::Break(()) } else { ControlFlow::Continue(()) });
+        let output = nes.run_frame(InputFrame::default()).unwrap();
+        assert!(output.paused);
+        assert_eq!(output.frame, 0);
+        assert!(nes.is_paused());
+        assert_eq!(nes.cpu().memory().ppu.scanline(), 100);
+        // Nothing runs until it's resumed, and then the frame carries on from line 100
+        assert!(nes.run_frame(InputFrame::default()).unwrap().paused);
+        assert_eq!(nes.cpu().memory().ppu.scanline(), 100);
+        nes.resume();
+        let output = nes.run_frame(InputFrame::default()).unwrap();
+        assert_eq!((output.paused, output.frame), (false, 1));
+        // Lines 100 to 241, give or take where in line 100 it stopped
+        assert!((output.cycles as i64 - 141 * 341 / 3).abs() < 341 / 3, "{} cycles", output.cycles);
+    }
+
+    #[test]
+    fn frames_only_count_on_advance_while_paused() {
+        let mut nes = Nes::builder().rom_bytes(&idle_rom()).ppu_warmup(false).build().unwrap();
+        assert_eq!(nes.run_frame(InputFrame::default()).unwrap().frame, 1);
+        nes.pause();
+        assert!(nes.is_paused());
+        for _ in 0..3 {
+            let output = nes.run_frame(InputFrame::default()).unwrap();
+            assert!(output.paused && output.audio.is_empty());
+            assert_eq!((output.frame, output.cycles), (1, 0));
+        }
+
+        let output = nes.frame_advance(InputFrame::default()).unwrap();
+        assert!(!output.paused && !output.audio.is_empty());
+        assert_eq!(output.frame, 2);
+        assert!(nes.is_paused());
+        assert_eq!(nes.run_frame(InputFrame::default()).unwrap().frame, 2);
+
+        nes.resume();
+        assert_eq!(nes.run_frame(InputFrame::default()).unwrap().frame, 3);
+        assert_eq!(nes.run_frame(InputFrame::default()).unwrap().frame, 4);
+        // Advancing while running pauses afterwards
+        assert_eq!(nes.frame_advance(InputFrame::default()).unwrap().frame, 5);
+        assert!(nes.run_frame(InputFrame::default()).unwrap().paused);
+    }
+
+    fn strobing_nes() -> Nes {
+        let rom = testing::build_test_rom(STROBE_EVERY_NMI, None);
+        Nes::builder().rom_bytes(&rom).ppu_warmup(false).build().unwrap()
+    }
+
+    #[test]
+    fn input_display_and_log_follow_latched_buttons() {
+        let mut nes = strobing_nes();
+        nes.set_input_display(true);
+        // A, then Right and B, then nothing, then Start and Up on controller 2
+        let script = [InputFrame::new(0x01, 0), InputFrame::new(0x82, 0), InputFrame::new(0, 0), InputFrame::new(0, 0x18)];
+        let mut log = Vec::new();
+        for &input in script.iter() {
+            let output = nes.run_frame(input).unwrap();
+            let latched = output.osd.input().unwrap().to_vec();
+            log.push(movie::input_log_line(output.frame, &latched));
+        }
+        // Each frame's NMI comes at the start of the next run_frame, once its input is held, so
+        // the first frame has latched nothing yet
+        assert_eq!(log, ["     1 ........ ........", "     2 R.....B. ........",
+                         "     3 ........ ........", "     4 ........ ...UT..."]);
+    }
+
+    #[test]
+    fn input_display_draws_the_latched_frame() {
+        let mut nes = strobing_nes();
+        nes.set_input_display(true);
+        nes.run_frame(InputFrame::new(0, 0)).unwrap();
+        let output = nes.run_frame(InputFrame::new(0x81, 0)).unwrap();
+        let mut expected = output.framebuffer.to_vec();
+        osd::draw_input(&mut expected, &[0x81, 0]);
+        assert_eq!(output.with_osd(), expected);
+        // The PPU's own picture is left alone
+        assert_ne!(output.framebuffer, &expected[..]);
+    }
+
+    // Paints the backdrop with the first controller's buttons every NMI, so the picture
+    // depends on the input
+    const BUTTONS_TO_BACKDROP: &str = "
+reset:  LDA #$80
+        STA $2000
+loop:   JMP loop
+nmi:    LDA #$01
+        STA $4016
+        LDA #$00
+        STA $4016
+        LDX #$00
+read:   LDA $4016
+        LSR A
+        ROL $10
+        INX
+        CPX #8
+        BNE read
+        LDA #$3F
+        STA $2006
+        LDA #$00
+        STA $2006
+        LDA $10
+        AND #$3F
+        STA $2007
+        LDA #$20
+        STA $2006
+        LDA #$00
+        STA $2006
+        RTI
+";
+
+    #[test]
+    fn replaying_inputs_from_a_state_resyncs() {
+        let rom = testing::build_test_rom(BUTTONS_TO_BACKDROP, None);
+        let build = || Nes::builder().rom_bytes(&rom).ppu_warmup(false).build().unwrap();
+        let mut nes = build();
+        nes.run_frames_with(&[InputFrame::new(0x01, 0); 5]).unwrap();
+        let state = nes.save_state().unwrap();
+
+        let inputs: Vec<InputFrame> = (0..50).map(|i| InputFrame::new((i * 7) as u8, (i * 3) as u8)).collect();
+        let replay = |inputs: &[InputFrame]| {
+            let mut nes = build();
+            nes.load_state(&state).unwrap();
+            nes.run_frames_with(inputs).unwrap();
+            nes.cpu().memory().ppu.frame_hash()
+        };
+        nes.run_frames_with(&inputs).unwrap();
+        let replayed = replay(&inputs);
+        assert_eq!(replayed, nes.cpu().memory().ppu.frame_hash());
+
+        // Right lands in the low bit of the color
+        let mut diverged = inputs.clone();
+        diverged[49].ports[0] ^= 0x80;
+        assert_ne!(replay(&diverged), replayed);
+    }
+
+    #[test]
+    fn debug_output_collects_what_the_program_prints() {
+        let rom = testing::build_test_rom("
+reset:  LDX #0
+print:  LDA msg,X
+        STA $4018
+        INX
+        CPX #6
+        BNE print
+        LDA #0
+        STA $4019
+spin:   JMP spin
+msg:    .byte $48, $45, $4C, $4C, $4F, $0A
+", None);
+        let mut nes = Nes::builder().rom_bytes(&rom).debug_device(true).build().unwrap();
+        assert!(matches!(nes.run_frame(InputFrame::default()), Err(cpu::EmulationError::Exited { code: 0 })));
+        assert_eq!(nes.debug_output(), "HELLO\n");
+
+        let mut nes = Nes::builder().rom_bytes(&rom).build().unwrap();
+        nes.run_frame(InputFrame::default()).unwrap();
+        assert_eq!(nes.debug_output(), "");
+    }
+
+    // Writes to PRG RAM and idles, with its reset at $8000
+    const FIRST_BUILD: &str = "
+reset:  LDA #$11
+        STA $6000
+loop:   JMP loop
+";
+
+    // Reset at $8004 this time. It copies what the first build left in PRG RAM to $0300, and
+    // stores at $800C.
+    const SECOND_BUILD: &str = "
+        .byte $EA, $EA, $EA, $EA
+reset:  LDA $6000
+        STA $0300
+        LDA #$22
+store:  STA $6001
+loop:   JMP loop
+";
+
+    // NROM only has PRG RAM with the battery bit set
+    fn battery_rom(program: &str) -> Vec<u8> {
+        let mut rom = testing::build_test_rom(program, None);
+        rom[6] |= 0x02;
+        rom
+    }
+
+    #[test]
+    fn swapped_roms_start_from_their_reset_and_keep_breakpoints() {
+        let mut nes = Nes::builder().rom_bytes(&battery_rom(FIRST_BUILD)).ram_init(RamInit::Zero).build().unwrap();
+        let mut debugger = Debugger::new();
+        debugger.add_breakpoint("$800C").unwrap();
+        nes.run_frame(InputFrame::default()).unwrap();
+        nes.run_frame(InputFrame::default()).unwrap();
+        assert_eq!(nes.cpu().memory().peek(0x6000), 0x11);
+        assert_eq!(nes.cpu().memory().ppu.frame, 2);
+
+        let rom = rom::ROM::from_bytes(&battery_rom(SECOND_BUILD)).unwrap();
+        nes.swap_rom(rom).unwrap();
+        assert_eq!(nes.cpu().pc(), 0x8004);
+        assert_eq!(nes.cpu().memory().ppu.frame, 0);
+        assert_eq!(nes.osd().messages().last(), Some("ROM reloaded"));
+
+        let mut stopped = None;
+        for _ in 0..10 {
+            if debugger.check(nes.cpu()).is_some() {
+                stopped = Some(nes.cpu().pc());
+                break;
+            }
+            assert!(nes.step().unwrap().is_continue());
+        }
+        assert_eq!(stopped, Some(0x800C));
+        // PRG RAM came through the swap
+        assert_eq!(nes.cpu().memory().peek(0x0300), 0x11);
+    }
+
+    #[test]
+    fn swapping_in_a_rom_for_the_other_region_is_refused() {
+        let mut nes = Nes::builder().rom_bytes(&idle_rom()).build().unwrap();
+        let mut pal = testing::build_test_rom(SECOND_BUILD, None);
+        pal[7] = 0x08;
+        pal[12] = 0x01;
+        assert_eq!(nes.swap_rom(rom::ROM::from_bytes(&pal).unwrap()),
+                   Err(SwapError::RegionMismatch { running: Region::Ntsc, rom: Region::Pal }));
+        // The old cartridge is still in
+        assert_eq!(nes.cpu().memory().peek(0x8000), 0x4C);
+    }
+}
@@ -0,0 +1,759 @@
+// Famicom Disk System: the RAM adapter cartridge, its BIOS, and the disk drive behind it. Games
+// come as disk images, one or more 65500-byte sides of blocks, which the BIOS reads into the
+// adapter's RAM a byte at a time through the drive registers.
+
+use mapper::{self, Mapper};
+use rom::{Mirroring, ROM};
+use state::StateError;
+
+use std::fmt;
+use std::fs;
+use std::io;
+
+const FDS_MAGIC: &[u8; 4] = b"FDS\x1A";
+const FWNES_HEADER_SIZE: usize = 16;
+const VERIFICATION: &[u8; 14] = b"*NINTENDO-HVC*";
+const SIDE_SIZE: usize = 65500;
+// .qd images keep each block's CRC and pad sides to 64 KiB
+const QD_SIDE_SIZE: usize = 0x10000;
+pub const BIOS_SIZE: usize = 0x2000;
+
+// Gaps of zero bits on the disk surface, in bytes: the lead-in before the first block and the
+// gap between blocks. Each block starts with a $80 mark and is followed by two CRC bytes.
+const LEAD_IN: usize = 28300 / 8;
+const BLOCK_GAP: usize = 976 / 8;
+// CPU cycles per byte at the drive's 96.4 kbit/s, and for the head to get back to the start
+const BYTE_CYCLES: u32 = 149;
+const REWIND_CYCLES: u32 = 50000;
+// How long a side swap leaves the drive empty, so the BIOS notices the disk came out
+const SWAP_CYCLES: u32 = 1_000_000;
+
+#[derive(Debug)]
+pub enum FdsError {
+    Io(io::Error),
+    BadMagic,
+    // Shorter than a whole side
+    Truncated,
+    // A block wasn't what the layout calls for
+    BadBlock { side: usize, offset: usize, expected: u8 },
+    // The BIOS is an 8 KiB ROM
+    BadBios { len: usize },
+}
+
+impl fmt::Display for FdsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FdsError::Io(ref e) => write!(f, "{}", e),
+            FdsError::BadMagic => write!(f, "not an FDS disk image"),
+            FdsError::Truncated => write!(f, "disk image ends partway through a side"),
+            FdsError::BadBlock { side, offset, expected } =>
+                write!(f, "side {} has a bad block at {:#06x}, expected block type {}", side, offset, expected),
+            FdsError::BadBios { len } => write!(f, "FDS BIOS is {} bytes, expected {}", len, BIOS_SIZE),
+        }
+    }
+}
+
+impl From<io::Error> for FdsError {
+    fn from(e: io::Error) -> FdsError {
+        FdsError::Io(e)
+    }
+}
+
+// Whether data looks like a disk image rather than an iNES file
+pub fn is_disk_image(data: &[u8]) -> bool {
+    data.starts_with(FDS_MAGIC) || data.get(1..15) == Some(&VERIFICATION[..])
+}
+
+// Block 1, the disk info block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiskInfo {
+    pub manufacturer: u8,
+    pub game_name: String,
+    pub game_type: u8,
+    pub version: u8,
+    // 0 for side A, 1 for side B
+    pub side: u8,
+    pub disk: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    Prg,
+    Chr,
+    // Copied into the nametables
+    Nametable,
+    Other(u8),
+}
+
+// A file header (block 3) and its data (block 4)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiskFile {
+    pub number: u8,
+    // The BIOS loads every file with an ID up to the boot ID it's asked for
+    pub id: u8,
+    pub name: String,
+    // Where the BIOS loads it, in CPU or PPU space depending on kind
+    pub addr: u16,
+    pub kind: FileKind,
+    pub data: Vec<u8>,
+}
+
+pub struct DiskSide {
+    pub info: DiskInfo,
+    // What block 2 says; games sometimes have hidden files past it
+    pub file_count: u8,
+    pub files: Vec<DiskFile>,
+    // Every block in order, without CRCs, which is what the drive plays back
+    blocks: Vec<Vec<u8>>,
+}
+
+// Pull the block of a type and length out of a side, skipping its CRC in .qd images
+fn take_block(data: &[u8], pos: &mut usize, side: usize, kind: u8, len: usize, crc: bool) -> Result<Vec<u8>, FdsError> {
+    if data.get(*pos) != Some(&kind) || *pos + len > data.len() {
+        return Err(FdsError::BadBlock { side: side, offset: *pos, expected: kind })
+    }
+    let block = data[*pos..*pos + len].to_vec();
+    *pos += len + if crc { 2 } else { 0 };
+    Ok(block)
+}
+
+fn word(bytes: &[u8], at: usize) -> u16 {
+    bytes[at] as u16 | (bytes[at + 1] as u16) << 8
+}
+
+impl DiskSide {
+    fn parse(data: &[u8], side: usize, crc: bool) -> Result<DiskSide, FdsError> {
+        let mut pos = 0;
+        let info = take_block(data, &mut pos, side, 1, 56, crc)?;
+        if &info[1..15] != VERIFICATION {
+            return Err(FdsError::BadBlock { side: side, offset: 0, expected: 1 })
+        }
+        let count = take_block(data, &mut pos, side, 2, 2, crc)?;
+        let mut blocks = vec![info.clone(), count.clone()];
+
+        // Read files for as long as there are file headers, hidden ones included
+        let mut files = Vec::new();
+        while data.get(pos) == Some(&3) {
+            let header = take_block(data, &mut pos, side, 3, 16, crc)?;
+            let size = word(&header, 13) as usize;
+            let body = take_block(data, &mut pos, side, 4, 1 + size, crc)?;
+            files.push(DiskFile {
+                number: header[1],
+                id: header[2],
+                name: String::from_utf8_lossy(&header[3..11]).into_owned(),
+                addr: word(&header, 11),
+                kind: match header[15] {
+                    0 => FileKind::Prg,
+                    1 => FileKind::Chr,
+                    2 => FileKind::Nametable,
+                    kind => FileKind::Other(kind),
+                },
+                data: body[1..].to_vec(),
+            });
+            blocks.push(header);
+            blocks.push(body);
+        }
+
+        Ok(DiskSide {
+            info: DiskInfo {
+                manufacturer: info[15],
+                game_name: String::from_utf8_lossy(&info[16..19]).into_owned(),
+                game_type: info[19],
+                version: info[20],
+                side: info[21],
+                disk: info[22],
+            },
+            file_count: count[1],
+            files: files,
+            blocks: blocks,
+        })
+    }
+
+    // The side as the drive head sees it: the lead-in, then each block behind its start mark
+    // with a gap after it. CRCs aren't checked, so the two bytes after each block are zero.
+    pub fn surface(&self) -> Vec<u8> {
+        let mut surface = vec![0; LEAD_IN];
+        for block in self.blocks.iter() {
+            surface.push(0x80);
+            surface.extend_from_slice(block);
+            surface.extend_from_slice(&[0; 2]);
+            surface.extend(std::iter::repeat_n(0, BLOCK_GAP));
+        }
+        surface.resize(surface.len().max(SIDE_SIZE), 0);
+        return surface
+    }
+}
+
+pub struct FdsImage {
+    pub sides: Vec<DiskSide>,
+}
+
+impl FdsImage {
+    pub fn from_file(filename: &str) -> Result<FdsImage, FdsError> {
+        let data = fs::read(filename)?;
+        FdsImage::from_bytes(&data)
+    }
+
+    // .fds with or without the fwNES header, or .qd
+    pub fn from_bytes(data: &[u8]) -> Result<FdsImage, FdsError> {
+        let data = if data.starts_with(FDS_MAGIC) { &data[FWNES_HEADER_SIZE.min(data.len())..] } else { data };
+        if !is_disk_image(data) {
+            return Err(FdsError::BadMagic)
+        }
+        let qd = data.len() % QD_SIDE_SIZE == 0 && data.len() % SIDE_SIZE != 0;
+        let size = if qd { QD_SIDE_SIZE } else { SIDE_SIZE };
+        if data.len() % size != 0 {
+            return Err(FdsError::Truncated)
+        }
+        let sides = data.chunks(size).enumerate()
+            .map(|(i, side)| DiskSide::parse(side, i, qd))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(FdsImage { sides: sides })
+    }
+
+    // The cartridge side of things: the BIOS as PRG, no CHR
+    pub fn rom(bios: &[u8]) -> Result<ROM, FdsError> {
+        if bios.len() != BIOS_SIZE {
+            return Err(FdsError::BadBios { len: bios.len() })
+        }
+        Ok(ROM::from_parts(bios.to_vec(), Vec::new()))
+    }
+}
+
+// The RAM adapter: 32 KiB of PRG RAM at $6000-$DFFF, the BIOS at $E000, 8 KiB of CHR RAM, a
+// timer IRQ, and the drive. The console's own 8 KiB at $6000 serves as the first part of the
+// PRG RAM, so this covers $8000-$DFFF.
+//
+// The drive is simplified: it seeks instantly to the start of the side when the motor comes on,
+// never reports a CRC error, and doesn't save writes back to the image file.
+pub struct FdsMapper {
+    bios: Vec<u8>,
+    ram: Vec<u8>,
+    chr: Vec<u8>,
+    // Each side's surface, including whatever the game has written to it
+    sides: Vec<Vec<u8>>,
+    side: Option<usize>,
+    // A side waiting to go in once the drive has been empty long enough
+    swap: Option<(usize, u32)>,
+
+    // $4020-$4022
+    timer_reload: u16,
+    timer_counter: u16,
+    timer_repeat: bool,
+    timer_enabled: bool,
+    timer_irq: bool,
+    // $4023 bit 0
+    disk_registers: bool,
+
+    // $4025
+    motor: bool,
+    reset_transfer: bool,
+    read_mode: bool,
+    horizontal: bool,
+    crc_control: bool,
+    // Bit 6: past the gap, looking for a block's start mark
+    disk_ready: bool,
+    disk_irq_enabled: bool,
+
+    // Where the head is and when it reaches the next byte
+    position: usize,
+    delay: u32,
+    end_of_head: bool,
+    scanning: bool,
+    gap_ended: bool,
+    transfer_complete: bool,
+    disk_irq: bool,
+    read_data: u8,
+    write_data: u8,
+    // $4026, the expansion port
+    external: u8,
+}
+
+impl FdsMapper {
+    // Side A of the first disk starts out in the drive
+    pub fn new(image: &FdsImage, bios: &[u8]) -> FdsMapper {
+        FdsMapper {
+            bios: bios.to_vec(),
+            ram: vec![0; 0x6000],
+            chr: vec![0; 0x2000],
+            sides: image.sides.iter().map(|side| side.surface()).collect(),
+            side: if image.sides.is_empty() { None } else { Some(0) },
+            swap: None,
+            timer_reload: 0,
+            timer_counter: 0,
+            timer_repeat: false,
+            timer_enabled: false,
+            timer_irq: false,
+            disk_registers: true,
+            motor: false,
+            reset_transfer: false,
+            read_mode: true,
+            horizontal: false,
+            crc_control: false,
+            disk_ready: false,
+            disk_irq_enabled: false,
+            position: 0,
+            delay: 0,
+            end_of_head: true,
+            scanning: false,
+            gap_ended: false,
+            transfer_complete: false,
+            disk_irq: false,
+            read_data: 0,
+            write_data: 0,
+            external: 0,
+        }
+    }
+
+    pub fn side(&self) -> Option<usize> {
+        self.side
+    }
+
+    fn clock_timer(&mut self) {
+        if !self.timer_enabled {
+            return;
+        }
+        if self.timer_counter == 0 {
+            self.timer_irq = true;
+            self.timer_counter = self.timer_reload;
+            self.timer_enabled = self.timer_repeat;
+        } else {
+            self.timer_counter -= 1;
+        }
+    }
+
+    // One CPU cycle of the drive. A byte passes under the head every BYTE_CYCLES; reading
+    // skips the gap up to a block's start mark, then hands each byte over through $4031.
+    fn clock_drive(&mut self) {
+        if let Some((side, cycles)) = self.swap {
+            if cycles == 0 {
+                self.side = Some(side);
+                self.end_of_head = true;
+                self.swap = None;
+            } else {
+                self.swap = Some((side, cycles - 1));
+            }
+        }
+        let side = match self.side {
+            Some(side) if self.motor => side,
+            _ => {
+                self.end_of_head = true;
+                self.scanning = false;
+                return;
+            },
+        };
+        if self.reset_transfer && !self.scanning {
+            return;
+        }
+        if self.end_of_head {
+            self.delay = REWIND_CYCLES;
+            self.end_of_head = false;
+            self.position = 0;
+            self.gap_ended = false;
+            return;
+        }
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+
+        self.scanning = true;
+        let mut raise_irq = self.disk_irq_enabled;
+        if self.read_mode {
+            let data = self.sides[side][self.position];
+            if !self.disk_ready {
+                self.gap_ended = false;
+            } else if data != 0 && !self.gap_ended {
+                // The start mark
+                self.gap_ended = true;
+                raise_irq = false;
+            }
+            if self.gap_ended {
+                self.transfer_complete = true;
+                self.read_data = data;
+                self.disk_irq |= raise_irq;
+            }
+        } else {
+            let mut data = 0;
+            if !self.crc_control {
+                self.transfer_complete = true;
+                self.disk_irq |= raise_irq;
+                if self.disk_ready {
+                    data = self.write_data;
+                }
+            }
+            self.sides[side][self.position] = data;
+            self.gap_ended = false;
+        }
+
+        self.position += 1;
+        if self.position >= self.sides[side].len() {
+            self.motor = false;
+            self.end_of_head = true;
+        } else {
+            self.delay = BYTE_CYCLES;
+        }
+    }
+
+    // $4020-$5FFF as the CPU sees it
+    fn register(&self, addr: u16) -> u8 {
+        match addr {
+            0x4030 => (self.timer_irq as u8) | (self.transfer_complete as u8) << 1 | (self.end_of_head as u8) << 6,
+            0x4031 => self.read_data,
+            0x4032 => {
+                let empty = self.side.is_none();
+                0x40 | empty as u8 | ((empty || !self.scanning) as u8) << 1 | (empty as u8) << 2
+            },
+            // The battery is always good
+            0x4033 => 0x80 | (self.external & 0x7F),
+            _ => 0,
+        }
+    }
+}
+
+impl Mapper for FdsMapper {
+    fn cpu_peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x4020..=0x5FFF => self.register(addr),
+            0x8000..=0xDFFF => self.ram[addr as usize - 0x8000],
+            0xE000..=0xFFFF => self.bios[addr as usize - 0xE000],
+            _ => 0,
+        }
+    }
+
+    // Reading the status or data acknowledges the IRQs
+    fn cpu_read(&mut self, addr: u16) -> u8 {
+        let val = self.cpu_peek(addr);
+        match addr {
+            0x4030 => {
+                self.timer_irq = false;
+                self.transfer_complete = false;
+                self.disk_irq = false;
+            },
+            0x4031 => {
+                self.transfer_complete = false;
+                self.disk_irq = false;
+            },
+            _ => {},
+        }
+        return val
+    }
+
+    fn cpu_write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x4020 => self.timer_reload = (self.timer_reload & 0xFF00) | val as u16,
+            0x4021 => self.timer_reload = (self.timer_reload & 0x00FF) | (val as u16) << 8,
+            0x4022 => {
+                self.timer_repeat = val & 0x01 != 0;
+                self.timer_enabled = val & 0x02 != 0 && self.disk_registers;
+                if self.timer_enabled {
+                    self.timer_counter = self.timer_reload;
+                } else {
+                    self.timer_irq = false;
+                }
+            },
+            0x4023 => {
+                self.disk_registers = val & 0x01 != 0;
+                if !self.disk_registers {
+                    self.timer_enabled = false;
+                    self.timer_irq = false;
+                }
+            },
+            0x4024..=0x4026 if !self.disk_registers => {},
+            0x4024 => {
+                self.write_data = val;
+                self.transfer_complete = false;
+                self.disk_irq = false;
+            },
+            0x4025 => {
+                self.motor = val & 0x01 != 0;
+                self.reset_transfer = val & 0x02 != 0;
+                self.read_mode = val & 0x04 != 0;
+                self.horizontal = val & 0x08 != 0;
+                self.crc_control = val & 0x10 != 0;
+                self.disk_ready = val & 0x40 != 0;
+                self.disk_irq_enabled = val & 0x80 != 0;
+                self.disk_irq = false;
+            },
+            0x4026 => self.external = val,
+            0x8000..=0xDFFF => self.ram[addr as usize - 0x8000] = val,
+            _ => {},
+        }
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        self.chr[addr as usize & 0x1FFF]
+    }
+
+    fn ppu_write(&mut self, addr: u16, val: u8) {
+        self.chr[addr as usize & 0x1FFF] = val;
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        if self.horizontal { Mirroring::Horizontal } else { Mirroring::Vertical }
+    }
+
+    fn irq(&self) -> bool {
+        self.timer_irq || self.disk_irq
+    }
+
+    fn cpu_clock(&mut self, cycles: u32) {
+        for _ in 0..cycles {
+            self.clock_timer();
+            self.clock_drive();
+        }
+    }
+
+    fn insert_disk_side(&mut self, side: usize) -> bool {
+        if side >= self.sides.len() {
+            return false
+        }
+        self.side = None;
+        self.swap = Some((side, SWAP_CYCLES));
+        return true
+    }
+
+    // Only the BIOS is ROM
+    fn prg_offset(&self, addr: u16) -> Option<usize> {
+        if addr >= 0xE000 { Some(addr as usize - 0xE000) } else { None }
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let side_byte = |side: Option<usize>| side.map_or(0xFF, |side| side as u8);
+        let flags = |bits: &[bool]| bits.iter().enumerate().fold(0u8, |acc, (i, &bit)| acc | (bit as u8) << i);
+        let (swap_side, swap_cycles) = self.swap.map_or((None, 0), |(side, cycles)| (Some(side), cycles));
+        let mut state = Vec::new();
+        state.extend_from_slice(&self.timer_reload.to_le_bytes());
+        state.extend_from_slice(&self.timer_counter.to_le_bytes());
+        state.extend_from_slice(&(self.position as u32).to_le_bytes());
+        state.extend_from_slice(&self.delay.to_le_bytes());
+        state.extend_from_slice(&swap_cycles.to_le_bytes());
+        state.push(side_byte(self.side));
+        state.push(side_byte(swap_side));
+        state.push(flags(&[self.timer_repeat, self.timer_enabled, self.timer_irq, self.disk_registers,
+                           self.motor, self.reset_transfer, self.read_mode, self.horizontal]));
+        state.push(flags(&[self.crc_control, self.disk_ready, self.disk_irq_enabled, self.end_of_head,
+                           self.scanning, self.gap_ended, self.transfer_complete, self.disk_irq]));
+        state.extend_from_slice(&[self.read_data, self.write_data, self.external]);
+        state.extend_from_slice(&self.ram);
+        for side in self.sides.iter() {
+            state.extend_from_slice(side);
+        }
+        mapper::save_with_chr_ram(&state, &self.chr, true)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        const REGISTERS: usize = 23;
+        let disk_len: usize = self.sides.iter().map(|side| side.len()).sum();
+        let r = mapper::load_with_chr_ram(state, REGISTERS + self.ram.len() + disk_len, &mut self.chr, true)?;
+        let side = |byte: u8| -> Result<Option<usize>, StateError> {
+            match byte {
+                0xFF => Ok(None),
+                side if (side as usize) < self.sides.len() => Ok(Some(side as usize)),
+                _ => Err(StateError::Corrupt("mapper state")),
+            }
+        };
+        let (current, swap) = (side(r[16])?, side(r[17])?);
+        let u32_at = |at: usize| u32::from_le_bytes([r[at], r[at + 1], r[at + 2], r[at + 3]]);
+        self.timer_reload = u16::from_le_bytes([r[0], r[1]]);
+        self.timer_counter = u16::from_le_bytes([r[2], r[3]]);
+        let position = u32_at(4) as usize;
+        if current.is_some_and(|side| position > self.sides[side].len()) {
+            return Err(StateError::Corrupt("mapper state"))
+        }
+        self.position = position;
+        self.delay = u32_at(8);
+        self.swap = swap.map(|side| (side, u32_at(12)));
+        self.side = current;
+        let bit = |byte: u8, i: usize| byte & (1 << i) != 0;
+        let (a, b) = (r[18], r[19]);
+        self.timer_repeat = bit(a, 0);
+        self.timer_enabled = bit(a, 1);
+        self.timer_irq = bit(a, 2);
+        self.disk_registers = bit(a, 3);
+        self.motor = bit(a, 4);
+        self.reset_transfer = bit(a, 5);
+        self.read_mode = bit(a, 6);
+        self.horizontal = bit(a, 7);
+        self.crc_control = bit(b, 0);
+        self.disk_ready = bit(b, 1);
+        self.disk_irq_enabled = bit(b, 2);
+        self.end_of_head = bit(b, 3);
+        self.scanning = bit(b, 4);
+        self.gap_ended = bit(b, 5);
+        self.transfer_complete = bit(b, 6);
+        self.disk_irq = bit(b, 7);
+        self.read_data = r[20];
+        self.write_data = r[21];
+        self.external = r[22];
+        let ram_end = REGISTERS + self.ram.len();
+        self.ram.copy_from_slice(&r[REGISTERS..ram_end]);
+        let mut at = ram_end;
+        for side in self.sides.iter_mut() {
+            let len = side.len();
+            side.copy_from_slice(&r[at..at + len]);
+            at += len;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A side holding files of (id, name, addr, data) with block 2 counting only the first
+    // `count`, laid out as in an .fds image
+    fn side(side: u8, count: u8, files: &[(u8, &str, u16, &[u8])]) -> Vec<u8> {
+        let mut data = vec![1];
+        data.extend_from_slice(VERIFICATION);
+        data.extend_from_slice(&[0xA4, b'T', b'S', b'T', 0x20, 0x01, side, 0x00]);
+        data.resize(56, 0);
+        data.extend_from_slice(&[2, count]);
+        for (number, &(id, name, addr, body)) in files.iter().enumerate() {
+            data.extend_from_slice(&[3, number as u8, id]);
+            data.extend_from_slice(format!("{:8}", name).as_bytes());
+            data.extend_from_slice(&addr.to_le_bytes());
+            data.extend_from_slice(&(body.len() as u16).to_le_bytes());
+            data.push(0);
+            data.push(4);
+            data.extend_from_slice(body);
+        }
+        data.resize(SIDE_SIZE, 0);
+        data
+    }
+
+    fn two_sided_image() -> Vec<u8> {
+        let mut image = FDS_MAGIC.to_vec();
+        image.extend_from_slice(&[2; 12]);
+        image.extend(side(0, 1, &[(0, "KYODAKU-", 0x2800, &[0x55; 0xE0]), (1, "HIDDEN", 0x8123, &[0xA0; 40])]));
+        image.extend(side(1, 1, &[(0, "SIDEB", 0x6000, &[0x11; 4])]));
+        image
+    }
+
+    #[test]
+    fn file_tables_are_parsed() {
+        let image = FdsImage::from_bytes(&two_sided_image()).unwrap();
+        assert_eq!(image.sides.len(), 2);
+        let a = &image.sides[0];
+        assert_eq!(a.info, DiskInfo { manufacturer: 0xA4, game_name: "TST".to_string(), game_type: 0x20, version: 1, side: 0, disk: 0 });
+        // The hidden file past block 2's count is kept
+        assert_eq!(a.file_count, 1);
+        assert_eq!(a.files.len(), 2);
+        assert_eq!(a.files[1], DiskFile {
+            number: 1, id: 1, name: "HIDDEN  ".to_string(), addr: 0x8123, kind: FileKind::Prg, data: vec![0xA0; 40],
+        });
+        assert_eq!(image.sides[1].info.side, 1);
+        assert_eq!(image.sides[1].files[0].data, [0x11; 4]);
+
+        // Without the fwNES header
+        let headerless = FdsImage::from_bytes(&two_sided_image()[FWNES_HEADER_SIZE..]).unwrap();
+        assert_eq!(headerless.sides[0].files, a.files);
+    }
+
+    #[test]
+    fn qd_images_skip_crcs() {
+        let fds = side(0, 1, &[(0, "FILE", 0x8000, &[1, 2, 3])]);
+        // Two CRC bytes after each block, which fall at these lengths into the side
+        let mut qd = Vec::new();
+        for &(start, end) in [(0, 56), (56, 58), (58, 74), (74, 78)].iter() {
+            qd.extend_from_slice(&fds[start..end]);
+            qd.extend_from_slice(&[0xCC, 0xCC]);
+        }
+        qd.resize(QD_SIDE_SIZE, 0);
+        let image = FdsImage::from_bytes(&qd).unwrap();
+        assert_eq!(image.sides[0].files[0].data, [1, 2, 3]);
+    }
+
+    #[test]
+    fn bad_images_are_refused() {
+        assert!(matches!(FdsImage::from_bytes(b"NES\x1A"), Err(FdsError::BadMagic)));
+        let image = two_sided_image();
+        assert!(matches!(FdsImage::from_bytes(&image[..image.len() - 1]), Err(FdsError::Truncated)));
+        let mut bad = image.clone();
+        // Side B's block 2
+        bad[FWNES_HEADER_SIZE + SIDE_SIZE + 56] = 7;
+        assert!(matches!(FdsImage::from_bytes(&bad), Err(FdsError::BadBlock { side: 1, offset: 56, expected: 2 })));
+        assert!(matches!(FdsImage::rom(&[0; 100]), Err(FdsError::BadBios { len: 100 })));
+    }
+
+    // Wait for the drive's next byte the way the BIOS does, on the transfer IRQ
+    fn next_byte(fds: &mut FdsMapper) -> u8 {
+        for _ in 0..REWIND_CYCLES + SIDE_SIZE as u32 * (BYTE_CYCLES + 1) {
+            if fds.irq() {
+                return fds.cpu_read(0x4031)
+            }
+            fds.cpu_clock(1);
+        }
+        panic!("the drive never delivered a byte");
+    }
+
+    // Let a byte of the gap pass with the drive not ready, then look for the next start mark
+    // and read len bytes of block and its CRC
+    fn read_block(fds: &mut FdsMapper, len: usize) -> Vec<u8> {
+        fds.cpu_write(0x4025, 0x85);
+        fds.cpu_clock(BYTE_CYCLES + 1);
+        fds.cpu_write(0x4025, 0xC5);
+        let block = (0..len).map(|_| next_byte(fds)).collect();
+        next_byte(fds);
+        next_byte(fds);
+        block
+    }
+
+    #[test]
+    fn the_bios_protocol_reads_a_file_into_ram() {
+        let image = FdsImage::from_bytes(&two_sided_image()).unwrap();
+        let mut fds = FdsMapper::new(&image, &[0; BIOS_SIZE]);
+        assert_eq!(fds.cpu_read(0x4032) & 0x01, 0);
+        let info = read_block(&mut fds, 56);
+        assert_eq!(&info[1..15], VERIFICATION);
+        assert_eq!(read_block(&mut fds, 2), [2, 1]);
+        // Skip the first file, then load the hidden one where its header says
+        let header = read_block(&mut fds, 16);
+        read_block(&mut fds, 1 + word(&header, 13) as usize);
+        let header = read_block(&mut fds, 16);
+        let addr = word(&header, 11);
+        let body = read_block(&mut fds, 1 + word(&header, 13) as usize);
+        for (i, &byte) in body[1..].iter().enumerate() {
+            fds.cpu_write(addr + i as u16, byte);
+        }
+        assert_eq!((0x8123..0x814B).map(|addr| fds.cpu_peek(addr)).collect::<Vec<_>>(), [0xA0; 40]);
+        assert_eq!(fds.cpu_peek(0x814B), 0);
+    }
+
+    #[test]
+    fn timer_irq_fires_after_its_reload() {
+        let image = FdsImage::from_bytes(&two_sided_image()).unwrap();
+        let mut fds = FdsMapper::new(&image, &[0; BIOS_SIZE]);
+        fds.cpu_write(0x4020, 10);
+        fds.cpu_write(0x4021, 0);
+        fds.cpu_write(0x4022, 0x02);
+        fds.cpu_clock(10);
+        assert!(!fds.irq());
+        fds.cpu_clock(1);
+        assert!(fds.irq());
+        assert_eq!(fds.cpu_read(0x4030) & 0x01, 1);
+        assert!(!fds.irq());
+        // Not repeating, so that was the only one
+        fds.cpu_clock(100);
+        assert!(!fds.irq());
+    }
+
+    #[test]
+    fn swapping_sides_empties_the_drive_first() {
+        let image = FdsImage::from_bytes(&two_sided_image()).unwrap();
+        let mut fds = FdsMapper::new(&image, &[0; BIOS_SIZE]);
+        assert!(!fds.insert_disk_side(2));
+        assert!(fds.insert_disk_side(1));
+        assert_eq!(fds.side(), None);
+        assert_eq!(fds.cpu_read(0x4032) & 0x01, 1);
+        fds.cpu_clock(SWAP_CYCLES + 1);
+        assert_eq!(fds.side(), Some(1));
+
+        let state = fds.save_state();
+        let mut restored = FdsMapper::new(&image, &[0; BIOS_SIZE]);
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.side(), Some(1));
+        assert_eq!(restored.save_state(), state);
+    }
+}
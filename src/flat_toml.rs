@@ -0,0 +1,111 @@
+// The small subset of TOML the config file and the regression manifest are written in: one
+// key = value per line, # comments, and values that are
+//   "strings"            with \" and \\ escapes
+//   true, false
+//   numbers              decimal or 0x hex, with _ separators, left as text for the caller
+//   ["one", "two"]       one-line arrays of strings
+// Table headers like [[rom]] are left for the caller to pick out.
+
+pub enum Value {
+    Str(String),
+    Bool(bool),
+    // Without its _ separators. Callers parse it as the number they need, see parse_int.
+    Number(String),
+    List(Vec<String>),
+}
+
+// The lines with something on them, counting from 1, with comments and surrounding space cut off
+pub fn content_lines(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.lines().enumerate()
+        .map(|(index, raw)| (index + 1, strip_comment(raw).trim()))
+        .filter(|&(_, content)| !content.is_empty())
+}
+
+// Cut a # comment off, unless the # is inside a string
+pub fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => {},
+        }
+    }
+    return line
+}
+
+pub fn parse_string(s: &str) -> Option<String> {
+    let inner = s.strip_prefix('"')?.strip_suffix('"')?;
+    let mut out = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next()? {
+                c @ ('"' | '\\') => out.push(c),
+                _ => return None,
+            },
+            '"' => return None,
+            c => out.push(c),
+        }
+    }
+    Some(out)
+}
+
+// s as a string parse_string reads back
+pub fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+pub fn parse_value(s: &str) -> Option<Value> {
+    match s {
+        "true" => Some(Value::Bool(true)),
+        "false" => Some(Value::Bool(false)),
+        _ if s.starts_with('"') => parse_string(s).map(Value::Str),
+        _ if s.starts_with('[') && s.ends_with(']') => {
+            let items = s[1..s.len() - 1].split(',').map(str::trim).filter(|item| !item.is_empty());
+            items.map(parse_string).collect::<Option<Vec<_>>>().map(Value::List)
+        },
+        _ if s.starts_with(|c: char| c.is_ascii_digit() || c == '-' || c == '+') => Some(Value::Number(s.replace('_', ""))),
+        _ => None,
+    }
+}
+
+// A Number's text as an unsigned integer, decimal or 0x hex
+pub fn parse_int(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strings_keep_escapes_and_hashes() {
+        let line = r#"path = "C:\\dir\\\"a\" #1.pal"  # comment"#;
+        let value = strip_comment(line).split_once('=').unwrap().1.trim();
+        assert_eq!(parse_string(value).as_deref(), Some(r#"C:\dir\"a" #1.pal"#));
+        assert_eq!(parse_string(&quote(r#"C:\dir\"a" #1.pal"#)).as_deref(), Some(r#"C:\dir\"a" #1.pal"#));
+        assert!(parse_string(r#""bad \n escape""#).is_none());
+        assert!(parse_string(r#""stray " quote""#).is_none());
+        assert!(parse_string("\"unterminated").is_none());
+    }
+
+    #[test]
+    fn values_and_lines() {
+        assert!(matches!(parse_value("true"), Some(Value::Bool(true))));
+        assert!(matches!(parse_value("1_000"), Some(Value::Number(ref n)) if n == "1000"));
+        assert!(matches!(parse_value("[\"a\", \"b\" ]"), Some(Value::List(ref items)) if items == &["a", "b"]));
+        assert!(parse_value("[\"a\", b]").is_none());
+        assert!(parse_value("yes").is_none());
+        assert_eq!((parse_int("0x10"), parse_int("16"), parse_int("-1")), (Some(16), Some(16), None));
+
+        let lines: Vec<_> = content_lines("# header\n\na = 1  # one\n  [[rom]]\n").collect();
+        assert_eq!(lines, [(3, "a = 1"), (4, "[[rom]]")]);
+    }
+}
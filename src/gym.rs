@@ -0,0 +1,208 @@
+// The emulator as a reinforcement learning environment: reset, then step one action at a time
+// and get back the picture and whichever RAM the agent watches. Runs are fully deterministic:
+// RAM powers on from RamInit (seeded if random), nothing reads the clock and there's no audio
+// device. The core has no global state, so Envs are independent of each other, and like a Nes
+// they can be moved to another thread to run in parallel.
+
+use controller::InputFrame;
+use cpu::EmulationError;
+use emulator::{BuildError, Nes};
+use mem::RamInit;
+use region::Region;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvConfig {
+    // CPU addresses read into each observation, in this order
+    pub ram_watch: Vec<u16>,
+    pub ram_init: RamInit,
+    // Overrides the ROM header's region
+    pub region: Option<Region>,
+    // Frames each step runs with its action held, at least 1
+    pub frames_per_step: u32,
+}
+
+impl Default for EnvConfig {
+    fn default() -> EnvConfig {
+        EnvConfig {
+            ram_watch: Vec::new(),
+            ram_init: RamInit::default(),
+            region: None,
+            frames_per_step: 1,
+        }
+    }
+}
+
+pub struct Observation<'a> {
+    // NES color indices, SCREEN_WIDTH x SCREEN_HEIGHT
+    pub framebuffer: &'a [u8],
+    // One byte per ram_watch address, read without side effects
+    pub ram: Vec<u8>,
+}
+
+// What a step did besides change the picture
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StepInfo {
+    // Frames completed since the last reset
+    pub frame: u64,
+    // CPU cycles the step took
+    pub cycles: u64,
+}
+
+pub struct Env {
+    rom: Vec<u8>,
+    config: EnvConfig,
+    nes: Nes,
+}
+
+impl Env {
+    pub fn new(rom: &[u8], config: EnvConfig) -> Result<Env, BuildError> {
+        let nes = Env::power_on(rom, &config)?;
+        Ok(Env {
+            rom: rom.to_vec(),
+            config: config,
+            nes: nes,
+        })
+    }
+
+    fn power_on(rom: &[u8], config: &EnvConfig) -> Result<Nes, BuildError> {
+        let mut builder = Nes::builder().rom_bytes(rom).ram_init(config.ram_init);
+        if let Some(region) = config.region {
+            builder = builder.region(region);
+        }
+        builder.build()
+    }
+
+    // Back to a freshly powered-on console, exactly as new() left it
+    pub fn reset(&mut self) -> Observation<'_> {
+        // The ROM already built once, so it builds the same way again
+        self.nes = Env::power_on(&self.rom, &self.config).unwrap();
+        self.observe()
+    }
+
+    // Hold action for frames_per_step frames
+    pub fn step(&mut self, action: InputFrame) -> Result<(Observation<'_>, StepInfo), EmulationError> {
+        let mut cycles = 0;
+        for _ in 0..self.config.frames_per_step.max(1) {
+            cycles += self.nes.run_frame(action)?.cycles;
+        }
+        let info = StepInfo {
+            frame: self.nes.cpu().memory().ppu.frame,
+            cycles: cycles,
+        };
+        Ok((self.observe(), info))
+    }
+
+    pub fn observe(&self) -> Observation<'_> {
+        let memory = self.nes.cpu().memory();
+        Observation {
+            framebuffer: &memory.ppu.framebuffer,
+            ram: self.config.ram_watch.iter().map(|&addr| memory.peek(addr)).collect(),
+        }
+    }
+
+    pub fn config(&self) -> &EnvConfig {
+        &self.config
+    }
+
+    pub fn nes(&self) -> &Nes {
+        &self.nes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing;
+
+    use std::thread;
+
+    // Every NMI reads the first controller into $10, counts frames in $11, folds the buttons
+    // into $12 and paints the backdrop with them. The PPU ignores $2000 while it warms up, so
+    // the main loop keeps asking for NMIs.
+    const PLAYER: &str = "
+reset:  LDA #$80
+        STA $2000
+        JMP reset
+nmi:    LDA #$01
+        STA $4016
+        LDA #$00
+        STA $4016
+        LDX #$00
+read:   LDA $4016
+        LSR A
+        ROL $10
+        INX
+        CPX #8
+        BNE read
+        INC $11
+        LDA $10
+        EOR $12
+        STA $12
+        LDA #$3F
+        STA $2006
+        LDA #$00
+        STA $2006
+        LDA $10
+        AND #$3F
+        STA $2007
+        LDA #$20
+        STA $2006
+        LDA #$00
+        STA $2006
+        RTI
+";
+
+    fn config() -> EnvConfig {
+        EnvConfig { ram_watch: vec![0x10, 0x11, 0x12, 0x0300], ram_init: RamInit::Random(7), ..EnvConfig::default() }
+    }
+
+    // The same pseudo-random buttons for every run
+    fn actions(count: usize) -> Vec<InputFrame> {
+        let mut seed = 1u32;
+        (0..count).map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            InputFrame::new((seed >> 16) as u8, 0)
+        }).collect()
+    }
+
+    // A step's observation, owned
+    fn run(env: &mut Env, actions: &[InputFrame]) -> Vec<(Vec<u8>, Vec<u8>, StepInfo)> {
+        actions.iter().map(|&action| {
+            let (observation, info) = env.step(action).unwrap();
+            (observation.framebuffer.to_vec(), observation.ram, info)
+        }).collect()
+    }
+
+    #[test]
+    fn identical_actions_give_identical_observations() {
+        let rom = testing::build_test_rom(PLAYER, None);
+        let actions = actions(1000);
+        let mut first = Env::new(&rom, config()).unwrap();
+        let mut second = Env::new(&rom, config()).unwrap();
+        for (step, &action) in actions.iter().enumerate() {
+            let (a, a_info) = first.step(action).unwrap();
+            let (a_frame, a_ram) = (a.framebuffer.to_vec(), a.ram);
+            let (b, b_info) = second.step(action).unwrap();
+            assert_eq!((a_info, &a_ram), (b_info, &b.ram), "step {}", step);
+            assert!(a_frame == b.framebuffer, "step {}", step);
+        }
+        // Random power-on RAM comes from the seed, so resetting starts over the same way
+        assert_eq!(first.reset().ram, Env::new(&rom, config()).unwrap().observe().ram);
+    }
+
+    #[test]
+    fn envs_on_their_own_threads_match_one_run_alone() {
+        let rom = testing::build_test_rom(PLAYER, None);
+        let actions = actions(60);
+        let alone = run(&mut Env::new(&rom, config()).unwrap(), &actions);
+        let threads: Vec<_> = (0..4).map(|_| {
+            let (rom, actions) = (rom.clone(), actions.clone());
+            thread::spawn(move || run(&mut Env::new(&rom, config()).unwrap(), &actions))
+        }).collect();
+        for thread in threads {
+            assert!(thread.join().unwrap() == alone);
+        }
+        // The buttons did change the picture
+        assert!(alone.iter().any(|step| step.0 != alone[0].0));
+    }
+}
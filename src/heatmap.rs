@@ -0,0 +1,93 @@
+// Per-address access counts, for seeing which parts of memory a game actually uses
+
+use mem::BusObserver;
+
+use std::io;
+use std::io::prelude::*;
+
+pub struct AccessHeatmap {
+    reads: Vec<u32>,
+    writes: Vec<u32>,
+}
+
+impl Default for AccessHeatmap {
+    fn default() -> AccessHeatmap { AccessHeatmap::new() }
+}
+
+impl AccessHeatmap {
+    pub fn new() -> AccessHeatmap {
+        AccessHeatmap {
+            reads: vec![0; 0x10000],
+            writes: vec![0; 0x10000],
+        }
+    }
+
+    pub fn reads(&self, addr: u16) -> u32 {
+        self.reads[addr as usize]
+    }
+
+    pub fn writes(&self, addr: u16) -> u32 {
+        self.writes[addr as usize]
+    }
+
+    // One row per address that was touched at all
+    pub fn write_csv<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "addr,reads,writes")?;
+        for (addr, (reads, writes)) in self.reads.iter().zip(self.writes.iter()).enumerate() {
+            if *reads != 0 || *writes != 0 {
+                writeln!(out, "{:04X},{},{}", addr, reads, writes)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl BusObserver for AccessHeatmap {
+    fn on_read(&mut self, addr: u16, _val: u8) {
+        self.reads[addr as usize] = self.reads[addr as usize].saturating_add(1);
+    }
+
+    fn on_write(&mut self, addr: u16, _val: u8) {
+        self.writes[addr as usize] = self.writes[addr as usize].saturating_add(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AccessHeatmap;
+    use testing;
+
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn counts_fetches_operands_and_data() {
+        // LDA $10, STA $0200, JMP $8000, three times round
+        let mut cpu = testing::build_program(&[0xA5, 0x10, 0x8D, 0x00, 0x02, 0x4C, 0x00, 0x80]);
+        let heatmap = Arc::new(Mutex::new(AccessHeatmap::new()));
+        cpu.memory_mut().set_observer(Box::new(heatmap.clone()));
+        for _ in 0..9 {
+            cpu.emulate_cycle().unwrap();
+        }
+
+        let heatmap = heatmap.lock().unwrap();
+        for addr in 0x8000..0x8008 {
+            assert_eq!((heatmap.reads(addr), heatmap.writes(addr)), (3, 0), "${:04X}", addr);
+        }
+        assert_eq!((heatmap.reads(0x0010), heatmap.writes(0x0200)), (3, 3));
+        let mut csv = Vec::new();
+        heatmap.write_csv(&mut csv).unwrap();
+        assert_eq!(String::from_utf8(csv).unwrap(), "\
+addr,reads,writes
+0010,3,0
+0200,0,3
+8000,3,0
+8001,3,0
+8002,3,0
+8003,3,0
+8004,3,0
+8005,3,0
+8006,3,0
+8007,3,0
+");
+    }
+}
@@ -0,0 +1,41 @@
+use std::io;
+use std::io::prelude::*;
+
+// An indexed image: every pixel is an index into whatever palette it gets written out with
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize) -> Image {
+        Image {
+            width: width,
+            height: height,
+            pixels: vec![0; width * height],
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> u8 {
+        self.pixels[y * self.width + x]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, val: u8) {
+        self.pixels[y * self.width + x] = val;
+    }
+
+    // Write as a binary PPM (P6), looking every pixel up in palette
+    pub fn write_ppm<W: Write>(&self, out: &mut W, palette: &[[u8; 3]]) -> io::Result<()> {
+        write!(out, "P6\n{} {}\n255\n", self.width, self.height)?;
+        let mut rgb = Vec::with_capacity(self.pixels.len() * 3);
+        for &index in self.pixels.iter() {
+            let color = palette.get(index as usize).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData,
+                               format!("pixel index {} is outside the palette", index))
+            })?;
+            rgb.extend_from_slice(color);
+        }
+        out.write_all(&rgb)
+    }
+}
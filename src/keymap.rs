@@ -0,0 +1,508 @@
+// Which host keys and gamepad buttons drive which controller buttons and emulator actions.
+//
+// Keymaps are INI files. Each section lists `name = KEY` lines, and # or ; starts a comment:
+//   [player1] and [player2]  a, b, select, start, up, down, left, right, and turbo_ any of
+//                            those, e.g. turbo_a
+//   [actions]                save_state, load_state, next_slot, previous_slot, fast_forward,
+//                            reset, pause, frame_advance, post_process, input_display,
+//                            toggle_background, toggle_sprites, sprite_tint, and mute_ any
+//                            of pulse1, pulse2, triangle, noise and dmc, e.g. mute_noise
+// KEY is the frontend's name for a key or gamepad button, e.g. `Return` or `Pad1.A`, compared
+// without regard to case. A key can only be bound once. Anything a file leaves out keeps its
+// binding from DEFAULT_KEYMAP.
+//
+// Turbo is part of the mapping, not the controller: a turbo binding holds its button for the
+// first half of every TurboConfig::rate_frames frames, counted from power-on, when InputState
+// puts the frame's input together. So the input handed to the machine, and any movie made of
+// it, already has the button going on and off.
+
+use apu::Channel;
+use controller::{Button, Controller, InputFrame};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+
+pub const DEFAULT_KEYMAP: &str = "\
+[player1]
+a = X
+b = Z
+select = RShift
+start = Return
+up = Up
+down = Down
+left = Left
+right = Right
+turbo_a = S
+turbo_b = A
+
+[player2]
+a = Pad2.A
+b = Pad2.B
+select = Pad2.Back
+start = Pad2.Start
+up = Pad2.DPadUp
+down = Pad2.DPadDown
+left = Pad2.DPadLeft
+right = Pad2.DPadRight
+turbo_a = Pad2.Y
+turbo_b = Pad2.X
+
+[actions]
+save_state = F5
+next_slot = F6
+load_state = F7
+previous_slot = F8
+fast_forward = Tab
+reset = F12
+pause = P
+frame_advance = Backslash
+post_process = F9
+input_display = F10
+toggle_background = F2
+toggle_sprites = F3
+sprite_tint = F4
+mute_pulse1 = 1
+mute_pulse2 = 2
+mute_triangle = 3
+mute_noise = 4
+mute_dmc = 5
+";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    // Quick save and load, to and from the selected slot
+    SaveState,
+    LoadState,
+    // Select the slot quick save and load use
+    NextSlot,
+    PreviousSlot,
+    FastForward,
+    Reset,
+    // Toggles pause
+    Pause,
+    // Runs one frame and stays paused
+    FrameAdvance,
+    // Steps through the post-processing filters
+    PostProcess,
+    // Toggles the input display
+    InputDisplay,
+    // Toggle drawing a layer of the picture, see PPU::set_layer_visibility
+    ToggleBackground,
+    ToggleSprites,
+    // Toggles coloring sprites by OAM entry
+    SpriteTint,
+    // Toggles muting a sound channel, see APU::set_channel_enabled
+    MuteChannel(Channel),
+}
+
+// How fast a turbo button goes on and off
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TurboConfig {
+    // Frames per press and release, at least 1. At 2 the button alternates every frame.
+    pub rate_frames: u8,
+}
+
+impl Default for TurboConfig {
+    fn default() -> TurboConfig {
+        TurboConfig { rate_frames: 2 }
+    }
+}
+
+impl TurboConfig {
+    // Whether the button is down on frame, the first half of each cycle rounding up
+    pub fn pressed(&self, frame: u64) -> bool {
+        let rate = self.rate_frames.max(1) as u64;
+        frame % rate < rate.div_ceil(2)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Binding {
+    Button { port: usize, button: Button },
+    Turbo { port: usize, button: Button, turbo: TurboConfig },
+    Action(Action),
+}
+
+#[derive(Debug)]
+pub enum KeyMapError {
+    Io(io::Error),
+    Syntax { line: usize },
+    UnknownSection { line: usize, name: String },
+    UnknownSetting { line: usize, name: String },
+    // A setting outside of any section
+    NoSection { line: usize },
+    DuplicateKey { line: usize, key: String, first_line: usize },
+}
+
+impl fmt::Display for KeyMapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            KeyMapError::Io(ref e) => write!(f, "{}", e),
+            KeyMapError::Syntax { line } => write!(f, "line {}: expected [section] or name = KEY", line),
+            KeyMapError::UnknownSection { line, ref name } => write!(f, "line {}: unknown section [{}]", line, name),
+            KeyMapError::UnknownSetting { line, ref name } => write!(f, "line {}: unknown setting {}", line, name),
+            KeyMapError::NoSection { line } => write!(f, "line {}: setting before any [section]", line),
+            KeyMapError::DuplicateKey { line, ref key, first_line } =>
+                write!(f, "line {}: {} is already bound on line {}", line, key, first_line),
+        }
+    }
+}
+
+impl From<io::Error> for KeyMapError {
+    fn from(e: io::Error) -> KeyMapError {
+        KeyMapError::Io(e)
+    }
+}
+
+fn button(name: &str) -> Option<Button> {
+    match name {
+        "a" => Some(Button::A),
+        "b" => Some(Button::B),
+        "select" => Some(Button::Select),
+        "start" => Some(Button::Start),
+        "up" => Some(Button::Up),
+        "down" => Some(Button::Down),
+        "left" => Some(Button::Left),
+        "right" => Some(Button::Right),
+        _ => None,
+    }
+}
+
+fn action(name: &str) -> Option<Action> {
+    match name {
+        "save_state" => Some(Action::SaveState),
+        "load_state" => Some(Action::LoadState),
+        "next_slot" => Some(Action::NextSlot),
+        "previous_slot" => Some(Action::PreviousSlot),
+        "fast_forward" => Some(Action::FastForward),
+        "reset" => Some(Action::Reset),
+        "pause" => Some(Action::Pause),
+        "frame_advance" => Some(Action::FrameAdvance),
+        "post_process" => Some(Action::PostProcess),
+        "input_display" => Some(Action::InputDisplay),
+        "toggle_background" => Some(Action::ToggleBackground),
+        "toggle_sprites" => Some(Action::ToggleSprites),
+        "sprite_tint" => Some(Action::SpriteTint),
+        _ => name.strip_prefix("mute_").and_then(|channel| channel.parse().ok()).map(Action::MuteChannel),
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Section {
+    Player(usize),
+    Actions,
+}
+
+pub struct KeyMap {
+    // Keyed by lowercased key name
+    keys: HashMap<String, Binding>,
+}
+
+impl Default for KeyMap {
+    fn default() -> KeyMap {
+        KeyMap::parse_over(HashMap::new(), DEFAULT_KEYMAP).expect("the default keymap parses")
+    }
+}
+
+impl KeyMap {
+    pub fn load(path: &str) -> Result<KeyMap, KeyMapError> {
+        KeyMap::parse(&fs::read_to_string(path)?)
+    }
+
+    // Bindings from text, on top of the defaults
+    pub fn parse(text: &str) -> Result<KeyMap, KeyMapError> {
+        KeyMap::parse_over(KeyMap::default().keys, text)
+    }
+
+    fn parse_over(mut keys: HashMap<String, Binding>, text: &str) -> Result<KeyMap, KeyMapError> {
+        let mut section = None;
+        // Bindings this text makes, and the line each is on
+        let mut bound: HashMap<String, (Binding, usize)> = HashMap::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let line_no = i + 1;
+            let line = line.split(['#', ';']).next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                let name = name.trim().to_ascii_lowercase();
+                section = Some(match name.as_str() {
+                    "player1" => Section::Player(0),
+                    "player2" => Section::Player(1),
+                    "actions" => Section::Actions,
+                    _ => return Err(KeyMapError::UnknownSection { line: line_no, name: name }),
+                });
+                continue;
+            }
+
+            let (name, key) = match line.split_once('=') {
+                Some((name, key)) => (name.trim().to_ascii_lowercase(), key.trim().to_ascii_lowercase()),
+                None => return Err(KeyMapError::Syntax { line: line_no }),
+            };
+            if name.is_empty() || key.is_empty() || key.contains(char::is_whitespace) {
+                return Err(KeyMapError::Syntax { line: line_no });
+            }
+            let binding = match section {
+                Some(Section::Player(port)) => match name.strip_prefix("turbo_") {
+                    Some(name) => button(name).map(|b| Binding::Turbo { port: port, button: b, turbo: TurboConfig::default() }),
+                    None => button(&name).map(|b| Binding::Button { port: port, button: b }),
+                },
+                Some(Section::Actions) => action(&name).map(Binding::Action),
+                None => return Err(KeyMapError::NoSection { line: line_no }),
+            };
+            let binding = binding.ok_or(KeyMapError::UnknownSetting { line: line_no, name: name })?;
+
+            if let Some(&(_, first_line)) = bound.get(&key) {
+                return Err(KeyMapError::DuplicateKey { line: line_no, key: key, first_line: first_line });
+            }
+            // Rebinding something replaces whatever key it had before
+            bound.retain(|_, &mut (b, _)| b != binding);
+            bound.insert(key, (binding, line_no));
+        }
+
+        keys.retain(|key, binding| !bound.contains_key(key) && !bound.values().any(|&(b, _)| b == *binding));
+        keys.extend(bound.into_iter().map(|(key, (binding, _))| (key, binding)));
+        Ok(KeyMap { keys: keys })
+    }
+
+    pub fn binding(&self, key: &str) -> Option<Binding> {
+        self.keys.get(&key.to_ascii_lowercase()).cloned()
+    }
+
+    // The key bound to something, if any
+    pub fn key_for(&self, binding: Binding) -> Option<&str> {
+        self.keys.iter().find(|&(_, b)| *b == binding).map(|(key, _)| key.as_str())
+    }
+
+    // Give every turbo binding the same rate, e.g. the config file's
+    pub fn set_turbo_rate(&mut self, rate_frames: u8) {
+        for binding in self.keys.values_mut() {
+            if let Binding::Turbo { ref mut turbo, .. } = *binding {
+                turbo.rate_frames = rate_frames.max(1);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    // Feed a key or gamepad button event through the map. Controller buttons are pressed or
+    // released on the matching controller, and actions are returned when their key goes down.
+    // Turbo needs the frame number, so turbo bindings only work through InputState.
+    pub fn handle_key(&self, key: &str, pressed: bool, controllers: &mut [Controller]) -> Option<Action> {
+        match self.binding(key)? {
+            Binding::Button { port, button } => {
+                if let Some(controller) = controllers.get_mut(port) {
+                    controller.set_button(button, pressed);
+                }
+                None
+            },
+            Binding::Turbo { .. } => None,
+            Binding::Action(action) => if pressed { Some(action) } else { None },
+        }
+    }
+}
+
+// What the bound keys are holding, put together into each frame's input
+#[derive(Debug, Clone, Default)]
+pub struct InputState {
+    // By controller, four for a Four Score
+    held: [u8; 4],
+    // Turbo buttons whose keys are down
+    turbo: Vec<(usize, Button, TurboConfig)>,
+}
+
+impl InputState {
+    pub fn new() -> InputState {
+        InputState::default()
+    }
+
+    // Feed a key or gamepad button event through keymap, returning actions when their key goes
+    // down
+    pub fn handle_key(&mut self, keymap: &KeyMap, key: &str, pressed: bool) -> Option<Action> {
+        match keymap.binding(key)? {
+            Binding::Button { port, button } => self.set_button(port, button, pressed),
+            Binding::Turbo { port, button, turbo } => self.set_turbo(port, button, if pressed { Some(turbo) } else { None }),
+            Binding::Action(action) => return if pressed { Some(action) } else { None },
+        }
+        None
+    }
+
+    pub fn set_button(&mut self, port: usize, button: Button, pressed: bool) {
+        if let Some(buttons) = self.held.get_mut(port) {
+            let bit = 1 << button as u8;
+            if pressed { *buttons |= bit } else { *buttons &= !bit }
+        }
+    }
+
+    // Hold button down with turbo at turbo's rate, or let go of it with None
+    pub fn set_turbo(&mut self, port: usize, button: Button, turbo: Option<TurboConfig>) {
+        self.turbo.retain(|&(p, b, _)| (p, b) != (port, button));
+        if let Some(turbo) = turbo {
+            self.turbo.push((port, button, turbo));
+        }
+    }
+
+    // The input for frame, counting from power-on like PPU::frame. Held buttons stay down
+    // through turbo.
+    pub fn frame(&self, frame: u64) -> InputFrame {
+        let mut buttons = self.held;
+        for &(port, button, turbo) in &self.turbo {
+            if turbo.pressed(frame) {
+                if let Some(buttons) = buttons.get_mut(port) {
+                    *buttons |= 1 << button as u8;
+                }
+            }
+        }
+        InputFrame::four_players(buttons)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use emulator::Nes;
+    use mem::RamInit;
+    use movie::Movie;
+    use testing;
+
+    fn bound(port: usize, button: Button) -> Option<Binding> {
+        Some(Binding::Button { port: port, button: button })
+    }
+
+    #[test]
+    fn defaults_bind_both_players_and_the_actions() {
+        let keymap = KeyMap::default();
+        assert_eq!(keymap.binding("x"), bound(0, Button::A));
+        assert_eq!(keymap.binding("RETURN"), bound(0, Button::Start));
+        assert_eq!(keymap.binding("Pad2.DPadLeft"), bound(1, Button::Left));
+        assert_eq!(keymap.binding("S"), Some(Binding::Turbo { port: 0, button: Button::A, turbo: TurboConfig::default() }));
+        assert_eq!(keymap.binding("F5"), Some(Binding::Action(Action::SaveState)));
+        assert_eq!(keymap.binding("4"), Some(Binding::Action(Action::MuteChannel(Channel::Noise))));
+        assert_eq!(keymap.binding("Q"), None);
+        assert_eq!(keymap.len(), DEFAULT_KEYMAP.lines().filter(|line| line.contains('=')).count());
+    }
+
+    #[test]
+    fn a_file_rebinds_on_top_of_the_defaults() {
+        let keymap = KeyMap::parse("
+# swap A and B on player 1
+[Player1]
+a = Z     ; was B's
+b = X
+
+[actions]
+reset = Pad1.Guide
+").unwrap();
+        assert_eq!(keymap.binding("z"), bound(0, Button::A));
+        assert_eq!(keymap.binding("x"), bound(0, Button::B));
+        assert_eq!(keymap.binding("pad1.guide"), Some(Binding::Action(Action::Reset)));
+        // Reset's old key is free, and everything else is as it was
+        assert_eq!(keymap.binding("F12"), None);
+        assert_eq!(keymap.key_for(Binding::Action(Action::Reset)), Some("pad1.guide"));
+        assert_eq!(keymap.binding("Up"), bound(0, Button::Up));
+        assert_eq!(keymap.len(), KeyMap::default().len());
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let error = |text: &str| KeyMap::parse(text).err().unwrap().to_string();
+        assert_eq!(error("[player1]\na = X\nb = x"), "line 3: x is already bound on line 2");
+        assert_eq!(error("\n[player3]"), "line 2: unknown section [player3]");
+        assert_eq!(error("[player1]\n\njump = Space"), "line 3: unknown setting jump");
+        assert_eq!(error("[actions]\nmute_bass = 6"), "line 2: unknown setting mute_bass");
+        assert_eq!(error("a = X"), "line 1: setting before any [section]");
+        assert_eq!(error("[actions]\nreset"), "line 2: expected [section] or name = KEY");
+        assert_eq!(error("[actions]\nreset = Left Ctrl"), "line 2: expected [section] or name = KEY");
+        assert!(matches!(KeyMap::load("/nonexistent/keymap.ini"), Err(KeyMapError::Io(_))));
+    }
+
+    #[test]
+    fn key_events_press_controller_buttons() {
+        let keymap = KeyMap::default();
+        let mut controllers = [Controller::new(), Controller::new()];
+        let events = [("Right", true), ("x", true), ("Pad2.Start", true), ("X", false), ("F7", false)];
+        for &(key, pressed) in events.iter() {
+            assert_eq!(keymap.handle_key(key, pressed, &mut controllers), None, "{}", key);
+        }
+        assert_eq!(controllers[0].buttons(), 1 << Button::Right as u8);
+        assert_eq!(controllers[1].buttons(), 1 << Button::Start as u8);
+        // Actions come back when their key goes down, and turbo needs InputState
+        assert_eq!(keymap.handle_key("F7", true, &mut controllers), Some(Action::LoadState));
+        assert_eq!(keymap.handle_key("S", true, &mut controllers), None);
+        assert_eq!(controllers[0].buttons(), 1 << Button::Right as u8);
+    }
+
+    #[test]
+    fn turbo_alternates_with_the_frame() {
+        let mut keymap = KeyMap::default();
+        keymap.set_turbo_rate(4);
+        let mut input = InputState::new();
+        assert_eq!(input.handle_key(&keymap, "S", true), None);
+        assert_eq!(input.handle_key(&keymap, "Up", true), None);
+        let held = (0..8).map(|frame| input.frame(frame).ports[0]).collect::<Vec<_>>();
+        assert_eq!(held, [0x11, 0x11, 0x10, 0x10, 0x11, 0x11, 0x10, 0x10]);
+
+        input.handle_key(&keymap, "S", false);
+        input.handle_key(&keymap, "Pad2.Y", true);
+        assert_eq!((input.frame(0).ports, input.frame(2).ports), ([0x10, 0x01], [0x10, 0x00]));
+
+        // Odd rates round the pressed half up
+        let turbo = TurboConfig { rate_frames: 3 };
+        assert_eq!((0..6).map(|frame| turbo.pressed(frame)).collect::<Vec<_>>(), [true, true, false, true, true, false]);
+        assert!(TurboConfig { rate_frames: 0 }.pressed(1));
+    }
+
+    #[test]
+    fn turbo_at_the_default_rate_pulses_every_frame() {
+        let keymap = KeyMap::default();
+        let mut input = InputState::new();
+        input.handle_key(&keymap, "A", true);
+        let held = (0..6).map(|frame| input.frame(frame).ports[0]).collect::<Vec<_>>();
+        assert_eq!(held, [0x02, 0x00, 0x02, 0x00, 0x02, 0x00]);
+    }
+
+    // Runs controller_echo.s for 30 frames, with input from the movie or else turbo A, and
+    // gives back the movie of what the controllers held and the RAM it ended with
+    fn echo(replay: Option<&Movie>) -> (Movie, Vec<u8>) {
+        let rom = testing::build_test_rom(include_str!("../tests/roms/controller_echo.s"), None);
+        let mut nes = Nes::builder().rom_bytes(&rom).ram_init(RamInit::Zero).build().unwrap();
+        let keymap = KeyMap::default();
+        let mut input = InputState::new();
+        input.handle_key(&keymap, "S", true);
+        let mut recording = Movie::new("echo.nes", "");
+        for _ in 0..30 {
+            let frame = nes.cpu().memory().ppu.frame;
+            let buttons = match replay {
+                Some(movie) => InputFrame::from(movie.frame(frame as usize).unwrap()),
+                None => input.frame(frame),
+            };
+            nes.run_frame(buttons).unwrap();
+            let controllers = &nes.cpu().memory().controllers;
+            recording.record_frame([controllers[0].buttons(), controllers[1].buttons()]);
+        }
+        (recording, nes.cpu().memory().ram.data.to_vec())
+    }
+
+    #[test]
+    fn turbo_recordings_replay_the_same() {
+        let (movie, ram) = echo(None);
+        // The movie has the pulses, not a held button
+        let a: Vec<u8> = (0..6).map(|frame| movie.frame(frame).unwrap()[0] & 1).collect();
+        assert_eq!(a, [1, 0, 1, 0, 1, 0]);
+        // and the program saw A on every other NMI
+        assert!(ram[0x402] > 5);
+        let (replayed, replayed_ram) = echo(Some(&movie));
+        assert_eq!(replayed.frames, movie.frames);
+        assert_eq!(replayed_ram, ram);
+    }
+}
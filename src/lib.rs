@@ -0,0 +1,75 @@
+#![allow(dead_code)]
+#![allow(clippy::needless_return, clippy::upper_case_acronyms, clippy::redundant_field_names)]
+#[macro_use]
+extern crate log;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "serde")]
+extern crate serde_json;
+#[cfg(all(test, feature = "serde"))]
+extern crate bincode;
+#[cfg(feature = "wasm")]
+extern crate wasm_bindgen;
+
+pub mod apu;
+pub mod asm;
+pub mod cheats;
+pub mod chr;
+pub mod config;
+pub mod controller;
+pub mod coverage;
+pub mod crash;
+pub mod cpu;
+pub mod debug_device;
+pub mod debugger;
+pub mod disasm;
+pub mod dmc;
+pub mod emulator;
+pub mod fds;
+mod flat_toml;
+pub mod font;
+pub mod gym;
+pub mod hash;
+pub mod heatmap;
+pub mod image;
+pub mod keymap;
+#[cfg(feature = "lockstep")]
+pub mod lockstep;
+pub mod log_limit;
+pub mod mapper;
+pub mod media;
+pub mod mem;
+pub mod monitor;
+pub mod movie;
+pub mod nsf;
+pub mod opcodes;
+pub mod osd;
+pub mod overscan;
+pub mod palette;
+pub mod patch;
+pub mod postprocess;
+pub mod ppu;
+pub mod profile;
+pub mod region;
+pub mod regression;
+mod registers;
+pub mod resampler;
+pub mod rom;
+pub mod scan;
+pub mod search;
+#[cfg(feature = "serde")]
+pub mod slots;
+pub mod state;
+pub mod stats;
+pub mod symbols;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod thread;
+pub mod throttle;
+pub mod util;
+pub mod vrc6;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+pub mod zapper;
+
+pub use emulator::{BuildError, EmulatorBuilder, FrameOutput, Nes, NesView, SwapError};
@@ -0,0 +1,593 @@
+// Lockstep comparison against another emulator: both run the same program and write down the
+// CPU's state before every instruction, and the first instruction where the two disagree is
+// where one of them goes wrong.
+//
+// A stream is a file of CpuStateSnapshots. Ours are binary, starting with MAGIC, then one record
+// per instruction, all little-endian:
+//
+//   pc: u16, a, x, y, s, p: u8, cycles: u64 (all ones if unknown),
+//   instruction length: u8, then that many bytes,
+//   bus operations: u16, then for each addr: u16, kind: u8 (0 read, 1 write), value: u8
+//
+// The bus operations are everything the instruction put on the bus, including an interrupt it
+// was followed by and any DMC fetch that stole a cycle. Nintendulator-style trace text, as in
+// nestest.log or our own --trace output, reads as a stream too, with no bus operations and only
+// as many instruction bytes as the line shows. Fields one side doesn't have aren't compared.
+
+use cpu::{EmulationError, CPU};
+use mem::{AccessKind, BusAccess};
+use opcodes::{self, OPCODE_TABLE};
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+
+pub const MAGIC: &[u8; 8] = b"NESLOCK1";
+
+// How many instructions before a divergence to show by default
+pub const DEFAULT_CONTEXT: usize = 8;
+
+const UNKNOWN_CYCLES: u64 = !0;
+
+#[derive(Debug)]
+pub enum LockstepError {
+    Io(io::Error),
+    // A binary stream that ends partway through a record
+    Truncated { record: u64 },
+    // A trace line with an instruction address but not the registers
+    Syntax { line: usize },
+}
+
+impl fmt::Display for LockstepError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LockstepError::Io(ref e) => write!(f, "{}", e),
+            LockstepError::Truncated { record } => write!(f, "stream ends partway through record {}", record),
+            LockstepError::Syntax { line } => write!(f, "line {}: expected A:, X:, Y:, P: and SP: fields", line),
+        }
+    }
+}
+
+impl From<io::Error> for LockstepError {
+    fn from(e: io::Error) -> LockstepError {
+        LockstepError::Io(e)
+    }
+}
+
+// The CPU as it was before an instruction, and what the instruction did on the bus
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuStateSnapshot {
+    pub pc: u16,
+    // The instruction's bytes, or as many of them as are known
+    pub bytes: Vec<u8>,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub s: u8,
+    pub p: u8,
+    pub cycles: Option<u64>,
+    pub bus: Vec<BusAccess>,
+}
+
+impl CpuStateSnapshot {
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.pc.to_le_bytes());
+        out.extend_from_slice(&[self.a, self.x, self.y, self.s, self.p]);
+        out.extend_from_slice(&self.cycles.unwrap_or(UNKNOWN_CYCLES).to_le_bytes());
+        out.push(self.bytes.len() as u8);
+        out.extend_from_slice(&self.bytes);
+        out.extend_from_slice(&(self.bus.len() as u16).to_le_bytes());
+        for access in self.bus.iter() {
+            out.extend_from_slice(&access.addr.to_le_bytes());
+            out.push(if access.kind == AccessKind::Write { 1 } else { 0 });
+            out.push(access.value);
+        }
+    }
+
+    // The next record in a binary stream, None at the end of it
+    pub fn decode<R: Read>(input: &mut R) -> io::Result<Option<CpuStateSnapshot>> {
+        let mut pc = [0; 2];
+        // Only running out before the first byte is a clean end
+        if input.read(&mut pc[..1])? == 0 {
+            return Ok(None)
+        }
+        input.read_exact(&mut pc[1..])?;
+        let mut regs = [0; 5];
+        input.read_exact(&mut regs)?;
+        let mut cycles = [0; 8];
+        input.read_exact(&mut cycles)?;
+        let cycles = u64::from_le_bytes(cycles);
+        let mut len = [0; 1];
+        input.read_exact(&mut len)?;
+        let mut bytes = vec![0; len[0] as usize];
+        input.read_exact(&mut bytes)?;
+        let mut count = [0; 2];
+        input.read_exact(&mut count)?;
+        let mut bus = Vec::with_capacity(u16::from_le_bytes(count) as usize);
+        for _ in 0..u16::from_le_bytes(count) {
+            let mut access = [0; 4];
+            input.read_exact(&mut access)?;
+            bus.push(BusAccess {
+                addr: u16::from_le_bytes([access[0], access[1]]),
+                kind: if access[2] == 1 { AccessKind::Write } else { AccessKind::Read },
+                value: access[3],
+            });
+        }
+        Ok(Some(CpuStateSnapshot {
+            pc: u16::from_le_bytes(pc),
+            bytes: bytes,
+            a: regs[0],
+            x: regs[1],
+            y: regs[2],
+            s: regs[3],
+            p: regs[4],
+            cycles: if cycles == UNKNOWN_CYCLES { None } else { Some(cycles) },
+            bus: bus,
+        }))
+    }
+
+    // A Nintendulator-style trace line:
+    //   C000  4C F5 C5  JMP $C5F5    A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+    // Ok(None) for lines that don't start with an address, like blank ones and headers.
+    fn parse_trace(line: &str) -> Result<Option<CpuStateSnapshot>, ()> {
+        let mut tokens = line.split_whitespace().peekable();
+        let pc = match tokens.next().filter(|token| token.len() == 4).and_then(|token| u16::from_str_radix(token, 16).ok()) {
+            Some(pc) => pc,
+            None => return Ok(None),
+        };
+        let mut bytes = Vec::new();
+        while let Some(byte) = tokens.peek().filter(|token| token.len() == 2).and_then(|token| u8::from_str_radix(token, 16).ok()) {
+            bytes.push(byte);
+            tokens.next();
+        }
+
+        let (mut a, mut x, mut y, mut s, mut p, mut cycles) = (None, None, None, None, None, None);
+        while let Some(token) = tokens.next() {
+            let (name, value) = match token.split_once(':') {
+                Some(field) => field,
+                None => continue,
+            };
+            // "CYC:  7" splits the value off
+            let value = if value.is_empty() { tokens.peek().cloned().unwrap_or("") } else { value };
+            let byte = u8::from_str_radix(value, 16).ok();
+            match name {
+                "A" => a = byte,
+                "X" => x = byte,
+                "Y" => y = byte,
+                "P" => p = byte,
+                "SP" => s = byte,
+                "CYC" => cycles = value.parse().ok(),
+                _ => {}
+            }
+        }
+        match (a, x, y, s, p) {
+            (Some(a), Some(x), Some(y), Some(s), Some(p)) => Ok(Some(CpuStateSnapshot {
+                pc: pc,
+                bytes: bytes,
+                a: a,
+                x: x,
+                y: y,
+                s: s,
+                p: p,
+                cycles: cycles,
+                bus: Vec::new(),
+            })),
+            _ => Err(()),
+        }
+    }
+
+    // The fields that differ from other's, of those both sides know
+    pub fn differences(&self, other: &CpuStateSnapshot) -> Vec<&'static str> {
+        let mut differences = Vec::new();
+        if self.pc != other.pc { differences.push("PC"); }
+        let common = self.bytes.len().min(other.bytes.len());
+        if self.bytes[..common] != other.bytes[..common] { differences.push("bytes"); }
+        if self.a != other.a { differences.push("A"); }
+        if self.x != other.x { differences.push("X"); }
+        if self.y != other.y { differences.push("Y"); }
+        if self.p != other.p { differences.push("P"); }
+        if self.s != other.s { differences.push("SP"); }
+        if let (Some(mine), Some(theirs)) = (self.cycles, other.cycles) {
+            if mine != theirs { differences.push("CYC"); }
+        }
+        if !self.bus.is_empty() && !other.bus.is_empty() && self.bus != other.bus {
+            differences.push("bus");
+        }
+        differences
+    }
+
+    // The instruction as assembly, if enough of its bytes are known
+    pub fn disassemble(&self) -> String {
+        let needed = self.bytes.first().map_or(1, |&opcode| OPCODE_TABLE[opcode as usize].len as usize);
+        if self.bytes.len() < needed {
+            return self.bytes.first().map_or_else(String::new, |&opcode| OPCODE_TABLE[opcode as usize].mnemonic.to_string())
+        }
+        opcodes::disassemble(&self.bytes, self.pc).0
+    }
+
+    // "R $C000=A9 W $0200=01"
+    pub fn bus_text(&self) -> String {
+        self.bus.iter()
+            .map(|access| format!("{} ${:04X}={:02X}", if access.kind == AccessKind::Write { "W" } else { "R" }, access.addr, access.value))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+impl fmt::Display for CpuStateSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes = self.bytes.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(" ");
+        write!(f, "{:04X}  {:<8}  {:<13} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
+               self.pc, bytes, self.disassemble(), self.a, self.x, self.y, self.p, self.s)?;
+        match self.cycles {
+            Some(cycles) => write!(f, " CYC:{}", cycles),
+            None => Ok(()),
+        }
+    }
+}
+
+// Run one instruction as CPU::emulate_cycle does and return the state it started from, with what
+// it did on the bus. The memory's access log has to be on (Memory::enable_access_log) for the
+// bus operations to be recorded.
+pub fn step(cpu: &mut CPU) -> Result<CpuStateSnapshot, EmulationError> {
+    let pc = cpu.pc();
+    let opcode = cpu.memory().peek(pc);
+    let bytes = (0..OPCODE_TABLE[opcode as usize].len as u16).map(|i| cpu.memory().peek(pc.wrapping_add(i))).collect();
+    let mut snapshot = CpuStateSnapshot {
+        pc: pc,
+        bytes: bytes,
+        a: cpu.a(),
+        x: cpu.x(),
+        y: cpu.y(),
+        s: cpu.s(),
+        p: cpu.flags().bits(),
+        cycles: Some(cpu.cycles),
+        bus: Vec::new(),
+    };
+    // Whatever was logged before this instruction belongs to no snapshot
+    cpu.memory_mut().take_access_log();
+    cpu.emulate_cycle()?;
+    snapshot.bus = cpu.memory_mut().take_access_log();
+    Ok(snapshot)
+}
+
+// Writes a binary stream, to a file or a pipe
+pub struct StreamWriter<W: Write> {
+    out: W,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> StreamWriter<W> {
+    pub fn new(mut out: W) -> io::Result<StreamWriter<W>> {
+        out.write_all(MAGIC)?;
+        Ok(StreamWriter { out: out, buf: Vec::new() })
+    }
+
+    pub fn write(&mut self, snapshot: &CpuStateSnapshot) -> io::Result<()> {
+        self.buf.clear();
+        snapshot.encode(&mut self.buf);
+        self.out.write_all(&self.buf)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+// Reads a stream, binary or trace text, one snapshot at a time
+pub struct StreamReader<R: BufRead> {
+    input: R,
+    binary: bool,
+    // Records or lines read so far
+    count: u64,
+    line: String,
+}
+
+impl<R: BufRead> StreamReader<R> {
+    // Tells the two kinds apart by whether the stream starts with MAGIC
+    pub fn new(mut input: R) -> io::Result<StreamReader<R>> {
+        let binary = input.fill_buf()?.starts_with(MAGIC);
+        if binary {
+            input.consume(MAGIC.len());
+        }
+        Ok(StreamReader { input: input, binary: binary, count: 0, line: String::new() })
+    }
+
+    pub fn next_snapshot(&mut self) -> Result<Option<CpuStateSnapshot>, LockstepError> {
+        if self.binary {
+            let record = self.count;
+            self.count += 1;
+            return CpuStateSnapshot::decode(&mut self.input).map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => LockstepError::Truncated { record: record },
+                _ => LockstepError::Io(e),
+            })
+        }
+        loop {
+            self.line.clear();
+            if self.input.read_line(&mut self.line)? == 0 {
+                return Ok(None)
+            }
+            self.count += 1;
+            match CpuStateSnapshot::parse_trace(&self.line) {
+                Ok(Some(snapshot)) => return Ok(Some(snapshot)),
+                Ok(None) => continue,
+                Err(()) => return Err(LockstepError::Syntax { line: self.count as usize }),
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for StreamReader<R> {
+    type Item = Result<CpuStateSnapshot, LockstepError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_snapshot().transpose()
+    }
+}
+
+pub fn open(path: &str) -> io::Result<StreamReader<BufReader<File>>> {
+    StreamReader::new(BufReader::new(File::open(path)?))
+}
+
+// Where two streams first disagree, with the instructions leading up to it. One of the pair is
+// None if that stream ended first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    // Counting from 0
+    pub index: u64,
+    pub a: Option<CpuStateSnapshot>,
+    pub b: Option<CpuStateSnapshot>,
+    pub differences: Vec<&'static str>,
+    // The instructions before it, oldest first. Both streams agreed on these.
+    pub history_a: Vec<CpuStateSnapshot>,
+    pub history_b: Vec<CpuStateSnapshot>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.differences.is_empty() {
+            writeln!(f, "Streams diverge at instruction {}: {} ends first", self.index, if self.a.is_none() { "a" } else { "b" })?;
+        } else {
+            let verb = if self.differences.len() == 1 { "differs" } else { "differ" };
+            writeln!(f, "Streams diverge at instruction {}: {} {}", self.index, self.differences.join(", "), verb)?;
+        }
+        for (name, history, at) in [("a", &self.history_a, &self.a), ("b", &self.history_b, &self.b)] {
+            writeln!(f, "{}:", name)?;
+            for snapshot in history.iter() {
+                writeln!(f, "    {}", snapshot)?;
+            }
+            match *at {
+                Some(ref snapshot) => {
+                    writeln!(f, "  > {}", snapshot)?;
+                    if self.differences.contains(&"bus") {
+                        writeln!(f, "      bus: {}", snapshot.bus_text())?;
+                    }
+                }
+                None => writeln!(f, "  > (end of stream)")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+pub enum Outcome {
+    // Both streams ran the same number of instructions, given here, and agreed throughout
+    Match(u64),
+    Diverged(Box<Divergence>),
+}
+
+// Read both streams until they disagree, keeping the last context instructions of each
+pub fn compare<A, B>(a: A, b: B, context: usize) -> Result<Outcome, LockstepError>
+    where A: IntoIterator<Item = Result<CpuStateSnapshot, LockstepError>>,
+          B: IntoIterator<Item = Result<CpuStateSnapshot, LockstepError>> {
+    let (mut a, mut b) = (a.into_iter(), b.into_iter());
+    let mut history_a = VecDeque::with_capacity(context + 1);
+    let mut history_b = VecDeque::with_capacity(context + 1);
+    let mut index = 0;
+    loop {
+        let (next_a, next_b) = (a.next().transpose()?, b.next().transpose()?);
+        let differences = match (&next_a, &next_b) {
+            (Some(next_a), Some(next_b)) => next_a.differences(next_b),
+            (None, None) => return Ok(Outcome::Match(index)),
+            _ => Vec::new(),
+        };
+        if next_a.is_none() || next_b.is_none() || !differences.is_empty() {
+            return Ok(Outcome::Diverged(Box::new(Divergence {
+                index: index,
+                a: next_a,
+                b: next_b,
+                differences: differences,
+                history_a: history_a.into(),
+                history_b: history_b.into(),
+            })))
+        }
+        if context > 0 {
+            if history_a.len() == context {
+                history_a.pop_front();
+                history_b.pop_front();
+            }
+            history_a.extend(next_a);
+            history_b.extend(next_b);
+        }
+        index += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing;
+
+    use std::sync::{Arc, Mutex};
+
+    // LDX #0, then INX / STX $0200 forever
+    const PROGRAM: [u8; 9] = [0xA2, 0x00, 0xE8, 0x8E, 0x00, 0x02, 0x4C, 0x02, 0x80];
+
+    fn snapshots(count: usize) -> Vec<CpuStateSnapshot> {
+        let mut cpu = testing::build_program(&PROGRAM);
+        cpu.memory_mut().enable_access_log();
+        (0..count).map(|_| step(&mut cpu).unwrap()).collect()
+    }
+
+    fn stream(snapshots: &[CpuStateSnapshot]) -> Vec<Result<CpuStateSnapshot, LockstepError>> {
+        snapshots.iter().cloned().map(Ok).collect()
+    }
+
+    fn diverged(a: &[CpuStateSnapshot], b: &[CpuStateSnapshot], context: usize) -> Divergence {
+        match compare(stream(a), stream(b), context).unwrap() {
+            Outcome::Diverged(divergence) => *divergence,
+            Outcome::Match(count) => panic!("streams matched for {} instructions", count),
+        }
+    }
+
+    #[test]
+    fn binary_streams_round_trip() {
+        let snapshots = snapshots(20);
+        assert_eq!(snapshots[1].bus_text(), "R $8002=E8");
+        assert_eq!(snapshots[2].bus_text(), "R $8003=8E R $8004=00 R $8005=02 W $0200=01");
+
+        let mut writer = StreamWriter::new(Vec::new()).unwrap();
+        for snapshot in snapshots.iter() {
+            writer.write(snapshot).unwrap();
+        }
+        let bytes = writer.out;
+        assert!(bytes.starts_with(MAGIC));
+        let read = StreamReader::new(&bytes[..]).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(read, snapshots);
+
+        // Cut short partway through the last record
+        match StreamReader::new(&bytes[..bytes.len() - 3]).unwrap().collect::<Result<Vec<_>, _>>() {
+            Err(LockstepError::Truncated { record: 19 }) => {}
+            other => panic!("expected a truncated record 19, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reports_the_first_divergence_with_context() {
+        let a = snapshots(10);
+        let mut b = a.clone();
+        b[6].a = 0x40;
+        b[8].x = 0x99;
+        let divergence = diverged(&a, &b, 3);
+        assert_eq!(divergence.index, 6);
+        assert_eq!(divergence.differences, ["A"]);
+        assert_eq!(divergence.history_a, &a[3..6]);
+        assert_eq!(divergence.history_b, &b[3..6]);
+        assert_eq!(divergence.to_string(), "\
+Streams diverge at instruction 6: A differs
+a:
+    8006  4C 02 80  JMP $8002     A:00 X:01 Y:00 P:24 SP:FD CYC:8
+    8002  E8        INX           A:00 X:01 Y:00 P:24 SP:FD CYC:11
+    8003  8E 00 02  STX $0200     A:00 X:02 Y:00 P:24 SP:FD CYC:13
+  > 8006  4C 02 80  JMP $8002     A:00 X:02 Y:00 P:24 SP:FD CYC:17
+b:
+    8006  4C 02 80  JMP $8002     A:00 X:01 Y:00 P:24 SP:FD CYC:8
+    8002  E8        INX           A:00 X:01 Y:00 P:24 SP:FD CYC:11
+    8003  8E 00 02  STX $0200     A:00 X:02 Y:00 P:24 SP:FD CYC:13
+  > 8006  4C 02 80  JMP $8002     A:40 X:02 Y:00 P:24 SP:FD CYC:17
+");
+
+        // Before the context fills up there is only as much history as there were instructions
+        let mut b = a.clone();
+        b[1].p = 0x25;
+        b[1].s = 0xFC;
+        let divergence = diverged(&a, &b, 3);
+        assert_eq!((divergence.index, divergence.differences), (1, vec!["P", "SP"]));
+        assert_eq!(divergence.history_a, &a[..1]);
+
+        assert!(matches!(compare(stream(&a), stream(&a), 3).unwrap(), Outcome::Match(10)));
+    }
+
+    #[test]
+    fn bus_only_differences_are_shown() {
+        let a = snapshots(6);
+        let mut b = a.clone();
+        b[5].bus[3].value = 0x07;
+        let divergence = diverged(&a, &b, 1);
+        assert_eq!((divergence.index, divergence.differences.clone()), (5, vec!["bus"]));
+        assert!(divergence.to_string().ends_with("\
+b:
+    8002  E8        INX           A:00 X:01 Y:00 P:24 SP:FD CYC:11
+  > 8003  8E 00 02  STX $0200     A:00 X:02 Y:00 P:24 SP:FD CYC:13
+      bus: R $8003=8E R $8004=00 R $8005=02 W $0200=07
+"));
+
+        // A side without bus operations isn't compared on them
+        let mut b = a.clone();
+        b[5].bus.clear();
+        assert!(matches!(compare(stream(&a), stream(&b), 1).unwrap(), Outcome::Match(6)));
+    }
+
+    #[test]
+    fn a_stream_that_ends_early_diverges() {
+        let a = snapshots(8);
+        let divergence = diverged(&a, &a[..5], 2);
+        assert_eq!((divergence.index, divergence.b.clone()), (5, None));
+        assert_eq!(divergence.a.as_ref(), Some(&a[5]));
+        assert!(divergence.differences.is_empty());
+        let report = divergence.to_string();
+        assert!(report.starts_with("Streams diverge at instruction 5: b ends first\n"));
+        assert!(report.ends_with("  > (end of stream)\n"));
+
+        let divergence = diverged(&a[..5], &a, 2);
+        assert!(divergence.to_string().starts_with("Streams diverge at instruction 5: a ends first\n"));
+    }
+
+    #[test]
+    fn trace_text_reads_as_a_stream() {
+        let text = "\
+; a header line
+C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+
+C5F5  A2 00     LDX #$00                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 30 CYC:10
+";
+        let read = StreamReader::new(text.as_bytes()).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(read.len(), 2);
+        assert_eq!(read[0], CpuStateSnapshot {
+            pc: 0xC000,
+            bytes: vec![0x4C, 0xF5, 0xC5],
+            a: 0,
+            x: 0,
+            y: 0,
+            s: 0xFD,
+            p: 0x24,
+            cycles: Some(7),
+            bus: Vec::new(),
+        });
+        assert_eq!((read[1].pc, read[1].cycles), (0xC5F5, Some(10)));
+
+        match StreamReader::new("C000  4C F5 C5  JMP $C5F5  A:00 X:00\n".as_bytes()).unwrap().next() {
+            Some(Err(LockstepError::Syntax { line: 1 })) => {}
+            other => panic!("expected a syntax error on line 1, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn our_trace_matches_our_binary_stream() {
+        let mut cpu = testing::build_program(&PROGRAM);
+        cpu.memory_mut().enable_access_log();
+        let lines = Arc::new(Mutex::new(String::new()));
+        let log = lines.clone();
+        cpu.set_trace_hook(Box::new(move |event| {
+            let mut log = log.lock().unwrap();
+            log.push_str(&event.to_string());
+            log.push('\n');
+        }));
+        let mut writer = StreamWriter::new(Vec::new()).unwrap();
+        for _ in 0..300 {
+            writer.write(&step(&mut cpu).unwrap()).unwrap();
+        }
+        let binary = writer.out;
+        let text = lines.lock().unwrap().clone();
+        let outcome = compare(StreamReader::new(&binary[..]).unwrap(), StreamReader::new(text.as_bytes()).unwrap(), 4).unwrap();
+        assert!(matches!(outcome, Outcome::Match(300)));
+
+        // A register the trace disagrees on is caught
+        let text = text.replacen("X:05", "X:06", 1);
+        match compare(StreamReader::new(&binary[..]).unwrap(), StreamReader::new(text.as_bytes()).unwrap(), 4).unwrap() {
+            Outcome::Diverged(divergence) => assert_eq!(divergence.differences, ["X"]),
+            Outcome::Match(_) => panic!("the edited trace matched"),
+        }
+    }
+}
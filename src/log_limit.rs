@@ -0,0 +1,124 @@
+// Keeps a misbehaving game from flooding the log. Something like a stray write in a loop would
+// otherwise repeat the same warning every frame for as long as it runs.
+
+use std::collections::HashSet;
+
+// Distinct events logged before the rest are dropped
+const MAX_EVENTS: usize = 32;
+
+#[derive(Debug, Clone, Default)]
+pub struct LogLimiter {
+    seen: HashSet<u32>,
+    full: bool,
+}
+
+impl LogLimiter {
+    pub fn new() -> LogLimiter {
+        LogLimiter::default()
+    }
+
+    // Whether to log the event identified by key: only the first time it happens, and only
+    // until MAX_EVENTS different ones have been. Says so under target when it starts dropping.
+    pub fn allow(&mut self, target: &str, key: u32) -> bool {
+        if self.seen.contains(&key) || self.full {
+            return false
+        }
+        if self.seen.len() == MAX_EVENTS {
+            self.full = true;
+            warn!(target: target, "too many warnings, not logging any more of them");
+            return false
+        }
+        self.seen.insert(key);
+        return true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm;
+    use testing;
+
+    use log::{Level, Log, Metadata, Record};
+    use std::cell::RefCell;
+    use std::sync::Once;
+
+    // Records what each test thread logs, so tests running side by side don't see each other's
+    struct CaptureLogger;
+
+    thread_local! {
+        static CAPTURED: RefCell<Vec<(Level, String, String)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    impl Log for CaptureLogger {
+        fn enabled(&self, _metadata: &Metadata) -> bool { true }
+
+        fn log(&self, record: &Record) {
+            let entry = (record.level(), record.target().to_string(), record.args().to_string());
+            CAPTURED.with(|captured| captured.borrow_mut().push(entry));
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CaptureLogger = CaptureLogger;
+    static INSTALL: Once = Once::new();
+
+    // The warnings logged while running count instructions of a program at $8000, as
+    // (target, message)
+    fn warnings_running(program: &str, count: usize) -> Vec<(String, String)> {
+        INSTALL.call_once(|| {
+            log::set_logger(&LOGGER).unwrap();
+            log::set_max_level(log::LevelFilter::Trace);
+        });
+        let mut cpu = testing::build_program(&asm::assemble(program, 0x8000).unwrap());
+        CAPTURED.with(|captured| captured.borrow_mut().clear());
+        for _ in 0..count {
+            cpu.emulate_cycle().unwrap();
+        }
+        CAPTURED.with(|captured| captured.borrow().iter()
+            .filter(|entry| entry.0 == Level::Warn)
+            .map(|entry| (entry.1.clone(), entry.2.clone()))
+            .collect())
+    }
+
+    #[test]
+    fn repeated_events_log_once() {
+        let warnings = warnings_running("
+loop:   STA $8000
+        LDA $2000
+        JMP loop
+", 3000);
+        assert_eq!(warnings, [
+            ("nes::mapper".to_string(), "Write of $00 to ROM at $8000".to_string()),
+            ("nes::ppu".to_string(), "Read of write-only PPU register $2000".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn distinct_events_stop_after_the_limit() {
+        let warnings = warnings_running("
+        LDX #$00
+loop:   STA $8000,X
+        INX
+        CPX #40
+        BNE loop
+done:   JMP done
+", 500);
+        assert_eq!(warnings.len(), MAX_EVENTS + 1);
+        assert_eq!(warnings[MAX_EVENTS - 1].1, "Write of $00 to ROM at $801F");
+        assert_eq!(warnings[MAX_EVENTS], ("nes::mapper".to_string(), "too many warnings, not logging any more of them".to_string()));
+    }
+
+    #[test]
+    fn limiter_counts_distinct_keys() {
+        let mut limiter = LogLimiter::new();
+        assert!(limiter.allow("test", 1));
+        assert!(!limiter.allow("test", 1));
+        for key in 2..=MAX_EVENTS as u32 {
+            assert!(limiter.allow("test", key));
+        }
+        assert!(!limiter.allow("test", 100));
+        assert!(!limiter.allow("test", 101));
+    }
+}
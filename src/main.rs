@@ -1,21 +1,450 @@
-#![allow(dead_code)]
-mod cpu;
-mod mem;
-mod rom;
+#![allow(clippy::needless_return, clippy::redundant_field_names)]
+extern crate env_logger;
+#[macro_use]
+extern crate log;
+extern crate nes;
+#[cfg(feature = "line-editing")]
+extern crate rustyline;
+
+use nes::apu;
+use nes::cheats;
+use nes::chr;
+use nes::config::{Config, UnknownKey};
+use nes::crash;
+use nes::heatmap::AccessHeatmap;
+use nes::image::Image;
+use nes::keymap::KeyMap;
+#[cfg(feature = "lockstep")]
+use nes::lockstep::{self, StreamWriter};
+use nes::media::{WavWriter, Y4mWriter};
+use nes::cpu;
+use nes::debugger::Debugger;
+use nes::disasm;
+use nes::font;
+use nes::mem;
+use nes::monitor::{self, History};
+use nes::movie;
+use nes::nsf;
+use nes::osd;
+use nes::overscan::{CroppedFrame, Overscan};
+use nes::palette;
+use nes::patch;
+use nes::postprocess::{PostProcess, PostProcessor, RgbFrame};
+use nes::ppu;
+use nes::region;
+use nes::rom;
+use nes::scan;
+#[cfg(feature = "serde")]
+use nes::slots::StateManager;
+use nes::stats::PerfStats;
+use nes::symbols::{self, SymbolTable};
+use nes::throttle::Throttle;
+use nes::Nes;
 
 use std::env;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+// NES colors for the --show-stats overlay
+const STATS_COLOR: u8 = 0x30;
+const STATS_BACKGROUND: u8 = 0x0F;
+// Hottest addresses listed by --profile
+const PROFILE_TOP: usize = 20;
+
+// What to pass, printed with a command-line error or for --help
+const USAGE: &str = "\
+usage: nes [OPTIONS] ROM
+       nes scan DIR [--ext LIST] [--format csv|json] [--output PATH] [--threads N]
+
+Machine:
+  --region ntsc|pal          --ram-init zero|ff|pattern|random:SEED
+  --raw [--load-addr ADDR]   --start-at ADDR          --fds-bios PATH
+  --nsf PATH [--track N]     --patch IPS|BPS          --four-score
+  --illegal-nop              --cycle-step             --no-sprite-limit
+  --debug-device             --cheat CODE
+
+Running:
+  --frames N  --steps N  --bench N  --speed X  --turbo-rate N  --sample-rate HZ
+  --mute CHANNELS            --keymap PATH            --watch-rom
+  --state-dir DIR            --load-slot N            --save-slot N
+
+Picture:
+  --palette PATH             --overscan none|ntsc|T,B,L,R
+  --post-process none|blend|ntsc                      --show-stats
+  --input-display            --screenshot PATH        --screenshot-at FRAME
+
+Recording:
+  --record PATH  --play PATH  --input-log PATH  --record-video PATH  --record-audio PATH
+
+Debugging:
+  --trace  --lockstep-out PATH  --heatmap PATH  --cdl PATH  --profile
+  --break SPEC  --watch EXPR  --symbols PATH  --monitor COMMAND  --monitor-script PATH
+  --interactive
+  --log-level FILTERS
+
+Inspecting:
+  --info  --disassemble [--linear-sweep]  --dump-chr PATH [--chr-palette N]
+  --dump-ram PATH  --dump-palette  --dump-nametables PATH [--nametable-grid]
+
+Settings:
+  --config PATH              --write-config
+";
 
 #[derive(Debug)]
 pub struct Args {
     filename: String,
+    dump_chr: Option<String>,
+    // A palette group to color the CHR dump with, from palette RAM once the run is over
+    chr_palette: Option<usize>,
+    dump_nametables: Option<String>,
+    dump_ram: Option<String>,
+    dump_palette: bool,
+    nametable_grid: bool,
+    steps: Option<u64>,
+    frames: Option<u64>,
+    screenshot_at: Option<u64>,
+    screenshot: String,
+    overscan: Overscan,
+    post_process: PostProcess,
+    cheats: Vec<cheats::Cheat>,
+    record: Option<String>,
+    record_video: Option<String>,
+    record_audio: Option<String>,
+    play: Option<String>,
+    info: bool,
+    disassemble: bool,
+    linear_sweep: bool,
+    region: Option<region::Region>,
+    palette: Option<String>,
+    ram_init: mem::RamInit,
+    illegal_nop: bool,
+    cycle_step: bool,
+    sprite_limit: bool,
+    show_stats: bool,
+    input_display: bool,
+    input_log: Option<String>,
+    keymap: Option<String>,
+    speed: f32,
+    turbo_rate: u8,
+    sample_rate: u32,
+    muted: Vec<apu::Channel>,
+    bench: bool,
+    trace: bool,
+    lockstep_out: Option<String>,
+    heatmap: Option<String>,
+    cdl: Option<String>,
+    profile: bool,
+    breakpoints: Vec<String>,
+    symbols: Vec<String>,
+    watches: Vec<String>,
+    watch_rom: bool,
+    monitor: Vec<String>,
+    monitor_script: Option<String>,
+    // Prompt for monitor commands once the run stops
+    interactive: bool,
+    raw: bool,
+    load_addr: u16,
+    start_at: Option<u16>,
+    nsf: Option<String>,
+    fds_bios: Option<String>,
+    patches: Vec<String>,
+    four_score: bool,
+    debug_device: bool,
+    log_level: Option<String>,
+    track: Option<u8>,
+    write_config: bool,
+    state_dir: Option<String>,
+    load_slot: Option<usize>,
+    save_slot: Option<usize>,
 }
 
 impl Args {
-    fn parse_args() -> Result<Args, &'static str> {
-        let mut args = Args{ filename: "test.nes".to_string() };
+    // Options the command line, less the program name, doesn't give come from the config file
+    fn parse_args<I: Iterator<Item = String>>(config: &Config, mut argv: I) -> Result<Args, &'static str> {
+        let mut args = Args{
+            filename: "test.nes".to_string(),
+            dump_chr: None,
+            chr_palette: None,
+            dump_nametables: None,
+            dump_ram: None,
+            dump_palette: false,
+            nametable_grid: false,
+            steps: None,
+            frames: None,
+            screenshot_at: None,
+            screenshot: config.screenshot.clone(),
+            overscan: config.overscan,
+            post_process: config.post_process,
+            cheats: Vec::new(),
+            record: None,
+            record_video: None,
+            record_audio: None,
+            play: None,
+            info: false,
+            disassemble: false,
+            linear_sweep: false,
+            region: config.region,
+            palette: config.palette.clone(),
+            ram_init: config.ram_init,
+            illegal_nop: config.illegal_nop,
+            cycle_step: config.cycle_step,
+            sprite_limit: config.sprite_limit,
+            show_stats: config.show_stats,
+            input_display: false,
+            input_log: None,
+            keymap: config.keymap.clone(),
+            speed: config.speed,
+            turbo_rate: config.turbo_rate,
+            sample_rate: apu::DEFAULT_SAMPLE_RATE,
+            muted: Vec::new(),
+            bench: false,
+            trace: false,
+            lockstep_out: None,
+            heatmap: None,
+            cdl: None,
+            profile: false,
+            breakpoints: Vec::new(),
+            symbols: Vec::new(),
+            watches: Vec::new(),
+            watch_rom: false,
+            monitor: Vec::new(),
+            monitor_script: None,
+            interactive: false,
+            raw: false,
+            load_addr: 0x8000,
+            start_at: None,
+            nsf: None,
+            fds_bios: config.fds_bios.clone(),
+            patches: Vec::new(),
+            four_score: config.four_score,
+            debug_device: false,
+            log_level: config.log_level.clone(),
+            track: None,
+            write_config: false,
+            state_dir: None,
+            load_slot: None,
+            save_slot: None,
+        };
 
-        for arg in env::args() {
-            match arg {
+        while let Some(arg) = argv.next() {
+            match arg.as_str() {
+                // Already read by load_config
+                "--config" => {
+                    argv.next().ok_or("--config needs a config file")?;
+                }
+                "--write-config" => {
+                    args.write_config = true;
+                }
+                "--region" => {
+                    args.region = Some(argv.next().ok_or("--region needs ntsc or pal")?.parse()?);
+                }
+                "--raw" => {
+                    args.raw = true;
+                }
+                "--load-addr" => {
+                    let addr = argv.next().ok_or("--load-addr needs an address")?;
+                    args.load_addr = parse_addr(&addr).ok_or("--load-addr needs a hex address")?;
+                }
+                "--start-at" => {
+                    let addr = argv.next().ok_or("--start-at needs an address")?;
+                    args.start_at = Some(parse_addr(&addr).ok_or("--start-at needs a hex address")?);
+                }
+                "--nsf" => {
+                    args.nsf = Some(argv.next().ok_or("--nsf needs an NSF file")?);
+                }
+                "--fds-bios" => {
+                    args.fds_bios = Some(argv.next().ok_or("--fds-bios needs the path to disksys.rom")?);
+                }
+                "--log-level" => {
+                    args.log_level = Some(argv.next().ok_or("--log-level needs a level or RUST_LOG-style filters")?);
+                }
+                "--patch" => {
+                    args.patches.push(argv.next().ok_or("--patch needs an IPS or BPS file")?);
+                }
+                "--four-score" => {
+                    args.four_score = true;
+                }
+                "--debug-device" => {
+                    args.debug_device = true;
+                }
+                "--track" => {
+                    let track = argv.next().ok_or("--track needs a track number")?;
+                    args.track = Some(track.parse().map_err(|_| "--track needs a number")?);
+                }
+                "--heatmap" => {
+                    args.heatmap = Some(argv.next().ok_or("--heatmap needs an output path")?);
+                }
+                "--profile" => {
+                    args.profile = true;
+                }
+                "--break" => {
+                    args.breakpoints.push(argv.next().ok_or("--break needs an address, a condition or both")?);
+                }
+                "--symbols" => {
+                    args.symbols.push(argv.next().ok_or("--symbols needs a symbol file or FCEUX .nl file")?);
+                }
+                "--watch" => {
+                    args.watches.push(argv.next().ok_or("--watch needs an expression")?);
+                }
+                "--watch-rom" => {
+                    args.watch_rom = true;
+                }
+                "--monitor" => {
+                    args.monitor.push(argv.next().ok_or("--monitor needs a command")?);
+                }
+                "--interactive" => {
+                    args.interactive = true;
+                }
+                "--monitor-script" => {
+                    args.monitor_script = Some(argv.next().ok_or("--monitor-script needs a file of monitor commands")?);
+                }
+                "--state-dir" => {
+                    args.state_dir = Some(argv.next().ok_or("--state-dir needs a directory")?);
+                }
+                "--load-slot" => {
+                    let slot = argv.next().ok_or("--load-slot needs a slot number")?;
+                    args.load_slot = Some(slot.parse().map_err(|_| "--load-slot needs a number")?);
+                }
+                "--save-slot" => {
+                    let slot = argv.next().ok_or("--save-slot needs a slot number")?;
+                    args.save_slot = Some(slot.parse().map_err(|_| "--save-slot needs a number")?);
+                }
+                "--cdl" => {
+                    args.cdl = Some(argv.next().ok_or("--cdl needs an output path")?);
+                }
+                "--trace" => {
+                    args.trace = true;
+                }
+                "--lockstep-out" => {
+                    args.lockstep_out = Some(argv.next().ok_or("--lockstep-out needs an output path or pipe")?);
+                }
+                "--speed" => {
+                    let speed = argv.next().ok_or("--speed needs a multiplier")?;
+                    args.speed = speed.parse().map_err(|_| "--speed needs a number")?;
+                }
+                "--turbo-rate" => {
+                    let rate = argv.next().ok_or("--turbo-rate needs a frame count")?;
+                    args.turbo_rate = rate.parse().ok().filter(|&r: &u8| r >= 1).ok_or("--turbo-rate needs a number from 1 to 255")?;
+                }
+                "--sample-rate" => {
+                    let rate = argv.next().ok_or("--sample-rate needs a rate in Hz")?;
+                    args.sample_rate = rate.parse().ok().filter(|r| (8000..=192000).contains(r))
+                        .ok_or("--sample-rate needs a rate from 8000 to 192000 Hz")?;
+                }
+                "--mute" => {
+                    let channels = argv.next().ok_or("--mute needs channels, e.g. dmc,noise")?;
+                    for channel in channels.split(',') {
+                        args.muted.push(channel.trim().parse()?);
+                    }
+                }
+                "--bench" => {
+                    let frames = argv.next().ok_or("--bench needs a frame count")?;
+                    args.frames = Some(frames.parse().map_err(|_| "--bench needs a number")?);
+                    args.bench = true;
+                }
+                "--palette" => {
+                    args.palette = Some(argv.next().ok_or("--palette needs a .pal file")?);
+                }
+                "--ram-init" => {
+                    args.ram_init = argv.next().ok_or("--ram-init needs zero, ff, pattern or random:SEED")?.parse()?;
+                }
+                "--illegal-nop" => {
+                    args.illegal_nop = true;
+                }
+                "--cycle-step" => {
+                    args.cycle_step = true;
+                }
+                "--keymap" => {
+                    args.keymap = Some(argv.next().ok_or("--keymap needs a keymap file")?);
+                }
+                "--show-stats" => {
+                    args.show_stats = true;
+                }
+                "--input-display" => {
+                    args.input_display = true;
+                }
+                "--input-log" => {
+                    args.input_log = Some(argv.next().ok_or("--input-log needs an output path")?);
+                }
+                "--no-sprite-limit" => {
+                    args.sprite_limit = false;
+                }
+                "--info" => {
+                    args.info = true;
+                }
+                "--disassemble" => {
+                    args.disassemble = true;
+                }
+                "--linear-sweep" => {
+                    args.linear_sweep = true;
+                }
+                "--dump-chr" => {
+                    args.dump_chr = Some(argv.next().ok_or("--dump-chr needs an output path")?);
+                }
+                "--chr-palette" => {
+                    let group = argv.next().ok_or("--chr-palette needs a palette group")?;
+                    match group.parse() {
+                        Ok(group) if group < 8 => args.chr_palette = Some(group),
+                        _ => return Err("--chr-palette needs a palette group from 0 to 7"),
+                    }
+                }
+                "--dump-ram" => {
+                    args.dump_ram = Some(argv.next().ok_or("--dump-ram needs an output path")?);
+                }
+                "--dump-palette" => {
+                    args.dump_palette = true;
+                }
+                "--dump-nametables" => {
+                    args.dump_nametables = Some(argv.next().ok_or("--dump-nametables needs an output path")?);
+                }
+                "--nametable-grid" => {
+                    args.nametable_grid = true;
+                }
+                "--steps" => {
+                    let steps = argv.next().ok_or("--steps needs a count")?;
+                    args.steps = Some(steps.parse().map_err(|_| "--steps needs a number")?);
+                }
+                "--frames" => {
+                    let frames = argv.next().ok_or("--frames needs a count")?;
+                    args.frames = Some(frames.parse().map_err(|_| "--frames needs a number")?);
+                }
+                "--screenshot-at" => {
+                    let frame = argv.next().ok_or("--screenshot-at needs a frame number")?;
+                    args.screenshot_at = Some(frame.parse().map_err(|_| "--screenshot-at needs a number")?);
+                }
+                "--cheat" => {
+                    let code = argv.next().ok_or("--cheat needs a code")?;
+                    args.cheats.push(cheats::Cheat::parse(&code).map_err(|_| "--cheat needs a Game Genie code or ADDR:VALUE")?);
+                }
+                "--record" => {
+                    args.record = Some(argv.next().ok_or("--record needs an output path")?);
+                }
+                "--record-video" => {
+                    args.record_video = Some(argv.next().ok_or("--record-video needs an output path")?);
+                }
+                "--record-audio" => {
+                    args.record_audio = Some(argv.next().ok_or("--record-audio needs an output path")?);
+                }
+                "--play" => {
+                    args.play = Some(argv.next().ok_or("--play needs a movie path")?);
+                }
+                "--screenshot" => {
+                    args.screenshot = argv.next().ok_or("--screenshot needs an output path")?;
+                }
+                "--post-process" => {
+                    args.post_process = argv.next().ok_or("--post-process needs none, blend or ntsc")?.parse()?;
+                }
+                "--overscan" => {
+                    args.overscan = argv.next().ok_or("--overscan needs none, ntsc or t,b,l,r")?.parse()?;
+                }
                 _ => {
                     args.filename = arg;
                 }
@@ -23,20 +452,1010 @@ impl Args {
         }
         return Ok(args)
     }
+
+    // The settings in effect, as --write-config saves them
+    fn config(&self) -> Config {
+        Config {
+            region: self.region,
+            palette: self.palette.clone(),
+            keymap: self.keymap.clone(),
+            fds_bios: self.fds_bios.clone(),
+            ram_init: self.ram_init,
+            illegal_nop: self.illegal_nop,
+            cycle_step: self.cycle_step,
+            sprite_limit: self.sprite_limit,
+            four_score: self.four_score,
+            show_stats: self.show_stats,
+            speed: self.speed,
+            turbo_rate: self.turbo_rate,
+            overscan: self.overscan,
+            post_process: self.post_process,
+            screenshot: self.screenshot.clone(),
+            log_level: self.log_level.clone(),
+        }
+    }
+}
+
+// `nes scan DIR`, which reports on every ROM under DIR instead of running one
+struct ScanArgs {
+    dir: String,
+    extensions: Vec<String>,
+    json: bool,
+    output: Option<String>,
+    threads: usize,
+}
+
+impl ScanArgs {
+    fn parse_args() -> Result<ScanArgs, &'static str> {
+        let mut args = ScanArgs {
+            dir: String::new(),
+            extensions: vec!["nes".to_string()],
+            json: false,
+            output: None,
+            threads: std::thread::available_parallelism().map(|n| n.get()).unwrap_or(4),
+        };
+        let mut argv = env::args().skip(2);
+        while let Some(arg) = argv.next() {
+            match arg.as_str() {
+                "--ext" => {
+                    let list = argv.next().ok_or("--ext needs a comma-separated list of extensions")?;
+                    args.extensions = list.split(',').map(|ext| ext.trim().trim_start_matches('.').to_string()).collect();
+                }
+                "--format" => {
+                    args.json = match argv.next().ok_or("--format needs csv or json")?.as_str() {
+                        "csv" => false,
+                        "json" => true,
+                        _ => return Err("--format needs csv or json"),
+                    };
+                }
+                "--output" => {
+                    args.output = Some(argv.next().ok_or("--output needs a path")?);
+                }
+                "--threads" => {
+                    let threads = argv.next().ok_or("--threads needs a count")?;
+                    args.threads = threads.parse().map_err(|_| "--threads needs a number")?;
+                }
+                _ => {
+                    args.dir = arg;
+                }
+            }
+        }
+        if args.dir.is_empty() {
+            return Err("scan needs a directory")
+        }
+        return Ok(args)
+    }
+}
+
+fn scan_roms() {
+    let args = match ScanArgs::parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    let entries = scan::scan_dir(Path::new(&args.dir), &args.extensions, args.threads);
+    let mut out: Box<dyn io::Write> = match args.output {
+        Some(ref path) => Box::new(BufWriter::new(File::create(path).unwrap())),
+        None => Box::new(io::stdout().lock()),
+    };
+    if args.json {
+        scan::write_json(&entries, &mut out).unwrap();
+    } else {
+        scan::write_csv(&entries, &mut out).unwrap();
+    }
+    out.flush().unwrap();
+    let failed = entries.iter().filter(|entry| entry.result.is_err()).count();
+    eprintln!("Scanned {} files, {} failed", entries.len(), failed);
+}
+
+// The config file and where it is, before the rest of the command line is parsed since it
+// supplies the defaults. A missing file is only an error if --config named it, and not even
+// then if it's about to be written.
+fn load_config() -> (Config, Vec<UnknownKey>, Option<PathBuf>) {
+    let argv: Vec<String> = env::args().skip(1).collect();
+    let named = argv.iter().position(|arg| arg == "--config").and_then(|i| argv.get(i + 1)).map(PathBuf::from);
+    let writing = argv.iter().any(|arg| arg == "--write-config");
+    let path = match named.clone().or_else(Config::default_path) {
+        Some(path) => path,
+        None => return (Config::default(), Vec::new(), None),
+    };
+    match Config::load(&path) {
+        Ok((config, unknown)) => (config, unknown, Some(path)),
+        Err(nes::config::ConfigError::Io(ref e)) if e.kind() == io::ErrorKind::NotFound && (named.is_none() || writing) =>
+            (Config::default(), Vec::new(), Some(path)),
+        Err(e) => {
+            eprintln!("Can't load config {}: {}", path.display(), e);
+            process::exit(1);
+        }
+    }
+}
+
+// Hex, with or without a 0x or $ prefix
+fn parse_addr(s: &str) -> Option<u16> {
+    let digits = s.trim_start_matches("0x").trim_start_matches('$');
+    u16::from_str_radix(digits, 16).ok()
+}
+
+fn load_rom(rom_file: &str) -> rom::ROM {
+    match rom::ROM::from_file(rom_file) {
+        Ok(rom) => rom,
+        Err(e) => {
+            eprintln!("Can't load {}: {}", rom_file, e);
+            process::exit(1);
+        }
+    }
+}
+
+// The ROM file's modification time, checked every frame for --watch-rom
+struct RomWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+impl RomWatcher {
+    fn new(path: &str) -> RomWatcher {
+        let path = PathBuf::from(path);
+        let modified = fs::metadata(&path).and_then(|meta| meta.modified()).ok();
+        RomWatcher { path: path, modified: modified }
+    }
+
+    // Whether the file was written since the last call. A build caught halfway through writing
+    // it changes it again when it finishes.
+    fn changed(&mut self) -> bool {
+        let modified = fs::metadata(&self.path).and_then(|meta| meta.modified()).ok();
+        if modified.is_none() || modified == self.modified {
+            return false
+        }
+        self.modified = modified;
+        true
+    }
+}
+
+// The ROM again, loaded the way the builder loaded it at startup
+fn reload_rom(args: &Args) -> Result<rom::ROM, String> {
+    let mut data = fs::read(&args.filename).map_err(|e| e.to_string())?;
+    for path in args.patches.iter() {
+        let patch = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        patch::apply(&mut data, &patch).map_err(|e| format!("{}: {}", path, e))?;
+    }
+    let rom = if args.raw { rom::ROM::raw_binary(&data, args.load_addr) } else { rom::ROM::from_bytes(&data) };
+    rom.map_err(|e| e.to_string())
+}
+
+// Write an image as a PPM, or exit saying why we can't
+fn write_image(img: &Image, out_file: &str, colors: &[[u8; 3]]) {
+    let result = File::create(out_file).and_then(|file| {
+        let mut out = BufWriter::new(file);
+        img.write_ppm(&mut out, colors)?;
+        out.flush()
+    });
+    if let Err(e) = result {
+        eprintln!("Can't write {}: {}", out_file, e);
+        process::exit(1);
+    }
+}
+
+fn dump_chr(chr: &[u8], out_file: &str, colors: &[[u8; 3]; 4]) {
+    write_image(&chr::render_pattern_tables(chr), out_file, colors);
+    println!("Wrote pattern tables to {}", out_file);
+}
+
+// Groups 0-3 are the background palettes and 4-7 the sprite ones
+fn chr_colors(cpu: &cpu::CPU, group: usize) -> [[u8; 3]; 4] {
+    let ram = cpu.memory().ppu.palette_ram();
+    let mut entries = [0; 4];
+    entries.copy_from_slice(&ram[group * 4..group * 4 + 4]);
+    palette::group_rgb(&entries)
+}
+
+fn dump_nametables(cpu: &cpu::CPU, out_file: &str, grid: bool) {
+    let overlay = ppu::NametableOverlay { grid: grid, viewport: true };
+    write_image(&cpu.memory().ppu.render_nametables_with(overlay), out_file, &palette::SYSTEM_PALETTE);
+    println!("Wrote nametables to {}", out_file);
+}
+
+fn load_keymap(path: &str) -> KeyMap {
+    match KeyMap::load(path) {
+        Ok(keymap) => keymap,
+        Err(e) => {
+            eprintln!("Can't load keymap {}: {}", path, e);
+            process::exit(1);
+        }
+    }
+}
+
+// --symbols, each file added to one table. prg_bank_size is as for SymbolTable::load.
+fn load_symbols<F: Fn(u16) -> usize>(args: &Args, prg_bank_size: F) -> SymbolTable {
+    let mut symbols = SymbolTable::new();
+    for path in args.symbols.iter() {
+        match symbols.load(path, &prg_bank_size) {
+            Ok(count) => info!("Loaded {} symbols from {}", count, path),
+            Err(e) => {
+                eprintln!("Can't load symbols {}: {}", path, e);
+                process::exit(1);
+            }
+        }
+    }
+    return symbols
+}
+
+// --break and --watch, with parse errors pointing at the column they're in. Both can use
+// symbols' names.
+fn build_debugger(args: &Args, symbols: SymbolTable) -> Debugger {
+    let mut debugger = Debugger::new();
+    debugger.symbols = symbols;
+    for spec in args.breakpoints.iter() {
+        if let Err(e) = debugger.add_breakpoint(spec) {
+            eprintln!("Bad breakpoint {:?}: {}", spec, e);
+            process::exit(1);
+        }
+    }
+    for text in args.watches.iter() {
+        if let Err(e) = debugger.add_watch(text) {
+            eprintln!("Bad watch expression {:?}: {}", text, e);
+            process::exit(1);
+        }
+    }
+    return debugger
+}
+
+// Call the rip's play routine at its own rate, for --frames calls or until killed. --frames
+// counts play periods here, since there's no picture.
+fn play_nsf(args: &Args, path: &str) {
+    let rip = match nsf::Nsf::from_file(path) {
+        Ok(rip) => rip,
+        Err(e) => {
+            eprintln!("Can't load {}: {}", path, e);
+            process::exit(1);
+        }
+    };
+    println!("{} - {} ({})", rip.title, rip.artist, rip.copyright);
+    let track = args.track.unwrap_or(rip.starting_song);
+    let mut player = match nsf::NsfPlayer::new(&rip, track) {
+        Ok(player) => player,
+        Err(e) => {
+            eprintln!("Can't play {}: {}", path, e);
+            process::exit(1);
+        }
+    };
+    println!("Playing track {} of {}, {} CPU cycles per play call{}", track, rip.songs,
+             player.period_cycles(), if rip.is_banked() { ", bank switched" } else { "" });
+    // Nothing plays the APU's samples yet, so the music is silent; the CPU side still runs in
+    // real time
+    let period_us = rip.play_period_us(rip.region()).max(1);
+    let mut throttle = Throttle::new(1_000_000.0 / period_us as f64);
+    throttle.set_speed(args.speed);
+
+    let mut periods = 0u64;
+    while args.frames.is_none_or(|limit| periods < limit) {
+        if let Err(e) = player.run_period() {
+            eprintln!("CPU stopped after {} play calls: {}", player.play_calls(), e);
+            eprintln!("{}", player.cpu().dump_state());
+            process::exit(1);
+        }
+        periods += 1;
+        throttle.wait_frame();
+    }
+    println!("Ran {} periods, {} play calls, {} cycles", periods, player.play_calls(), player.cpu().cycles);
+}
+
+// Where a battery-backed cartridge keeps its RAM between runs: next to the ROM, as .sav. Carts
+// without a battery, or without RAM for it to keep, get no save file.
+fn battery_save_path(cpu: &cpu::CPU, rom_file: &str) -> Option<PathBuf> {
+    let memory = cpu.memory();
+    if !memory.rom.header.has_battery() || memory.cartridge_ram().is_none() {
+        return None
+    }
+    Some(Path::new(rom_file).with_extension("sav"))
+}
+
+fn load_battery_save(cpu: &mut cpu::CPU, path: &Path) {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        // Nothing saved yet
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return,
+        Err(e) => {
+            eprintln!("Can't read {}: {}", path.display(), e);
+            process::exit(1);
+        }
+    };
+    let ram = cpu.memory_mut().cartridge_ram_mut().unwrap();
+    if data.len() != ram.len() {
+        eprintln!("{} is {} bytes but the cartridge has {} bytes of RAM", path.display(), data.len(), ram.len());
+        process::exit(1);
+    }
+    ram.copy_from_slice(&data);
+    info!("Loaded battery save from {}", path.display());
+}
+
+// --lockstep-out: a snapshot of the CPU per instruction, for the lockstep tool to compare with
+// another emulator's trace
+#[cfg(feature = "lockstep")]
+type LockstepOut = StreamWriter<BufWriter<File>>;
+
+#[cfg(feature = "lockstep")]
+fn open_lockstep_out(args: &Args, cpu: &mut cpu::CPU) -> Option<LockstepOut> {
+    let path = args.lockstep_out.as_ref()?;
+    let out = File::create(path).and_then(|file| StreamWriter::new(BufWriter::new(file)));
+    match out {
+        Ok(out) => {
+            cpu.memory_mut().enable_access_log();
+            Some(out)
+        }
+        Err(e) => {
+            eprintln!("Can't write {}: {}", path, e);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(feature = "lockstep")]
+fn emulate_instruction(cpu: &mut cpu::CPU, lockstep_out: &mut Option<LockstepOut>) -> Result<(), cpu::EmulationError> {
+    match *lockstep_out {
+        Some(ref mut out) => {
+            let snapshot = lockstep::step(cpu)?;
+            out.write(&snapshot).unwrap();
+        }
+        None => {
+            cpu.emulate_cycle()?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "lockstep"))]
+type LockstepOut = io::Sink;
+
+#[cfg(not(feature = "lockstep"))]
+fn open_lockstep_out(args: &Args, _cpu: &mut cpu::CPU) -> Option<LockstepOut> {
+    if args.lockstep_out.is_some() {
+        eprintln!("--lockstep-out needs the lockstep feature");
+        process::exit(1);
+    }
+    None
+}
+
+#[cfg(not(feature = "lockstep"))]
+fn emulate_instruction(cpu: &mut cpu::CPU, _lockstep_out: &mut Option<LockstepOut>) -> Result<(), cpu::EmulationError> {
+    cpu.emulate_cycle().map(|_| ())
+}
+
+// Save-state slots for the loaded ROM, under --state-dir or next to the config file. The
+// monitor's slot commands use them too.
+#[cfg(feature = "serde")]
+fn attach_state_manager(args: &Args, cpu: &mut cpu::CPU, debugger: &mut Debugger) {
+    let dir = args.state_dir.as_ref().map(PathBuf::from).or_else(StateManager::default_dir);
+    let manager = dir.map(|dir| StateManager::new(&dir, &cpu.memory().rom));
+    if let Some(slot) = args.load_slot {
+        let loaded = match manager {
+            Some(ref manager) => manager.load_slot(cpu, slot).map_err(|e| e.to_string()),
+            None => Err("no save-state directory: set HOME or pass --state-dir".to_string()),
+        };
+        match loaded {
+            Ok(info) => println!("Loaded {}", info),
+            Err(e) => {
+                eprintln!("Can't load slot {}: {}", slot, e);
+                process::exit(1);
+            }
+        }
+    }
+    debugger.state_manager = manager;
+}
+
+#[cfg(not(feature = "serde"))]
+fn attach_state_manager(args: &Args, _cpu: &mut cpu::CPU, _debugger: &mut Debugger) {
+    if args.load_slot.is_some() || args.save_slot.is_some() {
+        eprintln!("Save-state slots need the serde feature");
+        process::exit(1);
+    }
+}
+
+#[cfg(feature = "serde")]
+fn save_slot(cpu: &mut cpu::CPU, debugger: &Debugger, slot: usize) {
+    let saved = match debugger.state_manager {
+        Some(ref manager) => manager.save_slot(cpu, slot).map_err(|e| e.to_string()),
+        None => Err("no save-state directory: set HOME or pass --state-dir".to_string()),
+    };
+    match saved {
+        Ok(info) => println!("Saved {}", info),
+        Err(e) => eprintln!("Can't save slot {}: {}", slot, e),
+    }
+}
+
+#[cfg(not(feature = "serde"))]
+fn save_slot(_cpu: &mut cpu::CPU, _debugger: &Debugger, _slot: usize) {}
+
+// Open a --record-video or --record-audio file, or say why not and exit
+fn start_recording<T>(path: &str, what: &str, start: impl FnOnce(BufWriter<File>) -> io::Result<T>) -> T {
+    match File::create(path).and_then(|file| start(BufWriter::new(file))) {
+        Ok(writer) => writer,
+        Err(e) => {
+            eprintln!("Can't record {} to {}: {}", what, path, e);
+            process::exit(1);
+        }
+    }
+}
+
+fn load_movie(path: &str, rom_checksum: &str) -> movie::Movie {
+    let text = fs::read_to_string(path).unwrap();
+    let movie = movie::Movie::parse(&text).and_then(|movie| {
+        movie.check_rom(rom_checksum)?;
+        Ok(movie)
+    });
+    match movie {
+        Ok(movie) => movie,
+        Err(e) => {
+            eprintln!("Can't play {}: {}", path, e);
+            process::exit(1);
+        }
+    }
+}
+
+// Feed the controllers from the movie being played and/or log them to the one being recorded
+fn movie_input(cpu: &mut cpu::CPU, frame: u64, playback: &Option<movie::Movie>, recording: &mut Option<movie::Movie>) {
+    if let Some(ref movie) = *playback {
+        match movie.frame(frame as usize) {
+            Some(buttons) => {
+                let controllers = &mut cpu.memory_mut().controllers;
+                controllers[0].set_buttons(buttons[0]);
+                controllers[1].set_buttons(buttons[1]);
+            },
+            None if frame as usize == movie.frames.len() => println!("Movie playback ended"),
+            None => {},
+        }
+    }
+    if let Some(ref mut movie) = *recording {
+        let controllers = &cpu.memory().controllers;
+        movie.record_frame([controllers[0].buttons(), controllers[1].buttons()]);
+    }
+}
+
+// Print what the program has written to the debug device since the last call
+fn print_debug_output(cpu: &cpu::CPU, printed: &mut usize) {
+    if let Some(ref device) = cpu.memory().debug_device {
+        let output = device.output();
+        if output.len() > *printed {
+            print!("{}", &output[*printed..]);
+            *printed = output.len();
+        }
+    }
+}
+
+// After a panic mid-instruction: leave what's needed for a bug report in a temp directory, say
+// where, and exit as an uncaught panic would
+fn write_crash_bundle(cpu: &cpu::CPU) -> ! {
+    let report = crash::take_panic_report().unwrap_or_else(|| "panic with no report".to_string());
+    match crash::bundle_dir().and_then(|dir| crash::write_bundle(&dir, cpu, &report).map(|_| dir)) {
+        Ok(dir) => eprintln!("The emulator crashed. Please attach the files in {} to a bug report.", dir.display()),
+        Err(e) => eprintln!("The emulator crashed, and writing a crash report failed: {}", e),
+    }
+    process::exit(101);
+}
+
+// --log-level takes the same filters as RUST_LOG ("debug", "nes::ppu=trace,warn") and
+// replaces it. Without either, only warnings and errors show.
+// The history file, or one kept only for this session if there's nowhere to put it
+fn monitor_history() -> History {
+    match History::default_path().map(|path| History::load(&path)) {
+        Some(Ok(history)) => history,
+        Some(Err(e)) => {
+            eprintln!("Can't read monitor history: {}", e);
+            History::new()
+        },
+        None => History::new(),
+    }
+}
+
+fn save_monitor_history(history: &History) {
+    if let Err(e) = history.save() {
+        eprintln!("Can't save monitor history: {}", e);
+    }
+}
+
+#[cfg(feature = "line-editing")]
+fn interactive_monitor(debugger: &mut Debugger, cpu: &mut cpu::CPU) {
+    use rustyline::error::ReadlineError;
+
+    let mut history = monitor_history();
+    let mut editor = match rustyline::DefaultEditor::new() {
+        Ok(editor) => editor,
+        Err(e) => {
+            eprintln!("Can't start the monitor prompt: {}", e);
+            return;
+        }
+    };
+    for entry in history.entries() {
+        let _ = editor.add_history_entry(entry.as_str());
+    }
+    loop {
+        match editor.readline(monitor::PROMPT) {
+            Ok(line) => {
+                let _ = editor.add_history_entry(line.as_str());
+                let mut out = String::new();
+                let prompt = monitor::handle_line(debugger, cpu, &mut history, &line, &mut out);
+                print!("{}", out);
+                if prompt == monitor::Prompt::Quit {
+                    break;
+                }
+            },
+            // Ctrl-C drops the line being typed
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                eprintln!("Can't read the monitor prompt: {}", e);
+                break;
+            },
+        }
+    }
+    save_monitor_history(&history);
+}
+
+#[cfg(not(feature = "line-editing"))]
+fn interactive_monitor(debugger: &mut Debugger, cpu: &mut cpu::CPU) {
+    let mut history = monitor_history();
+    let stdin = io::stdin();
+    if let Err(e) = monitor::run(debugger, cpu, &mut history, stdin.lock(), io::stdout()) {
+        eprintln!("Can't read the monitor prompt: {}", e);
+    }
+    save_monitor_history(&history);
+}
+
+fn init_logging(filters: Option<&str>) {
+    let mut builder = match filters {
+        Some(filters) => {
+            let mut builder = env_logger::Builder::new();
+            builder.parse_filters(filters);
+            builder
+        },
+        None => env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")),
+    };
+    builder.init();
 }
 
 fn main() {
-    let args = Args::parse_args().unwrap();
+    if env::args().nth(1).as_deref() == Some("scan") {
+        scan_roms();
+        return;
+    }
+    if env::args().skip(1).any(|arg| arg == "--help" || arg == "-h") {
+        print!("{}", USAGE);
+        return;
+    }
+    let (config, unknown_keys, config_path) = load_config();
+    let args = match Args::parse_args(&config, env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(1);
+        }
+    };
+    init_logging(args.log_level.as_deref());
+    crash::install_panic_hook();
+    if let Some(ref path) = config_path {
+        for key in unknown_keys.iter() {
+            warn!("{}: {}", path.display(), key);
+        }
+    }
+
+    if args.write_config {
+        let path = config_path.unwrap_or_else(|| {
+            eprintln!("No config path: set HOME or pass --config");
+            process::exit(1);
+        });
+        if let Err(e) = args.config().save(&path) {
+            eprintln!("Can't write config {}: {}", path.display(), e);
+            process::exit(1);
+        }
+        println!("Wrote settings to {}", path.display());
+        return;
+    }
+
+    if args.info {
+        let rom = load_rom(&args.filename);
+        println!("{}", rom.summary());
+        return;
+    }
+
+    if args.disassemble {
+        let rom = load_rom(&args.filename);
+        // The listing's banks are all one size
+        let bank_size = disasm::banks(&rom).ok().and_then(|banks| banks.first().map(|bank| bank.len)).unwrap_or(0x4000);
+        let symbols = load_symbols(&args, |_| bank_size);
+        match disasm::disassemble_with(&rom, args.linear_sweep, &symbols) {
+            Ok(listing) => print!("{}", listing),
+            Err(e) => {
+                eprintln!("Can't disassemble {}: {}", args.filename, e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    // Greyscale needs nothing but the ROM. A palette group needs palette RAM, so that dump
+    // waits for the end of the run.
+    if let (Some(out_file), None) = (args.dump_chr.as_ref(), args.chr_palette) {
+        dump_chr(&load_rom(&args.filename).chr, out_file, &palette::GREYSCALE);
+        return;
+    }
+
+    if let Some(ref path) = args.nsf {
+        play_nsf(&args, path);
+        return;
+    }
+
+    let mut builder = Nes::builder().ram_init(args.ram_init).sample_rate(args.sample_rate);
+    for &channel in args.muted.iter() {
+        builder = builder.mute(channel);
+    }
+    if args.raw {
+        builder = builder.raw_binary(&fs::read(&args.filename).unwrap(), args.load_addr);
+    } else {
+        builder = builder.rom_path(&args.filename);
+    }
+    if let Some(ref path) = args.fds_bios {
+        builder = builder.fds_bios(path);
+    }
+    for path in args.patches.iter() {
+        builder = builder.patch(path);
+    }
+    if args.four_score {
+        builder = builder.four_score(true);
+    }
+    if let Some(addr) = args.start_at {
+        builder = builder.start_at(addr);
+    }
+    if let Some(region) = args.region {
+        builder = builder.region(region);
+    }
+    if let Some(ref path) = args.palette {
+        let data = fs::read(path).unwrap_or_else(|e| {
+            eprintln!("Can't read palette {}: {}", path, e);
+            process::exit(1);
+        });
+        match palette::from_pal(&data) {
+            Some(palette) => builder = builder.palette(palette),
+            None => {
+                eprintln!("Can't use palette {}: a .pal file needs 64 RGB triples", path);
+                process::exit(1);
+            }
+        }
+    }
+    if args.illegal_nop {
+        builder = builder.illegal_opcode_policy(cpu::IllegalOpcodePolicy::Nop);
+    }
+    if args.cycle_step {
+        builder = builder.step_mode(cpu::StepMode::Cycle);
+    }
+    builder = builder.sprite_limit(args.sprite_limit);
+    builder = builder.debug_device(args.debug_device);
+    let mut nes = match builder.build() {
+        Ok(nes) => nes,
+        Err(e) => {
+            eprintln!("Can't load {}: {}", args.filename, e);
+            process::exit(1);
+        }
+    };
+    // Benchmarks always run flat out
+    let frame_rate = nes.region().timing().frame_rate;
+    let mut throttle = Throttle::new(frame_rate);
+    throttle.set_speed(if args.bench { 0.0 } else { args.speed });
+    let sample_rate = nes.sample_rate();
+    let mut cpu = nes.cpu_mut();
+    // Movies start from a blank cartridge, so they play back the same whatever was saved
+    let movie = args.play.is_some() || args.record.is_some();
+    let save_file = if args.raw || movie { None } else { battery_save_path(cpu, &args.filename) };
+    if let Some(ref path) = save_file {
+        load_battery_save(cpu, path);
+    }
+    debug!("Initializing CPU with state:\n{}{}\n{}", cpu.dump_prg(0x8000, 256), cpu.dump_state(), cpu.dump_memory(0, 256));
 
-    let mut cpu: cpu::CPU = cpu::CPU::new(&args.filename);
-    cpu.reset();
+    let symbols = load_symbols(&args, |addr| cpu.memory().prg_bank_size(addr));
+    if args.trace {
+        let symbols = symbols.clone();
+        cpu.set_trace_hook(Box::new(move |event| {
+            match symbols.nearest(event.pc, event.bank) {
+                Some((symbol, offset)) => println!("{} ; {}", event, symbols::label(&symbol.name, offset)),
+                None => println!("{}", event),
+            }
+        }));
+    }
+    let mut lockstep_out = open_lockstep_out(&args, cpu);
+    let heatmap = Arc::new(Mutex::new(AccessHeatmap::new()));
+    if args.heatmap.is_some() {
+        cpu.memory_mut().set_observer(Box::new(heatmap.clone()));
+    }
+    if args.cdl.is_some() {
+        cpu.enable_coverage();
+    }
+    if args.profile {
+        cpu.enable_profiler();
+    }
+    for cheat in args.cheats.iter() {
+        cpu.add_cheat(*cheat);
+    }
+    let mut debugger = build_debugger(&args, symbols);
+    attach_state_manager(&args, cpu, &mut debugger);
+    if let Some(ref path) = args.monitor_script {
+        let mut output = String::new();
+        let result = debugger.source(cpu, path, &mut output);
+        print!("{}", output);
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+    let mut post = PostProcessor::new(args.post_process);
+
+    // Recordings are of the picture as cropped by --overscan, before post-processing
+    let mut video = args.record_video.as_ref().map(|path| {
+        let size = CroppedFrame::new(&cpu.memory().ppu.framebuffer, args.overscan);
+        let rate = ((frame_rate * 1000.0).round() as u32, 1000);
+        start_recording(path, "video", |out| Y4mWriter::new(out, size.width(), size.height(), rate))
+    });
+    let mut audio = args.record_audio.as_ref().map(|path| {
+        start_recording(path, "audio", |out| WavWriter::new(out, sample_rate, 1))
+    });
+    let mut input_log = args.input_log.as_ref().map(|path| BufWriter::new(File::create(path).unwrap()));
 
-    println!("Initializing CPU with state:");
-    println!("{:256?}", cpu);
-    cpu.print_memory(0, 256);
+    // Movies always start from power-on, which is where we are now
+    let rom_checksum = movie::rom_checksum(&cpu.memory().rom.md5());
+    let playback = args.play.as_ref().map(|path| load_movie(path, &rom_checksum));
+    let mut recording = args.record.as_ref().map(|_| movie::Movie::new(&args.filename, &rom_checksum));
+
+    // There's no windowed frontend to take key events yet, so a keymap is only checked
+    if let Some(ref path) = args.keymap {
+        let mut keymap = load_keymap(path);
+        keymap.set_turbo_rate(args.turbo_rate);
+        info!("Loaded {} key bindings from {}", keymap.len(), path);
+    }
 
-    println!("Starting CPU");
+    info!("Starting CPU");
+    let start = Instant::now();
+    let mut steps = 0u64;
+    let mut input_frame = cpu.memory().ppu.frame;
+    let mut failed = false;
+    // Set when a test program exits through the debug device, and what's printed of its output
+    let mut exit_code = None;
+    let mut debug_printed = 0;
+    let mut stats = PerfStats::new(frame_rate);
+    let mut frame_start = (Instant::now(), 0u64);
+    let mut stats_printed = Instant::now();
+    let mut rom_watcher = if args.watch_rom { Some(RomWatcher::new(&args.filename)) } else { None };
     loop {
-        cpu.emulate_cycle();
+        if args.steps.is_some_and(|limit| steps >= limit) { break; }
+        if args.frames.is_some_and(|limit| cpu.memory().ppu.frame >= limit) { break; }
+
+        let frame = cpu.memory().ppu.frame;
+        if frame == input_frame {
+            movie_input(cpu, frame, &playback, &mut recording);
+            input_frame += 1;
+        }
+        // Breakpoints stop the run before the instruction they're on
+        if let Some(spec) = debugger.check(cpu).map(|breakpoint| breakpoint.spec.clone()) {
+            print!("{}", debugger.break_report(cpu, &spec, steps));
+            break;
+        }
+        let result = match panic::catch_unwind(AssertUnwindSafe(|| emulate_instruction(cpu, &mut lockstep_out))) {
+            Ok(result) => result,
+            Err(_) => write_crash_bundle(cpu),
+        };
+        if let Err(e) = result {
+            if let cpu::EmulationError::Exited { code } = e {
+                exit_code = Some(code);
+                break;
+            }
+            eprintln!("CPU stopped after {} instructions: {}", steps, e);
+            eprintln!("{}", cpu.dump_state());
+            eprint!("{}", debugger.watch_report(cpu));
+            failed = true;
+            break;
+        }
+        steps += 1;
+
+        if cpu.memory().ppu.frame != frame {
+            print_debug_output(cpu, &mut debug_printed);
+            let now = Instant::now();
+            stats.record_frame(now - frame_start.0, steps - frame_start.1);
+            frame_start = (now, steps);
+            if args.show_stats {
+                let framebuffer = &mut cpu.memory_mut().ppu.framebuffer;
+                font::draw_text(framebuffer, ppu::SCREEN_WIDTH, 8, 8, &stats.overlay_text(), STATS_COLOR, Some(STATS_BACKGROUND));
+                if now - stats_printed >= Duration::from_secs(1) {
+                    println!("{}", stats);
+                    stats_printed = now;
+                }
+            }
+            // What the game latched during the frame just finished, not what was held for it
+            let latched = cpu.memory().latched_buttons();
+            if args.input_display {
+                osd::draw_input(&mut cpu.memory_mut().ppu.framebuffer, &latched);
+            }
+            if let Some(ref mut log) = input_log {
+                writeln!(log, "{}", movie::input_log_line(frame, &latched)).unwrap();
+            }
+        }
+
+        let ppu = &cpu.memory().ppu;
+        if ppu.frame != frame {
+            // Blending needs the frame before the screenshot too
+            let picture = if args.post_process != PostProcess::None && args.screenshot_at.is_some_and(|at| ppu.frame + 1 >= at) {
+                let frame = RgbFrame::from_indices(&CroppedFrame::new(&ppu.framebuffer, args.overscan), &ppu.rgb_palette);
+                Some(post.process(frame))
+            } else {
+                None
+            };
+            if args.screenshot_at == Some(ppu.frame) {
+                match picture {
+                    Some(picture) => {
+                        let mut out = BufWriter::new(File::create(&args.screenshot).unwrap());
+                        picture.write_ppm(&mut out).unwrap();
+                    },
+                    None => ppu.write_screenshot_cropped(Path::new(&args.screenshot), args.overscan).unwrap(),
+                }
+                println!("Wrote frame {} to {}", ppu.frame, args.screenshot);
+            }
+            // A failed write stops that recording, keeping what was written, and the run carries on
+            if let (Some(ref mut writer), Some(path)) = (video.as_mut(), args.record_video.as_ref()) {
+                let picture = RgbFrame::from_indices(&CroppedFrame::new(&ppu.framebuffer, args.overscan), &ppu.rgb_palette);
+                if let Err(e) = writer.write_frame(&picture) {
+                    eprintln!("Can't write video to {}: {}", path, e);
+                    let frames = writer.frames();
+                    let _ = video.take().map(Y4mWriter::finish);
+                    println!("Stopped recording video after {} frames", frames);
+                }
+            }
+            if let (Some(ref mut writer), Some(path)) = (audio.as_mut(), args.record_audio.as_ref()) {
+                if let Err(e) = writer.write_pcm(&cpu.memory_mut().apu.take_samples()) {
+                    eprintln!("Can't write audio to {}: {}", path, e);
+                    let frames = writer.frames();
+                    let _ = audio.take().map(WavWriter::finish);
+                    println!("Stopped recording audio after {:.2}s", frames as f64 / sample_rate as f64);
+                }
+            }
+            throttle.wait_frame();
+        }
+
+        // Reloading powers on with the new cartridge, so the frame count starts over
+        if cpu.memory().ppu.frame != frame && rom_watcher.as_mut().is_some_and(|watcher| watcher.changed()) {
+            match reload_rom(&args).and_then(|rom| nes.swap_rom(rom).map_err(|e| e.to_string())) {
+                Ok(()) => {
+                    println!("Reloaded {}", args.filename);
+                    input_frame = 0;
+                },
+                Err(e) => eprintln!("Can't reload {}: {}", args.filename, e),
+            }
+            cpu = nes.cpu_mut();
+        }
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    print_debug_output(cpu, &mut debug_printed);
+    if let Some(ref mut out) = lockstep_out {
+        out.flush().unwrap();
+    }
+
+    if let (Some(ref path), Some(ram)) = (save_file.as_ref(), cpu.memory().cartridge_ram()) {
+        if let Err(e) = fs::write(path, ram) {
+            eprintln!("Can't write {}: {}", path.display(), e);
+        }
+    }
+
+    if let Some(slot) = args.save_slot {
+        save_slot(cpu, &debugger, slot);
+    }
+
+    println!("Ran {} instructions, {} cycles, {} frames", steps, cpu.cycles, cpu.memory().ppu.frame);
+    println!("Final frame hash: {:016x}", cpu.memory().ppu.frame_hash());
+    for command in args.monitor.iter() {
+        let mut output = String::new();
+        let result = debugger.run_command(cpu, command, &mut output);
+        print!("{}", output);
+        if let Err(e) = result {
+            eprintln!("{}: {}", command, e);
+        }
+    }
+    if args.interactive {
+        interactive_monitor(&mut debugger, cpu);
+    }
+    if args.bench {
+        println!("{:.3}s: {:.1} frames/sec, {:.0} instructions/sec", elapsed,
+                 cpu.memory().ppu.frame as f64 / elapsed, steps as f64 / elapsed);
+    }
+
+    if let Some(ref out_file) = args.dump_nametables {
+        dump_nametables(cpu, out_file, args.nametable_grid);
+    }
+
+    if let (Some(out_file), Some(group)) = (args.dump_chr.as_ref(), args.chr_palette) {
+        dump_chr(&cpu.memory().rom.chr, out_file, &chr_colors(cpu, group));
+    }
+
+    if args.dump_palette {
+        print!("{}", cpu.memory().ppu.palette_listing());
+    }
+
+    if let Some(ref out_file) = args.dump_ram {
+        fs::write(out_file, &cpu.memory().ram_snapshot()[..]).unwrap();
+        println!("Wrote RAM to {}", out_file);
+    }
+
+    if let Some(ref out_file) = args.heatmap {
+        let mut out = BufWriter::new(File::create(out_file).unwrap());
+        heatmap.lock().unwrap().write_csv(&mut out).unwrap();
+        println!("Wrote bus access counts to {}", out_file);
+    }
+
+    if let (Some(ref out_file), Some(coverage)) = (args.cdl.as_ref(), cpu.coverage()) {
+        let mut out = BufWriter::new(File::create(out_file).unwrap());
+        coverage.write_cdl(&mut out).unwrap();
+        let (code, data) = coverage.counts();
+        println!("Wrote code/data log to {} ({} code bytes, {} data bytes)", out_file, code, data);
+    }
+
+    if let Some(report) = cpu.profile_report(PROFILE_TOP) {
+        print!("\n{}", report);
+    }
+
+    if let (Some(video), Some(ref out_file)) = (video, args.record_video.as_ref()) {
+        let frames = video.frames();
+        if let Err(e) = video.finish() {
+            eprintln!("Can't finish {}: {}", out_file, e);
+            process::exit(1);
+        }
+        println!("Wrote {} frames of video to {}", frames, out_file);
+    }
+
+    if let (Some(audio), Some(ref out_file)) = (audio, args.record_audio.as_ref()) {
+        let frames = audio.frames();
+        if let Err(e) = audio.finish() {
+            eprintln!("Can't finish {}: {}", out_file, e);
+            process::exit(1);
+        }
+        println!("Wrote {:.2}s of audio to {}", frames as f64 / sample_rate as f64, out_file);
+    }
+
+    if let (Some(mut log), Some(ref out_file)) = (input_log, args.input_log.as_ref()) {
+        log.flush().unwrap();
+        println!("Wrote input log to {}", out_file);
+    }
+
+    if let (Some(ref movie), Some(ref out_file)) = (recording, args.record) {
+        let mut out = BufWriter::new(File::create(out_file).unwrap());
+        movie.write(&mut out).unwrap();
+        println!("Recorded {} frames to {}", movie.frames.len(), out_file);
+    }
+
+    if failed {
+        process::exit(1);
+    }
+    if let Some(code) = exit_code {
+        println!("Program exited with code {}", code);
+        process::exit(code as i32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(config: &Config, argv: &[&str]) -> Args {
+        Args::parse_args(config, argv.iter().map(|arg| arg.to_string())).unwrap()
+    }
+
+    #[test]
+    fn command_line_overrides_the_config_file() {
+        let (config, _) = Config::parse("region = \"pal\"\nspeed = 2.0\n").unwrap();
+        let args = parse(&config, &["--region", "ntsc", "game.nes"]);
+        assert_eq!((args.region, args.speed), (Some(region::Region::Ntsc), 2.0));
+        // and the config file overrides the defaults
+        let args = parse(&config, &["game.nes"]);
+        assert_eq!((args.region, args.speed), (Some(region::Region::Pal), 2.0));
+        let args = parse(&Config::default(), &["game.nes"]);
+        assert_eq!((args.region, args.speed), (None, 0.0));
+
+        // What --write-config saves is the settings in effect
+        let saved = parse(&config, &["--region", "ntsc", "--write-config", "game.nes"]).config();
+        assert_eq!(saved, Config { region: Some(region::Region::Ntsc), ..config });
     }
 }
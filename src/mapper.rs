@@ -0,0 +1,1207 @@
+// Cartridge hardware: what the CPU sees at $4020-$5FFF and $8000-$FFFF, what the PPU sees at
+// $0000-$1FFF, and how the nametables are mirrored. Bank switching lives here.
+
+use log_limit::LogLimiter;
+use rom::{self, Mirroring, ROM};
+use state::StateError;
+use vrc6::Vrc6;
+
+use std::sync::{Arc, Mutex, MutexGuard};
+
+// Send so the whole machine can be moved to another thread
+pub trait Mapper: Send {
+    // A CPU read without side effects
+    fn cpu_peek(&self, addr: u16) -> u8;
+    fn cpu_write(&mut self, addr: u16, val: u8);
+
+    fn cpu_read(&mut self, addr: u16) -> u8 {
+        self.cpu_peek(addr)
+    }
+
+    // A pattern table read without side effects
+    fn ppu_peek(&self, addr: u16) -> u8;
+    fn ppu_write(&mut self, addr: u16, val: u8);
+
+    // Some mappers watch the pattern fetches the PPU makes while rendering
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        self.ppu_peek(addr)
+    }
+
+    fn mirroring(&self) -> Mirroring;
+
+    // Where logical nametable 0-3 comes from. Mappers that only pick between the standard
+    // mirrorings keep the default.
+    fn nametable(&self, table: usize) -> NametableSource {
+        NametableSource::Vram(match self.mirroring() {
+            Mirroring::Horizontal => table / 2,
+            Mirroring::Vertical => table % 2,
+            Mirroring::FourScreen => table,
+        })
+    }
+
+    // Reads and writes of the nametables a mapper supplies itself, addr in $2000-$2FFF
+    fn nametable_peek(&self, _addr: u16) -> u8 {
+        0
+    }
+
+    fn nametable_write(&mut self, _addr: u16, _val: u8) {}
+
+    // Told what the PPU is about to fetch, for mappers that bank CHR differently for
+    // backgrounds and sprites or count scanlines
+    fn ppu_fetch_phase(&mut self, _phase: FetchPhase, _tall_sprites: bool) {}
+
+    // Mappers that watch the PPU's address bus, like MMC3 counting A12 rises through an
+    // A12Filter, say so here and then get each address the PPU puts on it, in order: every
+    // nametable, attribute and pattern fetch at the dot the hardware makes it, and $2007
+    // accesses. Asked once when the PPU is built, so the others don't pay for it.
+    fn watches_ppu_addresses(&self) -> bool {
+        false
+    }
+
+    fn on_ppu_address(&mut self, _addr: u16) {}
+
+    // Whether the cartridge is pulling the CPU's IRQ line
+    fn irq(&self) -> bool {
+        false
+    }
+
+    // Whether the cartridge has PRG RAM at $6000-$7FFF at all
+    fn has_prg_ram(&self) -> bool {
+        true
+    }
+
+    // Whether the PRG RAM at $6000-$7FFF responds at all. Disabled RAM reads as open bus and
+    // ignores writes.
+    fn prg_ram_enabled(&self) -> bool {
+        self.has_prg_ram()
+    }
+
+    // Whether writes to PRG RAM stick. Battery-backed carts protect it so a crash can't
+    // scribble over the save.
+    fn prg_ram_writable(&self) -> bool {
+        true
+    }
+
+    // CPU cycles just run, for mappers with timers or drives of their own
+    fn cpu_clock(&mut self, _cycles: u32) {}
+
+    // What the cartridge's own sound channels are putting out, in the APU mixer's units, where
+    // 32767 is its full scale. The APU adds it in after each cpu_clock. It's an integer for the
+    // same reason the APU's mixer is: so audio hashes match on every host.
+    fn audio_output(&self) -> u16 {
+        0
+    }
+
+    // Disk drives only: swap in a disk side, numbered from 0. False if there's no drive or
+    // no such side.
+    fn insert_disk_side(&mut self, _side: usize) -> bool {
+        false
+    }
+
+    // Something the game switched on that this mapper doesn't emulate. The CPU stops with an
+    // error rather than carry on drawing the wrong thing.
+    fn unsupported(&self) -> Option<&'static str> {
+        None
+    }
+
+    // Where in PRG ROM a CPU address currently lands, if anywhere
+    fn prg_offset(&self, addr: u16) -> Option<usize>;
+
+    // How big the PRG bank an address is in is, for telling which bank that is. Mappers that
+    // switch less than all 32 KiB at once override it.
+    fn prg_bank_size(&self, _addr: u16) -> usize {
+        0x8000
+    }
+
+    // Bank registers, latches, CHR RAM: whatever a save state needs to put the cartridge back
+    // the way it was. Mappers with no state of their own keep the defaults.
+    fn save_state(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        if state.is_empty() { Ok(()) } else { Err(StateError::Corrupt("mapper state")) }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NametableSource {
+    // One of the 1 KiB pages of the console's VRAM (four with a four-screen cartridge)
+    Vram(usize),
+    // The cartridge answers through nametable_peek and nametable_write
+    Cartridge,
+}
+
+// What the PPU's pattern fetches are for. A line's background is fetched first, then the
+// sprites for the next one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FetchPhase {
+    Background,
+    Sprites,
+    // Between lines, where pattern accesses are the CPU's through $2007
+    Hblank,
+    // Rendering is off or the frame's picture is done
+    Idle,
+}
+
+// What an A12Filter counts while A12 is low
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum A12Clock {
+    // Addresses the PPU puts on the bus, as on_address sees them
+    Fetches,
+    // Edges of M2, the CPU clock: whole CPU cycles as cpu_clock reports them, not counting
+    // the one A12 fell in. That's only as fine as the machine's steps, which are one cycle at
+    // a time with StepMode::Cycle.
+    M2,
+}
+
+// Picks out the rises of PPU A12 that clock a scanline counter like MMC3's. A12 bounces
+// between the fetches of neighbouring tiles and sprites, so the board only counts a rise after
+// A12 has been low for a while: three M2 edges on MMC3, which is five fetches. Mappers feed it
+// every address from on_ppu_address, and their cpu_clock too if it counts M2 edges.
+#[derive(Debug, Clone)]
+pub struct A12Filter {
+    clock: A12Clock,
+    min_low: u32,
+    high: bool,
+    // Fetches or M2 edges since A12 went low
+    low_for: u32,
+    // A12 fell during the cycle cpu_clock will report next
+    fell: bool,
+}
+
+impl A12Filter {
+    // Starting high, so the first rise waits out the low time like every other
+    pub fn new(clock: A12Clock, min_low: u32) -> A12Filter {
+        A12Filter { clock: clock, min_low: min_low, high: true, low_for: 0, fell: false }
+    }
+
+    // Whether addr makes a rise that counts
+    pub fn on_address(&mut self, addr: u16) -> bool {
+        let high = addr & 0x1000 != 0;
+        let counts = high && !self.high && self.low_for >= self.min_low;
+        if high {
+            self.low_for = 0;
+        } else if self.clock == A12Clock::Fetches {
+            self.low_for = self.low_for.saturating_add(1);
+        } else if self.high {
+            self.fell = true;
+        }
+        self.high = high;
+        counts
+    }
+
+    pub fn cpu_clock(&mut self, cycles: u32) {
+        if self.clock == A12Clock::M2 && !self.high {
+            self.low_for = self.low_for.saturating_add(cycles.saturating_sub(self.fell as u32));
+        }
+        self.fell = false;
+    }
+}
+
+fn mirroring_byte(mirroring: Mirroring) -> u8 {
+    match mirroring {
+        Mirroring::Horizontal => 0,
+        Mirroring::Vertical => 1,
+        Mirroring::FourScreen => 2,
+    }
+}
+
+fn byte_mirroring(byte: u8) -> Option<Mirroring> {
+    match byte {
+        0 => Some(Mirroring::Horizontal),
+        1 => Some(Mirroring::Vertical),
+        2 => Some(Mirroring::FourScreen),
+        _ => None,
+    }
+}
+
+// Mapper state that's some registers followed by CHR RAM, if the board has any
+pub fn save_with_chr_ram(registers: &[u8], chr: &[u8], chr_ram: bool) -> Vec<u8> {
+    let mut state = registers.to_vec();
+    if chr_ram {
+        state.extend_from_slice(chr);
+    }
+    return state
+}
+
+// The registers back out of save_with_chr_ram's state, with CHR RAM restored
+pub fn load_with_chr_ram<'a>(state: &'a [u8], count: usize, chr: &mut [u8], chr_ram: bool) -> Result<&'a [u8], StateError> {
+    let expected = count + if chr_ram { chr.len() } else { 0 };
+    if state.len() != expected {
+        return Err(StateError::Corrupt("mapper state"))
+    }
+    if chr_ram {
+        chr.copy_from_slice(&state[count..]);
+    }
+    Ok(&state[..count])
+}
+
+// The CPU and PPU both talk to the cartridge: Memory owns the PPU and both hold a handle to the
+// one mapper. The handle is a mutex so the machine stays Send, but it's never contended, since
+// the CPU and PPU run on the same thread. It only works without deadlocking because of how
+// it's borrowed:
+// - A borrow never outlives the expression or block that makes it, and nothing else is
+//   called while one is held. In particular, no borrow is held across a PPU or Memory call,
+//   since either may borrow again.
+// - Mapper methods get only their arguments, never the PPU or Memory. A mapper can't reach
+//   back into the bus, so a call into one can't re-borrow.
+// - What a mapper needs from the rest of the machine is pushed in through calls like
+//   cpu_clock and ppu_fetch_phase, and what it has to say is pulled out by polling, like irq.
+#[derive(Clone)]
+pub struct SharedMapper(Arc<Mutex<Box<dyn Mapper>>>);
+
+impl SharedMapper {
+    pub fn borrow(&self) -> MutexGuard<'_, Box<dyn Mapper>> {
+        // A panic while the mapper was borrowed can't have left it half-updated in a way a
+        // later borrow would care about more than the panic itself
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn borrow_mut(&self) -> MutexGuard<'_, Box<dyn Mapper>> {
+        self.borrow()
+    }
+}
+
+pub fn shared(mapper: Box<dyn Mapper>) -> SharedMapper {
+    SharedMapper(Arc::new(Mutex::new(mapper)))
+}
+
+// The mapper a ROM's header asks for. Mappers we don't have yet get NROM, which is wrong for
+// anything bigger than 32 KiB of PRG but gets the title screen up for some games.
+pub fn for_rom(rom: &ROM) -> Box<dyn Mapper> {
+    let number = rom.header.mapper();
+    match number {
+        0 | 5 | 9 | 10 | 11 | 24 | 26 | 66 => info!("Using mapper {} ({})", number, rom::mapper_name(number)),
+        _ => warn!("Mapper {} ({}) isn't supported, running it as NROM", number, rom::mapper_name(number)),
+    }
+    match number {
+        5 => Box::new(Mmc5::new(rom)),
+        9 => Box::new(Mmc2::new(rom)),
+        10 => Box::new(Mmc2::new_mmc4(rom)),
+        11 => Box::new(Gxrom::new_color_dreams(rom)),
+        24 => Box::new(Vrc6::new(rom, false)),
+        26 => Box::new(Vrc6::new(rom, true)),
+        66 => Box::new(Gxrom::new(rom)),
+        _ => Box::new(Nrom::new(rom)),
+    }
+}
+
+// CHR ROM, or 8 KiB of CHR RAM for carts that have none
+pub fn chr_memory(rom: &ROM) -> (Vec<u8>, bool) {
+    if rom.chr.is_empty() { (vec![0; 0x2000], true) } else { (rom.chr.clone(), false) }
+}
+
+// Where byte addr of bank lands in an image of size-byte banks. Bank numbers past the end wrap,
+// like a ROM with its upper address lines unconnected, and an image smaller than one bank
+// repeats through it.
+pub fn bank_offset(len: usize, size: usize, bank: usize, addr: usize) -> Option<usize> {
+    if len == 0 {
+        return None;
+    }
+    let bank_count = (len / size).max(1);
+    Some(((bank % bank_count) * size + (addr & (size - 1))) % len)
+}
+
+// Mapper 0: 16 or 32 KiB of PRG and 8 KiB of CHR, no switching
+pub struct Nrom {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+    chr_ram: bool,
+    prg_ram: bool,
+    mirroring: Mirroring,
+    rom_writes: LogLimiter,
+}
+
+impl Nrom {
+    pub fn new(rom: &ROM) -> Nrom {
+        let (chr, chr_ram) = chr_memory(rom);
+        // The only NROM board with RAM is Family BASIC's, which is battery-backed, so an iNES
+        // header says so with the battery bit. Games running as NROM because their mapper isn't
+        // supported keep RAM, since most boards that size have it.
+        let header = &rom.header;
+        let prg_ram = if header.mapper() != 0 {
+            true
+        } else if header.is_nes2() {
+            header.prg_ram_size() + header.prg_nvram_size() > 0
+        } else {
+            header.has_battery() || header.has_trainer()
+        };
+        Nrom {
+            prg: rom.prg.clone(),
+            chr: chr,
+            chr_ram: chr_ram,
+            prg_ram: prg_ram,
+            mirroring: rom.header.mirroring(),
+            rom_writes: LogLimiter::new(),
+        }
+    }
+}
+
+impl Mapper for Nrom {
+    fn cpu_peek(&self, addr: u16) -> u8 {
+        // Open bus past the end of an undersized PRG
+        self.prg_offset(addr).map_or(0, |offset| self.prg[offset])
+    }
+
+    // Writes to ROM go nowhere, and a game that makes them is probably confused about its
+    // cartridge
+    fn cpu_write(&mut self, addr: u16, val: u8) {
+        if self.rom_writes.allow("nes::mapper", addr as u32) {
+            warn!("Write of ${:02X} to ROM at ${:04X}", val, addr);
+        }
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        self.chr.get(addr as usize).cloned().unwrap_or(0)
+    }
+
+    fn ppu_write(&mut self, addr: u16, val: u8) {
+        if self.chr_ram {
+            self.chr[addr as usize] = val;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn has_prg_ram(&self) -> bool {
+        self.prg_ram
+    }
+
+    // 16 KiB of PRG shows up at both $8000 and $C000, 8 KiB (homebrew) four times over
+    fn prg_offset(&self, addr: u16) -> Option<usize> {
+        if addr < 0x8000 || self.prg.is_empty() {
+            return None;
+        }
+        let mut offset = (addr as usize) - 0x8000;
+        if self.prg.len() < 0x8000 && self.prg.len().is_power_of_two() {
+            offset &= self.prg.len() - 1;
+        }
+        if offset < self.prg.len() { Some(offset) } else { None }
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        save_with_chr_ram(&[], &self.chr, self.chr_ram)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        load_with_chr_ram(state, 0, &mut self.chr, self.chr_ram).map(|_| ())
+    }
+}
+
+// Mappers 9 (MMC2) and 10 (MMC4). Each pattern table has two CHR banks and a latch that picks
+// between them. The latch flips when the PPU fetches tile $FD or $FE, so a game can change
+// banks partway down the screen by drawing one of those tiles.
+pub struct Mmc2 {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+    // MMC4 switches 16 KiB of PRG instead of 8 KiB and watches whole tile rows for both latches
+    mmc4: bool,
+    prg_bank: u8,
+    // [pattern table][latch]: the 4 KiB bank used while the latch holds $FD, then $FE
+    chr_banks: [[u8; 2]; 2],
+    // Per pattern table, false for $FD and true for $FE
+    latches: [bool; 2],
+    mirroring: Mirroring,
+}
+
+impl Mmc2 {
+    pub fn new(rom: &ROM) -> Mmc2 {
+        let (chr, _) = chr_memory(rom);
+        Mmc2 {
+            prg: rom.prg.clone(),
+            chr: chr,
+            mmc4: false,
+            prg_bank: 0,
+            chr_banks: [[0; 2]; 2],
+            latches: [false; 2],
+            // Until the game writes $F000
+            mirroring: rom.header.mirroring(),
+        }
+    }
+
+    pub fn new_mmc4(rom: &ROM) -> Mmc2 {
+        Mmc2 { mmc4: true, ..Mmc2::new(rom) }
+    }
+
+    // The 4 KiB CHR bank a pattern table is using right now
+    pub fn chr_bank(&self, table: usize) -> u8 {
+        self.chr_banks[table][self.latches[table] as usize]
+    }
+
+    // What a fetch of addr does to the latches. MMC2 only watches the first byte of tile $FD
+    // or $FE in the left pattern table; everything else watches the whole tile row.
+    fn latch_for(&self, addr: u16) -> Option<(usize, bool)> {
+        let table = (addr >> 12) as usize & 1;
+        let exact = !self.mmc4 && table == 0;
+        match addr & 0x0FFF {
+            0x0FD8 => Some((table, false)),
+            0x0FE8 => Some((table, true)),
+            0x0FD9..=0x0FDF if !exact => Some((table, false)),
+            0x0FE9..=0x0FEF if !exact => Some((table, true)),
+            _ => None,
+        }
+    }
+}
+
+impl Mapper for Mmc2 {
+    fn cpu_peek(&self, addr: u16) -> u8 {
+        self.prg_offset(addr).map_or(0, |offset| self.prg[offset])
+    }
+
+    fn cpu_write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xA000..=0xAFFF => self.prg_bank = val & 0x0F,
+            0xB000..=0xBFFF => self.chr_banks[0][0] = val & 0x1F,
+            0xC000..=0xCFFF => self.chr_banks[0][1] = val & 0x1F,
+            0xD000..=0xDFFF => self.chr_banks[1][0] = val & 0x1F,
+            0xE000..=0xEFFF => self.chr_banks[1][1] = val & 0x1F,
+            0xF000..=0xFFFF => {
+                self.mirroring = if val & 1 != 0 { Mirroring::Horizontal } else { Mirroring::Vertical };
+            },
+            _ => {},
+        }
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        let bank = self.chr_bank((addr >> 12) as usize & 1) as usize;
+        bank_offset(self.chr.len(), 0x1000, bank, addr as usize).map_or(0, |offset| self.chr[offset])
+    }
+
+    // CHR is always ROM on these boards
+    fn ppu_write(&mut self, _addr: u16, _val: u8) {}
+
+    // The latch flips after the fetch, so the byte that triggers it still comes from the old bank
+    fn ppu_read(&mut self, addr: u16) -> u8 {
+        let val = self.ppu_peek(addr);
+        if let Some((table, latch)) = self.latch_for(addr) {
+            self.latches[table] = latch;
+        }
+        return val
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    // The switchable bank sits at $8000 and the rest of the space is fixed to the last banks
+    fn prg_offset(&self, addr: u16) -> Option<usize> {
+        if addr < 0x8000 {
+            return None;
+        }
+        let size = self.prg_bank_size(addr);
+        let bank_count = (self.prg.len() / size).max(1);
+        let slot = (addr as usize - 0x8000) / size;
+        let bank = if slot == 0 {
+            self.prg_bank as usize
+        } else {
+            (bank_count + slot).saturating_sub(0x8000 / size)
+        };
+        bank_offset(self.prg.len(), size, bank, addr as usize)
+    }
+
+    fn prg_bank_size(&self, _addr: u16) -> usize {
+        if self.mmc4 { 0x4000 } else { 0x2000 }
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        vec![self.prg_bank, self.chr_banks[0][0], self.chr_banks[0][1], self.chr_banks[1][0], self.chr_banks[1][1],
+             self.latches[0] as u8, self.latches[1] as u8, mirroring_byte(self.mirroring)]
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        if state.len() != 8 {
+            return Err(StateError::Corrupt("mapper state"))
+        }
+        self.mirroring = byte_mirroring(state[7]).ok_or(StateError::Corrupt("mapper state"))?;
+        self.prg_bank = state[0];
+        self.chr_banks = [[state[1], state[2]], [state[3], state[4]]];
+        self.latches = [state[5] != 0, state[6] != 0];
+        Ok(())
+    }
+}
+
+// Mapper 66 (GxROM) and mapper 11 (Color Dreams): one register anywhere in $8000-$FFFF picks a
+// 32 KiB PRG bank and an 8 KiB CHR bank
+pub struct Gxrom {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+    chr_ram: bool,
+    // Color Dreams has the PRG bank in the low bits and CHR in the high ones, GxROM the reverse
+    color_dreams: bool,
+    prg_bank: u8,
+    chr_bank: u8,
+    mirroring: Mirroring,
+}
+
+impl Gxrom {
+    pub fn new(rom: &ROM) -> Gxrom {
+        let (chr, chr_ram) = chr_memory(rom);
+        Gxrom {
+            prg: rom.prg.clone(),
+            chr: chr,
+            chr_ram: chr_ram,
+            color_dreams: false,
+            prg_bank: 0,
+            chr_bank: 0,
+            mirroring: rom.header.mirroring(),
+        }
+    }
+
+    pub fn new_color_dreams(rom: &ROM) -> Gxrom {
+        Gxrom { color_dreams: true, ..Gxrom::new(rom) }
+    }
+
+    pub fn prg_bank(&self) -> u8 {
+        self.prg_bank
+    }
+
+    pub fn chr_bank(&self) -> u8 {
+        self.chr_bank
+    }
+}
+
+impl Mapper for Gxrom {
+    fn cpu_peek(&self, addr: u16) -> u8 {
+        self.prg_offset(addr).map_or(0, |offset| self.prg[offset])
+    }
+
+    fn cpu_write(&mut self, addr: u16, val: u8) {
+        if addr < 0x8000 {
+            return;
+        }
+        // Bus conflict: the ROM drives the bus too, and a 0 from either side wins
+        let val = val & self.cpu_peek(addr);
+        if self.color_dreams {
+            self.prg_bank = val & 0x03;
+            self.chr_bank = val >> 4;
+        } else {
+            self.prg_bank = (val >> 4) & 0x03;
+            self.chr_bank = val & 0x03;
+        }
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        bank_offset(self.chr.len(), 0x2000, self.chr_bank as usize, addr as usize).map_or(0, |offset| self.chr[offset])
+    }
+
+    fn ppu_write(&mut self, addr: u16, val: u8) {
+        if self.chr_ram {
+            self.chr[addr as usize & 0x1FFF] = val;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn prg_offset(&self, addr: u16) -> Option<usize> {
+        if addr < 0x8000 {
+            return None;
+        }
+        bank_offset(self.prg.len(), 0x8000, self.prg_bank as usize, addr as usize)
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        save_with_chr_ram(&[self.prg_bank, self.chr_bank], &self.chr, self.chr_ram)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let registers = load_with_chr_ram(state, 2, &mut self.chr, self.chr_ram)?;
+        self.prg_bank = registers[0];
+        self.chr_bank = registers[1];
+        Ok(())
+    }
+}
+
+// Mapper 5 (MMC5), as much of it as Castlevania III uses: the PRG and CHR banking modes,
+// separate background and sprite CHR banks for 8x16 sprites, ExRAM and fill mode as
+// nametables, the scanline IRQ and the multiplier. PRG RAM stays the console's 8 KiB at $6000.
+// Vertical split mode, extended attributes and PRG RAM banked into $8000-$DFFF are reported
+// through unsupported().
+pub struct Mmc5 {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+    chr_ram: bool,
+    // $5100-$5107
+    prg_mode: u8,
+    chr_mode: u8,
+    exram_mode: u8,
+    // Two bits per logical nametable: VRAM page 0 or 1, ExRAM, fill mode
+    nametables: u8,
+    fill_tile: u8,
+    fill_attr: u8,
+    // $5113-$5117, in 8 KiB units with bit 7 set for ROM
+    prg_banks: [u8; 5],
+    // $5120-$5127 are set A, used for sprites; $5128-$512B set B, for backgrounds
+    chr_a: [u8; 8],
+    chr_b: [u8; 4],
+    // $5130: bits 8 and 9 of CHR bank numbers
+    chr_upper: u8,
+    // Outside 8x16 rendering, whichever set was written last is used for everything
+    last_b: bool,
+    phase: FetchPhase,
+    tall_sprites: bool,
+    // $5200
+    split: u8,
+    exram: Vec<u8>,
+    irq_compare: u8,
+    irq_enabled: bool,
+    irq_pending: bool,
+    in_frame: bool,
+    scanline: u8,
+    // $5205 and $5206
+    multiplicand: u8,
+    multiplier: u8,
+    // $5102 and $5103: PRG RAM only takes writes while they hold %10 and %01
+    ram_protect: [u8; 2],
+}
+
+impl Mmc5 {
+    pub fn new(rom: &ROM) -> Mmc5 {
+        let (chr, chr_ram) = chr_memory(rom);
+        Mmc5 {
+            prg: rom.prg.clone(),
+            chr: chr,
+            chr_ram: chr_ram,
+            // Power on with the last 8 KiB bank everywhere, which holds the reset vector
+            prg_mode: 3,
+            chr_mode: 0,
+            exram_mode: 0,
+            nametables: 0,
+            fill_tile: 0,
+            fill_attr: 0,
+            prg_banks: [0xFF; 5],
+            chr_a: [0; 8],
+            chr_b: [0; 4],
+            chr_upper: 0,
+            last_b: false,
+            phase: FetchPhase::Idle,
+            tall_sprites: false,
+            split: 0,
+            exram: vec![0; 0x400],
+            irq_compare: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            in_frame: false,
+            scanline: 0,
+            multiplicand: 0xFF,
+            multiplier: 0xFF,
+            ram_protect: [0, 0],
+        }
+    }
+
+    // The register and size of the PRG bank that covers addr, which is in $8000-$FFFF
+    fn prg_bank(&self, addr: u16) -> (u8, usize) {
+        let slot = (addr as usize - 0x8000) / 0x2000;
+        match self.prg_mode & 3 {
+            0 => (self.prg_banks[4], 0x8000),
+            1 => (self.prg_banks[2 + 2 * (slot / 2)], 0x4000),
+            2 if slot < 2 => (self.prg_banks[2], 0x4000),
+            _ => (self.prg_banks[1 + slot], 0x2000),
+        }
+    }
+
+    // The CHR bank register for addr, from set B when the PPU is fetching 8x16 backgrounds
+    fn chr_bank(&self, addr: u16) -> usize {
+        let use_b = match self.phase {
+            FetchPhase::Background if self.tall_sprites => true,
+            FetchPhase::Sprites if self.tall_sprites => false,
+            _ => self.last_b,
+        };
+        let size = self.chr_bank_size();
+        // With banks bigger than 1 KiB only the last register of each group counts
+        let step = size / 0x400;
+        let bank = if use_b {
+            // Set B only covers 4 KiB, repeated in both pattern tables
+            let addr = if size == 0x2000 { addr } else { addr & 0x0FFF };
+            self.chr_b[((addr as usize / size + 1) * step - 1) & 3]
+        } else {
+            self.chr_a[(addr as usize / size + 1) * step - 1]
+        };
+        ((self.chr_upper as usize & 3) << 8) | bank as usize
+    }
+
+    fn chr_bank_size(&self) -> usize {
+        0x2000 >> (self.chr_mode & 3)
+    }
+
+    // What the CPU sees at $5000-$5FFF
+    fn register(&self, addr: u16) -> u8 {
+        match addr {
+            0x5204 => ((self.irq_pending as u8) << 7) | ((self.in_frame as u8) << 6),
+            0x5205 => (self.multiplicand as u16 * self.multiplier as u16) as u8,
+            0x5206 => ((self.multiplicand as u16 * self.multiplier as u16) >> 8) as u8,
+            0x5C00..=0x5FFF if self.exram_mode >= 2 => self.exram[addr as usize - 0x5C00],
+            _ => 0,
+        }
+    }
+}
+
+impl Mapper for Mmc5 {
+    fn cpu_peek(&self, addr: u16) -> u8 {
+        if addr < 0x8000 {
+            return self.register(addr)
+        }
+        self.prg_offset(addr).map_or(0, |offset| self.prg[offset])
+    }
+
+    // Reading $5204 acknowledges the IRQ
+    fn cpu_read(&mut self, addr: u16) -> u8 {
+        let val = self.cpu_peek(addr);
+        if addr == 0x5204 {
+            self.irq_pending = false;
+        }
+        return val
+    }
+
+    fn cpu_write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x5100 => self.prg_mode = val & 3,
+            0x5101 => self.chr_mode = val & 3,
+            0x5102 | 0x5103 => self.ram_protect[addr as usize - 0x5102] = val & 3,
+            0x5104 => self.exram_mode = val & 3,
+            0x5105 => self.nametables = val,
+            0x5106 => self.fill_tile = val,
+            0x5107 => self.fill_attr = val & 3,
+            0x5113..=0x5117 => self.prg_banks[addr as usize - 0x5113] = val,
+            0x5120..=0x5127 => {
+                self.chr_a[addr as usize - 0x5120] = val;
+                self.last_b = false;
+            },
+            0x5128..=0x512B => {
+                self.chr_b[addr as usize - 0x5128] = val;
+                self.last_b = true;
+            },
+            0x5130 => self.chr_upper = val & 3,
+            0x5200 => self.split = val,
+            0x5203 => self.irq_compare = val,
+            0x5204 => self.irq_enabled = val & 0x80 != 0,
+            0x5205 => self.multiplicand = val,
+            0x5206 => self.multiplier = val,
+            // Read-only in mode 3
+            0x5C00..=0x5FFF if self.exram_mode != 3 => self.exram[addr as usize - 0x5C00] = val,
+            _ => {},
+        }
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        let size = self.chr_bank_size();
+        bank_offset(self.chr.len(), size, self.chr_bank(addr), addr as usize).map_or(0, |offset| self.chr[offset])
+    }
+
+    fn ppu_write(&mut self, addr: u16, val: u8) {
+        if self.chr_ram {
+            let size = self.chr_bank_size();
+            if let Some(offset) = bank_offset(self.chr.len(), size, self.chr_bank(addr), addr as usize) {
+                self.chr[offset] = val;
+            }
+        }
+    }
+
+    // Only for anything that asks; the PPU goes through nametable()
+    fn mirroring(&self) -> Mirroring {
+        match self.nametables {
+            0x44 => Mirroring::Vertical,
+            0x50 => Mirroring::Horizontal,
+            _ => Mirroring::FourScreen,
+        }
+    }
+
+    fn nametable(&self, table: usize) -> NametableSource {
+        match (self.nametables >> (table * 2)) & 3 {
+            page @ 0..=1 => NametableSource::Vram(page as usize),
+            _ => NametableSource::Cartridge,
+        }
+    }
+
+    // Fill mode repeats one tile and one palette over the whole nametable
+    fn nametable_peek(&self, addr: u16) -> u8 {
+        let table = (addr as usize & 0x0FFF) / 0x400;
+        let offset = addr as usize & 0x3FF;
+        match (self.nametables >> (table * 2)) & 3 {
+            2 if self.exram_mode <= 1 => self.exram[offset],
+            3 if offset < 0x3C0 => self.fill_tile,
+            3 => self.fill_attr * 0x55,
+            _ => 0,
+        }
+    }
+
+    fn nametable_write(&mut self, addr: u16, val: u8) {
+        let table = (addr as usize & 0x0FFF) / 0x400;
+        if (self.nametables >> (table * 2)) & 3 == 2 && self.exram_mode <= 1 {
+            self.exram[addr as usize & 0x3FF] = val;
+        }
+    }
+
+    // The scanline counter starts over at the first line of a frame and raises the IRQ when it
+    // reaches $5203's value, which makes 0 never fire
+    fn ppu_fetch_phase(&mut self, phase: FetchPhase, tall_sprites: bool) {
+        match phase {
+            FetchPhase::Background if !self.in_frame => {
+                self.in_frame = true;
+                self.scanline = 0;
+            },
+            FetchPhase::Background => {
+                self.scanline = self.scanline.wrapping_add(1);
+                if self.scanline == self.irq_compare {
+                    self.irq_pending = true;
+                }
+            },
+            FetchPhase::Idle => {
+                self.in_frame = false;
+                self.irq_pending = false;
+            },
+            _ => {},
+        }
+        self.phase = phase;
+        self.tall_sprites = tall_sprites;
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_enabled && self.irq_pending
+    }
+
+    fn prg_ram_writable(&self) -> bool {
+        self.ram_protect == [2, 1]
+    }
+
+    fn unsupported(&self) -> Option<&'static str> {
+        if self.split & 0x80 != 0 {
+            return Some("MMC5 vertical split mode")
+        }
+        if self.exram_mode == 1 {
+            return Some("MMC5 extended attribute mode")
+        }
+        // $5117 is always ROM
+        let ram_bank = match self.prg_mode & 3 {
+            0 => false,
+            1 => self.prg_banks[2] & 0x80 == 0,
+            2 => self.prg_banks[2] & 0x80 == 0 || self.prg_banks[3] & 0x80 == 0,
+            _ => self.prg_banks[1..4].iter().any(|bank| bank & 0x80 == 0),
+        };
+        if ram_bank { Some("MMC5 PRG RAM in $8000-$DFFF") } else { None }
+    }
+
+    fn prg_offset(&self, addr: u16) -> Option<usize> {
+        if addr < 0x8000 {
+            return None;
+        }
+        let (bank, size) = self.prg_bank(addr);
+        let bank = (bank as usize & 0x7F) / (size / 0x2000);
+        bank_offset(self.prg.len(), size, bank, addr as usize)
+    }
+
+    fn prg_bank_size(&self, addr: u16) -> usize {
+        if addr < 0x8000 { 0x2000 } else { self.prg_bank(addr).1 }
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut registers = vec![
+            self.prg_mode, self.chr_mode, self.exram_mode, self.nametables, self.fill_tile, self.fill_attr,
+            self.chr_upper, self.last_b as u8, self.phase as u8, self.tall_sprites as u8, self.split,
+            self.irq_compare, self.irq_enabled as u8, self.irq_pending as u8, self.in_frame as u8,
+            self.scanline, self.multiplicand, self.multiplier, self.ram_protect[0], self.ram_protect[1],
+        ];
+        registers.extend_from_slice(&self.prg_banks);
+        registers.extend_from_slice(&self.chr_a);
+        registers.extend_from_slice(&self.chr_b);
+        registers.extend_from_slice(&self.exram);
+        save_with_chr_ram(&registers, &self.chr, self.chr_ram)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let r = load_with_chr_ram(state, 20 + 5 + 8 + 4 + 0x400, &mut self.chr, self.chr_ram)?;
+        self.phase = match r[8] {
+            0 => FetchPhase::Background,
+            1 => FetchPhase::Sprites,
+            2 => FetchPhase::Hblank,
+            3 => FetchPhase::Idle,
+            _ => return Err(StateError::Corrupt("mapper state")),
+        };
+        self.prg_mode = r[0];
+        self.chr_mode = r[1];
+        self.exram_mode = r[2];
+        self.nametables = r[3];
+        self.fill_tile = r[4];
+        self.fill_attr = r[5];
+        self.chr_upper = r[6];
+        self.last_b = r[7] != 0;
+        self.tall_sprites = r[9] != 0;
+        self.split = r[10];
+        self.irq_compare = r[11];
+        self.irq_enabled = r[12] != 0;
+        self.irq_pending = r[13] != 0;
+        self.in_frame = r[14] != 0;
+        self.scanline = r[15];
+        self.multiplicand = r[16];
+        self.multiplier = r[17];
+        self.ram_protect = [r[18], r[19]];
+        self.prg_banks.copy_from_slice(&r[20..25]);
+        self.chr_a.copy_from_slice(&r[25..33]);
+        self.chr_b.copy_from_slice(&r[33..37]);
+        self.exram.copy_from_slice(&r[37..]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm;
+    use cpu::StepMode;
+    use emulator::Nes;
+    use mem::{Addressable, Memory};
+
+    // 64 KiB of PRG and 32 KiB of CHR, each bank filled with its number
+    fn numbered_rom(prg_bank: usize, chr_bank: usize) -> ROM {
+        let prg = (0..0x10000).map(|i| (i / prg_bank) as u8).collect();
+        let chr = (0..0x8000).map(|i| (i / chr_bank) as u8).collect();
+        ROM::from_parts(prg, chr)
+    }
+
+    // CHR banks $FD: 4 and $FE: 5 on the left, $FD: 6 and $FE: 7 on the right
+    fn mmc2(mmc4: bool) -> Mmc2 {
+        let rom = numbered_rom(if mmc4 { 0x4000 } else { 0x2000 }, 0x1000);
+        let mut mapper = if mmc4 { Mmc2::new_mmc4(&rom) } else { Mmc2::new(&rom) };
+        for (i, register) in [0xB000, 0xC000, 0xD000, 0xE000].iter().enumerate() {
+            mapper.cpu_write(*register, 4 + i as u8);
+        }
+        mapper
+    }
+
+    // An iNES image for mapper with prg and chr
+    fn ines(mapper: u8, prg: &[u8], chr: &[u8]) -> ROM {
+        let mut image = vec![b'N', b'E', b'S', 0x1A, (prg.len() / 0x4000) as u8, (chr.len() / 0x2000) as u8,
+                             mapper << 4, mapper & 0xF0, 0, 0, 0, 0, 0, 0, 0, 0];
+        image.extend_from_slice(prg);
+        image.extend_from_slice(chr);
+        ROM::from_bytes(&image).unwrap()
+    }
+
+    // Two 32 KiB PRG banks of $FF but for their number at $8001, and two 8 KiB CHR banks of
+    // their number
+    fn two_bank_gxrom(mapper: u8) -> Box<dyn Mapper> {
+        let mut prg = vec![0xFF; 0x10000];
+        prg[0x0001] = 0x00;
+        prg[0x8001] = 0x01;
+        let chr = (0..0x4000).map(|i| (i / 0x2000) as u8).collect::<Vec<_>>();
+        for_rom(&ines(mapper, &prg, &chr))
+    }
+
+    #[test]
+    fn gxrom_and_color_dreams_select_and_wrap() {
+        // (value, PRG bank, CHR bank) after wrapping to two banks of each
+        for &(mapper, writes) in [
+            (66, [(0x21, 0, 1), (0xFF, 1, 1), (0x10, 1, 0)]),
+            (11, [(0x21, 1, 0), (0xFF, 1, 1), (0x10, 0, 1)]),
+        ].iter() {
+            let mut gxrom = two_bank_gxrom(mapper);
+            for &(val, prg, chr) in writes.iter() {
+                gxrom.cpu_write(0x8000, val);
+                assert_eq!((gxrom.cpu_peek(0x8001), gxrom.ppu_peek(0x1FFF)), (prg, chr), "mapper {} ${:02X}", mapper, val);
+                assert_eq!(gxrom.prg_offset(0xFFFF), Some(prg as usize * 0x8000 + 0x7FFF));
+            }
+        }
+    }
+
+    #[test]
+    fn gxrom_writes_have_bus_conflicts() {
+        let mut gxrom = two_bank_gxrom(66);
+        // $8001 in bank 0 holds $00, so the write selects bank 0 whatever it says
+        gxrom.cpu_write(0x8001, 0x11);
+        assert_eq!((gxrom.cpu_peek(0x8001), gxrom.ppu_peek(0x0000)), (0, 0));
+        gxrom.cpu_write(0x8000, 0x11);
+        assert_eq!((gxrom.cpu_peek(0x8001), gxrom.ppu_peek(0x0000)), (1, 1));
+        // In bank 1 it holds $01, which only lets CHR bank 1 through
+        gxrom.cpu_write(0x8001, 0x11);
+        assert_eq!((gxrom.cpu_peek(0x8001), gxrom.ppu_peek(0x0000)), (0, 1));
+        // Below $8000 isn't the register
+        gxrom.cpu_write(0x6000, 0x00);
+        assert_eq!(gxrom.ppu_peek(0x0000), 1);
+    }
+
+    #[test]
+    fn small_gxrom_prg_is_mirrored() {
+        let mut prg = vec![0; 0x4000];
+        prg[0x0000] = 0xAB;
+        let mut gxrom = Gxrom::new(&ROM::from_parts(prg, vec![0; 0x2000]));
+        gxrom.cpu_write(0x8001, 0x30);
+        assert_eq!((gxrom.cpu_peek(0x8000), gxrom.cpu_peek(0xC000)), (0xAB, 0xAB));
+        assert_eq!((gxrom.prg_bank(), gxrom.chr_bank()), (0, 0));
+    }
+
+    #[test]
+    fn mmc2_latches_flip_after_the_magic_fetch() {
+        let mut mapper = mmc2(false);
+        assert_eq!((mapper.ppu_read(0x0000), mapper.ppu_read(0x1000)), (4, 6));
+        // The byte that flips the latch still comes from the old bank
+        assert_eq!(mapper.ppu_read(0x0FE8), 4);
+        assert_eq!((mapper.ppu_read(0x0000), mapper.chr_bank(0)), (5, 5));
+        // Only the first byte of the tile does it in the left table
+        assert_eq!(mapper.ppu_read(0x0FD9), 5);
+        assert_eq!(mapper.ppu_read(0x0000), 5);
+        mapper.ppu_read(0x0FD8);
+        assert_eq!(mapper.ppu_read(0x0000), 4);
+        // but any of the row in the right table
+        assert_eq!(mapper.ppu_read(0x1FEB), 6);
+        assert_eq!((mapper.ppu_read(0x1000), mapper.chr_bank(1), mapper.chr_bank(0)), (7, 7, 4));
+        // Peeks never flip a latch
+        assert_eq!(mapper.ppu_peek(0x1FD8), 7);
+        assert_eq!(mapper.ppu_read(0x1000), 7);
+    }
+
+    #[test]
+    fn mmc4_watches_the_whole_row_on_both_sides() {
+        let mut mapper = mmc2(true);
+        mapper.ppu_read(0x0FEC);
+        assert_eq!(mapper.ppu_read(0x0000), 5);
+        mapper.ppu_read(0x0FDF);
+        assert_eq!(mapper.ppu_read(0x0000), 4);
+        // Neither side watches other tiles
+        mapper.ppu_read(0x0FE0);
+        mapper.ppu_read(0x0FF8);
+        assert_eq!(mapper.chr_bank(0), 4);
+    }
+
+    #[test]
+    fn prg_banks_and_mirroring() {
+        // MMC2 switches 8 KiB at $8000 and fixes the last three banks after it
+        let mut mapper = mmc2(false);
+        mapper.cpu_write(0xA000, 0x13);
+        let banks = [0x8000, 0xA000, 0xC000, 0xE000].iter().map(|&addr| mapper.cpu_peek(addr)).collect::<Vec<_>>();
+        assert_eq!(banks, [3, 5, 6, 7]);
+        // MMC4 switches 16 KiB and fixes the last bank
+        let mut mapper = mmc2(true);
+        mapper.cpu_write(0xA000, 2);
+        assert_eq!((mapper.cpu_peek(0x8000), mapper.cpu_peek(0xBFFF), mapper.cpu_peek(0xC000)), (2, 2, 3));
+
+        mapper.cpu_write(0xF000, 1);
+        assert_eq!(mapper.mirroring(), Mirroring::Horizontal);
+        mapper.cpu_write(0xF000, 0);
+        assert_eq!(mapper.mirroring(), Mirroring::Vertical);
+        // CHR is ROM
+        mapper.ppu_write(0x0000, 0xAA);
+        assert_eq!(mapper.ppu_peek(0x0000), 4);
+    }
+
+    // 128 KiB of PRG in 8 KiB banks filled with their number, except the last, which holds
+    // program assembled at $E000 and its vectors
+    fn mmc5_rom(program: &str) -> Vec<u8> {
+        let (code, labels) = asm::assemble_with_labels(program, 0xE000).unwrap();
+        let mut prg = (0..0x20000).map(|i| (i / 0x2000) as u8).collect::<Vec<_>>();
+        prg[0x1E000..0x1E000 + code.len()].copy_from_slice(&code);
+        for (i, name) in ["nmi", "reset", "irq"].iter().enumerate() {
+            let vector = labels.get(*name).cloned().unwrap_or(0xE000);
+            prg[0x1FFFA + i * 2..0x1FFFC + i * 2].copy_from_slice(&vector.to_le_bytes());
+        }
+        let mut image = vec![b'N', b'E', b'S', 0x1A, 8, 1, 0x50, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        image.extend_from_slice(&prg);
+        image.extend_from_slice(&[0; 0x2000]);
+        image
+    }
+
+    #[test]
+    fn mmc5_multiplies() {
+        let mut mmc5 = Mmc5::new(&ROM::from_bytes(&mmc5_rom("")).unwrap());
+        // Both start at $FF
+        assert_eq!((mmc5.cpu_peek(0x5205), mmc5.cpu_peek(0x5206)), (0x01, 0xFE));
+        for &(a, b, product) in [(7u8, 9u8, 63u16), (200, 200, 40000), (0, 255, 0)].iter() {
+            mmc5.cpu_write(0x5205, a);
+            mmc5.cpu_write(0x5206, b);
+            assert_eq!((mmc5.cpu_read(0x5205), mmc5.cpu_read(0x5206)), (product as u8, (product >> 8) as u8));
+        }
+    }
+
+    #[test]
+    fn mmc5_prg_ram_is_write_protected() {
+        let mut memory = Memory::from_rom(ROM::from_bytes(&mmc5_rom("")).unwrap());
+        memory.storeb(0x6000, 0x11);
+        assert_eq!(memory.loadb(0x6000), 0x00);
+        // Writes stick only while $5102 holds %10 and $5103 %01
+        memory.storeb(0x5102, 0x02);
+        memory.storeb(0x6000, 0x22);
+        assert_eq!(memory.loadb(0x6000), 0x00);
+        memory.storeb(0x5103, 0x01);
+        memory.storeb(0x6000, 0x33);
+        assert_eq!(memory.loadb(0x6000), 0x33);
+        memory.storeb(0x5103, 0x00);
+        memory.storeb(0x6000, 0x44);
+        assert_eq!(memory.loadb(0x6000), 0x33);
+    }
+
+    #[test]
+    fn mmc5_prg_modes() {
+        let mut mmc5 = Mmc5::new(&ROM::from_bytes(&mmc5_rom("")).unwrap());
+        // $5114-$5117 pick banks 1, 2|4, 6 and 9, which wraps to 1 of the 16 8 KiB banks, and
+        // bigger banks ignore their low bits
+        for (i, &bank) in [0x81, 0x86, 0x8A, 0x89].iter().enumerate() {
+            mmc5.cpu_write(0x5114 + i as u16, bank);
+        }
+        let banks = |mmc5: &Mmc5| [0x8000, 0xA000, 0xC000, 0xE000].iter().map(|&addr| mmc5.cpu_peek(addr)).collect::<Vec<_>>();
+        assert_eq!(banks(&mmc5), [1, 6, 10, 9]);
+        mmc5.cpu_write(0x5100, 2);
+        assert_eq!(banks(&mmc5), [6, 7, 10, 9]);
+        mmc5.cpu_write(0x5100, 1);
+        assert_eq!(banks(&mmc5), [6, 7, 8, 9]);
+        mmc5.cpu_write(0x5100, 0);
+        assert_eq!(banks(&mmc5), [8, 9, 10, 11]);
+    }
+
+    #[test]
+    fn mmc5_irq_fires_on_the_programmed_line() {
+        // The APU's frame IRQ is turned off, so every IRQ is the mapper's
+        let rom = mmc5_rom("
+reset:  LDA #$40
+        STA $4017
+        LDA #$08
+        STA $2001
+        LDA #100
+        STA $5203
+        LDA #$80
+        STA $5204
+        CLI
+loop:   JMP loop
+irq:    LDA $5204
+        RTI
+");
+        // Where irq assembled to
+        let irq = 0xE018;
+        for &mode in [StepMode::Instruction, StepMode::Cycle].iter() {
+            let mut nes = Nes::builder().rom_bytes(&rom).ppu_warmup(false).step_mode(mode).build().unwrap();
+            let mut entered = Vec::new();
+            while entered.len() < 3 {
+                assert!(nes.step().unwrap().is_continue());
+                if nes.cpu().pc() == irq {
+                    entered.push(nes.cpu().memory().ppu.scanline());
+                }
+            }
+            assert_eq!(entered, [100, 100, 100], "{:?}", mode);
+        }
+
+        let mut mmc5 = Mmc5::new(&ROM::from_bytes(&rom).unwrap());
+        mmc5.cpu_write(0x5200, 0x80);
+        assert_eq!(mmc5.unsupported(), Some("MMC5 vertical split mode"));
+    }
+
+    #[test]
+    fn a12_filter_waits_out_the_low_time() {
+        let mut fetches = A12Filter::new(A12Clock::Fetches, 2);
+        let seen: Vec<bool> = [0x0000, 0x1000, 0x0000, 0x1000, 0x2000, 0x0000, 0x1000, 0x1008]
+            .iter().map(|&addr| fetches.on_address(addr)).collect();
+        assert_eq!(seen, [false, false, false, false, false, false, true, false]);
+
+        // The cycle A12 falls in doesn't count, and fetches don't either
+        let mut m2 = A12Filter::new(A12Clock::M2, 2);
+        m2.on_address(0x0000);
+        m2.cpu_clock(1);
+        m2.on_address(0x2000);
+        m2.cpu_clock(1);
+        assert!(!m2.on_address(0x1000));
+        m2.on_address(0x0000);
+        m2.cpu_clock(3);
+        assert!(m2.on_address(0x1000));
+    }
+}
@@ -0,0 +1,200 @@
+// Writers for recording what the emulator puts out, so it can be lined up against another
+// emulator's frame by frame: video as YUV4MPEG2 (.y4m), which most players and ffmpeg read,
+// and audio as 16-bit PCM WAV.
+//
+// Both are flushed as they go, so a recording cut short by a crash is still readable up to the
+// last frame or samples written. Y4M has no length in its header to go stale; the WAV's sizes
+// are patched after every write.
+
+use postprocess::RgbFrame;
+
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+
+const WAV_HEADER_SIZE: u32 = 44;
+const BITS_PER_SAMPLE: u16 = 16;
+
+// Uncompressed 4:4:4 video. Colors are converted to BT.601 studio-range YCbCr, which is what
+// players assume a Y4M without a colorspace tag holds.
+pub struct Y4mWriter<W: Write> {
+    out: W,
+    width: usize,
+    height: usize,
+    frames: u64,
+}
+
+impl<W: Write> Y4mWriter<W> {
+    // Write the stream header. The frame rate is a fraction, frames per second as
+    // numerator / denominator.
+    pub fn new(mut out: W, width: usize, height: usize, rate: (u32, u32)) -> io::Result<Y4mWriter<W>> {
+        writeln!(out, "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C444", width, height, rate.0, rate.1)?;
+        out.flush()?;
+        Ok(Y4mWriter { out: out, width: width, height: height, frames: 0 })
+    }
+
+    // Every frame has to be the size given to new
+    pub fn write_frame(&mut self, frame: &RgbFrame) -> io::Result<()> {
+        if frame.width != self.width || frame.height != self.height {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                "{}x{} frame in a {}x{} video", frame.width, frame.height, self.width, self.height)))
+        }
+        let mut planes = vec![0u8; frame.pixels.len() * 3];
+        let (y, rest) = planes.split_at_mut(frame.pixels.len());
+        let (cb, cr) = rest.split_at_mut(frame.pixels.len());
+        for (i, &rgb) in frame.pixels.iter().enumerate() {
+            let [luma, blue, red] = to_ycbcr(rgb);
+            y[i] = luma;
+            cb[i] = blue;
+            cr[i] = red;
+        }
+        self.out.write_all(b"FRAME\n")?;
+        self.out.write_all(&planes)?;
+        self.out.flush()?;
+        self.frames += 1;
+        Ok(())
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+fn to_ycbcr(rgb: [u8; 3]) -> [u8; 3] {
+    let [r, g, b] = rgb.map(|c| c as f32);
+    let y = 16.0 + 0.257 * r + 0.504 * g + 0.098 * b;
+    let cb = 128.0 - 0.148 * r - 0.291 * g + 0.439 * b;
+    let cr = 128.0 + 0.439 * r - 0.368 * g - 0.071 * b;
+    [y, cb, cr].map(|c| c.round().clamp(0.0, 255.0) as u8)
+}
+
+// 16-bit signed PCM. Samples come in as floats from -1.0 to 1.0, which are clipped to that
+// range, or as i16s, interleaved either way if there's more than one channel.
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    channels: u16,
+    // Bytes of sample data written so far
+    data_size: u32,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut out: W, sample_rate: u32, channels: u16) -> io::Result<WavWriter<W>> {
+        let block_align = channels * BITS_PER_SAMPLE / 8;
+        out.write_all(b"RIFF")?;
+        out.write_all(&(WAV_HEADER_SIZE - 8).to_le_bytes())?;
+        out.write_all(b"WAVEfmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        // PCM
+        out.write_all(&1u16.to_le_bytes())?;
+        out.write_all(&channels.to_le_bytes())?;
+        out.write_all(&sample_rate.to_le_bytes())?;
+        out.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        out.write_all(&block_align.to_le_bytes())?;
+        out.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+        out.write_all(b"data")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        out.flush()?;
+        Ok(WavWriter { out: out, channels: channels, data_size: 0 })
+    }
+
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        let pcm: Vec<i16> = samples.iter().map(|&sample| (sample.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16).collect();
+        self.write_pcm(&pcm)
+    }
+
+    // Samples that are already 16-bit, like the APU's, written as they are
+    pub fn write_pcm(&mut self, samples: &[i16]) -> io::Result<()> {
+        let mut data = Vec::with_capacity(samples.len() * 2);
+        for &sample in samples {
+            data.extend_from_slice(&sample.to_le_bytes());
+        }
+        self.out.write_all(&data)?;
+        self.data_size += data.len() as u32;
+        self.update_sizes()
+    }
+
+    // Sample frames written so far, one sample per channel each
+    pub fn frames(&self) -> u64 {
+        self.data_size as u64 / (self.channels as u64 * 2)
+    }
+
+    // Point the RIFF and data chunk sizes at everything written so far
+    fn update_sizes(&mut self) -> io::Result<()> {
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out.write_all(&(WAV_HEADER_SIZE - 8 + self.data_size).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(WAV_HEADER_SIZE as u64 - 4))?;
+        self.out.write_all(&self.data_size.to_le_bytes())?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.update_sizes()?;
+        Ok(self.out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    fn u16_at(bytes: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes([bytes[at], bytes[at + 1]])
+    }
+
+    fn u32_at(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+    }
+
+    #[test]
+    fn y4m_has_a_header_and_whole_frames() {
+        let mut writer = Y4mWriter::new(Vec::new(), 4, 2, (60099, 1000)).unwrap();
+        for &rgb in [[0, 0, 0], [255, 255, 255], [255, 0, 0]].iter() {
+            writer.write_frame(&RgbFrame { width: 4, height: 2, pixels: vec![rgb; 8] }).unwrap();
+        }
+        let wrong_size = RgbFrame { width: 2, height: 2, pixels: vec![[0; 3]; 4] };
+        assert_eq!(writer.write_frame(&wrong_size).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(writer.frames(), 3);
+        let video = writer.finish().unwrap();
+
+        let header = b"YUV4MPEG2 W4 H2 F60099:1000 Ip A1:1 C444\n";
+        assert_eq!(&video[..header.len()], header);
+        let frames: Vec<&[u8]> = video[header.len()..].chunks(6 + 3 * 8).collect();
+        assert_eq!(frames.len(), 3);
+        for frame in frames.iter() {
+            assert_eq!(frame.len(), 6 + 3 * 8);
+            assert_eq!(&frame[..6], b"FRAME\n");
+        }
+        // Studio range: black and white are 16 and 235 luma with neutral chroma, red is
+        // mostly Cr. Each plane is a whole frame of one component.
+        assert_eq!(frames[0][6..], [[16; 8], [128; 8], [128; 8]].concat()[..]);
+        assert_eq!(frames[1][6..], [[235; 8], [128; 8], [128; 8]].concat()[..]);
+        assert_eq!(frames[2][6..], [[82; 8], [90; 8], [240; 8]].concat()[..]);
+    }
+
+    #[test]
+    fn wav_header_counts_the_samples_written_so_far() {
+        let mut writer = WavWriter::new(Cursor::new(Vec::new()), 44100, 2).unwrap();
+        writer.write_samples(&[0.0, 1.0, -1.0, 2.0]).unwrap();
+        writer.write_pcm(&[0x1234, -2]).unwrap();
+        assert_eq!(writer.frames(), 3);
+        // Before finish, as a crash would leave it
+        let wav = writer.out.get_ref().clone();
+        assert_eq!(wav, writer.finish().unwrap().into_inner());
+
+        assert_eq!((&wav[..4], &wav[8..16], &wav[36..40]), (&b"RIFF"[..], &b"WAVEfmt "[..], &b"data"[..]));
+        assert_eq!(u32_at(&wav, 4) as usize, wav.len() - 8);
+        assert_eq!((u32_at(&wav, 16), u16_at(&wav, 20), u16_at(&wav, 22)), (16, 1, 2));
+        assert_eq!((u32_at(&wav, 24), u32_at(&wav, 28), u16_at(&wav, 32), u16_at(&wav, 34)), (44100, 44100 * 4, 4, 16));
+        assert_eq!(u32_at(&wav, 40), 12);
+        let samples: Vec<i16> = wav[44..].chunks(2).map(|pair| i16::from_le_bytes([pair[0], pair[1]])).collect();
+        assert_eq!(samples, [0, 32767, -32767, 32767, 0x1234, -2]);
+    }
+}
@@ -1,92 +1,1321 @@
+use apu;
+use cheats;
+use controller;
+use debug_device::DebugDevice;
+use dmc;
+use mapper;
+use ppu::{self, AccuracyLevel};
+use region::Region;
 use rom;
+use state::DirtyPages;
+use zapper;
 
+use std;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+// Reads take &mut self: on the real bus a read can have side effects (PPU registers, mappers).
 pub trait Addressable {
-    fn loadb(&self, addr: u16) -> u8;
+    fn loadb(&mut self, addr: u16) -> u8;
     fn storeb(&mut self, addr: u16, val: u8);
 
-    fn loadw(&self, addr: u16) -> u16 {
-        self.loadb(addr) as u16 | (self.loadb(addr + 1) as u16) << 8
+    fn loadw(&mut self, addr: u16) -> u16 {
+        self.loadb(addr) as u16 | (self.loadb(addr.wrapping_add(1)) as u16) << 8
     }
 
     fn storew(&mut self, addr: u16, val: u16) {
         self.storeb(addr, (val & 0xFF) as u8);
-        self.storeb(addr + 1, ((val >> 8) & 0xFF) as u8);
+        self.storeb(addr.wrapping_add(1), ((val >> 8) & 0xFF) as u8);
+    }
+}
+// What RAM holds at power-on. Real consoles come up with semi-random contents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RamInit {
+    #[default]
+    Zero,
+    Ff,
+    // Four bytes of 0x00 then four of 0xFF, repeating
+    Pattern00Ff,
+    Random(u64),
+}
+
+impl RamInit {
+    pub fn fill(&self, buf: &mut [u8]) {
+        match *self {
+            RamInit::Zero => for b in buf.iter_mut() { *b = 0; },
+            RamInit::Ff => for b in buf.iter_mut() { *b = 0xFF; },
+            RamInit::Pattern00Ff => for (i, b) in buf.iter_mut().enumerate() {
+                *b = if i & 4 == 0 { 0x00 } else { 0xFF };
+            },
+            RamInit::Random(seed) => {
+                let mut rng = Rng::new(seed);
+                for b in buf.iter_mut() { *b = rng.next() as u8; }
+            },
+        }
+    }
+}
+
+// zero, ff, pattern or random:SEED
+impl FromStr for RamInit {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<RamInit, &'static str> {
+        match s {
+            "zero" => Ok(RamInit::Zero),
+            "ff" => Ok(RamInit::Ff),
+            "pattern" => Ok(RamInit::Pattern00Ff),
+            _ => s.strip_prefix("random:").and_then(|seed| seed.parse().ok()).map(RamInit::Random)
+                .ok_or("RAM init must be zero, ff, pattern or random:SEED"),
+        }
+    }
+}
+
+impl fmt::Display for RamInit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RamInit::Zero => write!(f, "zero"),
+            RamInit::Ff => write!(f, "ff"),
+            RamInit::Pattern00Ff => write!(f, "pattern"),
+            RamInit::Random(seed) => write!(f, "random:{}", seed),
+        }
+    }
+}
+
+// xorshift64*, so seeded RAM contents are the same on every platform
+struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        // A zero state would stay zero forever
+        Rng { state: seed ^ 0x9E3779B97F4A7C15 }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        (self.state.wrapping_mul(0x2545F4914F6CDD1D)) >> 32
     }
 }
+
 pub struct RAM {
     pub data: [u8; 0x800],
 }
 
 impl RAM {
     pub fn new() -> RAM { RAM {data: [0; 0x800]} }
-    pub fn loadw(&self, addr: u16) -> u16 {
-        self.loadb(addr) as u16 | (self.loadb(addr + 1) as u16) << 8
+    pub fn init(&mut self, init: RamInit) { init.fill(&mut self.data); }
+    pub fn loadw(&mut self, addr: u16) -> u16 {
+        self.loadb(addr) as u16 | (self.loadb(addr.wrapping_add(1)) as u16) << 8
     }
     pub fn storew(&mut self, addr: u16, val: u16) {
         self.storeb(addr, (val & 0xFF) as u8);
-        self.storeb(addr + 1, ((val >> 8) & 0xFF) as u8);
+        self.storeb(addr.wrapping_add(1), ((val >> 8) & 0xFF) as u8);
     }
 }
 
+impl Default for RAM {
+    fn default() -> RAM { RAM::new() }
+}
+
 impl Addressable for RAM {
-    fn loadb(&self, addr: u16) -> u8 { self.data[addr as usize] }
+    fn loadb(&mut self, addr: u16) -> u8 { self.data[addr as usize] }
     fn storeb(&mut self, addr: u16, val: u8) { self.data[addr as usize] = val; }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+// A single operation seen on the CPU bus
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BusAccess {
+    pub addr: u16,
+    pub kind: AccessKind,
+    // The byte read or written
+    pub value: u8,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MemoryError {
+    // The cartridge's ROM can't be written to
+    RomWrite { addr: u16 },
+    // The data would run past $FFFF
+    OutOfRange { start: u16, len: usize },
+}
+
+impl fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MemoryError::RomWrite { addr } => write!(f, "${:04X} is cartridge ROM and can't be written", addr),
+            MemoryError::OutOfRange { start, len } =>
+                write!(f, "{} bytes at ${:04X} run past the end of the address space", len, start),
+        }
+    }
+}
+
+// Where an address goes on the CPU bus. Reads, writes, peeks and describe all route through
+// this, so what describe says is what the bus does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+    // Offset into the 2 KiB of internal RAM, mirrored four times
+    Ram(u16),
+    // PPU register 0-7, mirrored every 8 bytes
+    PpuRegister(u16),
+    // $4016 and $4017
+    ControllerPort(usize),
+    // $4010-$4013, by register
+    Dmc(u16),
+    // $4015
+    ApuStatus,
+    // The rest of $4000-$401F
+    Apu,
+    // $4020-$5FFF, which only some cartridges use
+    Expansion,
+    // Offset into $6000-$7FFF
+    PrgRam(u16),
+    PrgRom,
+}
+
+pub fn route(addr: u16) -> Route {
+    match addr {
+        0..=0x1FFF => Route::Ram(addr & 0x7ff),
+        0x2000 ..= 0x3FFF => Route::PpuRegister(addr & 7),
+        0x4016 | 0x4017 => Route::ControllerPort((addr - 0x4016) as usize),
+        0x4010 ..= 0x4013 => Route::Dmc(addr - 0x4010),
+        0x4015 => Route::ApuStatus,
+        0x4000 ..= 0x401F => Route::Apu,
+        0x4020 ..= 0x5FFF => Route::Expansion,
+        0x6000 ..= 0x7FFF => Route::PrgRam(addr - 0x6000),
+        _ => Route::PrgRom,
+    }
+}
+
+// Names, and whether they can be read and written
+const PPU_REGISTERS: [(&str, bool, bool); 8] = [
+    ("PPUCTRL", false, true), ("PPUMASK", false, true), ("PPUSTATUS", true, false), ("OAMADDR", false, true),
+    ("OAMDATA", true, true), ("PPUSCROLL", false, true), ("PPUADDR", false, true), ("PPUDATA", true, true),
+];
+const DMC_REGISTERS: [&str; 4] = ["DMC_FREQ", "DMC_RAW", "DMC_START", "DMC_LEN"];
+const APU_REGISTERS: [&str; 16] = [
+    "SQ1_VOL", "SQ1_SWEEP", "SQ1_LO", "SQ1_HI", "SQ2_VOL", "SQ2_SWEEP", "SQ2_LO", "SQ2_HI",
+    "TRI_LINEAR", "unused", "TRI_LO", "TRI_HI", "NOISE_VOL", "unused", "NOISE_LO", "NOISE_HI",
+];
+
+// What Memory::describe says about an address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressInfo {
+    pub addr: u16,
+    pub route: Route,
+    // The part of the memory map, e.g. "RAM" or "PRG ROM"
+    pub region: &'static str,
+    // What it reaches, e.g. "RAM $0123 via mirror", "PRG bank 3 offset $1234" or "PPUSTATUS"
+    pub target: String,
+    pub readable: bool,
+    pub writable: bool,
+}
+
+fn access(readable: bool, writable: bool) -> &'static str {
+    match (readable, writable) {
+        (true, true) => "read/write",
+        (true, false) => "read only",
+        (false, true) => "write only",
+        (false, false) => "unused",
+    }
+}
+
+impl fmt::Display for AddressInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "${:04X}  {:<13} {:<36} {}", self.addr, self.region, self.target, access(self.readable, self.writable))
+    }
+}
+
+// A run of addresses that all go to the same place, described by where the first one goes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapRange {
+    pub start: u16,
+    pub end: u16,
+    pub region: &'static str,
+    pub target: String,
+    pub readable: bool,
+    pub writable: bool,
+}
+
+impl MapRange {
+    fn new(addr: u16, info: &AddressInfo, target: &str, readable: bool, writable: bool) -> MapRange {
+        MapRange { start: addr, end: addr, region: info.region, target: target.to_string(), readable: readable, writable: writable }
+    }
+
+    fn same_kind(&self, other: &MapRange) -> bool {
+        self.region == other.region && self.readable == other.readable && self.writable == other.writable
+    }
+}
+
+impl fmt::Display for MapRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "${:04X}-${:04X}  {:<13} {:<36} {}", self.start, self.end, self.region, self.target,
+               access(self.readable, self.writable))
+    }
+}
+
+// Sees every access on the CPU bus, after the read or write has happened
+pub trait BusObserver: Send {
+    fn on_read(&mut self, addr: u16, val: u8);
+    fn on_write(&mut self, addr: u16, val: u8);
+}
+
+// So the caller can keep a handle on an observer after handing it to the bus
+impl<T: BusObserver> BusObserver for Arc<Mutex<T>> {
+    fn on_read(&mut self, addr: u16, val: u8) { self.lock().unwrap().on_read(addr, val); }
+    fn on_write(&mut self, addr: u16, val: u8) { self.lock().unwrap().on_write(addr, val); }
+}
+
+// The devices that can pull the CPU's IRQ line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqSource {
+    // The cartridge: MMC5's scanline counter, the FDS timer and drive
+    Mapper,
+    Dmc,
+    // The APU frame counter, acknowledged by reading $4015 or inhibiting it through $4017
+    FrameCounter,
+}
+
+impl IrqSource {
+    fn bit(self) -> u8 {
+        match self {
+            IrqSource::Mapper => 1 << 0,
+            IrqSource::Dmc => 1 << 1,
+            IrqSource::FrameCounter => 1 << 2,
+        }
+    }
+}
+
+// The CPU's IRQ input. Sources pull it independently and it's asserted while any of them is,
+// so a handler that only acknowledges one of two is entered again straight after RTI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct IrqLine {
+    sources: u8,
+}
+
+impl IrqLine {
+    pub fn assert(&mut self, source: IrqSource) {
+        self.sources |= source.bit();
+    }
+
+    pub fn ack(&mut self, source: IrqSource) {
+        self.sources &= !source.bit();
+    }
+
+    pub fn set(&mut self, source: IrqSource, asserted: bool) {
+        if asserted { self.assert(source) } else { self.ack(source) }
+    }
+
+    pub fn is_asserted(&self) -> bool {
+        self.sources != 0
+    }
+
+    pub fn is_asserted_by(&self, source: IrqSource) -> bool {
+        self.sources & source.bit() != 0
+    }
+}
+
+// What is plugged into the second controller port
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Port2Device {
+    #[default]
+    Controller,
+    Zapper,
+}
+
 pub struct Memory {
     pub ram: RAM,
-    // ppu: PPU,
-    // apu: APU,
+    pub ppu: ppu::PPU,
+    // The parts of the APU the rest of the machine can tell are there
+    pub apu: apu::Apu,
+    pub dmc: dmc::Dmc,
+    // The CPU's IRQ input, refreshed from the devices whenever one of them might have pulled or
+    // released it
+    pub irq: IrqLine,
+    // Cycles the DMC's sample fetches have stolen from the CPU, for it to catch up on
+    dma_stall: u32,
+    // A fetch was wanted while the CPU was writing, so it starts a cycle early on the next read
+    dma_after_write: bool,
+    // Whether a fetch landing on a controller read clocks the controller twice, as it does on
+    // the hardware. Only cycle stepping puts reads on the cycles where fetches can land on them.
+    pub accuracy: AccuracyLevel,
+    pub controllers: [controller::Controller; 2],
+    port2: Port2Device,
+    // Plugged into both ports instead of the two controllers when present
+    pub four_score: Option<controller::FourScore>,
+    pub zapper: zapper::Zapper,
+    // The test programs' output device at $4018-$4019, when turned on
+    pub debug_device: Option<DebugDevice>,
+    // Cartridge SRAM at $6000-$7FFF, as much as the header says and empty for boards without.
+    // It lives here rather than in the mapper because the bus, trainers, save states and
+    // dirty-page tracking all work on it directly; the mapper only says whether it responds.
+    pub prg_ram: Vec<u8>,
+    // RAM and PRG RAM pages written through the bus since take_dirty_pages. Writes straight to
+    // the pub arrays above aren't seen.
+    dirty: DirtyPages,
+    // The last value driven onto the data bus, which is what a read of nothing returns
+    open_bus: u8,
     pub rom: rom::ROM,
+    // Shared with the PPU, see SharedMapper for how it may be borrowed
+    pub mapper: mapper::SharedMapper,
+    cheats: cheats::Cheats,
+    pub ram_init: RamInit,
+    access_log: Option<Vec<BusAccess>>,
+    observer: Option<Box<dyn BusObserver>>,
+}
+
+// PRG RAM as big as the header says, work RAM and battery-backed RAM together. Boards with
+// none get none, and a header that gives no size gets the 8 KiB window's worth.
+fn prg_ram_for(rom: &rom::ROM, mapper: &dyn mapper::Mapper) -> Vec<u8> {
+    if !mapper.has_prg_ram() {
+        return Vec::new()
+    }
+    match rom.header.prg_ram_size() + rom.header.prg_nvram_size() {
+        0 => vec![0; 0x2000],
+        size => vec![0; size],
+    }
 }
 
 impl Memory {
     pub fn from_rom(rom: rom::ROM) -> Memory {
-        Memory {
+        let mapper = mapper::for_rom(&rom);
+        Memory::with_mapper(rom, mapper)
+    }
+
+    // For cartridges the header doesn't describe, like NSF rips
+    pub fn with_mapper(rom: rom::ROM, mapper: Box<dyn mapper::Mapper>) -> Memory {
+        let prg_ram = prg_ram_for(&rom, &*mapper);
+        let mapper = mapper::shared(mapper);
+        let timing = rom.header.region().unwrap_or(Region::Ntsc).timing();
+        let mut memory = Memory {
             ram: RAM::new(),
-            // ppu
-            // apu
+            ppu: ppu::PPU::new(mapper.clone(), timing),
+            apu: apu::Apu::new(timing),
+            dmc: dmc::Dmc::new(timing),
+            irq: IrqLine::default(),
+            dma_stall: 0,
+            dma_after_write: false,
+            accuracy: AccuracyLevel::default(),
+            controllers: [controller::Controller::new(), controller::Controller::new()],
+            port2: Port2Device::default(),
+            four_score: None,
+            zapper: zapper::Zapper::new(),
+            debug_device: None,
+            prg_ram: prg_ram,
+            dirty: DirtyPages::all(),
+            open_bus: 0,
             rom: rom,
+            mapper: mapper,
+            cheats: cheats::Cheats::new(),
+            ram_init: RamInit::default(),
+            access_log: None,
+            observer: None,
+        };
+        memory.load_trainer();
+        memory
+    }
+
+    pub fn power_on(&mut self) {
+        self.ram.init(self.ram_init);
+        self.mark_all_dirty();
+        self.ppu.power_on(self.ram_init);
+        self.apu.power_on();
+        self.dmc.set_enabled(false);
+        self.controllers = [controller::Controller::new(), controller::Controller::new()];
+        if self.four_score.is_some() {
+            self.four_score = Some(controller::FourScore::new());
+        }
+        self.load_trainer();
+        self.update_irq();
+    }
+
+    // Plug in another cartridge. PRG RAM is kept when both boards have the same amount, which
+    // is what a rebuilt ROM usually looks like, and cleared otherwise. Returns whether it was
+    // kept. Nothing is reset: power on afterwards.
+    pub fn swap_cartridge(&mut self, rom: rom::ROM, mapper: Box<dyn mapper::Mapper>) -> bool {
+        let prg_ram = prg_ram_for(&rom, &*mapper);
+        let keep = !prg_ram.is_empty() && prg_ram.len() == self.prg_ram.len();
+        self.mapper = mapper::shared(mapper);
+        self.ppu.set_mapper(self.mapper.clone());
+        self.rom = rom;
+        if !keep {
+            self.prg_ram = prg_ram;
+        }
+        self.mark_all_dirty();
+        keep
+    }
+
+    // RAM, PRG RAM and the cartridge keep their contents across a reset
+    pub fn reset(&mut self) {
+        self.ppu.reset();
+        self.apu.reset();
+        self.dmc.set_enabled(false);
+        self.update_irq();
+    }
+
+    // Run the rest of the machine for cycles CPU cycles
+    pub fn clock(&mut self, cycles: u32) {
+        let frame = self.ppu.frame;
+        self.ppu.step_cpu_cycles(cycles);
+        let (mapper_irq, expansion_audio) = {
+            let mut mapper = self.mapper.borrow_mut();
+            mapper.cpu_clock(cycles);
+            (mapper.irq(), mapper.audio_output())
+        };
+        self.apu.set_expansion_audio(expansion_audio);
+        self.apu.clock(cycles, &mut self.dmc);
+        if self.ppu.frame != frame {
+            self.apu.end_frame();
+            self.apply_freezes();
+        }
+        self.irq.set(IrqSource::Mapper, mapper_irq);
+        self.irq.set(IrqSource::Dmc, self.dmc.irq());
+        self.irq.set(IrqSource::FrameCounter, self.apu.irq());
+    }
+
+    // Each device keeps its own flag and its own way of acknowledging it: the DMC's is cleared
+    // by writing $4010 or $4015, the frame counter's by reading $4015 or writing $4017, MMC5's
+    // by reading $5204, the FDS's by reading $4030 or writing $4022/$4023. Mirror them onto the
+    // line after anything that could have changed one.
+    pub fn update_irq(&mut self) {
+        let mapper = self.mapper.borrow().irq();
+        self.irq.set(IrqSource::Mapper, mapper);
+        self.irq.set(IrqSource::Dmc, self.dmc.irq());
+        self.irq.set(IrqSource::FrameCounter, self.apu.irq());
+    }
+
+    // Cycles the DMC has stolen since the last call. The machine has already been clocked
+    // through them; the CPU only needs to count them.
+    pub fn take_dma_stall(&mut self) -> u32 {
+        std::mem::replace(&mut self.dma_stall, 0)
+    }
+
+    pub fn set_port2_device(&mut self, device: Port2Device) {
+        self.port2 = device;
+    }
+
+    // Swap the two controllers for a Four Score, or back
+    pub fn set_four_score(&mut self, enabled: bool) {
+        self.four_score = if enabled { Some(controller::FourScore::new()) } else { None };
+    }
+
+    pub fn set_debug_device(&mut self, enabled: bool) {
+        self.debug_device = if enabled { Some(DebugDevice::new()) } else { None };
+    }
+
+    pub fn port2_device(&self) -> Port2Device {
+        self.port2
+    }
+
+    // What each plugged-in controller last latched, controller 1 first: all four behind a Four
+    // Score, otherwise 1 and, unless something else is in port 2, 2
+    pub fn latched_buttons(&self) -> Vec<u8> {
+        if let Some(ref four_score) = self.four_score {
+            return four_score.controllers.iter().map(|controller| controller.latched()).collect()
+        }
+        let mut buttons = vec![self.controllers[0].latched()];
+        if self.port2 == Port2Device::Controller {
+            buttons.push(self.controllers[1].latched());
+        }
+        return buttons
+    }
+
+    // Where the Zapper points, in screen pixels
+    pub fn set_zapper_position(&mut self, x: usize, y: usize) {
+        self.zapper.set_position(x, y);
+    }
+
+    pub fn set_zapper_trigger(&mut self, pulled: bool) {
+        self.zapper.set_trigger(pulled);
+    }
+
+    // Trainers expect to be sitting at $7000-$71FF when the game starts
+    fn load_trainer(&mut self) {
+        if let Some(ref trainer) = self.rom.trainer {
+            for (offset, &byte) in (0x1000..).zip(trainer.iter()) {
+                if let Some(index) = self.prg_ram_index(offset) {
+                    self.prg_ram[index] = byte;
+                }
+            }
+        }
+    }
+
+    pub fn add_cheat(&mut self, cheat: cheats::Cheat) {
+        self.cheats.add(cheat);
+    }
+
+    // Returns whether the cheat was active
+    pub fn remove_cheat(&mut self, cheat: &cheats::Cheat) -> bool {
+        self.cheats.remove(cheat)
+    }
+
+    pub fn list_cheats(&self) -> &[cheats::Cheat] {
+        self.cheats.list()
+    }
+
+    // Rewrite every frozen RAM address, once per frame
+    pub fn apply_freezes(&mut self) {
+        for (addr, value) in self.cheats.freezes() {
+            self.ram.storeb(addr & 0x7ff, value);
+            self.dirty.ram |= 1 << ((addr & 0x7ff) >> 8);
+        }
+    }
+
+    // Start recording every bus access, dropping anything recorded so far
+    pub fn enable_access_log(&mut self) {
+        self.access_log = Some(Vec::new());
+    }
+
+    pub fn disable_access_log(&mut self) {
+        self.access_log = None;
+    }
+
+    // Return the accesses recorded since the last call, in bus order
+    pub fn take_access_log(&mut self) -> Vec<BusAccess> {
+        match self.access_log {
+            Some(ref mut log) => std::mem::take(log),
+            None => Vec::new(),
+        }
+    }
+
+    // What a read of addr would return, without any of a read's side effects: registers with
+    // read side effects (PPU, APU, controllers) read as 0 and no observer or log sees it
+    pub fn peek(&self, addr: u16) -> u8 {
+        match route(addr) {
+            Route::Ram(offset) => self.ram.data[offset as usize],
+            Route::PpuRegister(_) | Route::ControllerPort(_) | Route::Dmc(_) | Route::ApuStatus | Route::Apu => 0,
+            Route::Expansion => self.mapper.borrow().cpu_peek(addr),
+            Route::PrgRam(_) => self.prg_ram_read(addr),
+            Route::PrgRom => self.cheats.patch_read(addr, self.mapper.borrow().cpu_peek(addr)),
+        }
+    }
+
+    // What addr is wired to right now: RAM through its mirrors, which register, which PRG
+    // bank. Worked out from the same routing reads and writes use.
+    pub fn describe(&self, addr: u16) -> AddressInfo {
+        let route = route(addr);
+        let mirror = |base: u16| if addr >= base { " via mirror" } else { "" };
+        let (region, target, readable, writable) = match route {
+            Route::Ram(offset) => ("RAM", format!("RAM ${:04X}{}", offset, mirror(0x800)), true, true),
+            Route::PpuRegister(reg) => {
+                let (name, readable, writable) = PPU_REGISTERS[reg as usize];
+                ("PPU registers", format!("{}{}", name, mirror(0x2008)), readable, writable)
+            },
+            Route::ControllerPort(0) => ("APU and I/O", "JOY1, controller strobe on writes".to_string(), true, true),
+            Route::ControllerPort(_) => ("APU and I/O", "JOY2, APU frame counter on writes".to_string(), true, true),
+            Route::Dmc(reg) => ("APU and I/O", DMC_REGISTERS[reg as usize].to_string(), false, true),
+            Route::ApuStatus => ("APU and I/O", "SND_CHN".to_string(), true, true),
+            Route::Apu if addr <= 0x400F => ("APU and I/O", APU_REGISTERS[addr as usize & 0x0F].to_string(), false, true),
+            Route::Apu => match DebugDevice::register_name(addr) {
+                Some(name) if self.debug_device.is_some() => ("APU and I/O", name.to_string(), false, true),
+                _ => ("APU and I/O", "not emulated".to_string(), false, false),
+            },
+            Route::Expansion => ("Expansion", "cartridge".to_string(), true, true),
+            Route::PrgRam(offset) => {
+                let mapper = self.mapper.borrow();
+                let enabled = mapper.prg_ram_enabled();
+                ("PRG RAM", format!("PRG RAM ${:04X}", offset), enabled, enabled && mapper.prg_ram_writable())
+            },
+            Route::PrgRom => {
+                let mapper = self.mapper.borrow();
+                let target = match mapper.prg_offset(addr) {
+                    Some(offset) => {
+                        let size = mapper.prg_bank_size(addr);
+                        format!("PRG bank {} offset ${:04X}", offset / size, offset % size)
+                    },
+                    None => "nothing".to_string(),
+                };
+                // Writes go to the mapper, which may take them as bank switches
+                ("PRG ROM", target, true, true)
+            },
+        };
+        AddressInfo { addr: addr, route: route, region: region, target: target, readable: readable, writable: writable }
+    }
+
+    // The whole address space as runs of addresses that go to the same place, in order. The
+    // mirrors of RAM and the PPU registers are one run each.
+    pub fn layout(&self) -> Vec<MapRange> {
+        let mut ranges: Vec<MapRange> = Vec::new();
+        let mut last: Option<AddressInfo> = None;
+        for addr in 0..=0xFFFF {
+            let info = self.describe(addr);
+            let range = match info.route {
+                Route::Ram(_) if addr >= 0x800 => MapRange::new(addr, &info, "RAM $0000-$07FF via mirror", true, true),
+                Route::PpuRegister(_) if addr >= 0x2008 => MapRange::new(addr, &info, "$2000-$2007 via mirror", true, true),
+                _ => MapRange::new(addr, &info, &info.target, info.readable, info.writable),
+            };
+            let extends = match (last.as_ref(), ranges.last()) {
+                (Some(prev), Some(current)) => current.same_kind(&range) && self.continues(prev, &info),
+                _ => false,
+            };
+            if extends {
+                ranges.last_mut().unwrap().end = addr;
+            } else {
+                ranges.push(range);
+            }
+            last = Some(info);
+        }
+        return ranges
+    }
+
+    // Whether next, one address on from prev, is more of the same thing
+    fn continues(&self, prev: &AddressInfo, next: &AddressInfo) -> bool {
+        match (prev.route, next.route) {
+            (Route::Ram(a), Route::Ram(b)) => b == a + 1 || prev.addr >= 0x800,
+            // The first eight are each their own register, the rest are all mirrors
+            (Route::PpuRegister(_), Route::PpuRegister(_)) => prev.addr >= 0x2008,
+            (Route::Apu, Route::Apu) | (Route::Expansion, Route::Expansion) | (Route::PrgRam(_), Route::PrgRam(_)) => true,
+            (Route::PrgRom, Route::PrgRom) => {
+                let mapper = self.mapper.borrow();
+                let size = mapper.prg_bank_size(next.addr);
+                match (mapper.prg_offset(prev.addr), mapper.prg_offset(next.addr)) {
+                    (Some(a), Some(b)) => b == a + 1 && b % size != 0,
+                    (None, None) => true,
+                    _ => false,
+                }
+            },
+            _ => false,
+        }
+    }
+
+    // Where in PRG ROM a CPU address currently lands, if anywhere
+    pub fn prg_offset(&self, addr: u16) -> Option<usize> {
+        self.mapper.borrow().prg_offset(addr)
+    }
+
+    // The PRG bank mapped at addr right now, numbered the way describe shows it
+    pub fn prg_bank(&self, addr: u16) -> Option<usize> {
+        let mapper = self.mapper.borrow();
+        mapper.prg_offset(addr).map(|offset| offset / mapper.prg_bank_size(addr))
+    }
+
+    pub fn prg_bank_size(&self, addr: u16) -> usize {
+        self.mapper.borrow().prg_bank_size(addr)
+    }
+
+    // len bytes from start as the CPU would see them, through the mirrors, without side effects
+    pub fn dump_range(&self, start: u16, len: usize) -> Vec<u8> {
+        (0..len).map(|i| self.peek(start.wrapping_add(i as u16))).collect()
+    }
+
+    // Write data at start through the bus. Nothing is written if any of it would land in ROM.
+    pub fn load_range(&mut self, start: u16, data: &[u8]) -> Result<(), MemoryError> {
+        if start as usize + data.len() > 0x10000 {
+            return Err(MemoryError::OutOfRange { start: start, len: data.len() })
+        }
+        if start as usize + data.len() > 0x8000 {
+            return Err(MemoryError::RomWrite { addr: start.max(0x8000) })
+        }
+        for (i, &val) in data.iter().enumerate() {
+            self.storeb(start + i as u16, val);
+        }
+        Ok(())
+    }
+
+    // The 2 KiB of internal RAM, without the mirrors
+    pub fn ram_snapshot(&self) -> [u8; 0x800] {
+        self.ram.data
+    }
+
+    pub fn set_observer(&mut self, observer: Box<dyn BusObserver>) {
+        self.observer = Some(observer);
+    }
+
+    pub fn take_observer(&mut self) -> Option<Box<dyn BusObserver>> {
+        self.observer.take()
+    }
+
+    // The cartridge's PRG RAM, if it has any, for battery saves
+    pub fn cartridge_ram(&self) -> Option<&[u8]> {
+        if self.prg_ram.is_empty() { None } else { Some(&self.prg_ram) }
+    }
+
+    pub fn cartridge_ram_mut(&mut self) -> Option<&mut [u8]> {
+        self.dirty.prg_ram = !0;
+        if self.prg_ram.is_empty() { None } else { Some(&mut self.prg_ram) }
+    }
+
+    // The pages of RAM, PRG RAM, VRAM and the pattern tables written since the last call, for
+    // CPU::save_delta and rewinding. Writes made through the pattern tables by the mapper
+    // itself aren't counted: its state is compared whole instead.
+    pub fn take_dirty_pages(&mut self) -> DirtyPages {
+        let (vram, chr) = self.ppu.take_dirty_pages();
+        DirtyPages { vram: vram, chr: chr, ..std::mem::take(&mut self.dirty) }
+    }
+
+    // Everything has changed, e.g. a state was loaded
+    pub fn mark_all_dirty(&mut self) {
+        self.dirty = DirtyPages::all();
+    }
+
+    fn log_access(&mut self, addr: u16, kind: AccessKind, value: u8) {
+        if let Some(ref mut log) = self.access_log {
+            log.push(BusAccess { addr: addr, kind: kind, value: value });
         }
     }
 }
 
 impl Addressable for Memory {
-    fn loadb(&self, addr: u16) -> u8 {
-        match addr {
-            // First 0x2000 bytes are 0x800 bytes of RAM mirrored 4 times
-            0...0x1FFF => self.ram.loadb(addr & 0x7ff),
-            // Next 0x2000 are 8 bytes mirrored a ton
-            0x2000 ... 0x3FFF => 0u8,
-            // Next 0x20 are APU
-            0x4000 ... 0x401F => 0u8,
-            // 0x4020 - 0x6000 are Expansion ROM
-            0x4020 ... 0x5FFF => 0u8,
-            // 0x6000 - 0x8000 are Cartridge SRAM
-            0x6000 ... 0x7FFF => 0u8,
-            _ => self.rom.loadb(addr)
+    fn loadb(&mut self, addr: u16) -> u8 {
+        if self.dmc.needs_fetch() {
+            self.dmc_dma(addr);
         }
+        self.bus_read(addr)
     }
 
     fn storeb(&mut self, addr: u16, val: u8) {
-        match addr {
-            // First 0x2000 bytes are 0x800 bytes of RAM mirrored 4 times
-            0...0x1FFF => self.ram.storeb(addr & 0x7ff, val),
-            // Next 0x2000 are 8 bytes mirrored a ton
-            0x2000 ... 0x3FFF => {},
-            // Next 0x20 are APU
-            0x4000 ... 0x401F => {},
-            // 0x4020 - 0x6000 are Expansion ROM
-            0x4020 ... 0x5FFF => {},
-            // 0x6000 - 0x8000 are Cartridge SRAM
-            0x6000 ... 0x7FFF => {},
-            // The rest is mapped to the cartridge
-            // If the size_prg is 1, then it's mirrored twice
-            // * 0x8000 to 0xC000
-            // * 0xC000 to 0x10000
-            // If the size_prg is more than 2, then the cartridge must have a mapper
-            // TODO: Will the ROM panic if the writes are to ROM?
-            _ => {},
+        // The DMC can only halt the CPU on a read, so a fetch wanted now waits for the next one
+        if self.dmc.needs_fetch() {
+            self.dma_after_write = true;
+        }
+        self.log_access(addr, AccessKind::Write, val);
+        self.write(addr, val);
+        self.open_bus = val;
+        if let Some(ref mut observer) = self.observer {
+            observer.on_write(addr, val);
+        }
+    }
+}
+
+impl Memory {
+    fn bus_read(&mut self, addr: u16) -> u8 {
+        let val = self.read(addr);
+        self.log_access(addr, AccessKind::Read, val);
+        self.open_bus = val;
+        if let Some(ref mut observer) = self.observer {
+            observer.on_read(addr, val);
+        }
+        return val
+    }
+
+    // Fetch a DMC sample byte, halting the CPU on the read at addr. The halt and alignment
+    // cycles take 4 cycles in all, 3 when the fetch was wanted during a write and the halt is
+    // already under way. While halted the CPU keeps putting its read on the bus, which is
+    // harmless except for registers where a read has side effects: a controller port shifts
+    // out an extra bit, losing it.
+    fn dmc_dma(&mut self, addr: u16) {
+        let stall = if std::mem::replace(&mut self.dma_after_write, false) { 3 } else { 4 };
+        if self.accuracy == AccuracyLevel::Accurate && (addr == 0x4016 || addr == 0x4017) {
+            self.bus_read(addr);
         }
+        self.clock(stall - 1);
+        let sample = self.dmc.fetch_address();
+        let val = self.bus_read(sample);
+        self.dmc.fill(val);
+        self.clock(1);
+        self.dma_stall += stall;
+    }
+
+    // Where an offset into $6000-$7FFF lands in PRG RAM. Less than 8 KiB repeats through the
+    // window, and only the first 8 KiB of more can be reached.
+    fn prg_ram_index(&self, offset: u16) -> Option<usize> {
+        if self.prg_ram.is_empty() { None } else { Some(offset as usize % self.prg_ram.len()) }
+    }
+
+    // Disabled or missing PRG RAM doesn't drive the bus, so reads see whatever was last on it
+    fn prg_ram_read(&self, addr: u16) -> u8 {
+        match self.prg_ram_index(addr - 0x6000) {
+            Some(index) if self.mapper.borrow().prg_ram_enabled() => self.prg_ram[index],
+            _ => self.open_bus,
+        }
+    }
+
+    fn read(&mut self, addr: u16) -> u8 {
+        match route(addr) {
+            Route::Ram(offset) => self.ram.loadb(offset),
+            Route::PpuRegister(_) => self.ppu.read_register(addr),
+            // Controller ports, the upper bits are open bus
+            Route::ControllerPort(port) if self.four_score.is_some() =>
+                0x40 | self.four_score.as_mut().unwrap().read(port),
+            Route::ControllerPort(0) => 0x40 | self.controllers[0].read(),
+            Route::ControllerPort(_) => match self.port2 {
+                Port2Device::Controller => 0x40 | self.controllers[1].read(),
+                Port2Device::Zapper => 0x40 | self.zapper.read(&self.ppu),
+            },
+            Route::ApuStatus => {
+                let status = self.apu.read_status() | self.dmc.status();
+                self.update_irq();
+                status
+            },
+            Route::Dmc(_) | Route::Apu => 0u8,
+            Route::Expansion => {
+                let val = self.mapper.borrow_mut().cpu_read(addr);
+                self.update_irq();
+                val
+            },
+            Route::PrgRam(_) => self.prg_ram_read(addr),
+            Route::PrgRom => {
+                let val = self.mapper.borrow_mut().cpu_read(addr);
+                self.cheats.patch_read(addr, val)
+            }
+        }
+    }
+
+    fn write(&mut self, addr: u16, val: u8) {
+        match route(addr) {
+            Route::Ram(offset) => {
+                self.ram.storeb(offset, val);
+                self.dirty.ram |= 1 << (offset >> 8);
+            },
+            Route::PpuRegister(_) => self.ppu.write_register(addr, val),
+            // One strobe line feeds both controllers
+            Route::ControllerPort(0) => {
+                self.controllers[0].write(val);
+                self.controllers[1].write(val);
+                if let Some(ref mut four_score) = self.four_score {
+                    four_score.write(val);
+                }
+            },
+            Route::Dmc(_) => {
+                self.apu.sync();
+                self.dmc.write(addr, val);
+                self.update_irq();
+            },
+            Route::ApuStatus => {
+                self.apu.set_enabled(val);
+                self.dmc.set_enabled(val & dmc::STATUS_ACTIVE != 0);
+                self.update_irq();
+            },
+            // $4017 writes go to the APU's frame counter
+            Route::ControllerPort(_) => {
+                self.apu.write_frame_counter(val);
+                self.update_irq();
+            },
+            Route::Apu if addr <= 0x400F => self.apu.write(addr, val),
+            Route::Apu => {
+                if let Some(ref mut device) = self.debug_device {
+                    device.write(addr, val);
+                }
+            },
+            // Cartridge SRAM, which the mapper may have write-protected
+            Route::PrgRam(offset) => {
+                self.dirty.prg_ram |= 1 << (offset >> 8);
+                let index = self.prg_ram_index(offset);
+                let mapper = self.mapper.borrow();
+                if let Some(index) = index.filter(|_| mapper.prg_ram_enabled() && mapper.prg_ram_writable()) {
+                    self.prg_ram[index] = val;
+                }
+            },
+            // The cartridge decides what a write does: switch banks, or nothing at all
+            Route::Expansion | Route::PrgRom => {
+                self.mapper.borrow_mut().cpu_write(addr, val);
+                self.update_irq();
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm;
+    use cpu::{CPU, StepMode};
+    use controller::InputFrame;
+    use emulator::Nes;
+    use rom::Mirroring;
+    use testing;
+
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    // Turns rendering on over a nametable of MMC2's latch tiles, then writes every bank
+    // register the mapper has on every instruction of the main loop, while the PPU fetches
+    // through the same mapper between them. $10-$11 count the trips round the loop.
+    const BANK_WRITES: &str = "
+reset:  LDA #$20
+        STA $2006
+        LDA #$00
+        STA $2006
+        LDX #$00
+fill:   LDA #$FD
+        STA $2007
+        LDA #$FE
+        STA $2007
+        INX
+        CPX #$00
+        BNE fill
+        LDA #$1E
+        STA $2001
+loop:   INX
+        STX $REG0
+        STX $REG1
+        STX $REG2
+        STX $REG3
+        STX $REG4
+        STX $REG5
+        INC $10
+        LDA $10
+        CMP #$00
+        BNE loop
+        INC $11
+        JMP loop
+";
+
+    // 128 KiB each of PRG and CHR for mapper, with the program in the last 8 KiB of PRG
+    fn bank_write_rom(mapper: u8, registers: [u16; 6]) -> Vec<u8> {
+        let mut source = BANK_WRITES.to_string();
+        for (i, reg) in registers.iter().enumerate() {
+            source = source.replace(&format!("$REG{}", i), &format!("${:04X}", reg));
+        }
+        let program = asm::assemble(&source, 0xE000).unwrap();
+        let mut image = vec![b'N', b'E', b'S', 0x1A, 8, 16, (mapper & 0x0F) << 4, mapper & 0xF0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let mut prg = vec![0; 0x20000];
+        let last = prg.len() - 0x2000;
+        prg[last..last + program.len()].copy_from_slice(&program);
+        // NMI and IRQ never fire; reset at $E000
+        let vectors = prg.len() - 6;
+        prg[vectors..].copy_from_slice(&[0x00, 0xE0, 0x00, 0xE0, 0x00, 0xE0]);
+        image.extend_from_slice(&prg);
+        image.extend((0..0x20000).map(|i| (i * 7 + i / 0x1000) as u8));
+        return image
+    }
+
+    fn stress(mapper: u8, registers: [u16; 6]) {
+        for &mode in [StepMode::Instruction, StepMode::Cycle].iter() {
+            let rom = bank_write_rom(mapper, registers);
+            let mut nes = Nes::builder().rom_bytes(&rom).ppu_warmup(false).step_mode(mode).build().unwrap();
+            for _ in 0..10 {
+                nes.run_frame(InputFrame::default()).unwrap();
+            }
+            let memory = nes.cpu().memory();
+            assert_eq!(memory.ppu.frame, 10);
+            let trips = memory.peek(0x10) as u16 | (memory.peek(0x11) as u16) << 8;
+            assert!(trips > 5000, "{:?}: only {} trips round the loop", mode, trips);
+        }
+    }
+
+    // Writes $42 to $6000 and reads back $6000 and $6800 into $10 and $11
+    const PRG_RAM_PROGRAM: [u8; 16] = [
+        0xA9, 0x42, 0x8D, 0x00, 0x60, 0xAD, 0x00, 0x60, 0x85, 0x10, 0xAD, 0x00, 0x68, 0x85, 0x11, 0x00,
+    ];
+
+    // An NROM image running PRG_RAM_PROGRAM, with flags 6, 7 and 10 of the header given
+    fn prg_ram_rom(flags_6: u8, flags_7: u8, flags_10: u8) -> rom::ROM {
+        let mut image = vec![b'N', b'E', b'S', 0x1A, 1, 1, flags_6, flags_7, 0, 0, flags_10, 0, 0, 0, 0, 0];
+        let mut prg = vec![0xEA; 0x4000];
+        prg[..PRG_RAM_PROGRAM.len()].copy_from_slice(&PRG_RAM_PROGRAM);
+        prg[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0xC0]);
+        image.extend_from_slice(&prg);
+        image.extend_from_slice(&[0; 0x2000]);
+        rom::ROM::from_bytes(&image).unwrap()
+    }
+
+    // $10 and $11 after the program runs, and the cartridge's RAM
+    fn run_prg_ram_program(rom: rom::ROM) -> (u8, u8, Option<Vec<u8>>) {
+        let mut cpu = CPU::from_rom(rom);
+        cpu.power_on();
+        for _ in 0..6 {
+            cpu.emulate_cycle().unwrap();
+        }
+        let memory = cpu.memory();
+        (memory.peek(0x10), memory.peek(0x11), memory.cartridge_ram().map(|ram| ram.to_vec()))
+    }
+
+    #[test]
+    fn ram_init_modes_fill_as_documented() {
+        let filled = |init: RamInit| {
+            let mut buf = [0x55; 16];
+            init.fill(&mut buf);
+            buf
+        };
+        assert_eq!(filled(RamInit::Zero), [0x00; 16]);
+        assert_eq!(filled(RamInit::Ff), [0xFF; 16]);
+        assert_eq!(filled(RamInit::Pattern00Ff), [0, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF]);
+        // The same on every platform, and different for every seed, including 0
+        assert_eq!(&filled(RamInit::Random(42))[..8], &[0x7F, 0x79, 0x8F, 0xF4, 0xC1, 0xB7, 0x83, 0xD1]);
+        assert_eq!(filled(RamInit::Random(42)), filled(RamInit::Random(42)));
+        assert_ne!(filled(RamInit::Random(42)), filled(RamInit::Random(43)));
+        assert_ne!(filled(RamInit::Random(0)), [0; 16]);
+    }
+
+    #[test]
+    fn ram_init_names_round_trip() {
+        for &init in [RamInit::Zero, RamInit::Ff, RamInit::Pattern00Ff, RamInit::Random(1234)].iter() {
+            assert_eq!(init.to_string().parse::<RamInit>(), Ok(init));
+        }
+        assert_eq!("pattern".parse::<RamInit>(), Ok(RamInit::Pattern00Ff));
+        for bad in ["", "random", "random:", "random:x", "0xFF"] {
+            assert!(bad.parse::<RamInit>().is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn ram_init_reaches_the_ppu() {
+        let mut cpu = testing::build_program(&[]);
+        cpu.memory_mut().ram_init = RamInit::Random(7);
+        cpu.power_on();
+        let first = (cpu.memory().ram.data.to_vec(), cpu.memory().ppu.oam.to_vec());
+        assert!(first.0.iter().any(|&b| b != first.0[0]));
+
+        cpu.memory_mut().ram_init = RamInit::Ff;
+        cpu.power_on();
+        let memory = cpu.memory();
+        assert!(memory.ram.data.iter().all(|&b| b == 0xFF));
+        assert!(memory.ppu.oam.iter().all(|&b| b == 0xFF));
+        assert!((0x2000..0x2800).all(|addr| memory.ppu.vram_loadb(addr) == 0xFF));
+        // Palette RAM is only 6 bits wide
+        assert_eq!(memory.ppu.palette_ram(), [0x3F; 32]);
+
+        cpu.memory_mut().ram_init = RamInit::Random(7);
+        cpu.power_on();
+        assert_eq!((cpu.memory().ram.data.to_vec(), cpu.memory().ppu.oam.to_vec()), first);
+    }
+
+    #[test]
+    fn ranges_round_trip_through_ram_mirrors() {
+        let mut cpu = testing::build_program(&[]);
+        let memory = cpu.memory_mut();
+        let data: Vec<u8> = (0..0x20).collect();
+        // $17F0 is $07F0 in the fourth mirror, and the range wraps round to $1800 = $0000
+        memory.load_range(0x17F0, &data).unwrap();
+        assert_eq!(memory.dump_range(0x07F0, 0x10), &data[..0x10]);
+        assert_eq!(memory.dump_range(0x0000, 0x10), &data[0x10..]);
+        assert_eq!(memory.dump_range(0x0FF0, 0x20), data);
+        let ram = memory.ram_snapshot();
+        assert_eq!((ram[0x07F0], ram[0x07FF], ram[0x0000], ram[0x000F]), (0x00, 0x0F, 0x10, 0x1F));
+    }
+
+    #[test]
+    fn load_range_refuses_rom() {
+        let mut cpu = testing::build_program(&[0xEA; 4]);
+        let memory = cpu.memory_mut();
+        let before = memory.dump_range(0x7FFE, 8);
+        let err = memory.load_range(0x7FFE, &[0xAA; 4]).unwrap_err();
+        assert_eq!(err, MemoryError::RomWrite { addr: 0x8000 });
+        assert_eq!(err.to_string(), "$8000 is cartridge ROM and can't be written");
+        // Not even the bytes below ROM were written
+        assert_eq!(memory.dump_range(0x7FFE, 8), before);
+
+        let err = memory.load_range(0xFFFF, &[0; 2]).unwrap_err();
+        assert_eq!(err, MemoryError::OutOfRange { start: 0xFFFF, len: 2 });
+        assert_eq!(err.to_string(), "2 bytes at $FFFF run past the end of the address space");
+        assert_eq!(memory.load_range(0x8000, &[]), Ok(()));
+    }
+
+    #[test]
+    fn battery_header_gives_nrom_8k_of_ram() {
+        let (first, mirror, ram) = run_prg_ram_program(prg_ram_rom(0x02, 0, 0));
+        let ram = ram.unwrap();
+        assert_eq!(ram.len(), 0x2000);
+        assert_eq!((first, ram[0]), (0x42, 0x42));
+        // $6800 is a different byte of 8 KiB
+        assert_eq!(mirror, 0x00);
+    }
+
+    #[test]
+    fn nes2_header_sizes_prg_ram() {
+        // 2 KiB of work RAM repeats every $800, and battery RAM comes after it
+        let (first, mirror, ram) = run_prg_ram_program(prg_ram_rom(0, 0x08, 0x05));
+        assert_eq!(ram.unwrap().len(), 0x800);
+        assert_eq!((first, mirror), (0x42, 0x42));
+        let (_, _, ram) = run_prg_ram_program(prg_ram_rom(0x02, 0x08, 0x75));
+        assert_eq!(ram.unwrap().len(), 0x800 + 0x2000);
+    }
+
+    #[test]
+    fn nrom_without_ram_is_open_bus() {
+        for &(flags_7, flags_10) in [(0, 0), (0x08, 0x00)].iter() {
+            let (first, mirror, ram) = run_prg_ram_program(prg_ram_rom(0, flags_7, flags_10));
+            assert_eq!(ram, None);
+            // The last thing on the bus was the high byte of the address
+            assert_eq!((first, mirror), (0x60, 0x68));
+        }
+    }
+
+    // NROM-like, with PRG RAM the test turns on and off from outside
+    struct SwitchedRam {
+        prg: Vec<u8>,
+        enabled: Arc<AtomicBool>,
+    }
+
+    impl mapper::Mapper for SwitchedRam {
+        fn cpu_peek(&self, addr: u16) -> u8 {
+            if addr >= 0x8000 { self.prg[addr as usize & 0x3FFF] } else { 0 }
+        }
+        fn cpu_write(&mut self, _addr: u16, _val: u8) {}
+        fn ppu_peek(&self, _addr: u16) -> u8 { 0 }
+        fn ppu_write(&mut self, _addr: u16, _val: u8) {}
+        fn mirroring(&self) -> Mirroring { Mirroring::Horizontal }
+        fn prg_ram_enabled(&self) -> bool { self.enabled.load(Ordering::SeqCst) }
+        fn prg_offset(&self, addr: u16) -> Option<usize> {
+            if addr >= 0x8000 { Some(addr as usize & 0x3FFF) } else { None }
+        }
+    }
+
+    #[test]
+    fn disabled_prg_ram_reads_open_bus_and_keeps_its_contents() {
+        let rom = rom::ROM::from_bytes(&testing::build_rom(&[0xEA; 4])).unwrap();
+        let enabled = Arc::new(AtomicBool::new(true));
+        let mapper = SwitchedRam { prg: rom.prg.clone(), enabled: enabled.clone() };
+        let mut memory = Memory::with_mapper(rom, Box::new(mapper));
+        memory.storeb(0x6000, 0x42);
+        assert_eq!(memory.loadb(0x6000), 0x42);
+
+        enabled.store(false, Ordering::SeqCst);
+        // Whatever was last on the bus, here the opcode just read
+        assert_eq!(memory.loadb(0x8000), 0xEA);
+        assert_eq!(memory.loadb(0x6000), 0xEA);
+        assert_eq!(memory.peek(0x6000), 0xEA);
+        memory.storeb(0x6000, 0x99);
+        assert_eq!(memory.loadb(0x6000), 0x99);
+
+        enabled.store(true, Ordering::SeqCst);
+        assert_eq!(memory.loadb(0x6000), 0x42);
+    }
+
+    // Copies $7000 and $71FF to $10 and $11, then overwrites $7000
+    const TRAINER_PROGRAM: [u8; 16] = [
+        0xAD, 0x00, 0x70, 0x85, 0x10, 0xAD, 0xFF, 0x71, 0x85, 0x11, 0xA9, 0x99, 0x8D, 0x00, 0x70, 0x00,
+    ];
+
+    #[test]
+    fn trainer_is_at_7000_after_power_on() {
+        let mut image = vec![b'N', b'E', b'S', 0x1A, 1, 1, 0x04, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        image.extend((0..512).map(|i| (i as u8) ^ 0x5A));
+        let mut prg = vec![0xEA; 0x4000];
+        prg[..TRAINER_PROGRAM.len()].copy_from_slice(&TRAINER_PROGRAM);
+        prg[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0xC0]);
+        image.extend_from_slice(&prg);
+        image.extend_from_slice(&[0; 0x2000]);
+        let mut cpu = CPU::from_rom(rom::ROM::from_bytes(&image).unwrap());
+        for _ in 0..2 {
+            cpu.power_on();
+            for _ in 0..6 {
+                cpu.emulate_cycle().unwrap();
+            }
+            assert_eq!(cpu.memory().peek(0x7000), 0x99);
+            // Both ends of the trainer, and again after the next power on put it back
+            assert_eq!((cpu.memory().peek(0x10), cpu.memory().peek(0x11)), (0x5A, 0xA5));
+        }
+    }
+
+    #[test]
+    fn mmc2_bank_writes_while_rendering() {
+        // The PRG bank at $8000, the four CHR latch banks and mirroring
+        stress(9, [0xA000, 0xB000, 0xC000, 0xD000, 0xE000, 0xF000]);
+    }
+
+    #[test]
+    fn mmc5_bank_writes_while_rendering() {
+        // CHR banks from both the sprite and background sets, and the nametable mapping. PRG
+        // bank writes are left out, since most of the values would map PRG RAM at $8000.
+        stress(5, [0x5120, 0x5123, 0x5127, 0x5128, 0x512B, 0x5105]);
+    }
+
+    #[test]
+    fn irq_line_is_asserted_while_any_source_is() {
+        let mut line = IrqLine::default();
+        line.assert(IrqSource::Dmc);
+        line.assert(IrqSource::FrameCounter);
+        line.ack(IrqSource::Dmc);
+        assert!(line.is_asserted() && line.is_asserted_by(IrqSource::FrameCounter));
+        assert!(!line.is_asserted_by(IrqSource::Dmc));
+        line.set(IrqSource::FrameCounter, false);
+        assert!(!line.is_asserted());
+        line.set(IrqSource::Mapper, true);
+        assert!(line.is_asserted_by(IrqSource::Mapper));
+    }
+
+    // Plays a one-byte sample from $C000 on a loop at the fastest rate, then idles
+    const DMC_LOOP: &str = "
+        LDA #$4F
+        STA $4010
+        LDA #$00
+        STA $4012
+        STA $4013
+        LDA #$10
+        STA $4015
+idle:   NOP
+        JMP idle
+";
+
+    #[test]
+    fn dmc_fetches_stall_the_cpu() {
+        let (program, labels) = asm::assemble_with_labels(DMC_LOOP, 0x8000).unwrap();
+        for &mode in [StepMode::Instruction, StepMode::Cycle].iter() {
+            let mut cpu = testing::build_program(&program);
+            cpu.step_mode = mode;
+            while cpu.pc() != labels["idle"] {
+                cpu.emulate_cycle().unwrap();
+            }
+            cpu.memory_mut().enable_access_log();
+            // A thousand trips round the idle loop, five cycles each
+            let cycles: u32 = (0..2000).map(|_| cpu.emulate_cycle().unwrap() as u32).sum();
+            let fetches = cpu.memory_mut().take_access_log().iter()
+                .filter(|access| access.addr == 0xC000 && access.kind == AccessKind::Read)
+                .count() as u32;
+            assert!(fetches > 10, "{:?}: {} fetches", mode, fetches);
+            assert_eq!(cycles, 5000 + 4 * fetches, "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn dmc_fetch_on_a_controller_read_drops_a_bit_when_accurate() {
+        // The halted CPU's read shifts A out unseen at Accurate, so B reads first and the register
+        // runs out a read early
+        let cases = [(AccuracyLevel::Simple, [1, 1, 0, 0, 0, 0, 0, 0]), (AccuracyLevel::Accurate, [1, 0, 0, 0, 0, 0, 0, 1])];
+        for &(accuracy, expected) in cases.iter() {
+            let mut cpu = testing::build_program(&[]);
+            let memory = cpu.memory_mut();
+            memory.accuracy = accuracy;
+            memory.controllers[0].set_buttons(0x03);
+            memory.storeb(0x4016, 1);
+            memory.storeb(0x4016, 0);
+            // A one-byte sample, fetched on the next read
+            memory.storeb(0x4013, 0);
+            memory.storeb(0x4015, 0x10);
+            assert!(memory.dmc.needs_fetch());
+
+            let bits: Vec<u8> = (0..8).map(|_| memory.loadb(0x4016) & 1).collect();
+            assert_eq!(memory.take_dma_stall(), 4);
+            assert_eq!(bits, expected, "{:?}", accuracy);
+        }
+    }
+
+    #[test]
+    fn mirrors_describe_what_they_mirror() {
+        let cpu = testing::build_program(&[]);
+        let ram = cpu.memory().describe(0x0923);
+        assert_eq!((ram.region, ram.target.as_str()), ("RAM", "RAM $0123 via mirror"));
+        assert_eq!((ram.route, ram.readable, ram.writable), (Route::Ram(0x123), true, true));
+        assert_eq!(cpu.memory().describe(0x0123).target, "RAM $0123");
+        let status = cpu.memory().describe(0x3FFA);
+        assert_eq!((status.region, status.target.as_str()), ("PPU registers", "PPUSTATUS via mirror"));
+        assert_eq!((status.route, status.readable, status.writable), (Route::PpuRegister(2), true, false));
+        assert_eq!(status.to_string().split_whitespace().collect::<Vec<_>>(),
+                   ["$3FFA", "PPU", "registers", "PPUSTATUS", "via", "mirror", "read", "only"]);
+    }
+
+    #[test]
+    fn banked_prg_describes_the_bank_mapped_now() {
+        // GxROM with four 32 KiB banks. The ROM is all $FF, so the bank write isn't masked by
+        // a bus conflict.
+        let mut image = vec![b'N', b'E', b'S', 0x1A, 8, 1, 0x20, 0x40, 0, 0, 0, 0, 0, 0, 0, 0];
+        image.extend(std::iter::repeat_n(0xFF, 0x20000));
+        image.extend_from_slice(&[0; 0x2000]);
+        let mut cpu = CPU::from_rom(rom::ROM::from_bytes(&image).unwrap());
+        let memory = cpu.memory_mut();
+        assert_eq!(memory.describe(0x9234).target, "PRG bank 0 offset $1234");
+        memory.storeb(0x8000, 0x30);
+        let info = memory.describe(0x9234);
+        assert_eq!((info.region, info.target.as_str()), ("PRG ROM", "PRG bank 3 offset $1234"));
+        assert_eq!((info.readable, info.writable), (true, true));
+        assert_eq!(memory.prg_bank(0xFFFF), Some(3));
+
+        let layout = memory.layout();
+        let prg: Vec<_> = layout.iter().filter(|range| range.region == "PRG ROM").collect();
+        assert_eq!(prg.len(), 1);
+        assert_eq!((prg[0].start, prg[0].end, prg[0].target.as_str()), (0x8000, 0xFFFF, "PRG bank 3 offset $0000"));
+        let ram = &layout[..2];
+        assert_eq!((ram[0].start, ram[0].end, ram[1].start, ram[1].end), (0x0000, 0x07FF, 0x0800, 0x1FFF));
+        assert_eq!(ram[1].target, "RAM $0000-$07FF via mirror");
     }
 }
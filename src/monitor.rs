@@ -0,0 +1,197 @@
+// The interactive monitor: a prompt that reads commands and runs them through
+// Debugger::run_command, the same way source and --monitor-script do, with the commands typed
+// kept between sessions in a history file.
+//
+// Besides the debugger's commands the prompt takes
+//   history          the commands typed so far, oldest first
+//   quit, exit       leave the prompt, as end of input does
+// nes --interactive opens it once a run stops. Frontends with line editing read the lines
+// themselves and hand each to handle_line. run is the plain version, for any BufRead.
+
+use config::Config;
+use cpu::CPU;
+use debugger::Debugger;
+
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+pub const PROMPT: &str = "> ";
+
+// How many commands the history file keeps, the newest
+const MAX_HISTORY: usize = 1000;
+
+// The commands typed at the prompt, oldest first, and the file they're kept in
+#[derive(Debug, Default)]
+pub struct History {
+    entries: Vec<String>,
+    path: Option<PathBuf>,
+}
+
+impl History {
+    // A history that isn't kept anywhere
+    pub fn new() -> History {
+        History::default()
+    }
+
+    // <config dir>/history, if there's a config directory
+    pub fn default_path() -> Option<PathBuf> {
+        Config::dir().map(|dir| dir.join("history"))
+    }
+
+    // The history kept in path, one command per line. A file that isn't there yet is an empty
+    // history, which save creates.
+    pub fn load(path: &Path) -> io::Result<History> {
+        let entries = match fs::read_to_string(path) {
+            Ok(text) => text.lines().filter(|line| !line.trim().is_empty()).map(str::to_string).collect(),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(History { entries: entries, path: Some(path.to_path_buf()) })
+    }
+
+    // Add a command, unless it's blank or the same as the one before. True if it was added.
+    pub fn add(&mut self, line: &str) -> bool {
+        let line = line.trim();
+        if line.is_empty() || self.entries.last().is_some_and(|last| last == line) {
+            return false
+        }
+        self.entries.push(line.to_string());
+        true
+    }
+
+    pub fn entries(&self) -> &[String] {
+        &self.entries
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    // Write the newest MAX_HISTORY commands to the history file, making its directory if need
+    // be. Nothing to do for a history without a file.
+    pub fn save(&self) -> io::Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut text = String::new();
+        for entry in self.entries[self.entries.len().saturating_sub(MAX_HISTORY)..].iter() {
+            text.push_str(entry);
+            text.push('\n');
+        }
+        fs::write(path, text)
+    }
+}
+
+// Whether the prompt carries on after a line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Prompt {
+    Continue,
+    Quit,
+}
+
+// Run one line typed at the prompt, adding it to history and what it prints to out. Errors
+// are printed too, and don't end the session.
+pub fn handle_line(debugger: &mut Debugger, cpu: &mut CPU, history: &mut History, line: &str, out: &mut String) -> Prompt {
+    let line = line.trim();
+    if line.is_empty() {
+        return Prompt::Continue
+    }
+    history.add(line);
+    match line {
+        "quit" | "exit" => return Prompt::Quit,
+        "history" => {
+            for (n, entry) in history.entries().iter().enumerate() {
+                writeln!(out, "{:4}  {}", n + 1, entry).unwrap();
+            }
+        },
+        _ => {
+            if let Err(e) = debugger.run_command(cpu, line, out) {
+                writeln!(out, "error: {}", e).unwrap();
+            }
+        },
+    }
+    Prompt::Continue
+}
+
+// Prompt on output and run what's read from input until quit or the end of input
+pub fn run<R: BufRead, W: Write>(debugger: &mut Debugger, cpu: &mut CPU, history: &mut History, mut input: R, mut output: W)
+        -> io::Result<()> {
+    let mut line = String::new();
+    loop {
+        output.write_all(PROMPT.as_bytes())?;
+        output.flush()?;
+        line.clear();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(())
+        }
+        let mut out = String::new();
+        let prompt = handle_line(debugger, cpu, history, &line, &mut out);
+        output.write_all(out.as_bytes())?;
+        if prompt == Prompt::Quit {
+            return Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing;
+
+    #[test]
+    fn the_prompt_runs_commands_until_quit() {
+        // INC $10, JMP $8000
+        let mut cpu = testing::build_program(&[0xE6, 0x10, 0x4C, 0x00, 0x80]);
+        let mut debugger = Debugger::new();
+        let mut history = History::new();
+        let input = "break $8002\n\nrun\nbogus\nmem $10, 1\nmem $10, 1\nhistory\nquit\nregs\n";
+        let mut output = Vec::new();
+        run(&mut debugger, &mut cpu, &mut history, input.as_bytes(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+
+        assert!(output.starts_with("> > > Breakpoint $8002 hit at $8002"), "{}", output);
+        assert!(output.contains("> error: unknown command bogus\n"), "{}", output);
+        assert!(output.contains("> 0010:  01 "), "{}", output);
+        // Repeats and blank lines aren't kept, and nothing after quit runs
+        assert!(output.ends_with("\
+>    1  break $8002
+   2  run
+   3  bogus
+   4  mem $10, 1
+   5  history
+> "), "{}", output);
+        assert_eq!(history.entries().last().map(String::as_str), Some("quit"));
+        assert_eq!(cpu.pc(), 0x8002);
+    }
+
+    #[test]
+    fn history_is_kept_in_its_file() {
+        let dir = std::env::temp_dir().join(format!("nes-history-{}", std::process::id()));
+        let path = dir.join("nes_level").join("history");
+        let mut history = History::load(&path).unwrap();
+        assert!(history.entries().is_empty());
+        assert!(history.add("regs"));
+        assert!(!history.add("regs"));
+        assert!(!history.add("   "));
+        history.add("mem $10");
+        history.save().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "regs\nmem $10\n");
+
+        let mut history = History::load(&path).unwrap();
+        assert_eq!(history.entries(), ["regs", "mem $10"]);
+        for n in 0..MAX_HISTORY {
+            history.add(&format!("step {}", n));
+        }
+        history.save().unwrap();
+        let kept = History::load(&path).unwrap();
+        assert_eq!(kept.entries().len(), MAX_HISTORY);
+        assert_eq!(kept.entries()[0], "step 0");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
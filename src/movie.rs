@@ -0,0 +1,266 @@
+// FCEUX .fm2 input movies: a text header followed by one line of button states per frame
+
+use hash;
+
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+
+// Button letters as they appear in an fm2 frame line, Right (bit 7) first down to A (bit 0)
+const BUTTON_LETTERS: &[u8; 8] = b"RLDUTSBA";
+
+#[derive(Debug)]
+pub enum MovieError {
+    MissingChecksum,
+    BadFrame { line: usize },
+    ChecksumMismatch { movie: String, rom: String },
+    StartsFromSavestate,
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MovieError::MissingChecksum => write!(f, "movie has no romChecksum"),
+            MovieError::BadFrame { line } => write!(f, "malformed frame on line {}", line),
+            MovieError::ChecksumMismatch { ref movie, ref rom } =>
+                write!(f, "movie was recorded on ROM {} but this ROM is {}", movie, rom),
+            MovieError::StartsFromSavestate => write!(f, "only movies recorded from power-on can be played"),
+        }
+    }
+}
+
+// The romChecksum header value for a ROM with the given PRG+CHR MD5
+pub fn rom_checksum(md5: &[u8; 16]) -> String {
+    format!("base64:{}", hash::base64(md5))
+}
+
+pub struct Movie {
+    // "base64:" followed by the ROM's MD5, as FCEUX writes it
+    pub rom_checksum: String,
+    pub rom_filename: String,
+    // Buttons of both ports for every frame
+    pub frames: Vec<[u8; 2]>,
+}
+
+impl Movie {
+    pub fn new(rom_filename: &str, rom_checksum: &str) -> Movie {
+        Movie {
+            rom_checksum: rom_checksum.to_string(),
+            rom_filename: rom_filename.to_string(),
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Movie, MovieError> {
+        let mut movie = Movie::new("", "");
+        for (number, line) in text.lines().enumerate() {
+            if line.starts_with('|') {
+                movie.frames.push(parse_frame(line).ok_or(MovieError::BadFrame { line: number + 1 })?);
+                continue;
+            }
+
+            let mut parts = line.splitn(2, ' ');
+            let key = parts.next().unwrap_or("");
+            let value = parts.next().unwrap_or("").trim();
+            match key {
+                "romChecksum" => { movie.rom_checksum = value.to_string(); },
+                "romFilename" => { movie.rom_filename = value.to_string(); },
+                "savestate" => { return Err(MovieError::StartsFromSavestate) },
+                _ => {},
+            }
+        }
+
+        if movie.rom_checksum.is_empty() {
+            return Err(MovieError::MissingChecksum)
+        }
+        return Ok(movie)
+    }
+
+    // Playback must only ever happen on the ROM the movie was recorded with
+    pub fn check_rom(&self, rom_checksum: &str) -> Result<(), MovieError> {
+        if self.rom_checksum != rom_checksum {
+            return Err(MovieError::ChecksumMismatch {
+                movie: self.rom_checksum.clone(),
+                rom: rom_checksum.to_string(),
+            })
+        }
+        Ok(())
+    }
+
+    pub fn record_frame(&mut self, buttons: [u8; 2]) {
+        self.frames.push(buttons);
+    }
+
+    pub fn frame(&self, frame: usize) -> Option<[u8; 2]> {
+        self.frames.get(frame).cloned()
+    }
+
+    pub fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "version 3")?;
+        writeln!(out, "emuVersion 0")?;
+        writeln!(out, "rerecordCount 0")?;
+        writeln!(out, "palFlag 0")?;
+        writeln!(out, "romFilename {}", self.rom_filename)?;
+        writeln!(out, "romChecksum {}", self.rom_checksum)?;
+        writeln!(out, "guid 00000000-0000-0000-0000-000000000000")?;
+        writeln!(out, "fourscore 0")?;
+        writeln!(out, "port0 1")?;
+        writeln!(out, "port1 1")?;
+        writeln!(out, "port2 0")?;
+        for buttons in self.frames.iter() {
+            writeln!(out, "|0|{}|{}||", format_buttons(buttons[0]), format_buttons(buttons[1]))?;
+        }
+        Ok(())
+    }
+}
+
+pub fn format_buttons(buttons: u8) -> String {
+    BUTTON_LETTERS.iter().enumerate().map(|(i, &letter)| {
+        if buttons & (0x80 >> i) != 0 { letter as char } else { '.' }
+    }).collect()
+}
+
+// One line of an input log: the frame number, then each controller's latched buttons in the
+// same RLDUTSBA letters as a movie, e.g. "    42 ...U...A ........"
+pub fn input_log_line(frame: u64, latched: &[u8]) -> String {
+    let mut line = format!("{:>6}", frame);
+    for &buttons in latched {
+        line.push(' ');
+        line.push_str(&format_buttons(buttons));
+    }
+    return line
+}
+
+// Anything other than a space or '.' counts as pressed
+fn parse_buttons(field: &str) -> Option<u8> {
+    if field.is_empty() {
+        return Some(0)
+    }
+    if field.len() != 8 {
+        return None
+    }
+    let mut buttons = 0;
+    for (i, c) in field.chars().enumerate() {
+        if c != '.' && c != ' ' {
+            buttons |= 0x80 >> i;
+        }
+    }
+    Some(buttons)
+}
+
+// "|commands|port0|port1|port2|"
+fn parse_frame(line: &str) -> Option<[u8; 2]> {
+    let fields: Vec<&str> = line.split('|').collect();
+    if fields.len() < 4 {
+        return None
+    }
+    Some([parse_buttons(fields[2])?, parse_buttons(fields[3])?])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use controller::InputFrame;
+    use emulator::Nes;
+    use rom::ROM;
+    use testing;
+
+    // Shaped like an FCEUX recording, comments and all
+    const FIXTURE: &str = "version 3
+emuVersion 22020
+rerecordCount 12
+palFlag 0
+romFilename Some Game (U).nes
+romChecksum base64:npbLPonKmALtTk5JJNXw7Q==
+guid 452DE2C3-EF43-2FA9-77AC-0677FC51543B
+comment author someone
+fourscore 0
+port0 1
+port1 1
+port2 0
+|0|........|........||
+|0|R......A|.L..T...||
+|1|  D  S  |        ||
+";
+
+    // Reads the first controller on every NMI and logs the buttons, A in bit 0, from $0400 on
+    const INPUT_LOGGER: &str = "
+reset:  LDA #$80
+        STA $2000
+loop:   JMP loop
+
+nmi:    LDA #$01
+        STA $4016
+        LDA #$00
+        STA $4016
+        LDX #$00
+read:   LDA $4016
+        LSR A
+        ROR $10
+        INX
+        CPX #8
+        BNE read
+        LDX $11
+        LDA $10
+        STA $0400,X
+        INC $11
+        RTI
+";
+
+    #[test]
+    fn parses_an_fm2_file() {
+        let movie = Movie::parse(FIXTURE).unwrap();
+        assert_eq!(movie.rom_filename, "Some Game (U).nes");
+        assert_eq!(movie.rom_checksum, "base64:npbLPonKmALtTk5JJNXw7Q==");
+        assert_eq!(movie.frames, [[0x00, 0x00], [0x81, 0x48], [0x24, 0x00]]);
+        assert_eq!(movie.frame(1), Some([0x81, 0x48]));
+        assert_eq!(movie.frame(3), None);
+        assert!(movie.check_rom("base64:npbLPonKmALtTk5JJNXw7Q==").is_ok());
+        assert!(matches!(movie.check_rom("base64:AAAA"), Err(MovieError::ChecksumMismatch { .. })));
+    }
+
+    #[test]
+    fn rejects_what_it_cant_play() {
+        assert!(matches!(Movie::parse("|0|........|........||\n"), Err(MovieError::MissingChecksum)));
+        let savestate = format!("{}savestate base64:AAAA\n", FIXTURE);
+        assert!(matches!(Movie::parse(&savestate), Err(MovieError::StartsFromSavestate)));
+        let bad = FIXTURE.replace("|1|  D  S  |", "|1|DS|");
+        assert!(matches!(Movie::parse(&bad), Err(MovieError::BadFrame { line: 15 })));
+    }
+
+    // Run the logger for as many frames as there are inputs, returning what it logged
+    fn play(rom: &[u8], inputs: &[[u8; 2]]) -> Vec<u8> {
+        let mut nes = Nes::builder().rom_bytes(rom).ppu_warmup(false).build().unwrap();
+        for &buttons in inputs.iter() {
+            nes.run_frame(InputFrame::from(buttons)).unwrap();
+        }
+        let memory = nes.cpu().memory();
+        (0..memory.peek(0x11) as u16).map(|i| memory.peek(0x0400 + i)).collect()
+    }
+
+    #[test]
+    fn recording_plays_back_the_same_reads() {
+        let rom = testing::build_test_rom(INPUT_LOGGER, None);
+        let checksum = rom_checksum(&ROM::from_bytes(&rom).unwrap().md5());
+        let inputs: Vec<[u8; 2]> = (0..40u8).map(|i| [i.wrapping_mul(37) ^ (i >> 2), !i]).collect();
+
+        let mut recording = Movie::new("logger.nes", &checksum);
+        for &buttons in inputs.iter() {
+            recording.record_frame(buttons);
+        }
+        let recorded = play(&rom, &inputs);
+        let mut text = Vec::new();
+        recording.write(&mut text).unwrap();
+
+        let movie = Movie::parse(&String::from_utf8(text).unwrap()).unwrap();
+        movie.check_rom(&checksum).unwrap();
+        let played: Vec<[u8; 2]> = (0..inputs.len()).map(|i| movie.frame(i).unwrap()).collect();
+        assert_eq!(played, inputs);
+        let replayed = play(&rom, &played);
+        assert_eq!(replayed, recorded);
+        // Frames end as vblank starts, so each NMI lands at the start of the next frame and
+        // reads its buttons
+        let port1: Vec<u8> = inputs[1..].iter().map(|buttons| buttons[0]).collect();
+        assert_eq!(recorded, port1);
+    }
+}
@@ -0,0 +1,449 @@
+// NSF (NES Sound Format) rips: a game's music driver and data without the rest of the game,
+// played by calling its init routine once and its play routine at a fixed rate.
+
+use cpu::{EmulationError, CPU};
+use mapper::{self, Mapper};
+use region::Region;
+use rom::{Mirroring, ROM};
+use state::StateError;
+
+use std::fmt;
+use std::fs;
+use std::io;
+
+const NSF_MAGIC: &[u8; 5] = b"NESM\x1A";
+const HEADER_SIZE: usize = 0x80;
+const BANK_SIZE: usize = 0x1000;
+
+// The player's driver, served by the cartridge at $4100 since no rip uses that space:
+//   $4100  JSR init   with the track in A and 0 (NTSC) or 1 (PAL) in X
+//   $4103  JMP $4103
+//   $4106  JSR play
+//   $4109  JMP $4109
+// The CPU spins in one of the loops whenever the music isn't running.
+const DRIVER_START: u16 = 0x4100;
+const DRIVER_INIT: u16 = 0x4100;
+const INIT_IDLE: u16 = 0x4103;
+const DRIVER_PLAY: u16 = 0x4106;
+const PLAY_IDLE: u16 = 0x4109;
+const JSR: u8 = 0x20;
+const JMP: u8 = 0x4C;
+
+// Writes here pick the 4 KiB bank at $8000, $9000, ... $F000
+const BANK_REGISTERS: u16 = 0x5FF8;
+
+#[derive(Debug)]
+pub enum NsfError {
+    Io(io::Error),
+    BadMagic,
+    // Shorter than the header
+    Truncated,
+    // Without banking, the data has to fit between the load address and $FFFF
+    DataDoesNotFit { len: usize, load_addr: u16 },
+    // Tracks are numbered from 1
+    NoSuchTrack { track: u8, songs: u8 },
+}
+
+impl fmt::Display for NsfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NsfError::Io(ref e) => write!(f, "{}", e),
+            NsfError::BadMagic => write!(f, "not an NSF file"),
+            NsfError::Truncated => write!(f, "file is too short for an NSF header"),
+            NsfError::DataDoesNotFit { len, load_addr } =>
+                write!(f, "{} bytes loaded at ${:04X} don't fit in $8000-$FFFF", len, load_addr),
+            NsfError::NoSuchTrack { track, songs } => write!(f, "no track {}, there are {}", track, songs),
+        }
+    }
+}
+
+impl From<io::Error> for NsfError {
+    fn from(e: io::Error) -> NsfError {
+        NsfError::Io(e)
+    }
+}
+
+pub struct Nsf {
+    pub version: u8,
+    pub songs: u8,
+    // 1-based, like track numbers
+    pub starting_song: u8,
+    pub load_addr: u16,
+    pub init_addr: u16,
+    pub play_addr: u16,
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+    // Microseconds between play calls
+    pub ntsc_speed: u16,
+    pub pal_speed: u16,
+    // Initial 4 KiB banks for $8000-$FFFF. All zero means the rip doesn't bank switch.
+    pub banks: [u8; 8],
+    // Bit 0 set for PAL, bit 1 set for either
+    pub region_flags: u8,
+    // Expansion audio chips, one bit each
+    pub sound_chips: u8,
+    pub data: Vec<u8>,
+}
+
+// Header strings are NUL-padded
+fn header_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+fn word(bytes: &[u8], at: usize) -> u16 {
+    bytes[at] as u16 | (bytes[at + 1] as u16) << 8
+}
+
+impl Nsf {
+    pub fn from_file(filename: &str) -> Result<Nsf, NsfError> {
+        let data = fs::read(filename)?;
+        Nsf::from_bytes(&data)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Nsf, NsfError> {
+        if data.len() < HEADER_SIZE {
+            return Err(if data.starts_with(NSF_MAGIC) { NsfError::Truncated } else { NsfError::BadMagic })
+        }
+        if &data[0..5] != NSF_MAGIC {
+            return Err(NsfError::BadMagic)
+        }
+
+        let mut banks = [0; 8];
+        banks.copy_from_slice(&data[0x70..0x78]);
+        let nsf = Nsf {
+            version: data[0x05],
+            songs: data[0x06],
+            starting_song: data[0x07],
+            load_addr: word(data, 0x08),
+            init_addr: word(data, 0x0A),
+            play_addr: word(data, 0x0C),
+            title: header_string(&data[0x0E..0x2E]),
+            artist: header_string(&data[0x2E..0x4E]),
+            copyright: header_string(&data[0x4E..0x6E]),
+            ntsc_speed: word(data, 0x6E),
+            banks: banks,
+            pal_speed: word(data, 0x78),
+            region_flags: data[0x7A],
+            sound_chips: data[0x7B],
+            data: data[HEADER_SIZE..].to_vec(),
+        };
+
+        if !nsf.is_banked() {
+            let start = (nsf.load_addr as usize).wrapping_sub(0x8000);
+            if nsf.load_addr < 0x8000 || start + nsf.data.len() > 0x8000 {
+                return Err(NsfError::DataDoesNotFit { len: nsf.data.len(), load_addr: nsf.load_addr })
+            }
+        }
+        return Ok(nsf)
+    }
+
+    pub fn is_banked(&self) -> bool {
+        self.banks.iter().any(|&b| b != 0)
+    }
+
+    // PAL-only rips play as PAL, everything else as NTSC
+    pub fn region(&self) -> Region {
+        if self.region_flags & 0x03 == 0x01 { Region::Pal } else { Region::Ntsc }
+    }
+
+    // Microseconds between play calls in a region
+    pub fn play_period_us(&self, region: Region) -> u32 {
+        match region {
+            Region::Ntsc => self.ntsc_speed as u32,
+            Region::Pal => self.pal_speed as u32,
+        }
+    }
+
+    // The data as a list of 4 KiB banks. Banked rips are padded at the front so the load
+    // address lands at its offset within the first bank; unbanked ones are laid out across
+    // $8000-$FFFF as banks 0-7.
+    pub fn prg(&self) -> Vec<u8> {
+        let padding = if self.is_banked() {
+            self.load_addr as usize & (BANK_SIZE - 1)
+        } else {
+            self.load_addr as usize - 0x8000
+        };
+        let len = padding + self.data.len();
+        let mut prg = vec![0; len.div_ceil(BANK_SIZE).max(8) * BANK_SIZE];
+        prg[padding..len].copy_from_slice(&self.data);
+        return prg
+    }
+}
+
+// 4 KiB PRG banks switched through $5FF8-$5FFF, with the player's driver at $4100
+pub struct NsfMapper {
+    prg: Vec<u8>,
+    // Which bank is at $8000, $9000, ... $F000
+    banks: [u8; 8],
+    driver: [u8; 12],
+    // Rips have no graphics, but the PPU still fetches patterns
+    chr: Vec<u8>,
+}
+
+impl NsfMapper {
+    pub fn new(nsf: &Nsf) -> NsfMapper {
+        let banks = if nsf.is_banked() { nsf.banks } else { [0, 1, 2, 3, 4, 5, 6, 7] };
+        let init = nsf.init_addr.to_le_bytes();
+        let play = nsf.play_addr.to_le_bytes();
+        let init_idle = INIT_IDLE.to_le_bytes();
+        let play_idle = PLAY_IDLE.to_le_bytes();
+        NsfMapper {
+            prg: nsf.prg(),
+            banks: banks,
+            driver: [JSR, init[0], init[1], JMP, init_idle[0], init_idle[1],
+                     JSR, play[0], play[1], JMP, play_idle[0], play_idle[1]],
+            chr: vec![0; 0x2000],
+        }
+    }
+
+    pub fn bank(&self, slot: usize) -> u8 {
+        self.banks[slot]
+    }
+}
+
+impl Mapper for NsfMapper {
+    fn cpu_peek(&self, addr: u16) -> u8 {
+        let driver = (addr as usize).wrapping_sub(DRIVER_START as usize);
+        if driver < self.driver.len() {
+            return self.driver[driver]
+        }
+        self.prg_offset(addr).map_or(0, |offset| self.prg[offset])
+    }
+
+    fn cpu_write(&mut self, addr: u16, val: u8) {
+        if (BANK_REGISTERS..=0x5FFF).contains(&addr) {
+            self.banks[(addr - BANK_REGISTERS) as usize] = val;
+        }
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        self.chr[addr as usize & 0x1FFF]
+    }
+
+    fn ppu_write(&mut self, addr: u16, val: u8) {
+        self.chr[addr as usize & 0x1FFF] = val;
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        Mirroring::Vertical
+    }
+
+    // Banks past the end of the data wrap around, like a ROM with unconnected address lines
+    fn prg_offset(&self, addr: u16) -> Option<usize> {
+        if addr < 0x8000 {
+            return None;
+        }
+        let slot = (addr as usize - 0x8000) / BANK_SIZE;
+        let bank_count = self.prg.len() / BANK_SIZE;
+        let bank = self.banks[slot] as usize % bank_count;
+        Some(bank * BANK_SIZE + (addr as usize & (BANK_SIZE - 1)))
+    }
+
+    fn prg_bank_size(&self, _addr: u16) -> usize {
+        BANK_SIZE
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        mapper::save_with_chr_ram(&self.banks, &self.chr, true)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let banks = mapper::load_with_chr_ram(state, self.banks.len(), &mut self.chr, true)?;
+        self.banks.copy_from_slice(banks);
+        Ok(())
+    }
+}
+
+// Runs a rip: init once, then play every period. The console is otherwise idle, so the PPU
+// runs with rendering and NMIs off.
+pub struct NsfPlayer {
+    cpu: CPU,
+    // CPU cycles between play calls
+    period: u64,
+    // When the next play call is due
+    next_play: u64,
+    play_calls: u64,
+}
+
+impl NsfPlayer {
+    // track is 1-based
+    pub fn new(nsf: &Nsf, track: u8) -> Result<NsfPlayer, NsfError> {
+        if track == 0 || track > nsf.songs {
+            return Err(NsfError::NoSuchTrack { track: track, songs: nsf.songs })
+        }
+        let region = nsf.region();
+        let rom = ROM::from_parts(nsf.prg(), Vec::new());
+        let mut cpu = CPU::with_mapper(rom, Box::new(NsfMapper::new(nsf)));
+        cpu.set_region(region);
+        cpu.power_on();
+
+        cpu.set_a(track - 1);
+        cpu.set_x(if region == Region::Pal { 1 } else { 0 });
+        cpu.set_pc(DRIVER_INIT);
+
+        let cpu_hz = region.timing().cpu_clock_hz as u64;
+        let period = (nsf.play_period_us(region) as u64 * cpu_hz / 1_000_000).max(1);
+        Ok(NsfPlayer {
+            cpu: cpu,
+            period: period,
+            next_play: 0,
+            play_calls: 0,
+        })
+    }
+
+    // Whether the CPU is waiting in the driver rather than running the rip's code
+    pub fn is_idle(&self) -> bool {
+        let pc = self.cpu.pc();
+        pc == INIT_IDLE || pc == PLAY_IDLE
+    }
+
+    // Run one play period. Play is called at its start if the last call (or init) has
+    // returned; a routine that overruns the period just keeps going and the call is skipped.
+    pub fn run_period(&mut self) -> Result<(), EmulationError> {
+        if self.is_idle() {
+            self.cpu.set_pc(DRIVER_PLAY);
+            self.play_calls += 1;
+        }
+        self.next_play += self.period;
+        while self.cpu.cycles < self.next_play {
+            self.cpu.emulate_cycle()?;
+        }
+        Ok(())
+    }
+
+    pub fn play_calls(&self) -> u64 {
+        self.play_calls
+    }
+
+    pub fn period_cycles(&self) -> u64 {
+        self.period
+    }
+
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm;
+
+    // Init stores the track it's given in $10 and X in $11, and play counts calls in $12
+    const DRIVER: &str = "
+init:   STA $10
+        STX $11
+        RTS
+play:   INC $12
+        RTS
+";
+
+    fn nsf_image(load_addr: u16, banks: [u8; 8], data: &[u8]) -> Vec<u8> {
+        let mut image = vec![0; HEADER_SIZE];
+        image[..5].copy_from_slice(NSF_MAGIC);
+        image[0x05] = 1;
+        image[0x06] = 3;
+        image[0x07] = 2;
+        image[0x08..0x0A].copy_from_slice(&load_addr.to_le_bytes());
+        image[0x0A..0x0C].copy_from_slice(&load_addr.to_le_bytes());
+        image[0x0C..0x0E].copy_from_slice(&load_addr.wrapping_add(5).to_le_bytes());
+        image[0x0E..0x14].copy_from_slice(b"Title!");
+        image[0x2E..0x4E].copy_from_slice(&[b'A'; 32]);
+        image[0x4E..0x52].copy_from_slice(b"2026");
+        image[0x6E..0x70].copy_from_slice(&16639u16.to_le_bytes());
+        image[0x70..0x78].copy_from_slice(&banks);
+        image[0x78..0x7A].copy_from_slice(&19997u16.to_le_bytes());
+        image.extend_from_slice(data);
+        image
+    }
+
+    #[test]
+    fn header_fields_are_parsed() {
+        let data = asm::assemble(DRIVER, 0x8000).unwrap();
+        let mut image = nsf_image(0x8000, [0; 8], &data);
+        image[0x7A] = 1;
+        image[0x7B] = 0x20;
+        let nsf = Nsf::from_bytes(&image).unwrap();
+        assert_eq!((nsf.version, nsf.songs, nsf.starting_song), (1, 3, 2));
+        assert_eq!((nsf.load_addr, nsf.init_addr, nsf.play_addr), (0x8000, 0x8000, 0x8005));
+        // Strings stop at the first NUL, or fill the field
+        assert_eq!((nsf.title.as_str(), nsf.artist.len(), nsf.copyright.as_str()), ("Title!", 32, "2026"));
+        assert_eq!((nsf.play_period_us(Region::Ntsc), nsf.play_period_us(Region::Pal)), (16639, 19997));
+        assert_eq!((nsf.region(), nsf.sound_chips), (Region::Pal, 0x20));
+        assert!(!nsf.is_banked());
+        assert_eq!(nsf.data, data);
+
+        image[0x7A] = 3;
+        assert_eq!(Nsf::from_bytes(&image).unwrap().region(), Region::Ntsc);
+    }
+
+    #[test]
+    fn bad_files_are_refused() {
+        let image = nsf_image(0x8000, [0; 8], &[0x60]);
+        assert!(matches!(Nsf::from_bytes(&image[..0x7F]), Err(NsfError::Truncated)));
+        assert!(matches!(Nsf::from_bytes(b"NES\x1A"), Err(NsfError::BadMagic)));
+        let mut bad_magic = image.clone();
+        bad_magic[3] = b'N';
+        assert!(matches!(Nsf::from_bytes(&bad_magic), Err(NsfError::BadMagic)));
+
+        // Unbanked data has to fit in $8000-$FFFF
+        let err = Nsf::from_bytes(&nsf_image(0xFFFF, [0; 8], &[0x60, 0x60])).err().unwrap();
+        assert_eq!(err.to_string(), "2 bytes loaded at $FFFF don't fit in $8000-$FFFF");
+        assert!(matches!(Nsf::from_bytes(&nsf_image(0x6000, [0; 8], &[0x60])), Err(NsfError::DataDoesNotFit { .. })));
+
+        let nsf = Nsf::from_bytes(&image).unwrap();
+        for &track in [0, 4].iter() {
+            match NsfPlayer::new(&nsf, track) {
+                Err(NsfError::NoSuchTrack { track: t, songs }) => assert_eq!((t, songs), (track, 3)),
+                _ => panic!("track {} was accepted", track),
+            }
+        }
+    }
+
+    #[test]
+    fn banked_rips_start_in_their_banks() {
+        // Three 4 KiB banks, the first padded so $8123 is at offset $123, filled with the bank
+        // number from there on
+        let mut data = vec![0; 3 * BANK_SIZE - 0x123];
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = ((i + 0x123) / BANK_SIZE) as u8;
+        }
+        let nsf = Nsf::from_bytes(&nsf_image(0x8123, [2, 0, 1, 2, 0, 0, 0, 0], &data)).unwrap();
+        assert!(nsf.is_banked());
+        let prg = nsf.prg();
+        assert_eq!(prg.len(), 8 * BANK_SIZE);
+        assert_eq!((prg[0x122], prg[0x123], prg[0x2000]), (0, 0, 2));
+
+        let mut mapper = NsfMapper::new(&nsf);
+        assert_eq!((mapper.bank(0), mapper.bank(1), mapper.bank(2)), (2, 0, 1));
+        assert_eq!((mapper.cpu_peek(0x8000), mapper.cpu_peek(0x9123), mapper.cpu_peek(0xA000)), (2, 0, 1));
+        mapper.cpu_write(0x5FF9, 1);
+        assert_eq!((mapper.bank(1), mapper.cpu_peek(0x9000)), (1, 1));
+        // The driver calls init and play
+        assert_eq!(mapper.cpu_peek(DRIVER_INIT), JSR);
+        assert_eq!(word(&[mapper.cpu_peek(0x4107), mapper.cpu_peek(0x4108)], 0), 0x8128);
+    }
+
+    #[test]
+    fn play_is_called_once_a_period() {
+        let nsf = Nsf::from_bytes(&nsf_image(0x8000, [0; 8], &asm::assemble(DRIVER, 0x8000).unwrap())).unwrap();
+        let mut player = NsfPlayer::new(&nsf, 3).unwrap();
+        // 16639 microseconds of a 1789773 Hz CPU
+        assert_eq!(player.period_cycles(), 29780);
+        for _ in 0..10 {
+            player.run_period().unwrap();
+            assert!(player.is_idle());
+        }
+        // Init runs in the first period, play in every one after it
+        let memory = player.cpu().memory();
+        assert_eq!((memory.peek(0x10), memory.peek(0x11), memory.peek(0x12)), (2, 0, 9));
+        assert_eq!(player.play_calls(), 9);
+        assert!(player.cpu().cycles >= 10 * 29780);
+    }
+}
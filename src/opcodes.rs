@@ -0,0 +1,568 @@
+// Decoding information for every opcode: one table shared by execution, cycle counting and
+// anything that needs to disassemble
+
+use std::fmt;
+
+use self::AddressingMode::*;
+use self::Mnemonic::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mnemonic {
+    ADC, AND, ASL, BCC, BCS, BEQ, BIT, BMI, BNE, BPL, BRK, BVC, BVS, CLC,
+    CLD, CLI, CLV, CMP, CPX, CPY, DEC, DEX, DEY, EOR, INC, INX, INY, JMP,
+    JSR, LDA, LDX, LDY, LSR, NOP, ORA, PHA, PHP, PLA, PLP, ROL, ROR, RTI,
+    RTS, SBC, SEC, SED, SEI, STA, STX, STY, TAX, TAY, TSX, TXA, TXS, TYA,
+    // Anything outside the official instruction set
+    Illegal,
+}
+
+impl fmt::Display for Mnemonic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Illegal => write!(f, "???"),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressingMode {
+    Implied,
+    Accumulator,
+    Immediate,
+    ZeroPage,
+    ZeroPageX,
+    ZeroPageY,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    Indirect,
+    IndirectX,
+    IndirectY,
+    Relative,
+}
+
+impl AddressingMode {
+    // Bytes of operand following the opcode
+    pub const fn operand_len(&self) -> u8 {
+        match *self {
+            Implied | Accumulator => 0,
+            Immediate | ZeroPage | ZeroPageX | ZeroPageY | IndirectX | IndirectY | Relative => 1,
+            Absolute | AbsoluteX | AbsoluteY | Indirect => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Opcode {
+    pub mnemonic: Mnemonic,
+    pub mode: AddressingMode,
+    // Base cycles, before page-crossing and branch penalties
+    pub cycles: u8,
+    // Instruction length in bytes, including the opcode
+    pub len: u8,
+}
+
+const fn op(mnemonic: Mnemonic, mode: AddressingMode, cycles: u8) -> Opcode {
+    Opcode { mnemonic: mnemonic, mode: mode, cycles: cycles, len: 1 + mode.operand_len() }
+}
+
+// How unofficial opcodes are treated when they are let through: a one-byte, two-cycle NOP
+const ILLEGAL: Opcode = op(Illegal, Implied, 2);
+
+pub static OPCODE_TABLE: [Opcode; 256] = [
+    op(BRK, Implied, 7), // 00
+    op(ORA, IndirectX, 6), // 01
+    ILLEGAL, // 02
+    ILLEGAL, // 03
+    ILLEGAL, // 04
+    op(ORA, ZeroPage, 3), // 05
+    op(ASL, ZeroPage, 5), // 06
+    ILLEGAL, // 07
+    op(PHP, Implied, 3), // 08
+    op(ORA, Immediate, 2), // 09
+    op(ASL, Accumulator, 2), // 0a
+    ILLEGAL, // 0b
+    ILLEGAL, // 0c
+    op(ORA, Absolute, 4), // 0d
+    op(ASL, Absolute, 6), // 0e
+    ILLEGAL, // 0f
+    op(BPL, Relative, 2), // 10
+    op(ORA, IndirectY, 5), // 11
+    ILLEGAL, // 12
+    ILLEGAL, // 13
+    ILLEGAL, // 14
+    op(ORA, ZeroPageX, 4), // 15
+    op(ASL, ZeroPageX, 6), // 16
+    ILLEGAL, // 17
+    op(CLC, Implied, 2), // 18
+    op(ORA, AbsoluteY, 4), // 19
+    ILLEGAL, // 1a
+    ILLEGAL, // 1b
+    ILLEGAL, // 1c
+    op(ORA, AbsoluteX, 4), // 1d
+    op(ASL, AbsoluteX, 7), // 1e
+    ILLEGAL, // 1f
+    op(JSR, Absolute, 6), // 20
+    op(AND, IndirectX, 6), // 21
+    ILLEGAL, // 22
+    ILLEGAL, // 23
+    op(BIT, ZeroPage, 3), // 24
+    op(AND, ZeroPage, 3), // 25
+    op(ROL, ZeroPage, 5), // 26
+    ILLEGAL, // 27
+    op(PLP, Implied, 4), // 28
+    op(AND, Immediate, 2), // 29
+    op(ROL, Accumulator, 2), // 2a
+    ILLEGAL, // 2b
+    op(BIT, Absolute, 4), // 2c
+    op(AND, Absolute, 4), // 2d
+    op(ROL, Absolute, 6), // 2e
+    ILLEGAL, // 2f
+    op(BMI, Relative, 2), // 30
+    op(AND, IndirectY, 5), // 31
+    ILLEGAL, // 32
+    ILLEGAL, // 33
+    ILLEGAL, // 34
+    op(AND, ZeroPageX, 4), // 35
+    op(ROL, ZeroPageX, 6), // 36
+    ILLEGAL, // 37
+    op(SEC, Implied, 2), // 38
+    op(AND, AbsoluteY, 4), // 39
+    ILLEGAL, // 3a
+    ILLEGAL, // 3b
+    ILLEGAL, // 3c
+    op(AND, AbsoluteX, 4), // 3d
+    op(ROL, AbsoluteX, 7), // 3e
+    ILLEGAL, // 3f
+    op(RTI, Implied, 6), // 40
+    op(EOR, IndirectX, 6), // 41
+    ILLEGAL, // 42
+    ILLEGAL, // 43
+    ILLEGAL, // 44
+    op(EOR, ZeroPage, 3), // 45
+    op(LSR, ZeroPage, 5), // 46
+    ILLEGAL, // 47
+    op(PHA, Implied, 3), // 48
+    op(EOR, Immediate, 2), // 49
+    op(LSR, Accumulator, 2), // 4a
+    ILLEGAL, // 4b
+    op(JMP, Absolute, 3), // 4c
+    op(EOR, Absolute, 4), // 4d
+    op(LSR, Absolute, 6), // 4e
+    ILLEGAL, // 4f
+    op(BVC, Relative, 2), // 50
+    op(EOR, IndirectY, 5), // 51
+    ILLEGAL, // 52
+    ILLEGAL, // 53
+    ILLEGAL, // 54
+    op(EOR, ZeroPageX, 4), // 55
+    op(LSR, ZeroPageX, 6), // 56
+    ILLEGAL, // 57
+    op(CLI, Implied, 2), // 58
+    op(EOR, AbsoluteY, 4), // 59
+    ILLEGAL, // 5a
+    ILLEGAL, // 5b
+    ILLEGAL, // 5c
+    op(EOR, AbsoluteX, 4), // 5d
+    op(LSR, AbsoluteX, 7), // 5e
+    ILLEGAL, // 5f
+    op(RTS, Implied, 6), // 60
+    op(ADC, IndirectX, 6), // 61
+    ILLEGAL, // 62
+    ILLEGAL, // 63
+    ILLEGAL, // 64
+    op(ADC, ZeroPage, 3), // 65
+    op(ROR, ZeroPage, 5), // 66
+    ILLEGAL, // 67
+    op(PLA, Implied, 4), // 68
+    op(ADC, Immediate, 2), // 69
+    op(ROR, Accumulator, 2), // 6a
+    ILLEGAL, // 6b
+    op(JMP, Indirect, 5), // 6c
+    op(ADC, Absolute, 4), // 6d
+    op(ROR, Absolute, 6), // 6e
+    ILLEGAL, // 6f
+    op(BVS, Relative, 2), // 70
+    op(ADC, IndirectY, 5), // 71
+    ILLEGAL, // 72
+    ILLEGAL, // 73
+    ILLEGAL, // 74
+    op(ADC, ZeroPageX, 4), // 75
+    op(ROR, ZeroPageX, 6), // 76
+    ILLEGAL, // 77
+    op(SEI, Implied, 2), // 78
+    op(ADC, AbsoluteY, 4), // 79
+    ILLEGAL, // 7a
+    ILLEGAL, // 7b
+    ILLEGAL, // 7c
+    op(ADC, AbsoluteX, 4), // 7d
+    op(ROR, AbsoluteX, 7), // 7e
+    ILLEGAL, // 7f
+    ILLEGAL, // 80
+    op(STA, IndirectX, 6), // 81
+    ILLEGAL, // 82
+    ILLEGAL, // 83
+    op(STY, ZeroPage, 3), // 84
+    op(STA, ZeroPage, 3), // 85
+    op(STX, ZeroPage, 3), // 86
+    ILLEGAL, // 87
+    op(DEY, Implied, 2), // 88
+    ILLEGAL, // 89
+    op(TXA, Implied, 2), // 8a
+    ILLEGAL, // 8b
+    op(STY, Absolute, 4), // 8c
+    op(STA, Absolute, 4), // 8d
+    op(STX, Absolute, 4), // 8e
+    ILLEGAL, // 8f
+    op(BCC, Relative, 2), // 90
+    op(STA, IndirectY, 6), // 91
+    ILLEGAL, // 92
+    ILLEGAL, // 93
+    op(STY, ZeroPageX, 4), // 94
+    op(STA, ZeroPageX, 4), // 95
+    op(STX, ZeroPageY, 4), // 96
+    ILLEGAL, // 97
+    op(TYA, Implied, 2), // 98
+    op(STA, AbsoluteY, 5), // 99
+    op(TXS, Implied, 2), // 9a
+    ILLEGAL, // 9b
+    ILLEGAL, // 9c
+    op(STA, AbsoluteX, 5), // 9d
+    ILLEGAL, // 9e
+    ILLEGAL, // 9f
+    op(LDY, Immediate, 2), // a0
+    op(LDA, IndirectX, 6), // a1
+    op(LDX, Immediate, 2), // a2
+    ILLEGAL, // a3
+    op(LDY, ZeroPage, 3), // a4
+    op(LDA, ZeroPage, 3), // a5
+    op(LDX, ZeroPage, 3), // a6
+    ILLEGAL, // a7
+    op(TAY, Implied, 2), // a8
+    op(LDA, Immediate, 2), // a9
+    op(TAX, Implied, 2), // aa
+    ILLEGAL, // ab
+    op(LDY, Absolute, 4), // ac
+    op(LDA, Absolute, 4), // ad
+    op(LDX, Absolute, 4), // ae
+    ILLEGAL, // af
+    op(BCS, Relative, 2), // b0
+    op(LDA, IndirectY, 5), // b1
+    ILLEGAL, // b2
+    ILLEGAL, // b3
+    op(LDY, ZeroPageX, 4), // b4
+    op(LDA, ZeroPageX, 4), // b5
+    op(LDX, ZeroPageY, 4), // b6
+    ILLEGAL, // b7
+    op(CLV, Implied, 2), // b8
+    op(LDA, AbsoluteY, 4), // b9
+    op(TSX, Implied, 2), // ba
+    ILLEGAL, // bb
+    op(LDY, AbsoluteX, 4), // bc
+    op(LDA, AbsoluteX, 4), // bd
+    op(LDX, AbsoluteY, 4), // be
+    ILLEGAL, // bf
+    op(CPY, Immediate, 2), // c0
+    op(CMP, IndirectX, 6), // c1
+    ILLEGAL, // c2
+    ILLEGAL, // c3
+    op(CPY, ZeroPage, 3), // c4
+    op(CMP, ZeroPage, 3), // c5
+    op(DEC, ZeroPage, 5), // c6
+    ILLEGAL, // c7
+    op(INY, Implied, 2), // c8
+    op(CMP, Immediate, 2), // c9
+    op(DEX, Implied, 2), // ca
+    ILLEGAL, // cb
+    op(CPY, Absolute, 4), // cc
+    op(CMP, Absolute, 4), // cd
+    op(DEC, Absolute, 6), // ce
+    ILLEGAL, // cf
+    op(BNE, Relative, 2), // d0
+    op(CMP, IndirectY, 5), // d1
+    ILLEGAL, // d2
+    ILLEGAL, // d3
+    ILLEGAL, // d4
+    op(CMP, ZeroPageX, 4), // d5
+    op(DEC, ZeroPageX, 6), // d6
+    ILLEGAL, // d7
+    op(CLD, Implied, 2), // d8
+    op(CMP, AbsoluteY, 4), // d9
+    ILLEGAL, // da
+    ILLEGAL, // db
+    ILLEGAL, // dc
+    op(CMP, AbsoluteX, 4), // dd
+    op(DEC, AbsoluteX, 7), // de
+    ILLEGAL, // df
+    op(CPX, Immediate, 2), // e0
+    op(SBC, IndirectX, 6), // e1
+    ILLEGAL, // e2
+    ILLEGAL, // e3
+    op(CPX, ZeroPage, 3), // e4
+    op(SBC, ZeroPage, 3), // e5
+    op(INC, ZeroPage, 5), // e6
+    ILLEGAL, // e7
+    op(INX, Implied, 2), // e8
+    op(SBC, Immediate, 2), // e9
+    op(NOP, Implied, 2), // ea
+    ILLEGAL, // eb
+    op(CPX, Absolute, 4), // ec
+    op(SBC, Absolute, 4), // ed
+    op(INC, Absolute, 6), // ee
+    ILLEGAL, // ef
+    op(BEQ, Relative, 2), // f0
+    op(SBC, IndirectY, 5), // f1
+    ILLEGAL, // f2
+    ILLEGAL, // f3
+    ILLEGAL, // f4
+    op(SBC, ZeroPageX, 4), // f5
+    op(INC, ZeroPageX, 6), // f6
+    ILLEGAL, // f7
+    op(SED, Implied, 2), // f8
+    op(SBC, AbsoluteY, 4), // f9
+    ILLEGAL, // fa
+    ILLEGAL, // fb
+    ILLEGAL, // fc
+    op(SBC, AbsoluteX, 4), // fd
+    op(INC, AbsoluteX, 7), // fe
+    ILLEGAL, // ff
+];
+
+// The opcode for an official instruction in a given addressing mode, if the 6502 has one
+pub fn encode(mnemonic: Mnemonic, mode: AddressingMode) -> Option<u8> {
+    if mnemonic == Illegal {
+        return None;
+    }
+    OPCODE_TABLE.iter()
+        .position(|op| op.mnemonic == mnemonic && op.mode == mode)
+        .map(|opcode| opcode as u8)
+}
+
+// The instruction at the start of bytes, which were fetched from pc, as text and its length.
+// Branch targets are shown as absolute addresses. Unofficial opcodes and instructions cut off
+// by the end of bytes come out as a .byte directive, so the text always reassembles.
+pub fn disassemble(bytes: &[u8], pc: u16) -> (String, u8) {
+    let opcode = match bytes.first() {
+        Some(opcode) => *opcode,
+        None => return (String::new(), 0),
+    };
+    let op = OPCODE_TABLE[opcode as usize];
+    if op.mnemonic == Illegal || bytes.len() < op.len as usize {
+        return (format!(".byte ${:02X}", opcode), 1);
+    }
+
+    let lo = bytes.get(1).cloned().unwrap_or(0);
+    let word = ((bytes.get(2).cloned().unwrap_or(0) as u16) << 8) | lo as u16;
+    let operand = match op.mode {
+        Implied => String::new(),
+        Accumulator => "A".to_string(),
+        Immediate => format!("#${:02X}", lo),
+        ZeroPage => format!("${:02X}", lo),
+        ZeroPageX => format!("${:02X},X", lo),
+        ZeroPageY => format!("${:02X},Y", lo),
+        Absolute => format!("${:04X}", word),
+        AbsoluteX => format!("${:04X},X", word),
+        AbsoluteY => format!("${:04X},Y", word),
+        Indirect => format!("(${:04X})", word),
+        IndirectX => format!("(${:02X},X)", lo),
+        IndirectY => format!("(${:02X}),Y", lo),
+        Relative => format!("${:04X}", pc.wrapping_add(2).wrapping_add(lo as i8 as u16)),
+    };
+    let text = if operand.is_empty() {
+        op.mnemonic.to_string()
+    } else {
+        format!("{} {}", op.mnemonic, operand)
+    };
+    return (text, op.len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm;
+    use testing;
+
+    // The standard 6502 opcode matrix, official instructions only: a row per high nibble, a
+    // cell per low nibble, each the mnemonic, the addressing mode in the usual datasheet
+    // notation and the base cycle count. Typed in from the datasheet, not generated from
+    // OPCODE_TABLE, so the two check each other.
+    const REFERENCE: [&str; 16] = [
+        "BRK impl 7|ORA X,ind 6|---|---|---|ORA zpg 3|ASL zpg 5|---|PHP impl 3|ORA # 2|ASL A 2|---|---|ORA abs 4|ASL abs 6|---",
+        "BPL rel 2|ORA ind,Y 5|---|---|---|ORA zpg,X 4|ASL zpg,X 6|---|CLC impl 2|ORA abs,Y 4|---|---|---|ORA abs,X 4|ASL abs,X 7|---",
+        "JSR abs 6|AND X,ind 6|---|---|BIT zpg 3|AND zpg 3|ROL zpg 5|---|PLP impl 4|AND # 2|ROL A 2|---|BIT abs 4|AND abs 4|ROL abs 6|---",
+        "BMI rel 2|AND ind,Y 5|---|---|---|AND zpg,X 4|ROL zpg,X 6|---|SEC impl 2|AND abs,Y 4|---|---|---|AND abs,X 4|ROL abs,X 7|---",
+        "RTI impl 6|EOR X,ind 6|---|---|---|EOR zpg 3|LSR zpg 5|---|PHA impl 3|EOR # 2|LSR A 2|---|JMP abs 3|EOR abs 4|LSR abs 6|---",
+        "BVC rel 2|EOR ind,Y 5|---|---|---|EOR zpg,X 4|LSR zpg,X 6|---|CLI impl 2|EOR abs,Y 4|---|---|---|EOR abs,X 4|LSR abs,X 7|---",
+        "RTS impl 6|ADC X,ind 6|---|---|---|ADC zpg 3|ROR zpg 5|---|PLA impl 4|ADC # 2|ROR A 2|---|JMP ind 5|ADC abs 4|ROR abs 6|---",
+        "BVS rel 2|ADC ind,Y 5|---|---|---|ADC zpg,X 4|ROR zpg,X 6|---|SEI impl 2|ADC abs,Y 4|---|---|---|ADC abs,X 4|ROR abs,X 7|---",
+        "---|STA X,ind 6|---|---|STY zpg 3|STA zpg 3|STX zpg 3|---|DEY impl 2|---|TXA impl 2|---|STY abs 4|STA abs 4|STX abs 4|---",
+        "BCC rel 2|STA ind,Y 6|---|---|STY zpg,X 4|STA zpg,X 4|STX zpg,Y 4|---|TYA impl 2|STA abs,Y 5|TXS impl 2|---|---|STA abs,X 5|---|---",
+        "LDY # 2|LDA X,ind 6|LDX # 2|---|LDY zpg 3|LDA zpg 3|LDX zpg 3|---|TAY impl 2|LDA # 2|TAX impl 2|---|LDY abs 4|LDA abs 4|LDX abs 4|---",
+        "BCS rel 2|LDA ind,Y 5|---|---|LDY zpg,X 4|LDA zpg,X 4|LDX zpg,Y 4|---|CLV impl 2|LDA abs,Y 4|TSX impl 2|---|LDY abs,X 4|LDA abs,X 4|LDX abs,Y 4|---",
+        "CPY # 2|CMP X,ind 6|---|---|CPY zpg 3|CMP zpg 3|DEC zpg 5|---|INY impl 2|CMP # 2|DEX impl 2|---|CPY abs 4|CMP abs 4|DEC abs 6|---",
+        "BNE rel 2|CMP ind,Y 5|---|---|---|CMP zpg,X 4|DEC zpg,X 6|---|CLD impl 2|CMP abs,Y 4|---|---|---|CMP abs,X 4|DEC abs,X 7|---",
+        "CPX # 2|SBC X,ind 6|---|---|CPX zpg 3|SBC zpg 3|INC zpg 5|---|INX impl 2|SBC # 2|NOP impl 2|---|CPX abs 4|SBC abs 4|INC abs 6|---",
+        "BEQ rel 2|SBC ind,Y 5|---|---|---|SBC zpg,X 4|INC zpg,X 6|---|SED impl 2|SBC abs,Y 4|---|---|---|SBC abs,X 4|INC abs,X 7|---",
+    ];
+
+    fn reference_mode(notation: &str) -> AddressingMode {
+        match notation {
+            "impl" => Implied,
+            "A" => Accumulator,
+            "#" => Immediate,
+            "zpg" => ZeroPage,
+            "zpg,X" => ZeroPageX,
+            "zpg,Y" => ZeroPageY,
+            "abs" => Absolute,
+            "abs,X" => AbsoluteX,
+            "abs,Y" => AbsoluteY,
+            "ind" => Indirect,
+            "X,ind" => IndirectX,
+            "ind,Y" => IndirectY,
+            "rel" => Relative,
+            _ => panic!("unknown addressing mode {}", notation),
+        }
+    }
+
+    #[test]
+    fn table_matches_reference_matrix() {
+        let mut official = 0;
+        for (high, row) in REFERENCE.iter().enumerate() {
+            let cells: Vec<&str> = row.split('|').collect();
+            assert_eq!(cells.len(), 16, "row {:X}x", high);
+            for (low, cell) in cells.iter().enumerate() {
+                let opcode = high << 4 | low;
+                let op = OPCODE_TABLE[opcode];
+                if *cell == "---" {
+                    assert_eq!(op, ILLEGAL, "${:02X} should be unofficial", opcode);
+                    continue;
+                }
+                official += 1;
+                let fields: Vec<&str> = cell.split(' ').collect();
+                let expected = (fields[0], reference_mode(fields[1]), fields[2].parse::<u8>().unwrap());
+                assert_eq!((format!("{:?}", op.mnemonic).as_str(), op.mode, op.cycles), expected, "${:02X}", opcode);
+                assert_eq!(op.len, 1 + op.mode.operand_len(), "${:02X}", opcode);
+            }
+        }
+        assert_eq!(official, 151);
+    }
+
+    #[test]
+    fn x_register_loads_and_stores_index_by_y() {
+        assert_eq!(OPCODE_TABLE[0x96].mode, ZeroPageY);
+        assert_eq!(OPCODE_TABLE[0xB6].mode, ZeroPageY);
+        // LDX #$42, LDY #$05, STX $10,Y, LDX #$00, LDX $10,Y
+        let mut cpu = testing::build_program(&[0xA2, 0x42, 0xA0, 0x05, 0x96, 0x10, 0xA2, 0x00, 0xB6, 0x10]);
+        for _ in 0..5 {
+            cpu.emulate_cycle().unwrap();
+        }
+        assert_eq!(cpu.memory().peek(0x15), 0x42);
+        assert_eq!(cpu.x(), 0x42);
+    }
+
+    // Every mnemonic the CPU implements, in most of its addressing modes, ending at done
+    const EVERY_INSTRUCTION: &str = "
+        LDA #$C3
+        STA $10
+        LDX #$04
+        LDY #$02
+        STA $20,X
+        STX $0300
+        STY $30,X
+        STX $40,Y
+        STY $0302
+        LDA #$00
+        STA $02
+        LDA #$03
+        STA $03
+        LDA ($FE,X)
+        STA $11
+        LDA ($02),Y
+        STA $0301,Y
+        STA ($02),Y
+        LDA $0300,X
+        LDX $0300,Y
+        LDY $10
+        LDA $0304,X
+        ORA #$0F
+        AND #$3C
+        EOR $10
+        STA $12
+        LDA #$7F
+        ADC #$01
+        STA $13
+        PHP
+        SBC #$10
+        STA $14
+        PLP
+        ASL A
+        ROL A
+        LSR A
+        ROR A
+        STA $15
+        ASL $10
+        ROL $10,X
+        LSR $0300
+        ROR $0301,X
+        INC $11
+        DEC $12,X
+        INX
+        INY
+        DEX
+        DEY
+        DEY
+        CMP #$40
+        BCC skip
+        CPX #$04
+        BEQ skip
+        INC $16
+skip:   CPY $10
+        BCS over
+        INC $17
+over:   JSR sub
+back:   SEI
+        CLI
+        NOP
+        LDA #<done
+        STA $0304
+        LDA #>done
+        STA $0305
+        JMP ($0304)
+sub:    INC $18
+        RTS
+done:   JMP done
+";
+
+    #[test]
+    fn every_implemented_instruction_still_does_what_it_did() {
+        let (program, labels) = asm::assemble_with_labels(EVERY_INSTRUCTION, 0x8000).unwrap();
+        let (done, back) = (labels["done"], labels["back"]);
+        let mut cpu = testing::build_program(&program);
+        for _ in 0..100 {
+            if cpu.pc() == done {
+                break
+            }
+            cpu.emulate_cycle().unwrap();
+        }
+        assert_eq!(cpu.pc(), done);
+
+        // Pinned, so a dispatch change that alters any instruction's result shows up here
+        assert_eq!((cpu.a(), cpu.x(), cpu.y(), cpu.s(), cpu.flags().bits()), (0x80, 0x02, 0xC2, 0xFD, 0x20));
+        let memory = cpu.memory();
+        let peek = |range: ::std::ops::Range<u16>| range.map(|addr| memory.peek(addr)).collect::<Vec<u8>>();
+        assert_eq!(peek(0x10..0x19), [0x86, 0x05, 0x9F, 0x80, 0x6F, 0x30, 0x01, 0x01, 0x01]);
+        assert_eq!((memory.peek(0x24), memory.peek(0x34), memory.peek(0x42)), (0xC3, 0x02, 0x04));
+        assert_eq!(peek(0x0300..0x0306), [0x02, 0x00, 0x02, 0x01, (done & 0xFF) as u8, (done >> 8) as u8]);
+        // JSR's return address, over what PHP pushed
+        assert_eq!(peek(0x01FC..0x0200), [(back - 1) as u8, ((back - 1) >> 8) as u8, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn disassembly_uses_the_table() {
+        assert_eq!(disassemble(&[0x96, 0x10], 0x8000), ("STX $10,Y".to_string(), 2));
+        assert_eq!(disassemble(&[0xB6, 0x10], 0x8000), ("LDX $10,Y".to_string(), 2));
+        assert_eq!(disassemble(&[0xBC, 0x34, 0x12], 0x8000), ("LDY $1234,X".to_string(), 3));
+        assert_eq!(encode(STX, ZeroPageX), None);
+    }
+}
@@ -0,0 +1,254 @@
+// On-screen messages: short notices like "State saved" shown over the picture for a few
+// frames. They're drawn onto a copy of the frame, never the PPU's framebuffer, so frame hashes
+// and screenshots don't depend on what happened to be showing.
+
+use font;
+use ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+// NES colors for message text and the box behind it
+const TEXT_COLOR: u8 = 0x30;
+const BACKGROUND_COLOR: u8 = 0x0F;
+// Distance from the left and bottom edges, clear of a typical TV's overscan
+const MARGIN: usize = 8;
+// Older messages are dropped past this many
+const MAX_MESSAGES: usize = 4;
+
+// The input display's buttons left to right, laid out roughly like the pad: the d-pad, then
+// Select, Start, B and A. Bits in set_buttons' layout.
+const INPUT_ORDER: [u8; 8] = [6, 4, 5, 7, 2, 3, 1, 0];
+// Side of a button's box and the pitch between boxes, in pixels
+const BOX_SIZE: usize = 6;
+const BOX_PITCH: usize = 7;
+
+// A few seconds at 60 fps
+pub const DEFAULT_DURATION: u32 = 120;
+
+struct Message {
+    text: String,
+    frames_left: u32,
+}
+
+#[derive(Default)]
+pub struct Osd {
+    // Oldest first
+    messages: Vec<Message>,
+    // Each controller's latched buttons, while the input display is on
+    input: Option<Vec<u8>>,
+}
+
+impl Osd {
+    pub fn new() -> Osd {
+        Osd::default()
+    }
+
+    // Show text for duration_frames frames. Repeating the newest message restarts its timer
+    // instead of stacking a copy, so something that happens every frame shows once.
+    pub fn notify(&mut self, text: &str, duration_frames: u32) {
+        if let Some(newest) = self.messages.last_mut() {
+            if newest.text == text {
+                newest.frames_left = duration_frames;
+                return
+            }
+        }
+        if self.messages.len() == MAX_MESSAGES {
+            self.messages.remove(0);
+        }
+        self.messages.push(Message { text: text.to_string(), frames_left: duration_frames });
+    }
+
+    // Called as each frame is finished: drop the messages that have had their frames, and
+    // count this one off the rest
+    pub fn tick(&mut self) {
+        self.messages.retain(|message| message.frames_left > 0);
+        for message in self.messages.iter_mut() {
+            message.frames_left -= 1;
+        }
+    }
+
+    // Oldest first
+    pub fn messages(&self) -> impl Iterator<Item = &str> + '_ {
+        self.messages.iter().map(|message| message.text.as_str())
+    }
+
+    // Show these buttons in the input display, controller 1 first, until the next call or
+    // hide_input()
+    pub fn show_input(&mut self, buttons: &[u8]) {
+        self.input = Some(buttons.to_vec());
+    }
+
+    pub fn hide_input(&mut self) {
+        self.input = None;
+    }
+
+    pub fn input(&self) -> Option<&[u8]> {
+        self.input.as_deref()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty() && self.input.is_none()
+    }
+
+    pub fn clear(&mut self) {
+        self.messages.clear();
+    }
+
+    // Draw the messages into a SCREEN_WIDTH x SCREEN_HEIGHT frame, one per line stacked up
+    // from the bottom-left with the newest lowest
+    pub fn draw(&self, framebuffer: &mut [u8]) {
+        for (i, message) in self.messages.iter().rev().enumerate() {
+            let y = match SCREEN_HEIGHT.checked_sub(MARGIN + (i + 1) * font::GLYPH_SIZE) {
+                Some(y) => y,
+                None => break,
+            };
+            // One line each, so a stray newline can't draw over the message above
+            let text = message.text.lines().next().unwrap_or("");
+            font::draw_text(framebuffer, SCREEN_WIDTH, MARGIN, y, text, TEXT_COLOR, Some(BACKGROUND_COLOR));
+        }
+        if let Some(ref input) = self.input {
+            draw_input(framebuffer, input);
+        }
+    }
+
+    // A copy of framebuffer with the messages drawn over it
+    pub fn composite(&self, framebuffer: &[u8]) -> Vec<u8> {
+        let mut frame = framebuffer.to_vec();
+        self.draw(&mut frame);
+        return frame
+    }
+}
+
+// The input display: one row per controller in the bottom-right corner, controller 1 at the
+// top, each a player number and then a box per button, filled while it's held
+pub fn draw_input(framebuffer: &mut [u8], buttons: &[u8]) {
+    let width = font::GLYPH_SIZE + INPUT_ORDER.len() * BOX_PITCH;
+    let left = SCREEN_WIDTH - MARGIN - width;
+    let top = match SCREEN_HEIGHT.checked_sub(MARGIN + buttons.len() * font::GLYPH_SIZE) {
+        Some(top) => top,
+        None => return,
+    };
+    for (player, &held) in buttons.iter().enumerate() {
+        let y = top + player * font::GLYPH_SIZE;
+        // The label's background covers the row, so the boxes show over any picture
+        let row = format!("{:<1$}", player + 1, width / font::GLYPH_SIZE);
+        font::draw_text(framebuffer, SCREEN_WIDTH, left, y, &row, TEXT_COLOR, Some(BACKGROUND_COLOR));
+        for (i, &bit) in INPUT_ORDER.iter().enumerate() {
+            let x = left + font::GLYPH_SIZE + i * BOX_PITCH;
+            let filled = held & (1 << bit) != 0;
+            for dy in 0..BOX_SIZE {
+                for dx in 0..BOX_SIZE {
+                    let edge = dx == 0 || dy == 0 || dx == BOX_SIZE - 1 || dy == BOX_SIZE - 1;
+                    if filled || edge {
+                        framebuffer[(y + 1 + dy) * SCREEN_WIDTH + x + dx] = TEXT_COLOR;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use controller::InputFrame;
+    use emulator::Nes;
+    use testing;
+
+    // The middle of the box for INPUT_ORDER[i] in player's row, with rows for players drawn
+    fn box_center(players: usize, player: usize, i: usize) -> usize {
+        let left = SCREEN_WIDTH - MARGIN - (font::GLYPH_SIZE + INPUT_ORDER.len() * BOX_PITCH);
+        let top = SCREEN_HEIGHT - MARGIN - players * font::GLYPH_SIZE;
+        let x = left + font::GLYPH_SIZE + i * BOX_PITCH + BOX_SIZE / 2;
+        let y = top + player * font::GLYPH_SIZE + 1 + BOX_SIZE / 2;
+        y * SCREEN_WIDTH + x
+    }
+
+    #[test]
+    fn input_display_fills_held_buttons() {
+        let mut frame = vec![0; SCREEN_WIDTH * SCREEN_HEIGHT];
+        // A and Right on controller 1, Select on controller 2
+        let buttons = [0x81, 0x04];
+        draw_input(&mut frame, &buttons);
+        for (player, &held) in buttons.iter().enumerate() {
+            for (i, &bit) in INPUT_ORDER.iter().enumerate() {
+                let expected = if held & (1 << bit) != 0 { TEXT_COLOR } else { BACKGROUND_COLOR };
+                assert_eq!(frame[box_center(2, player, i)], expected, "player {} button {}", player + 1, bit);
+            }
+        }
+        // Nothing is drawn outside the corner
+        assert!(frame[..box_center(2, 0, 0) - 8 * SCREEN_WIDTH].iter().all(|&pixel| pixel == 0));
+    }
+
+    #[test]
+    fn composite_leaves_the_frame_alone() {
+        let mut osd = Osd::new();
+        osd.show_input(&[0xFF]);
+        let frame = vec![0x21; SCREEN_WIDTH * SCREEN_HEIGHT];
+        let drawn = osd.composite(&frame);
+        assert!(frame.iter().all(|&pixel| pixel == 0x21));
+        assert_eq!(drawn[box_center(1, 0, 0)], TEXT_COLOR);
+    }
+
+    // What draw() should give for these messages, newest last
+    fn expected(texts: &[&str]) -> Vec<u8> {
+        let mut frame = vec![0x21; SCREEN_WIDTH * SCREEN_HEIGHT];
+        for (i, text) in texts.iter().rev().enumerate() {
+            let y = SCREEN_HEIGHT - MARGIN - (i + 1) * font::GLYPH_SIZE;
+            font::draw_text(&mut frame, SCREEN_WIDTH, MARGIN, y, text, TEXT_COLOR, Some(BACKGROUND_COLOR));
+        }
+        frame
+    }
+
+    #[test]
+    fn messages_stack_up_newest_lowest() {
+        let mut osd = Osd::new();
+        for text in ["one", "two", "three", "four", "five"].iter() {
+            osd.notify(text, 10);
+        }
+        // Only four fit, so the oldest went
+        assert_eq!(osd.messages().collect::<Vec<_>>(), ["two", "three", "four", "five"]);
+        let frame = vec![0x21; SCREEN_WIDTH * SCREEN_HEIGHT];
+        assert!(osd.composite(&frame) == expected(&["two", "three", "four", "five"]));
+    }
+
+    #[test]
+    fn messages_last_their_duration() {
+        let mut osd = Osd::new();
+        osd.notify("Hello", 3);
+        for _ in 0..3 {
+            osd.tick();
+            assert_eq!(osd.messages().collect::<Vec<_>>(), ["Hello"]);
+        }
+        osd.tick();
+        assert!(osd.is_empty());
+
+        // Repeating the newest restarts it rather than adding another
+        osd.notify("Saved", 2);
+        osd.tick();
+        osd.notify("Saved", 2);
+        osd.tick();
+        osd.tick();
+        assert_eq!(osd.messages().collect::<Vec<_>>(), ["Saved"]);
+        osd.tick();
+        assert!(osd.is_empty());
+    }
+
+    #[test]
+    fn notifications_show_over_frames_and_then_go() {
+        let rom = testing::build_test_rom("loop: JMP loop", None);
+        let mut nes = Nes::builder().rom_bytes(&rom).build().unwrap();
+        let mut quiet = Nes::builder().rom_bytes(&rom).build().unwrap();
+        nes.osd_mut().notify("Hello", 5);
+        for frame in 1..=6 {
+            let output = nes.run_frame(InputFrame::default()).unwrap();
+            let shown = output.with_osd() != output.framebuffer;
+            assert_eq!(shown, frame <= 5, "frame {}", frame);
+            // The PPU's picture is the same as with nothing showing
+            let quiet = quiet.run_frame(InputFrame::default()).unwrap();
+            assert!(output.framebuffer == quiet.framebuffer, "frame {}", frame);
+        }
+        assert_eq!(nes.cpu().memory().ppu.frame_hash(), quiet.cpu().memory().ppu.frame_hash());
+
+        nes.save_state().unwrap();
+        assert_eq!(nes.osd().messages().collect::<Vec<_>>(), ["State saved"]);
+    }
+}
@@ -0,0 +1,176 @@
+// Cropping off the edges of the picture that a TV's bezel would hide. Games don't expect those
+// pixels to be seen and often leave garbage there (SMB3's left column).
+
+use image::Image;
+use ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+use std::fmt;
+use std::str::FromStr;
+
+// Pixels to hide on each edge
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Overscan {
+    pub top: usize,
+    pub bottom: usize,
+    pub left: usize,
+    pub right: usize,
+}
+
+impl Overscan {
+    pub const NONE: Overscan = Overscan { top: 0, bottom: 0, left: 0, right: 0 };
+    // What a typical NTSC set hides: the top and bottom 8 lines and nothing at the sides
+    pub const NTSC: Overscan = Overscan { top: 8, bottom: 8, left: 0, right: 0 };
+
+    pub fn width(&self) -> usize {
+        SCREEN_WIDTH.saturating_sub(self.left + self.right)
+    }
+
+    pub fn height(&self) -> usize {
+        SCREEN_HEIGHT.saturating_sub(self.top + self.bottom)
+    }
+}
+
+// none, ntsc, or top,bottom,left,right
+impl FromStr for Overscan {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Overscan, &'static str> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => return Ok(Overscan::NONE),
+            "ntsc" => return Ok(Overscan::NTSC),
+            _ => {},
+        }
+        let edges = s.split(',').map(|edge| edge.trim().parse()).collect::<Result<Vec<usize>, _>>()
+            .map_err(|_| "overscan edges must be numbers")?;
+        if edges.len() != 4 {
+            return Err("overscan must be none, ntsc or top,bottom,left,right")
+        }
+        let overscan = Overscan { top: edges[0], bottom: edges[1], left: edges[2], right: edges[3] };
+        if overscan.width() == 0 || overscan.height() == 0 {
+            return Err("overscan crops away the whole picture")
+        }
+        Ok(overscan)
+    }
+}
+
+// In the form FromStr takes
+impl fmt::Display for Overscan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Overscan::NONE => write!(f, "none"),
+            Overscan::NTSC => write!(f, "ntsc"),
+            Overscan { top, bottom, left, right } => write!(f, "{},{},{},{}", top, bottom, left, right),
+        }
+    }
+}
+
+// A window onto a SCREEN_WIDTH x SCREEN_HEIGHT framebuffer with the overscan cut off. Nothing
+// is copied: rows are stride pixels apart in the original.
+pub struct CroppedFrame<'a> {
+    // Starts at the first visible pixel
+    pixels: &'a [u8],
+    width: usize,
+    height: usize,
+}
+
+impl<'a> CroppedFrame<'a> {
+    pub fn new(framebuffer: &'a [u8], overscan: Overscan) -> CroppedFrame<'a> {
+        let (width, height) = (overscan.width(), overscan.height());
+        let start = if width == 0 || height == 0 { 0 } else { overscan.top * SCREEN_WIDTH + overscan.left };
+        CroppedFrame {
+            pixels: &framebuffer[start..],
+            width: width,
+            height: height,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    // Distance between the starts of two rows
+    pub fn stride(&self) -> usize {
+        SCREEN_WIDTH
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> u8 {
+        self.row(y)[x]
+    }
+
+    pub fn row(&self, y: usize) -> &'a [u8] {
+        let start = y * self.stride();
+        &self.pixels[start..start + self.width]
+    }
+
+    pub fn rows(&self) -> impl Iterator<Item = &'a [u8]> + '_ {
+        (0..self.height).map(move |y| self.row(y))
+    }
+
+    // A copy, for writing out
+    pub fn to_image(&self) -> Image {
+        let mut pixels = Vec::with_capacity(self.width * self.height);
+        for row in self.rows() {
+            pixels.extend_from_slice(row);
+        }
+        Image { width: self.width, height: self.height, pixels: pixels }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Each pixel holds a mix of its coordinates, so every crop reads something different
+    fn framebuffer() -> Vec<u8> {
+        (0..SCREEN_WIDTH * SCREEN_HEIGHT).map(|i| ((i % SCREEN_WIDTH) * 3 + (i / SCREEN_WIDTH) * 7) as u8).collect()
+    }
+
+    fn pixel(x: usize, y: usize) -> u8 {
+        (x * 3 + y * 7) as u8
+    }
+
+    #[test]
+    fn cropped_views_start_inside_the_edges() {
+        let framebuffer = framebuffer();
+        for &(overscan, width, height) in [
+            (Overscan::NONE, 256, 240),
+            (Overscan::NTSC, 256, 224),
+            (Overscan { top: 8, bottom: 8, left: 8, right: 8 }, 240, 224),
+            (Overscan { top: 0, bottom: 0, left: 16, right: 0 }, 240, 240),
+        ].iter() {
+            let frame = CroppedFrame::new(&framebuffer, overscan);
+            assert_eq!((frame.width(), frame.height(), frame.stride()), (width, height, SCREEN_WIDTH), "{}", overscan);
+            assert_eq!(frame.get(0, 0), pixel(overscan.left, overscan.top), "{}", overscan);
+            assert_eq!(frame.get(width - 1, height - 1), pixel(SCREEN_WIDTH - 1 - overscan.right, SCREEN_HEIGHT - 1 - overscan.bottom));
+            assert_eq!(frame.rows().count(), height);
+            assert!(frame.rows().all(|row| row.len() == width));
+
+            let image = frame.to_image();
+            assert_eq!((image.width, image.height, image.pixels.len()), (width, height, width * height));
+            assert_eq!(image.pixels[width + 1], pixel(overscan.left + 1, overscan.top + 1));
+        }
+    }
+
+    #[test]
+    fn specs_parse_and_print() {
+        for &(spec, overscan) in [
+            ("none", Overscan::NONE),
+            ("NTSC", Overscan::NTSC),
+            ("8, 8, 8, 0", Overscan { top: 8, bottom: 8, left: 8, right: 0 }),
+            ("0,0,0,0", Overscan::NONE),
+        ].iter() {
+            assert_eq!(spec.parse::<Overscan>(), Ok(overscan), "{}", spec);
+        }
+        assert_eq!(Overscan { top: 1, bottom: 2, left: 3, right: 4 }.to_string(), "1,2,3,4");
+        assert_eq!(Overscan::NTSC.to_string(), "ntsc");
+
+        assert_eq!("8,8".parse::<Overscan>(), Err("overscan must be none, ntsc or top,bottom,left,right"));
+        assert_eq!("8,8,-1,0".parse::<Overscan>(), Err("overscan edges must be numbers"));
+        assert_eq!("120,120,0,0".parse::<Overscan>(), Err("overscan crops away the whole picture"));
+        assert_eq!("0,0,200,56".parse::<Overscan>(), Err("overscan crops away the whole picture"));
+    }
+}
@@ -0,0 +1,59 @@
+// RGB for each of the 64 colors the PPU can output
+pub type Palette = [[u8; 3]; 64];
+
+// The 2C02's 64 output colors as RGB, indexed by the 6-bit value stored in palette RAM
+pub const SYSTEM_PALETTE: Palette = [
+    [0x54, 0x54, 0x54], [0x00, 0x1E, 0x74], [0x08, 0x10, 0x90], [0x30, 0x00, 0x88],
+    [0x44, 0x00, 0x64], [0x5C, 0x00, 0x30], [0x54, 0x04, 0x00], [0x3C, 0x18, 0x00],
+    [0x20, 0x2A, 0x00], [0x08, 0x3A, 0x00], [0x00, 0x40, 0x00], [0x00, 0x3C, 0x00],
+    [0x00, 0x32, 0x3C], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00],
+    [0x98, 0x96, 0x98], [0x08, 0x4C, 0xC4], [0x30, 0x32, 0xEC], [0x5C, 0x1E, 0xE4],
+    [0x88, 0x14, 0xB0], [0xA0, 0x14, 0x64], [0x98, 0x22, 0x20], [0x78, 0x3C, 0x00],
+    [0x54, 0x5A, 0x00], [0x28, 0x72, 0x00], [0x08, 0x7C, 0x00], [0x00, 0x76, 0x28],
+    [0x00, 0x66, 0x78], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00],
+    [0xEC, 0xEE, 0xEC], [0x4C, 0x9A, 0xEC], [0x78, 0x7C, 0xEC], [0xB0, 0x62, 0xEC],
+    [0xE4, 0x54, 0xEC], [0xEC, 0x58, 0xB4], [0xEC, 0x6A, 0x64], [0xD4, 0x88, 0x20],
+    [0xA0, 0xAA, 0x00], [0x74, 0xC4, 0x00], [0x4C, 0xD0, 0x20], [0x38, 0xCC, 0x6C],
+    [0x38, 0xB4, 0xCC], [0x3C, 0x3C, 0x3C], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00],
+    [0xEC, 0xEE, 0xEC], [0xA8, 0xCC, 0xEC], [0xBC, 0xBC, 0xEC], [0xD4, 0xB2, 0xEC],
+    [0xEC, 0xAE, 0xEC], [0xEC, 0xAE, 0xD4], [0xEC, 0xB4, 0xB0], [0xE4, 0xC4, 0x90],
+    [0xCC, 0xD2, 0x78], [0xB4, 0xDE, 0x78], [0xA8, 0xE2, 0x90], [0x98, 0xE2, 0xB4],
+    [0xA0, 0xD6, 0xE4], [0xA0, 0xA2, 0xA0], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00],
+];
+
+// Four shades for viewing 2-bit pattern data without any palette RAM
+pub const GREYSCALE: [[u8; 3]; 4] = [
+    [0x00, 0x00, 0x00], [0x55, 0x55, 0x55], [0xAA, 0xAA, 0xAA], [0xFF, 0xFF, 0xFF],
+];
+
+// Resolve a 4-entry palette group (as stored in palette RAM) to RGB
+pub fn group_rgb(group: &[u8; 4]) -> [[u8; 3]; 4] {
+    let mut rgb = [[0; 3]; 4];
+    for (out, &color) in rgb.iter_mut().zip(group.iter()) {
+        *out = SYSTEM_PALETTE[(color & 0x3F) as usize];
+    }
+    return rgb
+}
+
+// Parse a .pal file: 64 RGB triples. Files with emphasis variants (512 entries) only have
+// their first 64 colors used.
+// NES color indices as 8-bit RGBA, ready for a canvas or texture
+pub fn to_rgba(indices: &[u8], palette: &Palette) -> Vec<u8> {
+    let mut rgba = Vec::with_capacity(indices.len() * 4);
+    for &color in indices.iter() {
+        rgba.extend_from_slice(&palette[(color & 0x3F) as usize]);
+        rgba.push(0xFF);
+    }
+    return rgba
+}
+
+pub fn from_pal(data: &[u8]) -> Option<Palette> {
+    if data.len() < 64 * 3 {
+        return None
+    }
+    let mut palette = [[0; 3]; 64];
+    for (color, rgb) in palette.iter_mut().zip(data.chunks(3)) {
+        color.copy_from_slice(rgb);
+    }
+    Some(palette)
+}
@@ -0,0 +1,355 @@
+// ROM hacks and translations are shipped as patches against the original dump. These apply
+// IPS and BPS patches to a file's bytes in memory, before anything parses them.
+
+use hash;
+
+use std::fmt;
+use std::io;
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const BPS_MAGIC: &[u8] = b"BPS1";
+// Source, target and patch CRC32s
+const BPS_FOOTER_SIZE: usize = 12;
+
+#[derive(Debug)]
+pub enum PatchError {
+    Io(io::Error),
+    // Neither an IPS nor a BPS patch
+    BadMagic,
+    // The patch ends partway through a record
+    Truncated,
+    // A BPS patch for a file of a different size
+    SourceSize { expected: usize, found: usize },
+    // Which CRC32 didn't match: the file being patched, the result, or the patch itself
+    SourceChecksum { expected: u32, found: u32 },
+    TargetChecksum { expected: u32, found: u32 },
+    PatchChecksum { expected: u32, found: u32 },
+    // A BPS action reads or writes outside the files
+    OutOfRange,
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PatchError::Io(ref e) => write!(f, "{}", e),
+            PatchError::BadMagic => write!(f, "not an IPS or BPS patch"),
+            PatchError::Truncated => write!(f, "patch ends partway through a record"),
+            PatchError::SourceSize { expected, found } =>
+                write!(f, "patch is for a {} byte file, this one is {} bytes", expected, found),
+            PatchError::SourceChecksum { expected, found } =>
+                write!(f, "source checksum mismatch: patch expects CRC32 {:08x}, file is {:08x}", expected, found),
+            PatchError::TargetChecksum { expected, found } =>
+                write!(f, "target checksum mismatch: patch expects CRC32 {:08x}, result is {:08x}", expected, found),
+            PatchError::PatchChecksum { expected, found } =>
+                write!(f, "patch checksum mismatch: footer says CRC32 {:08x}, patch is {:08x}", expected, found),
+            PatchError::OutOfRange => write!(f, "patch copies from outside the file"),
+        }
+    }
+}
+
+impl From<io::Error> for PatchError {
+    fn from(e: io::Error) -> PatchError {
+        PatchError::Io(e)
+    }
+}
+
+// Whichever kind of patch this is
+pub fn apply(rom: &mut Vec<u8>, patch: &[u8]) -> Result<(), PatchError> {
+    if patch.starts_with(IPS_MAGIC) {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(BPS_MAGIC) {
+        apply_bps(rom, patch)
+    } else {
+        Err(PatchError::BadMagic)
+    }
+}
+
+// "PATCH", then records of a 3-byte offset and 2-byte length followed by that many bytes, or a
+// zero length and a 2-byte count of one repeated byte (RLE). "EOF" ends the patch, optionally
+// followed by a 3-byte size to truncate the file to. Writes past the end grow the file.
+pub fn apply_ips(rom: &mut Vec<u8>, patch: &[u8]) -> Result<(), PatchError> {
+    if !patch.starts_with(IPS_MAGIC) {
+        return Err(PatchError::BadMagic)
+    }
+    let mut pos = IPS_MAGIC.len();
+    let take = |pos: &mut usize, len: usize| -> Result<&[u8], PatchError> {
+        let bytes = patch.get(*pos..*pos + len).ok_or(PatchError::Truncated)?;
+        *pos += len;
+        Ok(bytes)
+    };
+    loop {
+        // $454F46 is a legal offset that spells "EOF", so it only ends the patch when nothing
+        // but a truncation size could follow
+        let rest = patch.len() - pos;
+        if patch[pos..].starts_with(IPS_EOF) && (rest == 3 || rest == 6) {
+            if rest == 6 {
+                let size = &patch[pos + 3..];
+                rom.truncate((size[0] as usize) << 16 | (size[1] as usize) << 8 | size[2] as usize);
+            }
+            return Ok(())
+        }
+        let record = take(&mut pos, 5)?;
+        let offset = (record[0] as usize) << 16 | (record[1] as usize) << 8 | record[2] as usize;
+        let len = (record[3] as usize) << 8 | record[4] as usize;
+        if len == 0 {
+            let rle = take(&mut pos, 3)?;
+            let count = (rle[0] as usize) << 8 | rle[1] as usize;
+            write_at(rom, offset, &vec![rle[2]; count]);
+        } else {
+            write_at(rom, offset, take(&mut pos, len)?);
+        }
+    }
+}
+
+fn write_at(rom: &mut Vec<u8>, offset: usize, data: &[u8]) {
+    if rom.len() < offset + data.len() {
+        rom.resize(offset + data.len(), 0);
+    }
+    rom[offset..offset + data.len()].copy_from_slice(data);
+}
+
+// BPS numbers are little-endian groups of 7 bits, the last with bit 7 set, each group after
+// the first implicitly adding one so every number has a single encoding
+fn read_number(patch: &[u8], pos: &mut usize) -> Result<usize, PatchError> {
+    let (mut value, mut shift) = (0usize, 1usize);
+    loop {
+        let byte = *patch.get(*pos).ok_or(PatchError::Truncated)?;
+        *pos += 1;
+        value = value.checked_add((byte & 0x7F) as usize * shift).ok_or(PatchError::OutOfRange)?;
+        if byte & 0x80 != 0 {
+            return Ok(value)
+        }
+        shift = shift.checked_shl(7).ok_or(PatchError::OutOfRange)?;
+        value += shift;
+    }
+}
+
+// Offsets in copy actions are relative to the last copy's end, sign in bit 0
+fn read_offset(patch: &[u8], pos: &mut usize, from: usize) -> Result<usize, PatchError> {
+    let data = read_number(patch, pos)?;
+    let delta = data >> 1;
+    let offset = if data & 1 != 0 { from.checked_sub(delta) } else { from.checked_add(delta) };
+    offset.ok_or(PatchError::OutOfRange)
+}
+
+// "BPS1", the source and target sizes, metadata to skip, then actions building the target
+// from the source, the patch and the target so far. Every checksum in the footer is checked,
+// and rom is only replaced once they all match.
+pub fn apply_bps(rom: &mut Vec<u8>, patch: &[u8]) -> Result<(), PatchError> {
+    if !patch.starts_with(BPS_MAGIC) {
+        return Err(PatchError::BadMagic)
+    }
+    if patch.len() < BPS_MAGIC.len() + BPS_FOOTER_SIZE {
+        return Err(PatchError::Truncated)
+    }
+    let footer = &patch[patch.len() - BPS_FOOTER_SIZE..];
+    let crc = |i: usize| u32::from_le_bytes([footer[i], footer[i + 1], footer[i + 2], footer[i + 3]]);
+    let (source_crc, target_crc, patch_crc) = (crc(0), crc(4), crc(8));
+    let found = hash::crc32(&patch[..patch.len() - 4]);
+    if found != patch_crc {
+        return Err(PatchError::PatchChecksum { expected: patch_crc, found: found })
+    }
+
+    let actions_end = patch.len() - BPS_FOOTER_SIZE;
+    let patch = &patch[..actions_end];
+    let mut pos = BPS_MAGIC.len();
+    let source_size = read_number(patch, &mut pos)?;
+    let target_size = read_number(patch, &mut pos)?;
+    let metadata_size = read_number(patch, &mut pos)?;
+    pos = pos.checked_add(metadata_size).filter(|&end| end <= actions_end).ok_or(PatchError::Truncated)?;
+
+    if rom.len() != source_size {
+        return Err(PatchError::SourceSize { expected: source_size, found: rom.len() })
+    }
+    let found = hash::crc32(rom);
+    if found != source_crc {
+        return Err(PatchError::SourceChecksum { expected: source_crc, found: found })
+    }
+
+    let source = &rom[..];
+    let mut target = Vec::with_capacity(target_size);
+    let (mut source_offset, mut target_offset) = (0, 0);
+    while pos < actions_end {
+        let data = read_number(patch, &mut pos)?;
+        let len = (data >> 2) + 1;
+        if target.len() + len > target_size {
+            return Err(PatchError::OutOfRange)
+        }
+        match data & 3 {
+            // SourceRead: the source bytes at the same position
+            0 => {
+                let at = target.len();
+                target.extend_from_slice(source.get(at..at + len).ok_or(PatchError::OutOfRange)?);
+            },
+            // TargetRead: bytes from the patch
+            1 => {
+                target.extend_from_slice(patch.get(pos..pos + len).ok_or(PatchError::Truncated)?);
+                pos += len;
+            },
+            // SourceCopy: source bytes from anywhere
+            2 => {
+                source_offset = read_offset(patch, &mut pos, source_offset)?;
+                target.extend_from_slice(source.get(source_offset..source_offset + len).ok_or(PatchError::OutOfRange)?);
+                source_offset += len;
+            },
+            // TargetCopy: bytes already written, one at a time since the ranges may overlap
+            _ => {
+                target_offset = read_offset(patch, &mut pos, target_offset)?;
+                for _ in 0..len {
+                    let byte = *target.get(target_offset).ok_or(PatchError::OutOfRange)?;
+                    target.push(byte);
+                    target_offset += 1;
+                }
+            },
+        }
+    }
+
+    let found = hash::crc32(&target);
+    if target.len() != target_size || found != target_crc {
+        return Err(PatchError::TargetChecksum { expected: target_crc, found: found })
+    }
+    *rom = target;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source() -> Vec<u8> {
+        (0..0x100).map(|i| i as u8).collect()
+    }
+
+    fn ips(records: &[(usize, &[u8])], rle: &[(usize, u16, u8)], eof: &[u8]) -> Vec<u8> {
+        let mut patch = IPS_MAGIC.to_vec();
+        for &(offset, data) in records.iter() {
+            patch.extend_from_slice(&[(offset >> 16) as u8, (offset >> 8) as u8, offset as u8]);
+            patch.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            patch.extend_from_slice(data);
+        }
+        for &(offset, count, byte) in rle.iter() {
+            patch.extend_from_slice(&[(offset >> 16) as u8, (offset >> 8) as u8, offset as u8, 0, 0]);
+            patch.extend_from_slice(&count.to_be_bytes());
+            patch.push(byte);
+        }
+        patch.extend_from_slice(eof);
+        patch
+    }
+
+    #[test]
+    fn ips_records_and_rle() {
+        let mut rom = source();
+        apply(&mut rom, &ips(&[(0x10, b"ABC")], &[(0x20, 5, 0x7E)], b"EOF")).unwrap();
+        assert_eq!(&rom[0x10..0x13], b"ABC");
+        assert_eq!(&rom[0x1F..0x26], [0x1F, 0x7E, 0x7E, 0x7E, 0x7E, 0x7E, 0x25]);
+        assert_eq!(rom.len(), 0x100);
+        // Writes past the end grow the file
+        apply(&mut rom, &ips(&[(0xFF, b"xyz")], &[], b"EOF")).unwrap();
+        assert_eq!(&rom[0xFE..], b"\xFExyz");
+    }
+
+    #[test]
+    fn ips_record_at_the_eof_offset() {
+        // $454F46 spells "EOF", but with a record after it it's an offset
+        let patch = ips(&[(0x454F46, b"XY")], &[], b"EOF");
+        assert_eq!(&patch[5..8], b"EOF");
+        let mut rom = source();
+        apply_ips(&mut rom, &patch).unwrap();
+        assert_eq!(rom.len(), 0x454F48);
+        assert_eq!(&rom[0x454F46..], b"XY");
+
+        // Then cut back to the original size
+        let mut rom = source();
+        apply_ips(&mut rom, &ips(&[(0x454F46, b"XY"), (0x40, b"!")], &[], b"EOF\x00\x01\x00")).unwrap();
+        assert_eq!(rom.len(), 0x100);
+        assert_eq!(rom[0x40], b'!');
+    }
+
+    #[test]
+    fn ips_errors() {
+        let mut rom = source();
+        let mut patch = ips(&[(0x10, b"ABCD")], &[], b"");
+        patch.truncate(patch.len() - 1);
+        assert!(matches!(apply_ips(&mut rom, &patch), Err(PatchError::Truncated)));
+        assert!(matches!(apply(&mut rom, b"NOTAPATCH"), Err(PatchError::BadMagic)));
+    }
+
+    fn number(patch: &mut Vec<u8>, mut n: usize) {
+        loop {
+            let low = (n & 0x7F) as u8;
+            n >>= 7;
+            if n == 0 {
+                patch.push(0x80 | low);
+                return
+            }
+            patch.push(low);
+            n -= 1;
+        }
+    }
+
+    // Builds a 0x40 byte target: source[..0x30], DEADBEEF, ZZZZ and source[0x10..0x18]. The
+    // footer's checksums are for source and the target it makes, unless overridden.
+    fn bps(source: &[u8], target_crc: Option<u32>) -> (Vec<u8>, Vec<u8>) {
+        let mut patch = BPS_MAGIC.to_vec();
+        number(&mut patch, source.len());
+        number(&mut patch, 0x40);
+        number(&mut patch, 3);
+        patch.extend_from_slice(b"xml");
+        // SourceRead
+        number(&mut patch, (0x30 - 1) << 2);
+        // TargetRead
+        number(&mut patch, (4 - 1) << 2 | 1);
+        patch.extend_from_slice(&[0xDE, 0xAD, 0xBE, 0xEF]);
+        number(&mut patch, 1);
+        patch.push(b'Z');
+        // TargetCopy from 0x34, overlapping what it writes
+        number(&mut patch, (3 - 1) << 2 | 3);
+        number(&mut patch, 0x34 << 1);
+        // SourceCopy from 0x10
+        number(&mut patch, (8 - 1) << 2 | 2);
+        number(&mut patch, 0x10 << 1);
+
+        let mut target = source[..0x30].to_vec();
+        target.extend_from_slice(&[0xDE, 0xAD, 0xBE, 0xEF, b'Z', b'Z', b'Z', b'Z']);
+        target.extend_from_slice(&source[0x10..0x18]);
+        patch.extend_from_slice(&hash::crc32(source).to_le_bytes());
+        patch.extend_from_slice(&target_crc.unwrap_or(hash::crc32(&target)).to_le_bytes());
+        let crc = hash::crc32(&patch);
+        patch.extend_from_slice(&crc.to_le_bytes());
+        (patch, target)
+    }
+
+    #[test]
+    fn bps_runs_every_action() {
+        let (patch, target) = bps(&source(), None);
+        let mut rom = source();
+        apply(&mut rom, &patch).unwrap();
+        assert_eq!(rom, target);
+        assert_eq!(&rom[0x30..0x38], b"\xDE\xAD\xBE\xEFZZZZ");
+    }
+
+    #[test]
+    fn bps_checksums_are_checked() {
+        let (patch, _) = bps(&source(), None);
+        let mut other = source();
+        other[0x80] ^= 0xFF;
+        let err = apply_bps(&mut other, &patch).unwrap_err();
+        assert!(matches!(err, PatchError::SourceChecksum { .. }));
+        assert!(err.to_string().starts_with("source checksum mismatch: patch expects CRC32 "));
+        // Nothing was written
+        assert_eq!(other[0x80], 0x7F);
+
+        let mut flipped = patch.clone();
+        flipped[10] ^= 1;
+        let err = apply_bps(&mut source(), &flipped).unwrap_err();
+        assert!(err.to_string().starts_with("patch checksum mismatch: footer says CRC32 "), "{}", err);
+
+        let (wrong_target, _) = bps(&source(), Some(0x12345678));
+        let err = apply_bps(&mut source(), &wrong_target).unwrap_err();
+        assert!(matches!(err, PatchError::TargetChecksum { expected: 0x12345678, .. }));
+
+        let err = apply_bps(&mut vec![0; 0x80], &patch).unwrap_err();
+        assert_eq!(err.to_string(), "patch is for a 256 byte file, this one is 128 bytes");
+    }
+}
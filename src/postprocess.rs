@@ -0,0 +1,238 @@
+// Filters run on the finished picture on its way to the screen: blending each frame with the
+// one before, for games that flicker sprites on alternate frames to fake transparency, and an
+// approximation of how the console looks over composite video. They work on an RGB copy, so
+// the PPU's framebuffer, and frame_hash with it, stay exactly what the PPU drew.
+//
+// The filters themselves are plain functions from frames to a frame; PostProcessor just keeps
+// the previous frame around for blending.
+
+use overscan::CroppedFrame;
+use palette::Palette;
+
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::str::FromStr;
+
+// The NTSC filter's output is this many times the input's size each way. The extra columns give
+// chroma room to smear, the extra rows are the gaps between scanlines.
+pub const NTSC_SCALE: usize = 2;
+// How many half-pixel samples chroma is averaged over, and how far it lags luma. A composite
+// signal carries color at a fraction of luma's bandwidth, so color bleeds past edges.
+const CHROMA_TAPS: usize = 6;
+const CHROMA_DELAY: usize = 1;
+// Brightness of the dark line between scanlines
+const SCANLINE_LEVEL: f32 = 0.75;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PostProcess {
+    #[default]
+    None,
+    // Each pixel is the average of this frame and the last
+    FrameBlend,
+    NtscComposite,
+}
+
+impl PostProcess {
+    // The next filter along, for a hotkey that steps through them
+    pub fn next(self) -> PostProcess {
+        match self {
+            PostProcess::None => PostProcess::FrameBlend,
+            PostProcess::FrameBlend => PostProcess::NtscComposite,
+            PostProcess::NtscComposite => PostProcess::None,
+        }
+    }
+}
+
+impl FromStr for PostProcess {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<PostProcess, &'static str> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(PostProcess::None),
+            "blend" => Ok(PostProcess::FrameBlend),
+            "ntsc" => Ok(PostProcess::NtscComposite),
+            _ => Err("post-processing must be none, blend or ntsc"),
+        }
+    }
+}
+
+impl fmt::Display for PostProcess {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PostProcess::None => write!(f, "none"),
+            PostProcess::FrameBlend => write!(f, "blend"),
+            PostProcess::NtscComposite => write!(f, "ntsc"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RgbFrame {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<[u8; 3]>,
+}
+
+impl RgbFrame {
+    // Look a frame's color indices up in palette
+    pub fn from_indices(frame: &CroppedFrame, palette: &Palette) -> RgbFrame {
+        let mut pixels = Vec::with_capacity(frame.width() * frame.height());
+        for row in frame.rows() {
+            pixels.extend(row.iter().map(|&color| palette[(color & 0x3F) as usize]));
+        }
+        RgbFrame { width: frame.width(), height: frame.height(), pixels: pixels }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> [u8; 3] {
+        self.pixels[y * self.width + x]
+    }
+
+    // 8-bit RGBA, ready for a canvas or texture
+    pub fn to_rgba(&self) -> Vec<u8> {
+        let mut rgba = Vec::with_capacity(self.pixels.len() * 4);
+        for rgb in self.pixels.iter() {
+            rgba.extend_from_slice(rgb);
+            rgba.push(0xFF);
+        }
+        return rgba
+    }
+
+    // Write as a binary PPM (P6)
+    pub fn write_ppm<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        write!(out, "P6\n{} {}\n255\n", self.width, self.height)?;
+        out.write_all(&self.pixels.concat())
+    }
+}
+
+// The average of two frames the same size, rounding up
+pub fn blend(current: &RgbFrame, previous: &RgbFrame) -> RgbFrame {
+    assert!(current.width == previous.width && current.height == previous.height, "blending frames of different sizes");
+    let pixels = current.pixels.iter().zip(previous.pixels.iter()).map(|(a, b)| {
+        [0, 1, 2].map(|c| ((a[c] as u16 + b[c] as u16).div_ceil(2)) as u8)
+    }).collect();
+    RgbFrame { width: current.width, height: current.height, pixels: pixels }
+}
+
+fn to_yiq(rgb: [u8; 3]) -> [f32; 3] {
+    let [r, g, b] = rgb.map(|c| c as f32);
+    [0.299 * r + 0.587 * g + 0.114 * b,
+     0.596 * r - 0.274 * g - 0.322 * b,
+     0.211 * r - 0.523 * g + 0.312 * b]
+}
+
+fn from_yiq(yiq: [f32; 3], level: f32) -> [u8; 3] {
+    let [y, i, q] = yiq;
+    let rgb = [y + 0.956 * i + 0.621 * q, y - 0.272 * i - 0.647 * q, y - 1.106 * i + 1.703 * q];
+    rgb.map(|c| (c * level).round().clamp(0.0, 255.0) as u8)
+}
+
+// A rough composite look: each line is sampled at twice the width, luma lightly blurred and
+// chroma averaged over a wide, lagging window so colors fringe at edges, then every line is
+// followed by a darker copy of itself for the scanline gaps. The output is NTSC_SCALE times
+// the input's size each way.
+pub fn ntsc_composite(frame: &RgbFrame) -> RgbFrame {
+    let width = frame.width * NTSC_SCALE;
+    let mut pixels = Vec::with_capacity(width * frame.height * NTSC_SCALE);
+    for y in 0..frame.height {
+        let samples: Vec<[f32; 3]> = (0..width).map(|x| to_yiq(frame.get(x / NTSC_SCALE, y))).collect();
+        // Past the edges the signal holds its last value
+        let sample = |x: isize| samples[x.clamp(0, width as isize - 1) as usize];
+        let line: Vec<[f32; 3]> = (0..width as isize).map(|x| {
+            let luma = (sample(x - 1)[0] + 2.0 * sample(x)[0] + sample(x + 1)[0]) / 4.0;
+            let start = x - (CHROMA_TAPS / 2 + CHROMA_DELAY) as isize;
+            let (mut i, mut q) = (0.0, 0.0);
+            for tap in 0..CHROMA_TAPS as isize {
+                let [_, si, sq] = sample(start + tap);
+                i += si;
+                q += sq;
+            }
+            [luma, i / CHROMA_TAPS as f32, q / CHROMA_TAPS as f32]
+        }).collect();
+        pixels.extend(line.iter().map(|&yiq| from_yiq(yiq, 1.0)));
+        pixels.extend(line.iter().map(|&yiq| from_yiq(yiq, SCANLINE_LEVEL)));
+    }
+    RgbFrame { width: width, height: frame.height * NTSC_SCALE, pixels: pixels }
+}
+
+// Runs frames through the chosen filter, which can be changed between any two frames
+#[derive(Default)]
+pub struct PostProcessor {
+    pub mode: PostProcess,
+    // The last frame given, unfiltered
+    previous: Option<RgbFrame>,
+}
+
+impl PostProcessor {
+    pub fn new(mode: PostProcess) -> PostProcessor {
+        PostProcessor { mode: mode, previous: None }
+    }
+
+    // Filter the next frame. Blending starts with the second frame, or the first after the
+    // picture changes size.
+    pub fn process(&mut self, frame: RgbFrame) -> RgbFrame {
+        let out = match self.mode {
+            PostProcess::None => frame.clone(),
+            PostProcess::FrameBlend => match self.previous {
+                Some(ref previous) if previous.width == frame.width && previous.height == frame.height =>
+                    blend(&frame, previous),
+                _ => frame.clone(),
+            },
+            PostProcess::NtscComposite => ntsc_composite(&frame),
+        };
+        self.previous = Some(frame);
+        return out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flat(width: usize, height: usize, rgb: [u8; 3]) -> RgbFrame {
+        RgbFrame { width: width, height: height, pixels: vec![rgb; width * height] }
+    }
+
+    // Red on the left half, blue on the right
+    fn red_blue() -> RgbFrame {
+        let mut frame = flat(8, 2, [255, 0, 0]);
+        for y in 0..2 {
+            for x in 4..8 {
+                frame.pixels[y * 8 + x] = [0, 0, 255];
+            }
+        }
+        frame
+    }
+
+    #[test]
+    fn blending_black_and_white_gives_gray() {
+        let (black, white) = (flat(4, 4, [0; 3]), flat(4, 4, [255; 3]));
+        assert_eq!(blend(&white, &black), flat(4, 4, [128; 3]));
+
+        // The first frame has nothing to blend with, then each blends with the one before
+        let mut processor = PostProcessor::new(PostProcess::FrameBlend);
+        assert_eq!(processor.process(white.clone()), white);
+        assert_eq!(processor.process(black.clone()), flat(4, 4, [128; 3]));
+        assert_eq!(processor.process(white.clone()), flat(4, 4, [128; 3]));
+        processor.mode = PostProcess::None;
+        assert_eq!(processor.process(black.clone()), black);
+    }
+
+    #[test]
+    fn ntsc_filter_doubles_the_size_and_fringes_edges() {
+        let out = ntsc_composite(&red_blue());
+        assert_eq!((out.width, out.height, out.pixels.len()), (16, 4, 64));
+        // Flat either side of the edge, with the color smeared over samples 7-11
+        assert_eq!([out.get(0, 0), out.get(6, 0), out.get(12, 0), out.get(15, 0)],
+                   [[255, 0, 0], [255, 0, 0], [0, 0, 255], [0, 0, 255]]);
+        assert_eq!([out.get(7, 0), out.get(8, 0), out.get(9, 0), out.get(10, 0), out.get(11, 0)],
+                   [[209, 0, 38], [150, 0, 65], [104, 0, 104], [69, 0, 154], [35, 0, 205]]);
+        // The scanline gap under each line is darker
+        assert_eq!([out.get(0, 1), out.get(9, 1), out.get(15, 1)], [[191, 0, 0], [78, 0, 78], [0, 0, 192]]);
+        assert_eq!(out.get(9, 2), out.get(9, 0));
+
+        let gray = ntsc_composite(&flat(8, 2, [120, 120, 120]));
+        assert!(gray.pixels.chunks(16).step_by(2).flatten().all(|&rgb| rgb == [120, 120, 120]));
+        assert!(gray.pixels.chunks(16).skip(1).step_by(2).flatten().all(|&rgb| rgb == [90, 90, 90]));
+    }
+}
@@ -0,0 +1,2308 @@
+use chr;
+use image::Image;
+use log_limit::LogLimiter;
+use mapper::{FetchPhase, NametableSource, SharedMapper};
+use mem::RamInit;
+use overscan::{CroppedFrame, Overscan};
+use palette;
+use region::TimingConfig;
+use state::StateError;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use std;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::path::Path;
+use std::str::FromStr;
+
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+
+const DOTS_PER_SCANLINE: u16 = 341;
+
+// Bits for PPU::ctrl ($2000)
+const CTRL_NAMETABLE: u8 = 0x03;
+const CTRL_INCREMENT_32: u8 = 1 << 2;
+const CTRL_SPRITE_TABLE: u8 = 1 << 3;
+const CTRL_BG_TABLE: u8 = 1 << 4;
+const CTRL_SPRITE_SIZE: u8 = 1 << 5;
+const CTRL_NMI_ENABLE: u8 = 1 << 7;
+
+// Bits for PPU::mask ($2001)
+const MASK_SHOW_BG: u8 = 1 << 3;
+const MASK_SHOW_SPRITES: u8 = 1 << 4;
+
+// Bits for PPU::status ($2002)
+const STATUS_OVERFLOW: u8 = 1 << 5;
+const STATUS_SPRITE_ZERO: u8 = 1 << 6;
+const STATUS_VBLANK: u8 = 1 << 7;
+
+// The CPU only notices a new NMI this many dots after it's raised. Until then, reading $2002
+// or turning NMI off takes it back, which is how a read right at the start of vblank
+// suppresses that frame's NMI.
+const NMI_WINDOW_DOTS: u8 = 2;
+
+// Bits for a sprite's attribute byte in OAM
+const SPRITE_PALETTE: u8 = 0x03;
+const SPRITE_BEHIND_BG: u8 = 1 << 5;
+const SPRITE_FLIP_H: u8 = 1 << 6;
+const SPRITE_FLIP_V: u8 = 1 << 7;
+
+// Sprites the hardware can fetch for one scanline
+const SPRITES_PER_LINE: usize = 8;
+// Bits of an attribute byte that aren't wired up in OAM and read back as 0
+const SPRITE_UNUSED_BITS: u8 = 0x1C;
+
+// How closely to follow the hardware's quirks, for the PPU around OAM access and for the bus
+// around DMC fetches
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AccuracyLevel {
+    // $2004 reads return OAM and writes during rendering are dropped. Controller reads are
+    // never disturbed by DMC fetches.
+    #[default]
+    Simple,
+    // Also: $2004 reads during rendering see secondary OAM, writes during rendering bump
+    // OAMADDR, OAMADDR is cleared during sprite fetches, and rendering starting with OAMADDR
+    // at 8 or more copies that row of OAM over the first sprites. A DMC fetch during a
+    // controller read clocks the controller an extra time.
+    Accurate,
+}
+
+// Colors used when drawing debug overlays onto nametable views
+const GRID_COLOR: u8 = 0x2D;
+const VIEWPORT_COLOR: u8 = 0x16;
+
+pub struct PPU {
+    ctrl: u8,
+    mask: u8,
+    status: u8,
+    oam_addr: u8,
+    pub oam: [u8; 256],
+    // The sprites found for the last line drawn, padded with $FF
+    secondary_oam: [u8; 32],
+
+    // Internal "loopy" registers: current and temporary VRAM address, fine X scroll and the
+    // write toggle shared by $2005 and $2006. v and t are laid out as yyy NN YYYYY XXXXX: fine
+    // Y, nametable, coarse Y and coarse X.
+    v: u16,
+    t: u16,
+    x: u8,
+    w: bool,
+    read_buffer: u8,
+
+    // Pattern tables and nametable mirroring come from the cartridge
+    mapper: SharedMapper,
+    // The mapper wants every address put on the bus, see Mapper::watches_ppu_addresses
+    watch_addresses: bool,
+    vram: [u8; 0x1000],
+    palette: [u8; 32],
+    // Pages of VRAM, and of the pattern tables by address, written since take_dirty_pages
+    dirty_vram: u64,
+    dirty_chr: u64,
+
+    timing: &'static TimingConfig,
+    // Leftover fraction of a dot from CPU cycles that don't divide evenly (PAL)
+    dot_remainder: u32,
+    // Position of the next dot to be drawn
+    dot: u16,
+    scanline: u16,
+    // Frames completed so far
+    pub frame: u64,
+    // Set on the rising edge of the NMI output, until the CPU takes it
+    nmi_pending: bool,
+    // Dots since nmi_pending was raised, up to just past NMI_WINDOW_DOTS
+    nmi_age: u8,
+    // $2002 was read the dot before vblank starts, so this frame's flag never goes up
+    suppress_vblank: bool,
+    // CPU cycles left until the PPU takes register writes, after power-on or reset
+    warmup_cycles: u32,
+
+    // One NES color index per pixel
+    pub framebuffer: Vec<u8>,
+    // How color indices are shown when the framebuffer is turned into an image
+    pub rgb_palette: palette::Palette,
+    // Only draw the first 8 sprites on each line, like the hardware. Turning this off gets rid
+    // of flicker, but not the overflow flag.
+    pub sprite_limit: bool,
+    // Set the overflow flag with the hardware's buggy diagonal OAM scan rather than by count
+    pub sprite_overflow_bug: bool,
+    // Ignore writes to $2000, $2001, $2005 and $2006 for a while after power-on and reset, as
+    // the hardware does. Games wait it out; test programs that set the PPU up straight away
+    // can turn it off.
+    pub warmup: bool,
+    pub accuracy: AccuracyLevel,
+    register_warnings: LogLimiter,
+    // Debugging views, see set_layer_visibility and set_sprite_tint
+    show_background: bool,
+    show_sprites: bool,
+    sprite_tint: bool,
+    // The frame being drawn's sprite evaluation, and the last whole frame's, see
+    // sprites_on_scanline
+    evaluation: Box<SpriteEvaluation>,
+    last_evaluation: Box<SpriteEvaluation>,
+}
+
+// Which sprites each visible line found, a frame at a time
+#[derive(Clone)]
+struct SpriteEvaluation {
+    // OAM when the frame's first line was drawn. It's only written in vblank in practice.
+    oam: [u8; 256],
+    // Bit n is set when OAM entry n is on the line, whether or not it made the first 8
+    lines: [u64; SCREEN_HEIGHT],
+}
+
+impl SpriteEvaluation {
+    fn new() -> SpriteEvaluation {
+        SpriteEvaluation { oam: [0; 256], lines: [0; SCREEN_HEIGHT] }
+    }
+}
+
+// One OAM entry, decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpriteInfo {
+    pub oam_index: u8,
+    pub x: u8,
+    // As stored, one less than the sprite's top line
+    pub y: u8,
+    pub tile: u8,
+    pub attributes: u8,
+    // Past the first 8 on its line, so the hardware doesn't draw it. With the sprite limit off
+    // the emulator still does.
+    pub dropped: bool,
+}
+
+impl SpriteInfo {
+    pub fn from_oam(oam: &[u8], oam_index: u8) -> SpriteInfo {
+        let entry = &oam[oam_index as usize * 4..oam_index as usize * 4 + 4];
+        SpriteInfo { oam_index: oam_index, y: entry[0], tile: entry[1], attributes: entry[2], x: entry[3], dropped: false }
+    }
+
+    pub fn palette(&self) -> u8 {
+        self.attributes & SPRITE_PALETTE
+    }
+
+    pub fn behind_background(&self) -> bool {
+        self.attributes & SPRITE_BEHIND_BG != 0
+    }
+
+    pub fn flip_h(&self) -> bool {
+        self.attributes & SPRITE_FLIP_H != 0
+    }
+
+    pub fn flip_v(&self) -> bool {
+        self.attributes & SPRITE_FLIP_V != 0
+    }
+}
+
+// A sprite's pixel before priority against the background is settled. Only opaque pixels
+// become SpritePixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpritePixel {
+    // Palette RAM index, $11-$1F
+    pub index: u8,
+    // The attribute byte's priority bit
+    pub behind_background: bool,
+    pub sprite_zero: bool,
+    // Which OAM entry it's from, 0-63
+    pub oam_index: u8,
+}
+
+// A layer of the picture that can be left out while debugging
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layer {
+    Background,
+    Sprites,
+}
+
+impl FromStr for Layer {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Layer, &'static str> {
+        match s.to_ascii_lowercase().as_str() {
+            "bg" | "background" => Ok(Layer::Background),
+            "sprites" => Ok(Layer::Sprites),
+            _ => Err("layer must be bg or sprites"),
+        }
+    }
+}
+
+// The color a sprite is drawn in with the sprite tint on: one of 48 hue and brightness pairs,
+// so entries 48 apart share one. Neighbouring entries get hues 5 apart, far from each other.
+pub fn sprite_tint_color(oam_index: u8) -> u8 {
+    let n = oam_index % 48;
+    let hue = (n * 5) % 12 + 1;
+    let brightness = [2, 3, 1, 0][(n / 12) as usize];
+    (brightness << 4) | hue
+}
+
+// The priority multiplexer: the palette RAM index one pixel shows. background is None where
+// it's transparent. sprite is the first opaque sprite pixel in OAM order whatever its priority,
+// so a sprite behind the background still hides later sprites in front of it (the trick SMB3
+// uses to hide Mario in pipes). With neither, the backdrop at $00 shows.
+pub fn composite(background: Option<u8>, sprite: Option<SpritePixel>) -> u8 {
+    match (background, sprite) {
+        (Some(background), Some(sprite)) if sprite.behind_background => background,
+        (_, Some(sprite)) => sprite.index,
+        (Some(background), None) => background,
+        (None, None) => 0,
+    }
+}
+
+// Sprite 0 hits wherever one of its opaque pixels overlaps opaque background, before priority
+// decides which shows. The last column never hits.
+pub fn sprite_zero_hit(x: usize, background: Option<u8>, sprite: Option<SpritePixel>) -> bool {
+    x != SCREEN_WIDTH - 1 && background.is_some() && sprite.is_some_and(|sprite| sprite.sprite_zero)
+}
+
+// Which overlays to draw over a nametable view
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NametableOverlay {
+    pub grid: bool,
+    pub viewport: bool,
+}
+
+// The PPU's part of a save state. Settings like the sprite limit belong to the frontend and
+// aren't included.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PpuState {
+    pub ctrl: u8,
+    pub mask: u8,
+    pub status: u8,
+    pub oam_addr: u8,
+    #[cfg_attr(feature = "serde", serde(with = "::state::bytes"))]
+    pub oam: Vec<u8>,
+    #[cfg_attr(feature = "serde", serde(with = "::state::bytes"))]
+    pub secondary_oam: Vec<u8>,
+    pub v: u16,
+    pub t: u16,
+    pub x: u8,
+    pub w: bool,
+    pub read_buffer: u8,
+    #[cfg_attr(feature = "serde", serde(with = "::state::bytes"))]
+    pub vram: Vec<u8>,
+    #[cfg_attr(feature = "serde", serde(with = "::state::bytes"))]
+    pub palette: Vec<u8>,
+    pub dot_remainder: u32,
+    pub dot: u16,
+    pub scanline: u16,
+    pub frame: u64,
+    pub nmi_pending: bool,
+    pub nmi_age: u8,
+    pub warmup_cycles: u32,
+    // The picture so far, since a state can be taken partway down the screen
+    #[cfg_attr(feature = "serde", serde(with = "::state::bytes"))]
+    pub framebuffer: Vec<u8>,
+}
+
+// Copy a saved block back into a fixed-size one, if it's the right size
+fn restore(dest: &mut [u8], src: &[u8], part: &'static str) -> Result<(), StateError> {
+    if dest.len() != src.len() {
+        return Err(StateError::Corrupt(part))
+    }
+    dest.copy_from_slice(src);
+    Ok(())
+}
+
+impl PPU {
+    pub fn new(mapper: SharedMapper, timing: &'static TimingConfig) -> PPU {
+        let watch_addresses = mapper.borrow().watches_ppu_addresses();
+        PPU {
+            ctrl: 0,
+            mask: 0,
+            status: 0,
+            oam_addr: 0,
+            oam: [0; 256],
+            secondary_oam: [0xFF; 32],
+            v: 0,
+            t: 0,
+            x: 0,
+            w: false,
+            read_buffer: 0,
+            mapper: mapper,
+            watch_addresses: watch_addresses,
+            vram: [0; 0x1000],
+            palette: [0; 32],
+            dirty_vram: !0,
+            dirty_chr: !0,
+            timing: timing,
+            dot_remainder: 0,
+            dot: 0,
+            scanline: 0,
+            frame: 0,
+            nmi_pending: false,
+            nmi_age: 0,
+            suppress_vblank: false,
+            warmup_cycles: 0,
+            framebuffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            rgb_palette: palette::SYSTEM_PALETTE,
+            sprite_limit: true,
+            sprite_overflow_bug: false,
+            warmup: true,
+            accuracy: AccuracyLevel::default(),
+            register_warnings: LogLimiter::new(),
+            show_background: true,
+            show_sprites: true,
+            sprite_tint: false,
+            evaluation: Box::new(SpriteEvaluation::new()),
+            last_evaluation: Box::new(SpriteEvaluation::new()),
+        }
+    }
+
+    // Everything but the cartridge's pattern memory comes up holding whatever init says
+    pub fn power_on(&mut self, init: RamInit) {
+        self.reset();
+        self.status = 0;
+        self.oam_addr = 0;
+        init.fill(&mut self.oam);
+        self.v = 0;
+        init.fill(&mut self.vram);
+        self.dirty_vram = !0;
+        init.fill(&mut self.palette);
+        // Palette RAM is only 6 bits wide
+        for entry in self.palette.iter_mut() { *entry &= 0x3F; }
+        self.dot_remainder = 0;
+        self.dot = 0;
+        self.scanline = 0;
+        self.frame = 0;
+        self.nmi_pending = false;
+        self.suppress_vblank = false;
+        for pixel in self.framebuffer.iter_mut() { *pixel = 0; }
+    }
+
+    // The reset line clears the control registers, the scroll and the write toggle, but not
+    // VRAM, OAM or PPUSTATUS, and starts the warm-up over
+    pub fn reset(&mut self) {
+        self.ctrl = 0;
+        self.mask = 0;
+        self.t = 0;
+        self.x = 0;
+        self.w = false;
+        self.read_buffer = 0;
+        self.warmup_cycles = if self.warmup { self.timing.ppu_warmup_cycles } else { 0 };
+    }
+
+    // Whether writes to $2000, $2001, $2005 and $2006 are still being ignored
+    pub fn warming_up(&self) -> bool {
+        self.warmup_cycles > 0
+    }
+
+    pub fn save_state(&self) -> PpuState {
+        PpuState { vram: self.vram.to_vec(), framebuffer: self.framebuffer.clone(), ..self.save_registers() }
+    }
+
+    // The state without VRAM and the picture, for a delta to add the pages it needs
+    pub fn save_registers(&self) -> PpuState {
+        PpuState {
+            ctrl: self.ctrl,
+            mask: self.mask,
+            status: self.status,
+            oam_addr: self.oam_addr,
+            oam: self.oam.to_vec(),
+            secondary_oam: self.secondary_oam.to_vec(),
+            v: self.v,
+            t: self.t,
+            x: self.x,
+            w: self.w,
+            read_buffer: self.read_buffer,
+            vram: Vec::new(),
+            palette: self.palette.to_vec(),
+            dot_remainder: self.dot_remainder,
+            dot: self.dot,
+            scanline: self.scanline,
+            frame: self.frame,
+            nmi_pending: self.nmi_pending,
+            nmi_age: self.nmi_age,
+            warmup_cycles: self.warmup_cycles,
+            framebuffer: Vec::new(),
+        }
+    }
+
+    pub fn load_state(&mut self, state: &PpuState) -> Result<(), StateError> {
+        restore(&mut self.oam, &state.oam, "OAM")?;
+        restore(&mut self.secondary_oam, &state.secondary_oam, "secondary OAM")?;
+        restore(&mut self.vram, &state.vram, "VRAM")?;
+        // The pattern tables come back with the mapper's state
+        self.dirty_vram = !0;
+        self.dirty_chr = !0;
+        restore(&mut self.palette, &state.palette, "palette")?;
+        restore(&mut self.framebuffer, &state.framebuffer, "framebuffer")?;
+        self.ctrl = state.ctrl;
+        self.mask = state.mask;
+        self.status = state.status;
+        self.oam_addr = state.oam_addr;
+        self.v = state.v;
+        self.t = state.t;
+        self.x = state.x;
+        self.w = state.w;
+        self.read_buffer = state.read_buffer;
+        self.dot_remainder = state.dot_remainder;
+        self.dot = state.dot;
+        self.scanline = state.scanline;
+        self.frame = state.frame;
+        self.nmi_pending = state.nmi_pending;
+        self.nmi_age = state.nmi_age;
+        self.warmup_cycles = state.warmup_cycles;
+        self.suppress_vblank = false;
+        Ok(())
+    }
+
+    // A new cartridge was plugged in
+    pub fn set_mapper(&mut self, mapper: SharedMapper) {
+        self.watch_addresses = mapper.borrow().watches_ppu_addresses();
+        self.mapper = mapper;
+    }
+
+    pub fn set_timing(&mut self, timing: &'static TimingConfig) {
+        self.timing = timing;
+        self.dot_remainder = 0;
+    }
+
+    // Catch up with the CPU after it ran for some cycles
+    pub fn step_cpu_cycles(&mut self, cycles: u32) {
+        self.warmup_cycles = self.warmup_cycles.saturating_sub(cycles);
+        let total = cycles * self.timing.ppu_dots_per_cycle + self.dot_remainder;
+        self.dot_remainder = total % self.timing.cycles_per_ppu_dots;
+        self.step(total / self.timing.cycles_per_ppu_dots);
+    }
+
+    // Advance by a number of PPU dots
+    pub fn step(&mut self, dots: u32) {
+        for _ in 0..dots {
+            self.tick();
+        }
+    }
+
+    fn rendering_enabled(&self) -> bool {
+        self.mask & (MASK_SHOW_BG | MASK_SHOW_SPRITES) != 0
+    }
+
+    // Whether the PPU is busy with OAM: drawing a visible or the pre-render line
+    fn rendering(&self) -> bool {
+        let line = self.scanline;
+        self.rendering_enabled() && ((line as usize) < SCREEN_HEIGHT || line == self.timing.prerender_scanline)
+    }
+
+    fn tick(&mut self) {
+        let visible = (self.scanline as usize) < SCREEN_HEIGHT;
+        let prerender = self.scanline == self.timing.prerender_scanline;
+
+        if self.dot == 1 {
+            if self.scanline == self.timing.vblank_scanline {
+                if !std::mem::replace(&mut self.suppress_vblank, false) {
+                    self.status |= STATUS_VBLANK;
+                    if self.ctrl & CTRL_NMI_ENABLE != 0 {
+                        self.raise_nmi();
+                    }
+                }
+                self.frame += 1;
+                self.fetch_phase(FetchPhase::Idle);
+            } else if prerender {
+                self.status &= !(STATUS_VBLANK | STATUS_SPRITE_ZERO | STATUS_OVERFLOW);
+                if self.accuracy == AccuracyLevel::Accurate && self.rendering_enabled() && self.oam_addr >= 8 {
+                    let row = (self.oam_addr & 0xF8) as usize;
+                    self.oam.copy_within(row..row + 8, 0);
+                }
+            }
+        }
+
+        // Each line is drawn in one go from the registers as they are by the end of its fetches,
+        // so writes made between lines (split screens, status bars) show up where they should
+        if visible && self.dot == 256 {
+            let line = self.scanline as usize;
+            self.render_scanline(line);
+        }
+        if self.rendering_enabled() && (visible || prerender) {
+            if self.watch_addresses {
+                if let Some(addr) = self.fetch_address(prerender) {
+                    self.mapper.borrow_mut().on_ppu_address(addr);
+                }
+            }
+            match self.dot {
+                256 => self.increment_y(),
+                257 => { self.v = (self.v & !0x041F) | (self.t & 0x041F); },
+                280 if prerender => { self.v = (self.v & !0x7BE0) | (self.t & 0x7BE0); },
+                _ => {},
+            }
+            if self.accuracy == AccuracyLevel::Accurate && (257..=320).contains(&self.dot) {
+                self.oam_addr = 0;
+            }
+        }
+
+        if self.nmi_pending {
+            self.nmi_age = self.nmi_age.saturating_add(1);
+        }
+        self.dot += 1;
+        // On odd frames with rendering on, NTSC skips the pre-render line's last dot and goes
+        // straight to the first line, making every other frame one dot short
+        if prerender && self.dot == DOTS_PER_SCANLINE - 1 && self.timing.odd_frame_skip
+            && self.frame & 1 == 1 && self.rendering_enabled() {
+            self.dot = DOTS_PER_SCANLINE;
+        }
+        if self.dot == DOTS_PER_SCANLINE {
+            self.dot = 0;
+            self.scanline = (self.scanline + 1) % self.timing.scanlines_per_frame;
+        }
+    }
+
+    // The address the renderer puts on the bus at this dot, for mappers that watch it. A fetch
+    // takes two dots, with the address going out on the first. Lines are drawn in one go, so v
+    // stays where the line started and the tile being fetched is worked out from the dot.
+    fn fetch_address(&self, prerender: bool) -> Option<u16> {
+        let dot = self.dot as usize;
+        if dot & 1 == 0 {
+            return None
+        }
+        // Nametable, attribute, pattern low, pattern high
+        let step = (dot - 1) % 8 / 2;
+        match dot {
+            // The line's tiles from the third on, the first two having been fetched at the end
+            // of the line before
+            1..=255 => Some(self.background_fetch(tile_ahead(self.v, 2 + (dot - 1) / 8), step)),
+            // Two garbage nametable fetches and then the pattern for each sprite slot
+            257..=319 if step < 2 => Some(0x2000 | (self.v & 0x0FFF)),
+            257..=319 => Some(self.sprite_fetch((dot - 257) / 8, step == 3, prerender)),
+            // The first two tiles of the next line, which v is on by now
+            321..=335 => Some(self.background_fetch(tile_ahead(self.v, (dot - 321) / 8), step)),
+            // Two more nametable fetches that nothing uses
+            337 | 339 => Some(0x2000 | (tile_ahead(self.v, 2) & 0x0FFF)),
+            _ => None,
+        }
+    }
+
+    // One of a background tile's four fetches, for the tile a loopy address points at
+    fn background_fetch(&self, v: u16, step: usize) -> u16 {
+        match step {
+            0 => 0x2000 | (v & 0x0FFF),
+            1 => 0x23C0 | (v & 0x0C00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07),
+            _ => {
+                let tile = self.vram_loadb(0x2000 | (v & 0x0FFF)) as u16;
+                let table = if self.ctrl & CTRL_BG_TABLE != 0 { 0x1000 } else { 0 };
+                table | (tile << 4) | (v >> 12) | if step == 3 { 8 } else { 0 }
+            },
+        }
+    }
+
+    // A pattern fetch for a slot of secondary OAM. Empty slots, and every slot on the
+    // pre-render line, fetch tile $FF.
+    fn sprite_fetch(&self, slot: usize, high: bool, prerender: bool) -> u16 {
+        let entry = &self.secondary_oam[slot * 4..slot * 4 + 4];
+        let line = self.scanline as usize;
+        let found = !prerender && self.sprite_on_line(entry[0], line);
+        let (start, row) = if found {
+            self.sprite_pattern(entry[0], entry[1], entry[2], line)
+        } else {
+            self.sprite_pattern(0, 0xFF, 0, 1)
+        };
+        (start + row % 8) as u16 | if high { 8 } else { 0 }
+    }
+
+    // Move v right a tile, carrying into the horizontal nametable bit
+    fn increment_x(&mut self) {
+        if self.v & 0x001F == 31 {
+            self.v = (self.v & !0x001F) ^ 0x0400;
+        } else {
+            self.v += 1;
+        }
+    }
+
+    // Move v down a pixel row, carrying into coarse Y and then the vertical nametable bit
+    fn increment_y(&mut self) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+            return;
+        }
+        self.v &= !0x7000;
+        let mut coarse_y = (self.v & 0x03E0) >> 5;
+        if coarse_y == 29 {
+            coarse_y = 0;
+            self.v ^= 0x0800;
+        } else if coarse_y == 31 {
+            // Scrolled into the attribute table, which wraps without switching nametables
+            coarse_y = 0;
+        } else {
+            coarse_y += 1;
+        }
+        self.v = (self.v & !0x03E0) | (coarse_y << 5);
+    }
+
+    // The scanline currently being drawn, with the pre-render line last
+    pub fn scanline(&self) -> u16 {
+        self.scanline
+    }
+
+    // 262 on NTSC, 312 on PAL
+    pub fn scanlines_per_frame(&self) -> u16 {
+        self.timing.scanlines_per_frame
+    }
+
+    // The next dot to be drawn on the scanline, 0-340
+    pub fn dot(&self) -> u16 {
+        self.dot
+    }
+
+    // The current VRAM address, v
+    pub fn vram_addr(&self) -> u16 {
+        self.v
+    }
+
+    // The temporary VRAM address, t, which $2000, $2005 and $2006 build up
+    pub fn temp_addr(&self) -> u16 {
+        self.t
+    }
+
+    pub fn fine_x(&self) -> u8 {
+        self.x
+    }
+
+    // Whether the next $2005 or $2006 write is the second of a pair
+    pub fn write_toggle(&self) -> bool {
+        self.w
+    }
+
+    // The console's 4 KiB of nametable RAM, before mirroring
+    pub fn nametable_ram(&self) -> &[u8] {
+        &self.vram
+    }
+
+    // The VRAM and pattern table pages written since the last call, see state::DirtyPages
+    pub fn take_dirty_pages(&mut self) -> (u64, u64) {
+        (std::mem::take(&mut self.dirty_vram), std::mem::take(&mut self.dirty_chr))
+    }
+
+    fn raise_nmi(&mut self) {
+        self.nmi_pending = true;
+        self.nmi_age = 0;
+    }
+
+    // Whether an NMI was raised too recently for the CPU to have seen it yet
+    fn nmi_in_window(&self) -> bool {
+        self.nmi_pending && self.nmi_age <= NMI_WINDOW_DOTS
+    }
+
+    // Whether the CPU should take an NMI, clearing the request. One raised in the last couple
+    // of dots stays pending for the CPU's next poll.
+    pub fn take_nmi(&mut self) -> bool {
+        if !self.nmi_pending || self.nmi_in_window() {
+            return false
+        }
+        self.nmi_pending = false;
+        true
+    }
+
+    // CPU-facing registers, reg is the address modulo 8
+    pub fn read_register(&mut self, reg: u16) -> u8 {
+        match reg & 7 {
+            2 => {
+                if self.scanline == self.timing.vblank_scanline && self.dot == 1 {
+                    // The dot before the flag goes up: it reads clear and stays clear
+                    self.suppress_vblank = true;
+                } else if self.nmi_in_window() {
+                    self.nmi_pending = false;
+                }
+                let val = self.status | (self.read_buffer & 0x1F);
+                self.status &= !STATUS_VBLANK;
+                // The next $2005 or $2006 write is a first write again, whichever it is
+                self.w = false;
+                val
+            },
+            4 => self.read_oam_data(),
+            7 => {
+                let addr = self.v;
+                self.bus_address(addr);
+                let val = if addr & 0x3FFF >= 0x3F00 {
+                    // Palette reads skip the buffer, which picks up the nametable byte
+                    // underneath instead. The top two bits aren't there and read as 0.
+                    self.read_buffer = self.vram_loadb(addr & 0x2FFF);
+                    self.vram_loadb(addr)
+                } else {
+                    let val = self.read_buffer;
+                    self.read_buffer = self.vram_loadb(addr);
+                    val
+                };
+                self.increment_v();
+                val
+            },
+            // Write-only registers
+            _ => {
+                if self.register_warnings.allow("nes::ppu", reg as u32 & 7) {
+                    warn!("Read of write-only PPU register $200{}", reg & 7);
+                }
+                0
+            },
+        }
+    }
+
+    pub fn write_register(&mut self, reg: u16, val: u8) {
+        if self.warming_up() && matches!(reg & 7, 0 | 1 | 5 | 6) {
+            return;
+        }
+        match reg & 7 {
+            0 => {
+                // Enabling NMI during vblank raises it straight away, and disabling it before the
+                // CPU has noticed one takes it back
+                if self.ctrl & CTRL_NMI_ENABLE == 0 && val & CTRL_NMI_ENABLE != 0 && self.status & STATUS_VBLANK != 0 {
+                    self.raise_nmi();
+                } else if val & CTRL_NMI_ENABLE == 0 && self.nmi_in_window() {
+                    self.nmi_pending = false;
+                }
+                self.ctrl = val;
+                self.t = (self.t & !0x0C00) | (((val & CTRL_NAMETABLE) as u16) << 10);
+            },
+            1 => { self.mask = val; },
+            3 => { self.oam_addr = val; },
+            4 => {
+                if !self.rendering() {
+                    self.oam[self.oam_addr as usize] = val;
+                    self.oam_addr = self.oam_addr.wrapping_add(1);
+                } else if self.accuracy == AccuracyLevel::Accurate {
+                    // The write is lost but OAMADDR moves on to the next sprite
+                    self.oam_addr = self.oam_addr.wrapping_add(4);
+                }
+            },
+            // $2005 and $2006 share w, so mixing them mixes which half of t each write lands in
+            5 => {
+                if !self.w {
+                    // Coarse X and fine X
+                    self.t = (self.t & !0x001F) | (val >> 3) as u16;
+                    self.x = val & 0x07;
+                } else {
+                    // Fine Y and coarse Y
+                    self.t = (self.t & !0x73E0) | (((val & 0x07) as u16) << 12) | (((val >> 3) as u16) << 5);
+                }
+                self.w = !self.w;
+            },
+            6 => {
+                if !self.w {
+                    // The high six bits, and bit 14 is cleared
+                    self.t = (self.t & 0x00FF) | (((val & 0x3F) as u16) << 8);
+                } else {
+                    self.t = (self.t & 0xFF00) | val as u16;
+                    self.v = self.t;
+                }
+                self.w = !self.w;
+            },
+            7 => {
+                let addr = self.v;
+                self.bus_address(addr);
+                self.vram_storeb(addr, val);
+                self.increment_v();
+            },
+            // $2002 is read-only
+            _ => {},
+        }
+    }
+
+    // A $2007 access, which puts v on the bus
+    fn bus_address(&self, addr: u16) {
+        if self.watch_addresses {
+            self.mapper.borrow_mut().on_ppu_address(addr & 0x3FFF);
+        }
+    }
+
+    // $2004 reads don't move OAMADDR
+    fn read_oam_data(&self) -> u8 {
+        if self.accuracy == AccuracyLevel::Accurate && self.rendering() {
+            // The PPU has secondary OAM on its bus: $FF while it is cleared at the start of the
+            // line, then the sprites it found while it fetches their patterns
+            match self.dot {
+                1..=64 => return 0xFF,
+                257..=320 => {
+                    let dot = (self.dot - 257) as usize;
+                    return self.secondary_oam[(dot / 8) * 4 + (dot % 8).min(3)]
+                },
+                _ => {},
+            }
+        }
+        let val = self.oam[self.oam_addr as usize];
+        if self.oam_addr & 3 == 2 { val & !SPRITE_UNUSED_BITS } else { val }
+    }
+
+    // After a $2007 access. While the PPU is drawing, v is its scroll position and the access
+    // moves it with the renderer's own increments, coarse X and Y together, instead of adding
+    // 1 or 32.
+    fn increment_v(&mut self) {
+        if self.rendering() {
+            self.increment_x();
+            self.increment_y();
+            return;
+        }
+        let step = if self.ctrl & CTRL_INCREMENT_32 != 0 { 32 } else { 1 };
+        self.v = self.v.wrapping_add(step) & 0x3FFF;
+    }
+
+    // Map a $2000-$3EFF address onto physical VRAM according to the mirroring, or None if
+    // the cartridge supplies that nametable
+    fn nametable_offset(&self, addr: u16) -> Option<usize> {
+        let addr = (addr as usize - 0x2000) & 0x0FFF;
+        match self.mapper.borrow().nametable(addr / 0x400) {
+            NametableSource::Vram(page) => Some(page * 0x400 + addr % 0x400),
+            NametableSource::Cartridge => None,
+        }
+    }
+
+    // $3F10/$3F14/$3F18/$3F1C are mirrors of the backdrop entries
+    fn palette_offset(addr: u16) -> usize {
+        let index = (addr & 0x1F) as usize;
+        if index & 0x13 == 0x10 { index - 0x10 } else { index }
+    }
+
+    pub fn vram_loadb(&self, addr: u16) -> u8 {
+        let addr = addr & 0x3FFF;
+        match addr {
+            0..=0x1FFF => self.mapper.borrow_mut().ppu_read(addr),
+            0x2000..=0x3EFF => match self.nametable_offset(addr) {
+                Some(offset) => self.vram[offset],
+                None => self.mapper.borrow().nametable_peek(0x2000 | (addr & 0x0FFF)),
+            },
+            _ => self.palette[PPU::palette_offset(addr)],
+        }
+    }
+
+    pub fn vram_storeb(&mut self, addr: u16, val: u8) {
+        let addr = addr & 0x3FFF;
+        match addr {
+            0..=0x1FFF => {
+                self.dirty_chr |= 1 << (addr >> 8);
+                self.mapper.borrow_mut().ppu_write(addr, val);
+            },
+            0x2000..=0x3EFF => match self.nametable_offset(addr) {
+                Some(offset) => {
+                    self.vram[offset] = val;
+                    self.dirty_vram |= 1 << (offset >> 8);
+                },
+                None => self.mapper.borrow_mut().nametable_write(0x2000 | (addr & 0x0FFF), val),
+            },
+            // Palette RAM is only 6 bits wide
+            _ => { self.palette[PPU::palette_offset(addr)] = val & 0x3F; },
+        }
+    }
+
+    // One row of the tile at start in the pattern tables, fetched through the cartridge. A
+    // peek doesn't count as a fetch for mappers that watch them.
+    fn pattern_row(&self, start: usize, row: usize, peek: bool) -> [u8; 8] {
+        let (lo_addr, hi_addr) = ((start + row) as u16, (start + row + 8) as u16);
+        let (lo, hi) = if peek {
+            let mapper = self.mapper.borrow();
+            (mapper.ppu_peek(lo_addr), mapper.ppu_peek(hi_addr))
+        } else {
+            let mut mapper = self.mapper.borrow_mut();
+            (mapper.ppu_read(lo_addr), mapper.ppu_read(hi_addr))
+        };
+        chr::decode_row(lo, hi)
+    }
+
+    // The 2-bit palette group the attribute table assigns to a tile
+    fn attribute_group(&self, nametable: u16, coarse_x: usize, coarse_y: usize) -> u8 {
+        let addr = nametable + 0x3C0 + ((coarse_y / 4) * 8 + coarse_x / 4) as u16;
+        let attr = self.vram_loadb(addr);
+        let shift = ((coarse_y & 2) << 1) | (coarse_x & 2);
+        (attr >> shift) & 0x03
+    }
+
+    // The palette RAM indices (0-15) of the 8 background pixels in the tile row under (x, y),
+    // in the 512x480 space the four logical nametables tile. Transparent pixels resolve to the
+    // backdrop at index 0.
+    fn background_row(&self, x: usize, y: usize, peek: bool) -> [u8; 8] {
+        let table = (y / SCREEN_HEIGHT) * 2 + x / SCREEN_WIDTH;
+        let nametable = 0x2000 + 0x400 * table as u16;
+        let (x, y) = (x % SCREEN_WIDTH, y % SCREEN_HEIGHT);
+        let (coarse_x, coarse_y) = (x / 8, y / 8);
+
+        let tile = self.vram_loadb(nametable + (coarse_y * 32 + coarse_x) as u16);
+        let pattern_base = if self.ctrl & CTRL_BG_TABLE != 0 { 0x1000 } else { 0 };
+        let start = pattern_base + tile as usize * chr::TILE_SIZE;
+        let group = self.attribute_group(nametable, coarse_x, coarse_y) << 2;
+        let mut row = self.pattern_row(start, y % 8, peek);
+        for pixel in row.iter_mut().filter(|pixel| **pixel != 0) {
+            *pixel |= group;
+        }
+        return row
+    }
+
+    // Where a loopy register points in the 512x480 space of the four nametables
+    fn scroll_position(addr: u16, fine_x: u8) -> (usize, usize) {
+        let coarse_x = (addr & 0x1F) as usize;
+        let coarse_y = ((addr >> 5) & 0x1F) as usize;
+        let table = ((addr >> 10) & 0x03) as usize;
+        let fine_y = ((addr >> 12) & 0x07) as usize;
+        ((table & 1) * SCREEN_WIDTH + coarse_x * 8 + fine_x as usize,
+         (table >> 1) * SCREEN_HEIGHT + coarse_y * 8 + fine_y)
+    }
+
+    // The top-left of the visible screen in nametable space, as set through $2000/$2005/$2006
+    pub fn scroll_origin(&self) -> (usize, usize) {
+        PPU::scroll_position(self.t, self.x)
+    }
+
+    // Leave a layer out of the picture, or put it back. Only the picture changes: the layer is
+    // still drawn as far as sprite 0 hits and everything else are concerned, so games run on
+    // just the same. PPUMASK can still turn a layer off as usual.
+    pub fn set_layer_visibility(&mut self, layer: Layer, visible: bool) {
+        match layer {
+            Layer::Background => self.show_background = visible,
+            Layer::Sprites => self.show_sprites = visible,
+        }
+    }
+
+    pub fn layer_visible(&self, layer: Layer) -> bool {
+        match layer {
+            Layer::Background => self.show_background,
+            Layer::Sprites => self.show_sprites,
+        }
+    }
+
+    // Draw every sprite in a flat color that says which OAM entry it is, see sprite_tint_color
+    pub fn set_sprite_tint(&mut self, enabled: bool) {
+        self.sprite_tint = enabled;
+    }
+
+    pub fn sprite_tint(&self) -> bool {
+        self.sprite_tint
+    }
+
+    fn sprite_height(&self) -> usize {
+        if self.ctrl & CTRL_SPRITE_SIZE != 0 { 16 } else { 8 }
+    }
+
+    // Whether sprite n covers a line given its OAM Y, which is one less than its top line
+    fn sprite_on_line(&self, y: u8, line: usize) -> bool {
+        let top = y as usize + 1;
+        line >= top && line < top + self.sprite_height()
+    }
+
+    // Pick the sprites that are drawn on a line, in OAM order, and set the overflow flag if
+    // more than 8 are on it. Returns the OAM indices and how many there are.
+    fn evaluate_sprites(&mut self, line: usize) -> ([u8; 64], usize) {
+        let mut on_line = 0u64;
+        let mut found = [0u8; 64];
+        let mut count = 0;
+        for n in 0..64 {
+            if self.sprite_on_line(self.oam[n * 4], line) {
+                on_line |= 1 << n;
+                found[count] = n as u8;
+                count += 1;
+            }
+        }
+        self.evaluation.lines[line] = on_line;
+
+        if self.sprite_overflow_bug {
+            // Past the eighth sprite the hardware also steps the byte within each entry,
+            // so it compares tile numbers, attributes and X positions as if they were Y
+            let start = if count >= SPRITES_PER_LINE { found[SPRITES_PER_LINE - 1] as usize + 1 } else { 64 };
+            let (mut n, mut m) = (start, 0);
+            while n < 64 {
+                if self.sprite_on_line(self.oam[n * 4 + m], line) {
+                    self.status |= STATUS_OVERFLOW;
+                    break;
+                }
+                n += 1;
+                m = (m + 1) & 3;
+            }
+        } else if count > SPRITES_PER_LINE {
+            self.status |= STATUS_OVERFLOW;
+        }
+
+        if self.sprite_limit {
+            count = count.min(SPRITES_PER_LINE);
+        }
+        return (found, count)
+    }
+
+    // The sprites on a visible line as the last whole frame evaluated them, in OAM order: the
+    // first 8 are drawn and the rest are dropped. Empty for lines drawn with rendering off.
+    pub fn sprites_on_scanline(&self, line: u16) -> Vec<SpriteInfo> {
+        let evaluation = &self.last_evaluation;
+        let on_line = match evaluation.lines.get(line as usize) {
+            Some(&on_line) => on_line,
+            None => return Vec::new(),
+        };
+        (0..64u8).filter(|&n| on_line & (1 << n) != 0).enumerate().map(|(i, n)| {
+            SpriteInfo { dropped: i >= SPRITES_PER_LINE, ..SpriteInfo::from_oam(&evaluation.oam, n) }
+        }).collect()
+    }
+
+    // The 2-bit pixels of sprite n on a line, flips applied
+    fn sprite_row(&self, n: usize, line: usize) -> [u8; 8] {
+        let (y, tile, attr) = (self.oam[n * 4], self.oam[n * 4 + 1], self.oam[n * 4 + 2]);
+        let (start, row) = self.sprite_pattern(y, tile, attr, line);
+        let mut pixels = self.pattern_row(start, row % 8, false);
+        if attr & SPRITE_FLIP_H != 0 {
+            pixels.reverse();
+        }
+        return pixels
+    }
+
+    // Where a sprite's tile starts in the pattern tables, and which of its rows is on a line
+    // it covers, counting down a whole 8x16 sprite. Flipping counts up from the bottom of the
+    // whole sprite too, so a flipped 8x16 sprite swaps its two tiles as well as flipping each.
+    fn sprite_pattern(&self, y: u8, tile: u8, attr: u8, line: usize) -> (usize, usize) {
+        let height = self.sprite_height();
+        let mut row = line - (y as usize + 1);
+        if attr & SPRITE_FLIP_V != 0 {
+            row = height - 1 - row;
+        }
+        // 8x16 sprites take their pattern table from bit 0 of the tile number
+        let (table, tile) = if height == 16 {
+            ((tile & 1) as usize * chr::PATTERN_TABLE_SIZE, (tile & 0xFE) as usize + row / 8)
+        } else {
+            (if self.ctrl & CTRL_SPRITE_TABLE != 0 { chr::PATTERN_TABLE_SIZE } else { 0 }, tile as usize)
+        };
+        return (table + tile * chr::TILE_SIZE, row)
+    }
+
+    fn fetch_phase(&self, phase: FetchPhase) {
+        self.mapper.borrow_mut().ppu_fetch_phase(phase, self.sprite_height() == 16);
+    }
+
+    // Render one line of the picture from the current VRAM address and fine X scroll
+    fn render_scanline(&mut self, line: usize) {
+        if line == 0 {
+            self.evaluation.oam = self.oam;
+        }
+        self.evaluation.lines[line] = 0;
+        self.fetch_phase(if self.rendering_enabled() { FetchPhase::Background } else { FetchPhase::Idle });
+        // Palette RAM indices, 0 where the background is transparent
+        let mut background = [0u8; SCREEN_WIDTH];
+        if self.mask & MASK_SHOW_BG != 0 {
+            let (origin_x, y) = PPU::scroll_position(self.v, self.x);
+            let y = y % (SCREEN_HEIGHT * 2);
+            // Each tile row is fetched once, as the hardware does
+            let mut row = [0; 8];
+            for (x, index) in background.iter_mut().enumerate() {
+                let x = (origin_x + x) % (SCREEN_WIDTH * 2);
+                if x & 7 == 0 || x == origin_x {
+                    row = self.background_row(x, y, false);
+                }
+                *index = row[x & 7];
+            }
+        }
+
+        // The first opaque sprite pixel in OAM order at each x
+        let mut sprites: [Option<SpritePixel>; SCREEN_WIDTH] = [None; SCREEN_WIDTH];
+        if self.rendering_enabled() {
+            self.fetch_phase(FetchPhase::Sprites);
+            let (found, count) = self.evaluate_sprites(line);
+            self.secondary_oam = [0xFF; 32];
+            for (i, &n) in found[..count.min(SPRITES_PER_LINE)].iter().enumerate() {
+                let n = n as usize;
+                self.secondary_oam[i * 4..i * 4 + 4].copy_from_slice(&self.oam[n * 4..n * 4 + 4]);
+                self.secondary_oam[i * 4 + 2] &= !SPRITE_UNUSED_BITS;
+            }
+            if self.mask & MASK_SHOW_SPRITES != 0 {
+                for &n in found[..count].iter() {
+                    let n = n as usize;
+                    let (x, attr) = (self.oam[n * 4 + 3] as usize, self.oam[n * 4 + 2]);
+                    let group = 4 + (attr & SPRITE_PALETTE);
+                    for (i, &pixel) in self.sprite_row(n, line).iter().enumerate() {
+                        if pixel == 0 || x + i >= SCREEN_WIDTH || sprites[x + i].is_some() {
+                            continue;
+                        }
+                        sprites[x + i] = Some(SpritePixel {
+                            index: (group << 2) | pixel,
+                            behind_background: attr & SPRITE_BEHIND_BG != 0,
+                            sprite_zero: n == 0,
+                            oam_index: n as u8,
+                        });
+                    }
+                }
+            }
+            self.fetch_phase(FetchPhase::Hblank);
+        }
+
+        let mut pixels = [0u8; SCREEN_WIDTH];
+        for (x, pixel) in pixels.iter_mut().enumerate() {
+            let background = if background[x] != 0 { Some(background[x]) } else { None };
+            if sprite_zero_hit(x, background, sprites[x]) {
+                self.status |= STATUS_SPRITE_ZERO;
+            }
+            // The debugging views only change what's shown, after sprite 0 has been checked
+            let background = background.filter(|_| self.show_background);
+            let sprite = sprites[x].filter(|_| self.show_sprites);
+            *pixel = match sprite {
+                Some(sprite) if self.sprite_tint && !(sprite.behind_background && background.is_some()) =>
+                    sprite_tint_color(sprite.oam_index),
+                _ => self.palette[composite(background, sprite) as usize],
+            };
+        }
+        self.framebuffer[line * SCREEN_WIDTH..(line + 1) * SCREEN_WIDTH].copy_from_slice(&pixels);
+        if line == SCREEN_HEIGHT - 1 {
+            std::mem::swap(&mut self.evaluation, &mut self.last_evaluation);
+        }
+    }
+
+    // All four logical nametables as a 512x480 image of NES color indices. Mirrored tables
+    // show up as duplicates.
+    pub fn render_nametables(&self) -> Image {
+        self.render_nametables_with(NametableOverlay::default())
+    }
+
+    pub fn render_nametables_with(&self, overlay: NametableOverlay) -> Image {
+        let (width, height) = (SCREEN_WIDTH * 2, SCREEN_HEIGHT * 2);
+        let mut img = Image::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                let index = self.background_row(x, y, true)[x & 7];
+                img.set(x, y, self.palette[index as usize]);
+            }
+        }
+
+        if overlay.grid {
+            for y in 0..height {
+                for x in 0..width {
+                    if x & 7 == 0 || y & 7 == 0 { img.set(x, y, GRID_COLOR); }
+                }
+            }
+        }
+
+        if overlay.viewport {
+            let (origin_x, origin_y) = self.scroll_origin();
+            for i in 0..SCREEN_WIDTH {
+                let x = (origin_x + i) % width;
+                img.set(x, origin_y % height, VIEWPORT_COLOR);
+                img.set(x, (origin_y + SCREEN_HEIGHT - 1) % height, VIEWPORT_COLOR);
+            }
+            for i in 0..SCREEN_HEIGHT {
+                let y = (origin_y + i) % height;
+                img.set(origin_x % width, y, VIEWPORT_COLOR);
+                img.set((origin_x + SCREEN_WIDTH - 1) % width, y, VIEWPORT_COLOR);
+            }
+        }
+
+        return img
+    }
+
+    // A stable FNV-1a hash of the framebuffer, for comparing frames against golden values
+    pub fn frame_hash(&self) -> u64 {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for &pixel in self.framebuffer.iter() {
+            hash ^= pixel as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        return hash
+    }
+
+    // All 32 palette entries as reads of $3F00-$3F1F see them, mirrors included
+    pub fn palette_ram(&self) -> [u8; 32] {
+        let mut ram = [0; 32];
+        for (i, entry) in ram.iter_mut().enumerate() {
+            *entry = self.palette[PPU::palette_offset(i as u16)];
+        }
+        return ram
+    }
+
+    // Change an entry the way a write to $3F00 + index would, so $10 is the backdrop at $00
+    pub fn set_palette_entry(&mut self, index: usize, val: u8) {
+        self.palette[PPU::palette_offset(index as u16)] = val & 0x3F;
+    }
+
+    // Palette RAM one sub-palette per line, four background then four sprite, each entry with
+    // the color it shows as
+    pub fn palette_listing(&self) -> String {
+        let ram = self.palette_ram();
+        let mut out = String::new();
+        for (group, entries) in ram.chunks(4).enumerate() {
+            out += &format!("{}{} ${:02X}:", if group < 4 { "BG" } else { "SP" }, group % 4, 0x3F00 + group * 4);
+            for &entry in entries {
+                let [r, g, b] = self.rgb_palette[(entry & 0x3F) as usize];
+                out += &format!("  {:02X} #{:02X}{:02X}{:02X}", entry, r, g, b);
+            }
+            out += "\n";
+        }
+        return out
+    }
+
+    // The framebuffer as 8-bit RGBA, ready for a canvas or texture
+    pub fn framebuffer_rgba(&self) -> Vec<u8> {
+        palette::to_rgba(&self.framebuffer, &self.rgb_palette)
+    }
+
+    // Write the framebuffer out as a PPM image
+    pub fn write_screenshot(&self, path: &Path) -> io::Result<()> {
+        self.write_screenshot_cropped(path, Overscan::NONE)
+    }
+
+    pub fn write_screenshot_cropped(&self, path: &Path, overscan: Overscan) -> io::Result<()> {
+        let img = CroppedFrame::new(&self.framebuffer, overscan).to_image();
+        let mut out = BufWriter::new(File::create(path)?);
+        img.write_ppm(&mut out, &self.rgb_palette)
+    }
+}
+
+// A loopy address moved right some tiles, into the next nametable across if it goes off the
+// edge
+fn tile_ahead(v: u16, tiles: usize) -> u16 {
+    let coarse_x = (v & 0x001F) as usize + tiles;
+    let moved = (v & !0x001F) | (coarse_x & 0x1F) as u16;
+    if coarse_x >= 32 { moved ^ 0x0400 } else { moved }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AccuracyLevel, Layer, NametableOverlay, PPU, SpriteInfo, SpritePixel, CTRL_BG_TABLE, CTRL_SPRITE_SIZE,
+                CTRL_SPRITE_TABLE, DOTS_PER_SCANLINE, GRID_COLOR, MASK_SHOW_BG, MASK_SHOW_SPRITES, SCREEN_HEIGHT,
+                SCREEN_WIDTH, STATUS_OVERFLOW, STATUS_SPRITE_ZERO, STATUS_VBLANK, VIEWPORT_COLOR, composite,
+                sprite_tint_color, sprite_zero_hit};
+    use controller::InputFrame;
+    use cpu::StepMode;
+    use emulator::Nes;
+    use image::Image;
+    use mapper::{self, A12Clock, A12Filter, Mapper, SharedMapper};
+    use mem::Addressable;
+    use mem::RamInit;
+    use palette;
+    use region::{Region, TimingConfig, NTSC_TIMING, PAL_TIMING};
+    use rom::{Mirroring, ROM};
+    use testing;
+
+    use std::sync::{Arc, Mutex};
+
+    // Turns the background on and idles. Every tile is tile 0.
+    const SHOW_BACKGROUND: &str = "
+reset:  LDA #$0A
+        STA $2001
+loop:   JMP loop
+";
+
+    fn nes_with(program: &str, chr: &[u8]) -> Nes {
+        let rom = testing::build_test_rom(program, Some(chr));
+        Nes::builder().rom_bytes(&rom).ppu_warmup(false).build().unwrap()
+    }
+
+    fn run_frame(nes: &mut Nes) -> Vec<u8> {
+        nes.run_frame(InputFrame::default()).unwrap().framebuffer.to_vec()
+    }
+
+    #[test]
+    fn palette_edits_show_in_the_next_frame() {
+        // Tile 0 is solid color 1, so the whole picture is palette entry $01
+        let mut chr = vec![0; 16];
+        chr[..8].copy_from_slice(&[0xFF; 8]);
+        let mut nes = nes_with(SHOW_BACKGROUND, &chr);
+        nes.cpu_mut().memory_mut().ppu.set_palette_entry(0x01, 0x16);
+        run_frame(&mut nes);
+        assert!(run_frame(&mut nes).iter().all(|&pixel| pixel == 0x16));
+
+        nes.cpu_mut().memory_mut().ppu.set_palette_entry(0x01, 0x2A);
+        assert!(run_frame(&mut nes).iter().all(|&pixel| pixel == 0x2A));
+    }
+
+    #[test]
+    fn backdrop_edits_through_the_sprite_mirror() {
+        // Blank tiles show the backdrop
+        let mut nes = nes_with(SHOW_BACKGROUND, &[0; 16]);
+        nes.cpu_mut().memory_mut().ppu.set_palette_entry(0x10, 0x21);
+        assert_eq!(nes.cpu().memory().ppu.palette_ram()[0x00], 0x21);
+        run_frame(&mut nes);
+        assert!(run_frame(&mut nes).iter().all(|&pixel| pixel == 0x21));
+        // Entries past $3F are masked to a color
+        nes.cpu_mut().memory_mut().ppu.set_palette_entry(0x00, 0xFF);
+        assert_eq!(nes.cpu().memory().ppu.palette_ram()[0x10], 0x3F);
+    }
+    #[test]
+    fn mask_writes_mid_frame_split_the_picture() {
+        let mut chr = vec![0; 16];
+        chr[..8].copy_from_slice(&[0xFF; 8]);
+        let mut nes = nes_with(SHOW_BACKGROUND, &chr);
+        nes.cpu_mut().memory_mut().ppu.set_palette_entry(0x00, 0x21);
+        nes.cpu_mut().memory_mut().ppu.set_palette_entry(0x01, 0x16);
+        run_frame(&mut nes);
+
+        // Background off during scanline 100 and back on during 200
+        for &(line, mask) in [(100, 0x00), (200, 0x0A)].iter() {
+            while nes.cpu().memory().ppu.scanline() != line {
+                assert!(nes.step().unwrap().is_continue());
+            }
+            nes.cpu_mut().memory_mut().storeb(0x2001, mask);
+        }
+        let frame = run_frame(&mut nes);
+        let row = |y: usize| &frame[y * SCREEN_WIDTH..(y + 1) * SCREEN_WIDTH];
+        for y in (0..100).chain(201..SCREEN_HEIGHT) {
+            assert!(row(y).iter().all(|&pixel| pixel == 0x16), "scanline {}", y);
+        }
+        for y in 101..200 {
+            assert!(row(y).iter().all(|&pixel| pixel == 0x21), "scanline {}", y);
+        }
+    }
+
+    #[test]
+    fn oam_data_reads_back_with_attributes_masked() {
+        let mut cpu = testing::build_program(&[]);
+        let memory = cpu.memory_mut();
+        memory.storeb(0x2003, 0x00);
+        for i in 0..=0xFF {
+            memory.storeb(0x2004, i);
+        }
+        // OAMADDR wrapped from $FF back to $00
+        assert_eq!(memory.ppu.oam_addr, 0x00);
+        for i in 0..=0xFF {
+            memory.storeb(0x2003, i);
+            let expected = if i & 3 == 2 { i & 0xE3 } else { i };
+            // Reads don't move OAMADDR
+            assert_eq!((memory.loadb(0x2004), memory.loadb(0x2004)), (expected, expected), "${:02X}", i);
+            assert_eq!(memory.ppu.oam[i as usize], i);
+        }
+        memory.storeb(0x2003, 0xFF);
+        memory.storeb(0x2004, 0x42);
+        memory.storeb(0x2004, 0x43);
+        assert_eq!((memory.ppu.oam[0xFF], memory.ppu.oam[0x00], memory.ppu.oam_addr), (0x42, 0x43, 0x01));
+    }
+
+    // Step until the PPU is on scanline 100, somewhere in dots
+    fn step_to(nes: &mut Nes, dots: std::ops::RangeInclusive<u16>) {
+        let ppu = |nes: &Nes| (nes.cpu().memory().ppu.scanline(), nes.cpu().memory().ppu.dot());
+        while ppu(nes).0 != 100 || !dots.contains(&ppu(nes).1) {
+            assert!(nes.step().unwrap().is_continue());
+        }
+    }
+
+    #[test]
+    fn oam_data_during_rendering() {
+        // A sprite on lines 96-103, so line 100's fetches find it
+        for &accuracy in [AccuracyLevel::Simple, AccuracyLevel::Accurate].iter() {
+            let mut nes = nes_with(SHOW_BACKGROUND, &[0; 16]);
+            let ppu = &mut nes.cpu_mut().memory_mut().ppu;
+            ppu.accuracy = accuracy;
+            ppu.oam = [0xF8; 256];
+            ppu.oam[..4].copy_from_slice(&[95, 0x01, 0x02, 0x30]);
+            run_frame(&mut nes);
+
+            step_to(&mut nes, 100..=200);
+            let memory = nes.cpu_mut().memory_mut();
+            memory.storeb(0x2003, 0x10);
+            memory.storeb(0x2004, 0xAA);
+            // The write is dropped, and only the accurate PPU moves OAMADDR, a sprite on
+            let moved = if accuracy == AccuracyLevel::Accurate { 0x14 } else { 0x10 };
+            assert_eq!((memory.ppu.oam[0x10], memory.ppu.oam_addr), (0xF8, moved));
+
+            // Early in the line, while secondary OAM is cleared, and during the first sprite's
+            // fetches, where it reads the sprite's X
+            memory.storeb(0x2003, 0x01);
+            step_to(&mut nes, 2..=40);
+            let early = nes.cpu_mut().memory_mut().loadb(0x2004);
+            step_to(&mut nes, 260..=262);
+            let fetching = nes.cpu_mut().memory_mut().loadb(0x2004);
+            match accuracy {
+                AccuracyLevel::Simple => assert_eq!((early, fetching), (0x01, 0x01)),
+                AccuracyLevel::Accurate => assert_eq!((early, fetching), (0xFF, 0x30)),
+            }
+        }
+    }
+
+    // A CPU-side register access, for scroll_register_sequences
+    #[derive(Debug, Clone, Copy)]
+    enum Access {
+        Write(u16, u8),
+        ReadStatus,
+    }
+    use self::Access::{ReadStatus, Write};
+
+    #[test]
+    fn scroll_register_sequences() {
+        // $2005 writes of coarse X 15, fine X 5 then fine Y 6, coarse Y 11, and $2006 writes of
+        // $3D then $A2. Expected t, x and w worked out from the loopy layout by hand.
+        let cases: &[(&[Access], u16, u8, bool)] = &[
+            (&[Write(5, 0x7D), Write(5, 0x5E)], 0x616F, 5, false),
+            (&[ReadStatus, Write(5, 0x7D), Write(5, 0x5E)], 0x616F, 5, false),
+            // The read makes the second write a first one: coarse X 11, fine X 6
+            (&[Write(5, 0x7D), ReadStatus, Write(5, 0x5E)], 0x000B, 6, true),
+            (&[Write(6, 0x3D), Write(6, 0xA2)], 0x3DA2, 0, false),
+            (&[ReadStatus, Write(6, 0x3D), Write(6, 0xA2)], 0x3DA2, 0, false),
+            // $A2 as a first write keeps only its low six bits, and leaves t's low byte alone
+            (&[Write(6, 0x3D), ReadStatus, Write(6, 0xA2)], 0x2200, 0, true),
+            // The $2006 write is a second write, so it takes t's low byte and clears w for the
+            // last $2005 to set coarse and fine X again
+            (&[Write(5, 0x7D), Write(6, 0xA2), Write(5, 0x5E)], 0x00AB, 6, true),
+            (&[Write(5, 0x7D), Write(6, 0xA2), ReadStatus, Write(5, 0x5E)], 0x00AB, 6, true),
+            // Now the $2006 write is a first write, and the last $2005 a second: fine and
+            // coarse Y cover every bit the $2006 write set
+            (&[Write(5, 0x7D), ReadStatus, Write(6, 0xA2), Write(5, 0x5E)], 0x616F, 5, false),
+            // $2000 sets the nametable bits and leaves w alone
+            (&[Write(5, 0x7D), Write(0, 0x03), Write(5, 0x5E)], 0x6D6F, 5, false),
+        ];
+        for &(accesses, t, x, w) in cases.iter() {
+            let mut nes = nes_with(SHOW_BACKGROUND, &[0; 0x2000]);
+            let ppu = &mut nes.cpu_mut().memory_mut().ppu;
+            assert_eq!((ppu.temp_addr(), ppu.fine_x(), ppu.write_toggle()), (0, 0, false));
+            for &access in accesses.iter() {
+                match access {
+                    Write(reg, val) => ppu.write_register(reg, val),
+                    ReadStatus => { ppu.read_register(2); },
+                }
+            }
+            assert_eq!((ppu.temp_addr(), ppu.fine_x(), ppu.write_toggle()), (t, x, w), "{:?}", accesses);
+        }
+    }
+    // 8x16 sprites with an idle background, and the sprites in the leftmost column too
+    const TALL_SPRITES: &str = "
+reset:  LDA #$20
+        STA $2000
+        LDA #$14
+        STA $2001
+loop:   JMP loop
+";
+
+    // A tall sprite from tile $03 at (32, 64+1), as it comes out in two frames' time. Tiles 2
+    // and 3 of the $1000 table have a diagonal line down them, in colors 1 and 2; the same
+    // tiles of the $0000 table are solid color 3, and show up if the table comes from $2000.
+    fn tall_sprite(attributes: u8) -> Vec<u8> {
+        let mut chr = vec![0; 0x2000];
+        for row in 0..8 {
+            chr[0x20 + row] = 0xFF;
+            chr[0x28 + row] = 0xFF;
+            chr[0x30 + row] = 0xFF;
+            chr[0x38 + row] = 0xFF;
+            chr[0x1020 + row] = 0x80 >> row;
+            chr[0x1038 + row] = 0x80 >> row;
+        }
+        let mut nes = nes_with(TALL_SPRITES, &chr);
+        let ppu = &mut nes.cpu_mut().memory_mut().ppu;
+        ppu.set_palette_entry(0x00, 0x0F);
+        ppu.set_palette_entry(0x11, 0x16);
+        ppu.set_palette_entry(0x12, 0x2A);
+        ppu.set_palette_entry(0x13, 0x30);
+        ppu.write_register(3, 0);
+        for &byte in [0x40, 0x03, attributes, 0x20].iter() {
+            ppu.write_register(4, byte);
+        }
+        run_frame(&mut nes);
+        run_frame(&mut nes)
+    }
+
+    // Which of the sprite's 8 columns on a line are lit, and in what color
+    fn sprite_row(frame: &[u8], line: usize) -> Vec<(usize, u8)> {
+        (0..8).filter(|&col| frame[line * 256 + 0x20 + col] != 0x0F)
+            .map(|col| (col, frame[line * 256 + 0x20 + col]))
+            .collect()
+    }
+
+    #[test]
+    fn tall_sprites_draw_top_tile_then_bottom() {
+        let frame = tall_sprite(0x00);
+        for row in 0..8 {
+            assert_eq!(sprite_row(&frame, 0x41 + row), vec![(row, 0x16)], "top row {}", row);
+            assert_eq!(sprite_row(&frame, 0x49 + row), vec![(row, 0x2A)], "bottom row {}", row);
+        }
+        // Nothing above or below the 16 lines
+        assert!(sprite_row(&frame, 0x40).is_empty());
+        assert!(sprite_row(&frame, 0x51).is_empty());
+    }
+
+    #[test]
+    fn flipped_tall_sprites_swap_tiles_and_rows() {
+        let frame = tall_sprite(0x80);
+        for row in 0..8 {
+            // The bottom tile comes first, upside down, then the top tile
+            assert_eq!(sprite_row(&frame, 0x41 + row), vec![(7 - row, 0x2A)], "top row {}", row);
+            assert_eq!(sprite_row(&frame, 0x49 + row), vec![(7 - row, 0x16)], "bottom row {}", row);
+        }
+        assert!(sprite_row(&frame, 0x40).is_empty());
+        assert!(sprite_row(&frame, 0x51).is_empty());
+
+        // Flipping both ways mirrors each row as well
+        let frame = tall_sprite(0xC0);
+        assert_eq!(sprite_row(&frame, 0x41), vec![(0, 0x2A)]);
+        assert_eq!(sprite_row(&frame, 0x50), vec![(7, 0x16)]);
+    }
+    // Sprites on with the background off, in the leftmost column too
+    const SHOW_SPRITES: &str = "
+reset:  LDA #$14
+        STA $2001
+loop:   JMP loop
+";
+
+    #[test]
+    fn sprites_past_eight_on_a_line_are_dropped_in_oam_order() {
+        // Ten sprites on lines 100-107, spread through OAM, and one more that joins them from
+        // line 105. The rest sit below the picture.
+        const ON_LINE_100: [u8; 10] = [3, 7, 12, 20, 21, 30, 40, 50, 55, 63];
+        let mut nes = nes_with(SHOW_SPRITES, &[0; 0x2000]);
+        let ppu = &mut nes.cpu_mut().memory_mut().ppu;
+        ppu.write_register(3, 0);
+        for n in 0..64u8 {
+            let y = if ON_LINE_100.contains(&n) { 99 } else if n == 1 { 104 } else { 0xF0 };
+            for &byte in [y, n, 0x00, n * 4].iter() {
+                ppu.write_register(4, byte);
+            }
+        }
+        run_frame(&mut nes);
+        run_frame(&mut nes);
+        let ppu = &nes.cpu().memory().ppu;
+
+        let sprites = ppu.sprites_on_scanline(100);
+        let order: Vec<u8> = sprites.iter().map(|sprite| sprite.oam_index).collect();
+        assert_eq!(order, ON_LINE_100);
+        let dropped: Vec<u8> = sprites.iter().filter(|sprite| sprite.dropped).map(|sprite| sprite.oam_index).collect();
+        assert_eq!(dropped, [55, 63]);
+        assert_eq!(sprites[2], SpriteInfo { oam_index: 12, x: 48, y: 99, tile: 12, attributes: 0, dropped: false });
+
+        // Sprite 1 comes first in OAM, so it pushes sprite 50 out as well
+        let sprites = ppu.sprites_on_scanline(105);
+        let kept: Vec<u8> = sprites.iter().filter(|sprite| !sprite.dropped).map(|sprite| sprite.oam_index).collect();
+        assert_eq!(kept, [1, 3, 7, 12, 20, 21, 30, 40]);
+        let dropped: Vec<u8> = sprites.iter().filter(|sprite| sprite.dropped).map(|sprite| sprite.oam_index).collect();
+        assert_eq!(dropped, [50, 55, 63]);
+
+        assert!(ppu.sprites_on_scanline(99).is_empty());
+        assert_eq!(ppu.sprites_on_scanline(108).len(), 1);
+        assert!(ppu.sprites_on_scanline(240).is_empty());
+    }
+    // Count sprites of solid tile 1 side by side on lines 100-107, 16 dots apart from x = 8, as
+    // the second frame shows them, after setup has had a go at the PPU. Returns the frame and
+    // $2002 read at the end of it.
+    fn sprites_in_a_row<F: FnOnce(&mut PPU)>(count: u8, setup: F) -> (Vec<u8>, u8) {
+        let mut chr = vec![0; 0x2000];
+        chr[0x10..0x18].copy_from_slice(&[0xFF; 8]);
+        let mut nes = nes_with(SHOW_SPRITES, &chr);
+        let ppu = &mut nes.cpu_mut().memory_mut().ppu;
+        ppu.set_palette_entry(0x00, 0x0F);
+        ppu.set_palette_entry(0x11, 0x16);
+        ppu.write_register(3, 0);
+        for n in 0..64u8 {
+            let y = if n < count { 99 } else { 0xF0 };
+            for &byte in [y, 0x01, 0x00, 8 + n.wrapping_mul(16)].iter() {
+                ppu.write_register(4, byte);
+            }
+        }
+        setup(ppu);
+        run_frame(&mut nes);
+        let frame = run_frame(&mut nes);
+        let status = nes.cpu_mut().memory_mut().ppu.read_register(2);
+        (frame, status)
+    }
+
+    // Which of the sprites from sprites_in_a_row show on line
+    fn sprites_drawn(frame: &[u8], line: usize) -> Vec<usize> {
+        (0..16).filter(|&n| frame[line * 256 + 8 + n * 16] == 0x16).collect()
+    }
+
+    #[test]
+    fn ninth_sprite_on_a_line_is_dropped_and_overflows() {
+        let (frame, status) = sprites_in_a_row(9, |_| ());
+        for line in 100..108 {
+            assert_eq!(sprites_drawn(&frame, line), (0..8).collect::<Vec<_>>(), "line {}", line);
+        }
+        assert!(sprites_drawn(&frame, 99).is_empty());
+        assert!(sprites_drawn(&frame, 108).is_empty());
+        assert_ne!(status & STATUS_OVERFLOW, 0);
+
+        // Eight fit
+        let (frame, status) = sprites_in_a_row(8, |_| ());
+        assert_eq!(sprites_drawn(&frame, 100).len(), 8);
+        assert_eq!(status & STATUS_OVERFLOW, 0);
+    }
+
+    #[test]
+    fn without_the_sprite_limit_all_nine_draw() {
+        let (frame, status) = sprites_in_a_row(9, |ppu| ppu.sprite_limit = false);
+        for line in 100..108 {
+            assert_eq!(sprites_drawn(&frame, line), (0..9).collect::<Vec<_>>(), "line {}", line);
+        }
+        // The flag is the hardware's, limit or not
+        assert_ne!(status & STATUS_OVERFLOW, 0);
+    }
+
+    #[test]
+    fn overflow_bug_reads_other_bytes_as_y() {
+        // Eight sprites on the line, and sprite 9's tile number would be on it as a Y. Once
+        // sprite 8's Y misses, the buggy scan reads that tile number as the next Y.
+        let tile_like_y = |ppu: &mut PPU| {
+            ppu.oam[9 * 4 + 1] = 99;
+        };
+        let (_, status) = sprites_in_a_row(8, tile_like_y);
+        assert_eq!(status & STATUS_OVERFLOW, 0);
+        let (frame, status) = sprites_in_a_row(8, |ppu| {
+            tile_like_y(ppu);
+            ppu.sprite_overflow_bug = true;
+        });
+        assert_ne!(status & STATUS_OVERFLOW, 0);
+        assert_eq!(sprites_drawn(&frame, 100).len(), 8);
+    }
+    // Point v at addr through $2006
+    fn set_vram_addr(ppu: &mut PPU, addr: u16) {
+        ppu.write_register(6, (addr >> 8) as u8);
+        ppu.write_register(6, addr as u8);
+    }
+
+    #[test]
+    fn palette_reads_skip_the_buffer() {
+        let mut nes = nes_with(SHOW_BACKGROUND, &[0; 0x2000]);
+        let ppu = &mut nes.cpu_mut().memory_mut().ppu;
+        for &(addr, values) in [(0x2000, [0x11, 0x22]), (0x2F00, [0x77, 0x88]), (0x3F00, [0x0F, 0x30])].iter() {
+            set_vram_addr(ppu, addr);
+            for &val in values.iter() {
+                ppu.write_register(7, val);
+            }
+        }
+
+        // (address, what the read returns): nametable reads return the buffer, palette reads
+        // return the palette and fill the buffer from the nametable underneath, $2Fxx
+        let reads = [
+            (0x2000, None), (0x2001, Some(0x11)),
+            (0x3F00, Some(0x0F)), (0x2001, Some(0x77)),
+            (0x3F01, Some(0x30)), (0x2000, Some(0x88)),
+            (0x2001, Some(0x11)),
+        ];
+        for &(addr, expected) in reads.iter() {
+            set_vram_addr(ppu, addr);
+            let val = ppu.read_register(7);
+            if let Some(expected) = expected {
+                assert_eq!(val, expected, "${:04X}", addr);
+            }
+        }
+    }
+
+    #[test]
+    fn palette_entries_are_six_bits() {
+        let mut nes = nes_with(SHOW_BACKGROUND, &[0; 0x2000]);
+        let ppu = &mut nes.cpu_mut().memory_mut().ppu;
+        set_vram_addr(ppu, 0x3F05);
+        ppu.write_register(7, 0xFF);
+        set_vram_addr(ppu, 0x3F05);
+        assert_eq!(ppu.read_register(7), 0x3F);
+        assert_eq!(ppu.palette_ram()[0x05], 0x3F);
+    }
+    // Tile 1 in the top-left corner of $2000 with attribute palette 1, and tile 2 at row 1,
+    // column 2 of $2C00. Tiles 1 and 2 are solid colors 1 and 2.
+    fn nametable_view(vertical_mirroring: bool) -> Image {
+        let mut chr = vec![0; 0x2000];
+        chr[0x10..0x18].copy_from_slice(&[0xFF; 8]);
+        chr[0x28..0x30].copy_from_slice(&[0xFF; 8]);
+        let mut rom = testing::build_test_rom(SHOW_BACKGROUND, Some(&chr));
+        if vertical_mirroring {
+            rom[6] |= 1;
+        }
+        let mut nes = Nes::builder().rom_bytes(&rom).ppu_warmup(false).build().unwrap();
+        let ppu = &mut nes.cpu_mut().memory_mut().ppu;
+        for &(addr, val) in [(0x2000, 0x01), (0x23C0, 0x01), (0x2C22, 0x02)].iter() {
+            set_vram_addr(ppu, addr);
+            ppu.write_register(7, val);
+        }
+        for &(entry, color) in [(0x00, 0x0F), (0x02, 0x12), (0x05, 0x21)].iter() {
+            ppu.set_palette_entry(entry, color);
+        }
+        ppu.render_nametables()
+    }
+
+    // The (x, y, color) of every 8x8 tile in the view that isn't the backdrop
+    fn drawn_tiles(img: &Image) -> Vec<(usize, usize, u8)> {
+        let mut tiles = Vec::new();
+        for y in (0..img.height).step_by(8) {
+            for x in (0..img.width).step_by(8) {
+                let color = img.get(x, y);
+                for i in 0..64 {
+                    assert_eq!(img.get(x + i % 8, y + i / 8), color, "tile at ({}, {}) isn't solid", x, y);
+                }
+                if color != 0x0F {
+                    tiles.push((x, y, color));
+                }
+            }
+        }
+        tiles
+    }
+
+    #[test]
+    fn nametable_view_follows_horizontal_mirroring() {
+        let img = nametable_view(false);
+        assert_eq!((img.width, img.height), (512, 480));
+        // $2000 and $2400 are one table on top, $2800 and $2C00 the other below
+        assert_eq!(drawn_tiles(&img), [(0, 0, 0x21), (256, 0, 0x21), (16, 248, 0x12), (272, 248, 0x12)]);
+    }
+
+    #[test]
+    fn nametable_view_follows_vertical_mirroring() {
+        let img = nametable_view(true);
+        // $2000 and $2800 are one table on the left, $2400 and $2C00 the other on the right
+        assert_eq!(drawn_tiles(&img), [(0, 0, 0x21), (272, 8, 0x12), (0, 240, 0x21), (272, 248, 0x12)]);
+    }
+    #[test]
+    fn nametable_view_outlines_the_scroll() {
+        let mut nes = nes_with(SHOW_BACKGROUND, &[0; 0x2000]);
+        let ppu = &mut nes.cpu_mut().memory_mut().ppu;
+        ppu.set_palette_entry(0x00, 0x0F);
+        // $2400, scrolled 300 across and 20 down
+        ppu.write_register(0, 0x01);
+        ppu.write_register(5, 44);
+        ppu.write_register(5, 20);
+        assert_eq!(ppu.scroll_origin(), (300, 20));
+        let img = ppu.render_nametables_with(NametableOverlay { grid: false, viewport: true });
+        // The top and bottom edges run 256 across, wrapping off the right into $2000
+        for &y in [20, 20 + 239].iter() {
+            assert_eq!(img.get(300, y), VIEWPORT_COLOR);
+            assert_eq!(img.get(511, y), VIEWPORT_COLOR);
+            assert_eq!(img.get(0, y), VIEWPORT_COLOR);
+            assert_eq!(img.get(300 + 255 - 512, y), VIEWPORT_COLOR);
+            assert_eq!(img.get(300 + 256 - 512, y), 0x0F);
+        }
+        assert_eq!(img.get(300, 100), VIEWPORT_COLOR);
+        assert_eq!(img.get(300 + 255 - 512, 100), VIEWPORT_COLOR);
+        assert_eq!(img.get(200, 100), 0x0F);
+
+        let img = ppu.render_nametables_with(NametableOverlay { grid: true, viewport: false });
+        assert_eq!((img.get(8, 3), img.get(3, 8), img.get(3, 3)), (GRID_COLOR, GRID_COLOR, 0x0F));
+    }
+    // Diagonal stripes of all four colors across the background
+    fn striped_frame() -> Nes {
+        let mut chr = vec![0; 16];
+        for row in 0..8 {
+            chr[row] = 0x33 << (row % 4) | 0x33 >> (4 - row % 4);
+            chr[row + 8] = 0x0F << (row % 4) | 0x0F >> (4 - row % 4);
+        }
+        let mut nes = nes_with(SHOW_BACKGROUND, &chr);
+        for &(entry, color) in [(0x00, 0x0F), (0x01, 0x16), (0x02, 0x2A), (0x03, 0x30)].iter() {
+            nes.cpu_mut().memory_mut().ppu.set_palette_entry(entry, color);
+        }
+        run_frame(&mut nes);
+        run_frame(&mut nes);
+        nes
+    }
+
+    #[test]
+    fn frame_hash_is_stable_and_sees_one_pixel() {
+        let mut first = striped_frame();
+        let second = striped_frame();
+        let hash = first.cpu().memory().ppu.frame_hash();
+        assert_eq!(hash, second.cpu().memory().ppu.frame_hash());
+        assert!(first.cpu().memory().ppu.framebuffer.iter().any(|&pixel| pixel != 0x0F));
+
+        let ppu = &mut first.cpu_mut().memory_mut().ppu;
+        ppu.framebuffer[SCREEN_WIDTH * 100 + 100] ^= 1;
+        assert_ne!(ppu.frame_hash(), hash);
+        ppu.framebuffer[SCREEN_WIDTH * 100 + 100] ^= 1;
+        assert_eq!(ppu.frame_hash(), hash);
+    }
+
+    #[test]
+    fn screenshot_is_the_frame_in_rgb() {
+        let nes = striped_frame();
+        let ppu = &nes.cpu().memory().ppu;
+        let path = std::env::temp_dir().join(format!("nes-screenshot-{}.ppm", std::process::id()));
+        ppu.write_screenshot(&path).unwrap();
+        let ppm = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let header = b"P6\n256 240\n255\n";
+        assert_eq!(&ppm[..header.len()], &header[..]);
+        assert_eq!(ppm.len(), header.len() + SCREEN_WIDTH * SCREEN_HEIGHT * 3);
+        for (i, &pixel) in ppu.framebuffer.iter().enumerate().step_by(97) {
+            let at = header.len() + i * 3;
+            assert_eq!(ppm[at..at + 3], palette::SYSTEM_PALETTE[pixel as usize], "pixel {}", i);
+        }
+    }
+
+    #[test]
+    fn composite_truth_table() {
+        let sprite = |behind_background| Some(SpritePixel { index: 0x11, behind_background: behind_background, sprite_zero: true, oam_index: 0 });
+        let cases = [
+            (None, None, 0x00),
+            (None, sprite(false), 0x11),
+            (None, sprite(true), 0x11),
+            (Some(0x05), None, 0x05),
+            (Some(0x05), sprite(false), 0x11),
+            (Some(0x05), sprite(true), 0x05),
+        ];
+        for &(background, sprite, shown) in cases.iter() {
+            assert_eq!(composite(background, sprite), shown, "{:?} {:?}", background, sprite);
+            // Sprite 0 hits on the overlap whichever of them shows
+            let hit = background.is_some() && sprite.is_some();
+            assert_eq!(sprite_zero_hit(100, background, sprite), hit, "{:?} {:?}", background, sprite);
+        }
+        assert!(!sprite_zero_hit(SCREEN_WIDTH - 1, Some(0x05), sprite(false)));
+        let other = SpritePixel { sprite_zero: false, ..sprite(false).unwrap() };
+        assert!(!sprite_zero_hit(100, Some(0x05), Some(other)));
+    }
+
+    // Everything on, in the leftmost column too
+    const SHOW_ALL: &str = "
+reset:  LDA #$1E
+        STA $2001
+loop:   JMP loop
+";
+
+    #[test]
+    fn sprites_behind_the_background() {
+        // Tile 1 is solid color 1, for the background and sprites alike
+        let mut chr = vec![0; 0x2000];
+        chr[0x10..0x18].copy_from_slice(&[0xFF; 8]);
+        let mut nes = nes_with(SHOW_ALL, &chr);
+        let ppu = &mut nes.cpu_mut().memory_mut().ppu;
+        for &(entry, color) in [(0x00, 0x0F), (0x01, 0x21), (0x11, 0x16), (0x15, 0x2A)].iter() {
+            ppu.set_palette_entry(entry, color);
+        }
+        // Background on lines 96-103 from x = 32 to 71
+        ppu.write_register(6, 0x21);
+        ppu.write_register(6, 0x84);
+        for _ in 0..5 {
+            ppu.write_register(7, 0x01);
+        }
+        ppu.write_register(6, 0x20);
+        ppu.write_register(6, 0x00);
+        // Lines 100-107: sprite 0 behind the background at x = 32, sprite 1 in front of it
+        // at x = 36, and sprite 2 behind the background over the backdrop
+        ppu.write_register(3, 0);
+        for &byte in [99, 1, 0x20, 32, 99, 1, 0x01, 36, 99, 1, 0x20, 100].iter() {
+            ppu.write_register(4, byte);
+        }
+        for _ in 3..64 {
+            ppu.write_register(4, 0xF0);
+            for _ in 0..3 {
+                ppu.write_register(4, 0);
+            }
+        }
+        run_frame(&mut nes);
+        let frame = run_frame(&mut nes);
+        let status = nes.cpu_mut().memory_mut().ppu.read_register(2);
+        let colors = |line: usize, xs: std::ops::Range<usize>| -> Vec<u8> {
+            xs.map(|x| frame[line * SCREEN_WIDTH + x]).collect()
+        };
+
+        // Over the background sprite 0 is hidden, and so is sprite 1 where sprite 0 covers it
+        assert_eq!(colors(101, 32..40), [0x21; 8]);
+        assert_eq!(colors(101, 40..44), [0x2A; 4]);
+        assert_eq!(colors(101, 44..72), [0x21; 28]);
+        assert_eq!(colors(101, 100..108), [0x16; 8]);
+        // Below the background it shows
+        assert_eq!(colors(106, 32..40), [0x16; 8]);
+        assert_eq!(colors(106, 40..44), [0x2A; 4]);
+        assert_eq!(colors(106, 44..72), [0x0F; 28]);
+        assert_ne!(status & STATUS_SPRITE_ZERO, 0);
+    }
+
+    // Background on lines 96-103 from x = 32 to 71 with sprite 0 in front of it at x = 32, and
+    // sprites 1-8 on lines 150-157 from x = 100. Returns the second frame, and the line the
+    // sprite 0 hit is seen on in the third.
+    fn layered_frame(configure: fn(&mut PPU)) -> (Vec<u8>, u16) {
+        let mut chr = vec![0; 0x2000];
+        chr[0x10..0x18].copy_from_slice(&[0xFF; 8]);
+        let mut nes = nes_with(SHOW_ALL, &chr);
+        let ppu = &mut nes.cpu_mut().memory_mut().ppu;
+        for &(entry, color) in [(0x00, 0x0F), (0x01, 0x21), (0x11, 0x16)].iter() {
+            ppu.set_palette_entry(entry, color);
+        }
+        ppu.write_register(6, 0x21);
+        ppu.write_register(6, 0x84);
+        for _ in 0..5 {
+            ppu.write_register(7, 0x01);
+        }
+        ppu.write_register(6, 0x20);
+        ppu.write_register(6, 0x00);
+        ppu.oam = [0xF0; 256];
+        ppu.oam[..4].copy_from_slice(&[99, 1, 0x00, 32]);
+        for n in 1..9 {
+            ppu.oam[n * 4..n * 4 + 4].copy_from_slice(&[149, 1, 0x00, 100 + 10 * n as u8]);
+        }
+        configure(ppu);
+        run_frame(&mut nes);
+        let frame = run_frame(&mut nes);
+
+        let hit = |nes: &Nes| nes.cpu().memory().ppu.status & STATUS_SPRITE_ZERO != 0;
+        while hit(&nes) {
+            assert!(nes.step().unwrap().is_continue());
+        }
+        while !hit(&nes) {
+            assert!(nes.step().unwrap().is_continue());
+        }
+        (frame, nes.cpu().memory().ppu.scanline())
+    }
+
+    fn colors(frame: &[u8], line: usize, xs: std::ops::Range<usize>) -> Vec<u8> {
+        xs.map(|x| frame[line * SCREEN_WIDTH + x]).collect()
+    }
+
+    #[test]
+    fn hidden_layers_leave_sprite_zero_hits_alone() {
+        let (frame, hit_line) = layered_frame(|_| {});
+        assert!((100..=103).contains(&hit_line), "{}", hit_line);
+        assert_eq!(colors(&frame, 101, 32..40), [0x16; 8]);
+        assert_eq!(colors(&frame, 101, 40..72), [0x21; 32]);
+        assert_eq!(colors(&frame, 151, 110..118), [0x16; 8]);
+
+        // Without the background the sprite is over the backdrop
+        let (frame, line) = layered_frame(|ppu| ppu.set_layer_visibility(Layer::Background, false));
+        assert_eq!(line, hit_line);
+        assert_eq!(colors(&frame, 101, 32..40), [0x16; 8]);
+        assert_eq!(colors(&frame, 101, 40..72), [0x0F; 32]);
+        assert_eq!(colors(&frame, 151, 110..118), [0x16; 8]);
+
+        let (frame, line) = layered_frame(|ppu| ppu.set_layer_visibility(Layer::Sprites, false));
+        assert_eq!(line, hit_line);
+        assert_eq!(colors(&frame, 101, 32..72), [0x21; 40]);
+        assert!(colors(&frame, 151, 0..SCREEN_WIDTH).iter().all(|&color| color == 0x0F));
+
+        let (frame, line) = layered_frame(|ppu| {
+            ppu.set_layer_visibility(Layer::Background, false);
+            ppu.set_layer_visibility(Layer::Sprites, false);
+        });
+        assert_eq!(line, hit_line);
+        assert!(frame.iter().all(|&color| color == 0x0F));
+    }
+
+    #[test]
+    fn the_tint_gives_each_oam_entry_its_own_color() {
+        let (frame, _) = layered_frame(|ppu| ppu.set_sprite_tint(true));
+        assert_eq!(colors(&frame, 101, 32..40), [sprite_tint_color(0); 8]);
+        assert_eq!(colors(&frame, 101, 40..72), [0x21; 32]);
+        let mut seen = vec![sprite_tint_color(0)];
+        for n in 1..9 {
+            let x = 100 + 10 * n;
+            assert_eq!(colors(&frame, 151, x..x + 8), [sprite_tint_color(n as u8); 8], "sprite {}", n);
+            seen.push(sprite_tint_color(n as u8));
+        }
+        seen.sort();
+        seen.dedup();
+        assert_eq!(seen.len(), 9);
+
+        // 48 colors, then round again
+        let mut colors = (0..48).map(sprite_tint_color).collect::<Vec<_>>();
+        colors.sort();
+        colors.dedup();
+        assert_eq!(colors.len(), 48);
+        assert!((0..16).all(|n| sprite_tint_color(n) == sprite_tint_color(n + 48)));
+    }
+
+    #[test]
+    fn ppudata_access_while_drawing_increments_like_the_renderer() {
+        let mut nes = nes_with(SHOW_BACKGROUND, &[0; 16]);
+        step_to(&mut nes, 0..=340);
+        let ppu = &mut nes.cpu_mut().memory_mut().ppu;
+        // v before and after a $2007 write: a coarse X and a Y increment together. Coarse X
+        // wraps into the next nametable across, and coarse Y into the one below from row 29
+        // but not from the attribute rows.
+        for &(before, after) in [(0x2000, 0x3001), (0x201F, 0x3400), (0x73A0, 0x0801), (0x73E0, 0x0001),
+                                 (0x23A0, 0x33A1)].iter() {
+            ppu.v = before;
+            ppu.write_register(7, 0x42);
+            assert_eq!(ppu.vram_addr(), after, "${:04X}", before);
+        }
+        // Reads still go through the buffer
+        ppu.vram_storeb(0x2005, 0x42);
+        ppu.read_buffer = 0x99;
+        ppu.v = 0x2005;
+        assert_eq!(ppu.read_register(7), 0x99);
+        assert_eq!((ppu.vram_addr(), ppu.read_buffer), (0x3006, 0x42));
+
+        // In vblank it's the usual 1 or 32
+        ppu.scanline = 250;
+        ppu.v = 0x2000;
+        ppu.write_register(7, 0x42);
+        assert_eq!(ppu.vram_addr(), 0x2001);
+        ppu.write_register(0, 0x04);
+        ppu.read_register(7);
+        assert_eq!(ppu.vram_addr(), 0x2021);
+        // and mid-frame with rendering off
+        ppu.scanline = 100;
+        ppu.write_register(1, 0x00);
+        ppu.write_register(7, 0x42);
+        assert_eq!(ppu.vram_addr(), 0x2041);
+    }
+
+    #[test]
+    fn control_writes_are_ignored_while_warming_up() {
+        for &(region, cycles) in [(Region::Ntsc, 29658), (Region::Pal, 33132)].iter() {
+            let rom = testing::build_test_rom("loop: JMP loop", None);
+            let mut nes = Nes::builder().rom_bytes(&rom).region(region).build().unwrap();
+            let ppu = &mut nes.cpu_mut().memory_mut().ppu;
+            ppu.power_on(RamInit::Zero);
+            ppu.step_cpu_cycles(cycles - 1);
+            assert!(ppu.warming_up(), "{:?}", region);
+            ppu.write_register(6, 0x21);
+            ppu.write_register(6, 0x08);
+            assert_eq!(ppu.vram_addr(), 0, "{:?}", region);
+            // Other registers work all along
+            ppu.write_register(3, 0x10);
+            ppu.write_register(4, 0x42);
+            assert_eq!(ppu.oam[0x10], 0x42);
+
+            ppu.step_cpu_cycles(1);
+            assert!(!ppu.warming_up(), "{:?}", region);
+            ppu.write_register(6, 0x21);
+            ppu.write_register(6, 0x08);
+            assert_eq!(ppu.vram_addr(), 0x2108, "{:?}", region);
+            // Reset starts the wait over
+            ppu.reset();
+            assert!(ppu.warming_up(), "{:?}", region);
+        }
+    }
+
+    #[test]
+    fn nmi_enabled_while_warming_up_never_fires() {
+        let program = "
+reset:  LDA #$80
+        STA $2000
+loop:   JMP loop
+nmi:    INC $11
+        RTI
+";
+        let rom = testing::build_test_rom(program, None);
+        for &(warmup, nmis) in [(true, 0), (false, 2)].iter() {
+            let mut nes = Nes::builder().rom_bytes(&rom).ppu_warmup(warmup).build().unwrap();
+            for _ in 0..3 {
+                run_frame(&mut nes);
+            }
+            assert_eq!(nes.cpu().memory().peek(0x11), nmis, "warm-up {}", warmup);
+        }
+    }
+
+    // Each program's fourth instruction reads or writes a PPU register on its last cycle. The
+    // NMI handler counts into $11.
+    const READ_STATUS: &str = "
+reset:  LDA #$80
+        STA $2000
+        NOP
+        LDA $2002
+        STA $10
+idle:   JMP idle
+nmi:    INC $11
+        RTI
+";
+    const DISABLE_NMI: &str = "
+reset:  LDA #$80
+        STA $2000
+        LDA #$00
+        STA $2000
+idle:   JMP idle
+nmi:    INC $11
+        RTI
+";
+    const ENABLE_NMI: &str = "
+reset:  NOP
+        NOP
+        LDA #$80
+        STA $2000
+idle:   JMP idle
+nmi:    INC $11
+        RTI
+";
+
+    // Runs the first three instructions, then moves the PPU so the fourth's register access
+    // lands on the given dot of the line vblank starts on. Returns how many NMIs the handler
+    // saw over the next few dozen instructions.
+    fn nmis_with_access_at(program: &str, mode: StepMode, dot: u16) -> (Nes, u8) {
+        let rom = testing::build_test_rom(program, None);
+        let mut nes = Nes::builder().rom_bytes(&rom).ppu_warmup(false).step_mode(mode).build().unwrap();
+        for _ in 0..3 {
+            assert!(nes.step().unwrap().is_continue());
+        }
+        // Absolute loads and stores take four cycles, three dots each before the access
+        let ppu = &mut nes.cpu_mut().memory_mut().ppu;
+        let lead = 9;
+        let (scanline, dot) = if dot >= lead {
+            (ppu.timing.vblank_scanline, dot - lead)
+        } else {
+            (ppu.timing.vblank_scanline - 1, dot + DOTS_PER_SCANLINE - lead)
+        };
+        ppu.scanline = scanline;
+        ppu.dot = dot;
+        ppu.dot_remainder = 0;
+        if scanline == ppu.timing.vblank_scanline && dot > 1 {
+            // Jumping past the dot the flag goes up on skips it, so put it up here
+            ppu.status |= STATUS_VBLANK;
+        }
+        for _ in 0..40 {
+            assert!(nes.step().unwrap().is_continue());
+        }
+        let nmis = nes.cpu().memory().peek(0x11);
+        (nes, nmis)
+    }
+
+    #[test]
+    fn status_reads_around_vblank_suppress_its_nmi() {
+        for &mode in [StepMode::Instruction, StepMode::Cycle].iter() {
+            let seen = (0..7).map(|dot| {
+                let (nes, nmis) = nmis_with_access_at(READ_STATUS, mode, dot);
+                (nes.cpu().memory().peek(0x10) & STATUS_VBLANK != 0, nmis)
+            }).collect::<Vec<_>>();
+            // The dot before the flag goes up it reads clear and never sets, on the next two it
+            // reads set and the NMI is taken back
+            assert_eq!(seen, [(false, 1), (false, 0), (true, 0), (true, 0), (true, 1), (true, 1), (true, 1)],
+                       "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn disabling_nmi_just_after_vblank_cancels_it() {
+        for &mode in [StepMode::Instruction, StepMode::Cycle].iter() {
+            let nmis = (0..7).map(|dot| nmis_with_access_at(DISABLE_NMI, mode, dot).1).collect::<Vec<_>>();
+            assert_eq!(nmis, [0, 0, 0, 0, 1, 1, 1], "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn enabling_nmi_during_vblank_fires_one() {
+        for &mode in [StepMode::Instruction, StepMode::Cycle].iter() {
+            for &dot in [2, 10, 100, 340].iter() {
+                assert_eq!(nmis_with_access_at(ENABLE_NMI, mode, dot).1, 1, "dot {} {:?}", dot, mode);
+            }
+        }
+    }
+
+    // A cartridge that keeps every address the PPU puts on the bus
+    struct BusLog(Arc<Mutex<Vec<u16>>>);
+
+    impl Mapper for BusLog {
+        fn cpu_peek(&self, _addr: u16) -> u8 { 0 }
+        fn cpu_write(&mut self, _addr: u16, _val: u8) {}
+        fn ppu_peek(&self, _addr: u16) -> u8 { 0 }
+        fn ppu_write(&mut self, _addr: u16, _val: u8) {}
+        fn mirroring(&self) -> Mirroring { Mirroring::Vertical }
+        fn prg_offset(&self, _addr: u16) -> Option<usize> { None }
+
+        fn watches_ppu_addresses(&self) -> bool {
+            true
+        }
+
+        fn on_ppu_address(&mut self, addr: u16) {
+            self.0.lock().unwrap().push(addr);
+        }
+    }
+
+    // A cartridge that counts the A12 rises its filter lets through, the way MMC3 clocks its
+    // scanline counter
+    struct A12Counter {
+        filter: A12Filter,
+        rises: Arc<Mutex<usize>>,
+    }
+
+    impl Mapper for A12Counter {
+        fn cpu_peek(&self, _addr: u16) -> u8 { 0 }
+        fn cpu_write(&mut self, _addr: u16, _val: u8) {}
+        fn ppu_peek(&self, _addr: u16) -> u8 { 0 }
+        fn ppu_write(&mut self, _addr: u16, _val: u8) {}
+        fn mirroring(&self) -> Mirroring { Mirroring::Vertical }
+        fn prg_offset(&self, _addr: u16) -> Option<usize> { None }
+
+        fn watches_ppu_addresses(&self) -> bool {
+            true
+        }
+
+        fn on_ppu_address(&mut self, addr: u16) {
+            if self.filter.on_address(addr) {
+                *self.rises.lock().unwrap() += 1;
+            }
+        }
+
+        fn cpu_clock(&mut self, cycles: u32) {
+            self.filter.cpu_clock(cycles);
+        }
+    }
+
+    // A PPU rendering with ctrl and every sprite below the picture
+    fn watched_ppu(ctrl: u8, mapper: SharedMapper) -> PPU {
+        let mut ppu = PPU::new(mapper, &NTSC_TIMING);
+        ppu.oam = [0xFF; 256];
+        ppu.ctrl = ctrl;
+        ppu.mask = MASK_SHOW_BG | MASK_SHOW_SPRITES;
+        ppu
+    }
+
+    // The rises filter lets through in a frame rendered with ctrl, with the cartridge told of
+    // each CPU cycle as the PPU finishes it
+    fn filtered_a12_rises(ctrl: u8, filter: A12Filter) -> usize {
+        let rises = Arc::new(Mutex::new(0));
+        let mapper = mapper::shared(Box::new(A12Counter { filter: filter, rises: rises.clone() }));
+        let mut ppu = watched_ppu(ctrl, mapper.clone());
+        for _ in 0..(DOTS_PER_SCANLINE as u32 * 262).div_ceil(3) {
+            ppu.step(3);
+            mapper.borrow_mut().cpu_clock(1);
+        }
+        let count = *rises.lock().unwrap();
+        count
+    }
+
+    #[test]
+    fn rendering_puts_170_fetches_a_line_on_the_bus() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut ppu = watched_ppu(CTRL_SPRITE_TABLE, mapper::shared(Box::new(BusLog(log.clone()))));
+        ppu.step(DOTS_PER_SCANLINE as u32);
+        let line = std::mem::take(&mut *log.lock().unwrap());
+        assert_eq!(line.len(), 170);
+        // Tile 0 from $0000, in hardware order: nametable, attribute, pattern low and high
+        assert_eq!(line[..4], [0x2002, 0x23C0, 0x0000, 0x0008]);
+        // Then at dot 257 the first sprite slot: two garbage nametable fetches, then tile $FF
+        // from $1000, as every slot is empty
+        assert_eq!(line[128..132], [0x2000 | (ppu.v & 0x0FFF), 0x2000 | (ppu.v & 0x0FFF), 0x1FF0, 0x1FF8]);
+
+        // The rest of the frame fetches on the other 239 visible lines and the pre-render line,
+        // and nothing happens while rendering is off
+        ppu.step(DOTS_PER_SCANLINE as u32 * 261);
+        assert_eq!(log.lock().unwrap().len(), 170 * 240);
+        ppu.mask = 0;
+        ppu.step(DOTS_PER_SCANLINE as u32 * 262);
+        assert_eq!(log.lock().unwrap().len(), 170 * 240);
+
+        // $2007 puts v on the bus too
+        log.lock().unwrap().clear();
+        ppu.v = 0x2345;
+        ppu.read_register(7);
+        ppu.write_register(7, 0);
+        assert_eq!(*log.lock().unwrap(), [0x2345, 0x2346]);
+    }
+
+    #[test]
+    fn filtered_a12_rises_once_a_line() {
+        // With the background and sprites on different sides, A12 goes high once a line after
+        // a long low, and bounces between the fetches of neighbouring tiles or sprites. 8x16
+        // sprites' empty slots fetch tile $FF, from $1000. MMC3 wants A12 low for three M2
+        // edges, or five fetches: the four between lines with the background on $1000 aren't
+        // enough. Counting M2 edges also sees vblank go by with A12 low, so the background's
+        // first fetch from $1000 on the pre-render line counts as well.
+        for &(ctrl, m2_rises) in [(CTRL_SPRITE_TABLE, 241), (CTRL_BG_TABLE, 242), (CTRL_SPRITE_SIZE, 241)].iter() {
+            assert_eq!(filtered_a12_rises(ctrl, A12Filter::new(A12Clock::Fetches, 5)), 241, "ctrl {:02X}", ctrl);
+            assert_eq!(filtered_a12_rises(ctrl, A12Filter::new(A12Clock::M2, 3)), m2_rises, "ctrl {:02X}", ctrl);
+            assert!(filtered_a12_rises(ctrl, A12Filter::new(A12Clock::Fetches, 1)) > 241 * 7, "ctrl {:02X}", ctrl);
+        }
+    }
+
+    // The dots in each of the next count frames, vblank to vblank, stepping one at a time
+    fn frame_lengths(timing: &'static TimingConfig, mask: u8, count: usize) -> Vec<u32> {
+        let rom = ROM::from_bytes(&testing::build_rom(&[])).unwrap();
+        let mut ppu = PPU::new(mapper::shared(mapper::for_rom(&rom)), timing);
+        ppu.mask = mask;
+        let mut lengths = Vec::new();
+        let mut dots = 0;
+        let mut frame = ppu.frame;
+        // The first frame is only part of one
+        while lengths.len() <= count {
+            ppu.step(1);
+            dots += 1;
+            if ppu.frame != frame {
+                frame = ppu.frame;
+                lengths.push(std::mem::replace(&mut dots, 0));
+            }
+        }
+        lengths.split_off(1)
+    }
+
+    #[test]
+    fn odd_ntsc_frames_skip_a_dot_while_rendering() {
+        let rendering = MASK_SHOW_BG | MASK_SHOW_SPRITES;
+        let lengths = frame_lengths(&NTSC_TIMING, rendering, 4);
+        assert_eq!(lengths, [89341, 89342, 89341, 89342]);
+        // Either layer alone is enough
+        assert_eq!(frame_lengths(&NTSC_TIMING, MASK_SHOW_SPRITES, 2), [89341, 89342]);
+        assert_eq!(frame_lengths(&NTSC_TIMING, 0, 4), [89342; 4]);
+        assert_eq!(frame_lengths(&PAL_TIMING, rendering, 4), [106392; 4]);
+    }
+
+    #[test]
+    fn the_skip_looks_at_rendering_on_the_skip_dot() {
+        let rom = ROM::from_bytes(&testing::build_rom(&[])).unwrap();
+        // Where the PPU is a dot after reaching the skip dot, and a dot after that with
+        // rendering on for it: turning rendering on once the skip dot has gone by is too late
+        for &(mask_at_skip, expected) in [(MASK_SHOW_BG, [(0, 0), (0, 1)]), (0, [(261, 340), (0, 0)])].iter() {
+            let mut ppu = PPU::new(mapper::shared(mapper::for_rom(&rom)), &NTSC_TIMING);
+            ppu.frame = 1;
+            ppu.scanline = NTSC_TIMING.prerender_scanline;
+            ppu.dot = DOTS_PER_SCANLINE - 2;
+            ppu.mask = mask_at_skip;
+            ppu.step(1);
+            let skip_dot = (ppu.scanline(), ppu.dot());
+            ppu.mask = MASK_SHOW_BG;
+            ppu.step(1);
+            assert_eq!([skip_dot, (ppu.scanline(), ppu.dot())], expected, "mask {:02X}", mask_at_skip);
+        }
+    }
+}
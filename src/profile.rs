@@ -0,0 +1,176 @@
+// Where a game spends its time: how often each opcode ran and how often each instruction
+// address was executed. Useful for optimizing, and for spotting a game stuck in a loop it
+// should have left.
+
+use mem::Memory;
+use opcodes::{self, OPCODE_TABLE};
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+// Where an instruction was fetched from. Code in ROM is told apart by where in PRG it lands,
+// so the same address in two banks counts separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Location {
+    pub pc: u16,
+    // Offset into PRG ROM, None for code running from RAM
+    pub prg_offset: Option<usize>,
+}
+
+pub struct Profiler {
+    opcodes: [u64; 256],
+    pcs: HashMap<Location, u64>,
+    instructions: u64,
+}
+
+impl Default for Profiler {
+    fn default() -> Profiler { Profiler::new() }
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            opcodes: [0; 256],
+            pcs: HashMap::new(),
+            instructions: 0,
+        }
+    }
+
+    pub fn record(&mut self, location: Location, opcode: u8) {
+        self.opcodes[opcode as usize] += 1;
+        *self.pcs.entry(location).or_insert(0) += 1;
+        self.instructions += 1;
+    }
+
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    pub fn opcode_count(&self, opcode: u8) -> u64 {
+        self.opcodes[opcode as usize]
+    }
+
+    pub fn count(&self, location: Location) -> u64 {
+        self.pcs.get(&location).cloned().unwrap_or(0)
+    }
+
+    // The n most executed locations, most first. Ties go to the lower address.
+    pub fn hottest(&self, n: usize) -> Vec<(Location, u64)> {
+        let mut pcs: Vec<(Location, u64)> = self.pcs.iter().map(|(&location, &count)| (location, count)).collect();
+        pcs.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        pcs.truncate(n);
+        return pcs
+    }
+
+    pub fn clear(&mut self) {
+        *self = Profiler::new();
+    }
+
+    // Totals, the top hottest instructions disassembled, then every opcode that ran by how
+    // often. Code in ROM is disassembled from the bank it ran from, whatever is mapped now.
+    pub fn report(&self, memory: &Memory, cycles: u64, top: usize) -> String {
+        let mut out = String::new();
+        writeln!(out, "{} instructions, {} cycles", self.instructions, cycles).unwrap();
+        let percent = |count: u64| 100.0 * count as f64 / self.instructions.max(1) as f64;
+
+        writeln!(out, "\nHottest instructions:").unwrap();
+        writeln!(out, "{:>12} {:>6}  ADDR   PRG     INSTRUCTION", "COUNT", "%").unwrap();
+        for (location, count) in self.hottest(top) {
+            let (bytes, prg) = match location.prg_offset {
+                Some(offset) => {
+                    let prg = &memory.rom.prg;
+                    (prg[offset..(offset + 3).min(prg.len())].to_vec(), format!("${:05X}", offset))
+                },
+                None => (memory.dump_range(location.pc, 3), "RAM".to_string()),
+            };
+            let (text, _) = opcodes::disassemble(&bytes, location.pc);
+            writeln!(out, "{:>12} {:>5.1}%  ${:04X}  {:<6}  {}", count, percent(count), location.pc, prg, text).unwrap();
+        }
+
+        writeln!(out, "\nOpcodes:").unwrap();
+        writeln!(out, "{:>12} {:>6}  OP   INSTRUCTION", "COUNT", "%").unwrap();
+        let mut opcodes: Vec<usize> = (0..256).filter(|&opcode| self.opcodes[opcode] != 0).collect();
+        opcodes.sort_by(|&a, &b| self.opcodes[b].cmp(&self.opcodes[a]).then(a.cmp(&b)));
+        for opcode in opcodes {
+            let op = OPCODE_TABLE[opcode];
+            let count = self.opcodes[opcode];
+            writeln!(out, "{:>12} {:>5.1}%  ${:02X}  {} {:?}", count, percent(count), opcode, op.mnemonic, op.mode).unwrap();
+        }
+        return out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm;
+    use testing;
+
+    // A hundred trips round a three-instruction loop, then a jump to itself
+    const LOOP: &str = "
+        LDX #$00
+loop:   INX
+        CPX #100
+        BNE loop
+done:   JMP done
+";
+
+    fn rom_location(pc: u16) -> Location {
+        Location { pc: pc, prg_offset: Some(pc as usize - 0x8000) }
+    }
+
+    #[test]
+    fn loop_body_dominates_the_profile() {
+        let mut cpu = testing::build_program(&asm::assemble(LOOP, 0x8000).unwrap());
+        cpu.enable_profiler();
+        for _ in 0..1 + 300 + 10 {
+            cpu.emulate_cycle().unwrap();
+        }
+        let profiler = cpu.profiler().unwrap();
+        assert_eq!(profiler.instructions(), 311);
+        assert_eq!(profiler.hottest(4), [
+            (rom_location(0x8002), 100), (rom_location(0x8003), 100), (rom_location(0x8005), 100), (rom_location(0x8007), 10),
+        ]);
+        assert_eq!(profiler.count(rom_location(0x8000)), 1);
+        // INX, CPX #, BNE, JMP, LDX #
+        let opcodes: Vec<u64> = [0xE8, 0xE0, 0xD0, 0x4C, 0xA2].iter().map(|&op| profiler.opcode_count(op)).collect();
+        assert_eq!(opcodes, [100, 100, 100, 10, 1]);
+
+        let report = cpu.profile_report(3).unwrap();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "311 instructions, 731 cycles");
+        assert_eq!(&lines[4..7], [
+            "         100  32.2%  $8002  $00002  INX",
+            "         100  32.2%  $8003  $00003  CPX #$64",
+            "         100  32.2%  $8005  $00005  BNE $8002",
+        ]);
+        assert_eq!(lines[7], "");
+        assert_eq!(lines.last(), Some(&"           1   0.3%  $A2  LDX Immediate"));
+    }
+
+    #[test]
+    fn banks_are_counted_and_disassembled_apart() {
+        let mut cpu = testing::build_program(&asm::assemble(LOOP, 0x8000).unwrap());
+        cpu.memory_mut().load_range(0x0300, &[0xEA]).unwrap();
+        let mut profiler = Profiler::new();
+        // $8000 run from two places in PRG, and code in RAM
+        let other_bank = Location { pc: 0x8000, prg_offset: Some(2) };
+        profiler.record(rom_location(0x8000), 0xA2);
+        profiler.record(other_bank, 0xE8);
+        profiler.record(other_bank, 0xE8);
+        profiler.record(Location { pc: 0x0300, prg_offset: None }, 0xEA);
+        assert_eq!((profiler.count(rom_location(0x8000)), profiler.count(other_bank)), (1, 2));
+
+        let report = profiler.report(cpu.memory(), 0, 3);
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(&lines[4..7], [
+            "           2  50.0%  $8000  $00002  INX",
+            "           1  25.0%  $0300  RAM     NOP",
+            "           1  25.0%  $8000  $00000  LDX #$00",
+        ]);
+
+        profiler.clear();
+        assert_eq!((profiler.instructions(), profiler.opcode_count(0xE8)), (0, 0));
+        assert!(profiler.hottest(10).is_empty());
+    }
+}
@@ -0,0 +1,91 @@
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    Ntsc,
+    Pal,
+}
+
+impl FromStr for Region {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Region, &'static str> {
+        match s.to_ascii_lowercase().as_str() {
+            "ntsc" => Ok(Region::Ntsc),
+            "pal" => Ok(Region::Pal),
+            _ => Err("region must be ntsc or pal"),
+        }
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Region::Ntsc => write!(f, "ntsc"),
+            Region::Pal => write!(f, "pal"),
+        }
+    }
+}
+
+// Everything about the console's clocks that differs between regions
+pub struct TimingConfig {
+    pub cpu_clock_hz: u32,
+    pub frame_rate: f64,
+    pub scanlines_per_frame: u16,
+    pub vblank_scanline: u16,
+    pub prerender_scanline: u16,
+    // Whether the pre-render line is a dot short on odd frames while rendering is on
+    pub odd_frame_skip: bool,
+    // PPU dots per CPU cycle, as a fraction: 3/1 on NTSC, 16/5 on PAL
+    pub ppu_dots_per_cycle: u32,
+    pub cycles_per_ppu_dots: u32,
+    // CPU cycles at which the APU frame counter's sequence clocks. Four-step mode stops at the
+    // fourth, five-step mode skips it and goes on to the fifth.
+    pub frame_counter_steps: [u32; 5],
+    // Noise and DMC timer periods, in CPU cycles, by the 4-bit period index
+    pub noise_periods: [u16; 16],
+    pub dmc_rates: [u16; 16],
+    // CPU cycles after power-on or reset that the PPU ignores writes to $2000, $2001, $2005
+    // and $2006
+    pub ppu_warmup_cycles: u32,
+}
+
+pub const NTSC_TIMING: TimingConfig = TimingConfig {
+    cpu_clock_hz: 1789773,
+    frame_rate: 60.0988,
+    scanlines_per_frame: 262,
+    vblank_scanline: 241,
+    prerender_scanline: 261,
+    odd_frame_skip: true,
+    ppu_dots_per_cycle: 3,
+    cycles_per_ppu_dots: 1,
+    frame_counter_steps: [7457, 14913, 22371, 29829, 37281],
+    noise_periods: [4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068],
+    dmc_rates: [428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54],
+    ppu_warmup_cycles: 29658,
+};
+
+pub const PAL_TIMING: TimingConfig = TimingConfig {
+    cpu_clock_hz: 1662607,
+    frame_rate: 50.0070,
+    scanlines_per_frame: 312,
+    vblank_scanline: 241,
+    prerender_scanline: 311,
+    odd_frame_skip: false,
+    ppu_dots_per_cycle: 16,
+    cycles_per_ppu_dots: 5,
+    frame_counter_steps: [8313, 16627, 24939, 33253, 41565],
+    noise_periods: [4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778],
+    dmc_rates: [398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50],
+    ppu_warmup_cycles: 33132,
+};
+
+impl Region {
+    pub fn timing(&self) -> &'static TimingConfig {
+        match *self {
+            Region::Ntsc => &NTSC_TIMING,
+            Region::Pal => &PAL_TIMING,
+        }
+    }
+}
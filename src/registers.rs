@@ -0,0 +1,90 @@
+use std::fmt;
+
+// Bits for Registers::flags
+pub const CARRY_FLAG: u8 = 1 << 0;
+pub const ZERO_FLAG: u8 = 1 << 1;
+pub const INT_FLAG: u8 = 1 << 2;
+pub const DEC_FLAG: u8 = 1 << 3;
+// B: never held in the register, only set in the copy BRK and PHP push
+pub const S1_FLAG: u8 = 1 << 4;
+// Always reads as 1
+pub const S2_FLAG: u8 = 1 << 5;
+pub const OVERFLOW_FLAG: u8 = 1 << 6;
+pub const NEG_FLAG: u8 = 1 << 7;
+
+// The status register as seen from outside the CPU
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatusFlags(u8);
+
+impl StatusFlags {
+    // Bit 5 is forced on and B dropped, as in the register itself
+    pub fn from_bits(bits: u8) -> StatusFlags {
+        StatusFlags((bits & !S1_FLAG) | S2_FLAG)
+    }
+
+    pub fn bits(&self) -> u8 { self.0 }
+
+    fn set(&mut self, flag: u8, value: bool) {
+        if value { self.0 |= flag; } else { self.0 &= !flag; }
+    }
+
+    pub fn carry(&self) -> bool { self.0 & CARRY_FLAG != 0 }
+    pub fn zero(&self) -> bool { self.0 & ZERO_FLAG != 0 }
+    pub fn interrupt_disable(&self) -> bool { self.0 & INT_FLAG != 0 }
+    pub fn decimal(&self) -> bool { self.0 & DEC_FLAG != 0 }
+    pub fn overflow(&self) -> bool { self.0 & OVERFLOW_FLAG != 0 }
+    pub fn negative(&self) -> bool { self.0 & NEG_FLAG != 0 }
+
+    pub fn set_carry(&mut self, value: bool) { self.set(CARRY_FLAG, value); }
+    pub fn set_zero(&mut self, value: bool) { self.set(ZERO_FLAG, value); }
+    pub fn set_interrupt_disable(&mut self, value: bool) { self.set(INT_FLAG, value); }
+    pub fn set_decimal(&mut self, value: bool) { self.set(DEC_FLAG, value); }
+    pub fn set_overflow(&mut self, value: bool) { self.set(OVERFLOW_FLAG, value); }
+    pub fn set_negative(&mut self, value: bool) { self.set(NEG_FLAG, value); }
+}
+
+pub struct Registers {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub s: u8, // Stack pointer
+    pub pc: u16,
+    // Status register, only reachable through the accessors so bit 5 stays set and B clear
+    flags: u8,
+}
+
+impl Default for Registers {
+    fn default() -> Registers {
+        Registers { a: 0, x: 0, y: 0, s: 0, pc: 0, flags: S2_FLAG }
+    }
+}
+
+impl Registers {
+    pub fn flags(&self) -> u8 {
+        self.flags
+    }
+
+    // Load the whole status register, e.g. from the stack: B is dropped and bit 5 forced on
+    pub fn set_flags(&mut self, flags: u8) {
+        self.flags = (flags & !S1_FLAG) | S2_FLAG;
+    }
+
+    pub fn get_flag(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    pub fn set_flag(&mut self, flag: u8, value: bool) {
+        if value {
+            self.set_flags(self.flags | flag);
+        } else {
+            self.set_flags(self.flags & !flag);
+        }
+    }
+}
+
+impl fmt::Debug for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Registers a: {:#02x}, x: {:#02x}, y: {:#02x}, s: {:#02x}, flags: {:#02x}, pc: {:#04x}",
+            self.a, self.x, self.y, self.s, self.flags, self.pc)
+    }
+}
@@ -0,0 +1,510 @@
+// Regression runs: play each ROM in a directory to a given frame and compare the picture (and
+// optionally some RAM) against what a manifest says it should be.
+//
+// The manifest is a small subset of TOML, one [[rom]] table per check:
+//   [[rom]]
+//   file = "smb.nes"              # relative to the ROM directory
+//   frame = 240
+//   hash = "1f0e4c2a9b3d5e60"     # the frame's frame_hash(), filled in by blessing
+//   audio_hash = "8c1d3f09e2b4a675"  # the APU's output_hash_for_frame(), also from blessing
+//   movie = "smb-start.fm2"       # optional input, otherwise no buttons are pressed
+//   ram = ["075A:02", "0770:01"]  # optional ADDR:VALUE expectations, in hex
+// A file ending in .s is a program for the mini-assembler, built into an NROM image by
+// testing::build_test_rom when the run starts (this needs the testing feature). Its CHR comes
+// from an optional chr = "tiles.chr" file, otherwise the cartridge has CHR RAM.
+// Values are double-quoted strings (with \" and \\ escapes), integers, or one-line arrays of
+// strings, as flat_toml reads them.
+
+use controller::InputFrame;
+use cpu::EmulationError;
+use emulator::{BuildError, Nes};
+use flat_toml::{self, Value};
+use mem::RamInit;
+use movie;
+#[cfg(feature = "testing")]
+use testing;
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum ManifestError {
+    Io(io::Error),
+    Syntax { line: usize },
+    // A key = value line before the first [[rom]]
+    NoTable { line: usize },
+    UnknownKey { line: usize, name: String },
+    BadValue { line: usize, name: String },
+    // Every [[rom]] needs a file and a frame
+    Missing { line: usize, name: &'static str },
+}
+
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ManifestError::Io(ref e) => write!(f, "{}", e),
+            ManifestError::Syntax { line } => write!(f, "line {}: expected [[rom]] or key = value", line),
+            ManifestError::NoTable { line } => write!(f, "line {}: setting before any [[rom]]", line),
+            ManifestError::UnknownKey { line, ref name } => write!(f, "line {}: unknown key {}", line, name),
+            ManifestError::BadValue { line, ref name } => write!(f, "line {}: bad value for {}", line, name),
+            ManifestError::Missing { line, name } => write!(f, "line {}: [[rom]] has no {}", line, name),
+        }
+    }
+}
+
+impl From<io::Error> for ManifestError {
+    fn from(e: io::Error) -> ManifestError {
+        ManifestError::Io(e)
+    }
+}
+
+// One [[rom]] table
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub file: String,
+    pub frame: u64,
+    // None until the entry has been blessed
+    pub hash: Option<u64>,
+    // The hash of the samples the APU put out over the frame before. Entries blessed before
+    // there was sound don't have one, and their audio isn't checked.
+    pub audio_hash: Option<u64>,
+    pub movie: Option<String>,
+    // CHR for a .s program
+    pub chr: Option<String>,
+    pub ram: Vec<(u16, u8)>,
+    // Where the table's header and hashes are in the file, so blessing can rewrite just those
+    header_line: usize,
+    hash_line: Option<usize>,
+    audio_hash_line: Option<usize>,
+}
+
+pub struct Manifest {
+    pub entries: Vec<Entry>,
+    // The file as read, comments and all
+    lines: Vec<String>,
+}
+
+// ADDR:VALUE in hex, like a RAM freeze cheat
+fn parse_ram(s: &str) -> Option<(u16, u8)> {
+    let (addr, value) = s.split_once(':')?;
+    Some((u16::from_str_radix(addr, 16).ok()?, u8::from_str_radix(value, 16).ok()?))
+}
+
+fn hash_line(name: &str, hash: u64) -> String {
+    format!("{} = \"{:016x}\"", name, hash)
+}
+
+impl Manifest {
+    pub fn load(path: &Path) -> Result<Manifest, ManifestError> {
+        Manifest::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Manifest, ManifestError> {
+        let mut entries: Vec<Entry> = Vec::new();
+        // Entries start out with a frame of 0 and are checked for one at the end
+        let mut has_frame = Vec::new();
+        for (line, content) in flat_toml::content_lines(text) {
+            let index = line - 1;
+            if content == "[[rom]]" {
+                entries.push(Entry {
+                    file: String::new(),
+                    frame: 0,
+                    hash: None,
+                    audio_hash: None,
+                    movie: None,
+                    chr: None,
+                    ram: Vec::new(),
+                    header_line: index,
+                    hash_line: None,
+                    audio_hash_line: None,
+                });
+                has_frame.push(false);
+                continue;
+            }
+
+            let (name, value) = content.split_once('=').ok_or(ManifestError::Syntax { line: line })?;
+            let name = name.trim();
+            let entry = entries.last_mut().ok_or(ManifestError::NoTable { line: line })?;
+            let bad_value = || ManifestError::BadValue { line: line, name: name.to_string() };
+            let value = flat_toml::parse_value(value.trim()).ok_or_else(bad_value)?;
+            match (name, value) {
+                ("file", Value::Str(file)) => entry.file = file,
+                ("frame", Value::Number(frame)) => {
+                    entry.frame = flat_toml::parse_int(&frame).ok_or_else(bad_value)?;
+                    *has_frame.last_mut().unwrap() = true;
+                },
+                ("hash", Value::Str(hash)) => {
+                    entry.hash = Some(u64::from_str_radix(&hash, 16).map_err(|_| bad_value())?);
+                    entry.hash_line = Some(index);
+                },
+                ("audio_hash", Value::Str(hash)) => {
+                    entry.audio_hash = Some(u64::from_str_radix(&hash, 16).map_err(|_| bad_value())?);
+                    entry.audio_hash_line = Some(index);
+                },
+                ("movie", Value::Str(movie)) => entry.movie = Some(movie),
+                ("chr", Value::Str(chr)) => entry.chr = Some(chr),
+                ("ram", Value::List(items)) => {
+                    for item in items.iter() {
+                        entry.ram.push(parse_ram(item).ok_or_else(bad_value)?);
+                    }
+                },
+                ("file", _) | ("frame", _) | ("hash", _) | ("audio_hash", _) | ("movie", _) | ("chr", _) | ("ram", _) =>
+                    return Err(bad_value()),
+                _ => return Err(ManifestError::UnknownKey { line: line, name: name.to_string() }),
+            }
+        }
+
+        for (entry, &has_frame) in entries.iter().zip(has_frame.iter()) {
+            let line = entry.header_line + 1;
+            if entry.file.is_empty() {
+                return Err(ManifestError::Missing { line: line, name: "file" })
+            }
+            if !has_frame {
+                return Err(ManifestError::Missing { line: line, name: "frame" })
+            }
+        }
+        Ok(Manifest {
+            entries: entries,
+            lines: text.lines().map(str::to_string).collect(),
+        })
+    }
+
+    // The manifest as it was read, with each entry's hash lines replaced by its current hashes,
+    // or added under the [[rom]] header if it didn't have them
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for (index, line) in self.lines.iter().enumerate() {
+            let hashed = self.entries.iter().find(|entry| entry.hash_line == Some(index));
+            let audio_hashed = self.entries.iter().find(|entry| entry.audio_hash_line == Some(index));
+            match (hashed.and_then(|entry| entry.hash), audio_hashed.and_then(|entry| entry.audio_hash)) {
+                (Some(hash), _) => text.push_str(&hash_line("hash", hash)),
+                (_, Some(hash)) => text.push_str(&hash_line("audio_hash", hash)),
+                _ => text.push_str(line),
+            }
+            text.push('\n');
+
+            // A new audio hash goes under the picture's
+            if let Some(entry) = self.entries.iter().find(|entry| entry.header_line == index) {
+                if let (None, Some(hash)) = (entry.hash_line, entry.hash) {
+                    text.push_str(&hash_line("hash", hash));
+                    text.push('\n');
+                }
+            }
+            let anchored = self.entries.iter().find(|entry| entry.hash_line.unwrap_or(entry.header_line) == index);
+            if let Some(entry) = anchored {
+                if let (None, Some(hash)) = (entry.audio_hash_line, entry.audio_hash) {
+                    text.push_str(&hash_line("audio_hash", hash));
+                    text.push('\n');
+                }
+            }
+        }
+        return text
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_text())
+    }
+}
+
+#[derive(Debug)]
+pub enum RunError {
+    Io(io::Error),
+    Build(BuildError),
+    Movie(movie::MovieError),
+    Emulation(EmulationError),
+    // A .s program that doesn't assemble, or can't be without the testing feature
+    Assembly(String),
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RunError::Io(ref e) => write!(f, "{}", e),
+            RunError::Build(ref e) => write!(f, "{}", e),
+            RunError::Movie(ref e) => write!(f, "{}", e),
+            RunError::Emulation(ref e) => write!(f, "CPU stopped: {}", e),
+            RunError::Assembly(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for RunError {
+    fn from(e: io::Error) -> RunError {
+        RunError::Io(e)
+    }
+}
+
+impl From<BuildError> for RunError {
+    fn from(e: BuildError) -> RunError {
+        RunError::Build(e)
+    }
+}
+
+impl From<movie::MovieError> for RunError {
+    fn from(e: movie::MovieError) -> RunError {
+        RunError::Movie(e)
+    }
+}
+
+impl From<EmulationError> for RunError {
+    fn from(e: EmulationError) -> RunError {
+        RunError::Emulation(e)
+    }
+}
+
+// What a ROM looked like at its entry's frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outcome {
+    pub hash: u64,
+    pub audio_hash: u64,
+    // The values at the addresses the entry checks, in the same order
+    pub ram: Vec<u8>,
+}
+
+// The iNES image an entry runs, assembling it first if it's a program
+#[cfg(feature = "testing")]
+fn load_image(entry: &Entry, rom_dir: &Path) -> Result<Vec<u8>, RunError> {
+    if !entry.file.ends_with(".s") {
+        return Ok(fs::read(rom_dir.join(&entry.file))?)
+    }
+    let source = fs::read_to_string(rom_dir.join(&entry.file))?;
+    let chr = match entry.chr {
+        Some(ref path) => Some(fs::read(rom_dir.join(path))?),
+        None => None,
+    };
+    testing::try_build_test_rom(&source, chr.as_deref()).map_err(|e| RunError::Assembly(format!("{}: {}", entry.file, e)))
+}
+
+#[cfg(not(feature = "testing"))]
+fn load_image(entry: &Entry, rom_dir: &Path) -> Result<Vec<u8>, RunError> {
+    if entry.file.ends_with(".s") {
+        return Err(RunError::Assembly(format!("{}: assembling programs needs the testing feature", entry.file)))
+    }
+    Ok(fs::read(rom_dir.join(&entry.file))?)
+}
+
+// Power on from zeroed RAM and run to the entry's frame, pressing only what the movie says
+pub fn run_entry(entry: &Entry, rom_dir: &Path) -> Result<Outcome, RunError> {
+    let mut nes = Nes::builder()
+        .rom_bytes(&load_image(entry, rom_dir)?)
+        .ram_init(RamInit::Zero)
+        .build()?;
+    let input = match entry.movie {
+        Some(ref path) => {
+            let movie = movie::Movie::parse(&fs::read_to_string(rom_dir.join(path))?)?;
+            movie.check_rom(&movie::rom_checksum(&nes.cpu().memory().rom.md5()))?;
+            Some(movie)
+        },
+        None => None,
+    };
+
+    while nes.cpu().memory().ppu.frame < entry.frame {
+        let frame = nes.cpu().memory().ppu.frame;
+        let buttons = input.as_ref().and_then(|movie| movie.frame(frame as usize)).unwrap_or([0, 0]);
+        nes.run_frame(InputFrame::from(buttons))?;
+    }
+
+    let memory = nes.cpu().memory();
+    Ok(Outcome {
+        hash: memory.ppu.frame_hash(),
+        audio_hash: memory.apu.output_hash_for_frame(),
+        ram: entry.ram.iter().map(|&(addr, _)| memory.peek(addr)).collect(),
+    })
+}
+
+#[derive(Debug)]
+pub enum Verdict {
+    Pass,
+    // The manifest has no hash for this entry yet
+    Unblessed { actual: u64 },
+    HashMismatch { expected: u64, actual: u64 },
+    AudioMismatch { expected: u64, actual: u64 },
+    RamMismatch { addr: u16, expected: u8, actual: u8 },
+    // Two runs from power-on didn't end up in the same place. The hashes are the pictures',
+    // unless only the audio differed.
+    Nondeterministic { audio: bool, first: u64, second: u64 },
+    Failed(RunError),
+}
+
+impl Verdict {
+    pub fn passed(&self) -> bool {
+        matches!(*self, Verdict::Pass)
+    }
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Verdict::Pass => write!(f, "ok"),
+            Verdict::Unblessed { actual } => write!(f, "no expected hash, got {:016x}", actual),
+            Verdict::HashMismatch { expected, actual } => write!(f, "expected hash {:016x}, got {:016x}", expected, actual),
+            Verdict::AudioMismatch { expected, actual } =>
+                write!(f, "expected audio hash {:016x}, got {:016x}", expected, actual),
+            Verdict::RamMismatch { addr, expected, actual } =>
+                write!(f, "expected {:02X} at ${:04X}, got {:02X}", expected, addr, actual),
+            Verdict::Nondeterministic { audio, first, second } =>
+                write!(f, "nondeterministic, runs gave {}{:016x} and {:016x}", if audio { "audio " } else { "" }, first, second),
+            Verdict::Failed(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+// Run an entry twice from power-on and make sure both runs ended up in the same place
+pub fn run_twice(entry: &Entry, rom_dir: &Path) -> Result<Outcome, Verdict> {
+    let first = run_entry(entry, rom_dir).map_err(Verdict::Failed)?;
+    let second = run_entry(entry, rom_dir).map_err(Verdict::Failed)?;
+    if first.hash == second.hash && first.audio_hash != second.audio_hash {
+        return Err(Verdict::Nondeterministic { audio: true, first: first.audio_hash, second: second.audio_hash })
+    }
+    if first != second {
+        return Err(Verdict::Nondeterministic { audio: false, first: first.hash, second: second.hash })
+    }
+    Ok(first)
+}
+
+// Hold a run up against what the manifest expects
+pub fn judge(entry: &Entry, outcome: &Outcome) -> Verdict {
+    match entry.hash {
+        None => return Verdict::Unblessed { actual: outcome.hash },
+        Some(expected) if expected != outcome.hash => return Verdict::HashMismatch { expected: expected, actual: outcome.hash },
+        Some(_) => {},
+    }
+    match entry.audio_hash {
+        Some(expected) if expected != outcome.audio_hash =>
+            return Verdict::AudioMismatch { expected: expected, actual: outcome.audio_hash },
+        _ => {},
+    }
+    for (&(addr, expected), &actual) in entry.ram.iter().zip(outcome.ram.iter()) {
+        if expected != actual {
+            return Verdict::RamMismatch { addr: addr, expected: expected, actual: actual }
+        }
+    }
+    return Verdict::Pass
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    fn rom_dir() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("roms")
+    }
+
+    const MANIFEST: &str = "\
+# Checks
+[[rom]]
+file = \"a.nes\"   # the # in \"a#b\" below isn't a comment
+frame = 1_000
+
+[[rom]]
+file = \"a#b.s\"
+hash = \"00000000000000ff\"
+frame = 0x10
+chr = \"tiles.chr\"
+movie = \"in.fm2\"
+ram = [\"0010:7F\", \"07FF:00\"]
+";
+
+    #[test]
+    fn manifests_parse() {
+        let manifest = Manifest::parse(MANIFEST).unwrap();
+        assert_eq!(manifest.entries.len(), 2);
+        let (first, second) = (&manifest.entries[0], &manifest.entries[1]);
+        assert_eq!((first.file.as_str(), first.frame, first.hash, first.audio_hash), ("a.nes", 1000, None, None));
+        assert_eq!((second.file.as_str(), second.frame, second.hash), ("a#b.s", 16, Some(0xFF)));
+        assert_eq!((second.chr.as_deref(), second.movie.as_deref()), (Some("tiles.chr"), Some("in.fm2")));
+        assert_eq!(second.ram, [(0x0010, 0x7F), (0x07FF, 0x00)]);
+        // Nothing changed, so nothing moves
+        assert_eq!(manifest.to_text(), MANIFEST);
+    }
+
+    #[test]
+    fn blessing_rewrites_only_the_hashes() {
+        let mut manifest = Manifest::parse(MANIFEST).unwrap();
+        manifest.entries[0].hash = Some(0x1234);
+        manifest.entries[0].audio_hash = Some(0x5678);
+        manifest.entries[1].hash = Some(0xABCD);
+        manifest.entries[1].audio_hash = Some(0xEF);
+        assert_eq!(manifest.to_text(), "\
+# Checks
+[[rom]]
+hash = \"0000000000001234\"
+audio_hash = \"0000000000005678\"
+file = \"a.nes\"   # the # in \"a#b\" below isn't a comment
+frame = 1_000
+
+[[rom]]
+file = \"a#b.s\"
+hash = \"000000000000abcd\"
+audio_hash = \"00000000000000ef\"
+frame = 0x10
+chr = \"tiles.chr\"
+movie = \"in.fm2\"
+ram = [\"0010:7F\", \"07FF:00\"]
+");
+        // and blessing again rewrites them where they now are
+        let mut reparsed = Manifest::parse(&manifest.to_text()).unwrap();
+        reparsed.entries[0].audio_hash = Some(0x9);
+        assert!(reparsed.to_text().contains("hash = \"0000000000001234\"\naudio_hash = \"0000000000000009\"\nfile"));
+    }
+
+    #[test]
+    fn manifest_errors_give_the_line() {
+        let error = |text: &str| Manifest::parse(text).err().unwrap().to_string();
+        assert_eq!(error("file = \"a.nes\""), "line 1: setting before any [[rom]]");
+        assert_eq!(error("[[rom]]\nfile = \"a.nes\"\nframes = 3"), "line 3: unknown key frames");
+        assert_eq!(error("[[rom]]\nframe = \"3\""), "line 2: bad value for frame");
+        assert_eq!(error("[[rom]]\nram = [\"10:1FF\"]"), "line 2: bad value for ram");
+        assert_eq!(error("[[rom]]\nhash = \"xyz\""), "line 2: bad value for hash");
+        assert_eq!(error("[[rom]]\n[rom]"), "line 2: expected [[rom]] or key = value");
+        assert_eq!(error("\n[[rom]]\nframe = 1"), "line 2: [[rom]] has no file");
+        assert_eq!(error("[[rom]]\nfile = \"a.nes\"\n[[rom]]\nfile = \"b.nes\"\nframe = 1"), "line 1: [[rom]] has no frame");
+    }
+
+    #[test]
+    fn judging_checks_the_picture_then_audio_then_ram() {
+        let mut entry = Manifest::parse("[[rom]]\nfile = \"a.nes\"\nframe = 1\nram = [\"0010:01\"]").unwrap().entries.remove(0);
+        let outcome = Outcome { hash: 1, audio_hash: 2, ram: vec![3] };
+        assert!(matches!(judge(&entry, &outcome), Verdict::Unblessed { actual: 1 }));
+        entry.hash = Some(9);
+        assert_eq!(judge(&entry, &outcome).to_string(), "expected hash 0000000000000009, got 0000000000000001");
+        // Entries without an audio hash don't check audio
+        entry.hash = Some(1);
+        assert_eq!(judge(&entry, &outcome).to_string(), "expected 01 at $0010, got 03");
+        entry.audio_hash = Some(9);
+        assert!(matches!(judge(&entry, &outcome), Verdict::AudioMismatch { expected: 9, actual: 2 }));
+        entry.audio_hash = Some(2);
+        entry.ram[0].1 = 3;
+        assert!(judge(&entry, &outcome).passed());
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn the_test_programs_pass_their_manifest() {
+        let manifest = Manifest::load(&rom_dir().join("regression.toml")).unwrap();
+        assert!(!manifest.entries.is_empty());
+        for entry in manifest.entries.iter() {
+            let outcome = run_twice(entry, &rom_dir()).unwrap_or_else(|verdict| panic!("{}: {}", entry.file, verdict));
+            let verdict = judge(entry, &outcome);
+            assert!(verdict.passed(), "{}: {}", entry.file, verdict);
+        }
+    }
+
+    #[cfg(not(feature = "testing"))]
+    #[test]
+    fn programs_need_the_testing_feature() {
+        let entry = Manifest::parse("[[rom]]\nfile = \"cpu_arith.s\"\nframe = 1").unwrap().entries.remove(0);
+        let err = run_entry(&entry, &rom_dir()).err().unwrap();
+        assert_eq!(err.to_string(), "cpu_arith.s: assembling programs needs the testing feature");
+    }
+
+    #[test]
+    fn missing_roms_fail_the_run() {
+        let entry = Manifest::parse("[[rom]]\nfile = \"missing.nes\"\nframe = 1").unwrap().entries.remove(0);
+        assert!(matches!(run_twice(&entry, &rom_dir()), Err(Verdict::Failed(RunError::Io(_)))));
+    }
+}
@@ -0,0 +1,284 @@
+// Band-limited resampling from one fixed rate to another, for the APU's one level per CPU cycle
+// (about 1.79 MHz) down to the host's 44.1 or 48 kHz.
+//
+// The input is treated as a level that holds until it changes, and each change is added to
+// the output as a band-limited step, the way blip_buf does it: the step's shape, a windowed
+// sinc integrated, is spread over WIDTH output samples, picked from PHASES copies by where
+// between two output samples the change lands. Input that doesn't change costs nothing but
+// the phase count, and nothing above the output's Nyquist frequency folds back down the way
+// dropping or averaging samples lets it.
+//
+// It's all integer arithmetic, so the output is the same bit for bit everywhere. A change
+// can still move the WIDTH output samples after it, so output lags input by a fixed
+// WIDTH / 2 - 1 samples, about 0.15 ms at 48 kHz, and never more.
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use std::collections::VecDeque;
+
+// Output samples a step is spread over
+const WIDTH: usize = 16;
+// Positions between two output samples a step can land at
+const PHASES: u64 = 32;
+// Each row of STEP_KERNEL adds up to this, so a step of n ends up exactly n higher
+const KERNEL_BITS: u32 = 15;
+
+// The differences along a band-limited step, one row per phase. Generated from a sinc with
+// its cutoff at 0.45 of the output rate, under a Blackman window 14 samples wide, integrated
+// and sampled at i - phase / PHASES - 7 for i in 0..WIDTH, and rounded to KERNEL_BITS fraction
+// bits before taking differences, so each row sums exactly to 1 << KERNEL_BITS.
+const STEP_KERNEL: [[i16; WIDTH]; PHASES as usize] = [
+    [0, -9, 40, -41, -162, 949, -3216, 18823, 18823, -3216, 949, -162, -41, 40, -9, 0],
+    [0, -8, 33, -17, -218, 1050, -3357, 17980, 19637, -3035, 833, -101, -66, 47, -10, 0],
+    [0, -7, 26, 6, -270, 1138, -3462, 17113, 20419, -2815, 704, -35, -92, 54, -11, 0],
+    [0, -6, 20, 26, -316, 1212, -3530, 16225, 21164, -2553, 561, 34, -118, 61, -12, 0],
+    [0, -5, 14, 45, -357, 1273, -3566, 15321, 21869, -2249, 406, 108, -147, 69, -13, 0],
+    [0, -4, 9, 61, -391, 1318, -3568, 14404, 22532, -1904, 239, 185, -175, 76, -14, 0],
+    [0, -4, 5, 77, -422, 1351, -3539, 13478, 23149, -1517, 61, 265, -205, 84, -15, 0],
+    [0, -3, 0, 90, -446, 1372, -3484, 12548, 23716, -1086, -128, 348, -234, 91, -16, 0],
+    [0, -2, -4, 102, -467, 1381, -3401, 11616, 24232, -614, -325, 432, -264, 99, -17, 0],
+    [0, -2, -7, 112, -481, 1376, -3294, 10689, 24693, -100, -530, 517, -293, 106, -18, 0],
+    [0, -2, -10, 120, -490, 1361, -3165, 9768, 25097, 457, -743, 602, -320, 112, -19, 0],
+    [0, -1, -13, 127, -496, 1336, -3017, 8858, 25443, 1053, -960, 687, -348, 118, -19, 0],
+    [0, -1, -15, 132, -496, 1301, -2853, 7964, 25727, 1689, -1181, 771, -374, 124, -20, 0],
+    [0, -1, -16, 135, -492, 1256, -2672, 7086, 25951, 2362, -1404, 853, -398, 128, -20, 0],
+    [0, 0, -19, 138, -485, 1204, -2479, 6229, 26112, 3071, -1627, 933, -421, 132, -20, 0],
+    [0, 0, -19, 137, -473, 1145, -2276, 5397, 26207, 3816, -1848, 1008, -441, 135, -20, 0],
+    [0, 0, -20, 137, -459, 1080, -2065, 4591, 26240, 4591, -2065, 1080, -459, 137, -20, 0],
+    [0, 0, -20, 135, -441, 1008, -1848, 3816, 26207, 5397, -2276, 1145, -473, 137, -19, 0],
+    [0, 0, -20, 132, -421, 933, -1627, 3071, 26112, 6229, -2479, 1204, -485, 138, -19, 0],
+    [0, 0, -20, 128, -398, 853, -1404, 2362, 25951, 7086, -2672, 1256, -492, 135, -16, -1],
+    [0, 0, -20, 124, -374, 771, -1181, 1689, 25727, 7964, -2853, 1301, -496, 132, -15, -1],
+    [0, 0, -19, 118, -348, 687, -960, 1053, 25443, 8858, -3017, 1336, -496, 127, -13, -1],
+    [0, 0, -19, 112, -320, 602, -743, 457, 25097, 9768, -3165, 1361, -490, 120, -10, -2],
+    [0, 0, -18, 106, -293, 517, -530, -100, 24693, 10689, -3294, 1376, -481, 112, -7, -2],
+    [0, 0, -17, 99, -264, 432, -325, -614, 24232, 11616, -3401, 1381, -467, 102, -4, -2],
+    [0, 0, -16, 91, -234, 348, -128, -1086, 23716, 12548, -3484, 1372, -446, 90, 0, -3],
+    [0, 0, -15, 84, -205, 265, 61, -1517, 23149, 13478, -3539, 1351, -422, 77, 5, -4],
+    [0, 0, -14, 76, -175, 185, 239, -1904, 22532, 14404, -3568, 1318, -391, 61, 9, -4],
+    [0, 0, -13, 69, -147, 108, 406, -2249, 21869, 15321, -3566, 1273, -357, 45, 14, -5],
+    [0, 0, -12, 61, -118, 34, 561, -2553, 21164, 16225, -3530, 1212, -316, 26, 20, -6],
+    [0, 0, -11, 54, -92, -35, 704, -2815, 20419, 17113, -3462, 1138, -270, 6, 26, -7],
+    [0, 0, -10, 47, -66, -101, 833, -3035, 19637, 17980, -3357, 1050, -218, -17, 33, -8],
+];
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resampler {
+    in_rate: u64,
+    out_rate: u64,
+    // Goes up by out_rate every input sample, and an output sample is finished whenever it
+    // reaches in_rate. Where it is in between is where a step lands.
+    phase: u64,
+    // The last input level
+    level: i32,
+    // The differences still to come, for the WIDTH output samples from the one in progress,
+    // which is at index head
+    pending: [i64; WIDTH],
+    head: usize,
+    // The sum of every difference so far, which is the output scaled up by KERNEL_BITS
+    sum: i64,
+    // Finished output the caller hasn't taken yet
+    #[cfg_attr(feature = "serde", serde(skip))]
+    ready: VecDeque<i32>,
+}
+
+impl Resampler {
+    pub fn new(in_rate: u32, out_rate: u32) -> Resampler {
+        Resampler {
+            in_rate: in_rate.max(1) as u64,
+            out_rate: out_rate.max(1) as u64,
+            phase: 0,
+            level: 0,
+            pending: [0; WIDTH],
+            head: 0,
+            sum: 0,
+            ready: VecDeque::new(),
+        }
+    }
+
+    pub fn in_rate(&self) -> u32 {
+        self.in_rate as u32
+    }
+
+    pub fn out_rate(&self) -> u32 {
+        self.out_rate as u32
+    }
+
+    // Input samples at the input rate
+    pub fn push(&mut self, input: &[i16]) {
+        for run in input.chunk_by(|a, b| a == b) {
+            self.run(run[0] as i32, run.len() as u64);
+        }
+    }
+
+    // Fill out with finished samples, scaled so full-scale i16 input is 1.0, and return how
+    // many there were. Ringing can take them a little past 1.0.
+    pub fn pull(&mut self, out: &mut [f32]) -> usize {
+        let count = out.len().min(self.ready.len());
+        for (sample, level) in out.iter_mut().zip(self.ready.drain(..count)) {
+            *sample = level as f32 / 32768.0;
+        }
+        count
+    }
+
+    // How many samples pull has to give
+    pub fn available(&self) -> usize {
+        self.ready.len()
+    }
+
+    // count input samples, all at level
+    pub fn run(&mut self, level: i32, count: u64) {
+        if count == 0 {
+            return;
+        }
+        if level != self.level {
+            let delta = (level - self.level) as i64;
+            self.level = level;
+            let kernel = &STEP_KERNEL[(self.phase * PHASES / self.in_rate) as usize];
+            for (i, &tap) in kernel.iter().enumerate() {
+                self.pending[(self.head + i) % WIDTH] += delta * tap as i64;
+            }
+        }
+        self.phase += self.out_rate * count;
+        while self.phase >= self.in_rate {
+            self.phase -= self.in_rate;
+            self.sum += std::mem::replace(&mut self.pending[self.head], 0);
+            self.head = (self.head + 1) % WIDTH;
+            self.ready.push_back(((self.sum + (1 << (KERNEL_BITS - 1))) >> KERNEL_BITS) as i32);
+        }
+    }
+
+    // How many more input samples can go by without finishing an output sample
+    pub fn quiet(&self) -> u64 {
+        (self.in_rate - self.phase - 1) / self.out_rate
+    }
+
+    // The oldest finished sample, at the scale the input was
+    pub fn next_output(&mut self) -> Option<i32> {
+        self.ready.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use region::NTSC_TIMING;
+
+    use std::f64::consts::PI;
+
+    const OUT_RATE: u32 = 48000;
+    const LEVEL: f64 = 8000.0;
+    // One second of output, from a little after the start
+    const SKIP: usize = 1000;
+    const LENGTH: usize = 48000;
+
+    fn in_rate() -> u32 {
+        NTSC_TIMING.cpu_clock_hz
+    }
+
+    // A square wave at hz, one level per CPU cycle, a little longer than SKIP + LENGTH need
+    fn square(hz: f64) -> Vec<i16> {
+        let count = in_rate() as usize * 11 / 10 + in_rate() as usize * SKIP / OUT_RATE as usize;
+        (0..count)
+            .map(|i| if (i as f64 * hz / in_rate() as f64).fract() < 0.5 { LEVEL as i16 } else { -LEVEL as i16 })
+            .collect()
+    }
+
+    fn resampled(input: &[i16]) -> Vec<f64> {
+        let mut resampler = Resampler::new(in_rate(), OUT_RATE);
+        resampler.push(input);
+        let mut out = vec![0.0; resampler.available()];
+        resampler.pull(&mut out);
+        out[SKIP..SKIP + LENGTH].iter().map(|&sample| sample as f64 * 32768.0).collect()
+    }
+
+    // Taking every in_rate / OUT_RATE'th input sample, which is what band-limiting is against
+    fn decimated(input: &[i16]) -> Vec<f64> {
+        (SKIP..SKIP + LENGTH).map(|i| input[(i as u64 * in_rate() as u64 / OUT_RATE as u64) as usize] as f64).collect()
+    }
+
+    // The amplitude of the component at hz, from a DFT under a Blackman-Harris window
+    fn amplitude(samples: &[f64], hz: f64) -> f64 {
+        let n = samples.len() as f64;
+        let (mut re, mut im, mut gain) = (0.0, 0.0, 0.0);
+        for (i, &sample) in samples.iter().enumerate() {
+            let t = 2.0 * PI * i as f64 / n;
+            let window = 0.35875 - 0.48829 * t.cos() + 0.14128 * (2.0 * t).cos() - 0.01168 * (3.0 * t).cos();
+            let angle = 2.0 * PI * hz * i as f64 / OUT_RATE as f64;
+            re += sample * window * angle.cos();
+            im -= sample * window * angle.sin();
+            gain += window;
+        }
+        2.0 * (re * re + im * im).sqrt() / gain
+    }
+
+    fn dbc(amplitude: f64, fundamental: f64) -> f64 {
+        20.0 * (amplitude / fundamental).log10()
+    }
+
+    // Where the odd harmonics above the output's Nyquist frequency fold down to, for those that
+    // land below 20 kHz and away from the harmonics that belong there
+    fn aliases(hz: f64) -> Vec<f64> {
+        let nyquist = OUT_RATE as f64 / 2.0;
+        (1..400).step_by(2)
+            .map(|k| k as f64 * hz)
+            .filter(|&harmonic| harmonic > nyquist)
+            .map(|harmonic| {
+                let folded = harmonic % OUT_RATE as f64;
+                if folded > nyquist { OUT_RATE as f64 - folded } else { folded }
+            })
+            .filter(|&alias| alias < 20000.0)
+            .filter(|&alias| {
+                let nearest = ((alias / hz - 1.0) / 2.0).round() * 2.0 + 1.0;
+                (alias - nearest * hz).abs() > 20.0
+            })
+            .collect()
+    }
+
+    fn worst_alias(samples: &[f64], hz: f64) -> f64 {
+        let fundamental = amplitude(samples, hz);
+        aliases(hz).into_iter().map(|alias| dbc(amplitude(samples, alias), fundamental)).fold(f64::MIN, f64::max)
+    }
+
+    #[test]
+    fn a_1khz_square_keeps_its_fundamental() {
+        let samples = resampled(&square(1000.0));
+        // A square wave's fundamental is 4 / pi of its level
+        let ideal = 4.0 / PI * LEVEL;
+        assert!(dbc(amplitude(&samples, 1000.0), ideal).abs() < 0.2, "{}", amplitude(&samples, 1000.0));
+        assert!(dbc(amplitude(&samples, 3000.0), ideal / 3.0).abs() < 0.2);
+
+        // Its aliases land on its own harmonics, so between them there is nothing
+        for hz in [500.0, 2500.0, 7500.0, 12500.0, 19500.0] {
+            assert!(dbc(amplitude(&samples, hz), ideal) < -80.0, "{} Hz", hz);
+        }
+    }
+
+    #[test]
+    fn aliases_are_attenuated() {
+        // Off a multiple of the output rate, so the aliases fall between the harmonics
+        let hz = 1234.5;
+        let input = square(hz);
+        assert!(!aliases(hz).is_empty());
+        let band_limited = worst_alias(&resampled(&input), hz);
+        let plain = worst_alias(&decimated(&input), hz);
+        assert!(band_limited < -55.0, "worst alias at {:.1} dBc", band_limited);
+        assert!(plain > -35.0, "plain decimation's worst alias at {:.1} dBc", plain);
+        assert!(plain - band_limited > 20.0);
+    }
+
+    #[test]
+    fn steady_input_settles_exactly() {
+        let mut resampler = Resampler::new(in_rate(), OUT_RATE);
+        resampler.push(&vec![1234; in_rate() as usize]);
+        assert_eq!(resampler.available(), OUT_RATE as usize);
+        let mut out = vec![0.0; OUT_RATE as usize];
+        assert_eq!(resampler.pull(&mut out), OUT_RATE as usize);
+        assert_eq!(resampler.available(), 0);
+        assert!(out[WIDTH..].iter().all(|&sample| sample == 1234.0 / 32768.0));
+        // Nothing moves before the step's latency is up
+        assert!(out[..WIDTH / 2 - 2].iter().all(|&sample| sample.abs() < 0.001));
+    }
+}
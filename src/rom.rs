@@ -1,63 +1,295 @@
-use mem;
+use hash;
+use region::Region;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 use std;
+use std::fmt;
+use std::io;
 use std::io::prelude::*;
-use std::io::SeekFrom;
-use std::fs::File;
+use std::fs;
 
 const INES_HEADER_MAGIC: u32 = 0x1A53454E; // ELF\x1A
 
 pub struct ROM {
     pub header: INESHeader,
+    // Loaded into PRG RAM at $7000 on power-up
+    pub trainer: Option<[u8; 512]>,
     pub prg: Vec<u8>,
     pub chr: Vec<u8>,
 }
 
+#[derive(Debug)]
+pub enum RomError {
+    Io(io::Error),
+    BadMagic(u32),
+    // The file ends before the trainer, PRG and CHR the header advertises. Sizes are in bytes,
+    // not counting the header.
+    Truncated { expected: usize, found: usize },
+    // A raw binary that doesn't fit in PRG space ($8000-$FFFF) at its load address
+    RawDoesNotFit { len: usize, load_addr: u16 },
+    // Vs. System and PlayChoice-10 carts expect arcade hardware around them
+    UnsupportedSystem(&'static str),
+    // More CHR than the board can address, in bytes
+    ChrTooLarge { mapper: u16, size: usize, max: usize },
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RomError::Io(ref e) => write!(f, "{}", e),
+            RomError::BadMagic(magic) => write!(f, "not an iNES file (magic {:#010x})", magic),
+            RomError::Truncated { expected, found } =>
+                write!(f, "header advertises {} bytes of trainer, PRG and CHR but the file has {}", expected, found),
+            RomError::RawDoesNotFit { len, load_addr } =>
+                write!(f, "{} bytes loaded at ${:04X} don't fit in $8000-$FFFF", len, load_addr),
+            RomError::UnsupportedSystem(system) => write!(f, "{} ROMs aren't supported", system),
+            RomError::ChrTooLarge { mapper, size, max } =>
+                write!(f, "header advertises {} KiB of CHR but mapper {} ({}) only has room for {} KiB",
+                       size / 1024, mapper, mapper_name(mapper), max / 1024),
+        }
+    }
+}
+
+impl From<io::Error> for RomError {
+    fn from(e: io::Error) -> RomError {
+        RomError::Io(e)
+    }
+}
+
 impl ROM {
-    pub fn from_file(filename: &str) -> ROM {
-        let mut f = File::open(filename).unwrap();
+    pub fn from_file(filename: &str) -> Result<ROM, RomError> {
+        let data = fs::read(filename)?;
+        ROM::from_bytes(&data)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<ROM, RomError> {
+        let mut f = data;
         let mut header: [u8; 16] = [0; 16];
-        f.read_exact(&mut header).unwrap();
+        f.read_exact(&mut header)?;
 
-        let header = INESHeader::from_array(&header);
-        println!("Got magic {:x}", header.magic);
-        let mut prg = vec![0; header.size_prg as usize * 16384];
-        let mut chr = vec![0; header.size_chr as usize * 8192];
+        let header = INESHeader::from_array(&header)?;
+        debug!("Got magic {:x}", header.magic);
+        if header.is_vs_system() || header.is_playchoice() {
+            return Err(RomError::UnsupportedSystem(header.console_type()))
+        }
+        // NES 2.0 sizes can be bigger than byte 4 and 5 alone allow, or exponent-encoded for
+        // sizes that aren't a multiple of the usual units, like 8 KiB PRG homebrew. They're
+        // checked against the file before anything is allocated, since a bad header can ask for
+        // terabytes.
+        let trainer_size = if header.has_trainer() { 512 } else { 0 };
+        let expected = header.prg_rom_size().checked_add(header.chr_rom_size())
+            .and_then(|size| size.checked_add(trainer_size))
+            .unwrap_or(usize::MAX);
+        if f.len() < expected {
+            return Err(RomError::Truncated { expected: expected, found: f.len() })
+        }
+        if f.len() > expected {
+            warn!("Ignoring {} bytes after the end of CHR", f.len() - expected);
+        }
+        // NROM has no CHR banking, so the rest would silently never be seen
+        if header.mapper() == 0 && header.chr_rom_size() > 0x2000 {
+            return Err(RomError::ChrTooLarge { mapper: 0, size: header.chr_rom_size(), max: 0x2000 })
+        }
+        let mut prg = vec![0; header.prg_rom_size()];
+        let mut chr = vec![0; header.chr_rom_size()];
 
-        // We want to ignore the trainer, but if it's there we must seek past it.
-        if header.has_trainer() { f.seek(SeekFrom::Current(512)).unwrap(); }
+        let trainer = if header.has_trainer() {
+            let mut trainer = [0; 512];
+            f.read_exact(&mut trainer)?;
+            Some(trainer)
+        } else {
+            None
+        };
 
         // Read in PRG
         let mut len = prg.len();
-        f.read_exact(&mut prg[0..len]).unwrap();
+        f.read_exact(&mut prg[0..len])?;
 
         // Read in CHR
         len = chr.len();
-        f.read_exact(&mut chr[0..len]).unwrap();
+        f.read_exact(&mut chr[0..len])?;
+
+        info!("Loaded ROM: {} KiB PRG, {} KiB CHR, mapper {}", prg.len() / 1024, chr.len() / 1024, header.mapper());
+        return Ok(ROM {
+            header: header,
+            trainer: trainer,
+            prg: prg,
+            chr: chr,
+        })
+    }
+}
+
+impl ROM {
+    // A headerless 6502 program, mapped at load_addr in 32 KiB of otherwise empty PRG with
+    // 8 KiB of CHR RAM and 8 KiB of PRG RAM. The reset vector is whatever the program puts
+    // there.
+    pub fn raw_binary(data: &[u8], load_addr: u16) -> Result<ROM, RomError> {
+        let start = (load_addr as usize).wrapping_sub(0x8000);
+        if load_addr < 0x8000 || start + data.len() > 0x8000 {
+            return Err(RomError::RawDoesNotFit { len: data.len(), load_addr: load_addr })
+        }
+
+        let mut prg = vec![0; 0x8000];
+        prg[start..start + data.len()].copy_from_slice(data);
+        let mut rom = ROM::from_parts(prg, Vec::new());
+        // Only an NES 2.0 header can ask for RAM without a battery
+        rom.header.flags_7 = 0x08;
+        rom.header.flags_10 = 7;
+        return Ok(rom)
+    }
 
-        return ROM {
+    // A cartridge image with no header of its own, for formats that bring their own mapper
+    pub fn from_parts(prg: Vec<u8>, chr: Vec<u8>) -> ROM {
+        let mut header = INESHeader::new();
+        header.magic = INES_HEADER_MAGIC;
+        header.size_prg = (prg.len() / 16384) as u8;
+        header.size_chr = (chr.len() / 8192) as u8;
+        ROM {
             header: header,
+            trainer: None,
             prg: prg,
             chr: chr,
         }
     }
+
+    // MD5 of PRG followed by CHR, which is how FCEUX identifies a ROM
+    pub fn md5(&self) -> [u8; 16] {
+        let mut data = self.prg.clone();
+        data.extend_from_slice(&self.chr);
+        hash::md5(&data)
+    }
+
+    // SHA-1 of PRG followed by CHR, as No-Intro and --info give it
+    pub fn sha1(&self) -> [u8; 20] {
+        let mut data = self.prg.clone();
+        data.extend_from_slice(&self.chr);
+        hash::sha1(&data)
+    }
+
+    pub fn summary(&self) -> RomInfo {
+        let h = &self.header;
+        let mut data = self.prg.clone();
+        data.extend_from_slice(&self.chr);
+        RomInfo {
+            mapper: h.mapper(),
+            submapper: h.submapper(),
+            mapper_name: mapper_name(h.mapper()),
+            prg_size: self.prg.len(),
+            chr_size: self.chr.len(),
+            prg_ram_size: h.prg_ram_size(),
+            prg_nvram_size: h.prg_nvram_size(),
+            chr_ram_size: h.chr_ram_size(),
+            mirroring: h.mirroring(),
+            battery: h.has_battery(),
+            trainer: h.has_trainer(),
+            nes2: h.is_nes2(),
+            dirty: h.is_dirty(),
+            console: h.console_type(),
+            timing: h.timing(),
+            crc32: hash::crc32(&data),
+            sha1: hash::sha1(&data),
+            prg_crc32: hash::crc32(&self.prg),
+            prg_sha1: hash::sha1(&self.prg),
+            chr_crc32: hash::crc32(&self.chr),
+            chr_sha1: hash::sha1(&self.chr),
+        }
+    }
+}
+
+// Everything worth knowing about a cartridge image, for tools and `--info`
+pub struct RomInfo {
+    pub mapper: u16,
+    pub submapper: Option<u8>,
+    pub mapper_name: &'static str,
+    pub prg_size: usize,
+    pub chr_size: usize,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub mirroring: Mirroring,
+    pub battery: bool,
+    pub trainer: bool,
+    pub nes2: bool,
+    // Bytes 7-15 were junk and ignored
+    pub dirty: bool,
+    pub console: &'static str,
+    pub timing: &'static str,
+    // Hashes of the headerless image (PRG then CHR), as No-Intro lists them
+    pub crc32: u32,
+    pub sha1: [u8; 20],
+    pub prg_crc32: u32,
+    pub prg_sha1: [u8; 20],
+    pub chr_crc32: u32,
+    pub chr_sha1: [u8; 20],
 }
 
-impl mem::Addressable for ROM {
-    fn loadb(&self, mut addr: u16) -> u8 {
-        // TODO: Implement mapper and mirroring
-        if self.header.size_prg == 1 && addr >= 0xC000 {
-            addr -= 0x4000;
+impl fmt::Display for RomInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Format:      {}", if self.nes2 { "NES 2.0" } else if self.dirty { "iNES (junk in bytes 7-15 ignored)" } else { "iNES" })?;
+        match self.submapper {
+            Some(sub) => writeln!(f, "Mapper:      {}.{} ({})", self.mapper, sub, self.mapper_name)?,
+            None => writeln!(f, "Mapper:      {} ({})", self.mapper, self.mapper_name)?,
         }
-        self.prg[(addr as usize) - 0x8000]
+        writeln!(f, "PRG ROM:     {} KiB", self.prg_size / 1024)?;
+        if self.chr_size == 0 {
+            writeln!(f, "CHR ROM:     none (CHR RAM)")?;
+        } else {
+            writeln!(f, "CHR ROM:     {} KiB", self.chr_size / 1024)?;
+        }
+        writeln!(f, "PRG RAM:     {} bytes", self.prg_ram_size)?;
+        if self.nes2 {
+            writeln!(f, "PRG NVRAM:   {} bytes", self.prg_nvram_size)?;
+            writeln!(f, "CHR RAM:     {} bytes", self.chr_ram_size)?;
+            writeln!(f, "Timing:      {}", self.timing)?;
+        }
+        writeln!(f, "Console:     {}", self.console)?;
+        writeln!(f, "Mirroring:   {:?}", self.mirroring)?;
+        writeln!(f, "Battery:     {}", if self.battery { "yes" } else { "no" })?;
+        writeln!(f, "Trainer:     {}", if self.trainer { "yes" } else { "no" })?;
+        writeln!(f, "CRC32:       {:08x}", self.crc32)?;
+        writeln!(f, "SHA-1:       {}", hash::hex(&self.sha1))?;
+        writeln!(f, "PRG CRC32:   {:08x}", self.prg_crc32)?;
+        writeln!(f, "PRG SHA-1:   {}", hash::hex(&self.prg_sha1))?;
+        writeln!(f, "CHR CRC32:   {:08x}", self.chr_crc32)?;
+        write!(f, "CHR SHA-1:   {}", hash::hex(&self.chr_sha1))
     }
-    #[allow(unused_variables)]
-    fn storeb(&mut self, addr: u16, val: u8) {
-        panic!("You cannot write to PRG");
+}
+
+pub fn mapper_name(mapper: u16) -> &'static str {
+    match mapper {
+        0 => "NROM",
+        1 => "MMC1",
+        2 => "UxROM",
+        3 => "CNROM",
+        4 => "MMC3",
+        5 => "MMC5",
+        7 => "AxROM",
+        9 => "MMC2",
+        10 => "MMC4",
+        11 => "Color Dreams",
+        13 => "CPROM",
+        16 => "Bandai FCG",
+        19 => "Namco 163",
+        20 => "Famicom Disk System",
+        21 | 23 | 25 => "VRC4",
+        22 => "VRC2",
+        24 | 26 => "VRC6",
+        34 => "BNROM / NINA-001",
+        66 => "GxROM",
+        69 => "Sunsoft FME-7",
+        71 => "Camerica",
+        79 => "NINA-03/06",
+        85 => "VRC7",
+        206 => "Namco 118",
+        _ => "Unknown",
     }
 }
 
+// Laid out byte for byte like the file, since it is filled by transmuting the raw header
 #[derive(Default)]
+#[repr(C)]
 pub struct INESHeader {
     magic: u32,
     size_prg: u8,
@@ -76,19 +308,422 @@ impl INESHeader {
         return header
     }
 
-    fn from_array(a: &[u8; 16]) -> INESHeader {
+    fn from_array(a: &[u8; 16]) -> Result<INESHeader, RomError> {
         let mut header: INESHeader = INESHeader::default();
 
         // Create a mutable slice view
         let as_slice: &mut [u8; 16] = unsafe { std::mem::transmute(&mut header) };
         as_slice.copy_from_slice(a);
 
-        assert!(header.magic == INES_HEADER_MAGIC);
+        if header.magic != INES_HEADER_MAGIC {
+            return Err(RomError::BadMagic(header.magic))
+        }
+        if header.is_dirty() {
+            warn!("Header has junk in bytes 7-15 ({:?}), ignoring them", String::from_utf8_lossy(&a[7..]));
+        }
 
-        return header
+        return Ok(header)
+    }
+
+    // The header as it appears in the file
+    pub fn to_bytes(&self) -> [u8; 16] {
+        let mut bytes = [0; 16];
+        bytes[0..4].copy_from_slice(&self.magic.to_le_bytes());
+        bytes[4..11].copy_from_slice(&[self.size_prg, self.size_chr, self.flags_6, self.flags_7,
+                                       self.size_prg_ram, self.flags_9, self.flags_10]);
+        bytes[11..16].copy_from_slice(&self.zero);
+        return bytes
     }
 
-    fn has_trainer(&self) -> bool {
+    pub fn has_trainer(&self) -> bool {
         self.flags_6 & (1 << 2) != 0
     }
+
+    pub fn has_battery(&self) -> bool {
+        self.flags_6 & (1 << 1) != 0
+    }
+
+    pub fn is_nes2(&self) -> bool {
+        self.flags_7 & 0x0C == 0x08
+    }
+
+    // Old rippers signed their work in the unused end of iNES headers ("DiskDude!"), which
+    // garbles the mapper's high nibble. Bytes 12-15 are never set in a clean iNES 1 header.
+    pub fn is_dirty(&self) -> bool {
+        !self.is_nes2() && self.zero[1..].iter().any(|&b| b != 0)
+    }
+
+    // Byte 7, or nothing if it's part of a ripper's signature
+    fn flags_7(&self) -> u8 {
+        if self.is_dirty() { 0 } else { self.flags_7 }
+    }
+
+    pub fn is_vs_system(&self) -> bool {
+        self.flags_7() & 0x03 == 1
+    }
+
+    pub fn is_playchoice(&self) -> bool {
+        self.flags_7() & 0x03 == 2
+    }
+
+    pub fn mapper(&self) -> u16 {
+        let mut mapper = ((self.flags_6 >> 4) | (self.flags_7() & 0xF0)) as u16;
+        if self.is_nes2() {
+            mapper |= ((self.size_prg_ram & 0x0F) as u16) << 8;
+        }
+        mapper
+    }
+
+    pub fn submapper(&self) -> Option<u8> {
+        if self.is_nes2() { Some(self.size_prg_ram >> 4) } else { None }
+    }
+
+    // NES 2.0 stores the upper size bits in byte 9, or an exponent form when they're all set.
+    // The exponent form can describe sizes up to 2^63 * 7, so it's None when it doesn't fit.
+    fn rom_size(lsb: u8, msb: u8, unit: usize) -> Option<usize> {
+        if msb == 0x0F {
+            1usize.checked_shl((lsb >> 2) as u32)?.checked_mul((lsb & 0x03) as usize * 2 + 1)
+        } else {
+            Some((((msb as usize) << 8) | lsb as usize) * unit)
+        }
+    }
+
+    // PRG and CHR sizes in bytes. A size too big for a usize comes back as usize::MAX, which no
+    // file can hold, so loading it fails as truncated.
+    pub fn prg_rom_size(&self) -> usize {
+        let msb = if self.is_nes2() { self.flags_9 & 0x0F } else { 0 };
+        INESHeader::rom_size(self.size_prg, msb, 16384).unwrap_or(usize::MAX)
+    }
+
+    pub fn chr_rom_size(&self) -> usize {
+        let msb = if self.is_nes2() { self.flags_9 >> 4 } else { 0 };
+        INESHeader::rom_size(self.size_chr, msb, 8192).unwrap_or(usize::MAX)
+    }
+
+    // NES 2.0 RAM sizes are shift counts: 64 << n bytes, or none for 0
+    fn shift_size(shift: u8) -> usize {
+        if shift == 0 { 0 } else { 64 << shift }
+    }
+
+    pub fn prg_ram_size(&self) -> usize {
+        if self.is_nes2() {
+            INESHeader::shift_size(self.flags_10 & 0x0F)
+        } else if self.is_dirty() {
+            8192
+        } else {
+            // iNES byte 8 counts 8 KiB units, with 0 meaning 8 KiB for compatibility
+            std::cmp::max(self.size_prg_ram as usize, 1) * 8192
+        }
+    }
+
+    pub fn prg_nvram_size(&self) -> usize {
+        if self.is_nes2() { INESHeader::shift_size(self.flags_10 >> 4) } else { 0 }
+    }
+
+    pub fn chr_ram_size(&self) -> usize {
+        if self.is_nes2() {
+            INESHeader::shift_size(self.zero[0] & 0x0F)
+        } else if self.size_chr == 0 {
+            8192
+        } else {
+            0
+        }
+    }
+
+    pub fn console_type(&self) -> &'static str {
+        match self.flags_7() & 0x03 {
+            0 => "NES/Famicom",
+            1 => "Vs. System",
+            2 => "PlayChoice-10",
+            _ => "Extended",
+        }
+    }
+
+    // The region the header asks for, when it's one we can emulate
+    pub fn region(&self) -> Option<Region> {
+        if !self.is_nes2() {
+            return None
+        }
+        match self.zero[1] & 0x03 {
+            0 | 2 => Some(Region::Ntsc),
+            1 => Some(Region::Pal),
+            _ => None,
+        }
+    }
+
+    pub fn timing(&self) -> &'static str {
+        if !self.is_nes2() {
+            return "NTSC"
+        }
+        match self.zero[1] & 0x03 {
+            0 => "NTSC",
+            1 => "PAL",
+            2 => "Multi-region",
+            _ => "Dendy",
+        }
+    }
+
+    pub fn mirroring(&self) -> Mirroring {
+        if self.flags_6 & (1 << 3) != 0 {
+            Mirroring::FourScreen
+        } else if self.flags_6 & 1 != 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        }
+    }
+}
+
+// How the four logical nametables map onto the console's 2 KiB of VRAM
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    FourScreen,
+}
+
+// Headers serialize as what they mean rather than as flag bytes. The raw bytes come along too,
+// and are all deserializing looks at.
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize)]
+struct HeaderFields {
+    mapper: u16,
+    submapper: Option<u8>,
+    mirroring: Mirroring,
+    console_type: String,
+    timing: String,
+    prg_rom_size: usize,
+    chr_rom_size: usize,
+    prg_ram_size: usize,
+    prg_nvram_size: usize,
+    chr_ram_size: usize,
+    battery: bool,
+    trainer: bool,
+    nes2: bool,
+    #[serde(with = "::state::bytes")]
+    raw: Vec<u8>,
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for INESHeader {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        HeaderFields {
+            mapper: self.mapper(),
+            submapper: self.submapper(),
+            mirroring: self.mirroring(),
+            console_type: self.console_type().to_string(),
+            timing: self.timing().to_string(),
+            prg_rom_size: self.prg_rom_size(),
+            chr_rom_size: self.chr_rom_size(),
+            prg_ram_size: self.prg_ram_size(),
+            prg_nvram_size: self.prg_nvram_size(),
+            chr_ram_size: self.chr_ram_size(),
+            battery: self.has_battery(),
+            trainer: self.has_trainer(),
+            nes2: self.is_nes2(),
+            raw: self.to_bytes().to_vec(),
+        }.serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for INESHeader {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<INESHeader, D::Error> {
+        use serde::de::Error;
+
+        let fields = HeaderFields::deserialize(deserializer)?;
+        let mut raw = [0; 16];
+        if fields.raw.len() != raw.len() {
+            return Err(D::Error::custom("header is not 16 bytes"))
+        }
+        raw.copy_from_slice(&fields.raw);
+        INESHeader::from_array(&raw).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A mapper 0 header with prg 16 KiB PRG banks and chr 8 KiB CHR banks
+    fn header(prg: u8, chr: u8, flags_7: u8, flags_9: u8) -> Vec<u8> {
+        vec![b'N', b'E', b'S', 0x1A, prg, chr, 0, flags_7, 0, flags_9, 0, 0, 0, 0, 0, 0]
+    }
+
+    fn image(header: Vec<u8>, body_len: usize) -> Vec<u8> {
+        let mut data = header;
+        data.extend((0..body_len).map(|i| i as u8));
+        return data
+    }
+
+    #[test]
+    fn truncated_prg() {
+        match ROM::from_bytes(&image(header(2, 0, 0, 0), 0x6000)) {
+            Err(RomError::Truncated { expected, found }) => assert_eq!((expected, found), (0x8000, 0x6000)),
+            other => panic!("expected Truncated, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn truncated_chr() {
+        match ROM::from_bytes(&image(header(1, 1, 0, 0), 0x4000 + 0x1000)) {
+            Err(RomError::Truncated { expected, found }) => assert_eq!((expected, found), (0x6000, 0x5000)),
+            other => panic!("expected Truncated, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn truncated_trainer() {
+        let mut data = image(header(1, 0, 0, 0), 300);
+        data[6] = 0x04;
+        match ROM::from_bytes(&data) {
+            Err(RomError::Truncated { expected, found }) => assert_eq!((expected, found), (512 + 0x4000, 300)),
+            other => panic!("expected Truncated, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn raw_binary_must_fit_in_prg() {
+        let rom = ROM::raw_binary(&[0xEA; 0x8000], 0x8000).unwrap();
+        assert_eq!(rom.prg.len(), 0x8000);
+        let rom = ROM::raw_binary(&[0xA9, 0x42], 0xFFFE).unwrap();
+        assert_eq!((rom.prg[0x7FFE], rom.prg[0x7FFF], rom.prg[0]), (0xA9, 0x42, 0x00));
+        for &(len, load_addr) in [(0x8001, 0x8000), (3, 0xFFFE), (1, 0x7FFF), (1, 0x0000)].iter() {
+            match ROM::raw_binary(&vec![0; len], load_addr) {
+                Err(RomError::RawDoesNotFit { len: l, load_addr: a }) => assert_eq!((l, a), (len, load_addr)),
+                other => panic!("expected RawDoesNotFit for {} bytes at ${:04X}, got {:?}", len, load_addr, other.err()),
+            }
+        }
+    }
+
+    #[test]
+    fn trailing_garbage_is_ignored() {
+        let rom = ROM::from_bytes(&image(header(1, 1, 0, 0), 0x6000 + 100)).unwrap();
+        assert_eq!(rom.prg.len(), 0x4000);
+        assert_eq!(rom.chr.len(), 0x2000);
+        assert_eq!(rom.chr[0x1FFF], 0xFF);
+    }
+
+    #[test]
+    fn nes2_exponent_size() {
+        // 2^13 * 1 = 8 KiB of PRG, the exponent form's MSB nibble being $F
+        let rom = ROM::from_bytes(&image(header(13 << 2, 0, 0x08, 0x0F), 0x2000)).unwrap();
+        assert_eq!(rom.header.prg_rom_size(), 0x2000);
+        assert_eq!(rom.prg.len(), 0x2000);
+        // 2^8 * 3
+        assert_eq!(INESHeader::rom_size(8 << 2 | 1, 0x0F, 16384), Some(768));
+    }
+
+    #[test]
+    fn huge_exponent_sizes_are_truncated_not_allocated() {
+        // 2^63 * 7 overflows, and 2^40 would be a terabyte
+        for prg in [0xFC, 0xFF, 0xA0] {
+            match ROM::from_bytes(&header(prg, 0, 0x08, 0x0F)) {
+                Err(RomError::Truncated { found, .. }) => assert_eq!(found, 0),
+                other => panic!("expected Truncated for ${:02X}, got {:?}", prg, other.err()),
+            }
+        }
+        assert_eq!(INESHeader::rom_size(0xFF, 0x0F, 16384), None);
+    }
+
+    #[test]
+    fn ines_summary_prints_every_field() {
+        // Mapper 4, battery, vertical mirroring, 16 KiB PRG and 8 KiB CHR counting up from 0
+        let mut data = image(header(1, 1, 0, 0), 0x6000);
+        data[6] = 0x43;
+        let info = ROM::from_bytes(&data).unwrap().summary();
+        assert_eq!((info.mapper, info.submapper, info.mapper_name), (4, None, "MMC3"));
+        assert_eq!(info.to_string(), "\
+Format:      iNES
+Mapper:      4 (MMC3)
+PRG ROM:     16 KiB
+CHR ROM:     8 KiB
+PRG RAM:     8192 bytes
+Console:     NES/Famicom
+Mirroring:   Vertical
+Battery:     yes
+Trainer:     no
+CRC32:       900fceee
+SHA-1:       62b805d9f33d2774c66d6b3bfce5b090b9878f49
+PRG CRC32:   e81722f0
+PRG SHA-1:   80cb9c430d80c3084649f65e0ca25dabbffb1b62
+CHR CRC32:   b6675307
+CHR SHA-1:   ecca46e1a1d0a6012713b09a870d84f695b6d9b0");
+    }
+
+    #[test]
+    fn nes2_summary_prints_the_extensions() {
+        // Mapper 1.5 with a trainer, CHR RAM, 8 KiB of NVRAM and PAL timing
+        let mut data = image(header(1, 0, 0x08, 0), 512 + 0x4000);
+        data[6] = 0x14;
+        data[8] = 0x50;
+        data[10] = 0x70;
+        data[11] = 0x07;
+        data[12] = 0x01;
+        let info = ROM::from_bytes(&data).unwrap().summary();
+        assert_eq!((info.mapper, info.submapper), (1, Some(5)));
+        assert!(info.nes2 && info.trainer && !info.battery);
+        // The trainer isn't part of the image No-Intro hashes
+        assert_eq!(info.to_string(), "\
+Format:      NES 2.0
+Mapper:      1.5 (MMC1)
+PRG ROM:     16 KiB
+CHR ROM:     none (CHR RAM)
+PRG RAM:     0 bytes
+PRG NVRAM:   8192 bytes
+CHR RAM:     8192 bytes
+Timing:      PAL
+Console:     NES/Famicom
+Mirroring:   Horizontal
+Battery:     no
+Trainer:     yes
+CRC32:       e81722f0
+SHA-1:       80cb9c430d80c3084649f65e0ca25dabbffb1b62
+PRG CRC32:   e81722f0
+PRG SHA-1:   80cb9c430d80c3084649f65e0ca25dabbffb1b62
+CHR CRC32:   00000000
+CHR SHA-1:   da39a3ee5e6b4b0d3255bfef95601890afd80709");
+    }
+
+    #[test]
+    fn junk_in_the_header_is_flagged_and_ignored() {
+        let mut data = image(header(1, 1, 0, 0), 0x6000);
+        data[7] = 0x40;
+        data[10..16].copy_from_slice(b"Dude!\0");
+        let info = ROM::from_bytes(&data).unwrap().summary();
+        assert!(info.dirty);
+        assert_eq!(info.mapper, 0);
+        assert!(info.to_string().starts_with("Format:      iNES (junk in bytes 7-15 ignored)\nMapper:      0 (NROM)\n"));
+    }
+
+    #[test]
+    fn diskdude_header_keeps_the_low_mapper_nibble() {
+        let mut data = image(header(2, 1, 0, 0), 0xA000);
+        data[6] = 0x21;
+        data[7..16].copy_from_slice(b"DiskDude!");
+        let rom = ROM::from_bytes(&data).unwrap();
+        assert!(rom.header.is_dirty());
+        assert!(!rom.header.is_nes2());
+        // 'D' would have made it mapper $42
+        assert_eq!(rom.header.mapper(), 2);
+        assert_eq!(rom.prg.len(), 0x8000);
+    }
+
+    #[test]
+    fn vs_system_and_playchoice_are_refused() {
+        for &(flags_7, system) in [(0x01, "Vs. System"), (0x02, "PlayChoice-10"), (0x09, "Vs. System")].iter() {
+            let data = image(header(1, 1, flags_7, 0), 0x6000);
+            match ROM::from_bytes(&data) {
+                Err(RomError::UnsupportedSystem(refused)) => assert_eq!(refused, system),
+                other => panic!("flags 7 {:#04x} gave {:?}", flags_7, other.map(|rom| rom.header.mapper())),
+            }
+            assert_eq!(RomError::UnsupportedSystem(system).to_string(), format!("{} ROMs aren't supported", system));
+        }
+        // Unless the flag is part of a ripper's signature
+        let mut data = image(header(1, 1, 0x01, 0), 0x6000);
+        data[12] = b'!';
+        let header = ROM::from_bytes(&data).unwrap().header;
+        assert!(!header.is_vs_system() && !header.is_playchoice());
+    }
 }
@@ -0,0 +1,273 @@
+// Batch header analysis for a ROM collection: walk a directory tree, parse and hash every ROM
+// in it, and report the lot as CSV or JSON. Files are read and hashed on a pool of threads. A
+// file that fails to load gets a row with the error, the same as a directory that can't be
+// listed; neither stops the scan.
+
+use hash;
+use rom::{RomInfo, ROM};
+
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+pub struct ScanEntry {
+    pub path: PathBuf,
+    pub result: Result<RomInfo, String>,
+}
+
+// Every file under dir with one of extensions (compared without regard to case, no dots),
+// sorted, and the directories that couldn't be read
+pub fn find_roms(dir: &Path, extensions: &[String]) -> (Vec<PathBuf>, Vec<ScanEntry>) {
+    let mut files = Vec::new();
+    let mut errors = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                errors.push(ScanEntry { path: dir, result: Err(e.to_string()) });
+                continue;
+            }
+        };
+        for entry in entries {
+            let path = match entry {
+                Ok(entry) => entry.path(),
+                Err(e) => {
+                    errors.push(ScanEntry { path: dir.clone(), result: Err(e.to_string()) });
+                    continue;
+                }
+            };
+            if path.is_dir() {
+                pending.push(path);
+            } else if has_extension(&path, extensions) {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    return (files, errors)
+}
+
+fn has_extension(path: &Path, extensions: &[String]) -> bool {
+    let ext = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+    extensions.iter().any(|wanted| wanted.eq_ignore_ascii_case(ext))
+}
+
+// Load each file on up to threads threads. Entries come back in the order of paths.
+pub fn scan_files(paths: &[PathBuf], threads: usize) -> Vec<ScanEntry> {
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<ScanEntry>>> = Mutex::new(paths.iter().map(|_| None).collect());
+    thread::scope(|scope| {
+        for _ in 0..threads.clamp(1, paths.len().max(1)) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let path = match paths.get(i) {
+                    Some(path) => path,
+                    None => break,
+                };
+                let result = fs::read(path).map_err(|e| e.to_string())
+                    .and_then(|data| ROM::from_bytes(&data).map_err(|e| e.to_string()))
+                    .map(|rom| rom.summary());
+                results.lock().unwrap()[i] = Some(ScanEntry { path: path.clone(), result: result });
+            });
+        }
+    });
+    results.into_inner().unwrap().into_iter().map(|entry| entry.expect("every file is scanned")).collect()
+}
+
+// Everything under dir: the ROMs in path order, then any directories that couldn't be read
+pub fn scan_dir(dir: &Path, extensions: &[String], threads: usize) -> Vec<ScanEntry> {
+    let (files, errors) = find_roms(dir, extensions);
+    let mut entries = scan_files(&files, threads);
+    entries.extend(errors);
+    return entries
+}
+
+const COLUMNS: [&str; 18] = [
+    "path", "mapper", "submapper", "mapper_name", "prg_size", "chr_size", "prg_ram_size", "mirroring",
+    "battery", "trainer", "nes2", "crc32", "sha1", "prg_crc32", "prg_sha1", "chr_crc32", "chr_sha1", "error",
+];
+
+// An entry's fields in COLUMNS order, and whether each is a number or boolean rather than text.
+// A failed entry only has its path and error.
+fn fields(entry: &ScanEntry) -> Vec<(String, bool)> {
+    let path = (entry.path.display().to_string(), false);
+    let info = match entry.result {
+        Ok(ref info) => info,
+        Err(ref e) => {
+            let mut row = vec![path];
+            row.extend((0..COLUMNS.len() - 2).map(|_| (String::new(), true)));
+            row.push((e.clone(), false));
+            return row
+        }
+    };
+    vec![
+        path,
+        (info.mapper.to_string(), true),
+        (info.submapper.map(|sub| sub.to_string()).unwrap_or_default(), true),
+        (info.mapper_name.to_string(), false),
+        (info.prg_size.to_string(), true),
+        (info.chr_size.to_string(), true),
+        (info.prg_ram_size.to_string(), true),
+        (format!("{:?}", info.mirroring), false),
+        (info.battery.to_string(), true),
+        (info.trainer.to_string(), true),
+        (info.nes2.to_string(), true),
+        (format!("{:08x}", info.crc32), false),
+        (hash::hex(&info.sha1), false),
+        (format!("{:08x}", info.prg_crc32), false),
+        (hash::hex(&info.prg_sha1), false),
+        (format!("{:08x}", info.chr_crc32), false),
+        (hash::hex(&info.chr_sha1), false),
+        (String::new(), true),
+    ]
+}
+
+pub fn write_csv<W: Write>(entries: &[ScanEntry], out: &mut W) -> io::Result<()> {
+    writeln!(out, "{}", COLUMNS.join(","))?;
+    for entry in entries {
+        let row: Vec<String> = fields(entry).into_iter().map(|(field, _)| csv_field(&field)).collect();
+        writeln!(out, "{}", row.join(","))?;
+    }
+    Ok(())
+}
+
+// Quoted if it has anything CSV treats specially, with quotes doubled
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+// An array of objects, one per entry. Empty fields are null.
+pub fn write_json<W: Write>(entries: &[ScanEntry], out: &mut W) -> io::Result<()> {
+    writeln!(out, "[")?;
+    for (i, entry) in entries.iter().enumerate() {
+        let members: Vec<String> = COLUMNS.iter().zip(fields(entry)).map(|(name, (value, bare))| {
+            let value = if value.is_empty() { "null".to_string() } else if bare { value } else { json_string(&value) };
+            format!("\"{}\": {}", name, value)
+        }).collect();
+        writeln!(out, "  {{{}}}{}", members.join(", "), if i + 1 < entries.len() { "," } else { "" })?;
+    }
+    writeln!(out, "]")
+}
+
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    return quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing;
+
+    use std::env;
+    use std::process;
+
+    // A directory of two good ROMs, one nested with an upper-case extension, a truncated one,
+    // a text file with a comma in its name and a .bin that isn't picked up by default
+    fn collection(name: &str) -> (PathBuf, Vec<u8>) {
+        let dir = env::temp_dir().join(format!("nes-scan-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("nested")).unwrap();
+        let rom = testing::build_rom(&[0x4C, 0x00, 0x80]);
+        fs::write(dir.join("good.nes"), &rom).unwrap();
+        fs::write(dir.join("nested").join("GOOD.NES"), &rom).unwrap();
+        fs::write(dir.join("short.nes"), &rom[..100]).unwrap();
+        fs::write(dir.join("notes, really.nes"), "not a ROM\n").unwrap();
+        fs::write(dir.join("other.bin"), &rom).unwrap();
+        (dir, rom)
+    }
+
+    fn names(entries: &[ScanEntry], dir: &Path) -> Vec<String> {
+        entries.iter().map(|entry| entry.path.strip_prefix(dir).unwrap().display().to_string().replace('\\', "/")).collect()
+    }
+
+    #[test]
+    fn bad_files_get_their_own_rows() {
+        let (dir, rom) = collection("rows");
+        for &threads in &[1, 4] {
+            let entries = scan_dir(&dir, &["nes".to_string()], threads);
+            assert_eq!(names(&entries, &dir), ["good.nes", "nested/GOOD.NES", "notes, really.nes", "short.nes"]);
+            for entry in &entries[..2] {
+                let info = entry.result.as_ref().unwrap();
+                assert_eq!((info.mapper, info.prg_size, info.chr_size), (0, 32 * 1024, 0));
+                assert_eq!(info.crc32, hash::crc32(&rom[16..]));
+            }
+            assert!(entries[2].result.is_err());
+            assert!(entries[3].result.is_err());
+        }
+        let entries = scan_dir(&dir, &["nes".to_string(), "BIN".to_string()], 2);
+        assert_eq!(names(&entries, &dir)[3], "other.bin");
+        assert!(entries[3].result.is_ok());
+
+        // A directory that isn't there is a row too
+        let missing = scan_dir(&dir.join("missing"), &["nes".to_string()], 2);
+        assert_eq!(missing.len(), 1);
+        assert!(missing[0].result.is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reports_have_a_row_per_file() {
+        let (dir, rom) = collection("report");
+        let entries = scan_dir(&dir, &["nes".to_string()], 2);
+        let crc = format!("{:08x}", hash::crc32(&rom[16..]));
+
+        let mut csv = Vec::new();
+        write_csv(&entries, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0], COLUMNS.join(","));
+        let good: Vec<&str> = lines[1].split(',').collect();
+        assert_eq!(good.len(), COLUMNS.len());
+        assert_eq!(&good[1..12], ["0", "", "NROM", "32768", "0", "8192", "Horizontal", "false", "false", "false", &crc[..]]);
+        assert_eq!(good[17], "");
+        // The comma in a name gets it quoted, and a bad file's row only has its path and error
+        let bad = lines[3];
+        assert!(bad.starts_with('"') && bad.contains("notes, really.nes\","), "{}", bad);
+        assert!(bad.contains(&",".repeat(COLUMNS.len() - 1)), "{}", bad);
+        assert!(!bad.ends_with(','), "{}", bad);
+
+        let mut json = Vec::new();
+        write_json(&entries, &mut json).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        let lines: Vec<&str> = json.lines().collect();
+        assert_eq!((lines[0], lines[5]), ("[", "]"));
+        assert!(lines[1].contains("\"mapper\": 0, \"submapper\": null, \"mapper_name\": \"NROM\""), "{}", lines[1]);
+        assert!(lines[1].contains(&format!("\"crc32\": \"{}\"", crc)), "{}", lines[1]);
+        assert!(lines[1].ends_with("\"error\": null},"), "{}", lines[1]);
+        assert!(lines[3].contains("\"mapper\": null"), "{}", lines[3]);
+        assert!(!lines[3].contains("\"error\": null"), "{}", lines[3]);
+        assert!(lines[4].ends_with('}'), "{}", lines[4]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn json_strings_are_escaped() {
+        assert_eq!(json_string("a\"b\\c\nd\u{1}"), "\"a\\\"b\\\\c\\nd\\u0001\"");
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("say \"hi\", then"), "\"say \"\"hi\"\", then\"");
+    }
+}
@@ -0,0 +1,184 @@
+// Cheat search: snapshot a block of memory, then narrow down to the addresses that behave the
+// way a game variable should (changed since last time, now holds the value on screen, ...).
+
+use mem::Memory;
+
+// Which memory to search. Addresses are CPU addresses for RAM, PPU addresses for nametable
+// RAM and indices for OAM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SearchSpace {
+    #[default]
+    Ram,
+    Vram,
+    Oam,
+}
+
+impl SearchSpace {
+    fn base(self) -> u16 {
+        match self {
+            SearchSpace::Ram | SearchSpace::Oam => 0,
+            SearchSpace::Vram => 0x2000,
+        }
+    }
+
+    fn read(self, mem: &Memory) -> Vec<u8> {
+        match self {
+            SearchSpace::Ram => mem.ram.data.to_vec(),
+            SearchSpace::Vram => mem.ppu.nametable_ram().to_vec(),
+            SearchSpace::Oam => mem.ppu.oam.to_vec(),
+        }
+    }
+}
+
+// A candidate whose value is different from the last snapshot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Change {
+    pub addr: u16,
+    pub old: u8,
+    pub new: u8,
+}
+
+#[derive(Default)]
+pub struct MemorySearch {
+    space: SearchSpace,
+    snapshot: Vec<u8>,
+    // Offsets into the snapshot still in the running
+    candidates: Vec<usize>,
+}
+
+impl MemorySearch {
+    pub fn new(space: SearchSpace) -> MemorySearch {
+        MemorySearch { space: space, ..MemorySearch::default() }
+    }
+
+    pub fn space(&self) -> SearchSpace {
+        self.space
+    }
+
+    pub fn is_started(&self) -> bool {
+        !self.snapshot.is_empty()
+    }
+
+    // Snapshot the memory with every address a candidate
+    pub fn start(&mut self, mem: &Memory) {
+        self.snapshot = self.space.read(mem);
+        self.candidates = (0..self.snapshot.len()).collect();
+    }
+
+    // Candidates whose value differs from the snapshot, without refining anything
+    pub fn changes(&self, mem: &Memory) -> Vec<Change> {
+        let current = self.space.read(mem);
+        self.candidates.iter()
+            .filter(|&&i| current[i] != self.snapshot[i])
+            .map(|&i| Change { addr: self.space.base() + i as u16, old: self.snapshot[i], new: current[i] })
+            .collect()
+    }
+
+    // Keep the candidates that changed since the last snapshot, and take a new one
+    pub fn refine_changed(&mut self, mem: &Memory) {
+        self.refine(mem, |old, new| old != new);
+    }
+
+    // Keep the candidates that didn't change since the last snapshot, and take a new one
+    pub fn refine_unchanged(&mut self, mem: &Memory) {
+        self.refine(mem, |old, new| old == new);
+    }
+
+    // Keep the candidates that now hold val, and take a new snapshot
+    pub fn refine_equals(&mut self, mem: &Memory, val: u8) {
+        self.refine(mem, |_, new| new == val);
+    }
+
+    fn refine<F: Fn(u8, u8) -> bool>(&mut self, mem: &Memory, keep: F) {
+        if !self.is_started() {
+            self.start(mem);
+        }
+        let current = self.space.read(mem);
+        let snapshot = &self.snapshot;
+        self.candidates.retain(|&i| keep(snapshot[i], current[i]));
+        self.snapshot = current;
+    }
+
+    pub fn candidates(&self) -> Vec<u16> {
+        self.candidates.iter().map(|&i| self.space.base() + i as u16).collect()
+    }
+
+    // The value of a candidate as of the last snapshot
+    pub fn value(&self, addr: u16) -> Option<u8> {
+        self.snapshot.get(addr.wrapping_sub(self.space.base()) as usize).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::CPU;
+    use emulator::Nes;
+    use mem::Addressable;
+    use testing;
+
+    // INC $10, JMP $8000
+    fn counting_cpu() -> CPU {
+        testing::build_program(&[0xE6, 0x10, 0x4C, 0x00, 0x80])
+    }
+
+    fn run(cpu: &mut CPU, instructions: usize) {
+        for _ in 0..instructions {
+            cpu.emulate_cycle().unwrap();
+        }
+    }
+
+    #[test]
+    fn two_refinements_find_the_counter() {
+        let mut cpu = counting_cpu();
+        let mut search = MemorySearch::new(SearchSpace::Ram);
+        assert!(!search.is_started());
+        search.start(cpu.memory());
+        assert_eq!(search.candidates().len(), 0x800);
+
+        run(&mut cpu, 2);
+        assert_eq!(search.changes(cpu.memory()), [Change { addr: 0x0010, old: 0x00, new: 0x01 }]);
+        search.refine_changed(cpu.memory());
+        assert_eq!(search.candidates(), [0x0010]);
+
+        run(&mut cpu, 4);
+        search.refine_equals(cpu.memory(), 0x03);
+        assert_eq!(search.candidates(), [0x0010]);
+        assert_eq!(search.value(0x0010), Some(0x03));
+        // It keeps changing, so asking for what stayed the same leaves nothing
+        run(&mut cpu, 2);
+        search.refine_unchanged(cpu.memory());
+        assert!(search.candidates().is_empty());
+    }
+
+    #[test]
+    fn refining_first_starts_the_search() {
+        let mut cpu = counting_cpu();
+        let mut search = MemorySearch::new(SearchSpace::Ram);
+        search.refine_equals(cpu.memory(), 0x00);
+        assert_eq!(search.candidates().len(), 0x800);
+        run(&mut cpu, 2);
+        search.refine_unchanged(cpu.memory());
+        assert_eq!(search.candidates().len(), 0x7FF);
+        assert!(!search.candidates().contains(&0x0010));
+    }
+
+    #[test]
+    fn vram_and_oam_use_their_own_addresses() {
+        // Without the warm-up, so $2006 takes the writes
+        let rom = testing::build_rom(&[]);
+        let mut nes = Nes::builder().rom_bytes(&rom).ppu_warmup(false).build().unwrap();
+        let cpu = nes.cpu_mut();
+        let mut vram = MemorySearch::new(SearchSpace::Vram);
+        let mut oam = MemorySearch::new(SearchSpace::Oam);
+        vram.start(cpu.memory());
+        oam.start(cpu.memory());
+        cpu.memory_mut().ppu.oam[0x21] = 0x99;
+        for &(addr, val) in [(0x2006, 0x20), (0x2006, 0x05), (0x2007, 0x77)].iter() {
+            cpu.memory_mut().storeb(addr, val);
+        }
+        assert_eq!(vram.changes(cpu.memory()), [Change { addr: 0x2005, old: 0x00, new: 0x77 }]);
+        oam.refine_equals(cpu.memory(), 0x99);
+        assert_eq!((oam.space(), oam.candidates()), (SearchSpace::Oam, vec![0x0021]));
+    }
+}
@@ -0,0 +1,292 @@
+// Numbered save-state slots on disk. Each ROM gets a directory of its own, named for the
+// SHA-1 of its PRG and CHR, so one game's slots never turn up in another's list:
+//   <dir>/<sha1>/slot<N>.state
+// A slot file is JSON holding the state, when it was taken, the frame it was taken on and a
+// small thumbnail of the screen, so a frontend can show what's in a slot without loading it.
+
+use cpu::CPU;
+use hash;
+use ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use rom::ROM;
+use state::{SaveState, StateError};
+
+use serde::{Deserialize, Serialize};
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const SLOTS: usize = 10;
+// Thumbnails keep every fourth pixel each way, 64x60
+const THUMBNAIL_SCALE: usize = 4;
+
+#[derive(Debug)]
+pub enum SlotError {
+    // Slots are numbered from 0 to SLOTS - 1
+    NoSuchSlot(usize),
+    Empty(usize),
+    Io(io::Error),
+    // The file isn't a slot file this version can read
+    Format(String),
+    // The file names a different ROM than the one loaded, by SHA-1
+    WrongRom { found: String, expected: String },
+    State(StateError),
+}
+
+impl fmt::Display for SlotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SlotError::NoSuchSlot(slot) => write!(f, "no slot {}, slots are 0 to {}", slot, SLOTS - 1),
+            SlotError::Empty(slot) => write!(f, "slot {} is empty", slot),
+            SlotError::Io(ref e) => write!(f, "{}", e),
+            SlotError::Format(ref e) => write!(f, "bad slot file: {}", e),
+            SlotError::WrongRom { ref found, ref expected } =>
+                write!(f, "slot was saved from ROM {}, but {} is loaded", found, expected),
+            SlotError::State(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for SlotError {
+    fn from(e: io::Error) -> SlotError {
+        SlotError::Io(e)
+    }
+}
+
+impl From<StateError> for SlotError {
+    fn from(e: StateError) -> SlotError {
+        SlotError::State(e)
+    }
+}
+
+// What's in a slot
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlotInfo {
+    pub slot: usize,
+    // When it was saved, in milliseconds since the Unix epoch
+    pub timestamp: u64,
+    // The PPU's frame count when it was saved
+    pub frame: u64,
+}
+
+impl fmt::Display for SlotInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "slot {}: frame {}, saved {}", self.slot, self.frame, format_timestamp(self.timestamp))
+    }
+}
+
+// A shrunken copy of the screen, as NES color indices
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Thumbnail {
+    pub width: usize,
+    pub height: usize,
+    #[serde(with = "::state::bytes")]
+    pub pixels: Vec<u8>,
+}
+
+impl Thumbnail {
+    pub fn from_framebuffer(framebuffer: &[u8]) -> Thumbnail {
+        let width = SCREEN_WIDTH / THUMBNAIL_SCALE;
+        let height = SCREEN_HEIGHT / THUMBNAIL_SCALE;
+        let mut pixels = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                pixels.push(framebuffer[y * THUMBNAIL_SCALE * SCREEN_WIDTH + x * THUMBNAIL_SCALE]);
+            }
+        }
+        Thumbnail { width: width, height: height, pixels: pixels }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SlotFile {
+    // Hex SHA-1 of the ROM's PRG and CHR
+    rom_sha1: String,
+    info: SlotInfo,
+    thumbnail: Thumbnail,
+    state: SaveState,
+}
+
+pub struct StateManager {
+    // This ROM's directory
+    dir: PathBuf,
+    rom_sha1: String,
+}
+
+impl StateManager {
+    // Slots for rom, under dir
+    pub fn new(dir: &Path, rom: &ROM) -> StateManager {
+        let rom_sha1 = hash::hex(&rom.sha1());
+        StateManager { dir: dir.join(&rom_sha1), rom_sha1: rom_sha1 }
+    }
+
+    // Where slots go when the frontend isn't told, next to the config file
+    pub fn default_dir() -> Option<PathBuf> {
+        env::var_os("HOME").map(|home| Path::new(&home).join(".config").join("nes_level").join("states"))
+    }
+
+    pub fn slot_path(&self, slot: usize) -> Result<PathBuf, SlotError> {
+        if slot >= SLOTS {
+            return Err(SlotError::NoSuchSlot(slot))
+        }
+        Ok(self.dir.join(format!("slot{}.state", slot)))
+    }
+
+    // Save the machine to a slot, replacing whatever was there. The file is written whole
+    // before it replaces the old one, so a failed save leaves the slot as it was.
+    pub fn save_slot(&self, cpu: &mut CPU, slot: usize) -> Result<SlotInfo, SlotError> {
+        let path = self.slot_path(slot)?;
+        let state = cpu.save_state()?;
+        let info = SlotInfo { slot: slot, timestamp: now_ms(), frame: state.ppu.frame };
+        let file = SlotFile {
+            rom_sha1: self.rom_sha1.clone(),
+            info: info,
+            thumbnail: Thumbnail::from_framebuffer(&state.ppu.framebuffer),
+            state: state,
+        };
+        let json = serde_json::to_vec(&file).map_err(|e| SlotError::Format(e.to_string()))?;
+        fs::create_dir_all(&self.dir)?;
+        let temp = path.with_extension("state.tmp");
+        fs::write(&temp, json)?;
+        fs::rename(&temp, &path)?;
+        Ok(info)
+    }
+
+    pub fn load_slot(&self, cpu: &mut CPU, slot: usize) -> Result<SlotInfo, SlotError> {
+        let file = self.read(slot)?;
+        cpu.load_state(&file.state)?;
+        Ok(file.info)
+    }
+
+    pub fn slot_info(&self, slot: usize) -> Result<SlotInfo, SlotError> {
+        Ok(self.read(slot)?.info)
+    }
+
+    pub fn thumbnail(&self, slot: usize) -> Result<Thumbnail, SlotError> {
+        Ok(self.read(slot)?.thumbnail)
+    }
+
+    // The slots in use, in slot order. Files that can't be read are left out.
+    pub fn list(&self) -> Vec<SlotInfo> {
+        (0..SLOTS).filter_map(|slot| match self.slot_info(slot) {
+            Ok(info) => Some(info),
+            Err(SlotError::Empty(_)) => None,
+            Err(e) => {
+                warn!("Skipping save-state slot {}: {}", slot, e);
+                None
+            },
+        }).collect()
+    }
+
+    // The slot saved most recently
+    pub fn newest_slot(&self) -> Option<usize> {
+        self.list().iter().max_by_key(|info| (info.timestamp, info.slot)).map(|info| info.slot)
+    }
+
+    fn read(&self, slot: usize) -> Result<SlotFile, SlotError> {
+        let path = self.slot_path(slot)?;
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Err(SlotError::Empty(slot)),
+            Err(e) => return Err(SlotError::Io(e)),
+        };
+        let file: SlotFile = serde_json::from_slice(&data).map_err(|e| SlotError::Format(e.to_string()))?;
+        if file.rom_sha1 != self.rom_sha1 {
+            return Err(SlotError::WrongRom { found: file.rom_sha1, expected: self.rom_sha1.clone() })
+        }
+        Ok(file)
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+// "YYYY-MM-DD HH:MM:SS UTC", from milliseconds since the Unix epoch
+pub fn format_timestamp(ms: u64) -> String {
+    let secs = ms / 1000;
+    let (days, time) = (secs / 86400, secs % 86400);
+    // Days to a civil date, counting in 400-year eras that start on March 1st
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, time / 3600, time / 60 % 60, time % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use controller::InputFrame;
+    use emulator::Nes;
+    use testing;
+
+    use std::process;
+    use std::thread;
+    use std::time::Duration;
+
+    fn frame(nes: &Nes) -> u64 {
+        nes.cpu().memory().ppu.frame
+    }
+
+    fn run_to_frame(nes: &mut Nes, frame: u64) {
+        while nes.cpu().memory().ppu.frame < frame {
+            nes.run_frame(InputFrame::default()).unwrap();
+        }
+    }
+
+    #[test]
+    fn slots_list_and_load_what_was_saved() {
+        let dir = std::env::temp_dir().join(format!("nes-slots-{}", process::id()));
+        let image = testing::build_test_rom("loop: JMP loop", None);
+        let rom = ROM::from_bytes(&image).unwrap();
+        let slots = StateManager::new(&dir, &rom);
+        let mut nes = Nes::builder().rom_bytes(&image).ppu_warmup(false).build().unwrap();
+
+        run_to_frame(&mut nes, 5);
+        let older = slots.save_slot(nes.cpu_mut(), 3).unwrap();
+        // Far enough apart for the timestamps to tell them apart
+        thread::sleep(Duration::from_millis(5));
+        run_to_frame(&mut nes, 10);
+        let newer = slots.save_slot(nes.cpu_mut(), 1).unwrap();
+        assert_eq!((older.frame, newer.frame), (5, 10));
+        assert!(newer.timestamp > older.timestamp);
+
+        assert_eq!(slots.list(), [newer, older]);
+        assert_eq!(slots.newest_slot(), Some(1));
+        assert_eq!(slots.thumbnail(3).unwrap().pixels.len(), 64 * 60);
+        assert_eq!(slots.load_slot(nes.cpu_mut(), 3).unwrap(), older);
+        assert_eq!(frame(&nes), 5);
+        nes.run_frame(InputFrame::default()).unwrap();
+        assert_eq!(frame(&nes), 6);
+
+        assert!(matches!(slots.load_slot(nes.cpu_mut(), 0), Err(SlotError::Empty(0))));
+        assert!(matches!(slots.save_slot(nes.cpu_mut(), SLOTS), Err(SlotError::NoSuchSlot(10))));
+
+        // Another ROM's slot file copied over is refused
+        let other_rom = ROM::from_bytes(&testing::build_test_rom("loop: NOP\nJMP loop", None)).unwrap();
+        let other = StateManager::new(&dir, &other_rom);
+        fs::create_dir_all(&other.dir).unwrap();
+        fs::copy(slots.slot_path(3).unwrap(), other.slot_path(3).unwrap()).unwrap();
+        let error = other.load_slot(nes.cpu_mut(), 3).unwrap_err();
+        assert!(matches!(error, SlotError::WrongRom { .. }));
+        assert_eq!(error.to_string(), format!("slot was saved from ROM {}, but {} is loaded", slots.rom_sha1, other.rom_sha1));
+        assert_eq!(other.list(), []);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn timestamps_are_utc_dates() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_timestamp(951_782_400_000), "2000-02-29 00:00:00 UTC");
+        assert_eq!(format_timestamp(1_700_000_000_999), "2023-11-14 22:13:20 UTC");
+    }
+}
@@ -0,0 +1,308 @@
+// Save states: everything needed to pick a running machine back up where it left off. The
+// cartridge's ROM isn't included, only what the game has changed on it (bank registers, CHR
+// RAM), so a state can only be loaded into a machine running the same ROM.
+//
+// Taking a whole state every frame, as rewinding does, mostly copies memory that hasn't
+// changed. So RAM, PRG RAM, VRAM and the pattern tables are split into 256-byte pages that get
+// marked dirty when written, and a StateDelta holds only the pages written since the state
+// before it. The mapper's state, which holds any CHR RAM in a layout of the mapper's own, and
+// the picture, which nothing cheap tracks, are compared page by page against that state instead.
+
+use apu::Apu;
+use controller::{Controller, FourScore};
+use cpu::CpuState;
+use dmc::Dmc;
+use ppu::PpuState;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
+
+// Bumped whenever SaveState or anything in it changes shape
+pub const STATE_VERSION: u32 = 11;
+
+pub const PAGE_SIZE: usize = 0x100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateError {
+    WrongVersion { found: u32, expected: u32 },
+    // The state was taken with a different ROM loaded
+    WrongRom,
+    // Saving in the middle of a cycle-stepped instruction isn't supported
+    MidInstruction,
+    // Some part of the state is the wrong size for this machine
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StateError::WrongVersion { found, expected } =>
+                write!(f, "save state is version {}, expected {}", found, expected),
+            StateError::WrongRom => write!(f, "save state is for a different ROM"),
+            StateError::MidInstruction => write!(f, "can't save in the middle of an instruction"),
+            StateError::Corrupt(part) => write!(f, "save state has a bad {}", part),
+        }
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SaveState {
+    pub version: u32,
+    // Hex MD5 of the ROM's PRG and CHR
+    pub rom_md5: String,
+    pub cpu: CpuState,
+    pub nmi_count: u64,
+    // An NMI seen but not yet taken
+    pub nmi_latched: bool,
+    // The I flag interrupt polling sees, if the last instruction changed it too late
+    pub delayed_int_flag: Option<bool>,
+    #[cfg_attr(feature = "serde", serde(with = "bytes"))]
+    pub ram: Vec<u8>,
+    #[cfg_attr(feature = "serde", serde(with = "bytes"))]
+    pub prg_ram: Vec<u8>,
+    pub controllers: [Controller; 2],
+    pub four_score: Option<FourScore>,
+    pub ppu: PpuState,
+    pub apu: Apu,
+    pub dmc: Dmc,
+    // Whatever the mapper needs, in its own format
+    #[cfg_attr(feature = "serde", serde(with = "bytes"))]
+    pub mapper: Vec<u8>,
+}
+
+// Which pages have been written since the last snapshot, one bit per page
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DirtyPages {
+    pub ram: u64,
+    pub prg_ram: u64,
+    // The PPU's nametable RAM
+    pub vram: u64,
+    // The pattern tables at $0000-$1FFF, by PPU address, as written through $2007
+    pub chr: u64,
+}
+
+impl DirtyPages {
+    // Everything, for when memory has been replaced wholesale
+    pub fn all() -> DirtyPages {
+        DirtyPages { ram: !0, prg_ram: !0, vram: !0, chr: !0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == DirtyPages::default()
+    }
+
+    pub fn count(&self) -> u32 {
+        self.ram.count_ones() + self.prg_ram.count_ones() + self.vram.count_ones() + self.chr.count_ones()
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Page {
+    pub index: usize,
+    #[cfg_attr(feature = "serde", serde(with = "bytes"))]
+    pub data: Vec<u8>,
+}
+
+// The pages of data whose bits are set in dirty. The top bit stands for every page from 63 on,
+// for PRG RAM bigger than the bus can reach.
+pub fn dirty_pages(data: &[u8], dirty: u64) -> Vec<Page> {
+    data.chunks(PAGE_SIZE).enumerate()
+        .filter(|&(index, _)| dirty & 1 << index.min(63) != 0)
+        .map(|(index, page)| Page { index: index, data: page.to_vec() })
+        .collect()
+}
+
+// The pages of data that differ from base. Past the end of base they all do.
+pub fn changed_pages(data: &[u8], base: &[u8]) -> Vec<Page> {
+    data.chunks(PAGE_SIZE).enumerate()
+        .filter(|&(index, page)| base.get(index * PAGE_SIZE..index * PAGE_SIZE + page.len()) != Some(page))
+        .map(|(index, page)| Page { index: index, data: page.to_vec() })
+        .collect()
+}
+
+// base with pages written over it, len bytes long
+fn apply_pages(base: &[u8], pages: &[Page], len: usize, part: &'static str) -> Result<Vec<u8>, StateError> {
+    let mut data = base.to_vec();
+    data.resize(len, 0);
+    for page in pages {
+        let start = page.index * PAGE_SIZE;
+        match data.get_mut(start..start + page.data.len()) {
+            Some(dest) => dest.copy_from_slice(&page.data),
+            None => return Err(StateError::Corrupt(part)),
+        }
+    }
+    Ok(data)
+}
+
+// A save state as the pages that changed since an earlier one, its base. Applying it to the
+// base gives back the whole state. CPU::save_delta makes them.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateDelta {
+    // The state with its memory blocks left empty
+    pub rest: SaveState,
+    pub ram: Vec<Page>,
+    pub prg_ram: Vec<Page>,
+    pub vram: Vec<Page>,
+    pub framebuffer: Vec<Page>,
+    pub mapper: Vec<Page>,
+    pub mapper_len: usize,
+}
+
+impl StateDelta {
+    pub fn apply(&self, base: &SaveState) -> Result<SaveState, StateError> {
+        let mut state = self.rest.clone();
+        state.ram = apply_pages(&base.ram, &self.ram, base.ram.len(), "RAM")?;
+        state.prg_ram = apply_pages(&base.prg_ram, &self.prg_ram, base.prg_ram.len(), "PRG RAM")?;
+        state.ppu.vram = apply_pages(&base.ppu.vram, &self.vram, base.ppu.vram.len(), "VRAM")?;
+        state.ppu.framebuffer = apply_pages(&base.ppu.framebuffer, &self.framebuffer, base.ppu.framebuffer.len(), "framebuffer")?;
+        state.mapper = apply_pages(&base.mapper, &self.mapper, self.mapper_len, "mapper state")?;
+        Ok(state)
+    }
+
+    // The bytes of memory the delta carries
+    pub fn page_bytes(&self) -> usize {
+        [&self.ram, &self.prg_ram, &self.vram, &self.framebuffer, &self.mapper].iter()
+            .flat_map(|pages| pages.iter())
+            .map(|page| page.data.len())
+            .sum()
+    }
+}
+
+// Memory blocks as hex strings in human-readable formats like JSON, and as plain byte strings
+// in binary ones like bincode. Serde's default of a list of numbers is big in both.
+#[cfg(feature = "serde")]
+pub mod bytes {
+    use hash;
+
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&hash::hex(data))
+        } else {
+            serializer.serialize_bytes(data)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        if deserializer.is_human_readable() {
+            let text = String::deserialize(deserializer)?;
+            if text.len() % 2 != 0 {
+                return Err(D::Error::custom("odd number of hex digits"))
+            }
+            (0..text.len()).step_by(2)
+                .map(|i| u8::from_str_radix(&text[i..i + 2], 16).map_err(|_| D::Error::custom("bad hex digit")))
+                .collect()
+        } else {
+            byte_buf(deserializer)
+        }
+    }
+
+    // Accept either a byte string or a sequence of bytes, whichever the format hands back
+    fn byte_buf<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        struct Visitor;
+
+        impl<'de> ::serde::de::Visitor<'de> for Visitor {
+            type Value = Vec<u8>;
+
+            fn expecting(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+                write!(f, "a byte string")
+            }
+
+            fn visit_bytes<E: Error>(self, v: &[u8]) -> Result<Vec<u8>, E> {
+                Ok(v.to_vec())
+            }
+
+            fn visit_byte_buf<E: Error>(self, v: Vec<u8>) -> Result<Vec<u8>, E> {
+                Ok(v)
+            }
+
+            fn visit_seq<A: ::serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+                let mut data = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+                while let Some(b) = seq.next_element()? {
+                    data.push(b);
+                }
+                Ok(data)
+            }
+        }
+
+        deserializer.deserialize_byte_buf(Visitor)
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use super::*;
+    use bincode;
+    use emulator::Nes;
+    use serde_json;
+    use testing;
+
+    fn nes() -> Nes {
+        let rom = testing::build_test_rom(include_str!("../tests/roms/stack_interrupts.s"), None);
+        Nes::builder().rom_bytes(&rom).build().unwrap()
+    }
+
+    // Step both machines through instructions, which have to stay in step
+    fn run_together(original: &mut Nes, restored: &mut Nes, instructions: usize) {
+        for i in 0..instructions {
+            assert!(original.step().unwrap().is_continue());
+            assert!(restored.step().unwrap().is_continue());
+            assert_eq!(original.cpu().dump_state(), restored.cpu().dump_state(), "instruction {}", i);
+        }
+        let (original, restored) = (original.cpu().memory(), restored.cpu().memory());
+        assert_eq!(original.ram.data[..], restored.ram.data[..]);
+        assert_eq!(original.ppu.frame_hash(), restored.ppu.frame_hash());
+    }
+
+    #[test]
+    fn states_resume_the_same_through_json_and_bincode() {
+        let mut original = nes();
+        for _ in 0..12345 {
+            assert!(original.step().unwrap().is_continue());
+        }
+        let state = original.save_state().unwrap();
+
+        let json = serde_json::to_string(&state).unwrap();
+        // Memory is hex rather than an array of numbers
+        assert!(json.contains(&format!("\"ram\":\"{}\"", ::hash::hex(&state.ram))));
+        let from_json: SaveState = serde_json::from_str(&json).unwrap();
+        let binary = bincode::serialize(&state).unwrap();
+        assert!(binary.len() < json.len() / 2);
+        let from_bincode: SaveState = bincode::deserialize(&binary).unwrap();
+        // Samples not yet handed out aren't part of a state, so compare what gets saved
+        assert_eq!(serde_json::to_string(&from_json).unwrap(), json);
+        assert_eq!(bincode::serialize(&from_bincode).unwrap(), binary);
+
+        for loaded in [from_json, from_bincode].iter() {
+            let mut original = nes();
+            for _ in 0..12345 {
+                assert!(original.step().unwrap().is_continue());
+            }
+            let mut restored = nes();
+            restored.load_state(loaded).unwrap();
+            run_together(&mut original, &mut restored, 1000);
+        }
+    }
+
+    #[test]
+    fn headers_serialize_as_named_fields() {
+        let nes = nes();
+        let header = &nes.cpu().memory().rom.header;
+        let json: serde_json::Value = serde_json::to_value(header).unwrap();
+        assert_eq!(json["mapper"], 0);
+        assert_eq!(json["prg_rom_size"], 0x8000);
+        assert_eq!(json["battery"], false);
+        assert_eq!(json["raw"], ::hash::hex(&header.to_bytes()));
+        let back: ::rom::INESHeader = serde_json::from_value(json).unwrap();
+        assert_eq!(back.to_bytes(), header.to_bytes());
+        let back: ::rom::INESHeader = bincode::deserialize(&bincode::serialize(header).unwrap()).unwrap();
+        assert_eq!(back.to_bytes(), header.to_bytes());
+    }
+}
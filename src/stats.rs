@@ -0,0 +1,189 @@
+// Performance numbers for tuning, averaged over the last second or so of frames
+
+use std::fmt;
+use std::time::Duration;
+
+// Frames the averages cover
+const WINDOW: usize = 60;
+
+// The mean of the last few samples pushed
+pub struct RollingAverage {
+    samples: Vec<f64>,
+    capacity: usize,
+    // Where the next sample goes once the window is full
+    next: usize,
+}
+
+impl RollingAverage {
+    pub fn new(capacity: usize) -> RollingAverage {
+        RollingAverage {
+            samples: Vec::with_capacity(capacity),
+            capacity: capacity.max(1),
+            next: 0,
+        }
+    }
+
+    pub fn push(&mut self, sample: f64) {
+        if self.samples.len() < self.capacity {
+            self.samples.push(sample);
+        } else {
+            self.samples[self.next] = sample;
+        }
+        self.next = (self.next + 1) % self.capacity;
+    }
+
+    // None until something has been pushed
+    pub fn average(&self) -> Option<f64> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        Some(self.samples.iter().sum::<f64>() / self.samples.len() as f64)
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+        self.next = 0;
+    }
+}
+
+pub struct PerfStats {
+    // Frames per second the console itself produces
+    native_fps: f64,
+    // Wall-clock seconds per frame, including any time spent throttling
+    frame_times: RollingAverage,
+    instructions: RollingAverage,
+    // How full the audio output buffer is, 0.0-1.0, when there is one
+    audio_fill: Option<f32>,
+}
+
+impl PerfStats {
+    pub fn new(native_fps: f64) -> PerfStats {
+        PerfStats {
+            native_fps: native_fps,
+            frame_times: RollingAverage::new(WINDOW),
+            instructions: RollingAverage::new(WINDOW),
+            audio_fill: None,
+        }
+    }
+
+    // Call once per frame with how long it took and how many instructions it ran
+    pub fn record_frame(&mut self, frame_time: Duration, instructions: u64) {
+        self.frame_times.push(frame_time.as_secs_f64());
+        self.instructions.push(instructions as f64);
+    }
+
+    pub fn set_audio_fill(&mut self, fill: Option<f32>) {
+        self.audio_fill = fill;
+    }
+
+    pub fn fps(&self) -> Option<f64> {
+        self.frame_times.average().filter(|t| *t > 0.0).map(|t| 1.0 / t)
+    }
+
+    // 100 is full speed
+    pub fn speed_percent(&self) -> Option<f64> {
+        self.fps().map(|fps| fps / self.native_fps * 100.0)
+    }
+
+    pub fn instructions_per_frame(&self) -> Option<f64> {
+        self.instructions.average()
+    }
+
+    pub fn audio_fill(&self) -> Option<f32> {
+        self.audio_fill
+    }
+
+    pub fn reset(&mut self) {
+        self.frame_times.clear();
+        self.instructions.clear();
+    }
+
+    // The same numbers split over two lines, to fit in the 32 columns of the screen
+    pub fn overlay_text(&self) -> String {
+        self.to_string().replacen(" IPF", "\nIPF", 1)
+    }
+}
+
+// One line, with -- for anything not known yet
+impl fmt::Display for PerfStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.fps() {
+            Some(fps) => write!(f, "FPS {:.1}", fps)?,
+            None => write!(f, "FPS --")?,
+        }
+        match self.speed_percent() {
+            Some(speed) => write!(f, " SPD {:.0}%", speed)?,
+            None => write!(f, " SPD --")?,
+        }
+        match self.instructions_per_frame() {
+            Some(ipf) => write!(f, " IPF {:.0}", ipf)?,
+            None => write!(f, " IPF --")?,
+        }
+        match self.audio_fill {
+            Some(fill) => write!(f, " AUD {:.0}%", fill * 100.0),
+            None => write!(f, " AUD --"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rolling_average_covers_the_last_samples() {
+        let mut average = RollingAverage::new(3);
+        assert_eq!(average.average(), None);
+        average.push(1.0);
+        average.push(2.0);
+        assert_eq!(average.average(), Some(1.5));
+        average.push(3.0);
+        // 1.0 drops out, then 2.0
+        average.push(7.0);
+        assert_eq!((average.len(), average.average()), (3, Some(4.0)));
+        average.push(8.0);
+        assert_eq!(average.average(), Some(6.0));
+        average.clear();
+        assert!(average.is_empty());
+        average.push(5.0);
+        assert_eq!(average.average(), Some(5.0));
+
+        // A window of nothing still keeps the last sample
+        let mut average = RollingAverage::new(0);
+        average.push(1.0);
+        average.push(2.0);
+        assert_eq!(average.average(), Some(2.0));
+    }
+
+    #[test]
+    fn stats_average_over_the_window() {
+        let mut stats = PerfStats::new(60.0);
+        assert_eq!(stats.to_string(), "FPS -- SPD -- IPF -- AUD --");
+
+        // A second at 30 FPS pushes out everything before it
+        stats.record_frame(Duration::from_millis(10), 1);
+        for _ in 0..WINDOW {
+            stats.record_frame(Duration::from_secs(1) / 30, 9000);
+        }
+        stats.set_audio_fill(Some(0.25));
+        assert!((stats.fps().unwrap() - 30.0).abs() < 1e-6);
+        assert!((stats.speed_percent().unwrap() - 50.0).abs() < 1e-6);
+        assert_eq!(stats.instructions_per_frame(), Some(9000.0));
+        assert_eq!(stats.to_string(), "FPS 30.0 SPD 50% IPF 9000 AUD 25%");
+        assert_eq!(stats.overlay_text(), "FPS 30.0 SPD 50%\nIPF 9000 AUD 25%");
+
+        stats.reset();
+        assert_eq!((stats.fps(), stats.audio_fill()), (None, Some(0.25)));
+        // Frames that took no time don't divide by zero
+        stats.record_frame(Duration::ZERO, 0);
+        assert_eq!(stats.fps(), None);
+    }
+}
@@ -0,0 +1,255 @@
+// Names for addresses, for the debugger, tracer and disassembler to show and take instead of
+// numbers. Two kinds of file load:
+//
+//   FCEUX name lists (.nl)   "$C123#main_loop#comment" lines. FCEUX keeps one file per 16 KiB
+//                            PRG bank, game.nes.N.nl with N in hex, and game.nes.ram.nl for the
+//                            addresses below $8000 that aren't banked.
+//   Anything else            "name = $C123" lines, or "name = 3:$8123" for a label that's only
+//                            there while PRG bank 3 is mapped. # or ; starts a comment.
+//
+// Banks are numbered the way the monitor's map command shows them, which depends on how the
+// mapper switches PRG. FCEUX's 16 KiB bank numbers are converted to that when a list loads.
+
+use mem::Memory;
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// How far past a symbol an address can be and still be shown as name+offset
+const MAX_OFFSET: u16 = 0xFF;
+// The size of FCEUX's banks
+const FCEUX_BANK_SIZE: usize = 0x4000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: u16,
+    // The PRG bank that has to be mapped at addr for the name to apply, None for everywhere
+    pub bank: Option<usize>,
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.bank {
+            Some(bank) => write!(f, "{} = {}:${:04X}", self.name, bank, self.addr),
+            None => write!(f, "{} = ${:04X}", self.name, self.addr),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SymbolError {
+    Io(io::Error),
+    Syntax { line: usize },
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SymbolError::Io(ref e) => write!(f, "{}", e),
+            SymbolError::Syntax { line } => write!(f, "line {}: expected name = $ADDR or name = BANK:$ADDR", line),
+        }
+    }
+}
+
+impl From<io::Error> for SymbolError {
+    fn from(e: io::Error) -> SymbolError {
+        SymbolError::Io(e)
+    }
+}
+
+// "$C123", "0xC123" or "3:$8123"
+pub fn parse_location(text: &str) -> Option<(Option<usize>, u16)> {
+    let (bank, addr) = match text.split_once(':') {
+        Some((bank, addr)) => (Some(bank.trim().parse().ok()?), addr.trim()),
+        None => (None, text.trim()),
+    };
+    let hex = addr.strip_prefix('$').or_else(|| addr.strip_prefix("0x"))?;
+    Some((bank, u16::from_str_radix(hex, 16).ok()?))
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    // In the order they were loaded
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable::default()
+    }
+
+    // Add the symbols in a file, telling the kinds apart by extension. prg_bank_size is how big
+    // the mapper's PRG banks are at an address, which says how FCEUX's banks number; for a
+    // running machine that's Memory::prg_bank_size.
+    pub fn load<F: Fn(u16) -> usize>(&mut self, path: &str, prg_bank_size: F) -> Result<usize, SymbolError> {
+        let text = fs::read_to_string(path)?;
+        let path = Path::new(path);
+        if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("nl")) {
+            // game.nes.1F.nl is bank $1F, game.nes.ram.nl isn't banked
+            let bank = path.file_stem().and_then(|stem| Path::new(stem).extension())
+                .and_then(|bank| usize::from_str_radix(&bank.to_string_lossy(), 16).ok());
+            Ok(self.parse_nl(&text, bank, prg_bank_size))
+        } else {
+            self.parse(&text)
+        }
+    }
+
+    // "name = $ADDR" lines. Returns how many symbols there were.
+    pub fn parse(&mut self, text: &str) -> Result<usize, SymbolError> {
+        let mut count = 0;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split(['#', ';']).next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (name, location) = line.split_once('=').ok_or(SymbolError::Syntax { line: i + 1 })?;
+            let name = name.trim();
+            let (bank, addr) = parse_location(location).ok_or(SymbolError::Syntax { line: i + 1 })?;
+            if !is_name(name) {
+                return Err(SymbolError::Syntax { line: i + 1 })
+            }
+            self.add(Symbol { name: name.to_string(), addr: addr, bank: bank });
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    // An FCEUX name list for fceux_bank, or for unbanked addresses. Lines that aren't
+    // "$ADDR#name#comment" are skipped, as FCEUX does, and so are unnamed ones, which only carry
+    // a comment. Array sizes ("$0200/10#...") only name the first address.
+    pub fn parse_nl<F: Fn(u16) -> usize>(&mut self, text: &str, fceux_bank: Option<usize>, prg_bank_size: F) -> usize {
+        let mut count = 0;
+        for line in text.lines() {
+            let mut fields = line.trim().splitn(3, '#');
+            let (addr, name) = match (fields.next(), fields.next()) {
+                (Some(addr), Some(name)) => (addr, name.trim()),
+                _ => continue,
+            };
+            let addr = addr.split('/').next().unwrap_or("");
+            let addr = match addr.strip_prefix('$').and_then(|hex| u16::from_str_radix(hex, 16).ok()) {
+                Some(addr) => addr,
+                None => continue,
+            };
+            if !is_name(name) {
+                continue;
+            }
+            let bank = fceux_bank.filter(|_| addr >= 0x8000).map(|bank| {
+                let offset = bank * FCEUX_BANK_SIZE + addr as usize % FCEUX_BANK_SIZE;
+                offset / prg_bank_size(addr)
+            });
+            self.add(Symbol { name: name.to_string(), addr: addr, bank: bank });
+            count += 1;
+        }
+        count
+    }
+
+    pub fn add(&mut self, symbol: Symbol) {
+        self.symbols.push(symbol);
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter()
+    }
+
+    // The symbol called name, ignoring case. The first loaded wins if there are several.
+    pub fn lookup(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name.eq_ignore_ascii_case(name))
+    }
+
+    // The closest symbol at or below addr, and how far past it addr is, counting only symbols
+    // for bank (or for every bank). Nothing more than MAX_OFFSET back counts.
+    pub fn nearest(&self, addr: u16, bank: Option<usize>) -> Option<(&Symbol, u16)> {
+        self.symbols.iter()
+            .filter(|symbol| symbol.addr <= addr && addr - symbol.addr <= MAX_OFFSET)
+            .filter(|symbol| symbol.bank.is_none() || symbol.bank == bank)
+            .min_by_key(|symbol| (addr - symbol.addr, symbol.bank.is_none()))
+            .map(|symbol| (symbol, addr - symbol.addr))
+    }
+
+    // "main_loop" or "main_loop+$4" for addr as the machine has it mapped right now
+    pub fn describe(&self, addr: u16, memory: &Memory) -> Option<String> {
+        let bank = if addr >= 0x8000 { memory.prg_bank(addr) } else { None };
+        self.nearest(addr, bank).map(|(symbol, offset)| label(&symbol.name, offset))
+    }
+}
+
+// "name" or "name+$12"
+pub fn label(name: &str, offset: u16) -> String {
+    if offset == 0 { name.to_string() } else { format!("{}+${:X}", name, offset) }
+}
+
+// Names look like identifiers, so they can be used in expressions and assembled listings
+fn is_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symbol_files_go_both_ways() {
+        let mut symbols = SymbolTable::new();
+        let text = "\
+# Zero page
+counter = $0010
+main_loop = 1:$8005   ; only in bank 1
+Reset = 0xC000
+";
+        assert_eq!(symbols.parse(text).unwrap(), 3);
+        assert_eq!(symbols.lookup("MAIN_LOOP").unwrap().to_string(), "main_loop = 1:$8005");
+        assert_eq!(symbols.lookup("reset").unwrap().to_string(), "Reset = $C000");
+        assert!(symbols.lookup("missing").is_none());
+
+        let name = |addr, bank| symbols.nearest(addr, bank).map(|(symbol, offset)| label(&symbol.name, offset));
+        assert_eq!(name(0x0010, None).as_deref(), Some("counter"));
+        assert_eq!(name(0x0015, None).as_deref(), Some("counter+$5"));
+        assert_eq!(name(0x0110, None).as_deref(), None);
+        // A banked name only applies while its bank is there
+        assert_eq!(name(0x8007, Some(1)).as_deref(), Some("main_loop+$2"));
+        assert_eq!(name(0x8007, Some(0)).as_deref(), None);
+        assert_eq!(name(0xC001, Some(3)).as_deref(), Some("Reset+$1"));
+
+        let error = |text| SymbolTable::new().parse(text).unwrap_err().to_string();
+        assert_eq!(error("\nloop $8000"), "line 2: expected name = $ADDR or name = BANK:$ADDR");
+        assert_eq!(error("2loop = $8000"), "line 1: expected name = $ADDR or name = BANK:$ADDR");
+        assert_eq!(error("loop = 8000"), "line 1: expected name = $ADDR or name = BANK:$ADDR");
+    }
+
+    #[test]
+    fn fceux_banks_become_the_mappers() {
+        let text = "\
+$8005#main_loop#the game's loop
+$8010##a comment with no name
+$0200/10#buffer#
+junk
+";
+        // FCEUX's 16 KiB bank 3 is in the second 32 KiB bank, and its top half at $A005 is the
+        // eighth 8 KiB bank
+        let mut symbols = SymbolTable::new();
+        assert_eq!(symbols.parse_nl(text, Some(3), |_| 0x8000), 2);
+        assert_eq!(symbols.lookup("main_loop").unwrap().bank, Some(1));
+        // RAM isn't banked
+        assert_eq!(symbols.lookup("buffer").unwrap().to_string(), "buffer = $0200");
+
+        let mut symbols = SymbolTable::new();
+        symbols.parse_nl("$A005#late#", Some(3), |_| 0x2000);
+        assert_eq!(symbols.lookup("late").unwrap().bank, Some(7));
+        assert_eq!(parse_location("3:$8123"), Some((Some(3), 0x8123)));
+        assert_eq!(parse_location("0x10"), Some((None, 0x10)));
+        assert_eq!(parse_location("8123"), None);
+    }
+}
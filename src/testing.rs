@@ -0,0 +1,107 @@
+// Helpers for building machines straight from machine code or assembly, for benchmarks and tests
+
+use asm::{self, AsmError};
+use cpu::CPU;
+use rom;
+
+const PRG_SIZE: usize = 0x8000;
+const CHR_BANK_SIZE: usize = 0x2000;
+const PRG_ORIGIN: u16 = 0x8000;
+// Where build_test_rom puts an RTI for the vectors the program doesn't give a handler for,
+// just below the vectors
+const RTI_STUB: u16 = 0xFFF9;
+const RTI: u8 = 0x40;
+
+// An NROM image with 32 KiB of PRG and vectors pointing at nmi, reset and irq. CHR is padded to
+// whole 8 KiB banks, and without any the cartridge has CHR RAM.
+fn ines_image(program: &[u8], vectors: [u16; 3], chr: &[u8]) -> Vec<u8> {
+    assert!(program.len() <= PRG_SIZE - 7, "program doesn't fit below the vectors");
+    let chr_banks = chr.len().div_ceil(CHR_BANK_SIZE);
+    assert!(chr_banks <= 0xFF, "too much CHR for an iNES header");
+    let mut image = vec![b'N', b'E', b'S', 0x1A, 2, chr_banks as u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    let mut prg = vec![0; PRG_SIZE];
+    prg[..program.len()].copy_from_slice(program);
+    prg[RTI_STUB as usize - PRG_ORIGIN as usize] = RTI;
+    for (i, vector) in vectors.iter().enumerate() {
+        prg[PRG_SIZE - 6 + i * 2] = *vector as u8;
+        prg[PRG_SIZE - 5 + i * 2] = (*vector >> 8) as u8;
+    }
+    image.extend_from_slice(&prg);
+    image.extend_from_slice(chr);
+    image.resize(image.len() + chr_banks * CHR_BANK_SIZE - chr.len(), 0);
+    return image
+}
+
+// A 32 KiB NROM image with the program at $8000, where the reset vector points
+pub fn build_rom(program: &[u8]) -> Vec<u8> {
+    ines_image(program, [0, PRG_ORIGIN, 0], &[])
+}
+
+// A powered-on CPU about to execute the program
+pub fn build_program(program: &[u8]) -> CPU {
+    let rom = rom::ROM::from_bytes(&build_rom(program)).unwrap();
+    let mut cpu = CPU::from_rom(rom);
+    cpu.power_on();
+    return cpu
+}
+
+// Assemble a program for $8000 into a full NROM image, see build_test_rom
+pub fn try_build_test_rom(prg_asm: &str, chr: Option<&[u8]>) -> Result<Vec<u8>, AsmError> {
+    let (program, labels) = asm::assemble_with_labels(prg_asm, PRG_ORIGIN)?;
+    let vector = |name: &str, default: u16| labels.get(name).cloned().unwrap_or(default);
+    let vectors = [vector("nmi", RTI_STUB), vector("reset", PRG_ORIGIN), vector("irq", RTI_STUB)];
+    Ok(ines_image(&program, vectors, chr.unwrap_or(&[])))
+}
+
+// Assemble a program for $8000 into a full NROM image. The reset vector points at the label
+// reset, or at $8000 without one, and NMI and IRQ at labels nmi and irq, or at an RTI. CHR is
+// padded to whole 8 KiB banks, and None gives the cartridge 8 KiB of CHR RAM instead.
+pub fn build_test_rom(prg_asm: &str, chr: Option<&[u8]>) -> Vec<u8> {
+    try_build_test_rom(prg_asm, chr).unwrap_or_else(|e| panic!("test program doesn't assemble: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build_program, build_test_rom, try_build_test_rom, RTI, RTI_STUB};
+
+    use rom::ROM;
+
+    fn vectors(image: &[u8]) -> [u16; 3] {
+        let rom = ROM::from_bytes(image).unwrap();
+        let word = |at: usize| rom.prg[at] as u16 | (rom.prg[at + 1] as u16) << 8;
+        [word(0x7FFA), word(0x7FFC), word(0x7FFE)]
+    }
+
+    #[test]
+    fn program_runs_from_8000() {
+        let mut cpu = build_program(&[0xA9, 0x42, 0x85, 0x10]);
+        assert_eq!((cpu.pc(), cpu.s()), (0x8000, 0xFD));
+        assert_eq!(cpu.memory().peek(0x8001), 0x42);
+        cpu.emulate_cycle().unwrap();
+        cpu.emulate_cycle().unwrap();
+        assert_eq!(cpu.memory().peek(0x10), 0x42);
+    }
+
+    #[test]
+    fn vectors_follow_the_labels() {
+        let image = build_test_rom("NOP\nreset: NOP\nnmi: RTI\nirq: RTI", None);
+        assert_eq!(vectors(&image), [0x8002, 0x8001, 0x8003]);
+        // Without handlers, interrupts return straight away
+        let image = build_test_rom("NOP", None);
+        assert_eq!(vectors(&image), [RTI_STUB, 0x8000, RTI_STUB]);
+        assert_eq!(ROM::from_bytes(&image).unwrap().prg[(RTI_STUB - 0x8000) as usize], RTI);
+    }
+
+    #[test]
+    fn chr_is_padded_to_whole_banks() {
+        let rom = ROM::from_bytes(&build_test_rom("NOP", Some(&[0xAA; 0x1001]))).unwrap();
+        assert_eq!(rom.chr.len(), 0x2000);
+        assert_eq!((rom.chr[0x1000], rom.chr[0x1001]), (0xAA, 0x00));
+        assert!(ROM::from_bytes(&build_test_rom("NOP", None)).unwrap().chr.is_empty());
+    }
+
+    #[test]
+    fn bad_assembly_is_an_error() {
+        assert!(try_build_test_rom("LDA", None).is_err());
+    }
+}
@@ -0,0 +1,190 @@
+// Running the emulator on a thread of its own, so a frontend's event loop never waits on a
+// frame. The thread owns the Nes; the frontend sends it commands over one channel and gets each
+// finished frame back over another.
+
+use controller::InputFrame;
+use cpu::EmulationError;
+use emulator::Nes;
+
+use std::panic;
+use std::sync::mpsc::{self, Receiver, RecvError, Sender, TryRecvError};
+use std::thread::{self, JoinHandle};
+
+// Everything a Nes holds has to be Send for it to move to the worker. This stops compiling if
+// something that isn't, like an Rc, finds its way in.
+fn assert_send<T: Send>() {}
+const _: fn() = || { assert_send::<Nes>(); };
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    // Run a frame with these buttons held, answered with a Frame
+    RunFrame(InputFrame),
+    // Run one frame even when paused, and stay paused, answered with a Frame
+    FrameAdvance(InputFrame),
+    Pause,
+    Resume,
+    Stop,
+}
+
+// A finished frame, copied out of the machine
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    // NES color indices, SCREEN_WIDTH x SCREEN_HEIGHT, with the on-screen messages drawn over
+    pub framebuffer: Vec<u8>,
+    pub frame: u64,
+    pub cycles: u64,
+    pub paused: bool,
+}
+
+pub struct EmulatorThread {
+    commands: Sender<Command>,
+    frames: Receiver<Result<Frame, EmulationError>>,
+    handle: JoinHandle<Nes>,
+}
+
+impl EmulatorThread {
+    pub fn spawn(nes: Nes) -> EmulatorThread {
+        let (commands, command_rx) = mpsc::channel();
+        let (frame_tx, frames) = mpsc::channel();
+        let handle = thread::spawn(move || run(nes, command_rx, frame_tx));
+        EmulatorThread { commands: commands, frames: frames, handle: handle }
+    }
+
+    // False once the thread has gone, after a Stop or because nobody was taking its frames
+    pub fn send(&self, command: Command) -> bool {
+        self.commands.send(command).is_ok()
+    }
+
+    pub fn run_frame(&self, input: InputFrame) -> bool {
+        self.send(Command::RunFrame(input))
+    }
+
+    // Wait for the next frame. Fails once the thread has stopped and every frame it sent has
+    // been taken.
+    pub fn recv_frame(&self) -> Result<Result<Frame, EmulationError>, RecvError> {
+        self.frames.recv()
+    }
+
+    // The next frame if there's one ready
+    pub fn try_recv_frame(&self) -> Result<Result<Frame, EmulationError>, TryRecvError> {
+        self.frames.try_recv()
+    }
+
+    // Stop the thread and take the machine back. Frames not yet received are thrown away. A
+    // panic on the thread carries on here.
+    pub fn stop(self) -> Nes {
+        let _ = self.commands.send(Command::Stop);
+        match self.handle.join() {
+            Ok(nes) => nes,
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    }
+}
+
+// The worker: answer commands until told to stop, or until the other end goes away
+fn run(mut nes: Nes, commands: Receiver<Command>, frames: Sender<Result<Frame, EmulationError>>) -> Nes {
+    while let Ok(command) = commands.recv() {
+        let output = match command {
+            Command::RunFrame(input) => nes.run_frame(input),
+            Command::FrameAdvance(input) => nes.frame_advance(input),
+            Command::Pause => { nes.pause(); continue; },
+            Command::Resume => { nes.resume(); continue; },
+            Command::Stop => break,
+        };
+        let frame = output.map(|output| Frame {
+            framebuffer: output.with_osd(),
+            frame: output.frame,
+            cycles: output.cycles,
+            paused: output.paused,
+        });
+        if frames.send(frame).is_err() {
+            break;
+        }
+    }
+    return nes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing;
+
+    // Steps the backdrop color on every NMI, so each frame is different
+    const BACKDROP_CYCLE: &str = "
+reset:  LDA #$80
+        STA $2000
+loop:   JMP loop
+
+nmi:    INC $10
+        LDA #$3F
+        STA $2006
+        LDA #$00
+        STA $2006
+        LDA $10
+        AND #$3F
+        STA $2007
+        LDA #$00
+        STA $2006
+        STA $2006
+        RTI
+";
+
+    fn nes() -> Nes {
+        let rom = testing::build_test_rom(BACKDROP_CYCLE, None);
+        Nes::builder().rom_bytes(&rom).ppu_warmup(false).build().unwrap()
+    }
+
+    #[test]
+    fn frames_come_back_over_the_channel() {
+        let worker = EmulatorThread::spawn(nes());
+        let mut local = nes();
+        let mut previous = Vec::new();
+        for n in 1..=100 {
+            assert!(worker.run_frame(InputFrame::default()));
+            let frame = worker.recv_frame().unwrap().unwrap();
+            let expected = local.run_frame(InputFrame::default()).unwrap();
+            assert_eq!((frame.frame, frame.cycles, frame.paused), (n, expected.cycles, false));
+            assert_eq!(frame.framebuffer, expected.framebuffer, "frame {}", n);
+            assert_ne!(frame.framebuffer, previous, "frame {}", n);
+            previous = frame.framebuffer;
+        }
+        assert!(worker.try_recv_frame().is_err());
+
+        let nes = worker.stop();
+        assert_eq!(nes.cpu().memory().ppu.frame, 100);
+        assert_eq!(nes.cpu().memory().peek(0x10), local.cpu().memory().peek(0x10));
+    }
+
+    #[test]
+    fn pause_and_frame_advance() {
+        let worker = EmulatorThread::spawn(nes());
+        worker.run_frame(InputFrame::default());
+        let first = worker.recv_frame().unwrap().unwrap();
+        assert_eq!(first.frame, 1);
+
+        // Paused, the last frame comes back again
+        worker.send(Command::Pause);
+        worker.run_frame(InputFrame::default());
+        let again = worker.recv_frame().unwrap().unwrap();
+        assert_eq!((again.frame, again.paused), (1, true));
+        assert_eq!(again.framebuffer, first.framebuffer);
+
+        worker.send(Command::FrameAdvance(InputFrame::default()));
+        assert_eq!(worker.recv_frame().unwrap().unwrap().frame, 2);
+        worker.run_frame(InputFrame::default());
+        assert_eq!(worker.recv_frame().unwrap().unwrap().frame, 2);
+
+        worker.send(Command::Resume);
+        worker.run_frame(InputFrame::default());
+        assert_eq!(worker.recv_frame().unwrap().unwrap().frame, 3);
+        assert_eq!(worker.stop().cpu().memory().ppu.frame, 3);
+    }
+
+    #[test]
+    fn stopped_thread_refuses_commands() {
+        let worker = EmulatorThread::spawn(nes());
+        let commands = worker.commands.clone();
+        worker.stop();
+        assert!(commands.send(Command::RunFrame(InputFrame::default())).is_err());
+    }
+}
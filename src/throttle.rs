@@ -0,0 +1,218 @@
+// Paces emulation against wall-clock time, one frame at a time
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Falling further behind than this many frames gives up on catching up instead of
+// running flat out until the backlog is cleared
+const MAX_LAG_FRAMES: u32 = 4;
+
+pub trait Clock {
+    // Time since some fixed point
+    fn now(&self) -> Duration;
+    fn sleep(&mut self, duration: Duration);
+}
+
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> SystemClock {
+        SystemClock { start: Instant::now() }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> SystemClock { SystemClock::new() }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+pub struct Throttle<C: Clock = SystemClock> {
+    clock: C,
+    frame_period: Duration,
+    // 1.0 is realtime, 0.0 runs uncapped
+    speed: f32,
+    // When the frame currently being emulated should be finished
+    deadline: Duration,
+}
+
+impl Throttle<SystemClock> {
+    pub fn new(frame_rate: f64) -> Throttle<SystemClock> {
+        Throttle::with_clock(frame_rate, SystemClock::new())
+    }
+}
+
+impl<C: Clock> Throttle<C> {
+    pub fn with_clock(frame_rate: f64, clock: C) -> Throttle<C> {
+        let deadline = clock.now();
+        Throttle {
+            clock: clock,
+            frame_period: Duration::from_secs_f64(1.0 / frame_rate),
+            speed: 1.0,
+            deadline: deadline,
+        }
+    }
+
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    pub fn set_speed(&mut self, speed: f32) {
+        self.speed = speed.max(0.0);
+        // Pace from now on at the new rate rather than making up time owed under the old one
+        self.deadline = self.clock.now();
+    }
+
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    // Call once per emulated frame; sleeps until that frame is due
+    pub fn wait_frame(&mut self) {
+        if self.speed == 0.0 {
+            return
+        }
+
+        let period = self.frame_period.div_f32(self.speed);
+        self.deadline += period;
+        let now = self.clock.now();
+        if now < self.deadline {
+            let remaining = self.deadline - now;
+            self.clock.sleep(remaining);
+        } else if now - self.deadline > period * MAX_LAG_FRAMES {
+            self.deadline = now;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Clock, Throttle};
+
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    // Time only moves when the test says so or something sleeps, and sleeps can overshoot
+    // the way a coarse OS timer would
+    struct MockClock {
+        now: Rc<Cell<Duration>>,
+        oversleep: Duration,
+        sleeps: Vec<Duration>,
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Duration {
+            self.now.get()
+        }
+
+        fn sleep(&mut self, duration: Duration) {
+            self.sleeps.push(duration);
+            self.now.set(self.now.get() + duration + self.oversleep);
+        }
+    }
+
+    fn ms(ms: f64) -> Duration {
+        Duration::from_secs_f64(ms / 1000.0)
+    }
+
+    // A 50 Hz throttle, so frames are a round 20ms, and a handle for moving its time on
+    fn throttle(oversleep: Duration) -> (Throttle<MockClock>, Rc<Cell<Duration>>) {
+        let now = Rc::new(Cell::new(Duration::from_secs(10)));
+        let clock = MockClock { now: now.clone(), oversleep: oversleep, sleeps: Vec::new() };
+        (Throttle::with_clock(50.0, clock), now)
+    }
+
+    fn assert_close(actual: Duration, expected: Duration) {
+        assert!(actual.abs_diff(expected) < Duration::from_micros(1), "{:?} isn't {:?}", actual, expected);
+    }
+
+    #[test]
+    fn sleeps_out_what_the_frame_didnt_use() {
+        let (mut throttle, now) = throttle(Duration::from_secs(0));
+        for &work in [0.0, 5.0, 19.0].iter() {
+            now.set(now.get() + ms(work));
+            throttle.wait_frame();
+        }
+        let sleeps = &throttle.clock().sleeps;
+        assert_eq!(sleeps.len(), 3);
+        for (&sleep, &expected) in sleeps.iter().zip([20.0, 15.0, 1.0].iter()) {
+            assert_close(sleep, ms(expected));
+        }
+    }
+
+    #[test]
+    fn speed_scales_the_frame_period() {
+        for &(speed, period) in [(2.0, 10.0), (0.5, 40.0), (4.0, 5.0)].iter() {
+            let (mut throttle, now) = throttle(Duration::from_secs(0));
+            throttle.set_speed(speed);
+            let start = now.get();
+            for _ in 0..10 {
+                throttle.wait_frame();
+            }
+            assert_close(now.get() - start, ms(period * 10.0));
+        }
+    }
+
+    #[test]
+    fn uncapped_never_sleeps() {
+        let (mut throttle, _) = throttle(Duration::from_secs(0));
+        throttle.set_speed(0.0);
+        for _ in 0..100 {
+            throttle.wait_frame();
+        }
+        assert!(throttle.clock().sleeps.is_empty());
+        // Negative speeds mean uncapped too
+        throttle.set_speed(-1.0);
+        assert_eq!(throttle.speed(), 0.0);
+    }
+
+    #[test]
+    fn oversleeping_doesnt_drift() {
+        // Every sleep runs 3ms long, so the next one is cut short to stay on schedule
+        let (mut throttle, now) = throttle(ms(3.0));
+        let start = now.get();
+        for _ in 0..50 {
+            throttle.wait_frame();
+        }
+        assert_close(throttle.clock().sleeps[1], ms(17.0));
+        assert_close(now.get() - start, ms(50.0 * 20.0 + 3.0));
+    }
+
+    #[test]
+    fn a_slow_frame_is_made_up() {
+        let (mut throttle, now) = throttle(Duration::from_secs(0));
+        let start = now.get();
+        // 50ms of work is 1.5 frames behind, caught up over the next two frames
+        now.set(now.get() + ms(50.0));
+        throttle.wait_frame();
+        throttle.wait_frame();
+        throttle.wait_frame();
+        assert_close(now.get() - start, ms(60.0));
+        assert_eq!(throttle.clock().sleeps.len(), 1);
+        assert_close(throttle.clock().sleeps[0], ms(10.0));
+    }
+
+    #[test]
+    fn a_long_stall_is_forgotten() {
+        let (mut throttle, now) = throttle(Duration::from_secs(0));
+        // A second in the debugger is far more than MAX_LAG_FRAMES behind, so pacing starts
+        // over rather than running flat out for 50 frames
+        now.set(now.get() + Duration::from_secs(1));
+        throttle.wait_frame();
+        assert!(throttle.clock().sleeps.is_empty());
+        let stalled = now.get();
+        throttle.wait_frame();
+        assert_close(now.get() - stalled, ms(20.0));
+    }
+}
@@ -0,0 +1,61 @@
+// Small formatting helpers shared by the debugging output
+
+use std::fmt::Write;
+
+// Canonical hexdump: "addr: hex bytes  |ascii|" with width bytes per row, an extra space
+// every 8 bytes and '.' for anything unprintable. base is the address of data[0].
+pub fn hexdump(data: &[u8], base: u32, width: usize) -> String {
+    let width = width.max(1);
+    let mut dump = String::new();
+    for (row, chunk) in data.chunks(width).enumerate() {
+        write!(dump, "{:04x}:", base as usize + row * width).unwrap();
+        for i in 0..width {
+            if i % 8 == 0 {
+                dump.push(' ');
+            }
+            match chunk.get(i) {
+                Some(byte) => write!(dump, " {:02x}", byte).unwrap(),
+                // Keep the ASCII column lined up on a short final row
+                None => dump.push_str("   "),
+            }
+        }
+        dump.push_str("  |");
+        for &byte in chunk.iter() {
+            dump.push(if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' });
+        }
+        dump.push_str("|\n");
+    }
+    return dump
+}
+
+#[cfg(test)]
+mod tests {
+    use super::hexdump;
+
+    #[test]
+    fn empty_input_is_empty() {
+        assert_eq!(hexdump(&[], 0x8000, 16), "");
+    }
+
+    #[test]
+    fn sixteen_wide_with_a_short_last_row() {
+        assert_eq!(hexdump(b"Hello, world!\n\x00\xff\x80A", 0x0200, 16), concat!(
+            "0200:  48 65 6c 6c 6f 2c 20 77  6f 72 6c 64 21 0a 00 ff  |Hello, world!...|\n",
+            "0210:  80 41                                             |.A|\n",
+        ));
+    }
+
+    #[test]
+    fn narrow_rows_group_every_eight() {
+        assert_eq!(hexdump(&[0, 1, 2, 3, 4, 5], 0, 4), concat!(
+            "0000:  00 01 02 03  |....|\n",
+            "0004:  04 05        |..|\n",
+        ));
+        assert_eq!(hexdump(b"0123456789", 0x10, 10), "0010:  30 31 32 33 34 35 36 37  38 39  |0123456789|\n");
+    }
+
+    #[test]
+    fn zero_width_is_one_byte_a_row() {
+        assert_eq!(hexdump(b"ab", 0xFFFF, 0), "ffff:  61  |a|\n10000:  62  |b|\n");
+    }
+}
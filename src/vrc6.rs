@@ -0,0 +1,607 @@
+// Konami VRC6, mappers 24 and 26: switchable PRG and CHR banks, a scanline or CPU cycle IRQ
+// counter, and three sound channels of its own (two pulses and a sawtooth) that the console
+// mixes in with the APU's. Mapper 26 boards have the cartridge's A0 and A1 lines swapped, so
+// the same registers turn up at swapped addresses.
+//
+// Only the CHR banking Akumajou Densetsu, Madara and Esper Dream 2 use is emulated: eight
+// 1 KiB banks, with the nametables in console VRAM. The other $B003 modes are reported
+// through unsupported().
+
+use mapper::{self, Mapper, NametableSource};
+use rom::{Mirroring, ROM};
+use state::StateError;
+
+// The IRQ prescaler counts down by 3 a CPU cycle from 341, to clock the counter once per
+// 341 PPU dots, a scanline
+const PRESCALER_PERIOD: i16 = 341;
+const PRESCALER_STEP: i16 = 3;
+// One step of a VRC6 channel's volume against the APU's mixer, where 32767 is full scale.
+// The channels are about as loud as the APU's pulses.
+const MIX_STEP: u16 = 256;
+
+// A VRC6 pulse: a 16-step duty cycle at a volume, or the volume held with the mode bit set
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Vrc6Pulse {
+    volume: u8,
+    // Steps out of 16 the output is high for, less one
+    duty: u8,
+    // Ignore the duty and output the volume all the time
+    constant: bool,
+    enabled: bool,
+    period: u16,
+    timer: u16,
+    step: u8,
+}
+
+impl Vrc6Pulse {
+    fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => {
+                self.constant = val & 0x80 != 0;
+                self.duty = (val >> 4) & 7;
+                self.volume = val & 0x0F;
+            },
+            1 => self.period = (self.period & 0xF00) | val as u16,
+            _ => {
+                self.period = (self.period & 0xFF) | (val as u16 & 0x0F) << 8;
+                self.enabled = val & 0x80 != 0;
+                // The duty cycle starts over when the channel's turned off
+                if !self.enabled {
+                    self.step = 15;
+                }
+            },
+        }
+    }
+
+    // shift is how far $9003 has the period shifted down, speeding every channel up
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return
+        }
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.step = self.step.wrapping_sub(1) & 15;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.enabled && (self.constant || self.step <= self.duty) { self.volume } else { 0 }
+    }
+}
+
+// The sawtooth: an accumulator that adds the rate on every other clock and is cleared on the
+// fourteenth, for a seven-step ramp
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Vrc6Saw {
+    rate: u8,
+    enabled: bool,
+    period: u16,
+    timer: u16,
+    // Clocks since the ramp started, 0 to 13
+    step: u8,
+    accumulator: u8,
+}
+
+impl Vrc6Saw {
+    fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => self.rate = val & 0x3F,
+            1 => self.period = (self.period & 0xF00) | val as u16,
+            _ => {
+                self.period = (self.period & 0xFF) | (val as u16 & 0x0F) << 8;
+                self.enabled = val & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            },
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+            return
+        }
+        self.timer = self.period >> shift;
+        self.step += 1;
+        if self.step == 14 {
+            self.step = 0;
+            self.accumulator = 0;
+        } else if self.step & 1 == 0 {
+            self.accumulator = self.accumulator.wrapping_add(self.rate);
+        }
+    }
+
+    // The accumulator's top five bits
+    pub fn output(&self) -> u8 {
+        if self.enabled { self.accumulator >> 3 } else { 0 }
+    }
+}
+
+pub struct Vrc6 {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+    chr_ram: bool,
+    // Mapper 26, with A0 and A1 swapped
+    swapped: bool,
+    // $8000: 16 KiB at $8000. $C000: 8 KiB at $C000. The last 8 KiB is fixed at $E000.
+    prg_16k: u8,
+    prg_8k: u8,
+    // $D000-$D003 and $E000-$E003
+    chr_banks: [u8; 8],
+    // $B003
+    banking: u8,
+    // $9003: bit 0 halts all three channels, bits 1 and 2 speed them up 16 or 256 times
+    frequency_control: u8,
+    pulses: [Vrc6Pulse; 2],
+    saw: Vrc6Saw,
+    // $F000-$F002
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_prescaler: i16,
+    irq_enabled: bool,
+    // What $F002 sets irq_enabled back to
+    irq_enable_after_ack: bool,
+    // Count CPU cycles rather than scanlines
+    irq_cycle_mode: bool,
+    irq_pending: bool,
+}
+
+impl Vrc6 {
+    pub fn new(rom: &ROM, swapped: bool) -> Vrc6 {
+        let (chr, chr_ram) = mapper::chr_memory(rom);
+        Vrc6 {
+            prg: rom.prg.clone(),
+            chr: chr,
+            chr_ram: chr_ram,
+            swapped: swapped,
+            prg_16k: 0,
+            prg_8k: 0,
+            chr_banks: [0; 8],
+            banking: 0,
+            frequency_control: 0,
+            pulses: [Vrc6Pulse { step: 15, ..Vrc6Pulse::default() }; 2],
+            saw: Vrc6Saw::default(),
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_prescaler: PRESCALER_PERIOD,
+            irq_enabled: false,
+            irq_enable_after_ack: false,
+            irq_cycle_mode: false,
+            irq_pending: false,
+        }
+    }
+
+    // The register a write lands on, as mapper 24 numbers them: $x000-$x003
+    pub fn register(&self, addr: u16) -> u16 {
+        let addr = addr & 0xF003;
+        if self.swapped { (addr & !3) | (addr & 1) << 1 | (addr & 2) >> 1 } else { addr }
+    }
+
+    pub fn pulse(&self, channel: usize) -> &Vrc6Pulse {
+        &self.pulses[channel]
+    }
+
+    pub fn saw(&self) -> &Vrc6Saw {
+        &self.saw
+    }
+
+    pub fn irq_counter(&self) -> u8 {
+        self.irq_counter
+    }
+
+    fn clock_irq_counter(&mut self) {
+        if self.irq_counter == 0xFF {
+            self.irq_counter = self.irq_latch;
+            self.irq_pending = true;
+        } else {
+            self.irq_counter += 1;
+        }
+    }
+
+    fn clock_irq(&mut self) {
+        if !self.irq_enabled {
+            return
+        }
+        if self.irq_cycle_mode {
+            self.clock_irq_counter();
+            return
+        }
+        self.irq_prescaler -= PRESCALER_STEP;
+        if self.irq_prescaler <= 0 {
+            self.irq_prescaler += PRESCALER_PERIOD;
+            self.clock_irq_counter();
+        }
+    }
+
+    fn chr_offset(&self, addr: u16) -> Option<usize> {
+        let bank = self.chr_banks[(addr as usize >> 10) & 7];
+        mapper::bank_offset(self.chr.len(), 0x400, bank as usize, addr as usize)
+    }
+}
+
+impl Mapper for Vrc6 {
+    fn cpu_peek(&self, addr: u16) -> u8 {
+        self.prg_offset(addr).map_or(0, |offset| self.prg[offset])
+    }
+
+    fn cpu_write(&mut self, addr: u16, val: u8) {
+        if addr < 0x8000 {
+            return;
+        }
+        let reg = self.register(addr);
+        match reg {
+            0x8000..=0x8003 => self.prg_16k = val & 0x0F,
+            0x9000..=0x9002 => self.pulses[0].write(reg & 3, val),
+            0x9003 => self.frequency_control = val & 7,
+            0xA000..=0xA002 => self.pulses[1].write(reg & 3, val),
+            0xB000..=0xB002 => self.saw.write(reg & 3, val),
+            0xB003 => self.banking = val,
+            0xC000..=0xC003 => self.prg_8k = val & 0x1F,
+            0xD000..=0xD003 => self.chr_banks[(reg & 3) as usize] = val,
+            0xE000..=0xE003 => self.chr_banks[4 + (reg & 3) as usize] = val,
+            0xF000 => self.irq_latch = val,
+            0xF001 => {
+                self.irq_enable_after_ack = val & 1 != 0;
+                self.irq_enabled = val & 2 != 0;
+                self.irq_cycle_mode = val & 4 != 0;
+                if self.irq_enabled {
+                    self.irq_counter = self.irq_latch;
+                    self.irq_prescaler = PRESCALER_PERIOD;
+                }
+                self.irq_pending = false;
+            },
+            0xF002 => {
+                self.irq_pending = false;
+                self.irq_enabled = self.irq_enable_after_ack;
+            },
+            _ => {},
+        }
+    }
+
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        self.chr_offset(addr).map_or(0, |offset| self.chr[offset])
+    }
+
+    fn ppu_write(&mut self, addr: u16, val: u8) {
+        if self.chr_ram {
+            if let Some(offset) = self.chr_offset(addr) {
+                self.chr[offset] = val;
+            }
+        }
+    }
+
+    // Only for anything that asks; the PPU goes through nametable()
+    fn mirroring(&self) -> Mirroring {
+        match (self.banking >> 2) & 3 {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            _ => Mirroring::FourScreen,
+        }
+    }
+
+    // The two one-screen settings put all four on one VRAM page
+    fn nametable(&self, table: usize) -> NametableSource {
+        NametableSource::Vram(match (self.banking >> 2) & 3 {
+            0 => table % 2,
+            1 => table / 2,
+            2 => 0,
+            _ => 1,
+        })
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.banking & 0x80 != 0
+    }
+
+    fn cpu_clock(&mut self, cycles: u32) {
+        let halted = self.frequency_control & 1 != 0;
+        let shift = match self.frequency_control {
+            c if c & 4 != 0 => 8,
+            c if c & 2 != 0 => 4,
+            _ => 0,
+        };
+        for _ in 0..cycles {
+            self.clock_irq();
+            if !halted {
+                self.pulses[0].clock(shift);
+                self.pulses[1].clock(shift);
+                self.saw.clock(shift);
+            }
+        }
+    }
+
+    fn audio_output(&self) -> u16 {
+        let level = self.pulses[0].output() + self.pulses[1].output() + self.saw.output();
+        level as u16 * MIX_STEP
+    }
+
+    fn unsupported(&self) -> Option<&'static str> {
+        if self.banking & 0x03 != 0 {
+            return Some("VRC6 CHR banking modes other than 1 KiB")
+        }
+        if self.banking & 0x10 != 0 {
+            return Some("VRC6 nametables from CHR ROM")
+        }
+        None
+    }
+
+    fn prg_offset(&self, addr: u16) -> Option<usize> {
+        let (bank, size) = match addr {
+            0x8000..=0xBFFF => (self.prg_16k as usize, 0x4000),
+            0xC000..=0xDFFF => (self.prg_8k as usize, 0x2000),
+            0xE000..=0xFFFF => (self.prg.len() / 0x2000 - 1, 0x2000),
+            _ => return None,
+        };
+        mapper::bank_offset(self.prg.len(), size, bank, addr as usize)
+    }
+
+    fn prg_bank_size(&self, addr: u16) -> usize {
+        if addr < 0xC000 { 0x4000 } else { 0x2000 }
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut registers = vec![
+            self.prg_16k, self.prg_8k, self.banking, self.frequency_control, self.irq_latch, self.irq_counter,
+            self.irq_enabled as u8, self.irq_enable_after_ack as u8, self.irq_cycle_mode as u8,
+            self.irq_pending as u8,
+        ];
+        registers.extend_from_slice(&self.irq_prescaler.to_le_bytes());
+        registers.extend_from_slice(&self.chr_banks);
+        for pulse in self.pulses.iter() {
+            registers.extend_from_slice(&[pulse.volume, pulse.duty, pulse.constant as u8, pulse.enabled as u8, pulse.step]);
+            registers.extend_from_slice(&pulse.period.to_le_bytes());
+            registers.extend_from_slice(&pulse.timer.to_le_bytes());
+        }
+        let saw = &self.saw;
+        registers.extend_from_slice(&[saw.rate, saw.enabled as u8, saw.step, saw.accumulator]);
+        registers.extend_from_slice(&saw.period.to_le_bytes());
+        registers.extend_from_slice(&saw.timer.to_le_bytes());
+        mapper::save_with_chr_ram(&registers, &self.chr, self.chr_ram)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let r = mapper::load_with_chr_ram(state, 10 + 2 + 8 + 2 * 9 + 8, &mut self.chr, self.chr_ram)?;
+        let word = |i: usize| u16::from_le_bytes([r[i], r[i + 1]]);
+        self.prg_16k = r[0];
+        self.prg_8k = r[1];
+        self.banking = r[2];
+        self.frequency_control = r[3];
+        self.irq_latch = r[4];
+        self.irq_counter = r[5];
+        self.irq_enabled = r[6] != 0;
+        self.irq_enable_after_ack = r[7] != 0;
+        self.irq_cycle_mode = r[8] != 0;
+        self.irq_pending = r[9] != 0;
+        self.irq_prescaler = word(10) as i16;
+        self.chr_banks.copy_from_slice(&r[12..20]);
+        for (i, pulse) in self.pulses.iter_mut().enumerate() {
+            let p = 20 + i * 9;
+            pulse.volume = r[p];
+            pulse.duty = r[p + 1];
+            pulse.constant = r[p + 2] != 0;
+            pulse.enabled = r[p + 3] != 0;
+            pulse.step = r[p + 4];
+            pulse.period = word(p + 5);
+            pulse.timer = word(p + 7);
+        }
+        self.saw.rate = r[38];
+        self.saw.enabled = r[39] != 0;
+        self.saw.step = r[40];
+        self.saw.accumulator = r[41];
+        self.saw.period = word(42);
+        self.saw.timer = word(44);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use controller::InputFrame;
+    use emulator::Nes;
+    use testing;
+
+    // 128 KiB of PRG with each 8 KiB bank filled with its number, and 32 KiB of CHR with each
+    // 1 KiB bank filled with its number
+    fn vrc6(swapped: bool) -> Vrc6 {
+        let prg = (0..0x20000).map(|i| (i / 0x2000) as u8).collect();
+        let chr = (0..0x8000).map(|i| (i / 0x400) as u8).collect();
+        Vrc6::new(&ROM::from_parts(prg, chr), swapped)
+    }
+
+    // What a channel puts out over clocks clocks of the mapper
+    fn outputs<F: Fn(&Vrc6) -> u8>(mapper: &mut Vrc6, clocks: usize, output: F) -> Vec<u8> {
+        (0..clocks).map(|_| {
+            mapper.cpu_clock(1);
+            output(mapper)
+        }).collect()
+    }
+
+    #[test]
+    fn banks_wrap_and_the_last_is_fixed() {
+        let mut mapper = vrc6(false);
+        mapper.cpu_write(0x8000, 0x03);
+        mapper.cpu_write(0xC000, 0x15);
+        assert_eq!([mapper.cpu_peek(0x8000), mapper.cpu_peek(0xA000), mapper.cpu_peek(0xC000), mapper.cpu_peek(0xE000)],
+                   [6, 7, 5, 15]);
+        // Eight 16 KiB banks, so 11 is 3 again
+        mapper.cpu_write(0x8000, 0x0B);
+        assert_eq!(mapper.cpu_peek(0xBFFF), 7);
+        assert_eq!(mapper.prg_offset(0xC123), Some(5 * 0x2000 + 0x123));
+
+        for (i, &register) in [0xD000, 0xD001, 0xD002, 0xD003, 0xE000, 0xE001, 0xE002, 0xE003].iter().enumerate() {
+            mapper.cpu_write(register, 0x23 + i as u8);
+        }
+        let banks: Vec<u8> = (0..8).map(|i| mapper.ppu_peek(i * 0x400 + 0x3FF)).collect();
+        assert_eq!(banks, [3, 4, 5, 6, 7, 8, 9, 10]);
+    }
+
+    #[test]
+    fn mapper_26_swaps_a0_and_a1() {
+        let mut mapper = vrc6(true);
+        assert_eq!([mapper.register(0x9001), mapper.register(0x9002), mapper.register(0xF003), mapper.register(0xB000)],
+                   [0x9002, 0x9001, 0xF003, 0xB000]);
+        // $D001 is mapper 24's $D002, the third CHR bank
+        mapper.cpu_write(0xD001, 9);
+        assert_eq!((mapper.ppu_peek(0x0400), mapper.ppu_peek(0x0800)), (0, 9));
+        // and $B002 its $B001, the low bits of the sawtooth's period
+        mapper.cpu_write(0xB002, 0x34);
+        assert_eq!(mapper.saw().period, 0x34);
+        assert!(!mapper.saw().enabled);
+    }
+
+    #[test]
+    fn b003_sets_the_nametables_and_prg_ram() {
+        let mut mapper = vrc6(false);
+        let tables = |mapper: &Vrc6| (0..4).map(|table| mapper.nametable(table)).collect::<Vec<_>>();
+        let pages = |pages: [usize; 4]| pages.iter().map(|&page| NametableSource::Vram(page)).collect::<Vec<_>>();
+        assert_eq!(tables(&mapper), pages([0, 1, 0, 1]));
+        mapper.cpu_write(0xB003, 0x04);
+        assert_eq!(tables(&mapper), pages([0, 0, 1, 1]));
+        mapper.cpu_write(0xB003, 0x08);
+        assert_eq!(tables(&mapper), pages([0, 0, 0, 0]));
+        mapper.cpu_write(0xB003, 0x0C);
+        assert_eq!(tables(&mapper), pages([1, 1, 1, 1]));
+        assert!(!mapper.prg_ram_enabled());
+        mapper.cpu_write(0xB003, 0x80);
+        assert!(mapper.prg_ram_enabled());
+        assert_eq!(mapper.unsupported(), None);
+        mapper.cpu_write(0xB003, 0x01);
+        assert!(mapper.unsupported().is_some());
+    }
+
+    #[test]
+    fn cycle_mode_irq_counts_up_from_the_latch() {
+        let mut mapper = vrc6(false);
+        mapper.cpu_write(0xF000, 0xF0);
+        mapper.cpu_write(0xF001, 0x07);
+        mapper.cpu_clock(15);
+        assert_eq!((mapper.irq(), mapper.irq_counter()), (false, 0xFF));
+        mapper.cpu_clock(1);
+        assert_eq!((mapper.irq(), mapper.irq_counter()), (true, 0xF0));
+
+        // Acknowledging with E set carries on counting
+        mapper.cpu_write(0xF002, 0);
+        assert!(!mapper.irq());
+        mapper.cpu_clock(16);
+        assert!(mapper.irq());
+
+        // and with it clear stops the counter where it is
+        mapper.cpu_write(0xF001, 0x06);
+        mapper.cpu_clock(3);
+        mapper.cpu_write(0xF002, 0);
+        mapper.cpu_clock(100);
+        assert_eq!((mapper.irq(), mapper.irq_counter()), (false, 0xF3));
+    }
+
+    #[test]
+    fn scanline_mode_irq_counts_341_dots_at_a_time() {
+        let mut mapper = vrc6(false);
+        mapper.cpu_write(0xF000, 0xF0);
+        mapper.cpu_write(0xF001, 0x02);
+        // 16 scanlines of 341 dots, three a CPU cycle, is 1818 2/3 cycles
+        mapper.cpu_clock(1818);
+        assert!(!mapper.irq());
+        mapper.cpu_clock(1);
+        assert!(mapper.irq());
+        // Writing $F001 acknowledges it and starts the prescaler over
+        mapper.cpu_write(0xF001, 0x02);
+        assert!(!mapper.irq());
+        mapper.cpu_clock(1818);
+        assert!(!mapper.irq());
+    }
+
+    #[test]
+    fn sawtooth_adds_the_rate_every_other_clock() {
+        let mut mapper = vrc6(false);
+        mapper.cpu_write(0xB000, 8);
+        mapper.cpu_write(0xB002, 0x80);
+        assert_eq!(outputs(&mut mapper, 15, |mapper| mapper.saw().output()), [0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 0, 0]);
+
+        // The accumulator is eight bits, so a fast ramp wraps before it's cleared
+        mapper.cpu_write(0xB002, 0x00);
+        mapper.cpu_write(0xB000, 0x3F);
+        mapper.cpu_write(0xB002, 0x80);
+        assert_eq!(outputs(&mut mapper, 14, |mapper| mapper.saw().output()), [0, 7, 7, 15, 15, 23, 23, 31, 31, 7, 7, 15, 15, 0]);
+    }
+
+    #[test]
+    fn frequency_control_halts_and_speeds_up_the_channels() {
+        let mut mapper = vrc6(false);
+        mapper.cpu_write(0xB000, 8);
+        mapper.cpu_write(0xB001, 0x3F);
+        mapper.cpu_write(0xB002, 0x80);
+        // A period of $3F, so each step takes 64 clocks
+        mapper.cpu_clock(64 * 2);
+        assert_eq!(mapper.saw().output(), 1);
+        mapper.cpu_write(0x9003, 0x01);
+        mapper.cpu_clock(64 * 10);
+        assert_eq!(mapper.saw().output(), 1);
+        // Shifted down 4 it's 4 clocks, and shifted down 8 every clock
+        mapper.cpu_write(0x9003, 0x02);
+        mapper.cpu_clock(4 * 2);
+        assert_eq!(mapper.saw().output(), 2);
+        mapper.cpu_write(0x9003, 0x06);
+        mapper.cpu_clock(2);
+        assert_eq!(mapper.saw().output(), 3);
+    }
+
+    #[test]
+    fn pulses_play_their_duty_and_mix_in() {
+        let mut mapper = vrc6(false);
+        // Four steps high out of sixteen, at volume 10
+        mapper.cpu_write(0x9000, 0x3A);
+        mapper.cpu_write(0x9002, 0x80);
+        let mut expected = vec![0; 11];
+        expected.extend_from_slice(&[10, 10, 10, 10, 0]);
+        assert_eq!(outputs(&mut mapper, 16, |mapper| mapper.pulse(0).output()), expected);
+
+        mapper.cpu_write(0xA000, 0x85);
+        mapper.cpu_write(0xA002, 0x80);
+        mapper.cpu_clock(1);
+        assert_eq!((mapper.pulse(0).output(), mapper.pulse(1).output()), (0, 5));
+        assert_eq!(mapper.audio_output(), 5 * MIX_STEP);
+        // Turning it off restarts the duty cycle
+        mapper.cpu_clock(11);
+        assert_eq!(mapper.audio_output(), 15 * MIX_STEP);
+        mapper.cpu_write(0x9002, 0x00);
+        mapper.cpu_write(0x9002, 0x80);
+        assert_eq!(mapper.pulse(0).step, 15);
+    }
+
+    // Runs a program that turns the first pulse on at volume 15 for a few frames, on mapper
+    // 24 or as plain NROM, and gives back the loudest sample
+    fn loudest_sample(mapper: u8) -> f32 {
+        let mut rom = testing::build_test_rom("
+                LDA #$8F
+                STA $9000
+                LDA #$80
+                STA $9002
+        spin:   JMP spin", None);
+        rom[6] = mapper << 4;
+        rom[7] = mapper & 0xF0;
+        let mut nes = Nes::builder().rom_bytes(&rom).build().unwrap();
+        let mut loudest: f32 = 0.0;
+        for _ in 0..3 {
+            let output = nes.run_frame(InputFrame::default()).unwrap();
+            loudest = output.audio.iter().fold(loudest, |loudest, &sample| loudest.max(sample));
+        }
+        loudest
+    }
+
+    #[test]
+    fn expansion_audio_goes_into_the_mix() {
+        assert!(loudest_sample(24) > loudest_sample(0) + 0.05);
+    }
+}
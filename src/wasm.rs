@@ -0,0 +1,187 @@
+// Browser bindings. Build with `wasm-pack build --target web -- --features wasm`.
+
+use controller::Button;
+use emulator::Nes;
+use keymap::{InputState, TurboConfig};
+use osd;
+use overscan::{CroppedFrame, Overscan};
+use postprocess::{PostProcess, PostProcessor, RgbFrame};
+use ppu::Layer;
+
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen]
+pub struct WasmNes {
+    nes: Nes,
+    // Buttons held as of the last set_button or set_turbo_button, put together for each frame
+    input: InputState,
+    turbo: TurboConfig,
+    post: PostProcessor,
+    // The size of the last picture returned, which the NTSC filter changes
+    width: usize,
+    height: usize,
+}
+
+#[wasm_bindgen]
+impl WasmNes {
+    #[wasm_bindgen(constructor)]
+    pub fn new(rom: &[u8]) -> Result<WasmNes, JsValue> {
+        let nes = Nes::builder()
+            .rom_bytes(rom)
+            .build()
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        Ok(WasmNes {
+            nes: nes,
+            input: InputState::new(),
+            turbo: TurboConfig::default(),
+            post: PostProcessor::default(),
+            width: 0,
+            height: 0,
+        })
+    }
+
+    // The frame with the on-screen messages over it, through the post-processing filter, as
+    // RGBA
+    fn picture(&mut self, framebuffer: &[u8]) -> Vec<u8> {
+        let frame = CroppedFrame::new(framebuffer, Overscan::NONE);
+        let picture = self.post.process(RgbFrame::from_indices(&frame, &self.nes.cpu().memory().ppu.rgb_palette));
+        self.width = picture.width;
+        self.height = picture.height;
+        picture.to_rgba()
+    }
+
+    // Run until the PPU finishes a frame and return it as RGBA, with any on-screen messages
+    // drawn over it. Throws if the CPU stops.
+    pub fn run_frame(&mut self) -> Result<Vec<u8>, JsValue> {
+        let input = self.input.frame(self.nes.cpu().memory().ppu.frame);
+        let frame = self.nes.run_frame(input).map_err(|e| JsValue::from_str(&e.to_string()))?.with_osd();
+        Ok(self.picture(&frame))
+    }
+
+    // none, blend or ntsc. Takes effect from the next frame.
+    pub fn set_post_process(&mut self, mode: &str) -> Result<(), JsValue> {
+        self.post.mode = mode.parse::<PostProcess>().map_err(JsValue::from_str)?;
+        Ok(())
+    }
+
+    // The size of the last frame returned, in pixels
+    pub fn frame_width(&self) -> usize {
+        self.width
+    }
+
+    pub fn frame_height(&self) -> usize {
+        self.height
+    }
+
+    // While paused, run_frame keeps returning the last frame
+    pub fn set_paused(&mut self, paused: bool) {
+        if paused { self.nes.pause() } else { self.nes.resume() }
+        self.nes.osd_mut().notify(if paused { "Paused" } else { "Resumed" }, osd::DEFAULT_DURATION);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.nes.is_paused()
+    }
+
+    // Show the buttons the game latched each frame in the corner of the picture
+    pub fn set_input_display(&mut self, enabled: bool) {
+        self.nes.set_input_display(enabled);
+    }
+
+    // Show or hide bg or sprites in the picture, without the game noticing
+    pub fn set_layer_visible(&mut self, layer: &str, visible: bool) -> Result<(), JsValue> {
+        let layer = layer.parse::<Layer>().map_err(JsValue::from_str)?;
+        self.nes.cpu_mut().memory_mut().ppu.set_layer_visibility(layer, visible);
+        Ok(())
+    }
+
+    // Draw each sprite in a flat color for its OAM entry
+    pub fn set_sprite_tint(&mut self, enabled: bool) {
+        self.nes.cpu_mut().memory_mut().ppu.set_sprite_tint(enabled);
+    }
+
+    // Run one frame and stay paused, returning it as RGBA
+    pub fn frame_advance(&mut self) -> Result<Vec<u8>, JsValue> {
+        let input = self.input.frame(self.nes.cpu().memory().ppu.frame);
+        let frame = self.nes.frame_advance(input).map_err(|e| JsValue::from_str(&e.to_string()))?.with_osd();
+        Ok(self.picture(&frame))
+    }
+
+    // button is the shift register bit: A, B, Select, Start, Up, Down, Left, Right
+    pub fn set_button(&mut self, port: usize, button: u8, pressed: bool) {
+        if let Some(button) = Button::from_index(button) {
+            self.input.set_button(port, button, pressed);
+        }
+    }
+
+    // The same, but the button goes on and off at the turbo rate while held
+    pub fn set_turbo_button(&mut self, port: usize, button: u8, pressed: bool) {
+        if let Some(button) = Button::from_index(button) {
+            self.input.set_turbo(port, button, if pressed { Some(self.turbo) } else { None });
+        }
+    }
+
+    // Frames per press and release for turbo buttons pressed from now on
+    pub fn set_turbo_rate(&mut self, rate_frames: u8) {
+        self.turbo.rate_frames = rate_frames.max(1);
+    }
+
+    // The last frame's audio, mono at 44.1kHz from -1.0 to 1.0
+    pub fn audio_samples(&mut self) -> Vec<f32> {
+        self.nes.audio().to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WasmNes;
+    use palette;
+    use ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+    use testing;
+
+    // Keeps the first controller's A button in $10
+    const READ_A: &str = "
+loop:   LDA #$01
+        STA $4016
+        LDA #$00
+        STA $4016
+        LDA $4016
+        AND #$01
+        STA $10
+        JMP loop
+";
+
+    #[test]
+    fn run_frame_gives_the_picture_as_rgba() {
+        let mut nes = WasmNes::new(&testing::build_test_rom(READ_A, None)).unwrap();
+        let rgba = nes.run_frame().unwrap();
+        assert_eq!((nes.frame_width(), nes.frame_height()), (SCREEN_WIDTH, SCREEN_HEIGHT));
+        assert_eq!(rgba.len(), SCREEN_WIDTH * SCREEN_HEIGHT * 4);
+        let backdrop = palette::SYSTEM_PALETTE[nes.nes.cpu().memory().ppu.palette_ram()[0] as usize];
+        assert!(rgba.chunks(4).all(|pixel| pixel[..3] == backdrop && pixel[3] == 255));
+    }
+
+    #[test]
+    fn buttons_reach_the_game() {
+        let mut nes = WasmNes::new(&testing::build_test_rom(READ_A, None)).unwrap();
+        nes.set_button(0, 0, true);
+        nes.run_frame().unwrap();
+        assert_eq!(nes.nes.cpu().memory().peek(0x10), 1);
+        nes.set_button(0, 0, false);
+        nes.run_frame().unwrap();
+        assert_eq!(nes.nes.cpu().memory().peek(0x10), 0);
+        // Out of range buttons are ignored
+        nes.set_button(0, 8, true);
+    }
+
+    #[test]
+    fn audio_samples_are_the_last_frames() {
+        let mut nes = WasmNes::new(&testing::build_test_rom(READ_A, None)).unwrap();
+        nes.run_frame().unwrap();
+        nes.run_frame().unwrap();
+        // 44.1kHz over an NTSC frame
+        let samples = nes.audio_samples();
+        assert!((733..=735).contains(&samples.len()), "{} samples", samples.len());
+        assert!(samples.iter().all(|&sample| (-1.0..=1.0).contains(&sample)));
+    }
+}
@@ -0,0 +1,126 @@
+// The Zapper light gun. It reports its trigger and whether its photodiode sees light through
+// the controller 2 port.
+
+use ppu;
+
+// $4017 bits. Light sense is active low: the bit is clear while the gun sees light.
+const LIGHT_SENSE: u8 = 1 << 3;
+const TRIGGER: u8 = 1 << 4;
+
+// How bright (0-255 luma) a pixel has to be to register
+const BRIGHTNESS_THRESHOLD: u32 = 192;
+// Scanlines the photodiode keeps seeing a lit pixel after the beam has drawn it
+const SENSE_LINES: usize = 25;
+
+#[derive(Default)]
+pub struct Zapper {
+    // Screen pixel the gun points at, None when it points off screen
+    position: Option<(usize, usize)>,
+    trigger: bool,
+}
+
+impl Zapper {
+    pub fn new() -> Zapper {
+        Zapper::default()
+    }
+
+    // Points off screen if (x, y) is outside the picture
+    pub fn set_position(&mut self, x: usize, y: usize) {
+        self.position = if x < ppu::SCREEN_WIDTH && y < ppu::SCREEN_HEIGHT { Some((x, y)) } else { None };
+    }
+
+    pub fn set_trigger(&mut self, pulled: bool) {
+        self.trigger = pulled;
+    }
+
+    // Light only reaches the photodiode for a short while after the beam draws the pixel under
+    // it, so the gun only sees a bright pixel drawn this frame, for a few scanlines after it
+    pub fn senses_light(&self, ppu: &ppu::PPU) -> bool {
+        let (x, y) = match self.position {
+            Some(position) => position,
+            None => return false,
+        };
+        let scanline = ppu.scanline() as usize;
+        if scanline <= y || scanline > y + SENSE_LINES {
+            return false;
+        }
+        let color = ppu.framebuffer[y * ppu::SCREEN_WIDTH + x];
+        let [r, g, b] = ppu.rgb_palette[(color & 0x3F) as usize];
+        let luma = (299 * r as u32 + 587 * g as u32 + 114 * b as u32) / 1000;
+        luma >= BRIGHTNESS_THRESHOLD
+    }
+
+    // $4017 as the gun drives it
+    pub fn read(&self, ppu: &ppu::PPU) -> u8 {
+        let mut val = 0;
+        if !self.senses_light(ppu) {
+            val |= LIGHT_SENSE;
+        }
+        if self.trigger {
+            val |= TRIGGER;
+        }
+        return val
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use controller::InputFrame;
+    use emulator::Nes;
+    use mem::{Addressable, Port2Device};
+    use testing;
+
+    // $4017's trigger and light bits, read once at the start of every scanline for a frame, with
+    // the gun on (x, y) over a screen of backdrop
+    fn reads_over_a_frame(backdrop: u8, x: usize, y: usize) -> Vec<(u16, u8)> {
+        let rom = testing::build_test_rom("reset: LDA #$0A\n STA $2001\nloop: JMP loop", Some(&[0; 16]));
+        let mut nes = Nes::builder().rom_bytes(&rom).ppu_warmup(false).build().unwrap();
+        let memory = nes.cpu_mut().memory_mut();
+        memory.set_port2_device(Port2Device::Zapper);
+        memory.set_zapper_position(x, y);
+        memory.set_zapper_trigger(true);
+        memory.ppu.set_palette_entry(0x00, backdrop);
+        nes.run_frame(InputFrame::default()).unwrap();
+
+        let mut reads = Vec::new();
+        let mut scanline = None;
+        while reads.len() < nes.cpu().memory().ppu.scanlines_per_frame() as usize {
+            assert!(nes.step().unwrap().is_continue());
+            let memory = nes.cpu_mut().memory_mut();
+            let now = memory.ppu.scanline();
+            if scanline != Some(now) {
+                scanline = Some(now);
+                reads.push((now, memory.loadb(0x4017) & (LIGHT_SENSE | TRIGGER)));
+            }
+        }
+        reads
+    }
+
+    #[test]
+    fn light_is_seen_for_a_while_after_a_bright_pixel() {
+        for (scanline, val) in reads_over_a_frame(0x30, 128, 100) {
+            let lit = scanline > 100 && scanline <= 125;
+            assert_eq!(val, if lit { TRIGGER } else { TRIGGER | LIGHT_SENSE }, "scanline {}", scanline);
+        }
+    }
+
+    #[test]
+    fn dark_pixels_and_off_screen_are_never_seen() {
+        for &(backdrop, x, y) in [(0x0F, 128, 100), (0x30, ppu::SCREEN_WIDTH, 100), (0x30, 0, ppu::SCREEN_HEIGHT)].iter() {
+            let reads = reads_over_a_frame(backdrop, x, y);
+            assert!(reads.iter().all(|&(_, val)| val == TRIGGER | LIGHT_SENSE), "${:02X} at ({}, {})", backdrop, x, y);
+        }
+    }
+
+    #[test]
+    fn trigger_is_its_own_bit() {
+        let mut zapper = Zapper::new();
+        let rom = testing::build_rom(&[]);
+        let nes = Nes::builder().rom_bytes(&rom).build().unwrap();
+        let ppu = &nes.cpu().memory().ppu;
+        assert_eq!(zapper.read(ppu), LIGHT_SENSE);
+        zapper.set_trigger(true);
+        assert_eq!(zapper.read(ppu), LIGHT_SENSE | TRIGGER);
+    }
+}
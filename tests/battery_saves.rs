@@ -0,0 +1,54 @@
+// The nes binary keeps a battery-backed cartridge's PRG RAM next to the ROM as .sav, and only
+// when the header says there's RAM to keep
+use std::fs;
+use std::path::PathBuf;
+use std::process::{self, Command};
+
+// Writes $42 to $6000, then spins
+const PROGRAM: [u8; 8] = [0xA9, 0x42, 0x8D, 0x00, 0x60, 0x4C, 0x05, 0xC0];
+
+// An NROM image running PROGRAM with flags 6, 7 and 10 of the header given, in a directory of
+// its own
+fn write_rom(name: &str, flags_6: u8, flags_7: u8, flags_10: u8) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("nes-battery-{}-{}", process::id(), name));
+    fs::create_dir_all(&dir).unwrap();
+    let mut image = vec![b'N', b'E', b'S', 0x1A, 1, 1, flags_6, flags_7, 0, 0, flags_10, 0, 0, 0, 0, 0];
+    let mut prg = vec![0xEA; 0x4000];
+    prg[..PROGRAM.len()].copy_from_slice(&PROGRAM);
+    prg[0x3FFA..].copy_from_slice(&[0x00, 0xC0, 0x00, 0xC0, 0x00, 0xC0]);
+    image.extend_from_slice(&prg);
+    image.extend_from_slice(&[0; 0x2000]);
+    let path = dir.join(format!("{}.nes", name));
+    fs::write(&path, image).unwrap();
+    path
+}
+
+fn run(rom: &PathBuf) {
+    let output = Command::new(env!("CARGO_BIN_EXE_nes"))
+        .arg(rom).args(["--frames", "2"])
+        .env("HOME", rom.parent().unwrap())
+        .output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+#[test]
+fn ram_is_saved_when_the_header_has_it() {
+    // An iNES battery bit, and an NES 2.0 header giving 8 KiB of battery RAM
+    for &(name, flags_7, flags_10) in [("ines", 0x00, 0x00), ("nes2", 0x08, 0x70)].iter() {
+        let rom = write_rom(name, 0x02, flags_7, flags_10);
+        run(&rom);
+        let save = fs::read(rom.with_extension("sav")).unwrap();
+        assert_eq!(save.len(), 0x2000, "{}", name);
+        assert_eq!(save[0], 0x42, "{}", name);
+        fs::remove_dir_all(rom.parent().unwrap()).unwrap();
+    }
+}
+
+#[test]
+fn no_save_without_ram() {
+    // A battery but no RAM for it to keep
+    let rom = write_rom("no-ram", 0x02, 0x08, 0x00);
+    run(&rom);
+    assert!(!rom.with_extension("sav").exists());
+    fs::remove_dir_all(rom.parent().unwrap()).unwrap();
+}
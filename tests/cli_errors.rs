@@ -0,0 +1,106 @@
+// Bad paths and bad files given to the nes binary are reported and exit with 1, instead of
+// panicking into a crash bundle. A recording that fails partway through just stops.
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Output};
+
+// A directory of its own holding an NROM image that idles, and HOME and TMPDIR for the run
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("nes-cli-errors-{}-{}", process::id(), name));
+    fs::create_dir_all(&dir).unwrap();
+    let mut image = vec![b'N', b'E', b'S', 0x1A, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    let mut prg = vec![0xEA; 0x4000];
+    prg[..3].copy_from_slice(&[0x4C, 0x00, 0xC0]);
+    prg[0x3FFA..].copy_from_slice(&[0x00, 0xC0, 0x00, 0xC0, 0x00, 0xC0]);
+    image.extend_from_slice(&prg);
+    image.extend_from_slice(&[0; 0x2000]);
+    fs::write(dir.join("idle.nes"), image).unwrap();
+    dir
+}
+
+fn run(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_nes"))
+        .arg(dir.join("idle.nes")).args(["--frames", "2"]).args(args)
+        .env("HOME", dir)
+        .env("TMPDIR", dir)
+        .output().unwrap()
+}
+
+// Exited with 1 and said why, without a panic or a crash bundle
+fn assert_refused(dir: &Path, output: &Output, message: &str) {
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr);
+    assert!(stderr.contains(message), "{}", stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+    assert!(fs::read_dir(dir).unwrap().all(|entry| !entry.unwrap().file_name().to_string_lossy().starts_with("nes-crash")));
+}
+
+#[test]
+fn bad_palettes_are_refused() {
+    let dir = scratch("palette");
+    let missing = dir.join("missing.pal");
+    let output = run(&dir, &["--palette", missing.to_str().unwrap()]);
+    assert_refused(&dir, &output, &format!("Can't read palette {}", missing.display()));
+
+    let short = dir.join("short.pal");
+    fs::write(&short, [0; 100]).unwrap();
+    let output = run(&dir, &["--palette", short.to_str().unwrap()]);
+    assert_refused(&dir, &output, "needs 64 RGB triples");
+
+    let good = dir.join("good.pal");
+    fs::write(&good, [0; 192]).unwrap();
+    assert!(run(&dir, &["--palette", good.to_str().unwrap()]).status.success());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn bad_flags_print_the_usage() {
+    let dir = scratch("flags");
+    let output = run(&dir, &["--turbo-rate", "0"]);
+    assert_refused(&dir, &output, "--turbo-rate needs a number from 1 to 255\n\nusage: nes [OPTIONS] ROM");
+    let output = run(&dir, &["--frames"]);
+    assert_refused(&dir, &output, "--frames needs a count");
+
+    let output = run(&dir, &["--help"]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("usage: nes [OPTIONS] ROM\n"));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn recordings_that_cant_be_written() {
+    let dir = scratch("recording");
+    let nowhere = dir.join("missing").join("out.y4m");
+    let output = run(&dir, &["--record-video", nowhere.to_str().unwrap()]);
+    assert_refused(&dir, &output, &format!("Can't record video to {}", nowhere.display()));
+    let output = run(&dir, &["--record-audio", "/dev/full"]);
+    assert_refused(&dir, &output, "Can't record audio to /dev/full");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+// A reader that goes away after the header makes the first frame fail to write
+#[cfg(unix)]
+#[test]
+fn a_failed_write_stops_the_recording() {
+    use std::io::Read;
+    use std::thread;
+
+    let dir = scratch("broken-pipe");
+    let fifo = dir.join("video.y4m");
+    assert!(Command::new("mkfifo").arg(&fifo).status().unwrap().success());
+    let reader = {
+        let fifo = fifo.clone();
+        thread::spawn(move || {
+            let mut header = [0; 4];
+            File::open(fifo).unwrap().read_exact(&mut header).unwrap();
+            header
+        })
+    };
+    let output = run(&dir, &["--record-video", fifo.to_str().unwrap()]);
+    assert_eq!(&reader.join().unwrap(), b"YUV4");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(stderr.contains(&format!("Can't write video to {}", fifo.display())), "{}", stderr);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Stopped recording video after 0 frames"));
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,64 @@
+// Test programs print through the debug device and the nes binary exits with their code
+use std::fs;
+use std::path::PathBuf;
+use std::process::{self, Command};
+
+// Writes "HELLO\n" to $4018 a byte at a time, then code to $4019
+fn program(code: u8) -> Vec<u8> {
+    let mut program = vec![
+        0xA2, 0x00,             // LDX #0
+        0xBD, 0x15, 0xC0,       // print: LDA msg,X
+        0x8D, 0x18, 0x40,       // STA $4018
+        0xE8,                   // INX
+        0xE0, 0x06,             // CPX #6
+        0xD0, 0xF5,             // BNE print
+        0xA9, code,             // LDA #code
+        0x8D, 0x19, 0x40,       // STA $4019
+        0x4C, 0x12, 0xC0,       // spin: JMP spin
+    ];
+    program.extend_from_slice(b"HELLO\n");
+    program
+}
+
+// An NROM image running program from $C000, in a directory of its own
+fn write_rom(name: &str, program: &[u8]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("nes-debug-device-{}-{}", process::id(), name));
+    fs::create_dir_all(&dir).unwrap();
+    let mut image = vec![b'N', b'E', b'S', 0x1A, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    let mut prg = vec![0xEA; 0x4000];
+    prg[..program.len()].copy_from_slice(program);
+    prg[0x3FFA..].copy_from_slice(&[0x00, 0xC0, 0x00, 0xC0, 0x00, 0xC0]);
+    image.extend_from_slice(&prg);
+    image.extend_from_slice(&[0; 0x2000]);
+    let path = dir.join(format!("{}.nes", name));
+    fs::write(&path, image).unwrap();
+    path
+}
+
+#[test]
+fn the_binary_prints_the_output_and_exits_with_the_code() {
+    for &code in [0, 3].iter() {
+        let rom = write_rom(&format!("exit-{}", code), &program(code));
+        let output = Command::new(env!("CARGO_BIN_EXE_nes"))
+            .arg(&rom).args(["--debug-device", "--frames", "60"])
+            .env("HOME", rom.parent().unwrap())
+            .output().unwrap();
+        assert_eq!(output.status.code(), Some(code as i32), "{}", String::from_utf8_lossy(&output.stderr));
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(stdout.starts_with("HELLO\n"), "{}", stdout);
+        assert!(stdout.contains(&format!("Program exited with code {}", code)), "{}", stdout);
+        fs::remove_dir_all(rom.parent().unwrap()).unwrap();
+    }
+}
+
+#[test]
+fn without_the_device_the_program_runs_on() {
+    let rom = write_rom("off", &program(3));
+    let output = Command::new(env!("CARGO_BIN_EXE_nes"))
+        .arg(&rom).args(["--frames", "2"])
+        .env("HOME", rom.parent().unwrap())
+        .output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("HELLO"));
+    fs::remove_dir_all(rom.parent().unwrap()).unwrap();
+}
@@ -0,0 +1,118 @@
+// The steady-state emulation loop mustn't touch the heap. A global allocator counts what the
+// test's own thread allocates, since the harness runs other tests alongside.
+extern crate nes;
+
+use nes::asm::assemble;
+use nes::controller::InputFrame;
+use nes::cpu::{CPU, TraceEvent};
+use nes::emulator::Nes;
+use nes::testing::{build_program, build_test_rom};
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+struct CountingAllocator;
+
+thread_local! {
+    static COUNTING: Cell<bool> = const { Cell::new(false) };
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if COUNTING.with(|counting| counting.get()) {
+            ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        }
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if COUNTING.with(|counting| counting.get()) {
+            ALLOCATIONS.with(|count| count.set(count.get() + 1));
+        }
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+// Heap allocations made by f on this thread
+fn allocations<F: FnOnce()>(f: F) -> usize {
+    ALLOCATIONS.with(|count| count.set(0));
+    COUNTING.with(|counting| counting.set(true));
+    f();
+    COUNTING.with(|counting| counting.set(false));
+    ALLOCATIONS.with(|count| count.get())
+}
+
+const ADC_BRANCH_LOOP: &str = "
+loop:   ADC #$01
+        BNE skip
+        INX
+skip:   LDA $0280,X
+        STA $0380,X
+        JMP loop
+";
+
+// In batches, clearing the sound made in each the way a frontend would use it
+fn run_instructions(cpu: &mut CPU, batches: usize) {
+    for _ in 0..batches {
+        for _ in 0..1000 {
+            cpu.emulate_cycle().unwrap();
+        }
+        cpu.memory_mut().apu.clear_samples();
+    }
+}
+
+#[test]
+fn instructions_dont_allocate() {
+    let mut cpu = build_program(&assemble(ADC_BRANCH_LOOP, 0x8000).unwrap());
+    run_instructions(&mut cpu, 2);
+    assert_eq!(allocations(|| run_instructions(&mut cpu, 100)), 0);
+}
+
+#[test]
+fn trace_hook_doesnt_allocate_for_itself() {
+    let mut cpu = build_program(&assemble(ADC_BRANCH_LOOP, 0x8000).unwrap());
+    let traced = Arc::new(AtomicUsize::new(0));
+    let counter = traced.clone();
+    cpu.set_trace_hook(Box::new(move |event: &TraceEvent| {
+        counter.fetch_add(event.pc as usize & 1, Ordering::Relaxed);
+    }));
+    run_instructions(&mut cpu, 2);
+    assert_eq!(allocations(|| run_instructions(&mut cpu, 100)), 0);
+    assert!(traced.load(Ordering::Relaxed) > 0);
+}
+
+#[test]
+fn frames_dont_allocate() {
+    // Rendering on, with sound playing
+    let rom = build_test_rom("
+        LDA #$1E
+        STA $2001
+        LDA #$01
+        STA $4015
+        LDA #$BF
+        STA $4000
+        LDA #$FD
+        STA $4002
+        LDA #$08
+        STA $4003
+loop:   JMP loop
+", None);
+    let mut nes = Nes::builder().rom_bytes(&rom).ppu_warmup(false).build().unwrap();
+    for _ in 0..10 {
+        nes.run_frame(InputFrame::default()).unwrap();
+    }
+    let count = allocations(|| for _ in 0..10 {
+        nes.run_frame(InputFrame::default()).unwrap();
+    });
+    assert_eq!(count, 0);
+}
@@ -0,0 +1,230 @@
+// The programs in tests/roms, assembled into NROM images and run headlessly from power-on. The
+// same checks as tests/roms/regression.toml, as part of
+//   cargo test --features testing
+extern crate nes;
+
+use nes::controller::InputFrame;
+use nes::mem::RamInit;
+use nes::movie::{self, Movie};
+use nes::testing::build_test_rom;
+use nes::Nes;
+
+use std::fs;
+use std::path::PathBuf;
+
+// The picture every program that leaves rendering off ends up with, and the sound of an APU
+// that's never written
+const BLANK_FRAME: u64 = 0x3fd4ebc4ab9ce325;
+const SILENCE: u64 = 0xc9acfdd754197a55;
+
+fn rom_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("roms").join(name)
+}
+
+// Build a program, with its CHR file if it has one, and run it to frame, pressing what the
+// movie says
+fn run(program: &str, chr: Option<&str>, movie: Option<&str>, frame: u64) -> Nes {
+    let source = fs::read_to_string(rom_path(program)).unwrap();
+    let chr = chr.map(|name| fs::read(rom_path(name)).unwrap());
+    let rom = build_test_rom(&source, chr.as_deref());
+    let mut nes = Nes::builder().rom_bytes(&rom).ram_init(RamInit::Zero).build().unwrap();
+    let movie = movie.map(|name| {
+        let movie = Movie::parse(&fs::read_to_string(rom_path(name)).unwrap()).unwrap();
+        movie.check_rom(&movie::rom_checksum(&nes.cpu().memory().rom.md5())).unwrap();
+        movie
+    });
+    while nes.cpu().memory().ppu.frame < frame {
+        let current = nes.cpu().memory().ppu.frame as usize;
+        let buttons = movie.as_ref().and_then(|movie| movie.frame(current)).unwrap_or([0, 0]);
+        nes.run_frame(InputFrame::from(buttons)).unwrap();
+    }
+    nes
+}
+
+// A program written out in the test, run for frames frames with nothing pressed
+fn run_source(source: &str, frames: u64) -> Nes {
+    let mut nes = Nes::builder().rom_bytes(&build_test_rom(source, None)).ram_init(RamInit::Zero).build().unwrap();
+    for _ in 0..frames {
+        nes.run_frame(InputFrame::default()).unwrap();
+    }
+    nes
+}
+
+fn check_ram(nes: &Nes, ram: &[(u16, u8)]) {
+    let memory = nes.cpu().memory();
+    for &(addr, expected) in ram {
+        assert_eq!(memory.peek(addr), expected, "${:04X}", addr);
+    }
+}
+
+fn check(nes: &Nes, hash: u64, audio_hash: u64, ram: &[(u16, u8)]) {
+    check_ram(nes, ram);
+    let memory = nes.cpu().memory();
+    assert_eq!(memory.ppu.frame_hash(), hash, "frame hash");
+    assert_eq!(memory.apu.output_hash_for_frame(), audio_hash, "audio hash");
+}
+
+#[test]
+fn cpu_arith() {
+    let nes = run("cpu_arith.s", None, None, 5);
+    check(&nes, BLANK_FRAME, SILENCE, &[
+        (0x0200, 0x46), (0x0201, 0x00), (0x0202, 0x01), (0x0203, 0x05), (0x0204, 0x80), (0x0205, 0xCC),
+        (0x0206, 0x00), (0x0207, 0xFF), (0x0208, 0x8F), (0x0209, 0x44), (0x020A, 0x22), (0x020B, 0x33),
+        (0x020C, 0x11), (0x020D, 0x5A), (0x02FF, 0x01),
+    ]);
+}
+
+// cpu_arith.s steers around the CPU's known defects. These are what it would check without them,
+// and pass once each is fixed. Each program writes $01 to $03FF when it gets to the end.
+
+#[test]
+#[ignore = "N and V are never set, and Z only by compares"]
+fn cpu_sets_nzv_flags() {
+    let nes = run_source("
+reset:  LDA #$00            ; $0300: LDA of $00 sets Z
+        BNE not_zero
+        INC $0300
+not_zero: LDA #$80          ; $0301: and of $80 sets N
+        BPL not_negative
+        INC $0301
+not_negative: LDA #$00      ; $0302: $40 + $40 overflows into the sign bit, setting V
+        ASL A
+        LDA #$40
+        ADC #$40
+        BVC no_overflow
+        INC $0302
+no_overflow: LDA #$01
+        STA $03FF
+done:   JMP done
+", 2);
+    check_ram(&nes, &[(0x0300, 0x01), (0x0301, 0x01), (0x0302, 0x01), (0x03FF, 0x01)]);
+}
+
+#[test]
+#[ignore = "SBC, CMP and CPX treat the carry as a borrow, the wrong way round (src/cpu.rs sbc and compare)"]
+fn cpu_subtracts_with_carry_as_not_borrow() {
+    let nes = run_source("
+reset:  LDA #$05            ; $0310: CMP sets C when A is at least the operand
+        CMP #$03
+        BCC cmp_borrowed
+        INC $0310
+cmp_borrowed: LDX #$05      ; $0311: and CPX when X is
+        CPX #$03
+        BCC cpx_borrowed
+        INC $0311
+cpx_borrowed: LDA #$80      ; $0312: SBC with C set, so nothing borrowed, 5 - 3 = 2
+        ASL A
+        LDA #$05
+        SBC #$03
+        STA $0312
+        BCC sbc_borrowed    ; $0313: and nothing borrowed out leaves C set
+        INC $0313
+sbc_borrowed: LDA #$01
+        STA $03FF
+done:   JMP done
+", 2);
+    check_ram(&nes, &[(0x0310, 0x01), (0x0311, 0x01), (0x0312, 0x02), (0x0313, 0x01), (0x03FF, 0x01)]);
+}
+
+#[test]
+#[ignore = "CLC, SEC, CLD, BIT, PHA, PLA, TAX and TXS have no handler in src/cpu.rs, so they stop the CPU"]
+fn cpu_has_the_missing_instructions() {
+    let nes = run_source("
+reset:  SEC                 ; $0320: SEC then ROL = $01
+        LDA #$00
+        ROL A
+        STA $0320
+        CLC                 ; $0321: CLC then ROL = $02
+        ROL A
+        STA $0321
+        CLD
+        LDA #$42            ; $0322: PHA then PLA = $42
+        PHA
+        LDA #$00
+        PLA
+        STA $0322
+        TAX                 ; $0323: TAX = $42
+        STX $0323
+        LDA #$C0            ; $0324: BIT copies bit 6 into V
+        STA $10
+        BIT $10
+        BVC no_overflow
+        INC $0324
+no_overflow: LDX #$F0       ; $01F0: TXS moves the stack, so PHA pushes there
+        TXS
+        LDA #$99
+        PHA
+        LDA #$01
+        STA $03FF
+done:   JMP done
+", 2);
+    check_ram(&nes, &[
+        (0x0320, 0x01), (0x0321, 0x02), (0x0322, 0x42), (0x0323, 0x42), (0x0324, 0x01), (0x01F0, 0x99),
+        (0x03FF, 0x01),
+    ]);
+}
+
+// How nearly every game starts
+#[test]
+#[ignore = "the usual SEI; CLD; LDX #$FF; TXS startup stops at CLD, which has no handler"]
+fn cpu_runs_the_usual_startup() {
+    let nes = run_source("
+reset:  SEI
+        CLD
+        LDX #$FF
+        TXS
+        JSR mark            ; $0330: the subroutine ran, with its return address at the top of
+        LDA #$01            ; the stack
+        STA $03FF
+done:   JMP done
+mark:   LDA #$01
+        STA $0330
+        RTS
+", 2);
+    check_ram(&nes, &[(0x0330, 0x01), (0x01FF, 0x80), (0x03FF, 0x01)]);
+    assert_eq!(nes.cpu().s(), 0xFF);
+}
+
+#[test]
+fn stack_interrupts() {
+    let nes = run("stack_interrupts.s", None, None, 20);
+    check(&nes, BLANK_FRAME, SILENCE, &[
+        (0x0300, 0x02), (0x0301, 0x80), (0x0302, 0x01), (0x0303, 0x01), (0x0304, 0x11), (0x0305, 0x05),
+        (0x0306, 0xAA),
+    ]);
+}
+
+#[test]
+fn ppu_nametable() {
+    let nes = run("ppu_nametable.s", Some("ppu_nametable.chr"), None, 10);
+    check(&nes, 0x05f19c1a40933525, SILENCE, &[]);
+}
+
+#[test]
+fn controller_echo() {
+    let nes = run("controller_echo.s", None, Some("controller_echo.fm2"), 30);
+    check(&nes, BLANK_FRAME, SILENCE, &[(0x0400, 0x00), (0x0401, 0x8B), (0x0402, 0x05)]);
+}
+
+// Sound is generated with integer arithmetic only, so the hash is the same in every build, and
+// two runs agree on every frame's
+#[test]
+fn apu_channels() {
+    let nes = run("apu_channels.s", None, None, 30);
+    check(&nes, BLANK_FRAME, 0x1a40c14e6880a39c, &[]);
+
+    let frame_hashes = || {
+        let source = fs::read_to_string(rom_path("apu_channels.s")).unwrap();
+        let mut nes = Nes::builder().rom_bytes(&build_test_rom(&source, None)).ram_init(RamInit::Zero).build().unwrap();
+        (0..30).map(|_| {
+            nes.run_frame(InputFrame::default()).unwrap();
+            nes.cpu().memory().apu.output_hash_for_frame()
+        }).collect::<Vec<u64>>()
+    };
+    let first = frame_hashes();
+    assert_eq!(first, frame_hashes());
+    // Once the PPU has warmed up, every frame sounds different
+    let playing = &first[2..];
+    assert!(playing.iter().all(|&hash| hash != SILENCE));
+    assert!(playing.windows(2).all(|pair| pair[0] != pair[1]));
+}
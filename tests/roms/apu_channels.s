@@ -0,0 +1,47 @@
+; Plays every APU channel at once, in five-step mode: pulse 1 held at 440 Hz, pulse 2 decaying
+; and sweeping down, the triangle under its linear counter and looping noise. Each NMI bumps
+; pulse 1's period so the tone steps up frame by frame.
+reset:  SEI
+        LDA #$80            ; five-step mode, so the frame counter never interrupts
+        STA $4017
+        LDA #$0F
+        STA $4015
+        LDA #$BF            ; pulse 1: 50% duty, constant volume 15, period 253
+        STA $4000
+        LDA #$FD
+        STA $4002
+        LDA #$08
+        STA $4003
+        LDA #$44            ; pulse 2: 25% duty, envelope decaying from 15 at period 4
+        STA $4004
+        LDA #$8A            ; and its period sweeping down by a quarter every half frame
+        STA $4005
+        LDA #$80
+        STA $4006
+        LDA #$09
+        STA $4007
+        LDA #$60            ; triangle: linear counter of 96
+        STA $4008
+        LDA #$40
+        STA $400A
+        LDA #$08
+        STA $400B
+        LDA #$28            ; noise: looping envelope at period 8, mode 0, period index 6
+        STA $400C
+        LDA #$06
+        STA $400E
+        LDA #$08
+        STA $400F
+wait:   LDA #$80            ; the PPU ignores $2000 while it warms up, so keep asking until
+        STA $2000           ; the first NMI
+        LDA $11
+        CMP #1
+        BNE wait
+done:   JMP done
+
+nmi:    DEC $10             ; one period lower each frame
+        LDA $10
+        STA $4002
+        LDA #$01
+        STA $11
+        RTI
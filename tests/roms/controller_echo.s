@@ -0,0 +1,33 @@
+; Reads the first controller every NMI. $0400 has the last buttons read, A in bit 0 as in a
+; movie, $0401 every button seen and $0402 how many NMIs saw A.
+reset:  SEI
+wait:   LDA #$80            ; the PPU ignores $2000 while it warms up, so keep asking until
+        STA $2000           ; the first NMI
+        LDA $11
+        CMP #1
+        BNE wait
+done:   JMP done
+
+nmi:    LDA #$01
+        STA $4016
+        LDA #$00
+        STA $4016
+        LDX #$00
+read:   LDA $4016           ; A comes first, so it ends up in bit 0
+        LSR A
+        ROR $10
+        INX
+        CPX #8
+        BNE read
+        LDA $10
+        STA $0400
+        ORA $0401
+        STA $0401
+        LDA $10
+        AND #$01
+        CMP #$01
+        BNE skip
+        INC $0402
+skip:   LDA #$01
+        STA $11
+        RTI
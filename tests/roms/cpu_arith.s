@@ -0,0 +1,82 @@
+; Arithmetic, logic and addressing results written to $0200 onwards, with $01 at $02FF once
+; done. The CPU doesn't set N, Z or V outside of compares yet, and borrows the wrong way in SBC
+; and CMP, so the carry only comes from shifts here and loops end on CPX. The ignored tests in
+; tests/roms.rs check each of those, and the instructions this leaves out.
+reset:  SEI
+
+        LDA #$00            ; $0200: $12 + $34 = $46, with C cleared by shifting out a 0
+        ASL A
+        LDA #$12
+        ADC #$34
+        STA $0200
+        LDA #$80            ; $0201: $7F + $80 + C = $00, carrying out
+        ASL A
+        LDA #$7F
+        ADC #$80
+        STA $0201
+        LDA #$00            ; $0202: the carry out = $01
+        ROL A
+        STA $0202
+
+        LDA #$81            ; $0203: ASL carries the top bit into ROL = $05
+        ASL A
+        ROL A
+        STA $0203
+        LDA #$03            ; $0204: LSR carries the bottom bit into ROR = $80
+        LSR A
+        ROR A
+        STA $0204
+        LDA #$F0            ; $0205: ($F0 & $3C | $03) ^ $FF = $CC
+        AND #$3C
+        ORA #$03
+        EOR #$FF
+        STA $0205
+
+        LDA #$FF            ; $0206 and $0207: INC and DEC wrap, $00 and $FF
+        STA $10
+        INC $10
+        DEC $11
+        LDA $10
+        STA $0206
+        LDA $11
+        STA $0207
+
+        LDX #$00            ; $0208: 13 * 11 = 143 = $8F by repeated addition, clearing C
+        STX $12             ; each time since CPX sets it
+mul:    LDA #$00
+        ASL A
+        LDA $12
+        ADC #13
+        STA $12
+        INX
+        CPX #11
+        BNE mul
+        STA $0208
+
+        LDX #$03            ; $0209-$020C: table,X, table,Y, (ptr),Y and (ptr,X)
+        LDA table,X
+        STA $0209
+        LDY #$01
+        LDA table,Y
+        STA $020A
+        LDA #<table
+        STA $20
+        LDA #>table
+        STA $21
+        LDY #$02
+        LDA ($20),Y
+        STA $020B
+        LDX #$10
+        LDA ($10,X)
+        STA $020C
+        LDX #$05            ; $020D: zero page,X wraps within the zero page = $5A
+        LDA #$5A
+        STA $02
+        LDA $FD,X
+        STA $020D
+
+        LDA #$01
+        STA $02FF
+done:   JMP done
+
+table:  .byte $11, $22, $33, $44
@@ -0,0 +1,50 @@
+; Waits for two vblanks, then sets a palette, writes a row of tiles from ppu_nametable.chr to
+; the first nametable and turns the background on
+reset:  SEI
+wait:   LDA #$80            ; vblanks are counted by NMI. The PPU ignores $2000 while it warms
+        STA $2000           ; up, so keep asking.
+        LDA $11
+        CMP #2
+        BNE wait
+        LDA #$00
+        STA $2000
+
+        LDA #$3F            ; backdrop black, then white, red and blue
+        STA $2006
+        LDA #$00
+        STA $2006
+        LDX #$00
+pal:    LDA palette,X
+        STA $2007
+        INX
+        CPX #4
+        BNE pal
+
+        LDA #$21            ; row 12 of the first nametable, tiles 0 to 3 over and over
+        STA $2006
+        LDA #$80
+        STA $2006
+        LDX #$00
+row:    LDA $10
+        STA $2007
+        INC $10
+        LDA $10
+        AND #$03
+        STA $10
+        INX
+        CPX #32
+        BNE row
+
+        LDA $2002           ; scroll back to the top left
+        LDA #$00
+        STA $2005
+        STA $2005
+        STA $2000
+        LDA #$0A            ; background on, including the leftmost 8 pixels
+        STA $2001
+done:   JMP done
+
+nmi:    INC $11
+        RTI
+
+palette: .byte $0F, $30, $16, $12
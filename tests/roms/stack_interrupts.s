@@ -0,0 +1,55 @@
+; Subroutines, the stack, BRK and NMI, with results from $0300 onwards. The stack starts at
+; $01FD after reset.
+reset:  SEI
+
+        JSR outer           ; $0300: two nested calls each add 1 = $02
+
+        LDA #$80            ; $0302: PLP brings back the carry PHP saved = $01
+        ASL A
+        PHP
+        LDA #$00
+        ASL A
+        PLP
+        LDA #$00
+        ROL A
+        STA $0302
+
+        LDA #$80            ; $0303: BRK's handler clears C, RTI brings it back = $01
+        ASL A
+        BRK
+        .byte $EA           ; BRK skips a padding byte
+        LDA #$00
+        ROL A
+        STA $0303
+
+wait:   LDA #$80            ; count 5 NMIs, then turn them off and mark $0306 = $AA. The
+        STA $2000           ; PPU ignores $2000 while it warms up, so keep asking.
+        LDA $0305
+        CMP #5
+        BNE wait
+        LDA #$00
+        STA $2000
+        LDA #$AA
+        STA $0306
+done:   JMP done
+
+outer:  INC $0300
+        JSR inner
+        RTS
+; $0301: the high byte of the return address into outer, pushed at $01FB = $80
+inner:  INC $0300
+        LDA $01FB
+        STA $0301
+        RTS
+
+; $0304: B and C of the flags BRK pushed = $11
+irq:    LDA $01FB
+        AND #$11
+        STA $0304
+        LDA #$00
+        ASL A
+        RTI
+
+; $0305: NMIs taken
+nmi:    INC $0305
+        RTI
@@ -0,0 +1,22 @@
+// The browser bindings on a real wasm32 target. Run with
+//   wasm-pack test --node -- --features wasm,testing
+// Everywhere else this is empty; src/wasm.rs has the same checks natively.
+#![cfg(all(target_arch = "wasm32", feature = "wasm", feature = "testing"))]
+
+extern crate nes;
+extern crate wasm_bindgen_test;
+
+use nes::testing::build_test_rom;
+use nes::wasm::WasmNes;
+
+use wasm_bindgen_test::wasm_bindgen_test;
+
+#[wasm_bindgen_test]
+fn run_frame_on_a_synthetic_rom() {
+    let mut nes = WasmNes::new(&build_test_rom("loop: JMP loop", None)).unwrap();
+    for _ in 0..3 {
+        assert_eq!(nes.run_frame().unwrap().len(), 256 * 240 * 4);
+    }
+    assert_eq!((nes.frame_width(), nes.frame_height()), (256, 240));
+    assert!(!nes.audio_samples().is_empty());
+}